@@ -0,0 +1,19 @@
+#!/bin/sh
+# Runs a kernel with `bootimage runner`, which passes the arguments after the kernel on to QEMU.
+# The ATA test gets a blank disk of its own as the primary slave, created for every run; the other
+# tests run without it.
+set -e
+executable="$1"
+shift
+case "$(basename "$executable")" in
+ata_dma-*)
+    disk="$(dirname "$executable")/ata-disk.img"
+    rm -f "$disk"
+    dd if=/dev/zero of="$disk" bs=1M count=0 seek=4 2>/dev/null
+    exec bootimage runner "$executable" "$@" \
+        -drive "if=ide,index=1,format=raw,snapshot=on,file=$disk"
+    ;;
+*)
+    exec bootimage runner "$executable" "$@"
+    ;;
+esac
@@ -17,20 +17,28 @@ unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
             return null_mut();
         }
 
-        let a = self.lock();
+        #[cfg_attr(not(feature = "alloc_debug"), allow(unused_mut))]
+        let mut a = self.lock();
         match a.get_block_of_min_order(order) {
             Some(block) => {
                 let block = block
                     .as_mut()
                     .expect("got null addr instead of block pointer");
                 block.is_free = false;
-                block.get_memory_ptr(layout.align()) as *mut u8
+                let ptr = block.get_memory_ptr(layout.align()) as *mut u8;
+                // The free marker is written over the start of a freed block
+                #[cfg(feature = "alloc_debug")]
+                a.freed.on_alloc(ptr, layout.size(), size_of::<Block>());
+                ptr
             }
             None => null_mut(),
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+    #[cfg_attr(not(feature = "alloc_debug"), allow(unused_variables))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc_debug")]
+        self.lock().freed.on_free(ptr, layout.size());
         let block = (ptr as *mut Block)
             .as_mut()
             .expect("got invalid block pointer during dealloc");
@@ -41,14 +49,18 @@ unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
 
 pub struct BuddyAllocator {
     heap_start: usize,
-    heap_order: u8,
+    heap_end: usize,
+    #[cfg(feature = "alloc_debug")]
+    freed: super::debug::FreeTracker,
 }
 
 impl BuddyAllocator {
     pub const fn new() -> Self {
         Self {
             heap_start: 0,
-            heap_order: 0,
+            heap_end: 0,
+            #[cfg(feature = "alloc_debug")]
+            freed: super::debug::FreeTracker::new(),
         }
     }
 
@@ -57,20 +69,24 @@ impl BuddyAllocator {
     }
 
     fn init_inner(&mut self, heap_start: usize, heap_size: usize) {
-        let order = Self::order(heap_size).min(MAX_ORDER);
-        self.heap_order = order;
+        // The heap is split into the largest blocks which fit, from the largest down, so a heap
+        // which isn't a power of two only leaves out less than a block at the end. Each block
+        // starts at a multiple of its size from the start of the heap
         self.heap_start = heap_start;
-        let block = heap_start as *mut Block;
-        unsafe {
-            block.write(Block {
-                order,
-                is_free: true,
-            });
+        let mut end = heap_start;
+        let mut left = heap_size / BLOCK_SIZE;
+        while left > 0 {
+            let order = (left.ilog2() as u8).min(MAX_ORDER);
+            unsafe {
+                (end as *mut Block).write(Block {
+                    order,
+                    is_free: true,
+                });
+            }
+            end += BLOCK_SIZE << order;
+            left -= 1 << order;
         }
-    }
-
-    fn heap_size(&self) -> usize {
-        BLOCK_SIZE << self.heap_order
+        self.heap_end = end;
     }
 
     fn get_block_of_min_order(&self, order: u8) -> Option<*mut Block> {
@@ -97,7 +113,7 @@ impl BuddyAllocator {
 
     fn next_block(&self, block: &Block) -> Option<*mut Block> {
         let next_block = block.next_free_addr();
-        if next_block as usize - self.heap_start >= self.heap_size() {
+        if next_block >= self.heap_end {
             None
         } else {
             Some(next_block as *mut Block)
@@ -154,3 +170,24 @@ impl Block {
         }
     }
 }
+
+#[test_case]
+fn test_heap_which_isnt_a_power_of_two() {
+    use alloc::vec;
+
+    // 4 KiB, 2 KiB and 1 KiB blocks, rather than only the 4 KiB one
+    let mut memory = vec![0u128; 7 * 1024 / size_of::<u128>()];
+    let (start, size) = (memory.as_mut_ptr() as usize, 7 * 1024);
+    let heap = Locked::new(BuddyAllocator::new());
+    unsafe { heap.lock().init(start, size) };
+
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    let ptrs = (0..7)
+        .map(|_| unsafe { heap.alloc(layout) } as usize)
+        .collect::<alloc::vec::Vec<_>>();
+    assert!(ptrs.iter().all(|&ptr| ptr != 0));
+    assert!(ptrs
+        .iter()
+        .all(|&ptr| ptr >= start && ptr + layout.size() <= start + size));
+    assert!(unsafe { heap.alloc(layout) }.is_null());
+}
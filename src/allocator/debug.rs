@@ -0,0 +1,60 @@
+/// The byte freed blocks are filled with.
+pub const POISON: u8 = 0xde;
+/// The number of recently freed blocks remembered.
+const RECENT_FREES: usize = 64;
+
+/// Catches double frees and writes to freed memory. Freed blocks are poisoned and remembered
+/// until they are allocated again or pushed out by newer frees.
+#[derive(Debug)]
+pub struct FreeTracker {
+    // Addresses of recently freed blocks, zero for an empty entry
+    recent: [usize; RECENT_FREES],
+    next: usize,
+}
+
+impl FreeTracker {
+    pub const fn new() -> Self {
+        Self {
+            recent: [0; RECENT_FREES],
+            next: 0,
+        }
+    }
+
+    /// Called when a block of `size` bytes is freed, before the allocator writes its own
+    /// bookkeeping to it. Panics if the block was already freed.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a block of at least `size` bytes owned by the allocator.
+    pub unsafe fn on_free(&mut self, ptr: *mut u8, size: usize) {
+        if self.recent.contains(&(ptr as usize)) {
+            panic!("double free of {:p} ({} bytes)", ptr, size);
+        }
+        ptr.write_bytes(POISON, size);
+        self.recent[self.next] = ptr as usize;
+        self.next = (self.next + 1) % RECENT_FREES;
+    }
+
+    /// Called when a block of `size` bytes is handed out. If the block was freed recently, checks
+    /// that nothing but the allocator's first `header` bytes of bookkeeping changed since. The
+    /// block is cleared either way.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a block of at least `size` bytes owned by the allocator.
+    pub unsafe fn on_alloc(&mut self, ptr: *mut u8, size: usize, header: usize) {
+        if let Some(entry) = self.recent.iter_mut().find(|entry| **entry == ptr as usize) {
+            *entry = 0;
+            let block = core::slice::from_raw_parts(ptr, size);
+            if let Some(offset) = block.iter().skip(header).position(|&b| b != POISON) {
+                panic!(
+                    "use after free: {:p} ({} bytes) was written to at offset {} after being freed",
+                    ptr,
+                    size,
+                    header + offset
+                );
+            }
+        }
+        ptr.write_bytes(0, size);
+    }
+}
@@ -29,16 +29,30 @@ unsafe impl GlobalAlloc for Locked<FixedSizeAllocator> {
     }
 }
 
-const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// The sizes of the blocks handed out, each twice the one before.
+pub const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 const MIN_BLOCK_SIZE: usize = BLOCK_SIZES[0];
 const MAX_BLOCK_SIZE: usize = BLOCK_SIZES[BLOCK_SIZES.len() - 1];
 
+/// The free memory of a [`FixedSizeAllocator`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentationReport {
+    /// The number of free blocks of each size in [`BLOCK_SIZES`].
+    pub free_blocks: [usize; BLOCK_SIZES.len()],
+    /// Bytes in free blocks.
+    pub free_bytes: usize,
+    /// Bytes at the end of the heap which were never made into blocks.
+    pub untouched_bytes: usize,
+}
+
 #[derive(Debug)]
 pub struct FixedSizeAllocator {
     heap_start: usize,
     heap_size: usize,
     used_memory: usize,
     free_list: [Option<MyNonNull<ListNode>>; BLOCK_SIZES.len()],
+    #[cfg(feature = "alloc_debug")]
+    freed: super::debug::FreeTracker,
 }
 
 impl FixedSizeAllocator {
@@ -49,6 +63,8 @@ impl FixedSizeAllocator {
             heap_size: 0,
             used_memory: 0,
             free_list: [EMPTY; BLOCK_SIZES.len()],
+            #[cfg(feature = "alloc_debug")]
+            freed: super::debug::FreeTracker::new(),
         }
     }
 
@@ -72,25 +88,44 @@ impl FixedSizeAllocator {
             }
         }
 
+        let block = match self.find_block(layout) {
+            Some(block) => block,
+            // Merging free blocks may make one large enough
+            None if self.compact() > 0 => match self.find_block(layout) {
+                Some(block) => block,
+                None => return null_mut(),
+            },
+            None => return null_mut(), // out of memory
+        };
+        // The free list link is written over the start of a free block
+        #[cfg(feature = "alloc_debug")]
+        unsafe {
+            self.freed.on_alloc(
+                block.0.cast::<u8>().as_ptr(),
+                Self::round_up(size),
+                core::mem::size_of::<ListNode>(),
+            )
+        };
+        block.0.cast::<u8>().as_ptr()
+    }
+
+    fn find_block(&mut self, layout: Layout) -> Option<MyNonNull<ListNode>> {
+        let size = layout.size();
         let idx = Self::get_block_size_index(size);
-        let block = match self.remove_block(idx) {
-            Some(block_ptr) => block_ptr,
+        match self.remove_block(idx) {
+            Some(block_ptr) => Some(block_ptr),
             None => match self.find_index_of_larger_block(idx + 1) {
                 Some(larger_block_idx) => {
                     for i in 0..larger_block_idx - idx {
                         self.split_block(larger_block_idx - i);
                     }
-                    self.remove_block(idx).expect(
+                    Some(self.remove_block(idx).expect(
                         "split block function put the new block in the wrong free list slot",
-                    )
+                    ))
                 }
-                None => match self.create_block(size, layout.align()) {
-                    Some(block) => block,
-                    None => return null_mut(), // out of memory
-                },
+                None => self.create_block(size, layout.align()),
             },
-        };
-        block.0.cast::<u8>().as_ptr()
+        }
     }
 
     fn _alloc_huge(&mut self, layout: Layout) -> Option<NonNull<u8>> {
@@ -104,6 +139,14 @@ impl FixedSizeAllocator {
     }
 
     fn _dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc_debug")]
+        unsafe {
+            let size = match layout.size() {
+                size if size > MAX_BLOCK_SIZE => size,
+                size => Self::round_up(size),
+            };
+            self.freed.on_free(ptr, size)
+        };
         if layout.size() > MAX_BLOCK_SIZE {
             self._dealloc_huge(ptr, layout);
         } else {
@@ -121,6 +164,61 @@ impl FixedSizeAllocator {
         }
     }
 
+    /// Counts the free blocks of each size.
+    pub fn fragmentation_report(&self) -> FragmentationReport {
+        let mut report = FragmentationReport {
+            untouched_bytes: self.heap_size - self.used_memory,
+            ..Default::default()
+        };
+        for (idx, &head) in self.free_list.iter().enumerate() {
+            let mut node = head;
+            while let Some(block) = node {
+                report.free_blocks[idx] += 1;
+                report.free_bytes += BLOCK_SIZES[idx];
+                node = next(block);
+            }
+        }
+        report
+    }
+
+    /// Merges free blocks with their buddies, the free block of the same size next to them, into
+    /// blocks of the next size up. Returns the number of merges.
+    ///
+    /// Two blocks are only merged if the merged block is aligned to its size, like a block split
+    /// from it would have been. Padding blocks made by `create_block` often aren't, and are left
+    /// as they are.
+    pub fn compact(&mut self) -> usize {
+        let mut merges = 0;
+        // Blocks merged into the next size can be merged again when its list is compacted
+        for (idx, &size) in BLOCK_SIZES[..BLOCK_SIZES.len() - 1].iter().enumerate() {
+            let mut rest = sort_by_address(self.free_list[idx].take());
+            while let Some(block) = rest {
+                rest = next(block);
+                match rest {
+                    Some(buddy)
+                        if address(block) & (2 * size - 1) == 0
+                            && address(buddy) == address(block) + size =>
+                    {
+                        rest = next(buddy);
+                        // The link of the buddy is now in the middle of a free block
+                        #[cfg(feature = "alloc_debug")]
+                        unsafe {
+                            buddy
+                                .0
+                                .cast::<u8>()
+                                .as_ptr()
+                                .write_bytes(super::debug::POISON, core::mem::size_of::<ListNode>())
+                        };
+                        self.add_block(block.0.as_ptr(), 2 * size);
+                        merges += 1;
+                    }
+                    _ => self.add_block(block.0.as_ptr(), size),
+                }
+            }
+        }
+        merges
+    }
+
     fn split_block(&mut self, idx: usize) {
         let size = BLOCK_SIZES[idx
             .checked_sub(1)
@@ -208,3 +306,150 @@ impl FixedSizeAllocator {
 struct ListNode {
     next: Option<MyNonNull<ListNode>>,
 }
+
+fn address(node: MyNonNull<ListNode>) -> usize {
+    node.0.as_ptr() as usize
+}
+
+fn next(node: MyNonNull<ListNode>) -> Option<MyNonNull<ListNode>> {
+    unsafe { node.0.as_ref().next }
+}
+
+fn set_next(mut node: MyNonNull<ListNode>, next: Option<MyNonNull<ListNode>>) {
+    unsafe { node.0.as_mut().next = next };
+}
+
+/// Sorts a free list by address. A merge sort, since it can't allocate.
+fn sort_by_address(list: Option<MyNonNull<ListNode>>) -> Option<MyNonNull<ListNode>> {
+    let first = list?;
+    let Some(second) = next(first) else {
+        return list;
+    };
+    // Find the middle of the list with a pointer moving at half the speed
+    let (mut middle, mut end) = (first, second);
+    while let Some(after) = next(end) {
+        middle = next(middle).unwrap();
+        end = match next(after) {
+            Some(end) => end,
+            None => break,
+        };
+    }
+    let back = next(middle);
+    set_next(middle, None);
+
+    let (mut front, mut back) = (sort_by_address(list), sort_by_address(back));
+    let mut sorted = None;
+    let mut last: Option<MyNonNull<ListNode>> = None;
+    loop {
+        let node = match (front, back) {
+            (Some(a), Some(b)) if address(a) < address(b) => {
+                front = next(a);
+                a
+            }
+            (_, Some(b)) => {
+                back = next(b);
+                b
+            }
+            (Some(a), None) => {
+                front = next(a);
+                a
+            }
+            (None, None) => break,
+        };
+        match last {
+            Some(last) => set_next(last, Some(node)),
+            None => sorted = Some(node),
+        }
+        last = Some(node);
+    }
+    sorted
+}
+
+#[cfg(test)]
+fn test_heap() -> FixedSizeAllocator {
+    #[repr(align(4096))]
+    struct Heap([u8; 4096]);
+    static mut HEAP: Heap = Heap([0; 4096]);
+
+    let mut allocator = FixedSizeAllocator::new();
+    unsafe { allocator.init(core::ptr::addr_of_mut!(HEAP.0) as usize, 4096) };
+    allocator
+}
+
+#[test_case]
+fn test_compact_merges_fragmented_blocks() {
+    use alloc::vec::Vec;
+
+    let mut allocator = test_heap();
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let large = Layout::from_size_align(2048, 8).unwrap();
+    let blocks = core::iter::from_fn(|| NonNull::new(allocator._alloc(small))).collect::<Vec<_>>();
+    assert_eq!(blocks.len(), 4096 / 8);
+
+    // Every other block free, nothing to merge
+    for block in blocks.iter().step_by(2) {
+        allocator._dealloc(block.as_ptr(), small);
+    }
+    let report = allocator.fragmentation_report();
+    assert_eq!(report.free_blocks[0], 256);
+    assert_eq!(report.free_bytes, 2048);
+    assert_eq!(report.untouched_bytes, 0);
+    assert!(allocator._alloc(large).is_null());
+    assert_eq!(allocator.compact(), 0);
+
+    // The first half free, but in small blocks
+    for block in blocks[..256].iter().skip(1).step_by(2) {
+        allocator._dealloc(block.as_ptr(), small);
+    }
+    assert_eq!(allocator.fragmentation_report().free_blocks[8], 0);
+    assert_eq!(allocator.compact(), 128 + 64 + 32 + 16 + 8 + 4 + 2 + 1);
+    let report = allocator.fragmentation_report();
+    assert_eq!(report.free_blocks[0], 128);
+    assert_eq!(report.free_blocks[8], 1);
+    assert_eq!(report.free_bytes, 2048 + 1024);
+    assert_eq!(allocator._alloc(large), blocks[0].as_ptr());
+}
+
+#[test_case]
+fn test_failed_allocation_compacts() {
+    use alloc::vec::Vec;
+
+    let mut allocator = test_heap();
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let blocks = core::iter::from_fn(|| NonNull::new(allocator._alloc(small))).collect::<Vec<_>>();
+    // A pair of buddies and a pair of neighbours which would make a misaligned block
+    for i in [0, 1, 3, 4] {
+        allocator._dealloc(blocks[i].as_ptr(), small);
+    }
+    let merged = allocator._alloc(Layout::from_size_align(16, 16).unwrap());
+    assert_eq!(merged, blocks[0].as_ptr());
+    assert_eq!(allocator.fragmentation_report().free_blocks[0], 2);
+}
+
+#[test_case]
+fn test_compact_padding_blocks() {
+    let mut allocator = test_heap();
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let first = allocator._alloc(small);
+    // Padded with blocks of 32, 16 and 8 bytes, none aligned to twice their size
+    let aligned = allocator._alloc(Layout::from_size_align(64, 64).unwrap());
+    assert_eq!(aligned, first.wrapping_add(64));
+    allocator._dealloc(first, small);
+    assert_eq!(allocator.compact(), 0);
+    let report = allocator.fragmentation_report();
+    assert_eq!(report.free_blocks[..4], [2, 1, 1, 0]);
+    assert_eq!(report.free_bytes, 64);
+
+    let mut allocator = test_heap();
+    let layout = Layout::from_size_align(32, 32).unwrap();
+    let first = allocator._alloc(layout);
+    // Padded with an aligned 32 byte block, the buddy of the first one
+    let aligned = allocator._alloc(Layout::from_size_align(64, 64).unwrap());
+    assert_eq!(aligned, first.wrapping_add(64));
+    allocator._dealloc(first, layout);
+    assert_eq!(allocator.compact(), 1);
+    assert_eq!(
+        allocator.fragmentation_report().free_blocks[..4],
+        [0, 0, 0, 1]
+    );
+}
@@ -0,0 +1,368 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+};
+
+use alloc::vec::Vec;
+use thiserror_no_std::Error;
+
+use crate::{
+    error::{self, ErrorKind},
+    rand::Rng,
+};
+
+/// The most buffers live at once. Their bookkeeping is reserved before the test starts, so that it
+/// doesn't allocate in between the buffers.
+const MAX_LIVE: usize = 64;
+/// Most buffers are at most this large, one in [`LARGE_ONE_IN`] is up to [`MAX_LARGE`] bytes.
+const MAX_SMALL: usize = 512;
+const MAX_LARGE: usize = 8192;
+const LARGE_ONE_IN: u32 = 8;
+/// One buffer in this many is page aligned, the others are aligned to at most 64 bytes.
+const PAGE_ALIGNED_ONE_IN: u32 = 32;
+/// Every live buffer is checked after this many iterations, and once more at the end.
+const VERIFY_INTERVAL: usize = 4;
+
+/// A buffer handed out by the allocator under test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub addr: usize,
+    pub size: usize,
+}
+
+impl Block {
+    fn overlaps(&self, other: &Block) -> bool {
+        self.addr < other.addr + other.size && other.addr < self.addr + self.size
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes at {:#x}", self.size, self.addr)
+    }
+}
+
+/// A sign that the allocator handed out memory which wasn't free.
+#[derive(Error, Debug)]
+pub enum MemtestError {
+    #[error("after {iterations} iterations: {new} overlaps the live block of {live}")]
+    Overlap {
+        iterations: usize,
+        new: Block,
+        live: Block,
+    },
+    #[error(
+        "after {iterations} iterations: byte {offset} of the block of {block} was overwritten"
+    )]
+    Corrupted {
+        iterations: usize,
+        block: Block,
+        offset: usize,
+    },
+}
+
+impl error::Error for MemtestError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Corrupt
+    }
+}
+
+/// What a memory test did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemtestReport {
+    pub iterations: usize,
+    pub allocations: usize,
+    /// Allocations which failed because the heap was full, which end the round of allocations.
+    pub failed: usize,
+    /// The most bytes held in live buffers at once.
+    pub peak_live_bytes: usize,
+}
+
+/// A live buffer, filled with the pattern of its address and nonce.
+#[derive(Clone, Copy)]
+struct Live {
+    ptr: *mut u8,
+    layout: Layout,
+    nonce: u64,
+}
+
+impl Live {
+    fn block(&self) -> Block {
+        Block {
+            addr: self.ptr as usize,
+            size: self.layout.size(),
+        }
+    }
+
+    /// Returns the offset of the first byte which doesn't hold the pattern.
+    fn check(&self) -> Option<usize> {
+        let bytes = unsafe { core::slice::from_raw_parts(self.ptr, self.layout.size()) };
+        (0..bytes.len()).find(|&i| bytes[i] != pattern(self.ptr as usize, self.nonce, i))
+    }
+}
+
+/// The byte at offset `i` of a buffer at `addr`. Buffers at different addresses, or at the same
+/// address at different times, hold different bytes.
+fn pattern(addr: usize, nonce: u64, i: usize) -> u8 {
+    let x = (addr as u64 ^ nonce.rotate_left(32)).wrapping_add(i as u64);
+    (x.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8
+}
+
+/// Tortures the kernel heap, see [`memtest_with`].
+pub fn memtest(iterations: usize, rng: &mut Rng) -> Result<MemtestReport, MemtestError> {
+    memtest_with(&super::ALLOCATOR, iterations, rng)
+}
+
+/// Allocates buffers of random sizes and alignments from `heap` and frees a random half of them,
+/// `iterations` times. Each buffer is filled with a pattern made from its address, every new one
+/// is compared against the live ones for overlaps, and every few iterations all live buffers are
+/// checked for bytes written over, by another buffer or the allocator's own bookkeeping. Stops at
+/// the first sign of either, after freeing what's left.
+///
+/// The same seed of `rng` gives the same sizes and alignments every time, though not the same
+/// addresses.
+pub fn memtest_with<A: GlobalAlloc>(
+    heap: &A,
+    iterations: usize,
+    rng: &mut Rng,
+) -> Result<MemtestReport, MemtestError> {
+    let mut live = Vec::with_capacity(MAX_LIVE);
+    let mut report = MemtestReport::default();
+    let result = run(heap, iterations, rng, &mut live, &mut report);
+    for buffer in live.drain(..) {
+        unsafe { heap.dealloc(buffer.ptr, buffer.layout) };
+    }
+    result.map(|()| report)
+}
+
+fn run<A: GlobalAlloc>(
+    heap: &A,
+    iterations: usize,
+    rng: &mut Rng,
+    live: &mut Vec<Live>,
+    report: &mut MemtestReport,
+) -> Result<(), MemtestError> {
+    let mut live_bytes = 0;
+    for iteration in 0..iterations {
+        let target = rng.gen_range(MAX_LIVE / 2..MAX_LIVE + 1);
+        while live.len() < target {
+            let size = match rng.gen_range(0..LARGE_ONE_IN) {
+                0 => rng.gen_range(MAX_SMALL..MAX_LARGE + 1),
+                _ => rng.gen_range(1..MAX_SMALL + 1),
+            };
+            let align = match rng.gen_range(0..PAGE_ALIGNED_ONE_IN) {
+                0 => 4096,
+                _ => 1 << rng.gen_range(0..7u32),
+            };
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = unsafe { heap.alloc(layout) };
+            if ptr.is_null() {
+                report.failed += 1;
+                break;
+            }
+            let buffer = Live {
+                ptr,
+                layout,
+                nonce: rng.next_u64(),
+            };
+            report.allocations += 1;
+            // Pushed before the check, so that it's freed either way
+            live.push(buffer);
+            if let Some(other) = live[..live.len() - 1]
+                .iter()
+                .find(|other| other.block().overlaps(&buffer.block()))
+            {
+                return Err(MemtestError::Overlap {
+                    iterations: iteration,
+                    new: buffer.block(),
+                    live: other.block(),
+                });
+            }
+            for i in 0..size {
+                unsafe { ptr.add(i).write(pattern(ptr as usize, buffer.nonce, i)) };
+            }
+            live_bytes += size;
+            report.peak_live_bytes = report.peak_live_bytes.max(live_bytes);
+        }
+
+        if iteration % VERIFY_INTERVAL == VERIFY_INTERVAL - 1 {
+            verify(live, iteration)?;
+        }
+        let mut i = 0;
+        while i < live.len() {
+            if rng.gen_range(0..2u8) == 0 {
+                let buffer = live.swap_remove(i);
+                live_bytes -= buffer.layout.size();
+                unsafe { heap.dealloc(buffer.ptr, buffer.layout) };
+            } else {
+                i += 1;
+            }
+        }
+        report.iterations = iteration + 1;
+    }
+    verify(live, iterations)
+}
+
+/// Checks that every live buffer still holds its pattern.
+fn verify(live: &[Live], iterations: usize) -> Result<(), MemtestError> {
+    for buffer in live {
+        if let Some(offset) = buffer.check() {
+            return Err(MemtestError::Corrupted {
+                iterations,
+                block: buffer.block(),
+                offset,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Hands out blocks from the kernel heap, except that now and then a block lies inside the one
+/// handed out before it, for checking that [`memtest_with`] notices. Only blocks which fit in
+/// the one before them overlap it, so nothing outside the blocks is written over.
+#[cfg(feature = "memtest_faults")]
+pub struct OverlappingAllocator {
+    /// A block overlaps the last one once this many have been handed out since the last overlap.
+    every: usize,
+    state: spin::Mutex<OverlapState>,
+}
+
+#[cfg(feature = "memtest_faults")]
+#[derive(Default)]
+struct OverlapState {
+    since_overlap: usize,
+    /// The last block taken from the heap.
+    last: Option<Block>,
+    /// The overlapping block handed out, which isn't given back to the heap. The test stops at
+    /// the first one, so there's never more than one.
+    overlapping: Option<usize>,
+}
+
+#[cfg(feature = "memtest_faults")]
+impl OverlappingAllocator {
+    pub fn new(every: usize) -> Self {
+        Self {
+            every,
+            state: spin::Mutex::new(OverlapState::default()),
+        }
+    }
+}
+
+#[cfg(feature = "memtest_faults")]
+unsafe impl GlobalAlloc for OverlappingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut state = self.state.lock();
+        state.since_overlap += 1;
+        if let Some(last) = state.last.filter(|_| state.since_overlap >= self.every) {
+            let addr = super::align_up(last.addr + 1, layout.align());
+            if addr + layout.size() <= last.addr + last.size {
+                state.since_overlap = 0;
+                state.overlapping = Some(addr);
+                return addr as *mut u8;
+            }
+        }
+        let ptr = super::ALLOCATOR.alloc(layout);
+        if !ptr.is_null() {
+            state.last = Some(Block {
+                addr: ptr as usize,
+                size: layout.size(),
+            });
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut state = self.state.lock();
+        if state.overlapping == Some(ptr as usize) {
+            state.overlapping = None;
+            return;
+        }
+        if state.last.is_some_and(|last| last.addr == ptr as usize) {
+            state.last = None;
+        }
+        super::ALLOCATOR.dealloc(ptr, layout);
+    }
+}
+
+#[test_case]
+fn test_memtest() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the bytes of the blocks it hands out, which the test's own bookkeeping isn't
+    /// allocated with.
+    struct Counting {
+        live: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = super::ALLOCATOR.alloc(layout);
+            if !ptr.is_null() {
+                let live = self.live.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+                self.peak.fetch_max(live, Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.live.fetch_sub(layout.size(), Ordering::Relaxed);
+            super::ALLOCATOR.dealloc(ptr, layout);
+        }
+    }
+
+    let used = super::stats().used;
+    let heap = Counting {
+        live: AtomicUsize::new(0),
+        peak: AtomicUsize::new(0),
+    };
+    let report = memtest_with(&heap, 40, &mut Rng::new(3)).unwrap();
+    assert_eq!(report.iterations, 40);
+    assert_eq!(report.failed, 0);
+    assert!(report.allocations >= 40 * MAX_LIVE / 4);
+    // The peak is of the bytes handed out, which with at least half of the most buffers live at
+    // once is more than a single large one
+    assert_eq!(report.peak_live_bytes, heap.peak.load(Ordering::Relaxed));
+    assert!(report.peak_live_bytes > MAX_LARGE);
+    // Everything is freed again
+    assert_eq!(heap.live.load(Ordering::Relaxed), 0);
+    assert_eq!(super::stats().used, used);
+    assert_eq!(memtest(5, &mut Rng::new(4)).unwrap().iterations, 5);
+    assert_eq!(super::stats().used, used);
+
+    // Bytes written over behind the allocator's back are found
+    let mut live = Vec::new();
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    let buffer = Live {
+        ptr,
+        layout,
+        nonce: 1,
+    };
+    for i in 0..16 {
+        unsafe { ptr.add(i).write(pattern(ptr as usize, 1, i)) };
+    }
+    live.push(buffer);
+    assert!(verify(&live, 0).is_ok());
+    unsafe { ptr.add(5).write(!pattern(ptr as usize, 1, 5)) };
+    assert!(matches!(
+        verify(&live, 0),
+        Err(MemtestError::Corrupted { offset: 5, .. })
+    ));
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+}
+
+#[cfg(feature = "memtest_faults")]
+#[test_case]
+fn test_memtest_finds_overlaps() {
+    let used = super::stats().used;
+    let heap = OverlappingAllocator::new(50);
+    match memtest_with(&heap, 100, &mut Rng::new(3)) {
+        Err(MemtestError::Overlap { new, live, .. }) => {
+            assert!(new.overlaps(&live) && new.addr > live.addr);
+        }
+        other => panic!("expected an overlap, got {:?}", other),
+    }
+    assert!(heap.state.lock().overlapping.is_none());
+    assert_eq!(super::stats().used, used);
+}
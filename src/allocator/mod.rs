@@ -1,22 +1,199 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+
 use spin::{Mutex, MutexGuard};
+use thiserror_no_std::Error;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageSize,
+        PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-use self::fixed::FixedSizeAllocator;
+use crate::{
+    log,
+    log::LogLevel,
+    memory::{self, BootInfoFrameAllocator},
+};
+
+use self::{
+    buddy::BuddyAllocator,
+    bump::BumpAllocator,
+    fixed::{FixedSizeAllocator, FragmentationReport},
+    scrub::ScrubStats,
+};
 
 pub mod buddy;
 pub mod bump;
+#[cfg(feature = "alloc_debug")]
+mod debug;
 pub mod fixed;
+pub mod memtest;
+pub mod oom;
+pub mod scrub;
+pub mod slab;
+
+pub use self::oom::register_reclaim;
 
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeAllocator> = Locked::new(FixedSizeAllocator::new());
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+/// The virtual address of the heap, which can be set at build time with the `HANNOS_HEAP_START`
+/// environment variable.
+pub const HEAP_START: usize = match option_env!("HANNOS_HEAP_START") {
+    Some(start) => parse_build_option(start),
+    None => 0x_4444_4444_0000,
+};
+/// The size of the heap unless the command line sets it with `heap_size=<MiB>`, which can be set at
+/// build time with the `HANNOS_HEAP_SIZE` environment variable.
+pub const HEAP_SIZE: usize = match option_env!("HANNOS_HEAP_SIZE") {
+    Some(size) => parse_build_option(size),
+    None => 512 * 4096,
+};
+
+const _: () = assert!(
+    HEAP_START.is_multiple_of(PAGE_SIZE) && HEAP_SIZE.is_multiple_of(PAGE_SIZE),
+    "the heap must start and end at a page boundary"
+);
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+/// Frames left free when the heap size is clamped to the memory there is, for the page tables
+/// mapping the heap and for the mappings made after it.
+const RESERVED_FRAMES: usize = 256;
+/// The smallest heap which is mapped, leaving room for the canaries and the allocators.
+const MIN_HEAP_SIZE: usize = PAGE_SIZE;
+const _: () = assert!(MIN_HEAP_SIZE > 2 * CANARY_SIZE);
+/// Virtual addresses from here on aren't canonical in the lower half of the address space.
+const LOWER_HALF_END: usize = 0x_8000_0000_0000;
+
+/// The size of the heap as mapped by [`init_heap`].
+static HEAP_SIZE_MAPPED: AtomicUsize = AtomicUsize::new(HEAP_SIZE);
+
+/// The word canaries are filled with. Finding anything else in one means something wrote past the
+/// memory it owns.
+const CANARY: u64 = 0x_dead_c0de_dead_c0de;
+/// Bytes taken up by each canary. The heap canaries leave the rest of the heap 16-byte aligned.
+pub const CANARY_SIZE: usize = 16;
+/// Timer ticks between the canary checks made from the timer, see [`check_canaries`].
+pub const CANARY_CHECK_TICKS: u64 = 16;
+
+static FAIL_ALLOCATIONS: AtomicBool = AtomicBool::new(false);
+static HEAP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// The allocation strategies the kernel heap can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AllocatorKind {
+    Bump = 0,
+    Fixed = 1,
+    Buddy = 2,
+}
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 4096;
+impl AllocatorKind {
+    /// The name selecting the allocator on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bump => "bump",
+            Self::Fixed => "fixed",
+            Self::Buddy => "buddy",
+        }
+    }
+}
+
+/// The global allocator, forwarding to the allocator selected before the heap was initialized.
+struct KernelAllocator {
+    kind: AtomicU8,
+    // Bytes currently allocated, regardless of the allocator's own overhead
+    used: AtomicUsize,
+    // The most bytes allocated at once since boot or the last `reset_peak`
+    peak: AtomicUsize,
+    // Successful allocations since boot
+    allocations: AtomicUsize,
+    bump: Locked<BumpAllocator>,
+    fixed: Locked<FixedSizeAllocator>,
+    buddy: Locked<BuddyAllocator>,
+}
+
+impl KernelAllocator {
+    const fn new() -> Self {
+        Self {
+            kind: AtomicU8::new(AllocatorKind::Fixed as u8),
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            bump: Locked::new(BumpAllocator::new()),
+            fixed: Locked::new(FixedSizeAllocator::new()),
+            buddy: Locked::new(BuddyAllocator::new()),
+        }
+    }
+
+    fn kind(&self) -> AllocatorKind {
+        match self.kind.load(Ordering::Relaxed) {
+            0 => AllocatorKind::Bump,
+            1 => AllocatorKind::Fixed,
+            _ => AllocatorKind::Buddy,
+        }
+    }
+
+    unsafe fn alloc_selected(&self, layout: Layout) -> *mut u8 {
+        match self.kind() {
+            AllocatorKind::Bump => self.bump.alloc(layout),
+            AllocatorKind::Fixed => self.fixed.alloc(layout),
+            AllocatorKind::Buddy => self.buddy.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc_selected(&self, ptr: *mut u8, layout: Layout) {
+        match self.kind() {
+            AllocatorKind::Bump => self.bump.dealloc(ptr, layout),
+            AllocatorKind::Fixed => self.fixed.dealloc(ptr, layout),
+            AllocatorKind::Buddy => self.buddy.dealloc(ptr, layout),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if allocations_disabled() {
+            return null_mut();
+        }
+        if oom::reporting() {
+            return oom::EMERGENCY_POOL.alloc(layout);
+        }
+        let mut ptr = self.alloc_selected(layout);
+        // Only retried once, if that fails too the allocation error handler reports it
+        if ptr.is_null() && oom::reclaim() > 0 {
+            ptr = self.alloc_selected(layout);
+            if !ptr.is_null() {
+                oom::recovered();
+            }
+        }
+        if !ptr.is_null() {
+            let used = self.used.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            self.peak.fetch_max(used, Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if oom::EMERGENCY_POOL.contains(ptr) {
+            return;
+        }
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+        // Freed blocks wait in the quarantine until they have been scrubbed, the bump allocator
+        // never reuses them anyway
+        if self.kind() != AllocatorKind::Bump && scrub::quarantine(ptr, layout) {
+            return;
+        }
+        self.dealloc_selected(ptr, layout);
+    }
+}
 
 pub struct Locked<T> {
     inner: Mutex<T>,
@@ -32,24 +209,316 @@ impl<T> Locked<T> {
     pub fn lock(&self) -> MutexGuard<T> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.inner.try_lock()
+    }
+}
+
+/// Makes every following allocation fail, to test code paths that must work without a heap.
+#[cfg(feature = "alloc_fail_hook")]
+pub fn fail_allocations(fail: bool) {
+    FAIL_ALLOCATIONS.store(fail, Ordering::SeqCst);
+}
+
+/// Returns `true` if the global allocator should fail allocations without touching the heap.
+fn allocations_disabled() -> bool {
+    crate::panic::in_panic() || FAIL_ALLOCATIONS.load(Ordering::SeqCst)
+}
+
+/// Parses a number given at build time, in decimal or in hex with a `0x` prefix. Underscores are
+/// allowed between digits.
+const fn parse_build_option(value: &str) -> usize {
+    let bytes = value.as_bytes();
+    let (radix, mut i) = match bytes {
+        [b'0', b'x', ..] => (16, 2),
+        _ => (10, 0),
+    };
+    let mut n = 0;
+    while i < bytes.len() {
+        let digit = match bytes[i] {
+            b'_' => {
+                i += 1;
+                continue;
+            }
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'a'..=b'f' if radix == 16 => c - b'a' + 10,
+            c @ b'A'..=b'F' if radix == 16 => c - b'A' + 10,
+            _ => panic!("build option is not a number"),
+        };
+        n = n * radix + digit as usize;
+        i += 1;
+    }
+    n
+}
+
+/// Where the heap is and how large it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapInfo {
+    pub start: usize,
+    /// Size of the heap in bytes, including the canaries at its ends.
+    pub size: usize,
+}
+
+/// Returns the placement of the heap. Before [`init_heap`] the size is the one asked for at build
+/// time, afterwards it's the size which was mapped.
+pub fn heap_info() -> HeapInfo {
+    HeapInfo {
+        start: HEAP_START,
+        size: HEAP_SIZE_MAPPED.load(Ordering::Relaxed),
+    }
+}
+
+/// Memory usage of the kernel heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes currently allocated.
+    pub used: usize,
+    /// The most bytes allocated at once since boot, or since the last [`reset_peak`].
+    pub peak: usize,
+    /// Size of the heap in bytes.
+    pub total: usize,
+    /// The number of allocations made since boot.
+    pub allocations: usize,
+    /// The free blocks of the fixed size allocator, `None` if another allocator is used.
+    pub fragmentation: Option<FragmentationReport>,
+    /// What the scrubbing of freed blocks did.
+    pub scrub: ScrubStats,
+}
+
+pub fn stats() -> HeapStats {
+    HeapStats {
+        used: ALLOCATOR.used.load(Ordering::Relaxed),
+        peak: ALLOCATOR.peak.load(Ordering::Relaxed),
+        total: heap_info().size,
+        allocations: ALLOCATOR.allocations.load(Ordering::Relaxed),
+        fragmentation: match selected() {
+            AllocatorKind::Fixed => Some(ALLOCATOR.fixed.lock().fragmentation_report()),
+            _ => None,
+        },
+        scrub: scrub::stats(),
+    }
+}
+
+/// Starts tracking the peak heap usage again from the current usage, to measure how much memory
+/// something allocates.
+pub fn reset_peak() {
+    let used = ALLOCATOR.used.load(Ordering::Relaxed);
+    ALLOCATOR.peak.store(used, Ordering::Relaxed);
+}
+
+/// Merges free blocks of the fixed size allocator so larger allocations can be made from them.
+/// Returns the number of merges, or `None` if another allocator is used.
+pub fn compact() -> Option<usize> {
+    match selected() {
+        AllocatorKind::Fixed => Some(ALLOCATOR.fixed.lock().compact()),
+        _ => None,
+    }
+}
+
+/// Selects the allocator used for the heap. Must be called before [`init_heap`].
+pub fn select(kind: AllocatorKind) {
+    assert!(
+        !HEAP_INITIALIZED.load(Ordering::SeqCst),
+        "allocator selected after heap initialization"
+    );
+    ALLOCATOR.kind.store(kind as u8, Ordering::Relaxed);
+}
+
+/// Returns the allocator used for the heap.
+pub fn selected() -> AllocatorKind {
+    ALLOCATOR.kind()
+}
+
+/// The places canaries are kept, where memory corruption would go unnoticed otherwise. The heap
+/// canaries are outside of what the allocators are given, so no allocation overlaps them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Canary {
+    HeapStart,
+    HeapEnd,
+    StackBase,
+}
+
+impl Canary {
+    const ALL: [Self; 3] = [Self::HeapStart, Self::HeapEnd, Self::StackBase];
+
+    /// The lowest address of the canary.
+    pub fn addr(self) -> usize {
+        match self {
+            Self::HeapStart => HEAP_START,
+            Self::HeapEnd => HEAP_START + heap_info().size - CANARY_SIZE,
+            Self::StackBase => crate::stack::STACK_BOTTOM as usize,
+        }
+    }
+
+    fn placed(self) -> bool {
+        match self {
+            Self::HeapStart | Self::HeapEnd => HEAP_INITIALIZED.load(Ordering::SeqCst),
+            Self::StackBase => crate::stack::poisoned(),
+        }
+    }
+}
+
+impl fmt::Display for Canary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::HeapStart => "heap start",
+            Self::HeapEnd => "heap end",
+            Self::StackBase => "kernel stack base",
+        })
+    }
+}
+
+/// A canary which was written over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadCanary {
+    pub canary: Canary,
+    /// The address of the first word which changed.
+    pub addr: usize,
+    pub found: u64,
+}
+
+impl fmt::Display for DeadCanary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} canary at {:#x} overwritten with {:#018x}",
+            self.canary, self.addr, self.found
+        )
+    }
+}
+
+/// Fills the canary at `addr`.
+///
+/// # Safety
+///
+/// `addr` must point to [`CANARY_SIZE`] writable bytes which nothing else uses.
+pub(crate) unsafe fn place_canary(addr: usize) {
+    for word in (addr..addr + CANARY_SIZE).step_by(8) {
+        (word as *mut u64).write_volatile(CANARY);
+    }
+}
+
+/// Checks the canaries which have been placed, returning the first one which was written over.
+/// Debug builds and builds with the `canary_checks` feature do this from the timer interrupt every
+/// [`CANARY_CHECK_TICKS`] ticks.
+pub fn check_canaries() -> Result<(), DeadCanary> {
+    for canary in Canary::ALL.into_iter().filter(|canary| canary.placed()) {
+        for addr in (canary.addr()..canary.addr() + CANARY_SIZE).step_by(8) {
+            let found = unsafe { (addr as *const u64).read_volatile() };
+            if found != CANARY {
+                return Err(DeadCanary {
+                    canary,
+                    addr,
+                    found,
+                });
+            }
+        }
+    }
+    Ok(())
 }
 
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
+/// Why the heap couldn't be set up.
+#[derive(Error, Debug)]
+pub enum HeapError {
+    #[error("heap at {start:#x}..{end:#x} overlaps {what}")]
+    Overlap {
+        start: usize,
+        end: usize,
+        what: &'static str,
+    },
+    #[error("heap page {0:#x} is already mapped, by the kernel image or the bootloader")]
+    AlreadyMapped(u64),
+    #[error("only {0} bytes of memory are free for the heap, it needs {MIN_HEAP_SIZE}")]
+    TooSmall(usize),
+    #[error("mapping the heap failed: {0:?}")]
+    Map(#[from] MapToError<Size4KiB>),
+}
+
+/// Returns the size of the heap to map: `requested` bytes, or [`HEAP_SIZE`] if nothing was asked
+/// for, leaving [`RESERVED_FRAMES`] of the `free_frames` for everything else. Fails if that
+/// leaves less than [`MIN_HEAP_SIZE`].
+fn clamp_heap_size(requested: Option<usize>, free_frames: usize) -> Result<usize, HeapError> {
+    let size = requested.unwrap_or(HEAP_SIZE);
+    let available = free_frames.saturating_sub(RESERVED_FRAMES) * PAGE_SIZE;
+    match align_up(size.min(available), PAGE_SIZE) {
+        size if size < MIN_HEAP_SIZE => Err(HeapError::TooSmall(size)),
+        size => Ok(size),
+    }
+}
+
+/// Checks that the heap at `start` doesn't overlap the virtual addresses used for other things:
+/// the mapping of all physical memory at `phys_offset`, which ends `phys_end` bytes later, and the
+/// window device memory is mapped in.
+fn check_placement(
+    start: usize,
+    size: usize,
+    phys_offset: usize,
+    phys_end: usize,
+) -> Result<(), HeapError> {
+    let end = start + size;
+    let reserved = [
+        (
+            phys_offset,
+            phys_offset + phys_end,
+            "the physical memory mapping",
+        ),
+        (
+            memory::MMIO_START as usize,
+            (memory::MMIO_START + memory::MMIO_SIZE) as usize,
+            "the device memory window",
+        ),
+        (LOWER_HALF_END, usize::MAX, "non-canonical addresses"),
+    ];
+    match reserved
+        .into_iter()
+        .find(|&(from, to, _)| start < to && from < end)
+    {
+        Some((_, _, what)) => Err(HeapError::Overlap { start, end, what }),
+        None => Ok(()),
+    }
+}
+
+/// Maps the heap and hands it to the selected allocator. The size is [`HEAP_SIZE`] unless the
+/// command line asks for another one, and is clamped to the free memory. Fails without mapping
+/// anything if the heap would overlap memory which is already in use.
 pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), HeapError> {
+    let requested = crate::cmdline::args().heap_size;
+    let size = clamp_heap_size(requested, frame_allocator.free_frames())?;
+    if size < requested.unwrap_or(HEAP_SIZE) {
+        log!(
+            LogLevel::Warn,
+            "heap: only {} KiB of memory is free, shrinking the heap to fit",
+            size / 1024
+        );
+    }
+    check_placement(
+        HEAP_START,
+        size,
+        mapper.phys_offset().as_u64() as usize,
+        frame_allocator.phys_end() as usize,
+    )?;
+
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    for page in page_range {
+        if mapper.translate_page(page).is_ok() {
+            return Err(HeapError::AlreadyMapped(page.start_address().as_u64()));
+        }
+    }
     for page in page_range {
         let frame = frame_allocator
             .allocate_frame()
@@ -57,10 +526,73 @@ pub fn init_heap(
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
+    HEAP_SIZE_MAPPED.store(size, Ordering::Relaxed);
 
+    // The emergency pool comes right after the start canary, and the allocators are given the
+    // rest of the heap up to the end canary. Tiny heaps only give up an eighth of it for the pool
+    let size = size - 2 * CANARY_SIZE;
+    let pool_size = oom::EMERGENCY_POOL_SIZE.min((size / 8) & !(CANARY_SIZE - 1));
+    let (start, size) = (HEAP_START + CANARY_SIZE + pool_size, size - pool_size);
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        place_canary(Canary::HeapStart.addr());
+        place_canary(Canary::HeapEnd.addr());
+        oom::EMERGENCY_POOL.init(HEAP_START + CANARY_SIZE, pool_size);
+        match selected() {
+            AllocatorKind::Bump => ALLOCATOR.bump.lock().init(start, size),
+            AllocatorKind::Fixed => ALLOCATOR.fixed.lock().init(start, size),
+            AllocatorKind::Buddy => ALLOCATOR.buddy.lock().init(start, size),
+        }
     }
+    HEAP_INITIALIZED.store(true, Ordering::SeqCst);
+    crate::mark_initialized(crate::InitFlags::HEAP);
 
     Ok(())
 }
+
+#[test_case]
+fn test_heap_placement() {
+    const MIB: usize = 1024 * 1024;
+
+    assert_eq!(parse_build_option("0x_4444_0000"), 0x4444_0000);
+    assert_eq!(parse_build_option("2097152"), 2 * MIB);
+
+    // The size asked for is rounded up to whole pages, and leaves frames for everything else
+    let frames = 1000;
+    assert_eq!(
+        clamp_heap_size(None, frames).unwrap(),
+        HEAP_SIZE.min(744 * PAGE_SIZE)
+    );
+    assert_eq!(clamp_heap_size(Some(100), frames).unwrap(), PAGE_SIZE);
+    assert_eq!(
+        clamp_heap_size(Some(64 * MIB), frames).unwrap(),
+        744 * PAGE_SIZE
+    );
+    // Too little memory left, or none asked for, can't even hold the canaries
+    assert!(matches!(
+        clamp_heap_size(Some(MIB), 10),
+        Err(HeapError::TooSmall(0))
+    ));
+    assert!(matches!(
+        clamp_heap_size(Some(0), frames),
+        Err(HeapError::TooSmall(0))
+    ));
+
+    let phys_offset = 0x_1000_0000_0000;
+    assert!(check_placement(HEAP_START, 64 * MIB, phys_offset, 128 * MIB).is_ok());
+    let overlaps = |start, size| match check_placement(start, size, phys_offset, 128 * MIB) {
+        Err(HeapError::Overlap { what, .. }) => what,
+        other => panic!("expected an overlap, got {:?}", other),
+    };
+    assert_eq!(
+        overlaps(phys_offset + 127 * MIB, 4 * MIB),
+        "the physical memory mapping"
+    );
+    assert_eq!(
+        overlaps(memory::MMIO_START as usize - PAGE_SIZE, 2 * PAGE_SIZE),
+        "the device memory window"
+    );
+    assert_eq!(
+        overlaps(LOWER_HALF_END - MIB, 2 * MIB),
+        "non-canonical addresses"
+    );
+}
@@ -0,0 +1,229 @@
+use core::{
+    alloc::Layout,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use super::align_up;
+
+/// Bytes set aside at the start of the heap by [`init_heap`](super::init_heap) for the out of memory
+/// report, see [`EmergencyPool`]. Heaps smaller than eight times this set aside an eighth.
+pub const EMERGENCY_POOL_SIZE: usize = 4096;
+/// The most reclaim callbacks which can be registered.
+const MAX_RECLAIMERS: usize = 8;
+
+/// A callback freeing memory, see [`register_reclaim`].
+pub type Reclaim = fn() -> usize;
+
+static RECLAIMERS: Mutex<[Option<Reclaim>; MAX_RECLAIMERS]> = Mutex::new([None; MAX_RECLAIMERS]);
+/// Set while the reclaim callbacks run, so that an allocation failing in one of them doesn't run
+/// them all over again.
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+/// Set once the heap ran out for good and the report is being made, see [`report`].
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+static RECLAIMS: AtomicUsize = AtomicUsize::new(0);
+static RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+static RECOVERED: AtomicUsize = AtomicUsize::new(0);
+
+pub(super) static EMERGENCY_POOL: EmergencyPool = EmergencyPool::new();
+
+/// How often the heap ran out and what was done about it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OomStats {
+    /// Times the reclaim callbacks were run because an allocation failed.
+    pub reclaims: usize,
+    /// Bytes the reclaim callbacks said they freed.
+    pub reclaimed: usize,
+    /// Allocations which succeeded when retried after reclaiming memory.
+    pub recovered: usize,
+    /// Bytes of the emergency pool handed out, which stays zero until the heap runs out for good.
+    pub emergency_used: usize,
+}
+
+pub fn stats() -> OomStats {
+    OomStats {
+        reclaims: RECLAIMS.load(Ordering::Relaxed),
+        reclaimed: RECLAIMED.load(Ordering::Relaxed),
+        recovered: RECOVERED.load(Ordering::Relaxed),
+        emergency_used: EMERGENCY_POOL.used(),
+    }
+}
+
+/// Registers a callback freeing memory which can be done without, like cached disk blocks,
+/// returning the number of bytes it freed. The callbacks are run when an allocation fails, which
+/// is then tried once more.
+///
+/// The callbacks run in the middle of the failed allocation, with whatever locks its caller holds,
+/// so they must only try their locks and free nothing if one is taken. Allocations they make
+/// themselves don't reclaim memory again. Registering a callback twice has no effect.
+pub fn register_reclaim(reclaim: Reclaim) {
+    let mut reclaimers = RECLAIMERS.lock();
+    if reclaimers
+        .iter()
+        .flatten()
+        .any(|&registered| core::ptr::fn_addr_eq(registered, reclaim))
+    {
+        return;
+    }
+    let slot = reclaimers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many reclaim callbacks");
+    *slot = Some(reclaim);
+}
+
+/// Runs the reclaim callbacks, returning the number of bytes they freed.
+pub(super) fn reclaim() -> usize {
+    if RECLAIMING.swap(true, Ordering::SeqCst) {
+        return 0;
+    }
+    // The callbacks are copied out, as one of them may allocate and fail again
+    let reclaimers = RECLAIMERS.try_lock().map(|reclaimers| *reclaimers);
+    let freed = reclaimers
+        .iter()
+        .flatten()
+        .flatten()
+        .map(|reclaim| reclaim())
+        .sum();
+    RECLAIMS.fetch_add(1, Ordering::Relaxed);
+    RECLAIMED.fetch_add(freed, Ordering::Relaxed);
+    RECLAIMING.store(false, Ordering::SeqCst);
+    freed
+}
+
+/// Counts an allocation which succeeded once memory was reclaimed.
+pub(super) fn recovered() {
+    RECOVERED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns `true` once the out of memory report is being made, after which allocations are served
+/// from the emergency pool.
+pub(super) fn reporting() -> bool {
+    REPORTING.load(Ordering::SeqCst)
+}
+
+/// Memory set aside for reporting that the heap ran out, so that making the report can't fail for
+/// the lack of it. The report itself is formatted without allocating, the pool is there for
+/// anything it calls which does. Memory is handed out from the start of the pool and never reused.
+pub(super) struct EmergencyPool {
+    start: AtomicUsize,
+    end: AtomicUsize,
+    next: AtomicUsize,
+}
+
+impl EmergencyPool {
+    const fn new() -> Self {
+        Self {
+            start: AtomicUsize::new(0),
+            end: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `start` must point to `size` writable bytes which nothing else uses.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        self.start.store(start, Ordering::SeqCst);
+        self.end.store(start + size, Ordering::SeqCst);
+        self.next.store(start, Ordering::SeqCst);
+    }
+
+    pub fn alloc(&self, layout: Layout) -> *mut u8 {
+        let end = self.end.load(Ordering::SeqCst);
+        let mut next = self.next.load(Ordering::SeqCst);
+        loop {
+            let start = align_up(next, layout.align());
+            if next == 0 || start + layout.size() > end {
+                return null_mut();
+            }
+            match self.next.compare_exchange(
+                next,
+                start + layout.size(),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return start as *mut u8,
+                Err(current) => next = current,
+            }
+        }
+    }
+
+    /// Returns `true` if `ptr` was handed out by the pool. Such memory is never freed.
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let (start, end) = (
+            self.start.load(Ordering::SeqCst),
+            self.end.load(Ordering::SeqCst),
+        );
+        (start..end).contains(&(ptr as usize))
+    }
+
+    pub fn used(&self) -> usize {
+        self.next.load(Ordering::SeqCst) - self.start.load(Ordering::SeqCst)
+    }
+}
+
+/// Reports that allocating `layout` failed even after reclaiming memory, with the state of the
+/// heap, through the panic output which doesn't allocate. Allocations made from here on are
+/// served from the emergency pool.
+pub fn report(layout: Layout) {
+    REPORTING.store(true, Ordering::SeqCst);
+    let heap = super::stats();
+    let oom = stats();
+    crate::panic::emit(format_args!(
+        "out of memory: allocating {} bytes aligned to {} failed\n\
+         heap: {} of {} bytes used, peak {}, {} allocations since boot\n\
+         reclaimed {} bytes in {} passes, {} allocations recovered\n",
+        layout.size(),
+        layout.align(),
+        heap.used,
+        heap.total,
+        heap.peak,
+        heap.allocations,
+        oom.reclaimed,
+        oom.reclaims,
+        oom.recovered
+    ));
+    if let Some(report) = heap.fragmentation {
+        crate::panic::emit(format_args!(
+            "{} bytes in free blocks, {} untouched\n",
+            report.free_bytes, report.untouched_bytes
+        ));
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    // Allocations fail on purpose while panicking, there's nothing left to report then
+    if !crate::panic::in_panic() {
+        report(layout);
+    }
+    panic!(
+        "memory allocation of {} bytes (align {}) failed",
+        layout.size(),
+        layout.align()
+    )
+}
+
+#[test_case]
+fn test_emergency_pool() {
+    let pool = EmergencyPool::new();
+    let layout = Layout::from_size_align(100, 16).unwrap();
+    assert!(pool.alloc(layout).is_null());
+
+    let mut memory = [0u8; EMERGENCY_POOL_SIZE + 16];
+    let start = align_up(memory.as_mut_ptr() as usize, 16);
+    unsafe { pool.init(start, EMERGENCY_POOL_SIZE) };
+    let a = pool.alloc(Layout::from_size_align(3, 1).unwrap());
+    let b = pool.alloc(layout);
+    assert_eq!(a as usize, start);
+    assert_eq!(b as usize, start + 16);
+    assert!(pool.contains(b) && !pool.contains((start + EMERGENCY_POOL_SIZE) as *mut u8));
+    assert_eq!(pool.used(), 116);
+    assert!(pool
+        .alloc(Layout::from_size_align(EMERGENCY_POOL_SIZE, 1).unwrap())
+        .is_null());
+}
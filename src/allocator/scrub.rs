@@ -0,0 +1,228 @@
+use core::{
+    alloc::Layout,
+    future::poll_fn,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::Poll,
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+
+use crate::task::yield_now;
+
+/// The byte scrubbed blocks are filled with. A pointer read from freed memory holds
+/// `0x5c5c5c5c5c5c5c5c`, which isn't canonical, so following it faults.
+pub const POISON: u8 = 0x5c;
+/// The most freed blocks waiting to be scrubbed. Blocks freed while the quarantine is full go back
+/// to the allocator right away, unscrubbed.
+pub const QUARANTINE_SIZE: usize = 128;
+/// Bytes scrubbed before the scrubber yields to other tasks.
+const CHUNK_SIZE: usize = 256;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUARANTINE: OnceCell<ArrayQueue<Quarantined>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// Blocks in the quarantine or being scrubbed
+static QUARANTINED: AtomicUsize = AtomicUsize::new(0);
+static SCRUBBED_BLOCKS: AtomicUsize = AtomicUsize::new(0);
+static SCRUBBED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static BYPASSED: AtomicUsize = AtomicUsize::new(0);
+
+/// A freed block which isn't given back to the allocator until it has been scrubbed.
+#[derive(Debug, Clone, Copy)]
+struct Quarantined {
+    addr: usize,
+    layout: Layout,
+}
+
+impl Quarantined {
+    /// Gives the block back to the allocator it was freed to.
+    fn release(self) {
+        QUARANTINED.fetch_sub(1, Ordering::Relaxed);
+        unsafe { super::ALLOCATOR.dealloc_selected(self.addr as *mut u8, self.layout) };
+    }
+}
+
+/// What the scrubber did since boot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubStats {
+    pub enabled: bool,
+    pub scrubbed_blocks: usize,
+    pub scrubbed_bytes: usize,
+    /// Freed blocks waiting to be scrubbed, or being scrubbed.
+    pub quarantined: usize,
+    /// Blocks freed right away because the quarantine was full.
+    pub bypassed: usize,
+}
+
+pub fn stats() -> ScrubStats {
+    ScrubStats {
+        enabled: is_enabled(),
+        scrubbed_blocks: SCRUBBED_BLOCKS.load(Ordering::Relaxed),
+        scrubbed_bytes: SCRUBBED_BYTES.load(Ordering::Relaxed),
+        quarantined: QUARANTINED.load(Ordering::Relaxed),
+        bypassed: BYPASSED.load(Ordering::Relaxed),
+    }
+}
+
+/// Starts quarantining the blocks freed to the fixed size and buddy allocators, until the
+/// [`run`] task has filled them with [`POISON`]. A use after free then reads the poison, or
+/// corrupts a block which is about to be scrubbed, instead of going unnoticed until the block is
+/// handed out again.
+pub fn enable() {
+    let _ = QUARANTINE.try_init_once(|| ArrayQueue::new(QUARANTINE_SIZE));
+    super::register_reclaim(reclaim);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops quarantining freed blocks. Those already in the quarantine are still scrubbed.
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Puts a block being freed in the quarantine, returning `false` if it should be freed right away
+/// instead. Never allocates or blocks, since it's called from the allocator.
+pub(super) fn quarantine(ptr: *mut u8, layout: Layout) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let Ok(quarantine) = QUARANTINE.try_get() else {
+        return false;
+    };
+    let block = Quarantined {
+        addr: ptr as usize,
+        layout,
+    };
+    // Counted first, so a scrubber popping it right away doesn't take the count below zero
+    QUARANTINED.fetch_add(1, Ordering::Relaxed);
+    if quarantine.push(block).is_err() {
+        QUARANTINED.fetch_sub(1, Ordering::Relaxed);
+        BYPASSED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    WAKER.wake();
+    true
+}
+
+/// Gives every block still waiting in the quarantine back to the allocator without scrubbing it,
+/// returning the bytes released. Registered as a reclaim callback, since a quarantine full of
+/// large blocks can keep an allocation from succeeding.
+fn reclaim() -> usize {
+    let Ok(quarantine) = QUARANTINE.try_get() else {
+        return 0;
+    };
+    core::iter::from_fn(|| quarantine.pop())
+        .map(|block| {
+            block.release();
+            block.layout.size()
+        })
+        .sum()
+}
+
+/// Scrubs the quarantined blocks and gives them back to the allocator, yielding after every few
+/// bytes so the scrubbing only takes up time no other task wants. Should run at
+/// [`Priority::Low`](crate::task::Priority::Low).
+pub async fn run() {
+    let _ = QUARANTINE.try_init_once(|| ArrayQueue::new(QUARANTINE_SIZE));
+    let quarantine = QUARANTINE.try_get().unwrap();
+    loop {
+        let block = poll_fn(|cx| {
+            if let Some(block) = quarantine.pop() {
+                return Poll::Ready(block);
+            }
+            WAKER.register(cx.waker());
+            // A block freed before the waker was registered didn't wake us
+            match quarantine.pop() {
+                Some(block) => Poll::Ready(block),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+
+        let size = block.layout.size();
+        for start in (0..size).step_by(CHUNK_SIZE) {
+            let len = CHUNK_SIZE.min(size - start);
+            unsafe { (block.addr as *mut u8).add(start).write_bytes(POISON, len) };
+            yield_now().await;
+        }
+        block.release();
+        SCRUBBED_BLOCKS.fetch_add(1, Ordering::Relaxed);
+        SCRUBBED_BYTES.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+// Blocks handed out by debug builds are cleared, so the poison can't be seen in them
+#[cfg(not(feature = "alloc_debug"))]
+#[test_case]
+fn test_scrubbed_block_holds_poison() {
+    use crate::task::{executor::Executor, Priority, Task};
+
+    // Larger than anything the executor allocates, so nothing else is freed to its free list
+    let layout = Layout::from_size_align(1500, 8).unwrap();
+    let mut executor = Executor::new();
+    executor.spawn(Task::with_priority(run(), Priority::Low));
+    executor.run_ready_tasks();
+    let before = stats();
+
+    enable();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    unsafe { ptr.write_bytes(0x42, layout.size()) };
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+    assert_eq!(stats().quarantined, before.quarantined + 1);
+    // Still the pattern, nothing has been scrubbed yet
+    assert_eq!(unsafe { ptr.add(100).read() }, 0x42);
+
+    executor.run_ready_tasks();
+    disable();
+    let after = stats();
+    assert_eq!(after.quarantined, 0);
+    assert!(after.scrubbed_blocks > before.scrubbed_blocks);
+    assert!(after.scrubbed_bytes >= before.scrubbed_bytes + layout.size());
+
+    let again = unsafe { alloc::alloc::alloc(layout) };
+    assert_eq!(again, ptr);
+    // The free list link is written over the first word when the block is freed
+    let block = unsafe { core::slice::from_raw_parts(again, layout.size()) };
+    assert!(block[8..].iter().all(|&b| b == POISON));
+    unsafe { alloc::alloc::dealloc(again, layout) };
+}
+
+#[test_case]
+fn test_full_quarantine_frees_right_away() {
+    use alloc::vec::Vec;
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    // Left over by other tests, which didn't run a scrubber
+    reclaim();
+    let mut blocks = Vec::with_capacity(QUARANTINE_SIZE + 1);
+    for _ in 0..QUARANTINE_SIZE + 1 {
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        assert!(!ptr.is_null());
+        blocks.push(ptr);
+    }
+    let before = stats();
+
+    // Nothing scrubs the quarantine, so the last block doesn't fit in it
+    enable();
+    for &ptr in &blocks {
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+    }
+    disable();
+    let after = stats();
+    assert_eq!(after.quarantined, before.quarantined + QUARANTINE_SIZE);
+    assert_eq!(after.bypassed, before.bypassed + 1);
+
+    // The block which bypassed the quarantine was freed and can be handed out again
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert_eq!(ptr, blocks[QUARANTINE_SIZE]);
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+
+    assert_eq!(reclaim(), QUARANTINE_SIZE * layout.size());
+    assert_eq!(stats().quarantined, 0);
+}
@@ -0,0 +1,329 @@
+use core::{
+    alloc::Layout,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+use alloc::{alloc::alloc, alloc::dealloc, vec::Vec};
+
+use super::Locked;
+
+/// The smallest chunk of memory a slab cache takes from the global allocator at a time.
+const SLAB_SIZE: usize = 4096;
+
+/// A free object slot, linking to the next free slot.
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// A cache of equally sized slots for objects of type `T`, carved out of page sized slabs taken
+/// from the global allocator. Freed slots are kept on a free list and reused, slabs are only given
+/// back when the cache is dropped.
+///
+/// Objects are allocated through a [`Locked`] cache, so that a cache can be shared as a static.
+pub struct SlabCache<T> {
+    slabs: Vec<NonNull<u8>>,
+    free: Option<NonNull<FreeSlot>>,
+    in_use: usize,
+    // Slots to allocate up front when the first object is allocated
+    capacity_hint: usize,
+    _marker: PhantomData<T>,
+}
+
+// The cache owns its slabs, the pointers aren't shared with anything outside of it
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+/// Counters describing the memory used by a [`SlabCache`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Objects currently allocated.
+    pub in_use: usize,
+    /// Slabs taken from the global allocator.
+    pub slabs: usize,
+}
+
+impl<T> SlabCache<T> {
+    const SLOT_ALIGN: usize = if align_of::<T>() > align_of::<FreeSlot>() {
+        align_of::<T>()
+    } else {
+        align_of::<FreeSlot>()
+    };
+    const SLOT_SIZE: usize = {
+        let size = if size_of::<T>() > size_of::<FreeSlot>() {
+            size_of::<T>()
+        } else {
+            size_of::<FreeSlot>()
+        };
+        (size + Self::SLOT_ALIGN - 1) & !(Self::SLOT_ALIGN - 1)
+    };
+    const SLAB_SIZE: usize = if Self::SLOT_SIZE > SLAB_SIZE {
+        Self::SLOT_SIZE
+    } else {
+        SLAB_SIZE
+    };
+    /// The number of objects that fit in one slab.
+    pub const SLOTS_PER_SLAB: usize = Self::SLAB_SIZE / Self::SLOT_SIZE;
+
+    /// Creates an empty cache. The slabs for `capacity_hint` objects are allocated along with the
+    /// first object, more are added as needed.
+    pub const fn new(capacity_hint: usize) -> Self {
+        Self {
+            slabs: Vec::new(),
+            free: None,
+            in_use: 0,
+            capacity_hint,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            in_use: self.in_use,
+            slabs: self.slabs.len(),
+        }
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(Self::SLAB_SIZE, Self::SLOT_ALIGN).unwrap()
+    }
+
+    /// Takes a free slot, adding slabs if there is none.
+    fn take_slot(&mut self) -> Option<NonNull<T>> {
+        if self.free.is_none() {
+            let slabs = if self.slabs.is_empty() {
+                self.capacity_hint.div_ceil(Self::SLOTS_PER_SLAB).max(1)
+            } else {
+                1
+            };
+            for _ in 0..slabs {
+                self.add_slab()?;
+            }
+        }
+        let slot = self.free?;
+        self.free = unsafe { slot.as_ref().next };
+        self.in_use += 1;
+        Some(slot.cast())
+    }
+
+    /// Puts a slot whose object has been dropped back on the free list.
+    fn return_slot(&mut self, ptr: NonNull<T>) {
+        let mut slot = ptr.cast::<FreeSlot>();
+        unsafe { slot.as_mut().next = self.free };
+        self.free = Some(slot);
+        self.in_use -= 1;
+    }
+
+    fn add_slab(&mut self) -> Option<()> {
+        let slab = NonNull::new(unsafe { alloc(Self::slab_layout()) })?;
+        // Link the slots in address order, so that objects are handed out from the start
+        for i in (0..Self::SLOTS_PER_SLAB).rev() {
+            let slot = unsafe { slab.as_ptr().add(i * Self::SLOT_SIZE) }.cast::<FreeSlot>();
+            unsafe { slot.write(FreeSlot { next: self.free }) };
+            self.free = NonNull::new(slot);
+        }
+        self.slabs.push(slab);
+        Some(())
+    }
+
+    /// Gives the slabs with no object in them back to the global allocator, returning the number of
+    /// bytes given back. Doesn't allocate, so it can be used to reclaim memory when the heap runs
+    /// out.
+    pub fn shrink(&mut self) -> usize {
+        let mut freed = 0;
+        let mut i = 0;
+        while i < self.slabs.len() {
+            let slab = self.slabs[i].as_ptr() as usize;
+            let in_slab = |slot: NonNull<FreeSlot>| {
+                (slab..slab + Self::SLAB_SIZE).contains(&(slot.as_ptr() as usize))
+            };
+            let mut free = 0;
+            let mut slot = self.free;
+            while let Some(current) = slot {
+                free += in_slab(current) as usize;
+                slot = unsafe { current.as_ref().next };
+            }
+            if free < Self::SLOTS_PER_SLAB {
+                i += 1;
+                continue;
+            }
+
+            // Unlink the slab's slots from the free list before giving it back
+            let mut link = &mut self.free;
+            while let Some(mut current) = *link {
+                if in_slab(current) {
+                    *link = unsafe { current.as_ref().next };
+                } else {
+                    link = unsafe { &mut current.as_mut().next };
+                }
+            }
+            unsafe { dealloc(self.slabs.swap_remove(i).as_ptr(), Self::slab_layout()) };
+            freed += Self::SLAB_SIZE;
+        }
+        freed
+    }
+}
+
+impl<T> Drop for SlabCache<T> {
+    fn drop(&mut self) {
+        // Every `SlabBox` borrows the cache, so none can be left
+        for slab in self.slabs.drain(..) {
+            unsafe { dealloc(slab.as_ptr(), Self::slab_layout()) };
+        }
+    }
+}
+
+impl<T> Locked<SlabCache<T>> {
+    /// Moves `value` into a slot of the cache. Returns `None` if the global allocator is out of
+    /// memory for a new slab.
+    pub fn alloc(&self, value: T) -> Option<SlabBox<'_, T>> {
+        let ptr = self.lock().take_slot()?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.lock().stats()
+    }
+
+    /// Gives the empty slabs back like [`SlabCache::shrink`], unless the cache is locked, in which
+    /// case nothing is freed.
+    pub fn try_shrink(&self) -> usize {
+        self.try_lock().map_or(0, |mut cache| cache.shrink())
+    }
+}
+
+/// An object in a [`SlabCache`], dropped and returned to the cache when the box is dropped.
+pub struct SlabBox<'a, T> {
+    ptr: NonNull<T>,
+    cache: &'a Locked<SlabCache<T>>,
+}
+
+// The box owns its object like a `Box` does
+unsafe impl<T: Send> Send for SlabBox<'_, T> {}
+unsafe impl<T: Sync> Sync for SlabBox<'_, T> {}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.ptr.as_ptr().drop_in_place() };
+        self.cache.lock().return_slot(self.ptr);
+    }
+}
+
+#[test_case]
+fn test_slab_grows_when_full() {
+    let cache = Locked::new(SlabCache::<u64>::new(1));
+    let per_slab = SlabCache::<u64>::SLOTS_PER_SLAB;
+    let boxes = (0..per_slab as u64)
+        .map(|i| cache.alloc(i).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        cache.stats(),
+        SlabStats {
+            in_use: per_slab,
+            slabs: 1
+        }
+    );
+
+    let extra = cache.alloc(u64::MAX).unwrap();
+    assert_eq!(cache.stats().slabs, 2);
+    assert!(boxes.iter().enumerate().all(|(i, b)| **b == i as u64));
+    assert_eq!(*extra, u64::MAX);
+
+    drop(boxes);
+    drop(extra);
+    assert_eq!(
+        cache.stats(),
+        SlabStats {
+            in_use: 0,
+            slabs: 2
+        }
+    );
+}
+
+#[test_case]
+fn test_slab_reuses_freed_slot() {
+    let cache = Locked::new(SlabCache::<[u8; 100]>::new(8));
+    let a = cache.alloc([1; 100]).unwrap();
+    let b = cache.alloc([2; 100]).unwrap();
+    let freed = a.ptr;
+    drop(a);
+    let c = cache.alloc([3; 100]).unwrap();
+    assert_eq!(c.ptr, freed);
+    assert_eq!(*b, [2; 100]);
+    assert_eq!(*c, [3; 100]);
+    assert_eq!(
+        cache.stats(),
+        SlabStats {
+            in_use: 2,
+            slabs: 1
+        }
+    );
+}
+
+#[test_case]
+fn test_slab_drops_objects_once() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct Counted;
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let cache = Locked::new(SlabCache::new(4));
+    let a = cache.alloc(Counted).unwrap();
+    let b = cache.alloc(Counted).unwrap();
+    assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+    drop(a);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    drop(b);
+    drop(cache);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn test_slab_shrink() {
+    let cache = Locked::new(SlabCache::<[u8; 1000]>::new(1));
+    let per_slab = SlabCache::<[u8; 1000]>::SLOTS_PER_SLAB;
+    let mut boxes = (0..3 * per_slab)
+        .map(|_| cache.alloc([1; 1000]).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(cache.stats().slabs, 3);
+    assert_eq!(cache.try_shrink(), 0);
+
+    // Only the slab left empty is given back, and the others' free slots are still used
+    let mut kept = boxes.split_off(per_slab);
+    drop(boxes);
+    let freed = cache.try_shrink();
+    assert_eq!(freed, SlabCache::<[u8; 1000]>::SLAB_SIZE);
+    assert_eq!(
+        cache.stats(),
+        SlabStats {
+            in_use: 2 * per_slab,
+            slabs: 2
+        }
+    );
+    kept.truncate(per_slab + 1);
+    let again = cache.alloc([2; 1000]).unwrap();
+    assert_eq!(cache.stats().slabs, 2);
+    assert_eq!(*again, [2; 1000]);
+    assert!(kept.iter().all(|b| **b == [1; 1000]));
+}
@@ -0,0 +1,322 @@
+use core::fmt::{self, Display, Write};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{timer, util::fmt::FmtBuf};
+
+/// The most phases recorded, later ones are dropped. The table is a static array, so that the
+/// phases before the heap exists can be recorded.
+const MAX_PHASES: usize = 24;
+
+/// The fewest timer ticks the timestamp counter is measured over after boot finishes. A phase
+/// marked between two ticks may be up to a tick late, which matters less the longer the span.
+const CALIBRATION_TICKS: u64 = 10;
+
+static LOG: Mutex<BootLog> = Mutex::new(BootLog::new());
+
+/// When something happened during boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    /// The timestamp counter, which counts from before the timer is programmed.
+    pub tsc: u64,
+    /// The timer ticks, which stay 0 until the timer is programmed and interrupts are enabled.
+    pub ticks: u64,
+}
+
+impl Stamp {
+    pub fn now() -> Self {
+        Self {
+            tsc: unsafe { core::arch::x86_64::_rdtsc() },
+            ticks: timer::ticks(),
+        }
+    }
+}
+
+/// A phase of the boot, started and ended by [`mark`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Phase {
+    pub name: &'static str,
+    /// The number of phases which were still running when this one started.
+    pub depth: usize,
+    pub start: Stamp,
+    /// `None` if the phase was never ended, like when its step failed.
+    pub end: Option<Stamp>,
+}
+
+/// The phases of the boot, in the order they started.
+#[derive(Debug)]
+pub struct BootLog {
+    phases: [Option<Phase>; MAX_PHASES],
+    len: usize,
+    /// When boot finished, `None` while it's still going.
+    finished: Option<Stamp>,
+    /// How fast the timestamp counter runs, measured after boot finished.
+    tsc_per_ms: Option<u64>,
+}
+
+impl BootLog {
+    pub const fn new() -> Self {
+        Self {
+            phases: [None; MAX_PHASES],
+            len: 0,
+            finished: None,
+            tsc_per_ms: None,
+        }
+    }
+
+    /// Ends the running phase called `phase`, or starts a new one if none is running. Phases
+    /// started while others run are nested in them, and don't have to end in order.
+    pub fn mark(&mut self, phase: &'static str, now: Stamp) {
+        if self.finished.is_some() {
+            return;
+        }
+        if let Some(running) = self
+            .phases_mut()
+            .find(|running| running.name == phase && running.end.is_none())
+        {
+            running.end = Some(now);
+            return;
+        }
+        let depth = self.phases().filter(|phase| phase.end.is_none()).count();
+        if let Some(slot) = self.phases.get_mut(self.len) {
+            *slot = Some(Phase {
+                name: phase,
+                depth,
+                start: now,
+                end: None,
+            });
+            self.len += 1;
+        }
+    }
+
+    /// Stops recording phases, timing them with the timestamp counter running at `tsc_per_ms`,
+    /// or with the timer ticks if it's unknown.
+    pub fn finish(&mut self, now: Stamp, tsc_per_ms: Option<u64>) {
+        self.finished.get_or_insert(now);
+        self.tsc_per_ms = tsc_per_ms;
+    }
+
+    /// Times the phases with the timestamp counter running at `tsc_per_ms` from now on.
+    pub fn calibrated(&mut self, tsc_per_ms: u64) {
+        self.tsc_per_ms = Some(tsc_per_ms);
+    }
+
+    pub fn phases(&self) -> impl Iterator<Item = &Phase> {
+        self.phases[..self.len].iter().flatten()
+    }
+
+    fn phases_mut(&mut self) -> impl Iterator<Item = &mut Phase> {
+        self.phases[..self.len].iter_mut().flatten()
+    }
+
+    /// The microseconds between two stamps.
+    fn micros(&self, from: Stamp, to: Stamp) -> u64 {
+        match self.tsc_per_ms {
+            Some(rate) => (to.tsc.saturating_sub(from.tsc) as u128 * 1000 / rate as u128) as u64,
+            None => timer::ticks_to_millis(to.ticks.saturating_sub(from.ticks)) * 1000,
+        }
+    }
+
+    fn duration(&self, phase: &Phase) -> Millis {
+        Millis(phase.end.map(|end| self.micros(phase.start, end)))
+    }
+
+    fn total(&self) -> Millis {
+        let first = self.phases().next().map(|phase| phase.start);
+        Millis(
+            first
+                .zip(self.finished)
+                .map(|(first, end)| self.micros(first, end)),
+        )
+    }
+
+    /// Writes the one line summary shown at boot, with the phases which aren't nested in others:
+    /// `boot: 142.0ms total - heap 38.2ms, fs 61.0ms, ...`.
+    pub fn write_summary(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "boot: {} total", self.total())?;
+        let mut separator = " - ";
+        for phase in self.phases().filter(|phase| phase.depth == 0) {
+            write!(out, "{}{} {}", separator, phase.name, self.duration(phase))?;
+            separator = ", ";
+        }
+        Ok(())
+    }
+
+    /// Writes every phase with its start since the first one and its duration, nested phases
+    /// indented under the ones they ran in. Unknown times are shown as `?`.
+    pub fn write_table(&self, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "{:<20} {:>9} {:>9}", "phase", "start", "duration")?;
+        let first = self.phases().next().map(|phase| phase.start);
+        for phase in self.phases() {
+            let start = Millis(first.map(|first| self.micros(first, phase.start)));
+            let mut name = FmtBuf::<20>::new();
+            write!(
+                name,
+                "{:indent$}{}",
+                "",
+                phase.name,
+                indent = phase.depth * 2
+            )?;
+            writeln!(
+                out,
+                "{:<20} {:>9} {:>9}",
+                name.as_str(),
+                start,
+                self.duration(phase)
+            )?;
+        }
+        writeln!(out, "{:<20} {:>9} {:>9}", "total", "", self.total())?;
+        match self.tsc_per_ms {
+            Some(rate) => writeln!(
+                out,
+                "timed with the timestamp counter at {} MHz",
+                rate / 1000
+            ),
+            None => writeln!(out, "timed with the timer ticks"),
+        }
+    }
+}
+
+impl Default for BootLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A number of microseconds shown as milliseconds with a decimal, like `38.2ms`, or `?`.
+struct Millis(Option<u64>);
+
+impl Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = FmtBuf::<24>::new();
+        match self.0 {
+            Some(micros) => write!(buf, "{}.{}ms", micros / 1000, micros % 1000 / 100)?,
+            None => buf.write_char('?')?,
+        }
+        f.pad(buf.as_str())
+    }
+}
+
+/// Starts a boot phase, or ends it if it's running. Cheap enough to call before anything is set
+/// up, and does nothing once boot has finished.
+pub fn mark(phase: &'static str) {
+    let now = Stamp::now();
+    interrupts::without_interrupts(|| LOG.lock().mark(phase, now));
+}
+
+/// Stops recording phases, which are timed with the timer ticks until [`calibrate`] measures how
+/// fast the timestamp counter runs. Doesn't wait, so that it doesn't add to the time it measures.
+pub fn finish() {
+    let now = Stamp::now();
+    interrupts::without_interrupts(|| LOG.lock().finish(now, None));
+}
+
+/// Measures how many timestamp counter cycles pass per millisecond against the timer, to turn the
+/// stamps into times. Sleeps until [`CALIBRATION_TICKS`] have passed since the first stamp taken
+/// once the timer ticked, or since the next tick if there's none, so boot carries on meanwhile.
+pub async fn calibrate() {
+    let reference = interrupts::without_interrupts(|| {
+        let log = LOG.lock();
+        log.phases()
+            .flat_map(|phase| [Some(phase.start), phase.end])
+            .flatten()
+            .filter(|stamp| stamp.ticks > 0)
+            .min_by_key(|stamp| stamp.tsc)
+    });
+    let reference = match reference {
+        Some(reference) => reference,
+        None => next_tick().await,
+    };
+    let remaining = (reference.ticks + CALIBRATION_TICKS).saturating_sub(timer::ticks());
+    timer::sleep_ticks(remaining).await;
+    // Right after a tick, so that only the reference may be off
+    let end = next_tick().await;
+    let millis = timer::ticks_to_millis(end.ticks - reference.ticks).max(1);
+    let tsc_per_ms = (end.tsc - reference.tsc) / millis;
+    interrupts::without_interrupts(|| LOG.lock().calibrated(tsc_per_ms));
+}
+
+/// Waits for the timer to tick, returning a stamp taken right after it.
+async fn next_tick() -> Stamp {
+    timer::sleep_ticks(1).await;
+    Stamp::now()
+}
+
+/// The one line summary of the boot, see [`BootLog::write_summary`].
+pub struct Summary;
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        interrupts::without_interrupts(|| LOG.lock().write_summary(f))
+    }
+}
+
+/// Every boot phase, as shown by `bootinfo` and `/proc/boot`, see [`BootLog::write_table`].
+pub struct Table;
+
+impl Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        interrupts::without_interrupts(|| LOG.lock().write_table(f))
+    }
+}
+
+#[test_case]
+fn test_boot_phases() {
+    // Marked by the test kernel before the tests run
+    let log = LOG.lock();
+    for name in ["gdt+idt", "pic", "pit", "heap", "paging"] {
+        let phase = log.phases().find(|phase| phase.name == name);
+        let phase = phase.unwrap_or_else(|| panic!("{} wasn't marked", name));
+        let end = phase.end.unwrap_or_else(|| panic!("{} didn't end", name));
+        assert!(
+            0 < phase.start.tsc && phase.start.tsc <= end.tsc,
+            "{}",
+            name
+        );
+        assert!(phase.start.ticks <= end.ticks, "{}", name);
+    }
+    let starts = log.phases().map(|phase| phase.start.tsc);
+    assert!(starts
+        .zip(log.phases().skip(1))
+        .all(|(a, b)| a <= b.start.tsc));
+}
+
+#[test_case]
+fn test_boot_log() {
+    use alloc::string::String;
+
+    let stamp = |tsc| Stamp { tsc, ticks: 0 };
+    let mut log = BootLog::new();
+    log.mark("init", stamp(1000));
+    log.mark("gdt", stamp(1500));
+    log.mark("gdt", stamp(2000));
+    log.mark("init", stamp(3000));
+    // Never ended, like a step which failed
+    log.mark("fs", stamp(3000));
+    log.finish(stamp(45_000), None);
+    log.mark("late", stamp(46_000));
+
+    // Timed with the ticks, all 0 here, until calibrated
+    let mut table = String::new();
+    log.write_table(&mut table).unwrap();
+    assert!(
+        table.ends_with("total                              0.0ms\ntimed with the timer ticks\n")
+    );
+    log.calibrated(1000);
+
+    let mut summary = String::new();
+    log.write_summary(&mut summary).unwrap();
+    assert_eq!(summary, "boot: 44.0ms total - init 2.0ms, fs ?");
+    let mut table = String::new();
+    log.write_table(&mut table).unwrap();
+    assert_eq!(
+        table,
+        "phase                    start  duration\n\
+         init                     0.0ms     2.0ms\n  \
+         gdt                    0.5ms     0.5ms\n\
+         fs                       2.0ms         ?\n\
+         total                             44.0ms\n\
+         timed with the timestamp counter at 1 MHz\n"
+    );
+}
@@ -0,0 +1,248 @@
+use spin::Once;
+
+use crate::{
+    allocator::AllocatorKind, fs::ata::Drive, log, log::LogLevel, task::keyboard::ScancodeSetKind,
+};
+
+/// The kernel command line. The bootloader doesn't pass one to the kernel, so it's embedded at
+/// build time from the `HANNOS_CMDLINE` environment variable.
+pub const EMBEDDED_CMDLINE: &str = match option_env!("HANNOS_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+static ARGS: Once<KernelArgs> = Once::new();
+
+/// Where console output is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Console {
+    Vga = 0,
+    Serial = 1,
+    Both = 2,
+}
+
+/// The options parsed from the kernel command line, formatted as space separated `key=value`
+/// pairs. Values containing spaces can be put in double quotes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelArgs {
+    /// `console=vga|serial|both`
+    pub console: Console,
+    /// `loglevel=0..4`
+    pub loglevel: LogLevel,
+    /// `allocator=bump|fixed|buddy`
+    pub allocator: AllocatorKind,
+    /// `init=<shell command>`, run once when the shell has started
+    pub init: Option<&'static str>,
+    /// `scancodes=1|2`, the scancode set the keyboard sends
+    pub scancodes: ScancodeSetKind,
+    /// `serialecho=on|off`, off for host terminals which echo what's typed themselves
+    pub serial_echo: bool,
+    /// `heap_size=<MiB>`, overriding the heap size set at build time. Kept in bytes.
+    pub heap_size: Option<usize>,
+    /// `disk=ram|hda|hdb`, the disk the kernel filesystem lives on, a drive on the primary IDE
+    /// channel instead of the simulated disk
+    pub disk: Option<Drive>,
+}
+
+impl Default for KernelArgs {
+    fn default() -> Self {
+        Self {
+            console: Console::Vga,
+            loglevel: LogLevel::Info,
+            allocator: AllocatorKind::Fixed,
+            init: None,
+            scancodes: ScancodeSetKind::Set1,
+            serial_echo: true,
+            heap_size: None,
+            disk: None,
+        }
+    }
+}
+
+/// Parses the command line and uses it for the rest of the kernel's lifetime. Only the first
+/// call has any effect.
+pub fn init(cmdline: &'static str) -> &'static KernelArgs {
+    ARGS.call_once(|| parse(cmdline))
+}
+
+/// Returns the kernel arguments, parsing the embedded command line if [`init`] wasn't called.
+pub fn args() -> &'static KernelArgs {
+    init(EMBEDDED_CMDLINE)
+}
+
+/// Parses a command line. Unknown keys and malformed values are warned about and ignored.
+pub fn parse(cmdline: &'static str) -> KernelArgs {
+    let mut args = KernelArgs::default();
+    for token in tokens(cmdline) {
+        let Some((key, value)) = token.split_once('=') else {
+            log!(
+                LogLevel::Warn,
+                "cmdline: ignoring malformed argument '{}'",
+                token
+            );
+            continue;
+        };
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+
+        let valid = match key {
+            "console" => parse_console(value).map(|console| args.console = console),
+            "loglevel" => value
+                .parse()
+                .ok()
+                .and_then(LogLevel::from_u8)
+                .map(|level| args.loglevel = level),
+            "allocator" => parse_allocator(value).map(|kind| args.allocator = kind),
+            "scancodes" => parse_scancodes(value).map(|set| args.scancodes = set),
+            "serialecho" => parse_switch(value).map(|echo| args.serial_echo = echo),
+            "heap_size" => parse_mib(value).map(|size| args.heap_size = Some(size)),
+            "disk" => parse_disk(value).map(|disk| args.disk = disk),
+            "init" => {
+                args.init = Some(value).filter(|v| !v.is_empty());
+                Some(())
+            }
+            _ => {
+                log!(LogLevel::Warn, "cmdline: unknown key '{}'", key);
+                continue;
+            }
+        };
+        if valid.is_none() {
+            log!(
+                LogLevel::Warn,
+                "cmdline: invalid value '{}' for '{}', using the default",
+                value,
+                key
+            );
+        }
+    }
+    args
+}
+
+fn parse_console(value: &str) -> Option<Console> {
+    match value {
+        "vga" => Some(Console::Vga),
+        "serial" => Some(Console::Serial),
+        "both" => Some(Console::Both),
+        _ => None,
+    }
+}
+
+fn parse_allocator(value: &str) -> Option<AllocatorKind> {
+    match value {
+        "bump" => Some(AllocatorKind::Bump),
+        "fixed" => Some(AllocatorKind::Fixed),
+        "buddy" => Some(AllocatorKind::Buddy),
+        _ => None,
+    }
+}
+
+fn parse_scancodes(value: &str) -> Option<ScancodeSetKind> {
+    match value {
+        "1" => Some(ScancodeSetKind::Set1),
+        "2" => Some(ScancodeSetKind::Set2),
+        _ => None,
+    }
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_disk(value: &str) -> Option<Option<Drive>> {
+    match value {
+        "ram" => Some(None),
+        "hda" => Some(Some(Drive::Master)),
+        "hdb" => Some(Some(Drive::Slave)),
+        _ => None,
+    }
+}
+
+/// Parses a size in MiB, returning it in bytes.
+fn parse_mib(value: &str) -> Option<usize> {
+    value
+        .parse::<usize>()
+        .ok()
+        .filter(|&mib| mib > 0)
+        .and_then(|mib| mib.checked_mul(1024 * 1024))
+}
+
+/// Splits the command line on whitespace, except for whitespace inside double quotes.
+fn tokens(cmdline: &str) -> impl Iterator<Item = &str> {
+    let mut rest = cmdline;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c.is_whitespace() && !in_quotes
+            })
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        let (token, tail) = rest.split_at(end);
+        rest = tail;
+        Some(token)
+    })
+}
+
+#[test_case]
+fn test_parse_defaults() {
+    assert_eq!(parse(""), KernelArgs::default());
+    assert_eq!(parse("   "), KernelArgs::default());
+}
+
+#[test_case]
+fn test_parse_all_keys() {
+    let args = parse(concat!(
+        "console=both loglevel=3 allocator=buddy scancodes=2 serialecho=off heap_size=8 ",
+        "disk=hdb init=\"echo hello\"",
+    ));
+    assert_eq!(
+        args,
+        KernelArgs {
+            console: Console::Both,
+            loglevel: LogLevel::Debug,
+            allocator: AllocatorKind::Buddy,
+            init: Some("echo hello"),
+            scancodes: ScancodeSetKind::Set2,
+            serial_echo: false,
+            heap_size: Some(8 * 1024 * 1024),
+            disk: Some(Drive::Slave),
+        }
+    );
+}
+
+#[test_case]
+fn test_parse_invalid_values_fall_back() {
+    let args = parse(concat!(
+        "console=hdmi loglevel=7 allocator=slab scancodes=3 serialecho=maybe heap_size=0 ",
+        "disk=sda bogus=1 noequals init=help"
+    ));
+    assert_eq!(
+        args,
+        KernelArgs {
+            init: Some("help"),
+            ..KernelArgs::default()
+        }
+    );
+}
+
+#[test_case]
+fn test_parse_last_value_wins() {
+    let args = parse("console=serial console=vga loglevel=0");
+    assert_eq!(args.console, Console::Vga);
+    assert_eq!(args.loglevel, LogLevel::Error);
+}
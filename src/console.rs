@@ -0,0 +1,426 @@
+use core::{
+    fmt,
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+
+use futures_util::{task::AtomicWaker, StreamExt as _};
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    log, panic, statusbar,
+    task::{
+        keyboard::{self, KeyPress},
+        select2, Either,
+    },
+    vgabuf::{self, VGAColor, WRITER},
+};
+
+/// Bytes of screen output held back while it's paused. Once full, the oldest output is dropped to
+/// make room.
+pub const PENDING_SIZE: usize = 8 * 1024;
+/// Shown in place of output which was dropped while paused.
+pub const DROPPED_MARKER: &str = "... output dropped ...\n";
+
+// Held output is kept as records of [KIND (1 byte), LEN (1 byte), PAYLOAD (LEN bytes)]. Text is
+// kept as UTF-8, colored characters as their code point (4 bytes, LE) followed by the attribute
+// byte of their color. Longer writes are split over several records.
+const TEXT: u8 = 0;
+const COLORED: u8 = 1;
+const HEADER_SIZE: usize = 2;
+const MAX_PAYLOAD: usize = u8::MAX as usize;
+const CELL_SIZE: usize = 5;
+const MAX_CELLS: usize = MAX_PAYLOAD / CELL_SIZE;
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static PENDING: Mutex<Pending<PENDING_SIZE>> = Mutex::new(Pending::new());
+static LOG_PANE_FOCUSED: AtomicBool = AtomicBool::new(false);
+/// Wakes the task of the log pane when the screen is split or joined, see [`run_log_pane`].
+static LAYOUT_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The panes of a split screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pane {
+    /// The left pane, where the console and the shell on it are.
+    Console,
+    /// The right pane, following the kernel log.
+    Log,
+}
+
+/// Output written to the screen, as held back while paused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunk<'a> {
+    Text(&'a str),
+    Colored(&'a [(char, VGAColor)]),
+}
+
+/// A ring of held back output, dropping the oldest records when a new one doesn't fit.
+struct Pending<const N: usize> {
+    bytes: [u8; N],
+    start: usize,
+    len: usize,
+    /// Whether records were dropped since the ring was last drained.
+    dropped: bool,
+}
+
+impl<const N: usize> Pending<N> {
+    const fn new() -> Self {
+        // Otherwise a record could be too large to ever fit
+        const { assert!(N >= HEADER_SIZE + MAX_PAYLOAD) };
+        Self {
+            bytes: [0; N],
+            start: 0,
+            len: 0,
+            dropped: false,
+        }
+    }
+
+    fn byte(&self, i: usize) -> u8 {
+        self.bytes[(self.start + i) % N]
+    }
+
+    /// Adds a record, dropping the oldest ones until it fits.
+    fn push_record(&mut self, kind: u8, payload: &[u8]) {
+        let size = HEADER_SIZE + payload.len();
+        while N - self.len < size {
+            self.pop_record(&mut [0; MAX_PAYLOAD]);
+            self.dropped = true;
+        }
+        let header = [kind, payload.len() as u8];
+        for &byte in header.iter().chain(payload) {
+            self.bytes[(self.start + self.len) % N] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Removes the oldest record, copying its payload into `buf` and returning its kind and length.
+    fn pop_record(&mut self, buf: &mut [u8; MAX_PAYLOAD]) -> Option<(u8, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        let (kind, len) = (self.byte(0), self.byte(1) as usize);
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.byte(HEADER_SIZE + i);
+        }
+        self.start = (self.start + HEADER_SIZE + len) % N;
+        self.len -= HEADER_SIZE + len;
+        Some((kind, len))
+    }
+
+    fn push(&mut self, chunk: Chunk) {
+        match chunk {
+            Chunk::Text(mut s) => {
+                while !s.is_empty() {
+                    let mut end = s.len().min(MAX_PAYLOAD);
+                    while !s.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    self.push_record(TEXT, &s.as_bytes()[..end]);
+                    s = &s[end..];
+                }
+            }
+            Chunk::Colored(cells) => {
+                for cells in cells.chunks(MAX_CELLS) {
+                    let mut payload = [0; MAX_CELLS * CELL_SIZE];
+                    for (cell, &(c, color)) in payload.chunks_mut(CELL_SIZE).zip(cells) {
+                        cell[..4].copy_from_slice(&(c as u32).to_le_bytes());
+                        cell[4] = color.attribute();
+                    }
+                    self.push_record(COLORED, &payload[..cells.len() * CELL_SIZE]);
+                }
+            }
+        }
+    }
+
+    /// Removes the oldest record and passes it to `f`, returning `false` if there was none. The
+    /// first record after some were dropped is preceded by [`DROPPED_MARKER`].
+    fn pop(&mut self, mut f: impl FnMut(Chunk)) -> bool {
+        if core::mem::take(&mut self.dropped) {
+            f(Chunk::Text(DROPPED_MARKER));
+        }
+        let mut buf = [0; MAX_PAYLOAD];
+        let Some((kind, len)) = self.pop_record(&mut buf) else {
+            return false;
+        };
+        match kind {
+            TEXT => f(Chunk::Text(core::str::from_utf8(&buf[..len]).unwrap())),
+            _ => {
+                let mut cells = [(' ', VGAColor::from_attribute(0)); MAX_CELLS];
+                for (cell, bytes) in cells.iter_mut().zip(buf[..len].chunks(CELL_SIZE)) {
+                    let c = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+                    *cell = (
+                        char::from_u32(c).unwrap(),
+                        VGAColor::from_attribute(bytes[4]),
+                    );
+                }
+                f(Chunk::Colored(&cells[..len / CELL_SIZE]))
+            }
+        }
+        true
+    }
+}
+
+impl<const N: usize> fmt::Write for Pending<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(Chunk::Text(s));
+        Ok(())
+    }
+}
+
+/// Stops output from reaching the screen until [`resume`] is called, like XOFF. Output printed in
+/// the meantime is held back, up to [`PENDING_SIZE`] bytes of it. Interrupt handlers and the panic
+/// handler still print right away, and so does everything written to the serial port.
+///
+/// Only the text scrolling through the screen is held back. Rows drawn in place, like the input
+/// line and the status bar, are still drawn.
+pub fn pause() {
+    PAUSED.store(true, Ordering::SeqCst);
+    if statusbar::is_enabled() {
+        statusbar::render();
+    }
+}
+
+/// Shows the output held back since [`pause`] in the order it was written, after a
+/// [`DROPPED_MARKER`] if some of it had to be dropped, and lets output through again.
+pub fn resume() {
+    loop {
+        let more = interrupts::without_interrupts(|| {
+            let mut pending = PENDING.lock();
+            let mut writer = WRITER.lock();
+            let more = pending.pop(|chunk| match chunk {
+                Chunk::Text(s) => writer.write_str(s),
+                Chunk::Colored(cells) => writer.write_colored(cells),
+            });
+            if !more {
+                // Still holding the ring, so nothing can be added to it after it was drained
+                PAUSED.store(false, Ordering::SeqCst);
+                writer.flush();
+            }
+            more
+        });
+        if !more {
+            break;
+        }
+    }
+    if statusbar::is_enabled() {
+        statusbar::render();
+    }
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Returns the number of bytes of output held back, including their bookkeeping.
+pub fn pending() -> usize {
+    interrupts::without_interrupts(|| PENDING.lock().len)
+}
+
+/// Holds `chunk` back if output is paused, returning `false` if it should be written now.
+pub(crate) fn hold(chunk: Chunk) -> bool {
+    hold_with(|pending| pending.push(chunk))
+}
+
+/// Holds formatted output back if output is paused, see [`hold`].
+pub(crate) fn hold_fmt(args: fmt::Arguments) -> bool {
+    hold_with(|pending| {
+        let _ = fmt::Write::write_fmt(pending, args);
+    })
+}
+
+fn hold_with(f: impl FnOnce(&mut Pending<PENDING_SIZE>)) -> bool {
+    // The handlers can't wait for the output to be resumed, and whatever they print may be the
+    // last thing the kernel prints
+    if !is_paused() || crate::interrupts::in_handler() || panic::in_panic() {
+        return false;
+    }
+    interrupts::without_interrupts(|| {
+        let mut pending = PENDING.lock();
+        // Resumed while waiting for the ring
+        if !is_paused() {
+            return false;
+        }
+        f(&mut pending);
+        true
+    })
+}
+
+/// Splits the screen into two panes, see [`vgabuf::split`]. The console and the shell on it move to
+/// the left pane, and the right one follows the kernel log like `dmesg -f`. Keys go to the
+/// console until F6 gives the focus to the log pane, where `q` or Ctrl+C joins the panes again.
+///
+/// The status bar would have to share its row with the log pane, so it's hidden while the screen
+/// is split and comes back afterwards. Each pane keeps its own scroll region, the input line of
+/// the shell stays at the bottom of the console pane. Returns `false` if the screen was split
+/// already.
+pub fn split() -> bool {
+    if !vgabuf::split() {
+        return false;
+    }
+    if statusbar::is_enabled() {
+        statusbar::hide();
+    }
+    LAYOUT_WAKER.wake();
+    true
+}
+
+/// Joins the panes of a split screen again, giving the console the whole screen and the focus.
+/// Returns `false` if the screen wasn't split.
+pub fn unsplit() -> bool {
+    if !vgabuf::unsplit() {
+        return false;
+    }
+    LOG_PANE_FOCUSED.store(false, Ordering::SeqCst);
+    if statusbar::is_enabled() {
+        statusbar::show();
+    }
+    LAYOUT_WAKER.wake();
+    true
+}
+
+/// Gives the keyboard focus to the other pane of a split screen. Does nothing unless the screen
+/// is split.
+pub fn toggle_focus() {
+    if vgabuf::is_split() {
+        LOG_PANE_FOCUSED.fetch_xor(true, Ordering::SeqCst);
+    }
+}
+
+/// Returns the pane keys are routed to, always [`Pane::Console`] unless the screen is split.
+pub fn focused_pane() -> Pane {
+    match LOG_PANE_FOCUSED.load(Ordering::SeqCst) {
+        true => Pane::Log,
+        false => Pane::Console,
+    }
+}
+
+/// Waits until the screen is split, or until it's joined again if `split` is `false`.
+async fn wait_for_layout(split: bool) {
+    poll_fn(|cx| {
+        if vgabuf::is_split() == split {
+            return Poll::Ready(());
+        }
+        LAYOUT_WAKER.register(cx.waker());
+        match vgabuf::is_split() == split {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Shows the kernel log in the right pane whenever the screen is split, starting with the
+/// messages kept and adding new ones as they're logged. Takes the keys typed while the pane has
+/// the focus. There must be exactly one of these tasks running.
+pub async fn run_log_pane() {
+    let mut keys = keyboard::subscribe();
+    let _pane = keys.acquire_log_pane();
+    loop {
+        wait_for_layout(true).await;
+        let (messages, mut pos) = log::messages_since(0);
+        vgabuf::write_side(&messages);
+        loop {
+            let keypress = async {
+                loop {
+                    match keys.next().await {
+                        Some(KeyPress {
+                            key: DecodedKey::Unicode('q' | 'Q'),
+                            ..
+                        }) => break true,
+                        Some(KeyPress {
+                            key: DecodedKey::Unicode('c' | 'C'),
+                            modifiers,
+                        }) if modifiers.ctrl => break true,
+                        Some(_) => {}
+                        None => break false,
+                    }
+                }
+            };
+            let messages = log::wait_for_messages(pos);
+            match select2(messages, select2(keypress, wait_for_layout(false))).await {
+                Either::Left(()) => {
+                    let (messages, next) = log::messages_since(pos);
+                    vgabuf::write_side(&messages);
+                    pos = next;
+                }
+                Either::Right(Either::Left(true)) => {
+                    unsplit();
+                    break;
+                }
+                Either::Right(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn drain<const N: usize>(pending: &mut Pending<N>) -> alloc::string::String {
+    let mut text = alloc::string::String::new();
+    while pending.pop(|chunk| match chunk {
+        Chunk::Text(s) => text.push_str(s),
+        Chunk::Colored(cells) => text.extend(cells.iter().map(|&(c, _)| c)),
+    }) {}
+    text
+}
+
+#[test_case]
+fn test_paused_output_is_held_back() {
+    use crate::{
+        task::{executor::Executor, Task},
+        vgabuf::{row_text, HEIGHT},
+    };
+
+    crate::println!("before the pause");
+    pause();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        for i in 0..3 {
+            crate::println!("held back line {}", i);
+        }
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(row_text(HEIGHT - 2), "before the pause");
+    assert!(pending() > 0);
+
+    resume();
+    assert!(!is_paused());
+    assert_eq!(pending(), 0);
+    assert_eq!(row_text(HEIGHT - 4), "held back line 0");
+    assert_eq!(row_text(HEIGHT - 3), "held back line 1");
+    assert_eq!(row_text(HEIGHT - 2), "held back line 2");
+    crate::println!("after the pause");
+    assert_eq!(row_text(HEIGHT - 2), "after the pause");
+}
+
+#[test_case]
+fn test_full_pending_output_drops_oldest() {
+    use crate::vgabuf::Color;
+
+    let mut pending = Pending::<300>::new();
+    pending.push(Chunk::Text("first\n"));
+    let red = VGAColor::new(Color::Red, Color::Black);
+    pending.push(Chunk::Colored(&[('o', red), ('k', red)]));
+    assert_eq!(drain(&mut pending), "firstok");
+
+    // Each line takes a record of 12 bytes, so only the last 25 fit
+    let line = |i| alloc::format!("line {:04}\n", i);
+    for i in 0..30 {
+        pending.push(Chunk::Text(&line(i)));
+    }
+    let kept = (5..30).map(line).collect::<alloc::string::String>();
+    assert_eq!(
+        drain(&mut pending),
+        alloc::format!("{}{}", DROPPED_MARKER, kept)
+    );
+    // The marker is only shown once
+    pending.push(Chunk::Text("again\n"));
+    assert_eq!(drain(&mut pending), "again\n");
+
+    // Writes longer than a record are split without cutting characters in half
+    let long = "é".repeat(200);
+    pending.push(Chunk::Text(&long));
+    assert_eq!(drain(&mut pending), long);
+}
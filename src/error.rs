@@ -0,0 +1,196 @@
+use alloc::{boxed::Box, string::String};
+use core::fmt;
+
+use thiserror_no_std::Error;
+
+use crate::{
+    allocator::memtest::MemtestError,
+    fs::{
+        disk::DiskError, file::FileSystemError, partitions::PartitionError, stress::StressError,
+        transfer::TransferError,
+    },
+    screenshot::ScreenshotError,
+    settings::SettingsError,
+    shell::ShellError,
+    swap::SwapError,
+};
+
+/// What kind of failure an error is, so that callers can react to a failure without matching the
+/// variants of every subsystem's error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A file, inode, command or other named thing doesn't exist.
+    NotFound,
+    /// The disk, the swap device or a table with a fixed size is full.
+    OutOfSpace,
+    /// Data read from the disk or received over the serial port doesn't make sense.
+    Corrupt,
+    /// The operation can't be done on this kind of object, or by this kernel.
+    Unsupported,
+    /// A device failed or didn't answer in time.
+    Io,
+    /// The operation was asked for with arguments which don't make sense.
+    InvalidInput,
+    Other,
+}
+
+/// An error of a kernel subsystem. `thiserror_no_std` doesn't implement `core::error::Error`, so
+/// this trait takes its place.
+pub trait Error: fmt::Debug + fmt::Display {
+    fn kind(&self) -> ErrorKind;
+
+    /// The lower level error which caused this one, if there is one. Its message is already part
+    /// of this error's message.
+    fn source(&self) -> Option<&dyn Error> {
+        None
+    }
+}
+
+/// Returns the error at the bottom of the chain of sources of `err`.
+pub fn root_cause(err: &dyn Error) -> &dyn Error {
+    let mut err = err;
+    while let Some(source) = err.source() {
+        err = source;
+    }
+    err
+}
+
+/// An error of any subsystem, for code calling into several of them. Errors are converted with
+/// `?`, and [`context`](Self::context) says what was being done when one happened.
+#[derive(Error, Debug)]
+pub enum KernelError {
+    #[error("{0}")]
+    Shell(#[from] ShellError),
+    #[error("{0}")]
+    FileSystem(#[from] FileSystemError),
+    #[error("disk error: {0}")]
+    Disk(#[from] DiskError),
+    #[error("{0}")]
+    Partition(#[from] PartitionError),
+    #[error("{0}")]
+    Transfer(#[from] TransferError),
+    #[error("{0}")]
+    Swap(#[from] SwapError),
+    #[error("fsstress: {0}")]
+    Stress(#[from] StressError),
+    #[error("memtest: {0}")]
+    Memtest(#[from] MemtestError),
+    #[error("{0}")]
+    Screenshot(#[from] ScreenshotError),
+    #[error("{0}")]
+    Settings(#[from] SettingsError),
+    #[error("{context}: {cause}")]
+    Context {
+        context: String,
+        cause: Box<KernelError>,
+    },
+}
+
+impl KernelError {
+    /// Wraps the error in what was being done when it happened, which is put in front of its
+    /// message.
+    pub fn context(self, context: impl Into<String>) -> Self {
+        Self::Context {
+            context: context.into(),
+            cause: Box::new(self),
+        }
+    }
+
+    /// The error of the subsystem which failed, without any context.
+    fn inner(&self) -> &dyn Error {
+        match self {
+            Self::Shell(err) => err,
+            Self::FileSystem(err) => err,
+            Self::Disk(err) => err,
+            Self::Partition(err) => err,
+            Self::Transfer(err) => err,
+            Self::Swap(err) => err,
+            Self::Stress(err) => err,
+            Self::Memtest(err) => err,
+            Self::Screenshot(err) => err,
+            Self::Settings(err) => err,
+            Self::Context { cause, .. } => cause.inner(),
+        }
+    }
+}
+
+impl Error for KernelError {
+    fn kind(&self) -> ErrorKind {
+        self.inner().kind()
+    }
+
+    fn source(&self) -> Option<&dyn Error> {
+        match self {
+            Self::Context { cause, .. } => Some(&**cause),
+            _ => self.inner().source(),
+        }
+    }
+}
+
+/// Adds context to the error of a `Result`, see [`KernelError::context`].
+pub trait ResultExt<T> {
+    fn context(self, context: &str) -> Result<T, KernelError>;
+}
+
+impl<T, E: Into<KernelError>> ResultExt<T> for Result<T, E> {
+    fn context(self, context: &str) -> Result<T, KernelError> {
+        self.map_err(|err| err.into().context(context))
+    }
+}
+
+#[test_case]
+fn test_error_chain() {
+    use alloc::string::ToString;
+
+    let err = Err::<(), _>(FileSystemError::Disk(DiskError::BlockOutOfBounds(2048)))
+        .context("write failed")
+        .context("cp")
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "cp: write failed: disk error: block 2048 out of bounds"
+    );
+    let KernelError::Context { cause, .. } = &err else {
+        panic!("expected context");
+    };
+    assert_eq!(
+        cause.to_string(),
+        "write failed: disk error: block 2048 out of bounds"
+    );
+    assert_eq!(root_cause(&err).to_string(), "block 2048 out of bounds");
+}
+
+#[test_case]
+fn test_error_kinds() {
+    let kind = |err: KernelError| err.kind();
+    assert_eq!(
+        kind(FileSystemError::NotFound("x".into()).into()),
+        ErrorKind::NotFound
+    );
+    assert_eq!(
+        kind(ShellError::CommandNotFound("x".into()).into()),
+        ErrorKind::NotFound
+    );
+    assert_eq!(
+        kind(FileSystemError::NoFreeBlocks.into()),
+        ErrorKind::OutOfSpace
+    );
+    assert_eq!(kind(SwapError::Full.into()), ErrorKind::OutOfSpace);
+    assert_eq!(kind(TransferError::Timeout.into()), ErrorKind::Io);
+    assert_eq!(
+        kind(FileSystemError::ReadOnly.into()),
+        ErrorKind::Unsupported
+    );
+    // The kind of a wrapped error is the kind of the failure at the bottom
+    let err = KernelError::from(FileSystemError::Disk(DiskError::WriteFailed(3))).context("cp");
+    assert_eq!(err.kind(), ErrorKind::Io);
+    let corrupt = FileSystemError::CorruptDirectory {
+        dir: 1,
+        block: 2,
+        reason: "bad record",
+    };
+    assert!(matches!(
+        KernelError::from(corrupt).context("ls").kind(),
+        ErrorKind::Corrupt
+    ));
+}
@@ -0,0 +1,130 @@
+use spin::Mutex;
+
+use super::{
+    disk::BLOCK_SIZE,
+    file::{FileSystem, FileSystemError, INumber, MAX_FILE_SIZE},
+};
+use crate::task::yield_now;
+
+/// Reads from the file into `outbuf` starting at `offset`, like [`FileSystem::read`]. At most
+/// one block is read per poll, yielding to other tasks in between, and the filesystem is only
+/// locked while a block is read.
+pub async fn read_async(
+    fs: &Mutex<FileSystem>,
+    inumber: INumber,
+    offset: usize,
+    outbuf: &mut [u8],
+) -> Result<usize, FileSystemError> {
+    let mut bytes_read = 0;
+    loop {
+        let pos = offset + bytes_read;
+        let len = (BLOCK_SIZE - pos % BLOCK_SIZE).min(outbuf.len() - bytes_read);
+        let read = fs
+            .lock()
+            .read(inumber, pos, &mut outbuf[bytes_read..bytes_read + len])?;
+        bytes_read += read;
+        if read < len || bytes_read == outbuf.len() {
+            return Ok(bytes_read);
+        }
+        yield_now().await;
+    }
+}
+
+/// Writes `data` to the file at `offset`, like [`FileSystem::write`], one block per poll.
+pub async fn write_async(
+    fs: &Mutex<FileSystem>,
+    inumber: INumber,
+    offset: usize,
+    data: &[u8],
+) -> Result<usize, FileSystemError> {
+    // Fail before writing anything, rather than after some of the blocks
+    let new_size = offset + data.len();
+    if new_size > MAX_FILE_SIZE {
+        return Err(FileSystemError::FileTooLarge(new_size));
+    }
+
+    let mut bytes_written = 0;
+    loop {
+        let pos = offset + bytes_written;
+        let len = (BLOCK_SIZE - pos % BLOCK_SIZE).min(data.len() - bytes_written);
+        bytes_written +=
+            fs.lock()
+                .write(inumber, pos, &data[bytes_written..bytes_written + len])?;
+        if bytes_written == data.len() {
+            return Ok(bytes_written);
+        }
+        yield_now().await;
+    }
+}
+
+#[test_case]
+fn test_read_async_yields_between_blocks() {
+    use super::{file::InodeKind, FILESYSTEM};
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const BLOCKS: usize = 8;
+    crate::fs::init().unwrap();
+    let data = (0..BLOCKS * BLOCK_SIZE)
+        .map(|i| (i / 7) as u8)
+        .collect::<Vec<_>>();
+    let inumber = {
+        let mut fs = FILESYSTEM.lock();
+        let inumber = fs.create(InodeKind::File).unwrap();
+        fs.write(inumber, 0, &data).unwrap();
+        inumber
+    };
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new({
+        let counter = counter.clone();
+        let done = done.clone();
+        async move {
+            let start = counter.load(Ordering::SeqCst);
+            let mut buf = vec![0; data.len()];
+            let read = read_async(&FILESYSTEM, inumber, 0, &mut buf).await;
+            assert_eq!(read.unwrap(), data.len());
+            assert!(buf == data);
+            // The counter task ran once for every yield between blocks
+            assert!(counter.load(Ordering::SeqCst) - start >= BLOCKS - 1);
+            done.store(true, Ordering::SeqCst);
+        }
+    }));
+    executor.spawn(Task::new(async move {
+        while !done.load(Ordering::SeqCst) {
+            counter.fetch_add(1, Ordering::SeqCst);
+            yield_now().await;
+        }
+    }));
+    executor.run();
+}
+
+#[test_case]
+fn test_write_async_roundtrip() {
+    use super::{file::InodeKind, FILESYSTEM};
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+    use alloc::vec;
+
+    crate::fs::init().unwrap();
+    let inumber = FILESYSTEM.lock().create(InodeKind::File).unwrap();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        let data = vec![0xab; 3 * BLOCK_SIZE - 10];
+        let written = write_async(&FILESYSTEM, inumber, 10, &data).await;
+        assert_eq!(written.unwrap(), data.len());
+        assert!(matches!(
+            write_async(&FILESYSTEM, inumber, MAX_FILE_SIZE, &[1]).await,
+            Err(FileSystemError::FileTooLarge(_))
+        ));
+
+        let mut buf = vec![0xff; 3 * BLOCK_SIZE];
+        let read = read_async(&FILESYSTEM, inumber, 0, &mut buf).await;
+        assert_eq!(read.unwrap(), 3 * BLOCK_SIZE);
+        assert_eq!(&buf[..10], &[0; 10]);
+        assert!(buf[10..] == data[..]);
+    }));
+    executor.run();
+}
@@ -0,0 +1,862 @@
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    task::Poll,
+};
+
+use alloc::{vec, vec::Vec};
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use thiserror_no_std::Error;
+use x86_64::{instructions::interrupts, PhysAddr, VirtAddr};
+
+use super::disk::{check_bounds, BlockDevice, DiskError, DiskStats, BLOCK_SIZE, SECTOR_SIZE};
+use crate::{
+    error::{self, ErrorKind},
+    interrupts::{InterruptIndex, PICS},
+    io::{
+        self, BusMasterPorts, ATA_ALT_STATUS, ATA_COMMAND, ATA_CONTROL, ATA_DATA, ATA_DRIVE,
+        ATA_ERROR, ATA_LBA_HIGH, ATA_LBA_LOW, ATA_LBA_MID, ATA_SECTOR_COUNT, ATA_STATUS,
+    },
+    memory::{self, MmioError},
+    pci,
+    task::{
+        deferred::{self, WorkItem},
+        time,
+    },
+    timer,
+};
+
+const SECTORS_PER_BLOCK: usize = BLOCK_SIZE / SECTOR_SIZE;
+/// The frames DMA transfers go through, each a region of the descriptor table.
+const DMA_FRAMES: usize = 16;
+const FRAME_SIZE: usize = 4096;
+/// The most sectors moved by one command.
+pub const MAX_TRANSFER_SECTORS: usize = DMA_FRAMES * FRAME_SIZE / SECTOR_SIZE;
+/// How long a drive may take to answer.
+const TIMEOUT_MS: u64 = 2000;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+/// Keeps the drives from raising interrupts, which only DMA transfers wait for.
+const CONTROL_NIEN: u8 = 1 << 1;
+const CONTROL_SRST: u8 = 1 << 2;
+/// LBA addressing, along with the two bits which are always set.
+const DRIVE_LBA: u8 = 0xe0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_READ_DMA: u8 = 0xc8;
+const CMD_WRITE_DMA: u8 = 0xca;
+const CMD_FLUSH_CACHE: u8 = 0xe7;
+const CMD_IDENTIFY: u8 = 0xec;
+
+// The answer to IDENTIFY is 256 little-endian words, of which these are used
+const ID_CAPABILITIES: usize = 49;
+const CAP_DMA: u16 = 1 << 8;
+const CAP_LBA: u16 = 1 << 9;
+/// Two words holding the number of sectors reachable with 28-bit LBAs.
+const ID_LBA28_SECTORS: usize = 60;
+
+const BM_START: u8 = 1 << 0;
+/// Set for transfers from the drive to memory.
+const BM_TO_MEMORY: u8 = 1 << 3;
+const BM_ERROR: u8 = 1 << 1;
+const BM_INTERRUPT: u8 = 1 << 2;
+/// Marks the last region of the descriptor table.
+const PRD_END: u16 = 1 << 15;
+
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_IDE: u8 = 0x01;
+/// Set in the programming interface when the primary channel has been moved off the legacy
+/// ports.
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_BUS_MASTER: u8 = 1 << 7;
+const BUS_MASTER_BAR: u8 = 4;
+
+/// The channel, once [`AtaDisk::open`] has found its controller. Locked for as long as a command is
+/// sent or a blocking transfer runs, but not while an async transfer is awaited.
+static CHANNEL: Mutex<Option<Channel>> = Mutex::new(None);
+/// The bus master registers of the controller, for the interrupt handler.
+static BUS_MASTER: OnceCell<BusMasterPorts> = OnceCell::uninit();
+// Set by the interrupt handler when the DMA transfer running has ended, along with the status of
+// the drive and the bus master
+static DMA_DONE: AtomicBool = AtomicBool::new(false);
+static DRIVE_STATUS: AtomicU8 = AtomicU8::new(0);
+static DMA_STATUS: AtomicU8 = AtomicU8::new(0);
+/// The task awaiting an async transfer.
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Error, Debug)]
+pub enum AtaError {
+    #[error("no IDE controller on the PCI bus")]
+    NoController,
+    #[error("the IDE controller isn't at the legacy ports")]
+    NativeMode,
+    #[error("no ATA disk at {0}")]
+    NoDrive(&'static str),
+    #[error("{0} can't be addressed with LBAs")]
+    NoLba(&'static str),
+    #[error("DMA buffers: {0}")]
+    Mmio(#[from] MmioError),
+    #[error("{0}")]
+    Disk(#[from] DiskError),
+}
+
+impl error::Error for AtaError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NoController | Self::NoDrive(_) => ErrorKind::NotFound,
+            Self::NativeMode | Self::NoLba(_) => ErrorKind::Unsupported,
+            Self::Mmio(_) => ErrorKind::OutOfSpace,
+            Self::Disk(err) => err.kind(),
+        }
+    }
+
+    fn source(&self) -> Option<&dyn error::Error> {
+        match self {
+            Self::Disk(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// One of the two drives on the primary channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+impl Drive {
+    /// The name of the drive on the kernel command line, see [`cmdline`](crate::cmdline).
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Master => "hda",
+            Self::Slave => "hdb",
+        }
+    }
+
+    fn select_bit(self) -> u8 {
+        match self {
+            Self::Master => 0,
+            Self::Slave => DRIVE_SLAVE,
+        }
+    }
+}
+
+/// An entry of the descriptor table, a region of memory the bus master transfers.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Prd {
+    addr: u32,
+    /// In bytes, where 0 stands for 64 KiB.
+    len: u16,
+    flags: u16,
+}
+
+/// The descriptor table and the frames it points to, which are all a DMA transfer can move.
+struct DmaBuffers {
+    ports: BusMasterPorts,
+    table: (PhysAddr, VirtAddr),
+    frames: [(PhysAddr, VirtAddr); DMA_FRAMES],
+}
+
+impl DmaBuffers {
+    fn new(ports: BusMasterPorts) -> Result<Self, MmioError> {
+        let table = memory::alloc_dma_frame()?;
+        let mut frames = [table; DMA_FRAMES];
+        for frame in &mut frames {
+            *frame = memory::alloc_dma_frame()?;
+        }
+        Ok(Self {
+            ports,
+            table,
+            frames,
+        })
+    }
+
+    /// Points the descriptor table at the frames holding the first `len` bytes.
+    fn describe(&self, len: usize) {
+        let table = self.table.1.as_mut_ptr::<Prd>();
+        let mut remaining = len;
+        for (i, &(phys, _)) in self.frames.iter().enumerate() {
+            let len = remaining.min(FRAME_SIZE);
+            remaining -= len;
+            let prd = Prd {
+                addr: phys.as_u64() as u32,
+                len: len as u16,
+                flags: if remaining == 0 { PRD_END } else { 0 },
+            };
+            unsafe { table.add(i).write_volatile(prd) };
+            if remaining == 0 {
+                break;
+            }
+        }
+    }
+
+    fn copy_in(&self, data: &[u8]) {
+        for (chunk, &(_, virt)) in data.chunks(FRAME_SIZE).zip(&self.frames) {
+            let frame = virt.as_mut_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), frame, chunk.len()) };
+        }
+    }
+
+    fn copy_out(&self, buf: &mut [u8]) {
+        for (chunk, &(_, virt)) in buf.chunks_mut(FRAME_SIZE).zip(&self.frames) {
+            let frame = virt.as_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(frame, chunk.as_mut_ptr(), chunk.len()) };
+        }
+    }
+}
+
+/// An async transfer the controller has been given, which the future awaiting it finishes.
+struct InFlight {
+    id: u64,
+    sector: usize,
+    /// The bytes to copy out for a read, none for a write.
+    read: usize,
+}
+
+/// An async transfer which ended while its future wasn't polled, because another transfer had to
+/// start. Holds the data read.
+struct Parked {
+    id: u64,
+    result: Result<Vec<u8>, DiskError>,
+}
+
+/// The primary channel of the IDE controller. Commands go through the legacy ports, and transfers
+/// use DMA when the controller can do it.
+struct Channel {
+    dma: Option<DmaBuffers>,
+    in_flight: Option<InFlight>,
+    parked: Vec<Parked>,
+    next_id: u64,
+}
+
+impl Channel {
+    fn probe() -> Result<Self, AtaError> {
+        let controller = pci::find(CLASS_STORAGE, SUBCLASS_IDE).ok_or(AtaError::NoController)?;
+        if controller.prog_if & PROG_IF_PRIMARY_NATIVE != 0 {
+            return Err(AtaError::NativeMode);
+        }
+        // Without bus mastering, or with its registers in memory rather than at ports, the
+        // transfers are done with PIO
+        let bar = controller.bar(BUS_MASTER_BAR);
+        let dma = match controller.prog_if & PROG_IF_BUS_MASTER != 0 && bar & 1 == 1 {
+            true => {
+                controller.enable_bus_master();
+                let ports = unsafe { io::bus_master(bar as u16 & !0b11) };
+                let _ = BUS_MASTER.try_init_once(|| ports);
+                Some(DmaBuffers::new(ports)?)
+            }
+            false => None,
+        };
+        interrupts::without_interrupts(|| {
+            PICS.lock().unmask(InterruptIndex::PrimaryAta.irq());
+        });
+        Ok(Self {
+            dma,
+            in_flight: None,
+            parked: Vec::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Asks the drive for its size in sectors, and whether it can do DMA.
+    fn identify(&mut self, drive: Drive) -> Result<(usize, bool), AtaError> {
+        self.settle();
+        ATA_CONTROL.write(CONTROL_NIEN);
+        select(drive, 0);
+        for port in [ATA_SECTOR_COUNT, ATA_LBA_LOW, ATA_LBA_MID, ATA_LBA_HIGH] {
+            port.write(0);
+        }
+        ATA_COMMAND.write(CMD_IDENTIFY);
+        // Nothing answers for a missing drive, and a floating bus reads as all ones
+        if matches!(ATA_ALT_STATUS.read(), 0 | 0xff) {
+            return Err(AtaError::NoDrive(drive.name()));
+        }
+        let status = wait_ready(0)?;
+        // ATAPI drives fail IDENTIFY, leaving their signature in the LBA registers
+        if status & STATUS_ERR != 0 || ATA_LBA_MID.read() != 0 || ATA_LBA_HIGH.read() != 0 {
+            return Err(AtaError::NoDrive(drive.name()));
+        }
+        let mut id = [0; SECTOR_SIZE / 2];
+        for word in &mut id {
+            *word = ATA_DATA.read();
+        }
+        if id[ID_CAPABILITIES] & CAP_LBA == 0 {
+            return Err(AtaError::NoLba(drive.name()));
+        }
+        let sectors =
+            usize::from(id[ID_LBA28_SECTORS]) | usize::from(id[ID_LBA28_SECTORS + 1]) << 16;
+        Ok((sectors, id[ID_CAPABILITIES] & CAP_DMA != 0))
+    }
+
+    /// Sends a command for `count` sectors starting at `sector`, with interrupts from the drive
+    /// on or off.
+    fn command(drive: Drive, sector: usize, count: usize, command: u8, interrupt: bool) {
+        ATA_CONTROL.write(if interrupt { 0 } else { CONTROL_NIEN });
+        select(drive, sector);
+        // A count of 0 stands for 256 sectors
+        ATA_SECTOR_COUNT.write(count as u8);
+        ATA_LBA_LOW.write(sector as u8);
+        ATA_LBA_MID.write((sector >> 8) as u8);
+        ATA_LBA_HIGH.write((sector >> 16) as u8);
+        ATA_COMMAND.write(command);
+    }
+
+    /// Reads into `buf` starting at `sector`. The last sector may be read only in part.
+    fn read(&mut self, disk: &AtaDisk, sector: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        self.settle();
+        let chunks = buf.chunks_mut(MAX_TRANSFER_SECTORS * SECTOR_SIZE);
+        let result = chunks.enumerate().try_for_each(|(i, chunk)| {
+            let sector = sector + i * MAX_TRANSFER_SECTORS;
+            match (disk.dma, &self.dma) {
+                (true, Some(dma)) => {
+                    let count = chunk.len().div_ceil(SECTOR_SIZE);
+                    start_dma(dma, disk.drive, sector, count, false)?;
+                    wait_dma(sector)?;
+                    dma.copy_out(chunk);
+                    Ok(())
+                }
+                _ => read_pio(disk.drive, sector, chunk),
+            }
+        });
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+
+    /// Writes `data`, which has to be whole sectors, starting at `sector`.
+    fn write(&mut self, disk: &AtaDisk, sector: usize, data: &[u8]) -> Result<(), DiskError> {
+        self.settle();
+        let chunks = data.chunks(MAX_TRANSFER_SECTORS * SECTOR_SIZE);
+        let result = chunks.enumerate().try_for_each(|(i, chunk)| {
+            let sector = sector + i * MAX_TRANSFER_SECTORS;
+            match (disk.dma, &self.dma) {
+                (true, Some(dma)) => {
+                    dma.copy_in(chunk);
+                    start_dma(dma, disk.drive, sector, chunk.len() / SECTOR_SIZE, true)?;
+                    wait_dma(sector)
+                }
+                _ => write_pio(disk.drive, sector, chunk),
+            }
+        });
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+
+    fn flush(&mut self, drive: Drive) -> Result<(), DiskError> {
+        self.settle();
+        Self::command(drive, 0, 0, CMD_FLUSH_CACHE, false);
+        let result = wait_ready(0).and_then(|status| check(status, 0));
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+
+    /// Starts an async DMA transfer, with the data to write or nothing for a read. Returns the id
+    /// to finish it with, see [`Self::poll_async`].
+    fn start_async(
+        &mut self,
+        drive: Drive,
+        sector: usize,
+        count: usize,
+        data: Option<&[u8]>,
+    ) -> Result<u64, DiskError> {
+        self.settle();
+        let dma = self.dma.as_ref().expect("async transfer without DMA");
+        if let Some(data) = data {
+            dma.copy_in(data);
+        }
+        if let Err(err) = start_dma(dma, drive, sector, count, data.is_some()) {
+            self.reset();
+            return Err(err);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let read = match data {
+            Some(_) => 0,
+            None => count * SECTOR_SIZE,
+        };
+        self.in_flight = Some(InFlight { id, sector, read });
+        Ok(id)
+    }
+
+    /// Finishes the async transfer once it has ended, copying what it read into `buf`.
+    fn poll_async(&mut self, id: u64, buf: Option<&mut [u8]>) -> Poll<Result<(), DiskError>> {
+        if let Some(i) = self.parked.iter().position(|parked| parked.id == id) {
+            let parked = self.parked.swap_remove(i);
+            return Poll::Ready(parked.result.map(|data| {
+                if let Some(buf) = buf {
+                    buf.copy_from_slice(&data[..buf.len()]);
+                }
+            }));
+        }
+        let sector = match &self.in_flight {
+            Some(transfer) if transfer.id == id => transfer.sector,
+            // Dropped by a reset
+            _ => return Poll::Ready(Err(DiskError::Dma(0))),
+        };
+        if !DMA_DONE.load(Ordering::SeqCst) {
+            return Poll::Pending;
+        }
+        self.in_flight = None;
+        let result = finish_dma(sector);
+        match (&result, buf, &self.dma) {
+            (Ok(()), Some(buf), Some(dma)) => dma.copy_out(buf),
+            (Err(_), _, _) => self.reset(),
+            _ => {}
+        }
+        Poll::Ready(result)
+    }
+
+    /// Gives up on an async transfer which took too long, resetting the channel if it's still
+    /// running.
+    fn abandon(&mut self, id: u64) {
+        self.parked.retain(|parked| parked.id != id);
+        if self
+            .in_flight
+            .as_ref()
+            .is_some_and(|transfer| transfer.id == id)
+        {
+            self.in_flight = None;
+            self.reset();
+        }
+    }
+
+    /// Waits for the async transfer running, if there is one, so that another one can start. What
+    /// it read is kept for the future awaiting it, which is woken to take it.
+    fn settle(&mut self) {
+        let (Some(transfer), Some(dma)) = (self.in_flight.take(), &self.dma) else {
+            return;
+        };
+        let result = wait_dma(transfer.sector).map(|()| {
+            let mut data = vec![0; transfer.read];
+            dma.copy_out(&mut data);
+            data
+        });
+        if result.is_err() {
+            self.reset();
+        }
+        self.parked.push(Parked {
+            id: transfer.id,
+            result,
+        });
+        WAKER.wake();
+    }
+
+    /// Stops the DMA transfer running and resets both drives, so that the next command starts
+    /// from a known state. An async transfer in flight fails.
+    fn reset(&mut self) {
+        if let Some(dma) = &self.dma {
+            dma.ports.command.write(0);
+            dma.ports.status.write(BM_ERROR | BM_INTERRUPT);
+        }
+        if let Some(transfer) = self.in_flight.take() {
+            self.parked.push(Parked {
+                id: transfer.id,
+                result: Err(DiskError::Dma(transfer.sector)),
+            });
+            WAKER.wake();
+        }
+        // The reset bit has to be held for at least 5 microseconds
+        ATA_CONTROL.write(CONTROL_SRST | CONTROL_NIEN);
+        for _ in 0..5 {
+            io::io_wait();
+        }
+        ATA_CONTROL.write(CONTROL_NIEN);
+        let _ = wait_ready(0);
+        DMA_DONE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Selects the drive, along with the top 4 bits of the sector.
+fn select(drive: Drive, sector: usize) {
+    ATA_DRIVE.write(DRIVE_LBA | drive.select_bit() | (sector >> 24) as u8 & 0x0f);
+    // The drive takes 400 ns to answer, which reading the status 4 times waits for
+    for _ in 0..4 {
+        ATA_ALT_STATUS.read();
+    }
+}
+
+/// Waits until the drive isn't busy, returning its status.
+fn wait_ready(sector: usize) -> Result<u8, DiskError> {
+    let deadline = timer::millis() + TIMEOUT_MS;
+    loop {
+        let status = ATA_ALT_STATUS.read();
+        if status & STATUS_BSY == 0 {
+            return Ok(status);
+        }
+        if timer::millis() >= deadline {
+            return Err(DiskError::Timeout(sector));
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn check(status: u8, sector: usize) -> Result<(), DiskError> {
+    match status & (STATUS_ERR | STATUS_DF) {
+        0 => Ok(()),
+        _ => Err(DiskError::Device {
+            sector,
+            error: ATA_ERROR.read(),
+        }),
+    }
+}
+
+/// Waits for the drive to have the next sector of a PIO transfer ready.
+fn wait_data(sector: usize) -> Result<(), DiskError> {
+    let status = wait_ready(sector)?;
+    check(status, sector)?;
+    match status & STATUS_DRQ {
+        0 => Err(DiskError::Device { sector, error: 0 }),
+        _ => Ok(()),
+    }
+}
+
+fn read_pio(drive: Drive, sector: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+    let count = buf.len().div_ceil(SECTOR_SIZE);
+    Channel::command(drive, sector, count, CMD_READ_SECTORS, false);
+    for (i, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+        wait_data(sector + i)?;
+        let mut data = [0; SECTOR_SIZE];
+        for word in data.chunks_exact_mut(2) {
+            word.copy_from_slice(&ATA_DATA.read().to_le_bytes());
+        }
+        chunk.copy_from_slice(&data[..chunk.len()]);
+    }
+    Ok(())
+}
+
+fn write_pio(drive: Drive, sector: usize, data: &[u8]) -> Result<(), DiskError> {
+    Channel::command(
+        drive,
+        sector,
+        data.len() / SECTOR_SIZE,
+        CMD_WRITE_SECTORS,
+        false,
+    );
+    for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+        wait_data(sector + i)?;
+        for word in chunk.chunks_exact(2) {
+            ATA_DATA.write(u16::from_le_bytes([word[0], word[1]]));
+        }
+    }
+    let last = sector + data.len() / SECTOR_SIZE - 1;
+    wait_ready(last).and_then(|status| check(status, last))
+}
+
+/// Starts moving `count` sectors between the frames of `dma` and the drive. The interrupt handler
+/// tells when it's done.
+fn start_dma(
+    dma: &DmaBuffers,
+    drive: Drive,
+    sector: usize,
+    count: usize,
+    write: bool,
+) -> Result<(), DiskError> {
+    wait_ready(sector)?;
+    dma.describe(count * SECTOR_SIZE);
+    let direction = if write { 0 } else { BM_TO_MEMORY };
+    dma.ports.command.write(direction);
+    dma.ports.status.write(BM_ERROR | BM_INTERRUPT);
+    dma.ports.prdt.write(dma.table.0.as_u64() as u32);
+    DMA_DONE.store(false, Ordering::SeqCst);
+    let command = if write { CMD_WRITE_DMA } else { CMD_READ_DMA };
+    Channel::command(drive, sector, count, command, true);
+    dma.ports.command.write(direction | BM_START);
+    Ok(())
+}
+
+/// Waits for the DMA transfer running to end, halting the CPU until an interrupt comes.
+fn wait_dma(sector: usize) -> Result<(), DiskError> {
+    let deadline = timer::millis() + TIMEOUT_MS;
+    loop {
+        // Interrupts are disabled so that the transfer can't end between the check and the `hlt`
+        interrupts::disable();
+        if DMA_DONE.load(Ordering::SeqCst) {
+            interrupts::enable();
+            return finish_dma(sector);
+        }
+        if timer::millis() >= deadline {
+            interrupts::enable();
+            return Err(DiskError::Timeout(sector));
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Checks how the DMA transfer which has ended went.
+fn finish_dma(sector: usize) -> Result<(), DiskError> {
+    check(DRIVE_STATUS.load(Ordering::SeqCst), sector)?;
+    match DMA_STATUS.load(Ordering::SeqCst) & BM_ERROR {
+        0 => Ok(()),
+        _ => Err(DiskError::Dma(sector)),
+    }
+}
+
+fn with_channel<R>(f: impl FnOnce(&mut Channel) -> R) -> R {
+    f(CHANNEL
+        .lock()
+        .as_mut()
+        .expect("ATA channel used before it was found"))
+}
+
+/// Called for the interrupt of the primary channel. Marks the DMA transfer running as done, and
+/// has the task awaiting it woken by the deferred work task.
+pub fn handle_interrupt() {
+    // Reading the status acknowledges the interrupt, whichever command raised it
+    let status = ATA_STATUS.read();
+    let Ok(ports) = BUS_MASTER.try_get() else {
+        return;
+    };
+    let dma_status = ports.status.read();
+    if dma_status & BM_INTERRUPT == 0 {
+        return;
+    }
+    ports.command.write(ports.command.read() & !BM_START);
+    ports.status.write(BM_ERROR | BM_INTERRUPT);
+    DRIVE_STATUS.store(status, Ordering::SeqCst);
+    DMA_STATUS.store(dma_status, Ordering::SeqCst);
+    DMA_DONE.store(true, Ordering::SeqCst);
+    // A full queue only delays the wake until the timeout, which still finds the transfer done
+    let _ = deferred::submit(WorkItem::Call(wake));
+}
+
+fn wake() {
+    WAKER.wake();
+}
+
+/// A drive on the primary channel of the IDE controller, as a device with blocks of
+/// [`BLOCK_SIZE`] bytes. Transfers use bus master DMA when the controller and the drive can do
+/// it, and PIO otherwise.
+///
+/// The blocking transfers of [`BlockDevice`] halt the CPU until the drive is done.
+/// [`read_async`](Self::read_async) and [`write_async`](Self::write_async) let other tasks run
+/// meanwhile instead, the transfer is finished when its interrupt wakes the task. Only one
+/// transfer runs at a time, so a transfer started while an async one is awaited waits for it.
+pub struct AtaDisk {
+    drive: Drive,
+    sectors: usize,
+    dma: bool,
+    reads: AtomicUsize,
+    blocks_read: AtomicUsize,
+    writes: usize,
+}
+
+impl AtaDisk {
+    /// Finds the drive, along with the IDE controller the first time. Drives which aren't ATA
+    /// disks, like CD drives, are reported as missing.
+    pub fn open(drive: Drive) -> Result<Self, AtaError> {
+        let mut channel = CHANNEL.lock();
+        if channel.is_none() {
+            *channel = Some(Channel::probe()?);
+        }
+        let channel = channel.as_mut().unwrap();
+        let (sectors, dma) = channel.identify(drive)?;
+        Ok(Self {
+            drive,
+            sectors,
+            dma: dma && channel.dma.is_some(),
+            reads: AtomicUsize::new(0),
+            blocks_read: AtomicUsize::new(0),
+            writes: 0,
+        })
+    }
+
+    pub fn drive(&self) -> Drive {
+        self.drive
+    }
+
+    /// Returns `true` if transfers use DMA rather than PIO.
+    pub fn uses_dma(&self) -> bool {
+        self.dma
+    }
+
+    /// Checks that `len` bytes starting at the block are on the disk.
+    fn check_range(&self, block: usize, len: usize) -> Result<(), DiskError> {
+        let last = block + len.saturating_sub(1) / BLOCK_SIZE;
+        match last < self.size() {
+            true => Ok(()),
+            false => Err(DiskError::BlockOutOfBounds(last)),
+        }
+    }
+
+    /// Reads consecutive blocks starting at `block` into `buf`, letting other tasks run while the
+    /// drive works. Without DMA, the blocks are read like [`BlockDevice::read_blocks`].
+    pub async fn read_async(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        self.check_range(block, buf.len())?;
+        if !self.dma {
+            return with_channel(|channel| channel.read(self, block * SECTORS_PER_BLOCK, buf));
+        }
+        let first = block * SECTORS_PER_BLOCK;
+        for (i, chunk) in buf
+            .chunks_mut(MAX_TRANSFER_SECTORS * SECTOR_SIZE)
+            .enumerate()
+        {
+            let sector = first + i * MAX_TRANSFER_SECTORS;
+            let count = chunk.len().div_ceil(SECTOR_SIZE);
+            let id = with_channel(|channel| channel.start_async(self.drive, sector, count, None))?;
+            wait_async(id, sector, Some(chunk)).await?;
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+        self.blocks_read
+            .fetch_add(buf.len().div_ceil(BLOCK_SIZE), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Writes `data`, which has to be whole sectors, to consecutive blocks starting at `block`,
+    /// like [`read_async`](Self::read_async).
+    pub async fn write_async(&mut self, block: usize, data: &[u8]) -> Result<(), DiskError> {
+        self.check_range(block, data.len())?;
+        if !data.len().is_multiple_of(SECTOR_SIZE) {
+            return Err(DiskError::PartialSector(data.len()));
+        }
+        if !self.dma {
+            return with_channel(|channel| channel.write(self, block * SECTORS_PER_BLOCK, data));
+        }
+        let first = block * SECTORS_PER_BLOCK;
+        for (i, chunk) in data.chunks(MAX_TRANSFER_SECTORS * SECTOR_SIZE).enumerate() {
+            let sector = first + i * MAX_TRANSFER_SECTORS;
+            let count = chunk.len() / SECTOR_SIZE;
+            let id = with_channel(|channel| {
+                channel.start_async(self.drive, sector, count, Some(chunk))
+            })?;
+            wait_async(id, sector, None).await?;
+            self.writes += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the async transfer to be finished, see [`Channel::poll_async`].
+async fn wait_async(id: u64, sector: usize, mut buf: Option<&mut [u8]>) -> Result<(), DiskError> {
+    let done = poll_fn(|cx| {
+        WAKER.register(cx.waker());
+        with_channel(|channel| channel.poll_async(id, buf.as_deref_mut()))
+    });
+    match time::timeout(timer::millis_to_ticks(TIMEOUT_MS), done).await {
+        Ok(result) => result,
+        Err(_) => {
+            with_channel(|channel| channel.abandon(id));
+            Err(DiskError::Timeout(sector))
+        }
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn read(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        check_bounds(self, block, 0, buf.len())?;
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.blocks_read.fetch_add(1, Ordering::Relaxed);
+        with_channel(|channel| channel.read(self, block * SECTORS_PER_BLOCK, buf))
+    }
+
+    fn read_blocks(&self, start: usize, bufs: &mut [[u8; BLOCK_SIZE]]) -> Result<(), DiskError> {
+        self.check_range(start, bufs.len() * BLOCK_SIZE)?;
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.blocks_read.fetch_add(bufs.len(), Ordering::Relaxed);
+        let buf = bufs.as_flattened_mut();
+        with_channel(|channel| channel.read(self, start * SECTORS_PER_BLOCK, buf))
+    }
+
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), DiskError> {
+        self.write_at(block, 0, buf)
+    }
+
+    /// Writes the sectors `buf` falls in, reading those it only covers in part first.
+    fn write_at(&mut self, block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
+        check_bounds(self, block, offset, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.writes += 1;
+        let sector = block * SECTORS_PER_BLOCK + offset / SECTOR_SIZE;
+        let head = offset % SECTOR_SIZE;
+        if head == 0 && buf.len().is_multiple_of(SECTOR_SIZE) {
+            return with_channel(|channel| channel.write(self, sector, buf));
+        }
+        let mut data = vec![0; (head + buf.len()).div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+        with_channel(|channel| {
+            channel.read(self, sector, &mut data)?;
+            data[head..head + buf.len()].copy_from_slice(buf);
+            channel.write(self, sector, &data)
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.sectors / SECTORS_PER_BLOCK
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn stats(&self) -> DiskStats {
+        DiskStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            blocks_read: self.blocks_read.load(Ordering::Relaxed),
+            writes: self.writes,
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), DiskError> {
+        with_channel(|channel| channel.flush(self.drive))
+    }
+}
+
+// QEMU is run with a blank disk as the primary slave for these, the primary master being the boot
+// disk
+#[test_case]
+fn test_dma_round_trip() {
+    let mut disk = AtaDisk::open(Drive::Slave).unwrap();
+    assert!(disk.uses_dma());
+    assert!(disk.size() > 32);
+
+    // More blocks than one transfer moves, written and read back in one go
+    let blocks = MAX_TRANSFER_SECTORS / SECTORS_PER_BLOCK + 3;
+    let data = (0..blocks * BLOCK_SIZE)
+        .map(|i| (i / 3) as u8)
+        .collect::<Vec<_>>();
+    let bufs = data
+        .chunks(BLOCK_SIZE)
+        .map(|chunk| <&[u8; BLOCK_SIZE]>::try_from(chunk).unwrap())
+        .collect::<Vec<_>>();
+    disk.write_blocks(2, &bufs).unwrap();
+    let mut read = vec![[0; BLOCK_SIZE]; blocks];
+    disk.read_blocks(2, &mut read).unwrap();
+    assert!(read.as_flattened() == data);
+
+    // Writes within a sector keep the rest of it
+    disk.write_at(2, 10, &[0xaa; 4]).unwrap();
+    let mut buf = [0; 16];
+    BlockDevice::read(&disk, 2, &mut buf).unwrap();
+    assert_eq!(&buf[..10], &data[..10]);
+    assert_eq!(&buf[10..14], &[0xaa; 4]);
+    assert_eq!(&buf[14..], &data[14..16]);
+}
+
+#[test_case]
+fn test_errors_leave_channel_usable() {
+    let disk = AtaDisk::open(Drive::Slave).unwrap();
+    let mut buf = [0; BLOCK_SIZE];
+    assert_eq!(
+        BlockDevice::read(&disk, disk.size(), &mut buf),
+        Err(DiskError::BlockOutOfBounds(disk.size()))
+    );
+
+    // A sector past the end of the drive is refused by the drive itself, and the channel is reset
+    let result = with_channel(|channel| channel.read(&disk, disk.sectors, &mut buf));
+    assert!(matches!(result, Err(DiskError::Device { sector, .. }) if sector == disk.sectors));
+    BlockDevice::read(&disk, 0, &mut buf).unwrap();
+}
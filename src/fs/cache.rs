@@ -0,0 +1,384 @@
+use alloc::{vec, vec::Vec};
+
+use super::disk::{BlockDevice, DiskError, BLOCK_SIZE};
+use crate::allocator::{
+    slab::{SlabBox, SlabCache},
+    Locked,
+};
+
+/// Number of blocks kept in the block cache.
+pub const CACHE_BLOCKS: usize = 16;
+
+// Blocks are evicted and read constantly, so their buffers are recycled instead of going through
+// the general allocator
+static BUFFERS: Locked<SlabCache<[u8; BLOCK_SIZE]>> = Locked::new(SlabCache::new(CACHE_BLOCKS));
+
+/// Counters describing how well the block cache is doing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Blocks read ahead of time by [`BlockCache::prefetch`].
+    pub prefetched: usize,
+    /// Hits on prefetched blocks, counted once per block.
+    pub prefetch_hits: usize,
+    /// Dirty blocks written to the device by [`BlockCache::flush`].
+    pub flushed: usize,
+    /// Device requests the flushed blocks were written with, one per run of consecutive blocks.
+    pub flush_runs: usize,
+    /// The most blocks written with a single request.
+    pub largest_flush_run: usize,
+    /// Reads copying bytes out of a cached block, see [`BlockCache::read`].
+    pub copies: usize,
+    /// Cached blocks lent to a closure in place, see [`BlockCache::with_block`].
+    pub borrows: usize,
+}
+
+struct CacheEntry {
+    block: usize,
+    data: SlabBox<'static, [u8; BLOCK_SIZE]>,
+    /// Set until a prefetched block is read for the first time.
+    prefetched: bool,
+    /// Set while the block has changes which haven't been written to the device.
+    dirty: bool,
+    last_used: u64,
+}
+
+/// A write-back cache of whole disk blocks, evicting the least recently used block when full.
+/// Written blocks stay in the cache until [`flush`](BlockCache::flush) writes them to the device,
+/// or until they're evicted.
+pub struct BlockCache {
+    entries: Vec<CacheEntry>,
+    capacity: usize,
+    clock: u64,
+    stats: CacheStats,
+    /// Set while writes are refused, so that no block can become dirty.
+    read_only: bool,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Vec::with_capacity(capacity),
+            capacity,
+            clock: 0,
+            stats: CacheStats::default(),
+            read_only: false,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Makes [`write`](Self::write) and [`with_block_mut`](Self::with_block_mut) fail with
+    /// [`DiskError::WriteProtected`], or lets them change blocks again. Blocks which are already
+    /// dirty are still written by the next flush, so the cache is flushed before protecting it.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn contains(&self, block: usize) -> bool {
+        self.entries.iter().any(|entry| entry.block == block)
+    }
+
+    /// Drops every cached block. Changes which haven't been flushed are lost.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Drops the blocks without changes and gives the buffers which are no longer used back to the
+    /// heap, returning the number of bytes given back. Doesn't allocate, so it can be used to
+    /// reclaim memory when the heap runs out.
+    pub fn shrink(&mut self) -> usize {
+        self.entries.retain(|entry| entry.dirty);
+        BUFFERS.try_shrink()
+    }
+
+    /// Reads part of a block, fetching the whole block from the device if it isn't cached.
+    pub fn read(
+        &mut self,
+        device: &mut (impl BlockDevice + ?Sized),
+        block: usize,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), DiskError> {
+        let entry = self.lookup(device, block)?;
+        buf.copy_from_slice(&entry.data[offset..offset + buf.len()]);
+        self.stats.copies += 1;
+        Ok(())
+    }
+
+    /// Runs `f` on the bytes of a block where they are in the cache, fetching the block from the
+    /// device if it isn't cached. The cache is borrowed for as long as `f` runs, so the block
+    /// can't be evicted meanwhile, and `f` can't use the cache itself.
+    pub fn with_block<R>(
+        &mut self,
+        device: &mut (impl BlockDevice + ?Sized),
+        block: usize,
+        f: impl FnOnce(&[u8; BLOCK_SIZE]) -> R,
+    ) -> Result<R, DiskError> {
+        let entry = self.lookup(device, block)?;
+        let result = f(&entry.data);
+        self.stats.borrows += 1;
+        Ok(result)
+    }
+
+    /// Runs `f` on the bytes of a block like [`Self::with_block`], letting it change them. The
+    /// block is marked dirty, to be written to the device by the next flush.
+    pub fn with_block_mut<R>(
+        &mut self,
+        device: &mut (impl BlockDevice + ?Sized),
+        block: usize,
+        f: impl FnOnce(&mut [u8; BLOCK_SIZE]) -> R,
+    ) -> Result<R, DiskError> {
+        if self.read_only {
+            return Err(DiskError::WriteProtected(block));
+        }
+        let entry = self.lookup(device, block)?;
+        entry.dirty = true;
+        let result = f(&mut entry.data);
+        self.stats.borrows += 1;
+        Ok(result)
+    }
+
+    /// Returns the entry of a block, fetching the block from the device if it isn't cached.
+    fn lookup(
+        &mut self,
+        device: &mut (impl BlockDevice + ?Sized),
+        block: usize,
+    ) -> Result<&mut CacheEntry, DiskError> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = match self.entries.iter().position(|entry| entry.block == block) {
+            Some(i) => {
+                let entry = &mut self.entries[i];
+                self.stats.hits += 1;
+                if entry.prefetched {
+                    self.stats.prefetch_hits += 1;
+                    entry.prefetched = false;
+                }
+                entry
+            }
+            None => {
+                self.stats.misses += 1;
+                let mut data = [0; BLOCK_SIZE];
+                device.read(block, &mut data)?;
+                self.insert(device, block, data, false)?
+            }
+        };
+        entry.last_used = clock;
+        Ok(entry)
+    }
+
+    /// Writes part of a block to the cache, to be written to the device by the next flush. The
+    /// rest of the block is read from the device first unless it's cached, or the whole block is
+    /// written.
+    pub fn write(
+        &mut self,
+        device: &mut (impl BlockDevice + ?Sized),
+        block: usize,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::WriteProtected(block));
+        }
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = match self.entries.iter_mut().find(|entry| entry.block == block) {
+            Some(entry) => entry,
+            None => {
+                let mut data = [0; BLOCK_SIZE];
+                if buf.len() != BLOCK_SIZE {
+                    device.read(block, &mut data)?;
+                }
+                self.insert(device, block, data, false)?
+            }
+        };
+        entry.last_used = clock;
+        entry.dirty = true;
+        entry.data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// Writes the dirty blocks to the device in the order of their block numbers, with one request
+    /// per run of consecutive blocks. Blocks are only marked clean once their run is written, so a
+    /// failed flush can be tried again.
+    pub fn flush(&mut self, device: &mut (impl BlockDevice + ?Sized)) -> Result<(), DiskError> {
+        let mut dirty = (0..self.entries.len())
+            .filter(|&i| self.entries[i].dirty)
+            .map(|i| (self.entries[i].block, i))
+            .collect::<Vec<_>>();
+        dirty.sort_unstable();
+
+        for run in dirty.chunk_by(|(a, _), (b, _)| a + 1 == *b) {
+            let bufs = run
+                .iter()
+                .map(|&(_, i)| &*self.entries[i].data)
+                .collect::<Vec<_>>();
+            device.write_blocks(run[0].0, &bufs)?;
+            for &(_, i) in run {
+                self.entries[i].dirty = false;
+            }
+            self.stats.flushed += run.len();
+            self.stats.flush_runs += 1;
+            self.stats.largest_flush_run = self.stats.largest_flush_run.max(run.len());
+        }
+        Ok(())
+    }
+
+    /// Reads the blocks which aren't cached yet, with one device request per run of consecutive
+    /// blocks. At most half the cache is filled, so that read-ahead can't evict the blocks the
+    /// reader is using.
+    pub fn prefetch(
+        &mut self,
+        device: &mut (impl BlockDevice + ?Sized),
+        blocks: &[usize],
+    ) -> Result<(), DiskError> {
+        let missing = blocks
+            .iter()
+            .copied()
+            .filter(|&block| !self.contains(block))
+            .take(self.capacity / 2)
+            .collect::<Vec<_>>();
+        let mut missing = missing.into_iter().peekable();
+
+        while let Some(start) = missing.next() {
+            let mut len = 1;
+            while missing.next_if_eq(&(start + len)).is_some() {
+                len += 1;
+            }
+
+            let mut data = vec![[0; BLOCK_SIZE]; len];
+            device.read_blocks(start, &mut data)?;
+            for (i, data) in data.into_iter().enumerate() {
+                self.clock += 1;
+                self.insert(device, start + i, data, true)?;
+            }
+            self.stats.prefetched += len;
+        }
+        Ok(())
+    }
+
+    /// Adds a block to the cache. The evicted block is written to the device first if it's dirty.
+    fn insert(
+        &mut self,
+        device: &mut (impl BlockDevice + ?Sized),
+        block: usize,
+        data: [u8; BLOCK_SIZE],
+        prefetched: bool,
+    ) -> Result<&mut CacheEntry, DiskError> {
+        let entry = CacheEntry {
+            block,
+            data: BUFFERS
+                .alloc(data)
+                .expect("out of memory for block cache buffers"),
+            prefetched,
+            dirty: false,
+            last_used: self.clock,
+        };
+        if self.entries.len() < self.capacity {
+            self.entries.push(entry);
+            return Ok(self.entries.last_mut().unwrap());
+        }
+
+        let lru = self
+            .entries
+            .iter_mut()
+            .min_by_key(|entry| entry.last_used)
+            .unwrap();
+        if lru.dirty {
+            device.write(lru.block, &*lru.data)?;
+        }
+        *lru = entry;
+        Ok(lru)
+    }
+}
+
+#[test_case]
+fn test_flush_merges_runs() {
+    /// Records the requests of a flush as `(start, blocks)`.
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<(usize, usize)>,
+    }
+
+    impl BlockDevice for Recorder {
+        fn read(&self, _block: usize, _buf: &mut [u8]) -> Result<(), DiskError> {
+            Ok(())
+        }
+        fn write(&mut self, block: usize, _buf: &[u8]) -> Result<(), DiskError> {
+            self.writes.push((block, 1));
+            Ok(())
+        }
+        fn write_blocks(
+            &mut self,
+            start: usize,
+            bufs: &[&[u8; BLOCK_SIZE]],
+        ) -> Result<(), DiskError> {
+            self.writes.push((start, bufs.len()));
+            Ok(())
+        }
+        fn size(&self) -> usize {
+            32
+        }
+        fn block_size(&self) -> usize {
+            BLOCK_SIZE
+        }
+    }
+
+    let mut device = Recorder::default();
+    let mut cache = BlockCache::new(CACHE_BLOCKS);
+    for block in [5, 6, 7, 20, 21, 3] {
+        cache
+            .write(&mut device, block, 0, &[block as u8; BLOCK_SIZE])
+            .unwrap();
+    }
+    // A partial write to a dirty block doesn't add a block to the flush
+    cache.write(&mut device, 6, 10, &[1; 4]).unwrap();
+    assert!(device.writes.is_empty());
+
+    cache.flush(&mut device).unwrap();
+    assert_eq!(device.writes, [(3, 1), (5, 3), (20, 2)]);
+    let stats = cache.stats();
+    assert_eq!(
+        (stats.flushed, stats.flush_runs, stats.largest_flush_run),
+        (6, 3, 3)
+    );
+
+    // Nothing is left to write
+    cache.flush(&mut device).unwrap();
+    assert_eq!(device.writes.len(), 3);
+}
+
+#[test_case]
+fn test_with_block() {
+    use super::disk::Disk;
+
+    let mut device = Disk::new(4);
+    let mut cache = BlockCache::new(2);
+    cache.write(&mut device, 1, 0, &[7; BLOCK_SIZE]).unwrap();
+    let sum = cache
+        .with_block(&mut device, 1, |data| {
+            data.iter().map(|&b| b as usize).sum::<usize>()
+        })
+        .unwrap();
+    assert_eq!(sum, 7 * BLOCK_SIZE);
+
+    // A changed block is dirty, so it's written by the next flush
+    cache
+        .with_block_mut(&mut device, 2, |data| data[..3].copy_from_slice(b"abc"))
+        .unwrap();
+    assert_eq!((cache.stats().borrows, cache.stats().copies), (2, 0));
+    cache.flush(&mut device).unwrap();
+    cache.clear();
+    let mut buf = [0; 4];
+    cache.read(&mut device, 2, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc\0");
+    assert_eq!((cache.stats().borrows, cache.stats().copies), (2, 1));
+}
@@ -0,0 +1,642 @@
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use super::{
+    disk::BLOCK_SIZE,
+    file::{FileSystem, FileSystemError, INumber, InodeKind, ROOT_INUMBER},
+    proc,
+};
+use crate::util::fnv::fnv1a;
+
+/// The longest file name that fits in a directory entry.
+pub const MAX_NAME_LEN: usize = u8::MAX as usize;
+
+/// The most directories with a lookup index at a time. The least recently used index is dropped
+/// to make room for another.
+const MAX_INDEXED_DIRS: usize = 8;
+
+/// The version of the directory blocks written by this kernel. Records of newer versions can
+/// still be read, as fields they add after the name are skipped using the record length.
+/// Changes which can't be read that way need a feature flag in the superblock.
+const DIR_VERSION: u8 = 1;
+
+// Each block of a directory starts with a header
+// [VERSION (1 byte), RESERVED (1 byte), RECORD_COUNT (2 bytes, LE)],
+// followed by the records of its entries
+// [RECORD_LEN (2 bytes, LE), INUMBER (4 bytes, LE), KIND (1 byte), NAME_LEN (1 byte), NAME, ...].
+// The record length covers the whole record including any fields after the name. The rest of the
+// block is zeroes.
+const HEADER_SIZE: usize = 4;
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// An entry in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inumber: INumber,
+    pub kind: InodeKind,
+}
+
+/// An entry as it's stored in a directory block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    entry: DirEntry,
+    /// The fields after the name, added by a newer version. They're written back as they were.
+    extra: Vec<u8>,
+}
+
+/// A block of a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DirBlock {
+    version: u8,
+    records: Vec<Record>,
+}
+
+impl Record {
+    fn len(&self) -> usize {
+        RECORD_HEADER_SIZE + self.entry.name.len() + self.extra.len()
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        let entry = &self.entry;
+        bytes.extend_from_slice(&(self.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&entry.inumber.to_le_bytes());
+        bytes.push(entry.kind as u8);
+        bytes.push(entry.name.len() as u8);
+        bytes.extend_from_slice(entry.name.as_bytes());
+        bytes.extend_from_slice(&self.extra);
+    }
+}
+
+impl DirBlock {
+    fn new() -> Self {
+        Self {
+            version: DIR_VERSION,
+            records: Vec::new(),
+        }
+    }
+
+    /// Parses block number `block` of directory `dir`. Fails if a record or its name runs past
+    /// the end of the block or of the record.
+    fn parse(dir: INumber, block: usize, bytes: &[u8]) -> Result<Self, FileSystemError> {
+        let corrupt = |reason| FileSystemError::CorruptDirectory { dir, block, reason };
+        if bytes.len() != BLOCK_SIZE {
+            return Err(corrupt("the directory ends partway through the block"));
+        }
+        let version = bytes[0];
+        if version == 0 {
+            return Err(corrupt("the block has no header"));
+        }
+        let count = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+
+        let mut records = Vec::with_capacity(count);
+        let mut pos = HEADER_SIZE;
+        for _ in 0..count {
+            if pos + RECORD_HEADER_SIZE > bytes.len() {
+                return Err(corrupt("a record runs past the end of the block"));
+            }
+            let record = &bytes[pos..];
+            let len = u16::from_le_bytes([record[0], record[1]]) as usize;
+            if len < RECORD_HEADER_SIZE {
+                return Err(corrupt("a record is shorter than its header"));
+            }
+            if pos + len > bytes.len() {
+                return Err(corrupt("a record runs past the end of the block"));
+            }
+            let name_len = record[7] as usize;
+            if name_len == 0 {
+                return Err(corrupt("an entry has no name"));
+            }
+            if RECORD_HEADER_SIZE + name_len > len {
+                return Err(corrupt("a name runs past the end of its record"));
+            }
+            let name = &record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + name_len];
+            records.push(Record {
+                entry: DirEntry {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    inumber: INumber::from_le_bytes(record[2..6].try_into().unwrap()),
+                    kind: InodeKind::from_u8(record[6]).unwrap_or(InodeKind::File),
+                },
+                extra: record[RECORD_HEADER_SIZE + name_len..len].to_vec(),
+            });
+            pos += len;
+        }
+        Ok(Self { version, records })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BLOCK_SIZE);
+        bytes.extend_from_slice(&[self.version, 0]);
+        bytes.extend_from_slice(&(self.records.len() as u16).to_le_bytes());
+        for record in &self.records {
+            record.write_to(&mut bytes);
+        }
+        bytes.resize(BLOCK_SIZE, 0);
+        bytes
+    }
+
+    /// Whether a record of `len` bytes fits in the block.
+    fn has_room(&self, len: usize) -> bool {
+        let used = HEADER_SIZE + self.records.iter().map(Record::len).sum::<usize>();
+        used + len <= BLOCK_SIZE
+    }
+
+    fn entries(self) -> impl Iterator<Item = DirEntry> {
+        self.records.into_iter().map(|record| record.entry)
+    }
+}
+
+/// Counters describing how names are looked up in directories, see
+/// [`FileSystem::dir_index_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirIndexStats {
+    /// Indexes built by scanning a whole directory.
+    pub builds: usize,
+    /// Lookups answered using an index.
+    pub indexed: usize,
+    /// Lookups which scanned the whole directory.
+    pub scans: usize,
+    /// Directory blocks read by lookups.
+    pub blocks_read: usize,
+    /// Names of entries compared with the name looked up.
+    pub comparisons: usize,
+}
+
+/// Where the record of an entry with a name hashing to `hash` starts in its directory.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    hash: u32,
+    block: usize,
+    offset: usize,
+}
+
+/// A hash table from the names in a directory to their records, using open addressing with
+/// linear probing. It's at most half full, so that probes stay short.
+struct DirIndex {
+    slots: Vec<Option<Slot>>,
+}
+
+impl DirIndex {
+    fn build(blocks: &[DirBlock]) -> Self {
+        let entries = blocks
+            .iter()
+            .map(|block| block.records.len())
+            .sum::<usize>();
+        let mut index = Self {
+            slots: vec![None; (entries * 2).next_power_of_two().max(8)],
+        };
+        for (block, dir_block) in blocks.iter().enumerate() {
+            let mut offset = HEADER_SIZE;
+            for record in &dir_block.records {
+                let hash = fnv1a(record.entry.name.as_bytes());
+                index.insert(Slot {
+                    hash,
+                    block,
+                    offset,
+                });
+                offset += record.len();
+            }
+        }
+        index
+    }
+
+    fn insert(&mut self, slot: Slot) {
+        let mask = self.slots.len() - 1;
+        let mut i = slot.hash as usize & mask;
+        while self.slots[i].is_some() {
+            i = (i + 1) & mask;
+        }
+        self.slots[i] = Some(slot);
+    }
+
+    /// Returns the records of the names hashing to `hash`. Different names may hash the same, so
+    /// the names still have to be compared.
+    fn candidates(&self, hash: u32) -> impl Iterator<Item = Slot> + '_ {
+        let mask = self.slots.len() - 1;
+        (0..self.slots.len())
+            .map_while(move |n| self.slots[(hash as usize + n) & mask])
+            .filter(move |slot| slot.hash == hash)
+    }
+}
+
+/// The lookup indexes of the directories looked up most recently. They only live in memory, and
+/// an index is dropped whenever its directory is written to.
+#[derive(Default)]
+pub(super) struct DirIndexes {
+    indexes: Vec<(INumber, DirIndex, u64)>,
+    clock: u64,
+    stats: DirIndexStats,
+}
+
+impl DirIndexes {
+    fn get(&mut self, dir: INumber) -> Option<&DirIndex> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (_, index, last_used) = self.indexes.iter_mut().find(|(d, ..)| *d == dir)?;
+        *last_used = clock;
+        Some(index)
+    }
+
+    fn insert(&mut self, dir: INumber, index: DirIndex) {
+        self.remove(dir);
+        if self.indexes.len() == MAX_INDEXED_DIRS {
+            let lru = (0..self.indexes.len())
+                .min_by_key(|&i| self.indexes[i].2)
+                .unwrap();
+            self.indexes.swap_remove(lru);
+        }
+        self.clock += 1;
+        self.indexes.push((dir, index, self.clock));
+        self.stats.builds += 1;
+    }
+
+    fn remove(&mut self, dir: INumber) {
+        self.indexes.retain(|(d, ..)| *d != dir);
+    }
+}
+
+impl FileSystem {
+    /// Returns all entries in the directory.
+    pub fn list(&self, dir: INumber) -> Result<Vec<DirEntry>, FileSystemError> {
+        if dir == proc::PROC_DIR {
+            return Ok(proc::list());
+        }
+        Ok(self
+            .read_dir_blocks(dir)?
+            .into_iter()
+            .flat_map(DirBlock::entries)
+            .collect())
+    }
+
+    /// Looks up the inode of the entry called `name` in the directory. `/proc` is found in the root
+    /// directory even though it has no entry there.
+    pub fn lookup(&self, dir: INumber, name: &str) -> Result<Option<INumber>, FileSystemError> {
+        match dir {
+            ROOT_INUMBER if name == proc::PROC_NAME => return Ok(Some(proc::PROC_DIR)),
+            proc::PROC_DIR => return Ok(proc::lookup(name)),
+            _ => {}
+        }
+        if let Some(found) = self.lookup_indexed(dir, name)? {
+            return Ok(found);
+        }
+
+        let blocks = self.read_dir_blocks(dir)?;
+        let mut indexes = self.dir_indexes.borrow_mut();
+        indexes.stats.scans += 1;
+        indexes.stats.blocks_read += blocks.len();
+        let mut found = None;
+        for entry in blocks.iter().flat_map(|block| &block.records) {
+            indexes.stats.comparisons += 1;
+            if entry.entry.name == name {
+                found = Some(entry.entry.inumber);
+                break;
+            }
+        }
+        indexes.insert(dir, DirIndex::build(&blocks));
+        Ok(found)
+    }
+
+    /// Looks up `name` using the index of the directory, reading only the records of the names
+    /// with the same hash. Returns `None` if the directory has no index, or if the index is out of
+    /// date because a record it points to doesn't hold a name with the hash it was filed under.
+    fn lookup_indexed(
+        &self,
+        dir: INumber,
+        name: &str,
+    ) -> Result<Option<Option<INumber>>, FileSystemError> {
+        let hash = fnv1a(name.as_bytes());
+        let candidates = match self.dir_indexes.borrow_mut().get(dir) {
+            Some(index) => index.candidates(hash).collect::<Vec<_>>(),
+            None => return Ok(None),
+        };
+
+        let mut stale = false;
+        for slot in candidates {
+            let pos = slot.block * BLOCK_SIZE + slot.offset;
+            let mut header = [0; RECORD_HEADER_SIZE];
+            let mut record_name = [0; MAX_NAME_LEN];
+            let read = self.read(dir, pos, &mut header).and_then(|read| {
+                let name_len = header[7] as usize;
+                let name_read = self.read(dir, pos + read, &mut record_name[..name_len])?;
+                Ok(read == RECORD_HEADER_SIZE && name_read == name_len)
+            });
+            let record_name = &record_name[..header[7] as usize];
+            {
+                let stats = &mut self.dir_indexes.borrow_mut().stats;
+                stats.blocks_read += 1;
+                stats.comparisons += 1;
+            }
+            if !matches!(read, Ok(true)) || fnv1a(record_name) != slot.hash {
+                stale = true;
+            } else if record_name == name.as_bytes() {
+                self.dir_indexes.borrow_mut().stats.indexed += 1;
+                let inumber = INumber::from_le_bytes(header[2..6].try_into().unwrap());
+                return Ok(Some(Some(inumber)));
+            }
+        }
+        if stale {
+            return Ok(None);
+        }
+        self.dir_indexes.borrow_mut().stats.indexed += 1;
+        Ok(Some(None))
+    }
+
+    pub fn dir_index_stats(&self) -> DirIndexStats {
+        self.dir_indexes.borrow().stats
+    }
+
+    /// Drops the lookup index of the file, if it's a directory with one, as the file is about to
+    /// change.
+    pub(super) fn forget_dir_index(&self, inumber: INumber) {
+        self.dir_indexes.borrow_mut().remove(inumber);
+    }
+
+    /// Adds another entry called `name` pointing to the file `inumber` to the directory, raising
+    /// the link count of the file. Directories can't be linked, so that the tree has no cycles.
+    pub fn link(
+        &mut self,
+        dir: INumber,
+        name: &str,
+        inumber: INumber,
+    ) -> Result<(), FileSystemError> {
+        if self.stat(inumber)?.kind == InodeKind::Directory {
+            return Err(FileSystemError::IsADirectory(name.to_string()));
+        }
+        self.add_link(inumber)?;
+        if let Err(err) = self.add_entry(dir, name, inumber) {
+            self.drop_link(inumber)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Removes the entry called `name` from the directory and returns the inode it pointed to. The
+    /// link count of the inode is lowered, and it's deleted if that was its last link. Directories
+    /// have to be empty.
+    pub fn unlink(&mut self, dir: INumber, name: &str) -> Result<INumber, FileSystemError> {
+        self.check_writable()?;
+        if let Some(inumber) = self.lookup(dir, name)? {
+            if self.stat(inumber)?.kind == InodeKind::Directory && !self.list(inumber)?.is_empty() {
+                return Err(FileSystemError::DirectoryNotEmpty(name.to_string()));
+            }
+        }
+        let inumber = self.remove_entry(dir, name)?;
+        self.drop_link(inumber)?;
+        Ok(inumber)
+    }
+
+    /// Adds an entry called `name` pointing to `inumber` to the directory, without changing the
+    /// link count. The kind of the entry is taken from the inode.
+    pub(super) fn add_entry(
+        &mut self,
+        dir: INumber,
+        name: &str,
+        inumber: INumber,
+    ) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        if name.len() > MAX_NAME_LEN {
+            return Err(FileSystemError::NameTooLong(name.to_string()));
+        }
+
+        let kind = self.stat(inumber)?.kind;
+        let mut blocks = self.read_dir_blocks(dir)?;
+        if blocks
+            .iter()
+            .flat_map(|block| &block.records)
+            .any(|record| record.entry.name == name)
+        {
+            return Err(FileSystemError::AlreadyExists(name.to_string()));
+        }
+
+        let record = Record {
+            entry: DirEntry {
+                name: name.to_string(),
+                inumber,
+                kind,
+            },
+            extra: Vec::new(),
+        };
+        // Add the entry to the first block with room for it, or to a new block
+        let index = match blocks.iter().position(|block| block.has_room(record.len())) {
+            Some(index) => index,
+            None => {
+                blocks.push(DirBlock::new());
+                blocks.len() - 1
+            }
+        };
+        blocks[index].records.push(record);
+        self.write_dir_block(dir, index, &blocks[index])
+    }
+
+    /// Removes the entry called `name` from the directory and returns the inode it pointed to,
+    /// without changing the link count.
+    pub(super) fn remove_entry(
+        &mut self,
+        dir: INumber,
+        name: &str,
+    ) -> Result<INumber, FileSystemError> {
+        self.check_writable()?;
+        let mut blocks = self.read_dir_blocks(dir)?;
+        let (index, position) = blocks
+            .iter()
+            .enumerate()
+            .find_map(|(index, block)| {
+                let position = block
+                    .records
+                    .iter()
+                    .position(|record| record.entry.name == name)?;
+                Some((index, position))
+            })
+            .ok_or_else(|| FileSystemError::NotFound(name.to_string()))?;
+        let record = blocks[index].records.remove(position);
+        self.write_dir_block(dir, index, &blocks[index])?;
+        Ok(record.entry.inumber)
+    }
+
+    /// Reads and parses every block of the directory.
+    fn read_dir_blocks(&self, dir: INumber) -> Result<Vec<DirBlock>, FileSystemError> {
+        if proc::is_proc(dir) {
+            return Err(proc::not_supported(dir));
+        }
+        let metadata = self.stat(dir)?;
+        if metadata.kind != InodeKind::Directory {
+            return Err(FileSystemError::NotADirectory(dir.to_string()));
+        }
+
+        let mut buf = vec![0; metadata.size];
+        self.read(dir, 0, &mut buf)?;
+        buf.chunks(BLOCK_SIZE)
+            .enumerate()
+            .map(|(block, bytes)| DirBlock::parse(dir, block, bytes))
+            .collect()
+    }
+
+    fn write_dir_block(
+        &mut self,
+        dir: INumber,
+        index: usize,
+        block: &DirBlock,
+    ) -> Result<(), FileSystemError> {
+        self.write_reserved(dir, index * BLOCK_SIZE, &block.to_bytes())?;
+        Ok(())
+    }
+}
+
+/// Builds a directory block with a record for each name, followed by `extra`.
+#[cfg(test)]
+fn craft_block(version: u8, names: &[&str], extra: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![version, 0];
+    bytes.extend_from_slice(&(names.len() as u16).to_le_bytes());
+    for (i, name) in names.iter().enumerate() {
+        let len = RECORD_HEADER_SIZE + name.len() + extra.len();
+        bytes.extend_from_slice(&(len as u16).to_le_bytes());
+        bytes.extend_from_slice(&(i as INumber + 1).to_le_bytes());
+        bytes.extend_from_slice(&[InodeKind::File as u8, name.len() as u8]);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(extra);
+    }
+    bytes.resize(BLOCK_SIZE, 0);
+    bytes
+}
+
+#[test_case]
+fn test_dir_block_skips_unknown_fields() {
+    // A block from a newer version, with a timestamp after each name
+    let extra = 0x1234_5678_u32.to_le_bytes();
+    let bytes = craft_block(DIR_VERSION + 1, &["a", "longer name"], &extra);
+    let block = DirBlock::parse(0, 0, &bytes).unwrap();
+    let names = block
+        .records
+        .iter()
+        .map(|record| (record.entry.name.as_str(), record.entry.inumber))
+        .collect::<Vec<_>>();
+    assert_eq!(names, [("a", 1), ("longer name", 2)]);
+    // The unknown fields are kept when the block is written back
+    assert_eq!(block.to_bytes(), bytes);
+
+    crate::fs::init().unwrap();
+    let mut fs = crate::fs::FILESYSTEM.lock();
+    let dir = fs.create(InodeKind::Directory).unwrap();
+    let file = fs.create(InodeKind::File).unwrap();
+    fs.write(dir, 0, &bytes).unwrap();
+    fs.link(dir, "new", file).unwrap();
+    // The crafted entries don't point at real files, so only the entry is removed
+    fs.remove_entry(dir, "a").unwrap();
+    let names = fs
+        .list(dir)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["longer name", "new"]);
+    let mut buf = vec![0; BLOCK_SIZE];
+    fs.read(dir, 0, &mut buf).unwrap();
+    let block = DirBlock::parse(dir, 0, &buf).unwrap();
+    assert_eq!(block.version, DIR_VERSION + 1);
+    assert_eq!(block.records[0].extra, extra);
+    assert!(block.records[1].extra.is_empty());
+}
+
+#[test_case]
+fn test_dir_block_rejects_bad_lengths() {
+    let corrupt = |bytes: &[u8]| match DirBlock::parse(7, 1, bytes) {
+        Err(FileSystemError::CorruptDirectory { dir, block, reason }) => {
+            assert_eq!((dir, block), (7, 1));
+            reason
+        }
+        other => panic!("expected a corrupt directory, got {:?}", other),
+    };
+
+    // The second record claims to run past the end of the block
+    let mut bytes = craft_block(DIR_VERSION, &["first", "second"], &[]);
+    let second = HEADER_SIZE + RECORD_HEADER_SIZE + "first".len();
+    bytes[second..second + 2].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+    assert_eq!(corrupt(&bytes), "a record runs past the end of the block");
+
+    // A name longer than its record
+    let mut bytes = craft_block(DIR_VERSION, &["first"], &[]);
+    bytes[HEADER_SIZE + 7] = 6;
+    assert_eq!(corrupt(&bytes), "a name runs past the end of its record");
+
+    // More records than fit in the block
+    let mut bytes = craft_block(DIR_VERSION, &["first"], &[]);
+    bytes[2..4].copy_from_slice(&u16::MAX.to_le_bytes());
+    assert!(DirBlock::parse(7, 1, &bytes).is_err());
+
+    assert_eq!(corrupt(&[0; BLOCK_SIZE]), "the block has no header");
+    assert_eq!(
+        corrupt(&[DIR_VERSION; 10]),
+        "the directory ends partway through the block"
+    );
+}
+
+#[test_case]
+fn test_dir_index() {
+    use alloc::format;
+
+    crate::fs::init().unwrap();
+    let mut fs = crate::fs::FILESYSTEM.lock();
+    let dir = fs.create_at("big", InodeKind::Directory).unwrap();
+    let file = fs.create_at("big/file0", InodeKind::File).unwrap();
+    for i in 1..500 {
+        fs.link(dir, &format!("file{}", i), file).unwrap();
+    }
+    let target = fs.create_at("big/target", InodeKind::File).unwrap();
+    let since = |before: DirIndexStats, fs: &FileSystem| {
+        let after = fs.dir_index_stats();
+        DirIndexStats {
+            builds: after.builds - before.builds,
+            indexed: after.indexed - before.indexed,
+            scans: after.scans - before.scans,
+            blocks_read: after.blocks_read - before.blocks_read,
+            comparisons: after.comparisons - before.comparisons,
+        }
+    };
+
+    // The first lookup scans the directory, building the index
+    let before = fs.dir_index_stats();
+    assert_eq!(fs.lookup(dir, "file377").unwrap(), Some(file));
+    let blocks = fs.stat(dir).unwrap().blocks;
+    assert!(blocks > 1);
+    assert_eq!(
+        since(before, &fs),
+        DirIndexStats {
+            builds: 1,
+            indexed: 0,
+            scans: 1,
+            blocks_read: blocks,
+            comparisons: 378,
+        }
+    );
+
+    // Later lookups compare a single name, wherever it is in the directory
+    let before = fs.dir_index_stats();
+    for name in ["file377", "file0", "file499"] {
+        assert_eq!(fs.lookup(dir, name).unwrap(), Some(file));
+    }
+    assert_eq!(fs.lookup(dir, "target").unwrap(), Some(target));
+    assert_eq!(fs.lookup(dir, "missing").unwrap(), None);
+    assert_eq!(
+        since(before, &fs),
+        DirIndexStats {
+            builds: 0,
+            indexed: 5,
+            scans: 0,
+            blocks_read: 4,
+            comparisons: 4,
+        }
+    );
+
+    // Renaming writes to the directory, which drops the index
+    fs.rename("big/target", "big/moved").unwrap();
+    let before = fs.dir_index_stats();
+    assert_eq!(fs.lookup(dir, "target").unwrap(), None);
+    assert_eq!(fs.lookup(dir, "moved").unwrap(), Some(target));
+    assert_eq!(fs.lookup(dir, "file42").unwrap(), Some(file));
+    let stats = since(before, &fs);
+    assert_eq!((stats.builds, stats.scans, stats.indexed), (1, 1, 2));
+    assert_eq!(fs.resolve("big/moved").unwrap(), target);
+}
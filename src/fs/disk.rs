@@ -1,30 +1,271 @@
-use core::fmt::Debug;
+use core::{
+    fmt::Debug,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use alloc::vec::Vec;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    vec,
+    vec::Vec,
+};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use thiserror_no_std::Error;
 
+use super::cache::{BlockCache, CacheStats, CACHE_BLOCKS};
+use crate::{
+    error::{self, ErrorKind},
+    print_warn,
+};
+
 pub const BLOCK_SIZE: usize = 0x1000;
+/// The sector size of ATA disks, see [`SectorAdapter`].
+pub const SECTOR_SIZE: usize = 512;
+
+/// The size of the simulated disk in blocks. The disk lives on the kernel heap, so this has to
+/// leave enough room for everything else on the heap.
+const DISK_BLOCKS: usize = 128;
 
 lazy_static! {
-    static ref DISK: Mutex<Disk> = Mutex::new(Disk::new(1024));
+    static ref DISK: Mutex<Box<dyn BlockDevice + Send>> =
+        Mutex::new(Box::new(Disk::new(DISK_BLOCKS)));
+    static ref CACHE: Mutex<BlockCache> = Mutex::new(BlockCache::new(CACHE_BLOCKS));
 }
 
-/// Read a block from the disk into a buffer.
+/// The blocks kept by the [`Snapshot`] being read, `None` if there is none.
+static SNAPSHOT: Mutex<Option<SnapshotBlocks>> = Mutex::new(None);
+
+/// Read a block from the disk into a buffer, going through the block cache.
 ///
-/// # Panics
-/// If the offset and length of the buffer exceed the block size.
+/// Fails if the offset and length of the buffer exceed the block size.
 pub fn read(block: usize, offset: usize, buf: &mut [u8]) -> Result<(), DiskError> {
-    DISK.lock().read(block, offset, buf)
+    let mut disk = DISK.lock();
+    check_bounds(&**disk, block, offset, buf.len())?;
+    read_locked(&mut **disk, block, offset, buf)
+}
+
+/// Reads like [`read`] once the disk is locked and the bounds are checked.
+fn read_locked(
+    disk: &mut (dyn BlockDevice + Send),
+    block: usize,
+    offset: usize,
+    buf: &mut [u8],
+) -> Result<(), DiskError> {
+    if disk.block_size() != BLOCK_SIZE {
+        // The filesystem won't mount on such a device, so it's only read to find that out and
+        // isn't worth caching
+        let mut data = vec![0; disk.block_size()];
+        disk.read(block, &mut data)?;
+        buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        return Ok(());
+    }
+    CACHE.lock().read(disk, block, offset, buf)
 }
 
-/// Write a buffer to a block on the disk.
+/// Runs `f` on the bytes of a block in the block cache, without copying them out of it. Used to
+/// read a block into a buffer with a single copy, see [`BlockCache::with_block`].
 ///
-/// # Panics
-/// If the offset and length of the buffer exceed the block size.
+/// The disk and the block cache stay locked while `f` runs, so `f` must not use the disk itself:
+/// the locks aren't reentrant, and a nested access would deadlock.
+pub fn with_block<R>(block: usize, f: impl FnOnce(&[u8; BLOCK_SIZE]) -> R) -> Result<R, DiskError> {
+    let mut disk = DISK.lock();
+    check_bounds(&**disk, block, 0, BLOCK_SIZE)?;
+    if disk.block_size() != BLOCK_SIZE {
+        let mut data = vec![0; disk.block_size()];
+        disk.read(block, &mut data)?;
+        return Ok(f(data[..BLOCK_SIZE].try_into().unwrap()));
+    }
+    CACHE.lock().with_block(&mut **disk, block, f)
+}
+
+/// Runs `f` on the bytes of a block in the block cache like [`with_block`], letting it change
+/// them. The change reaches the disk with the next [`flush`] at the latest.
+pub fn with_block_mut<R>(
+    block: usize,
+    f: impl FnOnce(&mut [u8; BLOCK_SIZE]) -> R,
+) -> Result<R, DiskError> {
+    let mut disk = DISK.lock();
+    check_bounds(&**disk, block, 0, BLOCK_SIZE)?;
+    preserve(&mut **disk, block)?;
+    if disk.block_size() != BLOCK_SIZE {
+        check_write_protected(block)?;
+        let mut data = vec![0; disk.block_size()];
+        disk.read(block, &mut data)?;
+        let result = f((&mut data[..BLOCK_SIZE]).try_into().unwrap());
+        disk.write(block, &data)?;
+        return Ok(result);
+    }
+    CACHE.lock().with_block_mut(&mut **disk, block, f)
+}
+
+/// Write a buffer to a block on the disk. The write goes to the block cache, and reaches the disk
+/// with the next [`flush`] at the latest.
+///
+/// Fails if the offset and length of the buffer exceed the block size.
 pub fn write(block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
-    DISK.lock().write(block, offset, buf)
+    let mut disk = DISK.lock();
+    check_bounds(&**disk, block, offset, buf.len())?;
+    preserve(&mut **disk, block)?;
+    if disk.block_size() != BLOCK_SIZE {
+        check_write_protected(block)?;
+        return disk.write_at(block, offset, buf);
+    }
+    CACHE.lock().write(&mut **disk, block, offset, buf)
+}
+
+/// Blocks of the disk as they were when a [`Snapshot`] was taken.
+struct SnapshotBlocks {
+    /// The blocks which haven't been read from the snapshot or changed yet.
+    pending: BTreeSet<usize>,
+    /// The blocks which were changed before they were read from the snapshot, as they were.
+    saved: BTreeMap<usize, Vec<u8>>,
+}
+
+/// The blocks given to [`snapshot`] as they were when it was called, even if they're written to
+/// while the snapshot is read. A block is copied before the first write to it, so only the blocks
+/// written meanwhile take up memory. Each block is read from the snapshot once: after that, reads
+/// and writes of it go to the disk as usual.
+pub struct Snapshot(());
+
+/// Takes a [`Snapshot`] of the blocks. Only one snapshot is kept at a time.
+pub fn snapshot(blocks: impl IntoIterator<Item = usize>) -> Result<Snapshot, DiskError> {
+    let mut snapshot = SNAPSHOT.lock();
+    if snapshot.is_some() {
+        return Err(DiskError::SnapshotInUse);
+    }
+    *snapshot = Some(SnapshotBlocks {
+        pending: blocks.into_iter().collect(),
+        saved: BTreeMap::new(),
+    });
+    Ok(Snapshot(()))
+}
+
+/// Copies the block into the snapshot before it's written to, if the snapshot still has to read
+/// it.
+fn preserve(disk: &mut (dyn BlockDevice + Send), block: usize) -> Result<(), DiskError> {
+    let mut snapshot = SNAPSHOT.lock();
+    let Some(snapshot) = snapshot.as_mut() else {
+        return Ok(());
+    };
+    if !snapshot.pending.contains(&block) {
+        return Ok(());
+    }
+    let mut data = vec![0; disk.block_size()];
+    read_locked(disk, block, 0, &mut data)?;
+    snapshot.pending.remove(&block);
+    snapshot.saved.insert(block, data);
+    Ok(())
+}
+
+impl BlockDevice for Snapshot {
+    fn read(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        let mut disk = DISK.lock();
+        check_bounds(&**disk, block, 0, buf.len())?;
+        let mut snapshot = SNAPSHOT.lock();
+        let snapshot = snapshot.as_mut().expect("snapshot is gone");
+        snapshot.pending.remove(&block);
+        match snapshot.saved.remove(&block) {
+            Some(saved) => {
+                buf.copy_from_slice(&saved[..buf.len()]);
+                Ok(())
+            }
+            None => read_locked(&mut **disk, block, 0, buf),
+        }
+    }
+
+    fn write(&mut self, block: usize, _buf: &[u8]) -> Result<(), DiskError> {
+        Err(DiskError::WriteProtected(block))
+    }
+
+    fn size(&self) -> usize {
+        size()
+    }
+
+    fn block_size(&self) -> usize {
+        block_size()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        SNAPSHOT.lock().take();
+    }
+}
+
+/// Waits until every write made so far has reached the disk. Writes made after a flush never reach
+/// the disk before the ones made before it, which the filesystem relies on for crash consistency.
+///
+/// The dirty blocks of the block cache are written in the order of their block numbers, see
+/// [`BlockCache::flush`].
+pub fn flush() -> Result<(), DiskError> {
+    let mut disk = DISK.lock();
+    CACHE.lock().flush(&mut **disk)?;
+    disk.flush()
+}
+
+/// Reads the blocks into the block cache ahead of time, see [`BlockCache::prefetch`].
+pub fn prefetch(blocks: &[usize]) -> Result<(), DiskError> {
+    let mut disk = DISK.lock();
+    CACHE.lock().prefetch(&mut **disk, blocks)
+}
+
+pub fn is_cached(block: usize) -> bool {
+    CACHE.lock().contains(block)
+}
+
+/// Flushes the block cache, then makes it refuse every write with [`DiskError::WriteProtected`]
+/// until [`write_enable`] is called, so that nothing can reach the disk. Used while the filesystem
+/// is mounted read-only.
+pub fn write_protect() -> Result<(), DiskError> {
+    flush()?;
+    CACHE.lock().set_read_only(true);
+    Ok(())
+}
+
+/// Lets writes reach the disk again after [`write_protect`].
+pub fn write_enable() {
+    CACHE.lock().set_read_only(false);
+}
+
+/// Fails for writes to devices which bypass the block cache while the cache is write-protected.
+fn check_write_protected(block: usize) -> Result<(), DiskError> {
+    match CACHE.lock().is_read_only() {
+        true => Err(DiskError::WriteProtected(block)),
+        false => Ok(()),
+    }
+}
+
+/// Drops every block from the block cache, so that the next reads go to the disk. Writes which
+/// haven't been flushed are lost, like on a power cut.
+pub fn invalidate_cache() {
+    CACHE.lock().clear();
+}
+
+/// Drops the clean blocks from the block cache when the heap runs out, see
+/// [`BlockCache::shrink`]. Registered with [`allocator::register_reclaim`], so it frees nothing
+/// while the cache is locked.
+///
+/// [`allocator::register_reclaim`]: crate::allocator::register_reclaim
+pub fn reclaim_cache() -> usize {
+    CACHE.try_lock().map_or(0, |mut cache| cache.shrink())
+}
+
+pub fn cache_stats() -> CacheStats {
+    CACHE.lock().stats()
+}
+
+/// Returns the number of requests the disk has served.
+pub fn stats() -> DiskStats {
+    DISK.lock().stats()
+}
+
+/// Requests served by the disk, not counting reads served by the block cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskStats {
+    pub reads: usize,
+    pub blocks_read: usize,
+    pub writes: usize,
 }
 
 /// Returns the size of the disk in blocks.
@@ -32,75 +273,437 @@ pub fn size() -> usize {
     DISK.lock().size()
 }
 
-struct DiskBlock {
-    data: [u8; BLOCK_SIZE],
+/// Returns the block size of the disk in bytes.
+pub fn block_size() -> usize {
+    DISK.lock().block_size()
 }
 
-struct Disk {
-    blocks: Vec<DiskBlock>,
+/// Replaces the disk the kernel filesystem lives on, returning the previous one. The block cache is
+/// flushed to the previous disk and cleared, the filesystem has to be mounted again.
+pub fn attach(device: Box<dyn BlockDevice + Send>) -> Box<dyn BlockDevice + Send> {
+    if let Err(err) = flush() {
+        print_warn!("unflushed writes to the detached disk were lost: {}", err);
+    }
+    let previous = core::mem::replace(&mut *DISK.lock(), device);
+    invalidate_cache();
+    previous
 }
 
+/// Called with the block number before each write to a [`Disk`], failing the write if it returns
+/// an error.
+pub type WriteHook = Box<dyn FnMut(usize) -> Result<(), DiskError> + Send>;
+
+/// A simulated disk kept in memory.
+pub struct Disk {
+    blocks: Vec<Box<[u8]>>,
+    block_size: usize,
+    reads: AtomicUsize,
+    blocks_read: AtomicUsize,
+    writes: usize,
+    write_hook: Option<WriteHook>,
+}
+
+/// A device storing data in fixed size blocks. Reads and writes start at the beginning of a block
+/// and may be shorter than a block.
 pub trait BlockDevice {
-    fn read(&self, block: usize, buf: &mut [u8]);
-    fn write(&mut self, block: usize, buf: &[u8]);
+    fn read(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError>;
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), DiskError>;
+    /// Returns the size of the device in blocks.
+    fn size(&self) -> usize;
+    /// Returns the size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Writes `buf` at `offset` within the block, leaving the rest of the block as it was.
+    fn write_at(&mut self, block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
+        if offset == 0 {
+            return self.write(block, buf);
+        }
+        let mut data = vec![0; offset + buf.len()];
+        self.read(block, &mut data[..offset])?;
+        data[offset..].copy_from_slice(buf);
+        self.write(block, &data)
+    }
+
+    /// Returns the number of read requests the device has served.
+    fn stats(&self) -> DiskStats {
+        DiskStats::default()
+    }
+
+    /// Waits until the writes made so far have reached the storage, for devices which cache or
+    /// reorder writes.
+    fn flush(&mut self) -> Result<(), DiskError> {
+        Ok(())
+    }
+
+    /// Reads consecutive blocks starting at `start`. Devices which can should do this with a
+    /// single request.
+    fn read_blocks(&self, start: usize, bufs: &mut [[u8; BLOCK_SIZE]]) -> Result<(), DiskError> {
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            self.read(start + i, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Writes consecutive blocks starting at `start`, like [`read_blocks`](Self::read_blocks).
+    fn write_blocks(&mut self, start: usize, bufs: &[&[u8; BLOCK_SIZE]]) -> Result<(), DiskError> {
+        for (i, buf) in bufs.iter().enumerate() {
+            self.write(start + i, *buf)?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, PartialEq, Eq)]
 pub enum DiskError {
     #[error("block {0} out of bounds")]
     BlockOutOfBounds(usize),
-    #[error("tried to write {0} bytes at offset {1}, which exceeds block size of {BLOCK_SIZE}")]
+    #[error("offset {0} is past the end of the block (block size is {BLOCK_SIZE})")]
+    OffsetOutOfBounds(usize),
+    #[error("tried to access {0} bytes at offset {1}, which exceeds block size of {BLOCK_SIZE}")]
     BufferTooLarge(usize, usize),
+    #[error("writing block {0} failed")]
+    WriteFailed(usize),
+    #[error("block {0} is write-protected")]
+    WriteProtected(usize),
+    #[error("ATA error {error:#04x} at sector {sector}")]
+    Device { sector: usize, error: u8 },
+    #[error("DMA transfer at sector {0} failed")]
+    Dma(usize),
+    #[error("timed out waiting for sector {0}")]
+    Timeout(usize),
+    #[error("{0} bytes is not a whole number of sectors")]
+    PartialSector(usize),
+    #[error("a snapshot of the disk is already being read")]
+    SnapshotInUse,
+}
+
+impl error::Error for DiskError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::WriteFailed(_) | Self::Device { .. } | Self::Dma(_) | Self::Timeout(_) => {
+                ErrorKind::Io
+            }
+            Self::WriteProtected(_) => ErrorKind::Unsupported,
+            Self::SnapshotInUse => ErrorKind::Other,
+            _ => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// Checks that `len` bytes starting at `offset` lie within block number `block` of the device.
+pub(super) fn check_bounds(
+    device: &(impl BlockDevice + ?Sized),
+    block: usize,
+    offset: usize,
+    len: usize,
+) -> Result<(), DiskError> {
+    if block >= device.size() {
+        return Err(DiskError::BlockOutOfBounds(block));
+    }
+
+    if offset > device.block_size() {
+        return Err(DiskError::OffsetOutOfBounds(offset));
+    }
+
+    if len > device.block_size() - offset {
+        return Err(DiskError::BufferTooLarge(len, offset));
+    }
+
+    Ok(())
 }
 
 impl Disk {
     /// Creates an simulated disk with the given number of blocks.
     /// Each block is 4 KiB.
-    fn new(blocks: usize) -> Self {
+    pub fn new(blocks: usize) -> Self {
+        Self::with_block_size(blocks, BLOCK_SIZE)
+    }
+
+    /// Creates a simulated disk with the given number of blocks of `block_size` bytes.
+    pub fn with_block_size(blocks: usize, block_size: usize) -> Self {
         Self {
             blocks: (0..blocks)
-                .map(|_| DiskBlock {
-                    data: [0; BLOCK_SIZE],
-                })
+                .map(|_| vec![0; block_size].into_boxed_slice())
                 .collect(),
+            block_size,
+            reads: AtomicUsize::new(0),
+            blocks_read: AtomicUsize::new(0),
+            writes: 0,
+            write_hook: None,
         }
     }
 
+    /// Calls `hook` before every write. A write the hook fails leaves the disk as it was, so
+    /// failing every write from some point on simulates losing power.
+    pub fn set_write_hook(&mut self, hook: WriteHook) {
+        self.write_hook = Some(hook);
+    }
+
     fn size(&self) -> usize {
         self.blocks.len()
     }
 
     fn read(&self, block: usize, offset: usize, buf: &mut [u8]) -> Result<(), DiskError> {
-        if block >= self.blocks.len() {
-            return Err(DiskError::BlockOutOfBounds(block));
-        }
-
+        check_bounds(self, block, offset, buf.len())?;
         let block = &self.blocks[block];
-        buf.copy_from_slice(&block.data[offset..offset + buf.len()]);
+        buf.copy_from_slice(&block[offset..offset + buf.len()]);
         Ok(())
     }
 
     fn write(&mut self, block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
-        if block >= self.blocks.len() {
-            return Err(DiskError::BlockOutOfBounds(block));
+        check_bounds(self, block, offset, buf.len())?;
+        if let Some(hook) = &mut self.write_hook {
+            hook(block)?;
+        }
+        self.writes += 1;
+        let block = &mut self.blocks[block];
+        block[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl BlockDevice for Disk {
+    fn read(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.blocks_read.fetch_add(1, Ordering::Relaxed);
+        self.read(block, 0, buf)
+    }
+
+    fn read_blocks(&self, start: usize, bufs: &mut [[u8; BLOCK_SIZE]]) -> Result<(), DiskError> {
+        if start + bufs.len() > self.blocks.len() {
+            return Err(DiskError::BlockOutOfBounds(start + bufs.len() - 1));
+        }
+        if self.block_size != BLOCK_SIZE {
+            return Err(DiskError::BufferTooLarge(BLOCK_SIZE, 0));
+        }
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.blocks_read.fetch_add(bufs.len(), Ordering::Relaxed);
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            buf.copy_from_slice(&self.blocks[start + i]);
         }
+        Ok(())
+    }
+
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), DiskError> {
+        self.write(block, 0, buf)
+    }
+
+    fn write_at(&mut self, block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
+        self.write(block, offset, buf)
+    }
+
+    fn size(&self) -> usize {
+        self.size()
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
 
-        if offset + buf.len() > BLOCK_SIZE {
-            return Err(DiskError::BufferTooLarge(buf.len(), offset));
+    fn stats(&self) -> DiskStats {
+        DiskStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            blocks_read: self.blocks_read.load(Ordering::Relaxed),
+            writes: self.writes,
         }
+    }
+}
 
-        let block = &mut self.blocks[block];
-        block.data[offset..offset + buf.len()].copy_from_slice(buf);
+/// Presents a device with [`SECTOR_SIZE`] byte sectors, like an ATA disk, as a device with blocks
+/// of [`BLOCK_SIZE`] bytes. Each block is stored in consecutive sectors, writes which only cover
+/// part of a sector read the rest of it first.
+pub struct SectorAdapter<D> {
+    device: D,
+}
+
+impl<D: BlockDevice> SectorAdapter<D> {
+    const SECTORS_PER_BLOCK: usize = BLOCK_SIZE / SECTOR_SIZE;
+
+    /// Wraps a device, which must have sectors of [`SECTOR_SIZE`] bytes.
+    pub fn new(device: D) -> Self {
+        assert_eq!(device.block_size(), SECTOR_SIZE, "not a sector device");
+        Self { device }
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Returns the sector holding `offset` of the block, and the offset within that sector.
+    fn sector(block: usize, offset: usize) -> (usize, usize) {
+        (
+            block * Self::SECTORS_PER_BLOCK + offset / SECTOR_SIZE,
+            offset % SECTOR_SIZE,
+        )
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for SectorAdapter<D> {
+    fn read(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        check_bounds(self, block, 0, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            let (sector, _) = Self::sector(block, i * SECTOR_SIZE);
+            self.device.read(sector, chunk)?;
+        }
         Ok(())
     }
+
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), DiskError> {
+        self.write_at(block, 0, buf)
+    }
+
+    fn write_at(&mut self, block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
+        check_bounds(self, block, offset, buf.len())?;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (sector, sector_offset) = Self::sector(block, offset + pos);
+            let len = (SECTOR_SIZE - sector_offset).min(buf.len() - pos);
+            let chunk = &buf[pos..pos + len];
+            if len == SECTOR_SIZE {
+                self.device.write(sector, chunk)?;
+            } else {
+                let mut data = [0; SECTOR_SIZE];
+                self.device.read(sector, &mut data)?;
+                data[sector_offset..sector_offset + len].copy_from_slice(chunk);
+                self.device.write(sector, &data)?;
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> usize {
+        self.device.size() / Self::SECTORS_PER_BLOCK
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn stats(&self) -> DiskStats {
+        self.device.stats()
+    }
+
+    fn flush(&mut self) -> Result<(), DiskError> {
+        self.device.flush()
+    }
 }
 
-impl BlockDevice for Disk {
-    fn read(&self, block: usize, buf: &mut [u8]) {
-        self.read(block, 0, buf).unwrap();
+/// The disk the kernel filesystem lives on, accessed through the block cache like [`read`] and
+/// [`write`].
+pub struct KernelDisk;
+
+impl BlockDevice for KernelDisk {
+    fn read(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        read(block, 0, buf)
+    }
+
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), DiskError> {
+        write(block, 0, buf)
     }
 
-    fn write(&mut self, block: usize, buf: &[u8]) {
-        self.write(block, 0, buf).unwrap();
+    fn write_at(&mut self, block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
+        write(block, offset, buf)
     }
+
+    fn size(&self) -> usize {
+        size()
+    }
+
+    fn block_size(&self) -> usize {
+        block_size()
+    }
+
+    fn flush(&mut self) -> Result<(), DiskError> {
+        flush()
+    }
+}
+
+#[test_case]
+fn test_access_past_block_end() {
+    let mut disk = Disk::new(1);
+    let mut buf = [0; 2];
+    assert_eq!(
+        disk.read(0, BLOCK_SIZE - 1, &mut buf),
+        Err(DiskError::BufferTooLarge(2, BLOCK_SIZE - 1))
+    );
+    assert_eq!(
+        disk.write(0, BLOCK_SIZE - 1, &buf),
+        Err(DiskError::BufferTooLarge(2, BLOCK_SIZE - 1))
+    );
+}
+
+#[test_case]
+fn test_empty_access_at_block_end() {
+    let mut disk = Disk::new(1);
+    assert_eq!(disk.read(0, BLOCK_SIZE, &mut []), Ok(()));
+    assert_eq!(disk.write(0, BLOCK_SIZE, &[]), Ok(()));
+    assert_eq!(
+        disk.read(0, BLOCK_SIZE + 1, &mut []),
+        Err(DiskError::OffsetOutOfBounds(BLOCK_SIZE + 1))
+    );
+}
+
+#[test_case]
+fn test_access_out_of_bounds_block() {
+    let disk = Disk::new(1);
+    let mut buf = [0; 1];
+    assert_eq!(
+        disk.read(1, 0, &mut buf),
+        Err(DiskError::BlockOutOfBounds(1))
+    );
+}
+
+#[test_case]
+fn test_sector_adapter() {
+    let mut adapter = SectorAdapter::new(Disk::with_block_size(16, SECTOR_SIZE));
+    assert_eq!((adapter.size(), adapter.block_size()), (2, BLOCK_SIZE));
+
+    let data = (0..BLOCK_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    BlockDevice::write(&mut adapter, 1, &data).unwrap();
+    // A write within a sector and one across a sector boundary keep the rest of the sectors
+    adapter.write_at(1, 10, &[0xaa; 4]).unwrap();
+    adapter.write_at(1, SECTOR_SIZE - 2, &[0xbb; 4]).unwrap();
+
+    let mut expected = data.clone();
+    expected[10..14].fill(0xaa);
+    expected[SECTOR_SIZE - 2..SECTOR_SIZE + 2].fill(0xbb);
+    let mut buf = vec![0; BLOCK_SIZE];
+    BlockDevice::read(&adapter, 1, &mut buf).unwrap();
+    assert_eq!(buf, expected);
+
+    // The block is stored in the sectors after the ones of block 0
+    let disk = adapter.into_inner();
+    let mut sector = [0; SECTOR_SIZE];
+    BlockDevice::read(&disk, 8, &mut sector).unwrap();
+    assert_eq!(sector[..], expected[..SECTOR_SIZE]);
+    BlockDevice::read(&disk, 7, &mut sector).unwrap();
+    assert_eq!(sector, [0; SECTOR_SIZE]);
+}
+
+#[test_case]
+fn test_snapshot_keeps_blocks_written_meanwhile() {
+    let previous = attach(Box::new(Disk::new(4)));
+    write(1, 0, &[1; BLOCK_SIZE]).unwrap();
+    write(2, 0, &[2; BLOCK_SIZE]).unwrap();
+
+    let kept = snapshot([1, 2]).unwrap();
+    assert!(matches!(snapshot([3]), Err(DiskError::SnapshotInUse)));
+    write(1, 10, &[9; 4]).unwrap();
+    write(3, 0, &[3; 4]).unwrap();
+    let mut buf = [0; BLOCK_SIZE];
+    BlockDevice::read(&kept, 1, &mut buf).unwrap();
+    assert_eq!(buf, [1; BLOCK_SIZE]);
+    // Once read, the block is read from the disk again, and writing it doesn't copy it
+    BlockDevice::read(&kept, 2, &mut buf).unwrap();
+    write(2, 0, &[5; 4]).unwrap();
+    BlockDevice::read(&kept, 2, &mut buf).unwrap();
+    assert_eq!(buf[..5], [5, 5, 5, 5, 2]);
+    BlockDevice::read(&kept, 1, &mut buf).unwrap();
+    assert_eq!(buf[10..14], [9; 4]);
+    drop(kept);
+
+    let kept = snapshot([0]).unwrap();
+    drop(kept);
+    attach(previous);
 }
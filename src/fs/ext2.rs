@@ -0,0 +1,771 @@
+use core::fmt;
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use thiserror_no_std::Error;
+
+use super::{
+    dir::DirEntry,
+    disk::BlockDevice,
+    file::{FileSystemError, INumber, InodeKind, Metadata},
+    handle::File,
+    path::components,
+    vfs::Vfs,
+};
+use crate::{
+    error::{self, ErrorKind},
+    task::cancel::CancellationToken,
+};
+
+/// Where the superblock starts on the device, whatever the block size.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: INumber = 2;
+
+// The fields of the superblock which are read, all little-endian. Revision 0 filesystems stop
+// after REV_LEVEL, and have 128 byte inodes and no feature flags.
+const INODES_COUNT: usize = 0;
+const BLOCKS_COUNT: usize = 4;
+const FIRST_DATA_BLOCK: usize = 20;
+const LOG_BLOCK_SIZE: usize = 24;
+const BLOCKS_PER_GROUP: usize = 32;
+const INODES_PER_GROUP: usize = 40;
+const SB_MAGIC: usize = 56;
+const REV_LEVEL: usize = 76;
+const INODE_SIZE: usize = 88;
+const FEATURE_COMPAT: usize = 92;
+const FEATURE_INCOMPAT: usize = 96;
+
+/// The size of a block group descriptor, whose inode table is the third field.
+const GROUP_DESC_SIZE: usize = 32;
+const GROUP_INODE_TABLE: usize = 8;
+
+// The fields of an inode which are read. SIZE_HIGH holds the upper half of the size of regular
+// files, revision 0 leaves it zero.
+const INODE_MODE: usize = 0;
+const INODE_SIZE_LOW: usize = 4;
+const INODE_LINKS: usize = 26;
+const INODE_SECTORS: usize = 28;
+const INODE_FLAGS: usize = 32;
+const INODE_BLOCK: usize = 40;
+const INODE_SIZE_HIGH: usize = 108;
+/// The part of an inode every revision has.
+const OLD_INODE_SIZE: usize = 128;
+/// Block pointers of an inode, the direct ones followed by the single, double and triple indirect
+/// one.
+const INODE_POINTERS: usize = 15;
+const DIRECT_POINTERS: usize = 12;
+
+const MODE_TYPE: u16 = 0xf000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+/// Inode flags of ext4, for files which don't keep their blocks in block pointers.
+const FLAG_EXTENTS: u32 = 0x8_0000;
+const FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+/// Directory entries hold the type of the inode, and the name length is a single byte.
+const INCOMPAT_FILETYPE: u32 = 0x2;
+/// The incompatible features which can be read, any other one is rejected. Read-only compatible
+/// features don't change how the filesystem is read, and are ignored.
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+
+/// Names of the features which are rejected, as `mke2fs -O` calls them.
+const COMPAT_NAMES: [(u32, &str); 1] = [(COMPAT_HAS_JOURNAL, "has_journal")];
+const INCOMPAT_NAMES: [(u32, &str); 14] = [
+    (0x1, "compression"),
+    (0x4, "needs_recovery"),
+    (0x8, "journal_dev"),
+    (0x10, "meta_bg"),
+    (0x40, "extent"),
+    (0x80, "64bit"),
+    (0x100, "mmp"),
+    (0x200, "flex_bg"),
+    (0x400, "ea_inode"),
+    (0x1000, "dirdata"),
+    (0x2000, "metadata_csum_seed"),
+    (0x4000, "large_dir"),
+    (0x8000, "inline_data"),
+    (0x1_0000, "encrypt"),
+];
+
+/// Features of a filesystem which this driver can't read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub compat: u32,
+    pub incompat: u32,
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let compat = COMPAT_NAMES
+            .iter()
+            .map(|&(bit, name)| (self.compat & bit, name));
+        let incompat = INCOMPAT_NAMES
+            .iter()
+            .map(|&(bit, name)| (self.incompat & bit, name));
+        let mut names = compat
+            .chain(incompat)
+            .filter(|&(set, _)| set != 0)
+            .map(|(_, name)| String::from(name))
+            .collect::<Vec<_>>();
+        let known = INCOMPAT_NAMES.iter().fold(0, |bits, (bit, _)| bits | bit);
+        if self.incompat & !known != 0 {
+            names.push(format!("unknown {:#x}", self.incompat & !known));
+        }
+        f.write_str(&names.join(", "))
+    }
+}
+
+/// Why an ext2 filesystem can't be read.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    #[error("not an ext2 filesystem, the magic number is {0:#06x}")]
+    BadMagic(u16),
+    #[error("unsupported revision {0}")]
+    UnsupportedRevision(u32),
+    #[error("unsupported features: {0}")]
+    UnsupportedFeatures(Features),
+    #[error("inode {0} keeps its data in extents or inline, which aren't supported")]
+    UnsupportedInode(INumber),
+    #[error("the filesystem is {needed} bytes but the device only {device}")]
+    Truncated { needed: u64, device: u64 },
+    #[error("the superblock is corrupt, {0}")]
+    CorruptSuperblock(&'static str),
+    #[error("inode {inumber} is corrupt, {reason}")]
+    CorruptInode {
+        inumber: INumber,
+        reason: &'static str,
+    },
+}
+
+impl error::Error for Ext2Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::UnsupportedRevision(_)
+            | Self::UnsupportedFeatures(_)
+            | Self::UnsupportedInode(_) => ErrorKind::Unsupported,
+            _ => ErrorKind::Corrupt,
+        }
+    }
+}
+
+/// The fields of an inode the driver uses.
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+    inumber: INumber,
+    mode: u16,
+    size: usize,
+    links: u16,
+    /// The 512 byte sectors taken up by the data and indirect blocks.
+    sectors: u32,
+    pointers: [u32; INODE_POINTERS],
+}
+
+impl Inode {
+    fn kind(&self) -> InodeKind {
+        match self.mode & MODE_TYPE {
+            MODE_FILE => InodeKind::File,
+            MODE_DIRECTORY => InodeKind::Directory,
+            // Symbolic links, sockets and pipes as well, none of which can be read
+            _ => InodeKind::Device,
+        }
+    }
+}
+
+/// A read-only ext2 filesystem of revision 0 or 1, as made by `mke2fs -t ext2`. Files are read
+/// through their direct and indirect block pointers, and directories as the linked lists of
+/// entries every ext2 directory is, ignoring any hash index. Filesystems with a journal, or with
+/// incompatible features other than file types in directory entries, are rejected when mounted.
+///
+/// The ext2 blocks can be of any size from 1 KiB up, and don't need to match the blocks of the
+/// device, which are read from at byte offsets.
+///
+/// Files and directories are listed with times of 0, as ext2 keeps them in seconds since 1970
+/// while the kernel counts timer ticks since boot.
+pub struct Ext2Fs {
+    device: Box<dyn BlockDevice + Send>,
+    block_size: usize,
+    blocks: u32,
+    inodes: u32,
+    inodes_per_group: usize,
+    inode_size: usize,
+    /// The first block of the inode table of each block group.
+    inode_tables: Vec<u32>,
+    /// Whether directory entries hold the type of the inode.
+    filetype: bool,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads the bytes at `offset` of the device, across as many of its blocks as they cover.
+fn read_device(
+    device: &dyn BlockDevice,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<(), FileSystemError> {
+    let size = device.block_size();
+    let mut data = vec![0; size];
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let (block, start) = ((pos / size as u64) as usize, (pos % size as u64) as usize);
+        let len = (size - start).min(buf.len() - done);
+        device.read(block, &mut data[..start + len])?;
+        buf[done..done + len].copy_from_slice(&data[start..start + len]);
+        done += len;
+    }
+    Ok(())
+}
+
+impl Ext2Fs {
+    /// Reads the superblock and block group descriptors of the filesystem on `device`.
+    pub fn new(device: Box<dyn BlockDevice + Send>) -> Result<Self, FileSystemError> {
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        read_device(&*device, SUPERBLOCK_OFFSET, &mut sb)?;
+        let magic = u16_at(&sb, SB_MAGIC);
+        if magic != MAGIC {
+            return Err(Ext2Error::BadMagic(magic).into());
+        }
+        let revision = u32_at(&sb, REV_LEVEL);
+        let (compat, incompat, inode_size) = match revision {
+            0 => (0, 0, OLD_INODE_SIZE),
+            1 => (
+                u32_at(&sb, FEATURE_COMPAT),
+                u32_at(&sb, FEATURE_INCOMPAT),
+                u16_at(&sb, INODE_SIZE) as usize,
+            ),
+            _ => return Err(Ext2Error::UnsupportedRevision(revision).into()),
+        };
+        let unsupported = Features {
+            compat: compat & COMPAT_HAS_JOURNAL,
+            incompat: incompat & !SUPPORTED_INCOMPAT,
+        };
+        if unsupported.compat != 0 || unsupported.incompat != 0 {
+            return Err(Ext2Error::UnsupportedFeatures(unsupported).into());
+        }
+
+        let corrupt = |reason| Err(Ext2Error::CorruptSuperblock(reason).into());
+        let log_block_size = u32_at(&sb, LOG_BLOCK_SIZE);
+        if log_block_size > 6 {
+            return corrupt("the block size is larger than 64 KiB");
+        }
+        let block_size = 1024 << log_block_size;
+        if !inode_size.is_power_of_two() || inode_size < OLD_INODE_SIZE || inode_size > block_size {
+            return corrupt("the inode size is invalid");
+        }
+        let (blocks, inodes) = (u32_at(&sb, BLOCKS_COUNT), u32_at(&sb, INODES_COUNT));
+        let first_data_block = u32_at(&sb, FIRST_DATA_BLOCK);
+        let blocks_per_group = u32_at(&sb, BLOCKS_PER_GROUP);
+        let inodes_per_group = u32_at(&sb, INODES_PER_GROUP) as usize;
+        if blocks_per_group == 0 || inodes_per_group == 0 || first_data_block >= blocks {
+            return corrupt("the block groups are empty");
+        }
+        let needed = blocks as u64 * block_size as u64;
+        let size = device.size() as u64 * device.block_size() as u64;
+        if needed > size {
+            return Err(Ext2Error::Truncated {
+                needed,
+                device: size,
+            }
+            .into());
+        }
+
+        // The descriptors start in the block after the superblock
+        let groups = (blocks - first_data_block).div_ceil(blocks_per_group) as usize;
+        // Every inode is looked up in the table of its group
+        if inodes as usize > groups * inodes_per_group {
+            return corrupt("there are more inodes than the block groups hold");
+        }
+        let mut descriptors = vec![0; groups * GROUP_DESC_SIZE];
+        let table = (first_data_block as u64 + 1) * block_size as u64;
+        read_device(&*device, table, &mut descriptors)?;
+        let inode_tables = descriptors
+            .chunks(GROUP_DESC_SIZE)
+            .map(|descriptor| u32_at(descriptor, GROUP_INODE_TABLE))
+            .collect::<Vec<_>>();
+        if inode_tables.iter().any(|&table| table >= blocks) {
+            return corrupt("an inode table is past the end of the filesystem");
+        }
+
+        Ok(Self {
+            device,
+            block_size,
+            blocks,
+            inodes,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+        })
+    }
+
+    /// Returns the size of the ext2 blocks in bytes.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), FileSystemError> {
+        read_device(&*self.device, offset, buf)
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    fn inode(&self, inumber: INumber) -> Result<Inode, FileSystemError> {
+        if inumber == 0 || inumber > self.inodes {
+            return Err(FileSystemError::InvalidInode(inumber));
+        }
+        let index = inumber as usize - 1;
+        let table = self.inode_tables[index / self.inodes_per_group];
+        let offset = (index % self.inodes_per_group * self.inode_size) as u64;
+        let mut raw = [0; OLD_INODE_SIZE];
+        self.read_at(self.block_offset(table) + offset, &mut raw)?;
+
+        let mode = u16_at(&raw, INODE_MODE);
+        let mut size = u32_at(&raw, INODE_SIZE_LOW) as u64;
+        if mode & MODE_TYPE == MODE_FILE {
+            size |= (u32_at(&raw, INODE_SIZE_HIGH) as u64) << 32;
+        }
+        if u32_at(&raw, INODE_FLAGS) & (FLAG_EXTENTS | FLAG_INLINE_DATA) != 0 {
+            return Err(Ext2Error::UnsupportedInode(inumber).into());
+        }
+        Ok(Inode {
+            inumber,
+            mode,
+            size: size as usize,
+            links: u16_at(&raw, INODE_LINKS),
+            sectors: u32_at(&raw, INODE_SECTORS),
+            pointers: core::array::from_fn(|i| u32_at(&raw, INODE_BLOCK + 4 * i)),
+        })
+    }
+
+    /// Returns the pointer at `index` of the indirect block `block` of the inode.
+    fn pointer(&self, inode: &Inode, block: u32, index: usize) -> Result<u32, FileSystemError> {
+        self.check_block(inode, block)?;
+        let mut pointer = [0; 4];
+        self.read_at(self.block_offset(block) + 4 * index as u64, &mut pointer)?;
+        Ok(u32::from_le_bytes(pointer))
+    }
+
+    fn check_block(&self, inode: &Inode, block: u32) -> Result<(), FileSystemError> {
+        match block < self.blocks {
+            true => Ok(()),
+            false => Err(Ext2Error::CorruptInode {
+                inumber: inode.inumber,
+                reason: "it points past the end of the filesystem",
+            }
+            .into()),
+        }
+    }
+
+    /// Returns the block holding block `n` of the file, or 0 if it's a hole. Blocks past the
+    /// direct ones are found through the single, double and triple indirect blocks in turn.
+    fn file_block(&self, inode: &Inode, n: usize) -> Result<u32, FileSystemError> {
+        if n < DIRECT_POINTERS {
+            return Ok(inode.pointers[n]);
+        }
+        let per_block = self.block_size / 4;
+        let (mut n, mut span) = (n - DIRECT_POINTERS, 1);
+        for (depth, &root) in inode.pointers[DIRECT_POINTERS..].iter().enumerate() {
+            // The blocks reached through the indirect block at this depth
+            span *= per_block;
+            if n >= span {
+                n -= span;
+                continue;
+            }
+            let (mut block, mut covered) = (root, span);
+            for _ in 0..=depth {
+                if block == 0 {
+                    return Ok(0);
+                }
+                covered /= per_block;
+                block = self.pointer(inode, block, n / covered)?;
+                n %= covered;
+            }
+            return Ok(block);
+        }
+        Err(Ext2Error::CorruptInode {
+            inumber: inode.inumber,
+            reason: "its size is larger than its blocks can hold",
+        }
+        .into())
+    }
+
+    /// Reads the data of the inode at `offset` into `buf`, returning the number of bytes read.
+    /// Holes read as zeroes.
+    fn read_data(
+        &self,
+        inode: &Inode,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, FileSystemError> {
+        if offset > inode.size {
+            return Err(FileSystemError::OffsetPastEnd(offset));
+        }
+        let len = buf.len().min(inode.size - offset);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let start = pos % self.block_size;
+            let chunk = &mut buf[done..len.min(done + self.block_size - start)];
+            match self.file_block(inode, pos / self.block_size)? {
+                0 => chunk.fill(0),
+                block => {
+                    self.check_block(inode, block)?;
+                    self.read_at(self.block_offset(block) + start as u64, chunk)?;
+                }
+            }
+            done += chunk.len();
+        }
+        Ok(len)
+    }
+
+    /// Returns the entries of the directory, without `.` and `..`.
+    fn entries(&self, dir: &Inode) -> Result<Vec<DirEntry>, FileSystemError> {
+        if dir.kind() != InodeKind::Directory {
+            return Err(FileSystemError::NotADirectory(format!(
+                "inode {}",
+                dir.inumber
+            )));
+        }
+        let mut entries = Vec::new();
+        let mut data = vec![0; self.block_size];
+        for n in 0..dir.size.div_ceil(self.block_size) {
+            self.read_data(dir, n * self.block_size, &mut data)?;
+            let corrupt = |reason| FileSystemError::CorruptDirectory {
+                dir: dir.inumber,
+                block: n,
+                reason,
+            };
+            let mut pos = 0;
+            while pos < data.len() {
+                let entry = &data[pos..];
+                if entry.len() < 8 {
+                    return Err(corrupt("an entry is cut off at the end of the block"));
+                }
+                let inumber = u32_at(entry, 0);
+                let record_len = u16_at(entry, 4) as usize;
+                let name_len = match self.filetype {
+                    true => entry[6] as usize,
+                    false => u16_at(entry, 6) as usize,
+                };
+                if record_len < 8 || !record_len.is_multiple_of(4) || record_len > entry.len() {
+                    return Err(corrupt("an entry has an invalid length"));
+                }
+                if 8 + name_len > record_len {
+                    return Err(corrupt("a name is longer than its entry"));
+                }
+                pos += record_len;
+
+                let name = String::from_utf8_lossy(&entry[8..8 + name_len]);
+                // Unused entries are left with an inumber of zero
+                if inumber == 0 || name == "." || name == ".." {
+                    continue;
+                }
+                let kind = match (self.filetype, entry[7]) {
+                    (true, 1) => InodeKind::File,
+                    (true, 2) => InodeKind::Directory,
+                    (true, 3..=7) => InodeKind::Device,
+                    _ => self.inode(inumber)?.kind(),
+                };
+                entries.push(DirEntry {
+                    name: name.into_owned(),
+                    inumber,
+                    kind,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Vfs for Ext2Fs {
+    fn kind(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn resolve(&self, path: &str) -> Result<INumber, FileSystemError> {
+        let mut inumber = ROOT_INODE;
+        for name in components(path) {
+            let dir = self.inode(inumber)?;
+            if dir.kind() != InodeKind::Directory {
+                return Err(FileSystemError::NotADirectory(path.to_string()));
+            }
+            inumber = self
+                .entries(&dir)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .ok_or_else(|| FileSystemError::NotFound(path.to_string()))?
+                .inumber;
+        }
+        Ok(inumber)
+    }
+
+    fn stat(&self, inumber: INumber) -> Result<Metadata, FileSystemError> {
+        let inode = self.inode(inumber)?;
+        Ok(Metadata {
+            kind: inode.kind(),
+            size: inode.size,
+            physical_size: inode.sectors as usize * 512,
+            compressed: false,
+            blocks: inode.sectors as usize * 512 / self.block_size,
+            created: 0,
+            modified: 0,
+            links: inode.links as u32,
+        })
+    }
+
+    fn list(&self, dir: INumber) -> Result<Vec<DirEntry>, FileSystemError> {
+        self.entries(&self.inode(dir)?)
+    }
+
+    fn create_at(&mut self, _path: &str, _kind: InodeKind) -> Result<INumber, FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn create_dir_all(&mut self, _path: &str) -> Result<INumber, FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn remove_recursive(
+        &mut self,
+        _path: &str,
+        _cancel: Option<&CancellationToken>,
+    ) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn touch(&mut self, _inumber: INumber) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn truncate(&mut self, _inumber: INumber, _size: usize) -> Result<(), FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+
+    fn open(&self, inumber: INumber) -> Result<File, FileSystemError> {
+        self.inode(inumber)?;
+        Ok(File::new(inumber, 0))
+    }
+
+    fn read(&self, file: &mut File, buf: &mut [u8]) -> Result<usize, FileSystemError> {
+        let inode = self.inode(file.inumber())?;
+        match inode.kind() {
+            InodeKind::File => {}
+            InodeKind::Directory => {
+                return Err(FileSystemError::IsADirectory(format!(
+                    "inode {}",
+                    inode.inumber
+                )))
+            }
+            InodeKind::Device => {
+                return Err(FileSystemError::NotSupported(format!(
+                    "inode {}",
+                    inode.inumber
+                )))
+            }
+        }
+        let offset = file.offset();
+        let read = self.read_data(&inode, offset, buf)?;
+        file.seek(offset + read);
+        Ok(read)
+    }
+
+    fn write(&mut self, _file: &mut File, _data: &[u8]) -> Result<usize, FileSystemError> {
+        Err(FileSystemError::ReadOnly)
+    }
+}
+
+// The images are made by `mke2fs -t ext2 -b <1024|4096> -N 96 -O ^resize_inode -d <dir>` on a
+// 512 KiB file, with the zeroes at their end cut off. Both hold the same files:
+//   /hello.txt        a line of text
+//   /docs/notes.txt   another line
+//   /docs/empty-file-00 to -59, enough entries to take up two 1 KiB blocks
+//   /pattern.bin      52 KiB of `pattern`, reaching past the direct blocks
+//   /sparse.bin       `start`, a hole, and `end\n` at 300 KiB, in a double indirect block of
+//                     the 1 KiB image
+#[cfg(test)]
+const IMAGE_SIZE: usize = 512 * 1024;
+#[cfg(test)]
+static IMAGE_1K: &[u8] = include_bytes!("../../tests/fixtures/ext2-1k.img");
+#[cfg(test)]
+static IMAGE_4K: &[u8] = include_bytes!("../../tests/fixtures/ext2-4k.img");
+
+/// The byte at `i` of `/pattern.bin`.
+#[cfg(test)]
+fn pattern(i: usize) -> u8 {
+    ((i * 7 + i / 1024) % 251) as u8
+}
+
+/// Returns a RAM disk with `block_size` byte blocks holding `image`, padded with zeroes.
+#[cfg(test)]
+fn ram_disk(image: &[u8], block_size: usize) -> Box<dyn BlockDevice + Send> {
+    let mut disk = super::disk::Disk::with_block_size(IMAGE_SIZE / block_size, block_size);
+    for (block, data) in image.chunks(block_size).enumerate() {
+        BlockDevice::write(&mut disk, block, data).unwrap();
+    }
+    Box::new(disk)
+}
+
+#[test_case]
+fn test_ext2_read_image() {
+    use super::{disk::BLOCK_SIZE, vfs::VfsRouter};
+
+    for (image, block_size) in [(IMAGE_1K, 1024), (IMAGE_4K, 4096)] {
+        let fs = Ext2Fs::new(ram_disk(image, BLOCK_SIZE)).unwrap();
+        assert_eq!(fs.block_size(), block_size);
+        let mut vfs = VfsRouter::new("image", Box::new(fs));
+        let names = |path| {
+            let mut names = vfs
+                .list(path)
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.name, entry.kind))
+                .collect::<Vec<_>>();
+            names.sort_by(|a, b| a.0.cmp(&b.0));
+            names
+        };
+        assert_eq!(
+            names("/"),
+            [
+                ("docs".to_string(), InodeKind::Directory),
+                ("hello.txt".to_string(), InodeKind::File),
+                ("lost+found".to_string(), InodeKind::Directory),
+                ("pattern.bin".to_string(), InodeKind::File),
+                ("sparse.bin".to_string(), InodeKind::File),
+            ]
+        );
+        let docs = names("/docs");
+        assert_eq!(docs.len(), 61);
+        assert_eq!(docs[0].0, "empty-file-00");
+        assert_eq!(docs[60].0, "notes.txt");
+
+        let read = |path: &str| {
+            let mut file = vfs.open(path).unwrap();
+            let mut data = vec![0; vfs.stat(path).unwrap().size];
+            // Read in pieces which don't line up with the blocks
+            for chunk in data.chunks_mut(1000) {
+                assert_eq!(vfs.read(&mut file, chunk).unwrap(), chunk.len());
+            }
+            assert_eq!(vfs.read(&mut file, &mut [0; 8]).unwrap(), 0);
+            data
+        };
+        assert_eq!(read("/hello.txt"), b"Hello from the host!\n");
+        assert_eq!(
+            read("docs/notes.txt"),
+            b"ext2 directories are linked lists of entries\n"
+        );
+        let data = read("/pattern.bin");
+        assert_eq!(data.len(), 13 * 4096);
+        assert!(data.iter().enumerate().all(|(i, &b)| b == pattern(i)));
+        let sparse = read("/sparse.bin");
+        assert_eq!(sparse.len(), 300 * 1024 + 4);
+        assert_eq!(&sparse[..5], b"start");
+        assert!(sparse[5..300 * 1024].iter().all(|&b| b == 0));
+        assert_eq!(&sparse[300 * 1024..], b"end\n");
+
+        assert!(matches!(
+            vfs.stat("/docs/missing"),
+            Err(FileSystemError::NotFound(_))
+        ));
+        assert!(matches!(
+            vfs.list("/hello.txt"),
+            Err(FileSystemError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            vfs.create("/new", InodeKind::File),
+            Err(FileSystemError::ReadOnly)
+        ));
+        let mut file = vfs.open("/hello.txt").unwrap();
+        assert!(matches!(
+            vfs.write(&mut file, b"x"),
+            Err(FileSystemError::ReadOnly)
+        ));
+    }
+
+    // Sectors smaller than the ext2 blocks are read a few at a time
+    let fs = Ext2Fs::new(ram_disk(IMAGE_1K, super::disk::SECTOR_SIZE)).unwrap();
+    let mut file = fs.open(fs.resolve("/pattern.bin").unwrap()).unwrap();
+    file.seek(20 * 1024 - 3);
+    let mut buf = [0; 6];
+    assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 6);
+    assert_eq!(buf, core::array::from_fn(|i| pattern(20 * 1024 - 3 + i)));
+}
+
+#[test_case]
+fn test_ext2_rejects_unsupported_images() {
+    use super::disk::BLOCK_SIZE;
+
+    let mount = |image: &[u8]| Ext2Fs::new(ram_disk(image, BLOCK_SIZE)).map(|_| ());
+    let with = |offset: usize, bytes: &[u8]| {
+        let mut image = IMAGE_1K.to_vec();
+        let offset = SUPERBLOCK_OFFSET as usize + offset;
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        image
+    };
+    let message = |image: &[u8]| format!("{}", mount(image).unwrap_err());
+
+    // Extents and 64-bit block numbers would be misread as block pointers
+    let image = with(FEATURE_INCOMPAT, &(0x2 | 0x40 | 0x80u32).to_le_bytes());
+    assert!(matches!(
+        mount(&image),
+        Err(FileSystemError::Ext2(Ext2Error::UnsupportedFeatures(
+            Features {
+                compat: 0,
+                incompat: 0xc0
+            }
+        )))
+    ));
+    assert_eq!(message(&image), "ext2: unsupported features: extent, 64bit");
+    let image = with(FEATURE_COMPAT, &COMPAT_HAS_JOURNAL.to_le_bytes());
+    assert_eq!(message(&image), "ext2: unsupported features: has_journal");
+    let image = with(FEATURE_INCOMPAT, &0x2_0002u32.to_le_bytes());
+    assert_eq!(
+        message(&image),
+        "ext2: unsupported features: unknown 0x20000"
+    );
+
+    assert!(matches!(
+        mount(&with(SB_MAGIC, &[0, 0])),
+        Err(FileSystemError::Ext2(Ext2Error::BadMagic(0)))
+    ));
+    assert!(matches!(
+        mount(&with(REV_LEVEL, &2u32.to_le_bytes())),
+        Err(FileSystemError::Ext2(Ext2Error::UnsupportedRevision(2)))
+    ));
+    assert_eq!(
+        message(&with(INODES_COUNT, &u32::MAX.to_le_bytes())),
+        "ext2: the superblock is corrupt, there are more inodes than the block groups hold"
+    );
+    // The used blocks fit on a device half the size of the filesystem, the rest doesn't
+    let mut disk = super::disk::Disk::new(IMAGE_SIZE / BLOCK_SIZE / 2);
+    for (block, data) in IMAGE_1K.chunks(BLOCK_SIZE).enumerate() {
+        BlockDevice::write(&mut disk, block, data).unwrap();
+    }
+    assert!(matches!(
+        Ext2Fs::new(Box::new(disk)),
+        Err(FileSystemError::Ext2(Ext2Error::Truncated { .. }))
+    ));
+}
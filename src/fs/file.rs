@@ -1,181 +1,1074 @@
-use core::{mem::size_of, num::NonZeroU32};
+use core::{
+    cell::{Cell, RefCell},
+    mem::size_of,
+    num::NonZeroU32,
+    ops::Range,
+};
 
-use alloc::vec::Vec;
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use thiserror_no_std::Error;
 
-use super::disk;
+use super::{
+    cache::CACHE_BLOCKS,
+    dir::DirIndexes,
+    disk::{self, DiskError},
+    inode_cache::{inode_position, InodeCache, InodeCacheStats},
+    proc,
+    superblock::{Superblock, MAGIC_NUMBER, SUPERBLOCK_SIZE},
+};
+use crate::{
+    error::{self, ErrorKind},
+    log,
+    log::LogLevel,
+    task::{cancel::CancellationToken, job},
+    util::{
+        crc32::Crc32,
+        lz::{self, LzError},
+    },
+};
 
 // As the block at index 0 is the superblock which should rarely be referenced, we can assert that a block pointer
 // is non-zero. This will also enable Rust's `null pointer optimization` which will make `Option<BlockPtr>` take
 // up less space (as it can use a value of zero as the value for `None`)
 type BlockPtr = NonZeroU32;
-type INumber = u32;
+pub type INumber = u32;
+pub type Generation = u16;
 
-const MAGIC_NUMBER: usize = 0xdeadbeef;
-const INODES_PER_BLOCK: usize = disk::BLOCK_SIZE / size_of::<Inode>();
-const PTRS_PER_INODE: usize = 11;
+// Bumped whenever the on-disk layout changes, disks with another version are not mounted. Version 7
+// turned the first byte of an inode from a `bool` into `InodeFlags`, which older kernels can't read
+const VERSION: u32 = 7;
+/// Set once a compressed file has been created, see [`FileSystem::create_compressed`].
+const INCOMPAT_COMPRESSION: u64 = 1;
+/// The incompatible features this kernel knows how to write, see [`Superblock`].
+const KNOWN_INCOMPAT_FEATURES: u64 = INCOMPAT_COMPRESSION;
+pub(super) const INODES_PER_BLOCK: usize = disk::BLOCK_SIZE / size_of::<Inode>();
+const _: () = assert!(INODES_PER_BLOCK * size_of::<Inode>() == disk::BLOCK_SIZE);
+pub const PTRS_PER_INODE: usize = 6;
+/// The size of an inode on the disk.
+pub const INODE_SIZE: usize = size_of::<Inode>();
 const PTRS_PER_BLOCK: usize = disk::BLOCK_SIZE / size_of::<Option<BlockPtr>>();
-const INODE_BLOCKS_START: usize = 1;
+// The block after the superblock holds the bad block list, followed by the inode blocks
+const BAD_BLOCKS_BLOCK: usize = 1;
+pub(super) const INODE_BLOCKS_START: usize = 2;
+/// The number of bad blocks the bad block list has room for.
+const MAX_BAD_BLOCKS: usize = disk::BLOCK_SIZE / size_of::<u32>() - 1;
 
-#[derive(Clone)]
+/// Number of blocks read ahead when a file is read sequentially, unless changed with
+/// [`FileSystem::set_read_ahead`].
+pub const DEFAULT_READ_AHEAD: usize = 4;
+
+/// Number of blocks kept free for directories unless changed with
+/// [`FileSystem::set_reserved_blocks`], so that a full disk can still be cleaned up.
+pub const DEFAULT_RESERVED_BLOCKS: usize = 16;
+
+/// The largest file size that can be addressed by the direct and indirect pointers of an inode.
+pub const MAX_FILE_SIZE: usize = (PTRS_PER_INODE + PTRS_PER_BLOCK) * disk::BLOCK_SIZE;
+
+// The blocks of a compressed file hold its blocks of contents compressed one by one, as written
+// by `lz::compress_block`. They start with a map block [SIZE (8 bytes), OFFSETS (4 bytes each)]
+// holding the size of the contents and the offset of each compressed block, the compressed blocks
+// follow in any order. An offset of zero is a block of zeroes which was never written.
+const MAP_ENTRIES: usize = (disk::BLOCK_SIZE - size_of::<u64>()) / size_of::<u32>();
+/// The longest a block of a compressed file gets, when it's stored uncompressed.
+const MAX_COMPRESSED_BLOCK: usize = lz::BLOCK_HEADER_SIZE + disk::BLOCK_SIZE;
+
+/// The largest size of a compressed file, limited by the offsets its map block has room for.
+pub const MAX_COMPRESSED_FILE_SIZE: usize = MAP_ENTRIES * disk::BLOCK_SIZE;
+
+/// The inode of the root directory, created when the disk is formatted.
+pub const ROOT_INUMBER: INumber = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InodeKind {
+    File = 0,
+    Directory = 1,
+    Device = 2,
+}
+
+impl InodeKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Self::File),
+            1 => Some(Self::Directory),
+            2 => Some(Self::Device),
+            _ => None,
+        }
+    }
+}
+
+/// The flags in the first byte of an inode, combined with `|`. Before version 7 of the layout the
+/// byte was a `bool` telling whether the inode is in use.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct InodeFlags(u8);
+
+impl InodeFlags {
+    /// The inode is in use.
+    pub const VALID: Self = Self(1);
+    /// The contents of the file are compressed, see [`FileSystem::create_compressed`].
+    pub const COMPRESSED: Self = Self(1 << 1);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for InodeFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+// The inode is laid out without implicit padding, so that it can be copied to and from disk as raw bytes
+#[derive(Clone, Copy)]
+#[repr(C)]
 pub struct Inode {
-    valid: bool,
+    flags: InodeFlags,
+    kind: InodeKind,
+    // Bumped every time the inode is deleted, so that handles to the old file can tell that the
+    // inumber has been reused. Wraps around after 65536 reuses.
+    generation: Generation,
+    // CRC-32 of the file contents, kept up to date on every change
+    checksum: u32,
+    // The bytes in use of the blocks of the file. For a compressed file that's the map block and
+    // the compressed blocks, the size of the contents is in the map block.
     size: usize,
-    direct: [Option<BlockPtr>; 11],
+    // Timer ticks at which the file was created and last written to or truncated
+    created: u64,
+    modified: u64,
+    direct: [Option<BlockPtr>; PTRS_PER_INODE],
     indirect: Option<BlockPtr>,
+    // The number of directory entries pointing to the inode. The inode and its blocks are freed
+    // when the last one is removed.
+    links: u32,
+}
+
+/// How much space compressed files save, as returned by [`FileSystem::compression_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub files: usize,
+    /// The total size of their contents.
+    pub size: usize,
+    /// The bytes they take up on the disk, their map blocks included.
+    pub physical_size: usize,
+}
+
+/// Information about a file, as returned by [`FileSystem::stat`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: InodeKind,
+    pub size: usize,
+    /// The bytes the contents take up on the disk, which for a compressed file is less than
+    /// `size` unless it doesn't compress.
+    pub physical_size: usize,
+    /// Whether the contents are compressed, see [`FileSystem::create_compressed`].
+    pub compressed: bool,
+    /// The number of data blocks allocated to the file.
+    pub blocks: usize,
+    /// The timer tick at which the file was created.
+    pub created: u64,
+    /// The timer tick at which the file was last changed.
+    pub modified: u64,
+    /// The number of directory entries pointing to the file.
+    pub links: u32,
+}
+
+/// The fields of an inode as stored on the disk, decoded without checking them so that any bytes
+/// can be looked at. Block pointers of zero are unused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawInode {
+    /// The bits of the [`InodeFlags`].
+    pub flags: u8,
+    pub kind: u8,
+    pub generation: Generation,
+    pub checksum: u32,
+    pub size: usize,
+    pub created: u64,
+    pub modified: u64,
+    pub direct: [u32; PTRS_PER_INODE],
+    pub indirect: u32,
+    pub links: u32,
+}
+
+impl RawInode {
+    /// Decodes an inode laid out as [`Inode`], reading the fields in little endian.
+    pub fn from_bytes(bytes: &[u8; INODE_SIZE]) -> Self {
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Self {
+            flags: bytes[0],
+            kind: bytes[1],
+            generation: Generation::from_le_bytes([bytes[2], bytes[3]]),
+            checksum: u32_at(4),
+            size: u64_at(8) as usize,
+            created: u64_at(16),
+            modified: u64_at(24),
+            direct: core::array::from_fn(|i| u32_at(32 + i * 4)),
+            indirect: u32_at(32 + PTRS_PER_INODE * 4),
+            links: u32_at(36 + PTRS_PER_INODE * 4),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; INODE_SIZE] {
+        let mut bytes = [0; INODE_SIZE];
+        bytes[0] = self.flags;
+        bytes[1] = self.kind;
+        bytes[2..4].copy_from_slice(&self.generation.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[8..16].copy_from_slice(&(self.size as u64).to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.created.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.modified.to_le_bytes());
+        for (i, ptr) in self.direct.iter().enumerate() {
+            bytes[32 + i * 4..36 + i * 4].copy_from_slice(&ptr.to_le_bytes());
+        }
+        bytes[32 + PTRS_PER_INODE * 4..36 + PTRS_PER_INODE * 4]
+            .copy_from_slice(&self.indirect.to_le_bytes());
+        bytes[36 + PTRS_PER_INODE * 4..].copy_from_slice(&self.links.to_le_bytes());
+        bytes
+    }
+}
+
+/// Options for [`FileSystem::mount_with`], combined with `|`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MountFlags(u8);
+
+impl MountFlags {
+    /// Nothing is written to the disk: every change fails with [`FileSystemError::ReadOnly`], and
+    /// the block cache refuses writes as well.
+    pub const READ_ONLY: Self = Self(1);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
 }
 
+impl core::ops::BitOr for MountFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// A filesystem on the kernel disk.
+///
+/// The block bitmap only lives in memory and is rebuilt from the inodes when mounting, so what's
+/// on the disk after a crash is consistent as long as every pointer on the disk points at a block
+/// which was written before the pointer. Writes are therefore ordered, with [`disk::flush`]
+/// between the steps:
+///
+/// - Growing a file writes the newly allocated blocks and the data first, then the indirect block,
+///   then the inode. Allocated blocks are cleared before anything points at them.
+/// - Shrinking a file clears the end of its last block, then writes the inode, then the indirect
+///   block. An indirect block pointing past the end of the file only leaks blocks until they're
+///   reused.
+/// - Deleting a file writes the invalidated inode before its blocks are freed in the bitmap, so a
+///   failed write can't leave blocks in use by the file to be allocated again.
+/// - Link counts are raised before a directory entry is added and lowered after one is removed,
+///   so a count can be too high after a crash, which only leaks the file, but never too low.
+///
+/// The last [`reserved_blocks`](Self::reserved_blocks) free blocks are only given to directories,
+/// so that a file filling the disk doesn't keep files from being created, moved or removed.
+///
+/// A copy of the superblock is kept in the last block of the disk, outside of the filesystem, and
+/// is mounted from when the superblock in the first block is damaged.
 pub struct FileSystem {
     superblock: Superblock,
+    /// The copy of the superblock the filesystem was mounted from, `None` while unmounted.
+    mounted_from: Option<SuperblockCopy>,
     block_bitmap: Vec<u64>,
+    bad_blocks: Vec<BlockPtr>,
+    read_ahead: usize,
+    read_only: bool,
+    reserved_blocks: usize,
+    /// Lets names be looked up without scanning whole directories, see [`Self::lookup`].
+    pub(super) dir_indexes: RefCell<DirIndexes>,
+    /// Every inode is read and written through this, see [`InodeCache`].
+    inodes: RefCell<InodeCache>,
+    /// Counts the changes to inodes and mounts, so that a [`Check`] notices the filesystem
+    /// changing between its steps. Kept across unmounts.
+    changes: Cell<u64>,
+}
+
+/// A [`FileSystem::check`] in progress, made a step at a time.
+#[derive(Debug)]
+pub struct Check {
+    changes: u64,
+    problems: Vec<FileSystemError>,
+    /// The inode each data block seen so far belongs to.
+    owners: BTreeMap<BlockPtr, INumber>,
+    /// The inodes in use and their link counts.
+    inodes: Vec<(INumber, u32)>,
+    /// The number of directory entries pointing to each inode.
+    entries: BTreeMap<INumber, u32>,
+    /// The inode checked next, then the directory listed next counting on from the last inode.
+    next: usize,
+}
+
+impl Check {
+    /// Returns the problems found, once [`FileSystem::check_step`] has nothing left to check.
+    pub fn finish(mut self) -> Vec<FileSystemError> {
+        for (inumber, links) in self.inodes {
+            let entries = self.entries.get(&inumber).copied().unwrap_or(0);
+            if links != entries {
+                self.problems.push(FileSystemError::LinkCountMismatch {
+                    inumber,
+                    links,
+                    entries,
+                });
+            }
+        }
+        self.problems
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FileSystemError {
+    #[error("invalid magic number {0:#x}, is the disk formatted?")]
+    InvalidMagicNumber(u32),
+    #[error("the superblock is corrupt, {0}")]
+    CorruptSuperblock(&'static str),
+    #[error(
+        "the superblock has checksum {stored:#010x} but its fields add up to {computed:#010x}"
+    )]
+    SuperblockChecksum { stored: u32, computed: u32 },
+    #[error("the {0} superblock doesn't match the mounted filesystem")]
+    SuperblockMismatch(SuperblockCopy),
+    #[error("unsupported filesystem version {found}, expected {VERSION}")]
+    UnsupportedVersion { found: u32 },
+    #[error("the filesystem uses unknown features {0:#x}, it can only be mounted read-only")]
+    IncompatibleFeatures(u64),
+    #[error("the filesystem is mounted read-only")]
+    ReadOnly,
+    #[error("the filesystem has {0} problems, run `fsck` to see them")]
+    NotClean(usize),
+    #[error("directory {dir}: block {block} is corrupt, {reason}")]
+    CorruptDirectory {
+        dir: INumber,
+        block: usize,
+        reason: &'static str,
+    },
+    #[error("the filesystem has {filesystem} byte blocks but the disk has {device} byte blocks")]
+    BlockSizeMismatch { filesystem: usize, device: usize },
+    #[error("no free inodes left")]
+    NoFreeInodes,
+    #[error("no free blocks left")]
+    NoFreeBlocks,
+    #[error("inode {0} does not exist")]
+    InvalidInode(INumber),
+    #[error("no filesystem is mounted")]
+    NotMounted,
+    #[error("offset {0} is past the end of the file")]
+    OffsetPastEnd(usize),
+    #[error("file size {0} exceeds the maximum file size of {MAX_FILE_SIZE}")]
+    FileTooLarge(usize),
+    #[error("{0}: no such file or directory")]
+    NotFound(String),
+    #[error("{0}: file exists")]
+    AlreadyExists(String),
+    #[error("{0}: not a directory")]
+    NotADirectory(String),
+    #[error("{0}: is a directory")]
+    IsADirectory(String),
+    #[error("{0}: directory not empty")]
+    DirectoryNotEmpty(String),
+    #[error("{0}: file name too long")]
+    NameTooLong(String),
+    #[error("{0}: operation not supported")]
+    NotSupported(String),
+    #[error("{0}: invalid argument")]
+    InvalidArgument(String),
+    #[error("{0}: can't move a directory into itself")]
+    MoveIntoSelf(String),
+    #[error("{0}: can't move or link to another filesystem")]
+    CrossDevice(String),
+    #[error("{0}: a filesystem is mounted there or files are open on it")]
+    Busy(String),
+    #[error("{0}: not a mount point")]
+    NotMountPoint(String),
+    #[error("refusing to remove the root directory")]
+    RemoveRoot,
+    #[error("{path}: {error}")]
+    RemoveFailed {
+        path: String,
+        error: alloc::boxed::Box<FileSystemError>,
+    },
+    #[error(
+        "inode {inumber}: checksum mismatch, expected {expected:#010x} but got {actual:#010x}"
+    )]
+    ChecksumMismatch {
+        inumber: INumber,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("block {0} holds filesystem metadata")]
+    ReservedBlock(usize),
+    #[error("the bad block list is full")]
+    BadBlockListFull,
+    #[error("inode {0}: stale file handle")]
+    StaleHandle(INumber),
+    #[error("block {block} is used by both inode {first} and inode {second}")]
+    CrossLinkedBlock {
+        block: usize,
+        first: INumber,
+        second: INumber,
+    },
+    #[error("inode {inumber}: block {n} of the file has no pointer")]
+    MissingBlock { inumber: INumber, n: usize },
+    #[error("inode {inumber}: points to block {block}, which is not a data block")]
+    InvalidBlockPointer { inumber: INumber, block: usize },
+    #[error("inode {inumber}: link count is {links} but {entries} directory entries point to it")]
+    LinkCountMismatch {
+        inumber: INumber,
+        links: u32,
+        entries: u32,
+    },
+    #[error("inode {0}: the map block of the compressed file is corrupt")]
+    CorruptBlockMap(INumber),
+    #[error("inode {inumber}: compressed block {n} is corrupt, {error}")]
+    CorruptCompressedBlock {
+        inumber: INumber,
+        n: usize,
+        error: LzError,
+    },
+    #[error("interrupted")]
+    Interrupted,
+    #[error("disk error: {0}")]
+    Disk(#[from] DiskError),
+    #[error("ext2: {0}")]
+    Ext2(#[from] super::ext2::Ext2Error),
+}
+
+impl error::Error for FileSystemError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NotFound(_) | Self::InvalidInode(_) | Self::StaleHandle(_) | Self::NotMounted => {
+                ErrorKind::NotFound
+            }
+            Self::NoFreeInodes
+            | Self::NoFreeBlocks
+            | Self::FileTooLarge(_)
+            | Self::BadBlockListFull => ErrorKind::OutOfSpace,
+            Self::InvalidMagicNumber(_)
+            | Self::CorruptSuperblock(_)
+            | Self::SuperblockChecksum { .. }
+            | Self::SuperblockMismatch(_)
+            | Self::CorruptDirectory { .. }
+            | Self::BlockSizeMismatch { .. }
+            | Self::ChecksumMismatch { .. }
+            | Self::CrossLinkedBlock { .. }
+            | Self::MissingBlock { .. }
+            | Self::InvalidBlockPointer { .. }
+            | Self::LinkCountMismatch { .. }
+            | Self::CorruptBlockMap(_)
+            | Self::CorruptCompressedBlock { .. }
+            | Self::NotClean(_) => ErrorKind::Corrupt,
+            Self::UnsupportedVersion { .. }
+            | Self::IncompatibleFeatures(_)
+            | Self::ReadOnly
+            | Self::NotSupported(_)
+            | Self::CrossDevice(_) => ErrorKind::Unsupported,
+            Self::OffsetPastEnd(_)
+            | Self::AlreadyExists(_)
+            | Self::NotADirectory(_)
+            | Self::IsADirectory(_)
+            | Self::DirectoryNotEmpty(_)
+            | Self::NameTooLong(_)
+            | Self::InvalidArgument(_)
+            | Self::MoveIntoSelf(_)
+            | Self::NotMountPoint(_)
+            | Self::RemoveRoot
+            | Self::ReservedBlock(_) => ErrorKind::InvalidInput,
+            Self::RemoveFailed { error, .. } => error.kind(),
+            Self::Interrupted | Self::Busy(_) => ErrorKind::Other,
+            Self::Disk(err) => err.kind(),
+            Self::Ext2(err) => err.kind(),
+        }
+    }
+
+    fn source(&self) -> Option<&dyn error::Error> {
+        match self {
+            Self::RemoveFailed { error, .. } => Some(&**error),
+            Self::CorruptCompressedBlock { error, .. } => Some(error),
+            Self::Disk(err) => Some(err),
+            Self::Ext2(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// One of the two copies of the superblock written by [`FileSystem::format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuperblockCopy {
+    /// The superblock in the first block of the disk.
+    Primary,
+    /// The copy in the last block of the disk.
+    Backup,
+}
+
+impl SuperblockCopy {
+    /// Returns the block of the disk holding this copy.
+    pub fn block(self) -> usize {
+        match self {
+            Self::Primary => 0,
+            Self::Backup => disk::size() - 1,
+        }
+    }
+}
+
+impl core::fmt::Display for SuperblockCopy {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.pad(match self {
+            Self::Primary => "primary",
+            Self::Backup => "backup",
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct BadBlockList {
+    len: u32,
+    blocks: [u32; MAX_BAD_BLOCKS],
 }
 
-struct Superblock {
-    magic_number: usize,
-    blocks: usize,
-    inode_blocks: usize,
-    inodes: usize,
+/// Where a pointer to a data block is stored, as found by [`FileSystem::find_block_owner`].
+enum BlockOwner {
+    Direct(INumber, usize),
+    Indirect(INumber),
+    IndirectEntry(BlockPtr, usize),
 }
+
 type InodeBlock = [Inode; INODES_PER_BLOCK];
 type PointerBlock = [Option<BlockPtr>; PTRS_PER_BLOCK];
 type DataBlock = [u8; disk::BLOCK_SIZE];
 
-union Block<'a> {
-    superblock: &'a Superblock,
-    inodes: &'a InodeBlock,
-    pointers: &'a PointerBlock,
-    data: &'a DataBlock,
+/// The map block of a compressed file, see [`MAP_ENTRIES`].
+struct BlockMap {
+    size: usize,
+    offsets: [u32; MAP_ENTRIES],
+}
+
+impl BlockMap {
+    fn decode(bytes: &DataBlock) -> Self {
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Self {
+            size: u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize,
+            offsets: core::array::from_fn(|i| u32_at(8 + i * 4)),
+        }
+    }
+
+    fn encode(&self) -> DataBlock {
+        let mut bytes = [0; disk::BLOCK_SIZE];
+        bytes[..8].copy_from_slice(&(self.size as u64).to_le_bytes());
+        for (entry, offset) in bytes[8..].chunks_exact_mut(4).zip(&self.offsets) {
+            entry.copy_from_slice(&offset.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+#[repr(C)]
+union Block {
+    bad_blocks: BadBlockList,
+    inodes: InodeBlock,
+    pointers: PointerBlock,
+    data: DataBlock,
 }
 
-impl<'a> Block<'a> {
-    fn from_le_bytes(bytes: &'a [u8]) -> Option<&'a Block> {
-        let ptr = bytes as *const _ as *const Block;
-        unsafe { ptr.as_ref() }
+impl Block {
+    fn zeroed() -> Self {
+        Block {
+            data: [0; disk::BLOCK_SIZE],
+        }
     }
 }
 
 impl Inode {
-    fn new(valid: bool) -> Self {
+    fn new(valid: bool, kind: InodeKind, generation: Generation) -> Self {
         Self {
-            valid,
+            flags: match valid {
+                true => InodeFlags::VALID,
+                false => InodeFlags::empty(),
+            },
+            kind,
+            generation,
+            checksum: 0,
             size: 0,
+            created: 0,
+            modified: 0,
             direct: [None; PTRS_PER_INODE],
             indirect: None,
+            links: 0,
         }
     }
+
+    fn is_valid(&self) -> bool {
+        self.flags.contains(InodeFlags::VALID)
+    }
+
+    fn is_compressed(&self) -> bool {
+        self.flags.contains(InodeFlags::COMPRESSED)
+    }
 }
 
 impl FileSystem {
     pub fn new() -> Self {
         Self {
-            superblock: Superblock {
-                magic_number: 0,
-                blocks: 0,
-                inode_blocks: 0,
-                inodes: 0,
-            },
+            superblock: Superblock::default(),
+            mounted_from: None,
             block_bitmap: Vec::new(),
+            bad_blocks: Vec::new(),
+            read_ahead: DEFAULT_READ_AHEAD,
+            read_only: false,
+            reserved_blocks: DEFAULT_RESERVED_BLOCKS,
+            dir_indexes: RefCell::new(DirIndexes::default()),
+            inodes: RefCell::new(InodeCache::default()),
+            changes: Cell::new(0),
         }
     }
 
-    pub fn format() {
-        // The superblock should be formatted as [MAGIC_NUMBER, BLOCKS, INODE_BLOCKS, INODES]
-        let blocks = disk::size();
-        let inode_blocks = blocks / 10 + 1;
-        let inodes = inode_blocks * INODES_PER_BLOCK;
-        let superblock: Vec<u8> = [MAGIC_NUMBER, blocks, inode_blocks, inodes]
-            .iter()
-            .map(|v| v.to_le_bytes())
-            .flatten()
-            .collect();
+    pub fn format() -> Result<(), FileSystemError> {
+        Self::check_block_size(disk::BLOCK_SIZE)?;
 
-        // Write the superblock to disk block 0 (the first block)
-        disk::write(0, 0, &superblock).unwrap();
+        // The backup superblock is kept in the last block, which is left out of the filesystem
+        let blocks = disk::size() - 1;
+        let inode_blocks = blocks / 10 + 1;
+        let superblock = Superblock {
+            magic_number: MAGIC_NUMBER,
+            blocks,
+            inode_blocks,
+            inodes: inode_blocks * INODES_PER_BLOCK,
+            version: VERSION,
+            block_size: disk::BLOCK_SIZE,
+            incompat_features: 0,
+        };
+        for copy in [SuperblockCopy::Primary, SuperblockCopy::Backup] {
+            Self::write_superblock(copy.block(), &superblock)?;
+        }
 
-        // Clear all inode blocks
+        // Clear the bad block list and all inode blocks
         let zero_data = [0u8; disk::BLOCK_SIZE];
-        for i in INODE_BLOCKS_START..inode_blocks + INODE_BLOCKS_START {
-            disk::write(i, 0, &zero_data).unwrap();
+        for i in BAD_BLOCKS_BLOCK..inode_blocks + INODE_BLOCKS_START {
+            disk::write(i, 0, &zero_data)?;
         }
+
+        // Create the (empty) root directory, which counts as a link to itself
+        let mut root = Inode::new(true, InodeKind::Directory, 0);
+        root.links = 1;
+        root.created = crate::timer::ticks();
+        root.modified = root.created;
+        let mut inodes = InodeCache::default();
+        inodes.put(ROOT_INUMBER, &root)?;
+        inodes.write_back()?;
+        disk::flush()?;
+        Ok(())
     }
 
-    pub fn mount(&mut self) {
-        let mut buf = [0; disk::BLOCK_SIZE];
+    /// Checks that a filesystem with blocks of `block_size` bytes can live on the disk. The block
+    /// size is fixed when building the kernel, so it also has to match [`disk::BLOCK_SIZE`].
+    fn check_block_size(block_size: usize) -> Result<(), FileSystemError> {
+        let device = disk::block_size();
+        if block_size != device || device != disk::BLOCK_SIZE {
+            return Err(FileSystemError::BlockSizeMismatch {
+                filesystem: block_size,
+                device,
+            });
+        }
+        Ok(())
+    }
+
+    /// Mounts the filesystem for reading and writing. Fails if it uses features this kernel can't
+    /// write, such a filesystem can still be mounted with [`Self::mount_read_only`].
+    pub fn mount(&mut self) -> Result<(), FileSystemError> {
+        self.mount_with(MountFlags::empty())
+    }
+
+    /// Mounts the filesystem so that it can only be read, see [`MountFlags::READ_ONLY`].
+    pub fn mount_read_only(&mut self) -> Result<(), FileSystemError> {
+        self.mount_with(MountFlags::READ_ONLY)
+    }
+
+    /// Mounts the filesystem on the kernel disk with the flags, leaving it unmounted if that
+    /// fails. Pending writes of a filesystem mounted already are flushed first. After a read-only
+    /// mount nothing reaches the disk until the filesystem is unmounted or
+    /// [remounted writable](Self::remount_writable).
+    pub fn mount_with(&mut self, flags: MountFlags) -> Result<(), FileSystemError> {
+        let result = self.load(flags.contains(MountFlags::READ_ONLY));
+        if result.is_err() {
+            self.unmount();
+        }
+        result
+    }
+
+    /// Makes a filesystem mounted read-only writable, once [`Self::check`] finds no problems
+    /// with it. Does nothing if it's writable already.
+    pub fn remount_writable(&mut self) -> Result<(), FileSystemError> {
+        self.check_mounted()?;
+        if !self.read_only {
+            return Ok(());
+        }
+        let unknown = self.superblock.incompat_features & !KNOWN_INCOMPAT_FEATURES;
+        if unknown != 0 {
+            return Err(FileSystemError::IncompatibleFeatures(unknown));
+        }
+        let problems = self.check(None)?;
+        if !problems.is_empty() {
+            return Err(FileSystemError::NotClean(problems.len()));
+        }
+        disk::write_enable();
+        self.read_only = false;
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.mounted_from.is_some()
+    }
+
+    /// Returns the copy of the superblock the filesystem was mounted from, `None` if it isn't
+    /// mounted.
+    pub fn mounted_from(&self) -> Option<SuperblockCopy> {
+        self.mounted_from
+    }
+
+    /// Fails with [`FileSystemError::NotMounted`] unless a filesystem is mounted.
+    pub fn check_mounted(&self) -> Result<(), FileSystemError> {
+        match self.is_mounted() {
+            true => Ok(()),
+            false => Err(FileSystemError::NotMounted),
+        }
+    }
+
+    /// Forgets the mounted filesystem, after which everything but mounting fails with
+    /// [`FileSystemError::NotMounted`]. Changed inodes still in the inode cache are flushed first.
+    pub fn unmount(&mut self) {
+        if self.read_only {
+            disk::write_enable();
+        } else if let Err(err) = self.sync() {
+            log!(LogLevel::Error, "unmounting lost changed inodes: {}", err);
+        }
+        *self = Self {
+            read_ahead: self.read_ahead,
+            reserved_blocks: self.reserved_blocks,
+            changes: Cell::new(self.changes.get() + 1),
+            ..Self::new()
+        };
+    }
+
+    /// Writes the inodes changed since the last flush to the disk, so that the disk can be read
+    /// or written without going through the filesystem.
+    pub fn sync(&self) -> Result<(), FileSystemError> {
+        Ok(self.flush()?)
+    }
+
+    /// Drops every inode from the inode cache after the inode blocks were written without going
+    /// through the filesystem, so that the new inodes are read. Unsynced changes are lost.
+    pub fn invalidate_inode_cache(&self) {
+        self.inodes.borrow_mut().clear();
+    }
+
+    /// Drops the inode cache when the heap runs out, see [`InodeCache::shrink`]. Frees nothing if
+    /// the cache is in use.
+    pub fn shrink_inode_cache(&self) -> usize {
+        self.inodes
+            .try_borrow_mut()
+            .map_or(0, |mut inodes| inodes.shrink())
+    }
+
+    pub fn inode_cache_stats(&self) -> InodeCacheStats {
+        self.inodes.borrow().stats()
+    }
+
+    /// Makes both copies of the superblock match again, returning the copies which were
+    /// rewritten. A mounted filesystem is written to both. Otherwise the superblock is found like
+    /// when mounting, so a damaged primary superblock is restored from the backup.
+    pub fn repair_superblock(&mut self) -> Result<Vec<SuperblockCopy>, FileSystemError> {
+        let superblock = match self.is_mounted() {
+            true => {
+                self.check_writable()?;
+                self.superblock
+            }
+            false => Self::find_superblock(false)?.0,
+        };
+        let mut repaired = Vec::new();
+        for copy in [SuperblockCopy::Primary, SuperblockCopy::Backup] {
+            if Self::read_superblock(copy.block())? != superblock.encode() {
+                Self::write_superblock(copy.block(), &superblock)?;
+                repaired.push(copy);
+            }
+        }
+        disk::flush()?;
+        Ok(repaired)
+    }
+
+    /// Reads the superblock from the primary copy, or from the backup if the primary is damaged.
+    /// A superblock which is intact but can't be mounted, like one of another version, isn't
+    /// damaged, so the backup isn't tried and the error of the primary is returned.
+    fn find_superblock(read_only: bool) -> Result<(Superblock, SuperblockCopy), FileSystemError> {
+        let err = match Self::load_superblock(SuperblockCopy::Primary, read_only) {
+            Ok(sb) => return Ok((sb, SuperblockCopy::Primary)),
+            Err(
+                err @ (FileSystemError::InvalidMagicNumber(_)
+                | FileSystemError::CorruptSuperblock(_)
+                | FileSystemError::SuperblockChecksum { .. }
+                | FileSystemError::Disk(_)),
+            ) => err,
+            Err(err) => return Err(err),
+        };
+        match Self::load_superblock(SuperblockCopy::Backup, read_only) {
+            Ok(sb) => Ok((sb, SuperblockCopy::Backup)),
+            // The primary superblock failing is what matters, the backup was only a fallback
+            Err(_) => Err(err),
+        }
+    }
 
-        let sb = Self::read_block(0, &mut buf);
-        let sb = unsafe { sb.superblock };
+    /// Reads a copy of the superblock, checking that it describes a filesystem this kernel can
+    /// mount.
+    fn load_superblock(
+        copy: SuperblockCopy,
+        read_only: bool,
+    ) -> Result<Superblock, FileSystemError> {
+        let sb = Superblock::decode(&Self::read_superblock(copy.block())?)?;
+        if sb.version != VERSION {
+            return Err(FileSystemError::UnsupportedVersion { found: sb.version });
+        }
+        Self::check_block_size(sb.block_size)?;
+        let unknown = sb.incompat_features & !KNOWN_INCOMPAT_FEATURES;
+        if unknown != 0 && !read_only {
+            return Err(FileSystemError::IncompatibleFeatures(unknown));
+        }
+        if sb.blocks > SuperblockCopy::Backup.block() {
+            return Err(FileSystemError::CorruptSuperblock(
+                "it has more blocks than the disk",
+            ));
+        }
+        if sb.inode_blocks + INODE_BLOCKS_START >= sb.blocks {
+            return Err(FileSystemError::CorruptSuperblock(
+                "the inode blocks don't fit on the disk",
+            ));
+        }
+        if sb.inodes != sb.inode_blocks * INODES_PER_BLOCK {
+            return Err(FileSystemError::CorruptSuperblock(
+                "the inode count doesn't match the inode blocks",
+            ));
+        }
+        Ok(sb)
+    }
 
-        if sb.magic_number != MAGIC_NUMBER {
-            panic!("encountered invalid magic number while mounting drive");
+    fn load(&mut self, read_only: bool) -> Result<(), FileSystemError> {
+        // Mounting again drops the inode cache, whose changes would otherwise never reach the disk
+        if self.is_mounted() && !self.read_only {
+            self.sync()?;
+        }
+        // Protected before anything is read, a damaged superblock is left as it is
+        match read_only {
+            true => disk::write_protect()?,
+            false => disk::write_enable(),
         }
+        self.read_only = read_only;
+        *self.dir_indexes.get_mut() = DirIndexes::default();
+        *self.inodes.get_mut() = InodeCache::default();
+        let (sb, copy) = Self::find_superblock(read_only)?;
+        if copy == SuperblockCopy::Backup {
+            log!(
+                LogLevel::Warn,
+                "the primary superblock is damaged, mounting from the backup in block {}",
+                copy.block()
+            );
+        }
+        self.superblock = sb;
+        self.mounted_from = Some(copy);
 
-        self.block_bitmap = (0..sb.blocks / u64::BITS as usize)
-            .map(|_| 0xbbbbbbbbbbbbbbbb) // 0b1111...
+        // A set bit marks a free block. Bits past the end of the disk are marked as used.
+        self.block_bitmap = (0..sb.blocks.div_ceil(u64::BITS as usize))
+            .map(|i| {
+                let blocks_left = sb.blocks - i * u64::BITS as usize;
+                if blocks_left >= u64::BITS as usize {
+                    u64::MAX
+                } else {
+                    (1 << blocks_left) - 1
+                }
+            })
             .collect();
 
         // Mark the first block (index 0) as used, as it's the superblock
-        self.block_bitmap[0] &= !(1);
+        self.block_bitmap[0] &= !1;
+
+        // Bad blocks are never allocated
+        self.mark_block(BlockPtr::new(BAD_BLOCKS_BLOCK as u32).unwrap(), false);
+        let list = unsafe { Self::read_block(BAD_BLOCKS_BLOCK)?.bad_blocks };
+        self.bad_blocks = list.blocks[..(list.len as usize).min(MAX_BAD_BLOCKS)]
+            .iter()
+            .filter_map(|&block| BlockPtr::new(block))
+            .collect();
+        for block in self.bad_blocks.clone() {
+            self.mark_block(block, false);
+        }
 
         for block_idx in INODE_BLOCKS_START..sb.inode_blocks + INODE_BLOCKS_START {
-            let block = Self::read_block(block_idx, &mut buf);
+            let block = Self::read_block(block_idx)?;
 
             // Mark inode blocks as used
             self.mark_block(BlockPtr::new(block_idx as u32).unwrap(), false);
 
             for inode in unsafe { block.inodes } {
-                if !inode.valid {
+                if !inode.is_valid() {
                     continue;
                 }
 
-                for block in inode.direct {
-                    if let Some(block) = block {
+                for block in inode.direct.into_iter().flatten() {
+                    self.mark_block(block, false);
+                }
+
+                if let Some(block) = inode.indirect {
+                    self.mark_block(block, false);
+                    for block in Self::read_pointer_block(block)?.into_iter().flatten() {
                         self.mark_block(block, false);
                     }
                 }
             }
         }
+        Ok(())
     }
 
-    pub fn create(&self) -> Option<INumber> {
-        let inumber = self.next_free_inode()?;
-        let file = Inode::new(true);
-        Self::write_inode(inumber, &file);
-        Some(inumber)
+    /// Creates a new file with a link count of one, for the directory entry the caller is expected
+    /// to add. Until then, the file shows up as a link count mismatch in [`Self::check`].
+    pub fn create(&self, kind: InodeKind) -> Result<INumber, FileSystemError> {
+        self.create_with(kind, InodeFlags::empty())
     }
 
-    pub fn delete(&mut self, inumber: INumber) {
-        // Mark all directly pointed to data blocks as free
-        let inode = Self::read_inode(inumber);
-        for ptr in inode.direct {
-            if let Some(block_idx) = ptr {
-                self.mark_block(block_idx, true);
-            }
+    /// Creates a file like [`Self::create`] whose contents are compressed a block at a time, so
+    /// that reading part of it only decompresses the blocks read. It's read and written like any
+    /// other file, [`Metadata::physical_size`] shows the space it takes up.
+    ///
+    /// A block which no longer fits in its place when it's rewritten is moved to the end of the
+    /// file, and shrinking the file doesn't free its blocks. The space left unused is given back
+    /// when the file is packed with [`Self::set_compressed`].
+    pub fn create_compressed(&mut self) -> Result<INumber, FileSystemError> {
+        self.check_writable()?;
+        self.enable_features(INCOMPAT_COMPRESSION)?;
+        self.create_with(InodeKind::File, InodeFlags::COMPRESSED)
+    }
+
+    fn create_with(&self, kind: InodeKind, flags: InodeFlags) -> Result<INumber, FileSystemError> {
+        self.check_writable()?;
+        let inumber = self
+            .next_free_inode()?
+            .ok_or(FileSystemError::NoFreeInodes)?;
+        // The generation was already bumped when the previous file using the inode was deleted
+        let generation = self.read_inode(inumber)?.generation;
+        let mut file = Inode::new(true, kind, generation);
+        file.flags = file.flags | flags;
+        file.links = 1;
+        file.created = crate::timer::ticks();
+        file.modified = file.created;
+        self.write_inode(inumber, &file)?;
+        Ok(inumber)
+    }
+
+    /// Sets the modification time of the file to now, without changing its contents.
+    pub fn touch(&mut self, inumber: INumber) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        if proc::is_proc(inumber) {
+            return Err(proc::not_supported(inumber));
         }
+        let mut inode = self.valid_inode(inumber)?;
+        inode.modified = crate::timer::ticks();
+        self.write_inode(inumber, &inode)?;
+        Ok(())
+    }
 
-        // Mark all indirectly pointed to data blocks as free
-        if let Some(block) = inode.indirect {
-            let mut buf = [0; disk::BLOCK_SIZE];
-            let block = Self::read_block(block.get() as usize, &mut buf);
-            for ptr in unsafe { block.pointers } {
-                if let &Some(block_idx) = ptr {
-                    self.mark_block(block_idx, true)
-                }
-            }
+    /// Frees the inode and its blocks, no matter how many directory entries point to it. Files are
+    /// removed through [`Self::unlink`], which only deletes them when the last link is gone.
+    pub(super) fn delete(&mut self, inumber: INumber) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        let inode = self.valid_inode(inumber)?;
+        let blocks = Self::blocks_from(&inode, 0)?;
+        self.forget_dir_index(inumber);
+
+        // Overwrite the inode, invalidating any open handles to it. It's dropped from the inode
+        // cache, so that a file reusing it starts from what's on the disk.
+        let new_inode = Inode::new(false, InodeKind::File, inode.generation.wrapping_add(1));
+        self.write_inode(inumber, &new_inode)?;
+        self.inodes.borrow_mut().evict(inumber)?;
+        self.flush()?;
+        for block in blocks {
+            self.mark_block(block, true);
         }
+        Ok(())
+    }
 
-        // Overwrite the inode
-        let new_inode = Inode::new(false);
-        Self::write_inode(inumber, &new_inode);
+    pub fn stat(&self, inumber: INumber) -> Result<Metadata, FileSystemError> {
+        if proc::is_proc(inumber) {
+            return proc::stat(inumber);
+        }
+        let inode = self.valid_inode(inumber)?;
+        Ok(Metadata {
+            kind: inode.kind,
+            size: Self::content_size(inumber, &inode)?,
+            physical_size: inode.size,
+            compressed: inode.is_compressed(),
+            blocks: Self::data_blocks(&inode)?,
+            created: inode.created,
+            modified: inode.modified,
+            links: inode.links,
+        })
     }
 
-    pub fn read(&self, inumber: INumber, offset: usize, outbuf: &mut [u8]) -> Result<usize, ()> {
-        let inode = Self::read_inode(inumber);
+    pub fn read(
+        &self,
+        inumber: INumber,
+        offset: usize,
+        outbuf: &mut [u8],
+    ) -> Result<usize, FileSystemError> {
+        if proc::is_proc(inumber) {
+            return proc::read(self, inumber, offset, outbuf);
+        }
+        let inode = self.valid_inode(inumber)?;
+        match inode.is_compressed() {
+            true => Self::read_compressed(inumber, &inode, offset, outbuf),
+            false => Self::read_data(&inode, offset, outbuf),
+        }
+    }
 
-        if inode.size <= offset {
-            return Err(());
+    /// Reads the blocks of the file, which for a compressed file hold the compressed contents.
+    fn read_data(
+        inode: &Inode,
+        offset: usize,
+        outbuf: &mut [u8],
+    ) -> Result<usize, FileSystemError> {
+        if inode.size < offset {
+            return Err(FileSystemError::OffsetPastEnd(offset));
         }
 
         let bytes_to_read = outbuf.len().min(inode.size - offset);
         let mut bytes_read = 0;
+        if bytes_to_read == 0 {
+            return Ok(0);
+        }
 
         let (first_ptr_idx, first_offset) = (offset / disk::BLOCK_SIZE, offset % disk::BLOCK_SIZE);
         if first_ptr_idx < inode.direct.len() {
@@ -185,7 +1078,7 @@ impl FileSystem {
                 first_offset,
                 bytes_to_read - bytes_read,
                 outbuf,
-            );
+            )?;
 
             // If we are done reading, return
             if bytes_read >= bytes_to_read {
@@ -193,154 +1086,2378 @@ impl FileSystem {
             }
 
             // Otherwise, keep reading from the indirect pointers
-            if let Some(ptr) = inode.indirect {
-                let mut buf = [0; disk::BLOCK_SIZE];
-                let pointers = Self::read_pointer_block(ptr, &mut buf);
-                bytes_read +=
-                    Self::read_raw_data_many(pointers, 0, bytes_to_read - bytes_read, outbuf);
-            }
-        } else if let Some(ptr) = inode.indirect {
+            let pointers = Self::indirect_pointers(inode)?;
+            bytes_read += Self::read_raw_data_many(
+                &pointers,
+                0,
+                bytes_to_read - bytes_read,
+                &mut outbuf[bytes_read..],
+            )?;
+        } else {
             // Offset puts us into the indirect pointers from the start
-            let mut buf = [0; disk::BLOCK_SIZE];
-            let pointers = Self::read_pointer_block(ptr, &mut buf);
+            let pointers = Self::indirect_pointers(inode)?;
             bytes_read += Self::read_raw_data_many(
                 &pointers[first_ptr_idx - inode.direct.len()..],
                 first_offset,
                 bytes_to_read - bytes_read,
                 outbuf,
-            );
+            )?;
         }
 
         Ok(bytes_read)
     }
 
-    pub fn write(&mut self, inumber: INumber, offset: usize, data: &[u8]) -> Result<usize, ()> {
-        let inode = Self::read_inode(inumber);
-        let allocated_blocks = Self::allocated_blocks(inode.size);
-        let new_size = offset + data.len();
-        let new_allocated_blocks = Self::allocated_blocks(new_size);
-
-        // Allocate or deallocate blocks if needed
+    /// Writes `data` to the file at `offset`, allocating blocks as needed. Writing past the end of
+    /// the file leaves a hole, which reads as zeroes but has no blocks until it's written. Fails
+    /// with [`FileSystemError::NoFreeBlocks`] rather than use the reserved blocks, and with
+    /// [`FileSystemError::FileTooLarge`] if the file would grow past [`MAX_FILE_SIZE`], or
+    /// [`MAX_COMPRESSED_FILE_SIZE`] for a compressed file.
+    pub fn write(
+        &mut self,
+        inumber: INumber,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize, FileSystemError> {
+        self.write_with(inumber, offset, data, false)
+    }
 
-        Ok(0)
+    /// Writes like [`Self::write`], but may allocate the reserved blocks. For the metadata the
+    /// filesystem needs to keep working, like directories.
+    pub(super) fn write_reserved(
+        &mut self,
+        inumber: INumber,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize, FileSystemError> {
+        self.write_with(inumber, offset, data, true)
     }
 
-    fn allocated_blocks(size: usize) -> usize {
-        if size == 0 {
-            0
-        } else {
-            (size - 1) / disk::BLOCK_SIZE + 1
+    fn write_with(
+        &mut self,
+        inumber: INumber,
+        offset: usize,
+        data: &[u8],
+        use_reserve: bool,
+    ) -> Result<usize, FileSystemError> {
+        if proc::is_proc(inumber) {
+            return proc::write(inumber, data);
+        }
+        self.check_writable()?;
+        let mut inode = self.valid_inode(inumber)?;
+        self.forget_dir_index(inumber);
+        match inode.is_compressed() {
+            true => self.write_compressed(inumber, &mut inode, offset, data, use_reserve)?,
+            false => self.write_data(&mut inode, offset, data, use_reserve)?,
         }
+        inode.modified = crate::timer::ticks();
+        inode.checksum = Self::compute_checksum(&inode)?;
+        self.write_pointers_and_inode(inumber, &inode, None)?;
+        Ok(data.len())
     }
 
-    /// Marks a block as free or busy. Values of zero for the block index are disallowed, as that's the index of the superblock.
-    fn mark_block(&mut self, block: BlockPtr, free: bool) {
-        let block_idx = block.get();
-        let (idx, offset) = ((block_idx / u64::BITS) as usize, block_idx % u64::BITS);
-        self.block_bitmap[idx] &= !(1 << offset);
-        self.block_bitmap[idx] |= 1 << free as u64;
-    }
+    /// Writes to the blocks of the file, allocating the ones written to which are missing, and
+    /// grows the size of `inode` to match. The data and then the indirect block are written, the
+    /// inode is left to the caller.
+    fn write_data(
+        &mut self,
+        inode: &mut Inode,
+        offset: usize,
+        data: &[u8],
+        use_reserve: bool,
+    ) -> Result<(), FileSystemError> {
+        let new_size = offset + data.len();
+        if new_size > MAX_FILE_SIZE {
+            return Err(FileSystemError::FileTooLarge(new_size));
+        }
 
-    fn is_free(&self, block: BlockPtr) -> bool {
-        let block_idx = block.get();
-        let (idx, offset) = ((block_idx / u64::BITS) as usize, block_idx % u64::BITS);
-        self.block_bitmap[idx] & (1 << offset) > 0
-    }
+        // Allocate blocks if needed, the blocks skipped over are left as a hole
+        let blocks = offset / disk::BLOCK_SIZE..Self::allocated_blocks(new_size);
+        let (pointers, pointers_changed) = self.grow(inode, blocks, use_reserve)?;
 
-    /// Finds the next free inode and returns its `inumber`.
-    fn next_free_inode(&self) -> Option<INumber> {
-        let mut buf = [0; disk::BLOCK_SIZE];
-        for block_idx in INODE_BLOCKS_START..self.superblock.inode_blocks + INODE_BLOCKS_START {
-            let block = Self::read_block(block_idx, &mut buf);
-            for (offset, inode) in unsafe { block.inodes }.iter().enumerate() {
-                let file = unsafe { (inode as *const _ as *const Inode).as_ref().unwrap() };
-                if !file.valid {
-                    let inumber = (block_idx - INODE_BLOCKS_START) * INODES_PER_BLOCK + offset;
-                    return Some(inumber as INumber);
-                }
+        let mut bytes_written = 0;
+        while bytes_written < data.len() {
+            let pos = offset + bytes_written;
+            let (ptr_idx, block_offset) = (pos / disk::BLOCK_SIZE, pos % disk::BLOCK_SIZE);
+            let len = (disk::BLOCK_SIZE - block_offset).min(data.len() - bytes_written);
+            let block = if ptr_idx < PTRS_PER_INODE {
+                inode.direct[ptr_idx]
+            } else {
+                pointers[ptr_idx - PTRS_PER_INODE]
             }
+            .expect("null block pointer in inode");
+
+            disk::write(
+                block.get() as usize,
+                block_offset,
+                &data[bytes_written..bytes_written + len],
+            )?;
+            bytes_written += len;
         }
-        None
-    }
+        self.flush()?;
 
-    fn next_free_block(&self) -> Option<BlockPtr> {
-        let (idx, &bitmask) = self
-            .block_bitmap
-            .iter()
-            .enumerate()
-            .find(|(_, &value)| value > 0)?;
-        let first_one_idx = bitmask.trailing_zeros(); // number of trailing 0 will give the index of the first 1
-        NonZeroU32::new(idx as u32 * u64::BITS + first_one_idx)
+        if let (true, Some(indirect)) = (pointers_changed, inode.indirect) {
+            Self::write_block(indirect.get() as usize, &Block { pointers })?;
+            self.flush()?;
+        }
+        inode.size = inode.size.max(new_size);
+        Ok(())
     }
 
-    fn read_raw_data(block: BlockPtr, offset: usize, length: usize, outbuf: &mut [u8]) -> usize {
-        let mut buf = [0; disk::BLOCK_SIZE];
-        let block = Self::read_block(block.get() as usize, &mut buf);
-        let block_data = unsafe { block.data };
-        let block_data = &block_data[offset..block_data.len().min(offset + length)];
+    /// Sets the size of the file to `size`, freeing any blocks past the new end of the file or
+    /// leaving a hole up to the new size, like writing past the end does.
+    pub fn truncate(&mut self, inumber: INumber, size: usize) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        if proc::is_proc(inumber) {
+            return Err(proc::not_supported(inumber));
+        }
+        let mut inode = self.valid_inode(inumber)?;
+        if size > MAX_FILE_SIZE {
+            return Err(FileSystemError::FileTooLarge(size));
+        }
+        self.forget_dir_index(inumber);
 
-        let mut bytes_read = 0;
-        for (out, data) in outbuf.iter_mut().zip(block_data) {
-            *out = *data;
-            bytes_read += 1;
+        // Emptying a compressed file frees its blocks like for any other file
+        if inode.is_compressed() && size != 0 {
+            self.truncate_compressed(inumber, &mut inode, size)?;
+            inode.modified = crate::timer::ticks();
+            inode.checksum = Self::compute_checksum(&inode)?;
+            return self.write_pointers_and_inode(inumber, &inode, None);
         }
-        bytes_read
-    }
 
-    fn read_raw_data_many(
-        blocks: &[Option<BlockPtr>],
-        mut offset: usize,
-        length: usize,
-        outbuf: &mut [u8],
-    ) -> usize {
-        let mut bytes_read = 0;
-        let bytes_to_read = outbuf.len().min(length);
+        // The rest of the last block is already zeroes, see below
+        if size >= inode.size {
+            inode.size = size;
+            inode.modified = crate::timer::ticks();
+            inode.checksum = Self::compute_checksum(&inode)?;
+            return self.write_pointers_and_inode(inumber, &inode, None);
+        }
 
-        for ptr in blocks {
-            if let &Some(block_ptr) = ptr {
-                bytes_read +=
-                    Self::read_raw_data(block_ptr, offset, bytes_to_read - bytes_read, outbuf);
-                offset = 0; // set offset to 0 as we only want the offset for the first block
-            } else {
-                panic!("null block pointer in inode");
-            }
+        let blocks = Self::allocated_blocks(size);
+        let freed = Self::blocks_from(&inode, blocks)?;
 
-            if bytes_read >= bytes_to_read {
-                break;
+        // Clear the rest of the last block, so growing the file again reads zeroes
+        let tail = size % disk::BLOCK_SIZE;
+        if tail != 0 {
+            if let Some(block) = Self::block_ptr(&inode, blocks - 1)? {
+                disk::with_block_mut(block.get() as usize, |data| data[tail..].fill(0))?;
+                self.flush()?;
             }
         }
 
-        bytes_read
-    }
+        for ptr in inode.direct.iter_mut().skip(blocks) {
+            *ptr = None;
+        }
+        let indirect = inode.indirect;
+        if blocks <= PTRS_PER_INODE {
+            inode.indirect = None;
+        }
+        inode.size = size;
+        inode.modified = crate::timer::ticks();
+        inode.checksum = Self::compute_checksum(&inode)?;
+        self.write_inode(inumber, &inode)?;
+        self.flush()?;
 
-    fn read_block<'a>(block: usize, outbuf: &'a mut [u8]) -> &'a Block {
-        disk::read(block, 0, outbuf).expect("error when reading storage block");
-        Block::from_le_bytes(outbuf).expect("error when casting raw data as storage block")
+        if let (Some(ptr), Some(_)) = (indirect, inode.indirect) {
+            let mut pointers = Self::read_pointer_block(ptr)?;
+            for ptr in pointers.iter_mut().skip(blocks - PTRS_PER_INODE) {
+                *ptr = None;
+            }
+            Self::write_block(ptr.get() as usize, &Block { pointers })?;
+            self.flush()?;
+        }
+        for block in freed {
+            self.mark_block(block, true);
+        }
+        Ok(())
     }
 
-    fn read_pointer_block<'a>(block: BlockPtr, outbuf: &'a mut [u8]) -> &'a PointerBlock {
-        let block = Self::read_block(block.get() as usize, outbuf);
-        unsafe { block.pointers }
+    /// Like [`Self::truncate`], but frees the blocks past the new end one at a time from the end of
+    /// the file, so that it can stop with [`FileSystemError::Interrupted`] between them once
+    /// `cancel` or the job running the truncate is cancelled. The file is then left cut at the
+    /// last block freed.
+    pub fn truncate_with_cancel(
+        &mut self,
+        inumber: INumber,
+        size: usize,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), FileSystemError> {
+        // Growing a file frees nothing, and a compressed file is rewritten in one go
+        let shrinking = !proc::is_proc(inumber)
+            && self
+                .valid_inode(inumber)
+                .is_ok_and(|inode| !inode.is_compressed() && size < inode.size);
+        if !shrinking {
+            return self.truncate(inumber, size);
+        }
+        let map = self.block_map(inumber)?;
+        let freed = Self::allocated_blocks(size)..map.len();
+        for block in freed.rev().filter(|&block| map[block]) {
+            if job::cancelled(cancel) {
+                return Err(FileSystemError::Interrupted);
+            }
+            self.truncate(inumber, block * disk::BLOCK_SIZE)?;
+        }
+        self.truncate(inumber, size)
     }
 
-    fn write_inode(inumber: INumber, file: &Inode) {
-        let (block, offset) = Self::calc_inode_pos(inumber);
-        let buf_ptr = file as *const _ as *const [u8; size_of::<Inode>()];
-        disk::write(block, offset, unsafe { buf_ptr.as_ref().unwrap() }).unwrap();
+    /// Checks that the blocks of every file are where the inodes say: each block within the size
+    /// of a directory or compressed file has a pointer, pointers only point at data blocks, and no
+    /// block is used twice. Other files may have holes, see [`Self::write`].
+    /// Also checks that the link count of every file matches the directory entries pointing to it.
+    /// Returns the problems found.
+    ///
+    /// Stops with [`FileSystemError::Interrupted`] between inodes once `cancel` or the job running
+    /// the check is cancelled, see [`job::cancelled`].
+    pub fn check(
+        &self,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Vec<FileSystemError>, FileSystemError> {
+        let mut check = self.start_check()?;
+        while self.check_step(&mut check, cancel)? {}
+        Ok(check.finish())
     }
 
-    fn read_inode(inumber: INumber) -> Inode {
-        let (block, offset) = Self::calc_inode_pos(inumber);
-        let mut buf = [0; size_of::<Inode>()];
-        disk::read(block, offset, &mut buf).unwrap();
-        let file_ptr = &buf as *const _ as *const Inode;
-        unsafe { file_ptr.as_ref() }.unwrap().clone()
-    }
+    /// Starts a [`Self::check`] which is made a step at a time with [`Self::check_step`], so that
+    /// the filesystem only has to stay locked for each step.
+    pub fn start_check(&self) -> Result<Check, FileSystemError> {
+        self.check_mounted()?;
+        let mut problems = Vec::new();
+        for copy in [SuperblockCopy::Primary, SuperblockCopy::Backup] {
+            if Self::read_superblock(copy.block())? != self.superblock.encode() {
+                problems.push(FileSystemError::SuperblockMismatch(copy));
+            }
+        }
+        Ok(Check {
+            changes: self.changes.get(),
+            problems,
+            owners: BTreeMap::new(),
+            inodes: Vec::new(),
+            // The root directory has no entry pointing to it, it counts as its own link
+            entries: BTreeMap::from([(ROOT_INUMBER, 1)]),
+            next: 0,
+        })
+    }
+
+    /// Checks the next inode, or the entries of the next directory once every inode has been
+    /// checked. Returns whether there's more to check. The check starts over if the filesystem
+    /// was changed since the last step, and stops with [`FileSystemError::Interrupted`] once
+    /// `cancel` is cancelled.
+    pub fn check_step(
+        &self,
+        check: &mut Check,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<bool, FileSystemError> {
+        if job::cancelled(cancel) {
+            return Err(FileSystemError::Interrupted);
+        }
+        if check.changes != self.changes.get() {
+            *check = self.start_check()?;
+        }
+        let inodes = self.superblock.inodes;
+        if check.next < inodes {
+            self.check_inode(check, check.next as INumber)?;
+        } else if let Some(&(inumber, _)) = check.inodes.get(check.next - inodes) {
+            if self.stat(inumber)?.kind == InodeKind::Directory {
+                match self.list(inumber) {
+                    Ok(list) => {
+                        for entry in list {
+                            *check.entries.entry(entry.inumber).or_insert(0) += 1;
+                        }
+                    }
+                    Err(err @ FileSystemError::CorruptDirectory { .. }) => check.problems.push(err),
+                    Err(err) => return Err(err),
+                }
+            }
+        } else {
+            return Ok(false);
+        }
+        check.next += 1;
+        Ok(true)
+    }
+
+    /// Checks the block pointers of an inode, see [`Self::check`].
+    fn check_inode(&self, check: &mut Check, inumber: INumber) -> Result<(), FileSystemError> {
+        let inode = match self.valid_inode(inumber) {
+            Ok(inode) => inode,
+            Err(FileSystemError::InvalidInode(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        check.inodes.push((inumber, inode.links));
+        let mut blocks = inode.direct.iter().flatten().copied().collect::<Vec<_>>();
+        let mut pointers = None;
+        if let Some(indirect) = inode.indirect {
+            blocks.push(indirect);
+            if self.is_data_block(indirect) {
+                pointers = Some(Self::read_pointer_block(indirect)?);
+            }
+        }
+        blocks.extend(pointers.iter().flatten().flatten());
+
+        for block in blocks {
+            if !self.is_data_block(block) {
+                check.problems.push(FileSystemError::InvalidBlockPointer {
+                    inumber,
+                    block: block.get() as usize,
+                });
+            } else if let Some(&first) = check.owners.get(&block) {
+                check.problems.push(FileSystemError::CrossLinkedBlock {
+                    block: block.get() as usize,
+                    first,
+                    second: inumber,
+                });
+            } else {
+                check.owners.insert(block, inumber);
+            }
+        }
+        // Directories and compressed files have no holes once written, other files may
+        if inode.kind != InodeKind::Directory && !inode.is_compressed() {
+            return Ok(());
+        }
+        for n in 0..Self::allocated_blocks(inode.size) {
+            let ptr = match n.checked_sub(PTRS_PER_INODE) {
+                None => inode.direct[n],
+                Some(i) => pointers.and_then(|pointers| pointers[i]),
+            };
+            if ptr.is_none() {
+                check
+                    .problems
+                    .push(FileSystemError::MissingBlock { inumber, n });
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds one to the link count of the file, before a new directory entry is added for it.
+    pub(super) fn add_link(&mut self, inumber: INumber) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        if proc::is_proc(inumber) {
+            return Err(proc::not_supported(inumber));
+        }
+        let mut inode = self.valid_inode(inumber)?;
+        inode.links += 1;
+        self.write_inode(inumber, &inode)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Subtracts one from the link count of the file, after one of its directory entries has been
+    /// removed. The file is deleted when no links are left.
+    pub(super) fn drop_link(&mut self, inumber: INumber) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        let mut inode = self.valid_inode(inumber)?;
+        inode.links = inode.links.saturating_sub(1);
+        if inode.links == 0 {
+            return self.delete(inumber);
+        }
+        self.write_inode(inumber, &inode)?;
+        Ok(())
+    }
+
+    /// Returns `true` if the block is past the filesystem metadata and not a bad block.
+    fn is_data_block(&self, block: BlockPtr) -> bool {
+        let first = self.superblock.inode_blocks + INODE_BLOCKS_START;
+        (first..self.superblock.blocks).contains(&(block.get() as usize))
+            && !self.bad_blocks.contains(&block)
+    }
+
+    /// Re-reads the file and compares its contents with the checksum stored in the inode.
+    pub fn verify(&self, inumber: INumber) -> Result<(), FileSystemError> {
+        let inode = self.valid_inode(inumber)?;
+        let actual = Self::compute_checksum(&inode)?;
+        if actual != inode.checksum {
+            return Err(FileSystemError::ChecksumMismatch {
+                inumber,
+                expected: inode.checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Verifies every file and directory, returning the mismatches found.
+    pub fn verify_all(&self) -> Result<Vec<FileSystemError>, FileSystemError> {
+        self.check_mounted()?;
+        let mut mismatches = Vec::new();
+        for inumber in 0..self.superblock.inodes as INumber {
+            match self.verify(inumber) {
+                Ok(()) | Err(FileSystemError::InvalidInode(_)) => {}
+                Err(err @ FileSystemError::ChecksumMismatch { .. }) => mismatches.push(err),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// The size of the filesystem in blocks.
+    pub fn blocks(&self) -> usize {
+        self.superblock.blocks
+    }
+
+    /// The number of free blocks only directories may use.
+    pub fn reserved_blocks(&self) -> usize {
+        self.reserved_blocks
+    }
+
+    /// Sets how many of the free blocks are kept for directories, 0 letting files use every block.
+    pub fn set_reserved_blocks(&mut self, blocks: usize) {
+        self.reserved_blocks = blocks;
+    }
+
+    /// The number of blocks which can still be allocated, including the reserved ones.
+    pub fn free_blocks(&self) -> usize {
+        self.block_bitmap
+            .iter()
+            .map(|bits| bits.count_ones() as usize)
+            .sum()
+    }
+
+    /// Returns the blocks in use, including the superblock, bad block list and inode blocks.
+    pub fn used_blocks(&self) -> Vec<usize> {
+        (0..self.superblock.blocks)
+            .filter(|&block| {
+                self.block_bitmap[block / u64::BITS as usize] & (1 << (block % u64::BITS as usize))
+                    == 0
+            })
+            .collect()
+    }
+
+    /// Returns the blocks on the bad block list.
+    pub fn bad_blocks(&self) -> Vec<usize> {
+        self.bad_blocks
+            .iter()
+            .map(|block| block.get() as usize)
+            .collect()
+    }
+
+    /// Adds the block to the bad block list, so that it's never allocated again. If a file is
+    /// using the block, its data is first moved to a newly allocated block.
+    pub fn mark_bad(&mut self, block: usize) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        if block >= self.superblock.blocks {
+            return Err(DiskError::BlockOutOfBounds(block).into());
+        }
+        if block < self.superblock.inode_blocks + INODE_BLOCKS_START {
+            return Err(FileSystemError::ReservedBlock(block));
+        }
+        let block = BlockPtr::new(block as u32).unwrap();
+        if self.bad_blocks.contains(&block) {
+            return Ok(());
+        }
+        if self.bad_blocks.len() == MAX_BAD_BLOCKS {
+            return Err(FileSystemError::BadBlockListFull);
+        }
+
+        if let Some(owner) = self.find_block_owner(block)? {
+            self.migrate_block(block, owner)?;
+        }
+        self.mark_block(block, false);
+        self.bad_blocks.push(block);
+        self.write_bad_blocks()?;
+        Ok(())
+    }
+
+    /// Replaces the contents of `dst` with the contents of `src`. See [`Self::copy_with_progress`].
+    pub fn copy(&mut self, src: INumber, dst: INumber) -> Result<usize, FileSystemError> {
+        self.copy_with_progress(src, dst, None, |_, _| {})
+    }
+
+    /// Replaces the contents of `dst` with the contents of `src`, streaming one block at a time
+    /// through a single block sized buffer. Holes in `src` are skipped, so they stay holes in
+    /// `dst`, see [`Self::block_map`]. After each block `progress` is called with the number of
+    /// bytes copied so far and the total size. Returns the number of bytes copied.
+    ///
+    /// Stops with [`FileSystemError::Interrupted`] between blocks once `cancel` or the job running
+    /// the copy is cancelled, see [`job::cancel_requested`].
+    pub fn copy_with_progress(
+        &mut self,
+        src: INumber,
+        dst: INumber,
+        cancel: Option<&CancellationToken>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<usize, FileSystemError> {
+        let size = Self::content_size(src, &self.valid_inode(src)?)?;
+        if src == dst {
+            return Ok(size);
+        }
+
+        self.truncate_with_cancel(dst, 0, cancel)?;
+        let map = self.block_map(src)?;
+        let mut buf = [0; disk::BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            if job::cancelled(cancel) {
+                return Err(FileSystemError::Interrupted);
+            }
+            match map[offset / disk::BLOCK_SIZE] {
+                true => {
+                    let bytes_read = self.read(src, offset, &mut buf)?;
+                    self.write(dst, offset, &buf[..bytes_read])?;
+                    offset += bytes_read;
+                }
+                false => offset = size.min(offset + disk::BLOCK_SIZE),
+            }
+            progress(offset, size);
+        }
+        // A hole at the end isn't written, but still counts towards the size
+        if map.last() == Some(&false) {
+            self.truncate(dst, size)?;
+        }
+        Ok(size)
+    }
+
+    /// Returns whether each block of the contents of the file has data, or is a hole which reads as
+    /// zeroes and takes no space. The contents of a compressed file have holes where its map block
+    /// has no compressed block.
+    pub fn block_map(&self, inumber: INumber) -> Result<Vec<bool>, FileSystemError> {
+        if proc::is_proc(inumber) {
+            let blocks = proc::stat(inumber)?.size.div_ceil(disk::BLOCK_SIZE);
+            return Ok(alloc::vec![true; blocks]);
+        }
+        let inode = self.valid_inode(inumber)?;
+        if inode.is_compressed() {
+            let map = Self::read_block_map(inumber, &inode)?;
+            let blocks = map.size.div_ceil(disk::BLOCK_SIZE);
+            return Ok(map.offsets[..blocks]
+                .iter()
+                .map(|&offset| offset != 0)
+                .collect());
+        }
+        let pointers = Self::indirect_pointers(&inode)?;
+        let ptrs = inode.direct.iter().chain(&pointers);
+        let blocks = Self::allocated_blocks(inode.size);
+        Ok(ptrs.take(blocks).map(Option::is_some).collect())
+    }
+
+    /// Rewrites the file with its contents compressed or not, see [`Self::create_compressed`].
+    /// Compressing a compressed file again packs it, giving back the space left unused in it. The
+    /// contents are copied to a new file which then takes the place of the old one, so there has
+    /// to be room for both.
+    pub fn set_compressed(
+        &mut self,
+        inumber: INumber,
+        compressed: bool,
+    ) -> Result<(), FileSystemError> {
+        self.check_writable()?;
+        if proc::is_proc(inumber) {
+            return Err(proc::not_supported(inumber));
+        }
+        let inode = self.valid_inode(inumber)?;
+        match inode.kind {
+            InodeKind::File => {}
+            InodeKind::Directory => {
+                return Err(FileSystemError::IsADirectory(format!("inode {}", inumber)))
+            }
+            InodeKind::Device => {
+                return Err(FileSystemError::NotSupported(format!("inode {}", inumber)))
+            }
+        }
+        let flags = match compressed {
+            true => {
+                self.enable_features(INCOMPAT_COMPRESSION)?;
+                InodeFlags::COMPRESSED
+            }
+            false => InodeFlags::empty(),
+        };
+        let copy = self.create_with(InodeKind::File, flags)?;
+        if let Err(err) = self.copy(inumber, copy) {
+            self.delete(copy)?;
+            return Err(err);
+        }
+
+        // The copy is dropped before the file points at its blocks, so that no block is ever
+        // used by two inodes
+        let packed = self.valid_inode(copy)?;
+        let dropped = Inode::new(false, InodeKind::File, packed.generation.wrapping_add(1));
+        self.write_inode(copy, &dropped)?;
+        self.inodes.borrow_mut().evict(copy)?;
+        self.flush()?;
+        let freed = Self::blocks_from(&inode, 0)?;
+        let file = Inode {
+            flags: InodeFlags::VALID | flags,
+            checksum: packed.checksum,
+            size: packed.size,
+            direct: packed.direct,
+            indirect: packed.indirect,
+            ..inode
+        };
+        self.write_inode(inumber, &file)?;
+        self.flush()?;
+        for block in freed {
+            self.mark_block(block, true);
+        }
+        Ok(())
+    }
+
+    /// Adds up the compressed files, to see how much space compressing them saves.
+    pub fn compression_stats(&self) -> Result<CompressionStats, FileSystemError> {
+        self.check_mounted()?;
+        let mut stats = CompressionStats::default();
+        for inumber in 0..self.superblock.inodes as INumber {
+            let inode = match self.valid_inode(inumber) {
+                Ok(inode) if inode.is_compressed() => inode,
+                Ok(_) | Err(FileSystemError::InvalidInode(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            stats.files += 1;
+            stats.size += Self::content_size(inumber, &inode)?;
+            stats.physical_size += inode.size;
+        }
+        Ok(stats)
+    }
+
+    /// Sets incompatible features in both copies of the superblock, to be done before anything
+    /// using them is written.
+    fn enable_features(&mut self, features: u64) -> Result<(), FileSystemError> {
+        if self.superblock.incompat_features & features == features {
+            return Ok(());
+        }
+        self.superblock.incompat_features |= features;
+        for copy in [SuperblockCopy::Primary, SuperblockCopy::Backup] {
+            Self::write_superblock(copy.block(), &self.superblock)?;
+        }
+        disk::flush()?;
+        Ok(())
+    }
+
+    /// Returns the size of the contents of the file, which for a compressed file is kept in its
+    /// map block.
+    fn content_size(inumber: INumber, inode: &Inode) -> Result<usize, FileSystemError> {
+        match inode.is_compressed() {
+            true => Ok(Self::read_block_map(inumber, inode)?.size),
+            false => Ok(inode.size),
+        }
+    }
+
+    /// Reads the map block of a compressed file. A file which was never written has none yet.
+    fn read_block_map(inumber: INumber, inode: &Inode) -> Result<BlockMap, FileSystemError> {
+        if inode.size == 0 {
+            return Ok(BlockMap {
+                size: 0,
+                offsets: [0; MAP_ENTRIES],
+            });
+        }
+        let mut bytes = [0; disk::BLOCK_SIZE];
+        if Self::read_data(inode, 0, &mut bytes)? < disk::BLOCK_SIZE {
+            return Err(FileSystemError::CorruptBlockMap(inumber));
+        }
+        let map = BlockMap::decode(&bytes);
+        if map.size > MAX_COMPRESSED_FILE_SIZE {
+            return Err(FileSystemError::CorruptBlockMap(inumber));
+        }
+        Ok(map)
+    }
+
+    fn read_compressed(
+        inumber: INumber,
+        inode: &Inode,
+        offset: usize,
+        outbuf: &mut [u8],
+    ) -> Result<usize, FileSystemError> {
+        let map = Self::read_block_map(inumber, inode)?;
+        if map.size < offset {
+            return Err(FileSystemError::OffsetPastEnd(offset));
+        }
+        let bytes_to_read = outbuf.len().min(map.size - offset);
+        let mut block = [0; disk::BLOCK_SIZE];
+        let mut bytes_read = 0;
+        while bytes_read < bytes_to_read {
+            let pos = offset + bytes_read;
+            let (n, block_offset) = (pos / disk::BLOCK_SIZE, pos % disk::BLOCK_SIZE);
+            let len = (disk::BLOCK_SIZE - block_offset).min(bytes_to_read - bytes_read);
+            Self::read_compressed_block(inumber, inode, &map, n, &mut block)?;
+            outbuf[bytes_read..bytes_read + len]
+                .copy_from_slice(&block[block_offset..block_offset + len]);
+            bytes_read += len;
+        }
+        Ok(bytes_read)
+    }
+
+    /// Decompresses block `n` of the contents of a compressed file.
+    fn read_compressed_block(
+        inumber: INumber,
+        inode: &Inode,
+        map: &BlockMap,
+        n: usize,
+        block: &mut DataBlock,
+    ) -> Result<(), FileSystemError> {
+        let offset = map.offsets[n] as usize;
+        if offset == 0 {
+            block.fill(0);
+            return Ok(());
+        }
+        let mut compressed = [0; MAX_COMPRESSED_BLOCK];
+        // An offset outside of the compressed blocks leaves nothing to decompress
+        let len = match (disk::BLOCK_SIZE..inode.size).contains(&offset) {
+            true => Self::read_data(inode, offset, &mut compressed)?,
+            false => 0,
+        };
+        lz::decompress_block(&compressed[..len], block)
+            .map_err(|error| FileSystemError::CorruptCompressedBlock { inumber, n, error })
+    }
+
+    fn write_compressed(
+        &mut self,
+        inumber: INumber,
+        inode: &mut Inode,
+        offset: usize,
+        data: &[u8],
+        use_reserve: bool,
+    ) -> Result<(), FileSystemError> {
+        let new_size = offset + data.len();
+        if new_size > MAX_COMPRESSED_FILE_SIZE {
+            return Err(FileSystemError::FileTooLarge(new_size));
+        }
+        let mut map = Self::read_block_map(inumber, inode)?;
+        let mut block = [0; disk::BLOCK_SIZE];
+        let mut bytes_written = 0;
+        while bytes_written < data.len() {
+            let pos = offset + bytes_written;
+            let (n, block_offset) = (pos / disk::BLOCK_SIZE, pos % disk::BLOCK_SIZE);
+            let len = (disk::BLOCK_SIZE - block_offset).min(data.len() - bytes_written);
+            if len < disk::BLOCK_SIZE {
+                Self::read_compressed_block(inumber, inode, &map, n, &mut block)?;
+            }
+            block[block_offset..block_offset + len]
+                .copy_from_slice(&data[bytes_written..bytes_written + len]);
+            self.write_compressed_block(inode, &mut map, n, &block, use_reserve)?;
+            bytes_written += len;
+        }
+        // The map block is written last, so it only points at compressed blocks already written
+        map.size = map.size.max(new_size);
+        self.write_data(inode, 0, &map.encode(), use_reserve)
+    }
+
+    /// Compresses `block` as block `n` of the contents of a compressed file. It's written in the
+    /// place of the block it replaces if it fits there, or that's the last compressed block, and
+    /// after the last compressed block otherwise.
+    fn write_compressed_block(
+        &mut self,
+        inode: &mut Inode,
+        map: &mut BlockMap,
+        n: usize,
+        block: &DataBlock,
+        use_reserve: bool,
+    ) -> Result<(), FileSystemError> {
+        let mut compressed = [0; MAX_COMPRESSED_BLOCK];
+        let len = lz::compress_block(block, &mut compressed);
+        let end = inode.size.max(disk::BLOCK_SIZE);
+        let old = map.offsets[n] as usize;
+        let offset = match (disk::BLOCK_SIZE..inode.size).contains(&old) {
+            true => {
+                let mut header = [0; lz::BLOCK_HEADER_SIZE];
+                let header_len = Self::read_data(inode, old, &mut header)?;
+                match lz::block_len(&header[..header_len]) {
+                    Ok(old_len) if len <= old_len || old + old_len >= inode.size => old,
+                    _ => end,
+                }
+            }
+            false => end,
+        };
+        self.write_data(inode, offset, &compressed[..len], use_reserve)?;
+        map.offsets[n] = offset as u32;
+        Ok(())
+    }
+
+    /// Sets the size of the contents of a compressed file to `size`, which isn't zero. Blocks past
+    /// the new end are dropped from the map block but not freed.
+    fn truncate_compressed(
+        &mut self,
+        inumber: INumber,
+        inode: &mut Inode,
+        size: usize,
+    ) -> Result<(), FileSystemError> {
+        if size > MAX_COMPRESSED_FILE_SIZE {
+            return Err(FileSystemError::FileTooLarge(size));
+        }
+        let mut map = Self::read_block_map(inumber, inode)?;
+        if size < map.size {
+            let blocks = size.div_ceil(disk::BLOCK_SIZE);
+            map.offsets[blocks..].fill(0);
+            // Clear the rest of the last block, so growing the file again reads zeroes
+            let tail = size % disk::BLOCK_SIZE;
+            if tail != 0 {
+                let mut block = [0; disk::BLOCK_SIZE];
+                Self::read_compressed_block(inumber, inode, &map, blocks - 1, &mut block)?;
+                block[tail..].fill(0);
+                self.write_compressed_block(inode, &mut map, blocks - 1, &block, false)?;
+            }
+        }
+        map.size = size;
+        self.write_data(inode, 0, &map.encode(), false)
+    }
+
+    /// Computes the checksum of the file contents. Partial writes recompute the checksum of the
+    /// whole file, which keeps `Crc32` usable for updating only the changed blocks later.
+    fn compute_checksum(inode: &Inode) -> Result<u32, DiskError> {
+        let mut crc = Crc32::new();
+        let mut remaining = inode.size;
+        let pointers = Self::indirect_pointers(inode)?;
+        let ptrs = inode.direct.iter().chain(&pointers);
+        for ptr in ptrs.take(Self::allocated_blocks(inode.size)) {
+            let len = remaining.min(disk::BLOCK_SIZE);
+            match ptr {
+                Some(ptr) => disk::with_block(ptr.get() as usize, |data| crc.update(&data[..len]))?,
+                None => crc.update(&[0; disk::BLOCK_SIZE][..len]),
+            }
+            remaining -= len;
+        }
+        Ok(crc.finish())
+    }
+
+    fn allocated_blocks(size: usize) -> usize {
+        if size == 0 {
+            0
+        } else {
+            (size - 1) / disk::BLOCK_SIZE + 1
+        }
+    }
+
+    /// Allocates the data blocks numbered `blocks` which are missing, without changing the size of
+    /// the inode. Returns the indirect pointers of the inode, which are empty if it has no indirect
+    /// block, and whether new ones were added. They aren't written to the indirect block, see
+    /// [`Self::write_pointers_and_inode`]. If not all blocks can be allocated, the ones which were
+    /// are freed again and the inode is left as it was.
+    fn grow(
+        &mut self,
+        inode: &mut Inode,
+        blocks: Range<usize>,
+        use_reserve: bool,
+    ) -> Result<(PointerBlock, bool), FileSystemError> {
+        let before = *inode;
+        let mut allocated = Vec::new();
+        let result = self.grow_into(inode, blocks, use_reserve, &mut allocated);
+        if result.is_err() {
+            *inode = before;
+            for block in allocated {
+                self.mark_block(block, true);
+            }
+        }
+        result
+    }
+
+    fn grow_into(
+        &mut self,
+        inode: &mut Inode,
+        blocks: Range<usize>,
+        use_reserve: bool,
+        allocated: &mut Vec<BlockPtr>,
+    ) -> Result<(PointerBlock, bool), FileSystemError> {
+        let mut allocate = |fs: &mut Self| {
+            let block = fs.allocate_block(use_reserve)?;
+            allocated.push(block);
+            Ok::<_, FileSystemError>(block)
+        };
+
+        for ptr in inode
+            .direct
+            .iter_mut()
+            .take(blocks.end)
+            .skip(blocks.start)
+            .filter(|ptr| ptr.is_none())
+        {
+            *ptr = Some(allocate(self)?);
+        }
+
+        if blocks.is_empty() || blocks.end <= PTRS_PER_INODE {
+            return Ok(([None; PTRS_PER_BLOCK], false));
+        }
+
+        let indirect = match inode.indirect {
+            Some(ptr) => ptr,
+            None => {
+                let ptr = allocate(self)?;
+                inode.indirect = Some(ptr);
+                ptr
+            }
+        };
+        let mut pointers = Self::read_pointer_block(indirect)?;
+        let mut changed = false;
+        for ptr in pointers
+            .iter_mut()
+            .take(blocks.end - PTRS_PER_INODE)
+            .skip(blocks.start.saturating_sub(PTRS_PER_INODE))
+            .filter(|ptr| ptr.is_none())
+        {
+            *ptr = Some(allocate(self)?);
+            changed = true;
+        }
+        Ok((pointers, changed))
+    }
+
+    /// Writes the indirect pointers of a file which has grown, if they changed, and then its inode.
+    /// The data has to be flushed to the disk first.
+    fn write_pointers_and_inode(
+        &self,
+        inumber: INumber,
+        inode: &Inode,
+        pointers: Option<&PointerBlock>,
+    ) -> Result<(), FileSystemError> {
+        if let (Some(&pointers), Some(indirect)) = (pointers, inode.indirect) {
+            Self::write_block(indirect.get() as usize, &Block { pointers })?;
+            self.flush()?;
+        }
+        self.write_inode(inumber, inode)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Returns all data blocks of the inode starting at data block number `first`, including the
+    /// indirect block itself if no indirect pointers remain in use.
+    fn blocks_from(inode: &Inode, first: usize) -> Result<Vec<BlockPtr>, DiskError> {
+        let mut blocks = inode
+            .direct
+            .iter()
+            .skip(first)
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        if let Some(block) = inode.indirect {
+            let first_indirect = first.max(PTRS_PER_INODE) - PTRS_PER_INODE;
+            blocks.extend(
+                Self::read_pointer_block(block)?
+                    .iter()
+                    .skip(first_indirect)
+                    .flatten(),
+            );
+            if first_indirect == 0 {
+                blocks.push(block);
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Marks a block as free or busy. Values of zero for the block index are disallowed, as that's the index of the superblock.
+    fn mark_block(&mut self, block: BlockPtr, free: bool) {
+        let block_idx = block.get();
+        let (idx, offset) = ((block_idx / u64::BITS) as usize, block_idx % u64::BITS);
+        if free {
+            self.block_bitmap[idx] |= 1 << offset;
+        } else {
+            self.block_bitmap[idx] &= !(1 << offset);
+        }
+    }
+
+    /// Finds the pointer to a data block by scanning every inode in use.
+    fn find_block_owner(&self, block: BlockPtr) -> Result<Option<BlockOwner>, DiskError> {
+        self.inodes.borrow_mut().write_back()?;
+        for block_idx in INODE_BLOCKS_START..self.superblock.inode_blocks + INODE_BLOCKS_START {
+            let inodes = unsafe { Self::read_block(block_idx)?.inodes };
+            for (offset, inode) in inodes.iter().enumerate() {
+                if !inode.is_valid() {
+                    continue;
+                }
+                let inumber =
+                    ((block_idx - INODE_BLOCKS_START) * INODES_PER_BLOCK + offset) as INumber;
+                if let Some(i) = inode.direct.iter().position(|&ptr| ptr == Some(block)) {
+                    return Ok(Some(BlockOwner::Direct(inumber, i)));
+                }
+                if let Some(indirect) = inode.indirect {
+                    if indirect == block {
+                        return Ok(Some(BlockOwner::Indirect(inumber)));
+                    }
+                    let pointers = Self::read_pointer_block(indirect)?;
+                    if let Some(i) = pointers.iter().position(|&ptr| ptr == Some(block)) {
+                        return Ok(Some(BlockOwner::IndirectEntry(indirect, i)));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copies the block to a newly allocated block, and points its owner at the copy.
+    fn migrate_block(&mut self, block: BlockPtr, owner: BlockOwner) -> Result<(), FileSystemError> {
+        // Moving data off a bad block is worth using the reserve for
+        let copy = self.allocate_block(true)?;
+        let data = Self::read_block(block.get() as usize)?;
+        Self::write_block(copy.get() as usize, &data)?;
+        self.flush()?;
+
+        match owner {
+            BlockOwner::Direct(inumber, i) => {
+                let mut inode = self.read_inode(inumber)?;
+                inode.direct[i] = Some(copy);
+                self.write_inode(inumber, &inode)?;
+            }
+            BlockOwner::Indirect(inumber) => {
+                let mut inode = self.read_inode(inumber)?;
+                inode.indirect = Some(copy);
+                self.write_inode(inumber, &inode)?;
+            }
+            BlockOwner::IndirectEntry(indirect, i) => {
+                let mut pointers = Self::read_pointer_block(indirect)?;
+                pointers[i] = Some(copy);
+                Self::write_block(indirect.get() as usize, &Block { pointers })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_bad_blocks(&self) -> Result<(), DiskError> {
+        let mut list = BadBlockList {
+            len: self.bad_blocks.len() as u32,
+            blocks: [0; MAX_BAD_BLOCKS],
+        };
+        for (entry, block) in list.blocks.iter_mut().zip(&self.bad_blocks) {
+            *entry = block.get();
+        }
+        Self::write_block(BAD_BLOCKS_BLOCK, &Block { bad_blocks: list })
+    }
+
+    /// Finds a free block, marks it as used and clears its contents. The reserved blocks are only
+    /// handed out with `use_reserve`.
+    fn allocate_block(&mut self, use_reserve: bool) -> Result<BlockPtr, FileSystemError> {
+        if !use_reserve && self.free_blocks() <= self.reserved_blocks {
+            return Err(FileSystemError::NoFreeBlocks);
+        }
+        let block = self
+            .next_free_block()
+            .ok_or(FileSystemError::NoFreeBlocks)?;
+        Self::write_block(block.get() as usize, &Block::zeroed())?;
+        self.mark_block(block, false);
+        Ok(block)
+    }
+
+    /// Sets the number of blocks read ahead of sequential reads through a [`File`] handle, with
+    /// 0 turning read-ahead off. The read-ahead is limited to half of the block cache.
+    ///
+    /// [`File`]: super::handle::File
+    pub fn set_read_ahead(&mut self, blocks: usize) {
+        self.read_ahead = blocks.min(CACHE_BLOCKS / 2);
+    }
+
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead
+    }
+
+    /// Reads up to `read_ahead` blocks of the file, starting at block `first`, into the block
+    /// cache. Nothing is read past the end of the file, or if block `first` is already cached, as
+    /// the previous read-ahead is then still ahead of the reader.
+    pub(super) fn prefetch(&self, inumber: INumber, first: usize) -> Result<(), FileSystemError> {
+        if self.read_ahead == 0 || proc::is_proc(inumber) {
+            return Ok(());
+        }
+        let inode = self.valid_inode(inumber)?;
+        // The blocks of a compressed file don't line up with the blocks read from it
+        if inode.is_compressed() {
+            return Ok(());
+        }
+        let end = inode
+            .size
+            .div_ceil(disk::BLOCK_SIZE)
+            .min(first + self.read_ahead);
+        let mut blocks = Vec::new();
+        for n in first..end {
+            if let Some(block) = Self::block_ptr(&inode, n)? {
+                blocks.push(block.get() as usize);
+            }
+        }
+        match blocks.first() {
+            Some(&block) if !disk::is_cached(block) => Ok(disk::prefetch(&blocks)?),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the generation of an inode in use, to be captured by a file handle.
+    pub(super) fn generation(&self, inumber: INumber) -> Result<Generation, FileSystemError> {
+        if proc::is_proc(inumber) {
+            return proc::stat(inumber).map(|_| 0);
+        }
+        Ok(self.valid_inode(inumber)?.generation)
+    }
+
+    /// Checks that a handle captured with `generation` still refers to the file using the inode.
+    pub(super) fn check_generation(
+        &self,
+        inumber: INumber,
+        generation: Generation,
+    ) -> Result<(), FileSystemError> {
+        if proc::is_proc(inumber) {
+            return Ok(());
+        }
+        match self.valid_inode(inumber) {
+            Ok(inode) if inode.generation == generation => Ok(()),
+            Ok(_) | Err(FileSystemError::InvalidInode(_)) => {
+                Err(FileSystemError::StaleHandle(inumber))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads the inode straight from the disk, whether it's in use or not. Fails if it's out of
+    /// range or no filesystem is mounted.
+    pub fn raw_inode(&self, inumber: INumber) -> Result<RawInode, FileSystemError> {
+        self.check_mounted()?;
+        if inumber as usize >= self.superblock.inodes {
+            return Err(FileSystemError::InvalidInode(inumber));
+        }
+        self.inodes.borrow_mut().write_back()?;
+        let (block, idx) = inode_position(inumber);
+        let mut bytes = [0; INODE_SIZE];
+        disk::read(block, idx * INODE_SIZE, &mut bytes)?;
+        Ok(RawInode::from_bytes(&bytes))
+    }
+
+    /// Fails if the filesystem is mounted read-only.
+    pub(super) fn check_writable(&self) -> Result<(), FileSystemError> {
+        self.check_mounted()?;
+        match self.read_only {
+            true => Err(FileSystemError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns the number of inodes of the mounted filesystem.
+    pub fn inodes(&self) -> usize {
+        self.superblock.inodes
+    }
+
+    /// Reads the inode, failing if it's out of range or not in use.
+    fn valid_inode(&self, inumber: INumber) -> Result<Inode, FileSystemError> {
+        self.check_mounted()?;
+        if inumber as usize >= self.superblock.inodes {
+            return Err(FileSystemError::InvalidInode(inumber));
+        }
+        let inode = self.read_inode(inumber)?;
+        if !inode.is_valid() {
+            return Err(FileSystemError::InvalidInode(inumber));
+        }
+        Ok(inode)
+    }
+
+    /// Finds the next free inode and returns its `inumber`.
+    fn next_free_inode(&self) -> Result<Option<INumber>, DiskError> {
+        self.inodes.borrow_mut().write_back()?;
+        for block_idx in INODE_BLOCKS_START..self.superblock.inode_blocks + INODE_BLOCKS_START {
+            let block = Self::read_block(block_idx)?;
+            for (offset, inode) in unsafe { block.inodes }.iter().enumerate() {
+                if !inode.is_valid() {
+                    let inumber = (block_idx - INODE_BLOCKS_START) * INODES_PER_BLOCK + offset;
+                    return Ok(Some(inumber as INumber));
+                }
+            }
+        }
+        Ok(None)
+    }
 
-    fn calc_inode_pos(inumber: INumber) -> (usize, usize) {
-        (
-            inumber as usize / INODES_PER_BLOCK + INODE_BLOCKS_START,
-            inumber as usize % INODES_PER_BLOCK,
-        )
+    fn next_free_block(&self) -> Option<BlockPtr> {
+        let (idx, &bitmask) = self
+            .block_bitmap
+            .iter()
+            .enumerate()
+            .find(|(_, &value)| value > 0)?;
+        let first_one_idx = bitmask.trailing_zeros(); // number of trailing 0 will give the index of the first 1
+        NonZeroU32::new(idx as u32 * u64::BITS + first_one_idx)
+    }
+
+    /// Returns the pointer to data block number `n` of the inode.
+    fn block_ptr(inode: &Inode, n: usize) -> Result<Option<BlockPtr>, DiskError> {
+        if n < PTRS_PER_INODE {
+            return Ok(inode.direct[n]);
+        }
+        match inode.indirect {
+            Some(ptr) => Ok(Self::read_pointer_block(ptr)?[n - PTRS_PER_INODE]),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the indirect pointers of the inode, which are all missing if it has no indirect
+    /// block.
+    fn indirect_pointers(inode: &Inode) -> Result<PointerBlock, DiskError> {
+        match inode.indirect {
+            Some(ptr) => Self::read_pointer_block(ptr),
+            None => Ok([None; PTRS_PER_BLOCK]),
+        }
+    }
+
+    /// Counts the data blocks allocated to the inode, which for a sparse file is less than its size
+    /// needs.
+    fn data_blocks(inode: &Inode) -> Result<usize, DiskError> {
+        let pointers = Self::indirect_pointers(inode)?;
+        Ok(inode.direct.iter().chain(&pointers).flatten().count())
+    }
+
+    fn read_raw_data(
+        block: BlockPtr,
+        offset: usize,
+        length: usize,
+        outbuf: &mut [u8],
+    ) -> Result<usize, DiskError> {
+        if offset > disk::BLOCK_SIZE {
+            return Err(DiskError::OffsetOutOfBounds(offset));
+        }
+
+        // Copied straight out of the block cache, so each block is copied once
+        disk::with_block(block.get() as usize, |data| {
+            let data = &data[offset..disk::BLOCK_SIZE.min(offset + length)];
+            let len = data.len().min(outbuf.len());
+            outbuf[..len].copy_from_slice(&data[..len]);
+            len
+        })
+    }
+
+    fn read_raw_data_many(
+        blocks: &[Option<BlockPtr>],
+        mut offset: usize,
+        length: usize,
+        outbuf: &mut [u8],
+    ) -> Result<usize, DiskError> {
+        let mut bytes_read = 0;
+        let bytes_to_read = outbuf.len().min(length);
+
+        for ptr in blocks {
+            if let &Some(block_ptr) = ptr {
+                bytes_read += Self::read_raw_data(
+                    block_ptr,
+                    offset,
+                    bytes_to_read - bytes_read,
+                    &mut outbuf[bytes_read..],
+                )?;
+                offset = 0; // set offset to 0 as we only want the offset for the first block
+            } else {
+                // A hole in a sparse file
+                let len = (disk::BLOCK_SIZE - offset).min(bytes_to_read - bytes_read);
+                outbuf[bytes_read..bytes_read + len].fill(0);
+                bytes_read += len;
+                offset = 0;
+            }
+
+            if bytes_read >= bytes_to_read {
+                break;
+            }
+        }
+
+        Ok(bytes_read)
+    }
+
+    /// Reads the bytes of a copy of the superblock on its own, see [`SUPERBLOCK_SIZE`].
+    fn read_superblock(block: usize) -> Result<[u8; SUPERBLOCK_SIZE], DiskError> {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        disk::read(block, 0, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_superblock(block: usize, superblock: &Superblock) -> Result<(), DiskError> {
+        let mut data = [0; disk::BLOCK_SIZE];
+        data[..SUPERBLOCK_SIZE].copy_from_slice(&superblock.encode());
+        disk::write(block, 0, &data)
+    }
+
+    /// Returns `true` if neither copy of the superblock has ever been written, so the disk holds
+    /// no filesystem at all rather than a damaged one.
+    pub fn is_blank() -> Result<bool, FileSystemError> {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        for copy in [SuperblockCopy::Primary, SuperblockCopy::Backup] {
+            disk::read(copy.block(), 0, &mut bytes)?;
+            if bytes.iter().any(|&byte| byte != 0) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_block(block: usize) -> Result<Block, DiskError> {
+        let mut outbuf = Block::zeroed();
+        disk::read(block, 0, unsafe { &mut outbuf.data })?;
+        Ok(outbuf)
+    }
+
+    fn write_block(block: usize, data: &Block) -> Result<(), DiskError> {
+        disk::write(block, 0, unsafe { &data.data })
+    }
+
+    fn read_pointer_block(block: BlockPtr) -> Result<PointerBlock, DiskError> {
+        let block = Self::read_block(block.get() as usize)?;
+        Ok(unsafe { block.pointers })
+    }
+
+    /// Changes the inode in the inode cache, it reaches the disk with the next flush.
+    fn write_inode(&self, inumber: INumber, inode: &Inode) -> Result<(), DiskError> {
+        self.changes.set(self.changes.get() + 1);
+        self.inodes.borrow_mut().put(inumber, inode)
+    }
+
+    fn read_inode(&self, inumber: INumber) -> Result<Inode, DiskError> {
+        self.inodes.borrow_mut().get(inumber)
+    }
+
+    /// Writes the changed inodes back to the block cache and flushes it. The filesystem flushes
+    /// through this, so that inodes are ordered with the blocks written around them.
+    fn flush(&self) -> Result<(), DiskError> {
+        self.inodes.borrow_mut().write_back()?;
+        disk::flush()
+    }
+}
+
+#[test_case]
+fn test_write_read_multi_block() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let size = (PTRS_PER_INODE + 2) * disk::BLOCK_SIZE + 123;
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+
+    let inumber = fs.create(InodeKind::File).unwrap();
+    assert_eq!(fs.write(inumber, 0, &data).unwrap(), size);
+    assert_eq!(fs.stat(inumber).unwrap().size, size);
+
+    let mut buf = alloc::vec![0; size + 10];
+    assert_eq!(fs.read(inumber, 0, &mut buf).unwrap(), size);
+    assert_eq!(&buf[..size], &data[..]);
+
+    // Read across the boundary between direct and indirect blocks
+    let offset = PTRS_PER_INODE * disk::BLOCK_SIZE - 7;
+    assert_eq!(fs.read(inumber, offset, &mut buf[..14]).unwrap(), 14);
+    assert_eq!(&buf[..14], &data[offset..offset + 14]);
+}
+
+#[test_case]
+fn test_copy_multi_block() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let size = (PTRS_PER_INODE + 2) * disk::BLOCK_SIZE + 1000;
+    let data: Vec<u8> = (0..size).map(|i| (i * 7 % 256) as u8).collect();
+
+    let src = fs.create(InodeKind::File).unwrap();
+    fs.write(src, 0, &data).unwrap();
+    let dst = fs.create(InodeKind::File).unwrap();
+    fs.write(dst, 0, &[0xff; 3 * disk::BLOCK_SIZE]).unwrap();
+
+    let mut progress_calls = 0;
+    let copied = fs
+        .copy_with_progress(src, dst, None, |_, total| {
+            assert_eq!(total, size);
+            progress_calls += 1;
+        })
+        .unwrap();
+    assert_eq!(copied, size);
+    assert_eq!(progress_calls, size.div_ceil(disk::BLOCK_SIZE));
+    assert_eq!(fs.stat(dst).unwrap().size, size);
+
+    let mut buf = alloc::vec![0; size];
+    assert_eq!(fs.read(dst, 0, &mut buf).unwrap(), size);
+    assert!(buf == data);
+}
+
+#[test_case]
+fn test_truncate_frees_blocks() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let inumber = fs.create(InodeKind::File).unwrap();
+    fs.write(inumber, 0, &[1; 2 * disk::BLOCK_SIZE]).unwrap();
+    let free_before = fs.block_bitmap.iter().map(|w| w.count_ones()).sum::<u32>();
+
+    fs.truncate(inumber, 10).unwrap();
+    let free_after = fs.block_bitmap.iter().map(|w| w.count_ones()).sum::<u32>();
+    assert_eq!(free_after, free_before + 1);
+
+    // The truncated part of the last block reads as zeroes when the file grows again
+    fs.truncate(inumber, 20).unwrap();
+    let mut buf = [0xff; 20];
+    fs.read(inumber, 0, &mut buf).unwrap();
+    assert_eq!(&buf[..10], &[1; 10]);
+    assert_eq!(&buf[10..], &[0; 10]);
+}
+
+#[test_case]
+fn test_verify_detects_corruption() {
+    use crate::util::crc32::crc32;
+
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+
+    let data = [7; disk::BLOCK_SIZE + 100];
+    let inumber = fs.create(InodeKind::File).unwrap();
+    fs.write(inumber, 0, &data).unwrap();
+    fs.verify(inumber).unwrap();
+    fs.truncate(inumber, 50).unwrap();
+    fs.verify(inumber).unwrap();
+    assert!(fs.verify_all().unwrap().is_empty());
+
+    // Corrupt the data behind the filesystem's back
+    let block = FileSystem::block_ptr(&fs.valid_inode(inumber).unwrap(), 0)
+        .unwrap()
+        .unwrap();
+    disk::write(block.get() as usize, 10, &[8]).unwrap();
+    let mut corrupted = [7; 50];
+    corrupted[10] = 8;
+    match fs.verify(inumber) {
+        Err(FileSystemError::ChecksumMismatch {
+            inumber: i,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(i, inumber);
+            assert_eq!(expected, crc32(&[7; 50]));
+            assert_eq!(actual, crc32(&corrupted));
+        }
+        other => panic!("expected a checksum mismatch, got {:?}", other),
+    }
+    assert_eq!(fs.verify_all().unwrap().len(), 1);
+}
+
+#[test_case]
+fn test_mark_bad_blocks() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+
+    let size = (PTRS_PER_INODE + 2) * disk::BLOCK_SIZE;
+    let data: Vec<u8> = (0..size).map(|i| (i % 241) as u8).collect();
+    let inumber = fs.create(InodeKind::File).unwrap();
+    fs.write(inumber, 0, &data).unwrap();
+
+    // A direct block, the indirect block and a block the indirect block points to
+    let inode = fs.valid_inode(inumber).unwrap();
+    let bad = [
+        inode.direct[1].unwrap().get() as usize,
+        inode.indirect.unwrap().get() as usize,
+        FileSystem::block_ptr(&inode, PTRS_PER_INODE + 1)
+            .unwrap()
+            .unwrap()
+            .get() as usize,
+    ];
+    for block in bad {
+        fs.mark_bad(block).unwrap();
+    }
+    assert_eq!(fs.bad_blocks(), bad);
+
+    let mut buf = alloc::vec![0; size];
+    assert_eq!(fs.read(inumber, 0, &mut buf).unwrap(), size);
+    assert!(buf == data);
+    fs.verify(inumber).unwrap();
+    assert!(fs
+        .find_block_owner(BlockPtr::new(bad[0] as u32).unwrap())
+        .unwrap()
+        .is_none());
+
+    // The list survives a remount, and the blocks are never handed out again
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    assert_eq!(fs.bad_blocks(), bad);
+    while let Ok(block) = fs.allocate_block(true) {
+        assert!(!bad.contains(&(block.get() as usize)));
+    }
+
+    assert!(matches!(
+        fs.mark_bad(INODE_BLOCKS_START),
+        Err(FileSystemError::ReservedBlock(_))
+    ));
+}
+
+/// Waits for the next timer tick, so that timestamps taken before and after differ.
+#[cfg(test)]
+fn next_tick() {
+    let start = crate::timer::ticks();
+    while crate::timer::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_timestamps() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+
+    let inumber = fs.create(InodeKind::File).unwrap();
+    let created = fs.stat(inumber).unwrap();
+    assert_eq!(created.created, created.modified);
+
+    next_tick();
+    fs.write(inumber, 0, b"hello").unwrap();
+    let written = fs.stat(inumber).unwrap();
+    assert_eq!(written.created, created.created);
+    assert!(written.modified > created.modified);
+
+    next_tick();
+    fs.read(inumber, 0, &mut [0; 5]).unwrap();
+    assert_eq!(fs.stat(inumber).unwrap(), written);
+
+    fs.truncate(inumber, 2).unwrap();
+    let truncated = fs.stat(inumber).unwrap();
+    assert!(truncated.modified > written.modified);
+
+    next_tick();
+    fs.touch(inumber).unwrap();
+    let touched = fs.stat(inumber).unwrap();
+    assert!(touched.modified > truncated.modified);
+    assert_eq!(touched.size, 2);
+}
+
+#[test_case]
+fn test_remount_writes_cached_inodes() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let file = fs.create_at("a", InodeKind::File).unwrap();
+    fs.link_at("a", "b").unwrap();
+    fs.remove("b").unwrap();
+    next_tick();
+    fs.touch(file).unwrap();
+    let touched = fs.stat(file).unwrap();
+    assert_eq!(touched.links, 1);
+
+    // The inodes only changed in the inode cache, which mounting again starts over with
+    fs.mount_read_only().unwrap();
+    assert_eq!(fs.stat(file).unwrap(), touched);
+    fs.mount().unwrap();
+    assert_eq!(fs.stat(file).unwrap(), touched);
+    let mut fresh = FileSystem::new();
+    fresh.mount().unwrap();
+    assert_eq!(fresh.stat(fresh.resolve("a").unwrap()).unwrap(), touched);
+}
+
+#[test_case]
+fn test_mount_rejects_other_versions() {
+    FileSystem::format().unwrap();
+    let mut sb = Superblock::decode(&FileSystem::read_superblock(0).unwrap()).unwrap();
+    sb.version = VERSION + 1;
+    FileSystem::write_superblock(0, &sb).unwrap();
+    match FileSystem::new().mount() {
+        Err(FileSystemError::UnsupportedVersion { found }) => assert_eq!(found, VERSION + 1),
+        other => panic!("expected a version mismatch, got {:?}", other.err()),
+    }
+
+    FileSystem::format().unwrap();
+    FileSystem::new().mount().unwrap();
+}
+
+#[test_case]
+fn test_mount_rejects_flipped_superblock_bits() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let superblock = fs.superblock;
+    fs.unmount();
+
+    // A single flipped bit in the block count fails the checksum of both copies
+    for copy in [SuperblockCopy::Primary, SuperblockCopy::Backup] {
+        let mut bytes = FileSystem::read_superblock(copy.block()).unwrap();
+        bytes[8] ^= 1 << 3;
+        disk::write(copy.block(), 0, &bytes).unwrap();
+    }
+    assert!(matches!(
+        fs.mount(),
+        Err(FileSystemError::SuperblockChecksum { .. })
+    ));
+
+    // Both copies are written by the same serializer, so restoring one brings the other back
+    FileSystem::write_superblock(SuperblockCopy::Backup.block(), &superblock).unwrap();
+    fs.mount().unwrap();
+    assert_eq!(fs.mounted_from(), Some(SuperblockCopy::Backup));
+    assert_eq!(fs.repair_superblock().unwrap(), [SuperblockCopy::Primary]);
+    assert_eq!(FileSystem::read_superblock(0).unwrap(), superblock.encode());
+    fs.unmount();
+    FileSystem::format().unwrap();
+}
+
+#[test_case]
+fn test_filesystem_on_sector_device() {
+    use alloc::boxed::Box;
+    use disk::{Disk, SectorAdapter, SECTOR_SIZE};
+
+    let sectors = disk::size() * disk::BLOCK_SIZE / SECTOR_SIZE;
+    let previous = disk::attach(Box::new(SectorAdapter::new(Disk::with_block_size(
+        sectors,
+        SECTOR_SIZE,
+    ))));
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+
+    let size = (PTRS_PER_INODE + 2) * disk::BLOCK_SIZE + 123;
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    let inumber = fs.create(InodeKind::File).unwrap();
+    assert_eq!(fs.write(inumber, 0, &data).unwrap(), size);
+    // Unaligned writes within a block go through the adapter's read-modify-write
+    fs.write(inumber, 1000, &[0xee; 10]).unwrap();
+    disk::invalidate_cache();
+
+    let mut buf = alloc::vec![0; size];
+    assert_eq!(fs.read(inumber, 0, &mut buf).unwrap(), size);
+    assert_eq!(&buf[..1000], &data[..1000]);
+    assert_eq!(&buf[1000..1010], &[0xee; 10]);
+    assert_eq!(&buf[1010..], &data[1010..]);
+
+    disk::attach(previous);
+    FileSystem::format().unwrap();
+}
+
+#[test_case]
+fn test_mount_rejects_block_size_mismatch() {
+    use alloc::boxed::Box;
+    use disk::{BlockDevice, Disk, SECTOR_SIZE};
+
+    FileSystem::format().unwrap();
+    let mut superblock = [0; SECTOR_SIZE];
+    disk::read(0, 0, &mut superblock).unwrap();
+
+    // A filesystem claiming other blocks than the disk has
+    let mut sb = Superblock::decode(&FileSystem::read_superblock(0).unwrap()).unwrap();
+    sb.block_size = SECTOR_SIZE;
+    FileSystem::write_superblock(0, &sb).unwrap();
+    assert!(matches!(
+        FileSystem::new().mount(),
+        Err(FileSystemError::BlockSizeMismatch {
+            filesystem: SECTOR_SIZE,
+            device: disk::BLOCK_SIZE
+        })
+    ));
+
+    // The image moved to a disk with 512 byte sectors without an adapter
+    let mut sectors = Disk::with_block_size(8, SECTOR_SIZE);
+    BlockDevice::write(&mut sectors, 0, &superblock).unwrap();
+    let previous = disk::attach(Box::new(sectors));
+    assert!(matches!(
+        FileSystem::new().mount(),
+        Err(FileSystemError::BlockSizeMismatch {
+            filesystem: disk::BLOCK_SIZE,
+            device: SECTOR_SIZE
+        })
+    ));
+    assert!(matches!(
+        FileSystem::format(),
+        Err(FileSystemError::BlockSizeMismatch { .. })
+    ));
+
+    disk::attach(previous);
+    FileSystem::format().unwrap();
+    FileSystem::new().mount().unwrap();
+}
+
+#[test_case]
+fn test_power_cut_leaves_consistent_filesystem() {
+    use alloc::{boxed::Box, sync::Arc};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use disk::{Disk, DiskError};
+
+    // The number of writes left before the power is cut
+    let writes_left = Arc::new(AtomicUsize::new(usize::MAX));
+    let mut device = Disk::new(disk::size());
+    let hook_writes_left = writes_left.clone();
+    device.set_write_hook(Box::new(move |block| {
+        hook_writes_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .map(|_| ())
+            .map_err(|_| DiskError::WriteFailed(block))
+    }));
+    let previous = disk::attach(Box::new(device));
+
+    let block = disk::BLOCK_SIZE;
+    let data: Vec<u8> = (0..(PTRS_PER_INODE + 3) * block).map(|i| i as u8).collect();
+    for cut in 0.. {
+        writes_left.store(usize::MAX, Ordering::SeqCst);
+        FileSystem::format().unwrap();
+        let mut fs = FileSystem::new();
+        fs.mount().unwrap();
+        let first = fs.create_at("first", InodeKind::File).unwrap();
+        fs.write(first, 0, &[1; 2 * disk::BLOCK_SIZE]).unwrap();
+
+        writes_left.store(cut, Ordering::SeqCst);
+        let mut operations = || -> Result<(), FileSystemError> {
+            let second = fs.create_at("second", InodeKind::File)?;
+            fs.write(second, 0, &data)?;
+            fs.truncate(first, 100)?;
+            // Grows the first file into a new indirect block, then adds to it
+            fs.write(first, (PTRS_PER_INODE + 1) * block, &[2; 10])?;
+            fs.write(first, (PTRS_PER_INODE + 3) * block, &[3; 10])?;
+            fs.truncate(second, (PTRS_PER_INODE + 1) * block)?;
+            fs.remove("second")
+        };
+        let finished = operations().is_ok();
+
+        // The power comes back, and the disk is mounted from scratch
+        writes_left.store(usize::MAX, Ordering::SeqCst);
+        disk::invalidate_cache();
+        let mut survivor = FileSystem::new();
+        survivor.mount().unwrap();
+        let mut problems = survivor.check(None).unwrap();
+        // A cut between writing a link count and the directory entry only leaks the file
+        problems.retain(|problem| match problem {
+            FileSystemError::LinkCountMismatch { links, entries, .. } => links <= entries,
+            _ => true,
+        });
+        assert!(
+            problems.is_empty(),
+            "power cut after {} writes: {:?}",
+            cut,
+            problems
+        );
+        if finished {
+            break;
+        }
     }
+
+    disk::attach(previous);
+    FileSystem::format().unwrap();
+}
+
+#[test_case]
+fn test_check_finds_cross_links() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let first = fs.create_at("first", InodeKind::File).unwrap();
+    fs.write(first, 0, &[1; 2 * disk::BLOCK_SIZE]).unwrap();
+    let second = fs.create_at("second", InodeKind::File).unwrap();
+    fs.write(second, 0, &[2; 10]).unwrap();
+    assert!(fs.check(None).unwrap().is_empty());
+
+    // Point the second file at a block of the first, and past the end of the disk
+    let mut inode = fs.valid_inode(second).unwrap();
+    let shared = fs.valid_inode(first).unwrap().direct[1].unwrap();
+    inode.direct[0] = Some(shared);
+    inode.direct[1] = BlockPtr::new(disk::size() as u32);
+    inode.size = 3 * disk::BLOCK_SIZE;
+    fs.write_inode(second, &inode).unwrap();
+
+    let problems = fs.check(None).unwrap();
+    assert_eq!(problems.len(), 2);
+    assert!(matches!(
+        problems[0],
+        FileSystemError::CrossLinkedBlock { block, first: f, second: s }
+            if block == shared.get() as usize && f == first && s == second
+    ));
+    assert!(matches!(
+        problems[1],
+        FileSystemError::InvalidBlockPointer { inumber, .. } if inumber == second
+    ));
+
+    // A file may have holes, but a directory may not
+    let dir = fs.create_at("dir", InodeKind::Directory).unwrap();
+    let mut inode = fs.valid_inode(dir).unwrap();
+    inode.size = 2 * disk::BLOCK_SIZE;
+    fs.write_inode(dir, &inode).unwrap();
+    assert!(fs.check(None).unwrap().iter().any(|problem| matches!(
+        problem,
+        FileSystemError::MissingBlock { inumber, n: 1 } if *inumber == dir
+    )));
+
+    FileSystem::format().unwrap();
+}
+
+#[test_case]
+fn test_check_in_steps_starts_over_after_changes() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let file = fs.create_at("file", InodeKind::File).unwrap();
+    fs.write(file, 0, &[1; 10]).unwrap();
+
+    let mut check = fs.start_check().unwrap();
+    for _ in 0..3 {
+        assert!(fs.check_step(&mut check, None).unwrap());
+    }
+    // The removed file was already seen, it would be left with a link but no directory entry
+    fs.remove("file").unwrap();
+    while fs.check_step(&mut check, None).unwrap() {}
+    assert!(check.finish().is_empty());
+
+    FileSystem::format().unwrap();
+}
+
+#[test_case]
+fn test_raw_inode_matches_inode() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    assert!(matches!(fs.raw_inode(0), Err(FileSystemError::NotMounted)));
+    fs.mount().unwrap();
+    let inumber = fs.create(InodeKind::Directory).unwrap();
+    fs.write(inumber, 0, &[7; (PTRS_PER_INODE + 1) * disk::BLOCK_SIZE])
+        .unwrap();
+
+    let inode = fs.valid_inode(inumber).unwrap();
+    let raw = fs.raw_inode(inumber).unwrap();
+    assert_eq!((raw.flags, raw.kind), (1, InodeKind::Directory as u8));
+    assert_eq!(raw.generation, inode.generation);
+    assert_eq!(raw.checksum, inode.checksum);
+    assert_eq!(raw.size, inode.size);
+    assert_eq!((raw.created, raw.modified), (inode.created, inode.modified));
+    let ptr = |ptr: Option<BlockPtr>| ptr.map_or(0, BlockPtr::get);
+    assert_eq!(raw.direct, inode.direct.map(ptr));
+    assert_eq!(raw.indirect, ptr(inode.indirect));
+    assert_eq!(raw.links, 1);
+
+    let (block, idx) = inode_position(inumber);
+    let mut bytes = [0; INODE_SIZE];
+    disk::read(block, idx * INODE_SIZE, &mut bytes).unwrap();
+    assert_eq!(raw.to_bytes(), bytes);
+    assert!(matches!(
+        fs.raw_inode(fs.inodes() as INumber),
+        Err(FileSystemError::InvalidInode(_))
+    ));
+
+    FileSystem::format().unwrap();
+}
+
+#[test_case]
+fn test_mount_with_unknown_features() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let inumber = fs.create(InodeKind::File).unwrap();
+    fs.write(inumber, 0, b"data").unwrap();
+
+    let feature = 1 << 40;
+    let mut sb = Superblock::decode(&FileSystem::read_superblock(0).unwrap()).unwrap();
+    sb.incompat_features = feature;
+    FileSystem::write_superblock(0, &sb).unwrap();
+    let mut fs = FileSystem::new();
+    match fs.mount() {
+        Err(FileSystemError::IncompatibleFeatures(features)) => assert_eq!(features, feature),
+        other => panic!("expected unknown features, got {:?}", other.err()),
+    }
+
+    // Read-only, the files can be read but nothing can be changed
+    fs.mount_read_only().unwrap();
+    assert!(fs.is_read_only());
+    let mut buf = [0; 4];
+    fs.read(inumber, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    assert!(matches!(
+        fs.write(inumber, 0, b"more"),
+        Err(FileSystemError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.create(InodeKind::File),
+        Err(FileSystemError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.create_at("new", InodeKind::Directory),
+        Err(FileSystemError::ReadOnly)
+    ));
+    assert!(matches!(fs.delete(inumber), Err(FileSystemError::ReadOnly)));
+    assert!(matches!(
+        fs.remount_writable(),
+        Err(FileSystemError::IncompatibleFeatures(_))
+    ));
+
+    fs.unmount();
+    FileSystem::format().unwrap();
+    FileSystem::new().mount().unwrap();
+}
+
+#[test_case]
+fn test_read_only_mount_writes_nothing() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    fs.create_dir_all("dir").unwrap();
+    let file = fs.create_at("dir/file", InodeKind::File).unwrap();
+    fs.write(file, 0, &[7; 5000]).unwrap();
+
+    let mut fs = FileSystem::new();
+    fs.mount_with(MountFlags::READ_ONLY).unwrap();
+    assert!(fs.is_read_only());
+    let writes = disk::stats().writes;
+
+    // Reading and checking work as usual
+    let mut buf = [0; 5000];
+    fs.read(file, 0, &mut buf).unwrap();
+    assert_eq!(buf, [7; 5000]);
+    assert_eq!(fs.stat(file).unwrap().size, 5000);
+    assert_eq!(fs.list(fs.resolve("dir").unwrap()).unwrap().len(), 1);
+    assert!(fs.check(None).unwrap().is_empty());
+
+    let read_only = |result: Result<_, FileSystemError>| {
+        assert!(matches!(result, Err(FileSystemError::ReadOnly)));
+    };
+    read_only(fs.create(InodeKind::File).map(drop));
+    read_only(fs.create_at("new", InodeKind::File).map(drop));
+    read_only(fs.create_dir_all("dir/sub").map(drop));
+    read_only(fs.write(file, 0, b"changed").map(drop));
+    read_only(fs.truncate(file, 0));
+    read_only(fs.touch(file));
+    read_only(fs.rename("dir/file", "moved"));
+    read_only(fs.link_at("dir/file", "link"));
+    read_only(fs.remove("dir/file"));
+    read_only(fs.delete(file));
+    read_only(fs.mark_bad(fs.blocks() - 1));
+    read_only(fs.repair_superblock().map(drop));
+
+    // Nothing can slip past the filesystem into the block cache either
+    assert!(matches!(
+        disk::write(0, 0, &[0; 8]),
+        Err(DiskError::WriteProtected(0))
+    ));
+    assert!(matches!(
+        disk::with_block_mut(0, |data| data.fill(0)),
+        Err(DiskError::WriteProtected(0))
+    ));
+    disk::flush().unwrap();
+    assert_eq!(disk::stats().writes, writes);
+
+    fs.remount_writable().unwrap();
+    assert!(!fs.is_read_only());
+    fs.rename("dir/file", "moved").unwrap();
+    fs.write(file, 0, b"changed").unwrap();
+    disk::flush().unwrap();
+    assert!(disk::stats().writes > writes);
+    let mut buf = [0; 7];
+    fs.read(fs.resolve("moved").unwrap(), 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"changed");
+}
+
+#[test_case]
+fn test_remount_writable_refuses_damaged_filesystem() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let file = fs.create_at("a", InodeKind::File).unwrap();
+    // An entry added behind the back of the link count
+    fs.add_entry(ROOT_INUMBER, "b", file).unwrap();
+
+    fs.mount_read_only().unwrap();
+    assert!(matches!(
+        fs.remount_writable(),
+        Err(FileSystemError::NotClean(1))
+    ));
+    assert!(fs.is_read_only());
+
+    // Unmounting lets writes through again
+    fs.unmount();
+    FileSystem::format().unwrap();
+    FileSystem::new().mount().unwrap();
+}
+
+#[test_case]
+fn test_reserved_blocks() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    assert_eq!(fs.reserved_blocks(), DEFAULT_RESERVED_BLOCKS);
+
+    // A file fills the disk up to the reserve
+    let big = fs.create_at("big", InodeKind::File).unwrap();
+    let block = [1; disk::BLOCK_SIZE];
+    let mut size = 0;
+    while let Ok(written) = fs.write(big, size, &block) {
+        size += written;
+    }
+    assert_eq!(fs.free_blocks(), DEFAULT_RESERVED_BLOCKS);
+
+    // A write which doesn't fit frees the blocks it did allocate
+    fs.set_reserved_blocks(DEFAULT_RESERVED_BLOCKS - 1);
+    assert!(matches!(
+        fs.write(big, size, &alloc::vec![1; 2 * disk::BLOCK_SIZE]),
+        Err(FileSystemError::NoFreeBlocks)
+    ));
+    assert_eq!(fs.free_blocks(), DEFAULT_RESERVED_BLOCKS);
+    assert_eq!(fs.stat(big).unwrap().size, size);
+    fs.set_reserved_blocks(DEFAULT_RESERVED_BLOCKS);
+
+    // Directories can still grow into the reserve, files can't
+    fs.create_at("dir", InodeKind::Directory).unwrap();
+    let file = fs.create_at("dir/file", InodeKind::File).unwrap();
+    assert!(fs.free_blocks() < DEFAULT_RESERVED_BLOCKS);
+    assert!(matches!(
+        fs.write(file, 0, b"x"),
+        Err(FileSystemError::NoFreeBlocks)
+    ));
+    assert!(fs.check(None).unwrap().is_empty());
+
+    // Without a reserve, the rest of the disk is for anyone
+    fs.set_reserved_blocks(0);
+    fs.write(file, 0, b"x").unwrap();
+}
+
+#[test_case]
+fn test_max_file_size() {
+    use alloc::string::ToString;
+
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let inumber = fs.create(InodeKind::File).unwrap();
+    let free = fs.free_blocks();
+
+    // One byte past the limit is refused before anything is allocated, while the limit itself
+    // only needs the last block and the indirect block, the rest being a hole
+    assert_eq!(
+        MAX_FILE_SIZE,
+        (PTRS_PER_INODE + PTRS_PER_BLOCK) * disk::BLOCK_SIZE
+    );
+    let err = fs.write(inumber, MAX_FILE_SIZE, b"x").unwrap_err();
+    assert!(matches!(err, FileSystemError::FileTooLarge(size) if size == MAX_FILE_SIZE + 1));
+    assert!(err.to_string().ends_with(&MAX_FILE_SIZE.to_string()));
+    assert!(matches!(
+        fs.truncate(inumber, MAX_FILE_SIZE + 1),
+        Err(FileSystemError::FileTooLarge(_))
+    ));
+    assert_eq!(fs.free_blocks(), free);
+    assert_eq!(fs.stat(inumber).unwrap().size, 0);
+
+    assert_eq!(fs.write(inumber, MAX_FILE_SIZE - 1, b"x").unwrap(), 1);
+    assert_eq!(fs.free_blocks(), free - 2);
+    assert_eq!(fs.stat(inumber).unwrap().size, MAX_FILE_SIZE);
+    fs.truncate(inumber, 0).unwrap();
+    assert_eq!(fs.free_blocks(), free);
+}
+
+#[test_case]
+fn test_sparse_files() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let free = fs.free_blocks();
+
+    // Starting in the middle of a block past the end only allocates the blocks written to
+    let offset = (PTRS_PER_INODE + 3) * disk::BLOCK_SIZE + 100;
+    let data = [7; disk::BLOCK_SIZE];
+    let sparse = fs.create(InodeKind::File).unwrap();
+    fs.write(sparse, 0, b"head").unwrap();
+    fs.write(sparse, offset, &data).unwrap();
+    let metadata = fs.stat(sparse).unwrap();
+    assert_eq!((metadata.size, metadata.blocks), (offset + data.len(), 3));
+    // The indirect block comes on top of the data blocks
+    assert_eq!(fs.free_blocks(), free - 4);
+    assert!(fs.check(None).unwrap().is_empty());
+
+    // The hole reads as zeroes, up to the head of the block written to
+    let mut buf = alloc::vec![0xff; 2 * disk::BLOCK_SIZE];
+    let start = offset - disk::BLOCK_SIZE;
+    assert_eq!(fs.read(sparse, start, &mut buf).unwrap(), buf.len());
+    assert!(buf[..disk::BLOCK_SIZE].iter().all(|&byte| byte == 0));
+    assert!(buf[disk::BLOCK_SIZE..].iter().all(|&byte| byte == 7));
+    let mut head = [0xff; 8];
+    fs.read(sparse, 0, &mut head).unwrap();
+    assert_eq!(&head, b"head\0\0\0\0");
+    let mut hole = [0xff; 8];
+    fs.read(sparse, offset - 4, &mut hole).unwrap();
+    assert_eq!(hole, [0, 0, 0, 0, 7, 7, 7, 7]);
+
+    // Growing by truncating doesn't allocate either
+    fs.truncate(sparse, offset + 10 * disk::BLOCK_SIZE).unwrap();
+    assert_eq!(fs.stat(sparse).unwrap().blocks, 3);
+    assert_eq!(fs.free_blocks(), free - 4);
+
+    // A copy has the same holes, including the one at the end
+    let copy = fs.create(InodeKind::File).unwrap();
+    fs.copy(sparse, copy).unwrap();
+    let (src, dst) = (fs.stat(sparse).unwrap(), fs.stat(copy).unwrap());
+    assert_eq!((dst.size, dst.blocks), (src.size, src.blocks));
+    assert_eq!(fs.block_map(copy).unwrap(), fs.block_map(sparse).unwrap());
+    assert_eq!(fs.free_blocks(), free - 8);
+    let mut copied = alloc::vec![0xff; 2 * disk::BLOCK_SIZE];
+    fs.read(copy, start, &mut copied).unwrap();
+    assert!(copied == buf);
+}
+
+#[test_case]
+fn test_read_copies_each_block_once() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    fs.set_reserved_blocks(0);
+    const BLOCKS: usize = 100;
+    let big = fs.create_at("big", InodeKind::File).unwrap();
+    for i in 0..BLOCKS {
+        fs.write(big, i * disk::BLOCK_SIZE, &[i as u8; disk::BLOCK_SIZE])
+            .unwrap();
+    }
+
+    let mut buf = [0; disk::BLOCK_SIZE];
+    for _ in 0..3 {
+        let before = disk::cache_stats();
+        for i in 0..BLOCKS {
+            let read = fs.read(big, i * disk::BLOCK_SIZE, &mut buf).unwrap();
+            assert_eq!(read, disk::BLOCK_SIZE);
+            assert!(buf.iter().all(|&byte| byte == i as u8));
+        }
+        // Every data block is copied once, straight out of the cache. Only the inode and the
+        // pointer block are still read into a copy of their block
+        let after = disk::cache_stats();
+        assert_eq!(after.borrows - before.borrows, BLOCKS);
+        assert_eq!(
+            after.copies - before.copies,
+            BLOCKS + (BLOCKS - PTRS_PER_INODE)
+        );
+    }
+}
+
+#[test_case]
+fn test_copy_stops_when_job_is_cancelled() {
+    use crate::task::{cancel::CancellationToken, executor::Executor, Task};
+
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let src = fs.create_at("src", InodeKind::File).unwrap();
+    let dst = fs.create_at("dst", InodeKind::File).unwrap();
+    fs.write(src, 0, &[3; 3 * disk::BLOCK_SIZE]).unwrap();
+
+    // The token is found through the task running the copy, without being passed to it
+    let cancel = CancellationToken::new();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new({
+        let cancel = cancel.clone();
+        async move {
+            cancel.install();
+            let mut copied = 0;
+            let result = fs.copy_with_progress(src, dst, None, |bytes, _| {
+                copied = bytes;
+                cancel.cancel();
+            });
+            assert!(matches!(result, Err(FileSystemError::Interrupted)));
+            assert_eq!(copied, disk::BLOCK_SIZE);
+            assert!(job::cancel_requested());
+        }
+    }));
+    executor.run_until_done();
+    assert!(cancel.is_cancelled());
+    // Outside of the job nothing is cancelled
+    assert!(!job::cancel_requested());
+}
+
+#[test_case]
+fn test_copy_stops_when_token_is_cancelled() {
+    use crate::task::cancel::CancellationToken;
+
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let src = fs.create_at("src", InodeKind::File).unwrap();
+    let dst = fs.create_at("dst", InodeKind::File).unwrap();
+    fs.write(src, 0, &[5; 4 * disk::BLOCK_SIZE]).unwrap();
+
+    // Cancelling the job the copy is part of stops it too
+    let job = CancellationToken::new();
+    let cancel = job.child_token();
+    let mut copied = 0;
+    let result = fs.copy_with_progress(src, dst, Some(&cancel), |bytes, _| {
+        copied = bytes;
+        job.cancel();
+    });
+    assert!(matches!(result, Err(FileSystemError::Interrupted)));
+    assert_eq!(copied, disk::BLOCK_SIZE);
+    assert_eq!(fs.stat(dst).unwrap().size, disk::BLOCK_SIZE);
+}
+
+#[test_case]
+fn test_check_and_truncate_stop_when_cancelled() {
+    use crate::task::cancel::CancellationToken;
+
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let inumber = fs.create_at("file", InodeKind::File).unwrap();
+    let size = (PTRS_PER_INODE + 2) * disk::BLOCK_SIZE;
+    fs.write(inumber, 0, &alloc::vec![9; size]).unwrap();
+    let free = fs.free_blocks();
+
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    assert!(matches!(
+        fs.check(Some(&cancel)),
+        Err(FileSystemError::Interrupted)
+    ));
+    // Nothing is freed once cancelled, and growing a file has nothing to stop
+    assert!(matches!(
+        fs.truncate_with_cancel(inumber, 10, Some(&cancel)),
+        Err(FileSystemError::Interrupted)
+    ));
+    assert_eq!(fs.stat(inumber).unwrap().size, size);
+    assert_eq!(fs.free_blocks(), free);
+    fs.truncate_with_cancel(inumber, size + 10, Some(&cancel))
+        .unwrap();
+
+    // Without a cancelled token the blocks are freed one at a time down to the new size
+    fs.truncate_with_cancel(inumber, 10, None).unwrap();
+    assert_eq!(fs.stat(inumber).unwrap().size, 10);
+    assert_eq!(fs.free_blocks(), free + PTRS_PER_INODE + 2);
+    let mut buf = [0; 16];
+    assert_eq!(fs.read(inumber, 0, &mut buf).unwrap(), 10);
+    assert_eq!(buf[..10], [9; 10]);
+    assert!(fs.check(None).unwrap().is_empty());
+}
+
+#[test_case]
+fn test_compressed_files_round_trip() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let text = b"compressed file contents, ".repeat(1000);
+    let mut noise = alloc::vec![0; 3 * disk::BLOCK_SIZE];
+    crate::rand::fill_bytes(&mut noise);
+
+    let mut files = Vec::new();
+    for data in [&text, &noise] {
+        let inumber = fs.create_compressed().unwrap();
+        fs.write(inumber, 0, data).unwrap();
+        files.push(inumber);
+    }
+    let (text_file, noise_file) = (files[0], files[1]);
+    let stat = fs.stat(text_file).unwrap();
+    assert!(stat.compressed);
+    assert_eq!(stat.size, text.len());
+    assert!(stat.physical_size < text.len() / 2);
+    // Blocks which don't compress are stored as they are, after the map block
+    let stat = fs.stat(noise_file).unwrap();
+    assert_eq!(stat.size, noise.len());
+    assert_eq!(
+        stat.physical_size,
+        disk::BLOCK_SIZE + 3 * (lz::BLOCK_HEADER_SIZE + disk::BLOCK_SIZE)
+    );
+    assert_eq!(
+        fs.compression_stats().unwrap(),
+        CompressionStats {
+            files: 2,
+            size: text.len() + noise.len(),
+            physical_size: fs.stat(text_file).unwrap().physical_size + stat.physical_size,
+        }
+    );
+
+    // The map block and the compressed blocks survive remounting
+    fs.unmount();
+    fs.mount().unwrap();
+    for (inumber, data) in [(text_file, &text), (noise_file, &noise)] {
+        let mut buf = alloc::vec![0; data.len() + 10];
+        assert_eq!(fs.read(inumber, 0, &mut buf).unwrap(), data.len());
+        assert!(buf[..data.len()] == data[..]);
+        fs.verify(inumber).unwrap();
+    }
+
+    // Emptying a compressed file frees all of its blocks, but it stays compressed
+    let free = fs.free_blocks();
+    fs.truncate(text_file, 0).unwrap();
+    assert_eq!(fs.free_blocks(), free + 2);
+    fs.write(text_file, 0, b"again").unwrap();
+    assert!(fs.stat(text_file).unwrap().compressed);
+    assert_eq!(fs.stat(text_file).unwrap().size, 5);
+    assert_eq!(fs.raw_inode(text_file).unwrap().flags, 0b11);
+}
+
+#[test_case]
+fn test_compressed_file_random_access() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let inumber = fs.create_compressed().unwrap();
+    let size = 10 * disk::BLOCK_SIZE + 321;
+    let mut data: Vec<u8> = (0..size).map(|i| (i / 7 % 256) as u8).collect();
+    fs.write(inumber, 0, &data).unwrap();
+
+    let mut buf = [0; 3 * disk::BLOCK_SIZE];
+    for _ in 0..50 {
+        let offset = crate::rand::gen_range(0..size);
+        let len = crate::rand::gen_range(0..buf.len());
+        let read = fs.read(inumber, offset, &mut buf[..len]).unwrap();
+        assert_eq!(read, len.min(size - offset));
+        assert_eq!(buf[..read], data[offset..offset + read]);
+    }
+
+    // Overwriting across blocks with data which compresses worse moves the blocks
+    let mut noise = [0; disk::BLOCK_SIZE];
+    crate::rand::fill_bytes(&mut noise);
+    let offset = 2 * disk::BLOCK_SIZE + 1000;
+    fs.write(inumber, offset, &noise).unwrap();
+    data[offset..offset + noise.len()].copy_from_slice(&noise);
+    // Writing past the end leaves zeroes in between, which take no space
+    let stored = fs.stat(inumber).unwrap().physical_size;
+    fs.write(inumber, MAX_COMPRESSED_FILE_SIZE - 1, b"!")
+        .unwrap();
+    assert!(fs.stat(inumber).unwrap().physical_size < stored + disk::BLOCK_SIZE);
+    assert!(matches!(
+        fs.write(inumber, MAX_COMPRESSED_FILE_SIZE, b"!"),
+        Err(FileSystemError::FileTooLarge(_))
+    ));
+    let mut tail = [0xff; 2 * disk::BLOCK_SIZE];
+    fs.read(inumber, size - 1000, &mut tail).unwrap();
+    assert_eq!(tail[..1000], data[size - 1000..]);
+    assert!(tail[1000..].iter().all(|&byte| byte == 0));
+
+    // Shrinking clears the rest of the last block, so growing it again reads zeroes
+    fs.truncate(inumber, offset + 10).unwrap();
+    fs.truncate(inumber, offset + 100).unwrap();
+    let mut buf = [0xff; 100];
+    assert_eq!(fs.read(inumber, offset, &mut buf).unwrap(), 100);
+    assert_eq!(buf[..10], noise[..10]);
+    assert!(buf[10..].iter().all(|&byte| byte == 0));
+
+    // Packing the file gives back the space of the blocks which moved
+    let before = fs.stat(inumber).unwrap();
+    fs.set_compressed(inumber, true).unwrap();
+    let after = fs.stat(inumber).unwrap();
+    assert!(after.physical_size < before.physical_size);
+    assert_eq!((after.size, after.links), (before.size, before.links));
+    let mut buf = alloc::vec![0; offset + 100];
+    fs.read(inumber, 0, &mut buf).unwrap();
+    assert_eq!(buf[..offset + 10], data[..offset + 10]);
+}
+
+#[test_case]
+fn test_set_compressed() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let inumber = fs.create_at("log", InodeKind::File).unwrap();
+    let data = b"a line of the log\n".repeat(2000);
+    fs.write(inumber, 0, &data).unwrap();
+    let free = fs.free_blocks();
+
+    fs.set_compressed(inumber, true).unwrap();
+    assert!(fs.free_blocks() > free + 5);
+    let stat = fs.stat(inumber).unwrap();
+    assert_eq!((stat.size, stat.compressed), (data.len(), true));
+    // Kernels from before compressed files don't mount the disk at all, as its version is newer
+    assert_eq!(fs.superblock.incompat_features, INCOMPAT_COMPRESSION);
+    assert!(fs.check(None).unwrap().is_empty());
+
+    fs.set_compressed(inumber, false).unwrap();
+    assert_eq!(fs.free_blocks(), free);
+    assert!(!fs.stat(inumber).unwrap().compressed);
+    let mut buf = alloc::vec![0; data.len()];
+    fs.read(inumber, 0, &mut buf).unwrap();
+    assert!(buf == data);
+    assert!(fs.check(None).unwrap().is_empty());
+    assert!(matches!(
+        fs.set_compressed(ROOT_INUMBER, true),
+        Err(FileSystemError::IsADirectory(_))
+    ));
+}
+
+#[test_case]
+fn test_corrupt_compressed_block() {
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let inumber = fs.create_compressed().unwrap();
+    fs.write(inumber, 0, &[1; 2 * disk::BLOCK_SIZE]).unwrap();
+
+    // The first compressed block follows the map block. A match reaching back before the start
+    // of the block is caught rather than read out of bounds.
+    let block = fs.raw_inode(inumber).unwrap().direct[1] as usize;
+    disk::write(block, lz::BLOCK_HEADER_SIZE, &[0xff; 8]).unwrap();
+    let mut buf = [0; 10];
+    match fs.read(inumber, 5, &mut buf) {
+        Err(FileSystemError::CorruptCompressedBlock {
+            inumber: i,
+            n,
+            error,
+        }) => {
+            assert_eq!((i, n), (inumber, 0));
+            assert!(matches!(error, LzError::BadDistance { .. }));
+        }
+        other => panic!("expected a corrupt block, got {:?}", other),
+    }
+    // The other block is still read
+    assert_eq!(fs.read(inumber, disk::BLOCK_SIZE, &mut buf).unwrap(), 10);
+    assert_eq!(buf, [1; 10]);
+    assert!(matches!(
+        fs.verify(inumber),
+        Err(FileSystemError::ChecksumMismatch { .. })
+    ));
+
+    // So is a length running past the end of the file
+    disk::write(block, 0, &0x7fffu16.to_le_bytes()).unwrap();
+    assert!(matches!(
+        fs.read(inumber, 0, &mut buf),
+        Err(FileSystemError::CorruptCompressedBlock {
+            error: LzError::ShortBlock { .. },
+            ..
+        })
+    ));
+    FileSystem::format().unwrap();
 }
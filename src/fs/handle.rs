@@ -0,0 +1,238 @@
+use super::{
+    disk::BLOCK_SIZE,
+    file::{FileSystem, FileSystemError, Generation, INumber, Metadata},
+};
+
+/// An open file with a read/write position.
+///
+/// A handle remembers the generation of the inode it was opened on. Deleting a file bumps the
+/// generation of its inode, so once the inumber is reused by another file every operation on the
+/// old handle fails with [`FileSystemError::StaleHandle`] instead of touching the new file.
+///
+/// Reading a handle sequentially reads the following blocks of the file into the block cache
+/// ahead of time, see [`FileSystem::set_read_ahead`].
+///
+/// A handle in append mode writes at the end of the file wherever its position is, see
+/// [`File::set_append`].
+///
+/// Handles don't lock the filesystem, the filesystem is passed to each operation instead.
+#[derive(Debug)]
+pub struct File {
+    inumber: INumber,
+    generation: Generation,
+    offset: usize,
+    // The last block of the file read through this handle, to detect sequential reads
+    last_block: Option<usize>,
+    append: bool,
+}
+
+impl FileSystem {
+    /// Opens the file at the path, positioned at its start.
+    pub fn open(&self, path: &str) -> Result<File, FileSystemError> {
+        let inumber = self.resolve(path)?;
+        self.open_inode(inumber)
+    }
+
+    /// Opens the file using the inode, positioned at its start.
+    pub fn open_inode(&self, inumber: INumber) -> Result<File, FileSystemError> {
+        Ok(File::new(inumber, self.generation(inumber)?))
+    }
+}
+
+impl File {
+    /// Creates a handle positioned at the start of the file, for filesystems which keep their
+    /// files elsewhere, see [`Vfs::open`](super::vfs::Vfs::open).
+    pub(super) fn new(inumber: INumber, generation: Generation) -> Self {
+        Self {
+            inumber,
+            generation,
+            offset: 0,
+            last_block: None,
+            append: false,
+        }
+    }
+
+    pub fn inumber(&self) -> INumber {
+        self.inumber
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Moves the position used by the next read or write.
+    pub fn seek(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    /// Makes every write go to the end of the file, even when the file was grown through another
+    /// handle since the last write.
+    pub fn set_append(&mut self, append: bool) {
+        self.append = append;
+    }
+
+    pub fn is_append(&self) -> bool {
+        self.append
+    }
+
+    pub fn stat(&self, fs: &FileSystem) -> Result<Metadata, FileSystemError> {
+        fs.check_generation(self.inumber, self.generation)?;
+        fs.stat(self.inumber)
+    }
+
+    /// Reads from the current position, advancing it by the number of bytes read.
+    pub fn read(&mut self, fs: &FileSystem, outbuf: &mut [u8]) -> Result<usize, FileSystemError> {
+        fs.check_generation(self.inumber, self.generation)?;
+        let first = self.offset / BLOCK_SIZE;
+        let sequential = self
+            .last_block
+            .is_some_and(|last| first == last || first == last + 1);
+
+        let read = fs.read(self.inumber, self.offset, outbuf)?;
+        if read > 0 {
+            self.offset += read;
+            let last = (self.offset - 1) / BLOCK_SIZE;
+            self.last_block = Some(last);
+            if sequential {
+                fs.prefetch(self.inumber, last + 1)?;
+            }
+        }
+        Ok(read)
+    }
+
+    /// Writes at the current position, or at the end of the file in append mode, advancing the
+    /// position by the number of bytes written.
+    pub fn write(&mut self, fs: &mut FileSystem, data: &[u8]) -> Result<usize, FileSystemError> {
+        fs.check_generation(self.inumber, self.generation)?;
+        if self.append {
+            self.offset = fs.stat(self.inumber)?.size;
+        }
+        let written = fs.write(self.inumber, self.offset, data)?;
+        self.offset += written;
+        Ok(written)
+    }
+
+    pub fn truncate(&self, fs: &mut FileSystem, size: usize) -> Result<(), FileSystemError> {
+        fs.check_generation(self.inumber, self.generation)?;
+        fs.truncate(self.inumber, size)
+    }
+}
+
+#[test_case]
+fn test_stale_handle_after_inumber_reuse() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    fs.create_at("a", super::file::InodeKind::File).unwrap();
+    let mut a = fs.open("a").unwrap();
+    a.write(&mut fs, b"old file").unwrap();
+
+    fs.remove("a").unwrap();
+    let b = fs.create_at("b", super::file::InodeKind::File).unwrap();
+    assert_eq!(b, a.inumber());
+    fs.write(b, 0, b"new file").unwrap();
+
+    let mut buf = [0; 8];
+    a.seek(0);
+    assert!(matches!(
+        a.read(&fs, &mut buf),
+        Err(FileSystemError::StaleHandle(i)) if i == b
+    ));
+    assert!(matches!(
+        a.write(&mut fs, b"clobber!"),
+        Err(FileSystemError::StaleHandle(_))
+    ));
+    assert!(matches!(
+        a.truncate(&mut fs, 0),
+        Err(FileSystemError::StaleHandle(_))
+    ));
+
+    // The new file is untouched, and a fresh handle to it works
+    let mut b = fs.open("b").unwrap();
+    assert_eq!(b.read(&fs, &mut buf).unwrap(), 8);
+    assert_eq!(&buf, b"new file");
+}
+
+#[test_case]
+fn test_append_mode() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    fs.create_at("log", super::file::InodeKind::File).unwrap();
+    let mut log = fs.open("log").unwrap();
+    log.set_append(true);
+    log.write(&mut fs, b"one ").unwrap();
+
+    // Writes through another handle don't get overwritten, and seeking doesn't move the writes
+    let mut other = fs.open("log").unwrap();
+    other.seek(4);
+    other.write(&mut fs, b"two ").unwrap();
+    log.seek(0);
+    log.write(&mut fs, b"three").unwrap();
+    assert_eq!(log.offset(), 13);
+
+    let mut buf = [0; 16];
+    let read = fs.open("log").unwrap().read(&fs, &mut buf).unwrap();
+    assert_eq!(&buf[..read], b"one two three");
+}
+
+#[cfg(test)]
+fn cat(fs: &FileSystem, file: &mut File, expected: &[u8]) {
+    let mut buf = [0; BLOCK_SIZE];
+    let mut offset = 0;
+    loop {
+        let read = file.read(fs, &mut buf).unwrap();
+        if read == 0 {
+            break;
+        }
+        assert_eq!(&buf[..read], &expected[offset..offset + read]);
+        offset += read;
+    }
+    assert_eq!(offset, expected.len());
+}
+
+#[test_case]
+fn test_read_ahead() {
+    use super::disk;
+
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let data: alloc::vec::Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i % 253) as u8).collect();
+    let inumber = fs.create_at("big", super::file::InodeKind::File).unwrap();
+    fs.write(inumber, 0, &data).unwrap();
+
+    fs.set_read_ahead(0);
+    disk::invalidate_cache();
+    let before = disk::stats();
+    cat(&fs, &mut fs.open("big").unwrap(), &data);
+    let without = disk::stats().reads - before.reads;
+
+    fs.set_read_ahead(4);
+    disk::invalidate_cache();
+    let before = disk::stats();
+    let cache_before = disk::cache_stats();
+    cat(&fs, &mut fs.open("big").unwrap(), &data);
+    let after = disk::stats();
+    let with = after.reads - before.reads;
+    assert!(
+        with < without,
+        "{} reads with read-ahead, {} without",
+        with,
+        without
+    );
+    assert!(after.blocks_read - before.blocks_read > with);
+    let cache_after = disk::cache_stats();
+    assert_eq!(
+        cache_after.prefetch_hits - cache_before.prefetch_hits,
+        cache_after.prefetched - cache_before.prefetched
+    );
+    assert!(cache_after.prefetch_hits > cache_before.prefetch_hits);
+
+    // Jumping around the file doesn't read ahead
+    disk::invalidate_cache();
+    let mut file = fs.open("big").unwrap();
+    let mut buf = [0; 100];
+    for block in [15, 3, 9, 1] {
+        file.seek(block * BLOCK_SIZE);
+        file.read(&fs, &mut buf).unwrap();
+    }
+    assert_eq!(disk::cache_stats().prefetched, cache_after.prefetched);
+}
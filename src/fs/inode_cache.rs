@@ -0,0 +1,252 @@
+use core::{mem::size_of, slice};
+
+use alloc::vec::Vec;
+
+use super::{
+    disk::{self, DiskError},
+    file::{INumber, Inode, INODES_PER_BLOCK, INODE_BLOCKS_START, INODE_SIZE},
+};
+
+/// Number of inodes kept in the inode cache.
+pub const INODE_CACHE_SIZE: usize = 64;
+
+/// Counters describing how well the inode cache is doing, see [`InodeCache`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InodeCacheStats {
+    pub hits: usize,
+    /// Inodes read from their inode block.
+    pub misses: usize,
+    /// Changed inodes written back to their inode block.
+    pub write_backs: usize,
+}
+
+struct CachedInode {
+    inumber: INumber,
+    inode: Inode,
+    /// Set while the inode has changes which haven't been written to its inode block.
+    dirty: bool,
+    last_used: u64,
+}
+
+/// A write-back cache of decoded inodes, evicting the least recently used inode when full.
+/// Changed inodes stay in the cache until [`write_back`](Self::write_back) writes them to their
+/// inode blocks, which the filesystem does before every flush, or until they're evicted.
+///
+/// This is the only place inodes are written to the disk, so that the cache can't miss a change.
+pub(super) struct InodeCache {
+    entries: Vec<CachedInode>,
+    clock: u64,
+    stats: InodeCacheStats,
+}
+
+impl Default for InodeCache {
+    fn default() -> Self {
+        Self {
+            entries: Vec::with_capacity(INODE_CACHE_SIZE),
+            clock: 0,
+            stats: InodeCacheStats::default(),
+        }
+    }
+}
+
+impl InodeCache {
+    pub fn stats(&self) -> InodeCacheStats {
+        self.stats
+    }
+
+    /// Returns the inode, reading it from the disk if it isn't cached.
+    pub fn get(&mut self, inumber: INumber) -> Result<Inode, DiskError> {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entry(inumber) {
+            entry.last_used = clock;
+            let inode = entry.inode;
+            self.stats.hits += 1;
+            return Ok(inode);
+        }
+        self.stats.misses += 1;
+        let inode = read_inode(inumber)?;
+        self.insert(inumber, inode, false)?;
+        Ok(inode)
+    }
+
+    /// Replaces the inode, which reaches the disk when it's written back.
+    pub fn put(&mut self, inumber: INumber, inode: &Inode) -> Result<(), DiskError> {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entry(inumber) {
+            Some(entry) => {
+                entry.inode = *inode;
+                entry.dirty = true;
+                entry.last_used = clock;
+                Ok(())
+            }
+            None => self.insert(inumber, *inode, true),
+        }
+    }
+
+    /// Writes every changed inode to its inode block. The blocks still have to be flushed.
+    pub fn write_back(&mut self) -> Result<(), DiskError> {
+        for entry in self.entries.iter_mut().filter(|entry| entry.dirty) {
+            write_inode(entry.inumber, &entry.inode)?;
+            entry.dirty = false;
+            self.stats.write_backs += 1;
+        }
+        Ok(())
+    }
+
+    /// Writes the inode back if it changed and drops it from the cache, so that it's read from the
+    /// disk the next time.
+    pub fn evict(&mut self, inumber: INumber) -> Result<(), DiskError> {
+        let Some(i) = self
+            .entries
+            .iter()
+            .position(|entry| entry.inumber == inumber)
+        else {
+            return Ok(());
+        };
+        let entry = &self.entries[i];
+        if entry.dirty {
+            write_inode(entry.inumber, &entry.inode)?;
+            self.stats.write_backs += 1;
+        }
+        self.entries.swap_remove(i);
+        Ok(())
+    }
+
+    /// Drops every inode, losing the changes which weren't written back. Used when the inode
+    /// blocks were changed behind the cache's back.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Gives the memory of the cache back to the heap if no inode has unwritten changes, returning
+    /// the number of bytes given back. Doesn't allocate, so it can be used to reclaim memory when
+    /// the heap runs out.
+    pub fn shrink(&mut self) -> usize {
+        if self.entries.iter().any(|entry| entry.dirty) {
+            return 0;
+        }
+        let freed = self.entries.capacity() * size_of::<CachedInode>();
+        self.entries = Vec::new();
+        freed
+    }
+
+    fn entry(&mut self, inumber: INumber) -> Option<&mut CachedInode> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.inumber == inumber)
+    }
+
+    fn insert(&mut self, inumber: INumber, inode: Inode, dirty: bool) -> Result<(), DiskError> {
+        if self.entries.len() == INODE_CACHE_SIZE {
+            let (lru, _) = self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .unwrap();
+            let victim = &self.entries[lru];
+            if victim.dirty {
+                write_inode(victim.inumber, &victim.inode)?;
+                self.stats.write_backs += 1;
+            }
+            self.entries.swap_remove(lru);
+        }
+        self.entries.push(CachedInode {
+            inumber,
+            inode,
+            dirty,
+            last_used: self.clock,
+        });
+        Ok(())
+    }
+}
+
+/// Returns the block holding the inode and the index of the inode within that block.
+pub(super) fn inode_position(inumber: INumber) -> (usize, usize) {
+    (
+        inumber as usize / INODES_PER_BLOCK + INODE_BLOCKS_START,
+        inumber as usize % INODES_PER_BLOCK,
+    )
+}
+
+fn read_inode(inumber: INumber) -> Result<Inode, DiskError> {
+    let (block, idx) = inode_position(inumber);
+    let mut bytes = [0; INODE_SIZE];
+    disk::read(block, idx * INODE_SIZE, &mut bytes)?;
+    Ok(unsafe { core::ptr::read_unaligned(bytes.as_ptr().cast()) })
+}
+
+fn write_inode(inumber: INumber, inode: &Inode) -> Result<(), DiskError> {
+    let (block, idx) = inode_position(inumber);
+    // The inode has no padding, see `Inode`
+    let bytes = unsafe { slice::from_raw_parts((inode as *const Inode).cast::<u8>(), INODE_SIZE) };
+    disk::write(block, idx * INODE_SIZE, bytes)
+}
+
+#[test_case]
+fn test_inode_cache() {
+    use super::{
+        disk::BLOCK_SIZE,
+        file::{FileSystem, FileSystemError, InodeKind},
+    };
+
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let inumber = fs.create_at("cached", InodeKind::File).unwrap();
+    fs.write(inumber, 0, &[3; 4 * BLOCK_SIZE]).unwrap();
+    assert_eq!(fs.raw_inode(inumber).unwrap().size, 4 * BLOCK_SIZE);
+
+    // Reading the file over and over never goes back to its inode block
+    let before = fs.inode_cache_stats();
+    let mut buf = [0; BLOCK_SIZE];
+    for _ in 0..10 {
+        for n in 0..4 {
+            assert_eq!(
+                fs.read(inumber, n * BLOCK_SIZE, &mut buf).unwrap(),
+                BLOCK_SIZE
+            );
+        }
+    }
+    let after = fs.inode_cache_stats();
+    assert_eq!(after.misses, before.misses);
+    assert!(after.hits >= before.hits + 40);
+
+    // Inodes changed behind the cache's back are only seen once it's invalidated
+    let mut raw = fs.raw_inode(inumber).unwrap();
+    raw.links = 5;
+    let (block, idx) = inode_position(inumber);
+    disk::write(block, idx * INODE_SIZE, &raw.to_bytes()).unwrap();
+    assert_eq!(fs.stat(inumber).unwrap().links, 1);
+    fs.invalidate_inode_cache();
+    assert_eq!(fs.stat(inumber).unwrap().links, 5);
+    raw.links = 1;
+    disk::write(block, idx * INODE_SIZE, &raw.to_bytes()).unwrap();
+    fs.invalidate_inode_cache();
+
+    // A file reusing a deleted inode doesn't get the cached one
+    let mut handle = fs.open_inode(inumber).unwrap();
+    fs.remove("cached").unwrap();
+    let reused = fs.create(InodeKind::Directory).unwrap();
+    assert_eq!(reused, inumber);
+    let metadata = fs.stat(reused).unwrap();
+    assert_eq!((metadata.kind, metadata.size), (InodeKind::Directory, 0));
+    assert!(matches!(
+        handle.read(&fs, &mut buf),
+        Err(FileSystemError::StaleHandle(_))
+    ));
+    assert_eq!(
+        fs.raw_inode(reused).unwrap().kind,
+        InodeKind::Directory as u8
+    );
+
+    // Unmounting writes back the inodes which haven't been flushed
+    let touched = fs.create(InodeKind::File).unwrap();
+    fs.unmount();
+    fs.mount().unwrap();
+    assert_eq!(fs.stat(touched).unwrap().links, 1);
+
+    FileSystem::format().unwrap();
+}
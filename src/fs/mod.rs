@@ -1,2 +1,112 @@
+use alloc::boxed::Box;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use self::{
+    file::{FileSystem, FileSystemError},
+    vfs::{KernelFs, VfsRouter},
+};
+
+pub mod async_io;
+pub mod ata;
+pub mod cache;
+pub mod dir;
 pub mod disk;
+pub mod ext2;
 pub mod file;
+pub mod handle;
+pub mod inode_cache;
+pub mod partitions;
+pub mod path;
+pub mod proc;
+pub mod ramfs;
+pub mod stress;
+mod superblock;
+pub mod transfer;
+pub mod vfs;
+pub mod watch;
+
+/// The name of the disk in the mount table, where the kernel filesystem is mounted at `/`.
+pub const DISK_DEVICE: &str = "disk";
+
+lazy_static! {
+    pub static ref FILESYSTEM: Mutex<FileSystem> = Mutex::new(FileSystem::new());
+    /// The mount table, through which paths reach the kernel filesystem and whatever is mounted
+    /// on it. When the kernel filesystem is locked as well, this is locked first.
+    pub static ref VFS: Mutex<VfsRouter> = Mutex::new(root_router());
+}
+
+fn root_router() -> VfsRouter {
+    VfsRouter::new(DISK_DEVICE, Box::new(KernelFs))
+}
+
+/// Formats the disk and mounts it as the kernel filesystem, leaving an empty root directory with
+/// nothing else mounted.
+pub fn init() -> Result<(), FileSystemError> {
+    *VFS.lock() = root_router();
+    FileSystem::format()?;
+    FILESYSTEM.lock().mount()
+}
+
+/// Drops the inode cache of the kernel filesystem when the heap runs out. Registered with
+/// [`allocator::register_reclaim`](crate::allocator::register_reclaim), so it frees nothing while
+/// the filesystem is locked.
+pub fn reclaim_inodes() -> usize {
+    FILESYSTEM
+        .try_lock()
+        .map_or(0, |fs| fs.shrink_inode_cache())
+}
+
+/// Mounts the disk as the kernel filesystem at boot. A disk which has never been formatted is
+/// formatted first, but a damaged one is left alone for the shell to recover, and the kernel
+/// filesystem stays unmounted.
+pub fn mount_root() -> Result<(), FileSystemError> {
+    if FileSystem::is_blank()? {
+        FileSystem::format()?;
+    }
+    FILESYSTEM.lock().mount()
+}
+
+#[test_case]
+fn test_mount_root_uses_backup_superblock() {
+    use file::{InodeKind, SuperblockCopy};
+
+    init().unwrap();
+    FILESYSTEM
+        .lock()
+        .create_at("kept", InodeKind::File)
+        .unwrap();
+    let mounted = || {
+        let fs = FILESYSTEM.lock();
+        assert!(fs.resolve("kept").is_ok());
+        fs.mounted_from()
+    };
+
+    // A primary superblock overwritten with garbage, and one with an inode count changed behind
+    // its checksum, are both passed over for the backup
+    disk::write(0, 0, &[0xff; 64]).unwrap();
+    mount_root().unwrap();
+    assert_eq!(mounted(), Some(SuperblockCopy::Backup));
+    FILESYSTEM.lock().repair_superblock().unwrap();
+    disk::write(0, 24, &1u64.to_le_bytes()).unwrap();
+    mount_root().unwrap();
+    assert_eq!(mounted(), Some(SuperblockCopy::Backup));
+    {
+        let mut fs = FILESYSTEM.lock();
+        assert!(matches!(
+            fs.check(None).unwrap()[..],
+            [FileSystemError::SuperblockMismatch(SuperblockCopy::Primary)]
+        ));
+        assert_eq!(fs.repair_superblock().unwrap(), [SuperblockCopy::Primary]);
+        assert!(fs.check(None).unwrap().is_empty());
+    }
+    mount_root().unwrap();
+    assert_eq!(mounted(), Some(SuperblockCopy::Primary));
+
+    // Only a disk without either superblock is formatted
+    for copy in [SuperblockCopy::Primary, SuperblockCopy::Backup] {
+        disk::write(copy.block(), 0, &[0; 64]).unwrap();
+    }
+    mount_root().unwrap();
+    assert!(FILESYSTEM.lock().resolve("kept").is_err());
+}
@@ -0,0 +1,538 @@
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use spin::Mutex;
+use thiserror_no_std::Error;
+
+use super::disk::{
+    self, BlockDevice, Disk, DiskError, DiskStats, KernelDisk, BLOCK_SIZE, SECTOR_SIZE,
+};
+use crate::error::{self, ErrorKind};
+
+// The partition table is kept in the first sector of the device, the master boot record. The four
+// primary partitions are described by 16 byte entries starting at PARTITION_TABLE, each
+// [STATUS (1 byte), CHS START (3 bytes), TYPE (1 byte), CHS END (3 bytes), START LBA (4 bytes,
+// LE), SECTORS (4 bytes, LE)],
+// and the sector ends with SIGNATURE. The CHS addresses are ignored, the LBAs count sectors of
+// SECTOR_SIZE bytes.
+const PARTITION_TABLE: usize = 0x1be;
+const ENTRY_SIZE: usize = 16;
+const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// The number of primary partitions in a master boot record.
+pub const PRIMARY_PARTITIONS: usize = 4;
+
+/// The whole disk while one of its partitions is attached in its place, with the number of the
+/// partition, see [`attach`].
+static WHOLE_DISK: Mutex<Option<(Box<dyn BlockDevice + Send>, usize)>> = Mutex::new(None);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PartitionError {
+    #[error("no partition table, the first sector doesn't end in 55 aa")]
+    NoPartitionTable,
+    #[error("partition {0} isn't aligned to blocks of {1} bytes")]
+    Misaligned(usize, usize),
+    #[error("partition {0} extends past the end of the device")]
+    PastEnd(usize),
+    #[error("partition {0} isn't in the partition table")]
+    NotFound(usize),
+    #[error("{0}")]
+    Disk(#[from] DiskError),
+}
+
+impl error::Error for PartitionError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NoPartitionTable | Self::NotFound(_) => ErrorKind::NotFound,
+            Self::Misaligned(..) => ErrorKind::Unsupported,
+            Self::PastEnd(_) => ErrorKind::Corrupt,
+            Self::Disk(err) => err.kind(),
+        }
+    }
+
+    fn source(&self) -> Option<&dyn error::Error> {
+        match self {
+            Self::Disk(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// A primary partition of a master boot record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// The number of the partition, from 1 to 4.
+    pub number: usize,
+    /// The type byte, see [`type_name`](Self::type_name).
+    pub kind: u8,
+    pub start_lba: u32,
+    pub sectors: u32,
+}
+
+impl Partition {
+    /// Returns the size of the partition in bytes.
+    pub fn size(&self) -> u64 {
+        self.sectors as u64 * SECTOR_SIZE as u64
+    }
+
+    /// Returns the name of common partition types, or `"unknown"`.
+    pub fn type_name(&self) -> &'static str {
+        match self.kind {
+            0x01 => "FAT12",
+            0x04 | 0x06 | 0x0e => "FAT16",
+            0x05 | 0x0f | 0x85 => "extended",
+            0x07 => "NTFS/exFAT",
+            0x0b | 0x0c => "FAT32",
+            0x82 => "Linux swap",
+            0x83 => "Linux",
+            0x8e => "Linux LVM",
+            0xa5 => "FreeBSD",
+            0xee => "GPT protective",
+            0xef => "EFI system",
+            _ => "unknown",
+        }
+    }
+
+    /// Returns the name of the partition on the device called `device`, as in `ata0p1`.
+    pub fn device_name(&self, device: &str) -> String {
+        format!("{}p{}", device, self.number)
+    }
+
+    /// Returns the number of the partition called `name` on the device called `device`, if it's
+    /// named like [`Self::device_name`] names one.
+    pub fn number_in_name(name: &str, device: &str) -> Option<usize> {
+        let number = name.strip_prefix(device)?.strip_prefix('p')?;
+        if number.starts_with('0') {
+            return None;
+        }
+        number
+            .parse()
+            .ok()
+            .filter(|number| (1..=PRIMARY_PARTITIONS).contains(number))
+    }
+
+    /// Returns the first block of the partition and its number of blocks on a device with blocks
+    /// of `block_size` bytes, which the partition has to start and end on.
+    fn extent(&self, block_size: usize) -> Result<(usize, usize), PartitionError> {
+        let (start, size) = (
+            self.start_lba as usize * SECTOR_SIZE,
+            self.sectors as usize * SECTOR_SIZE,
+        );
+        if start % block_size != 0 || size % block_size != 0 {
+            return Err(PartitionError::Misaligned(self.number, block_size));
+        }
+        Ok((start / block_size, size / block_size))
+    }
+}
+
+/// Reads the partition table in the first sector of a device, returning the partitions which are
+/// used, those with a type other than 0. Fails with [`PartitionError::NoPartitionTable`] if the
+/// sector doesn't end in the signature of a master boot record.
+pub fn read_table(device: &(impl BlockDevice + ?Sized)) -> Result<Vec<Partition>, PartitionError> {
+    let mut sector = [0; SECTOR_SIZE];
+    device.read(0, &mut sector)?;
+    if sector[SECTOR_SIZE - 2..] != SIGNATURE {
+        return Err(PartitionError::NoPartitionTable);
+    }
+    let entries = sector[PARTITION_TABLE..].chunks_exact(ENTRY_SIZE);
+    let field = |entry: &[u8], at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+    Ok(entries
+        .take(PRIMARY_PARTITIONS)
+        .enumerate()
+        .filter(|(_, entry)| entry[4] != 0)
+        .map(|(i, entry)| Partition {
+            number: i + 1,
+            kind: entry[4],
+            start_lba: field(entry, 8),
+            sectors: field(entry, 12),
+        })
+        .collect())
+}
+
+/// Makes the kernel filesystem live in partition `number` of the disk, or on the whole disk again
+/// for `None`. The partition is wrapped in a [`PartitionDevice`] and attached in place of the
+/// disk, see [`disk::attach`], so the filesystem has to be mounted again afterwards. If the
+/// partition can't be attached the whole disk is left attached.
+pub fn attach(number: Option<usize>) -> Result<(), PartitionError> {
+    if attached() == number {
+        return Ok(());
+    }
+    attach_whole_disk();
+    let Some(number) = number else {
+        return Ok(());
+    };
+    let partition = read_table(&KernelDisk)?
+        .into_iter()
+        .find(|partition| partition.number == number)
+        .ok_or(PartitionError::NotFound(number))?;
+    let whole = disk::attach(Box::new(Disk::new(0)));
+    *WHOLE_DISK.lock() = Some((whole, number));
+    match PartitionDevice::new(WholeDisk, &partition) {
+        Ok(device) => {
+            disk::attach(Box::new(device));
+            Ok(())
+        }
+        Err(err) => {
+            attach_whole_disk();
+            Err(err)
+        }
+    }
+}
+
+/// Puts the whole disk back in place of the partition attached by [`attach`], if there is one.
+fn attach_whole_disk() {
+    if attached().is_none() {
+        return;
+    }
+    // The partition is detached first, flushing the blocks cached for it to the whole disk
+    disk::attach(Box::new(Disk::new(0)));
+    let whole = WHOLE_DISK.lock().take();
+    if let Some((whole, _)) = whole {
+        disk::attach(whole);
+    }
+}
+
+/// Returns the number of the partition attached in place of the disk, see [`attach`].
+pub fn attached() -> Option<usize> {
+    WHOLE_DISK.lock().as_ref().map(|&(_, number)| number)
+}
+
+/// Runs `f` on the whole disk, which is the kernel disk unless one of its partitions is attached
+/// in its place.
+pub fn with_whole_disk<R>(f: impl FnOnce(&dyn BlockDevice) -> R) -> R {
+    let whole = WHOLE_DISK.lock();
+    match &*whole {
+        Some((whole, _)) => f(&**whole),
+        None => {
+            // Released first, flushing an attached partition locks it with the kernel disk locked
+            drop(whole);
+            f(&KernelDisk)
+        }
+    }
+}
+
+/// The whole disk kept aside by [`attach`], which the attached partition reads and writes.
+struct WholeDisk;
+
+impl WholeDisk {
+    fn with<R>(
+        block: usize,
+        f: impl FnOnce(&mut (dyn BlockDevice + Send)) -> Result<R, DiskError>,
+    ) -> Result<R, DiskError> {
+        match &mut *WHOLE_DISK.lock() {
+            Some((whole, _)) => f(&mut **whole),
+            None => Err(DiskError::BlockOutOfBounds(block)),
+        }
+    }
+}
+
+impl BlockDevice for WholeDisk {
+    fn read(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        Self::with(block, |whole| whole.read(block, buf))
+    }
+
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), DiskError> {
+        Self::with(block, |whole| whole.write(block, buf))
+    }
+
+    fn write_at(&mut self, block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
+        Self::with(block, |whole| whole.write_at(block, offset, buf))
+    }
+
+    fn size(&self) -> usize {
+        Self::with(0, |whole| Ok(whole.size())).unwrap_or(0)
+    }
+
+    fn block_size(&self) -> usize {
+        Self::with(0, |whole| Ok(whole.block_size())).unwrap_or(BLOCK_SIZE)
+    }
+
+    fn stats(&self) -> DiskStats {
+        Self::with(0, |whole| Ok(whole.stats())).unwrap_or_default()
+    }
+
+    fn flush(&mut self) -> Result<(), DiskError> {
+        Self::with(0, |whole| whole.flush())
+    }
+
+    fn read_blocks(&self, start: usize, bufs: &mut [[u8; BLOCK_SIZE]]) -> Result<(), DiskError> {
+        Self::with(start, |whole| whole.read_blocks(start, bufs))
+    }
+
+    fn write_blocks(&mut self, start: usize, bufs: &[&[u8; BLOCK_SIZE]]) -> Result<(), DiskError> {
+        Self::with(start, |whole| whole.write_blocks(start, bufs))
+    }
+}
+
+/// A partition of a device, presented as a device of its own. Block numbers count from the start
+/// of the partition, and blocks past its end are out of bounds, so a filesystem formatted on it
+/// stays within the partition.
+pub struct PartitionDevice<D> {
+    device: D,
+    /// The first block of the partition on the device.
+    start: usize,
+    /// The number of blocks in the partition.
+    size: usize,
+}
+
+impl<D: BlockDevice> PartitionDevice<D> {
+    /// Wraps the part of `device` taken up by `partition`. Fails if the partition doesn't start
+    /// and end on a block of the device, or extends past its end.
+    pub fn new(device: D, partition: &Partition) -> Result<Self, PartitionError> {
+        let (start, size) = partition.extent(device.block_size())?;
+        if start + size > device.size() {
+            return Err(PartitionError::PastEnd(partition.number));
+        }
+        Ok(Self {
+            device,
+            start,
+            size,
+        })
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Returns the block of the device holding `block` of the partition.
+    fn block(&self, block: usize) -> Result<usize, DiskError> {
+        match block < self.size {
+            true => Ok(self.start + block),
+            false => Err(DiskError::BlockOutOfBounds(block)),
+        }
+    }
+
+    /// Returns the block of the device holding `start` of the partition, if all `len` blocks from
+    /// it are within the partition.
+    fn blocks(&self, start: usize, len: usize) -> Result<usize, DiskError> {
+        match start + len <= self.size {
+            true => Ok(self.start + start),
+            false => Err(DiskError::BlockOutOfBounds(start + len - 1)),
+        }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionDevice<D> {
+    fn read(&self, block: usize, buf: &mut [u8]) -> Result<(), DiskError> {
+        self.device.read(self.block(block)?, buf)
+    }
+
+    fn write(&mut self, block: usize, buf: &[u8]) -> Result<(), DiskError> {
+        let block = self.block(block)?;
+        self.device.write(block, buf)
+    }
+
+    fn write_at(&mut self, block: usize, offset: usize, buf: &[u8]) -> Result<(), DiskError> {
+        let block = self.block(block)?;
+        self.device.write_at(block, offset, buf)
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn stats(&self) -> DiskStats {
+        self.device.stats()
+    }
+
+    fn flush(&mut self) -> Result<(), DiskError> {
+        self.device.flush()
+    }
+
+    fn read_blocks(&self, start: usize, bufs: &mut [[u8; BLOCK_SIZE]]) -> Result<(), DiskError> {
+        self.device
+            .read_blocks(self.blocks(start, bufs.len())?, bufs)
+    }
+
+    fn write_blocks(&mut self, start: usize, bufs: &[&[u8; BLOCK_SIZE]]) -> Result<(), DiskError> {
+        let start = self.blocks(start, bufs.len())?;
+        self.device.write_blocks(start, bufs)
+    }
+}
+
+/// Builds a disk image of `blocks` blocks with a master boot record describing `partitions`, as
+/// pairs of a type byte and an extent in blocks. The partitions are filled with their number.
+#[cfg(test)]
+pub(crate) fn test_image(blocks: usize, partitions: &[(u8, usize, usize)]) -> super::disk::Disk {
+    let mut image = super::disk::Disk::new(blocks);
+    let mut mbr = [0; SECTOR_SIZE];
+    let sectors_per_block = BLOCK_SIZE / SECTOR_SIZE;
+    for (i, &(kind, start, size)) in partitions.iter().enumerate() {
+        let entry = &mut mbr[PARTITION_TABLE + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&((start * sectors_per_block) as u32).to_le_bytes());
+        entry[12..16].copy_from_slice(&((size * sectors_per_block) as u32).to_le_bytes());
+        for block in start..start + size {
+            BlockDevice::write(&mut image, block, &[i as u8 + 1; BLOCK_SIZE]).unwrap();
+        }
+    }
+    mbr[SECTOR_SIZE - 2..].copy_from_slice(&SIGNATURE);
+    BlockDevice::write(&mut image, 0, &mbr).unwrap();
+    image
+}
+
+#[test_case]
+fn test_partition_table() {
+    use super::disk::Disk;
+
+    let image = test_image(96, &[(0x83, 1, 8), (0x0c, 16, 64)]);
+    let partitions = read_table(&image).unwrap();
+    assert_eq!(
+        partitions,
+        [
+            Partition {
+                number: 1,
+                kind: 0x83,
+                start_lba: 8,
+                sectors: 64,
+            },
+            Partition {
+                number: 2,
+                kind: 0x0c,
+                start_lba: 128,
+                sectors: 512,
+            },
+        ]
+    );
+    assert_eq!(partitions[0].type_name(), "Linux");
+    assert_eq!(partitions[1].type_name(), "FAT32");
+    assert_eq!(partitions[1].device_name("ata0"), "ata0p2");
+    assert_eq!(partitions[1].size(), 64 * BLOCK_SIZE as u64);
+    assert_eq!(
+        read_table(&Disk::new(1)),
+        Err(PartitionError::NoPartitionTable)
+    );
+
+    // The first and last block of each partition are the partition's own, the next one is out of
+    // bounds and reaches neither the device nor the other partition
+    let mut image = Some(image);
+    for (partition, last_block) in partitions.iter().zip([8, 79]) {
+        let mut device = PartitionDevice::new(image.take().unwrap(), partition).unwrap();
+        let size = device.size();
+        let mut buf = [0; BLOCK_SIZE];
+        for block in [0, size - 1] {
+            device.read(block, &mut buf).unwrap();
+            assert!(buf.iter().all(|&byte| byte == partition.number as u8));
+        }
+        BlockDevice::write(&mut device, size - 1, &[0xff; 4]).unwrap();
+        assert_eq!(
+            device.read(size, &mut buf),
+            Err(DiskError::BlockOutOfBounds(size))
+        );
+        assert_eq!(
+            BlockDevice::write(&mut device, size, &buf),
+            Err(DiskError::BlockOutOfBounds(size))
+        );
+        assert_eq!(
+            device.write_blocks(size - 1, &[&buf, &buf]),
+            Err(DiskError::BlockOutOfBounds(size))
+        );
+        let raw = device.into_inner();
+        BlockDevice::read(&raw, last_block, &mut buf).unwrap();
+        assert_eq!(buf[..5], [0xff, 0xff, 0xff, 0xff, partition.number as u8]);
+        image = Some(raw);
+    }
+
+    // Partitions which don't fit the device are refused
+    let past_end = Partition {
+        number: 3,
+        kind: 0x83,
+        start_lba: 90 * 8,
+        sectors: 8 * 8,
+    };
+    let image = image.unwrap();
+    assert!(matches!(
+        PartitionDevice::new(image, &past_end),
+        Err(PartitionError::PastEnd(3))
+    ));
+    let misaligned = Partition {
+        start_lba: 9,
+        ..past_end
+    };
+    assert!(matches!(
+        PartitionDevice::new(Disk::new(96), &misaligned),
+        Err(PartitionError::Misaligned(3, BLOCK_SIZE))
+    ));
+}
+
+#[test_case]
+fn test_filesystem_in_partition() {
+    use super::{
+        disk,
+        file::{FileSystem, InodeKind},
+    };
+    use alloc::{boxed::Box, sync::Arc};
+    use spin::Mutex;
+
+    // Every block written to the image, to check that none of them is outside the partition
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let mut image = test_image(96, &[(0x83, 1, 8), (0x83, 16, 64)]);
+    let hook_written = written.clone();
+    image.set_write_hook(Box::new(move |block| {
+        hook_written.lock().push(block);
+        Ok(())
+    }));
+    let partitions = read_table(&image).unwrap();
+    let device = PartitionDevice::new(image, &partitions[1]).unwrap();
+    let previous = disk::attach(Box::new(device));
+
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let file = fs.create_at("file", InodeKind::File).unwrap();
+    fs.write(file, 0, b"inside partition 2").unwrap();
+    disk::flush().unwrap();
+
+    // Mounted again from what reached the image
+    disk::invalidate_cache();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    let file = fs.resolve("/file").unwrap();
+    let mut buf = [0; 18];
+    fs.read(file, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"inside partition 2");
+
+    let written = written.lock();
+    assert!(!written.is_empty());
+    assert!(written.iter().all(|block| (16..80).contains(block)));
+    drop(written);
+    disk::attach(previous);
+    super::init().unwrap();
+}
+
+#[test_case]
+fn test_attach_partition() {
+    use super::file::{FileSystem, InodeKind};
+
+    let previous = disk::attach(Box::new(test_image(96, &[(0x83, 1, 8), (0x83, 16, 64)])));
+    assert_eq!(attach(Some(3)), Err(PartitionError::NotFound(3)));
+    assert_eq!(attached(), None);
+
+    attach(Some(2)).unwrap();
+    assert_eq!((attached(), disk::size()), (Some(2), 64));
+    FileSystem::format().unwrap();
+    let mut fs = FileSystem::new();
+    fs.mount().unwrap();
+    fs.create_at("file", InodeKind::File).unwrap();
+    // Still read from the whole disk, around the attached partition
+    assert_eq!(with_whole_disk(|disk| read_table(disk)).unwrap().len(), 2);
+
+    // Switching partitions flushes the one attached, which then mounts again
+    fs.unmount();
+    attach(Some(1)).unwrap();
+    assert_eq!(disk::size(), 8);
+    attach(None).unwrap();
+    assert_eq!((attached(), disk::size()), (None, 96));
+    let mut block = [0; BLOCK_SIZE];
+    disk::read(1, 0, &mut block).unwrap();
+    assert_eq!(block, [1; BLOCK_SIZE]);
+    attach(Some(2)).unwrap();
+    fs.mount().unwrap();
+    assert!(fs.resolve("file").is_ok());
+    fs.unmount();
+
+    attach(None).unwrap();
+    disk::attach(previous);
+    super::init().unwrap();
+}
@@ -0,0 +1,432 @@
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::file::{FileSystem, FileSystemError, INumber, InodeKind, ROOT_INUMBER};
+use crate::task::{cancel::CancellationToken, job};
+
+/// Splits a path into its components, resolving `.` and `..` lexically. All paths are relative to
+/// the root directory, so a leading `/` is optional.
+pub fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    components
+}
+
+/// Returns whether `name` matches the glob `pattern`, in which `*` matches any run of characters
+/// and `?` any single character. Every other character only matches itself.
+pub fn matches_glob(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // The pattern after the last `*` seen, and how much of the name that `*` has matched up to.
+    // Only the last one needs backtracking, as an earlier one can't help when a later one fails
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                star = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, matched)) => {
+                    p = after;
+                    n = matched + 1;
+                    star = Some((after, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl FileSystem {
+    /// Returns the inode the path points to.
+    pub fn resolve(&self, path: &str) -> Result<INumber, FileSystemError> {
+        let mut inumber = ROOT_INUMBER;
+        for name in components(path) {
+            if self.stat(inumber)?.kind != InodeKind::Directory {
+                return Err(FileSystemError::NotADirectory(path.to_string()));
+            }
+            inumber = self
+                .lookup(inumber, name)?
+                .ok_or_else(|| FileSystemError::NotFound(path.to_string()))?;
+        }
+        Ok(inumber)
+    }
+
+    /// Returns the directory containing the last component of the path, along with the name of
+    /// the last component. The last component itself doesn't have to exist.
+    pub fn resolve_parent<'a>(&self, path: &'a str) -> Result<(INumber, &'a str), FileSystemError> {
+        let mut components = components(path);
+        let name = components
+            .pop()
+            .ok_or_else(|| FileSystemError::AlreadyExists(path.to_string()))?;
+
+        let mut parent = ROOT_INUMBER;
+        for component in components {
+            parent = self
+                .lookup(parent, component)?
+                .ok_or_else(|| FileSystemError::NotFound(path.to_string()))?;
+        }
+        if self.stat(parent)?.kind != InodeKind::Directory {
+            return Err(FileSystemError::NotADirectory(path.to_string()));
+        }
+        Ok((parent, name))
+    }
+
+    /// Returns the paths matching a glob pattern, sorted. Only the last component is matched with
+    /// [`matches_glob`], the directory before it is taken as it is and kept as written in the
+    /// paths returned. A directory which doesn't exist matches nothing.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<String>, FileSystemError> {
+        let (dir, name) = pattern.split_at(pattern.rfind('/').map_or(0, |slash| slash + 1));
+        let inumber = match self.resolve(dir) {
+            Ok(inumber) => inumber,
+            Err(FileSystemError::NotFound(_) | FileSystemError::NotADirectory(_)) => {
+                return Ok(Vec::new())
+            }
+            Err(err) => return Err(err),
+        };
+        if self.stat(inumber)?.kind != InodeKind::Directory {
+            return Ok(Vec::new());
+        }
+        let mut paths = self
+            .list(inumber)?
+            .into_iter()
+            .filter(|entry| matches_glob(name, &entry.name))
+            .map(|entry| format!("{}{}", dir, entry.name))
+            .collect::<Vec<_>>();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Creates a new empty file or directory at the path.
+    pub fn create_at(&mut self, path: &str, kind: InodeKind) -> Result<INumber, FileSystemError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        if self.lookup(parent, name)?.is_some() {
+            return Err(FileSystemError::AlreadyExists(path.to_string()));
+        }
+
+        let inumber = self.create(kind)?;
+        if let Err(err) = self.add_entry(parent, name, inumber) {
+            self.delete(inumber)?;
+            return Err(err);
+        }
+        Ok(inumber)
+    }
+
+    /// Removes the file or empty directory at the path. The file itself is only deleted once no
+    /// other links to it are left.
+    pub fn remove(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        match self.unlink(parent, name) {
+            Ok(_) => Ok(()),
+            Err(FileSystemError::NotFound(_)) => Err(FileSystemError::NotFound(path.to_string())),
+            Err(FileSystemError::DirectoryNotEmpty(_)) => {
+                Err(FileSystemError::DirectoryNotEmpty(path.to_string()))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Creates a hard link at `new` to the file at `existing`, so that both paths point to the
+    /// same file.
+    pub fn link_at(&mut self, existing: &str, new: &str) -> Result<(), FileSystemError> {
+        let inumber = self.resolve(existing)?;
+        let (parent, name) = self.resolve_parent(new)?;
+        if self.lookup(parent, name)?.is_some() {
+            return Err(FileSystemError::AlreadyExists(new.to_string()));
+        }
+        match self.link(parent, name, inumber) {
+            Err(FileSystemError::IsADirectory(_)) => {
+                Err(FileSystemError::IsADirectory(existing.to_string()))
+            }
+            result => result,
+        }
+    }
+
+    /// Moves the entry at `from` to `to`, which must not exist yet. The file keeps its link count.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        let (old_parent, old_name) = self.resolve_parent(from)?;
+        let inumber = self
+            .lookup(old_parent, old_name)?
+            .ok_or_else(|| FileSystemError::NotFound(from.to_string()))?;
+        if self.stat(inumber)?.kind == InodeKind::Directory
+            && components(to).starts_with(&components(from))
+        {
+            return Err(FileSystemError::MoveIntoSelf(from.to_string()));
+        }
+        let (new_parent, new_name) = self.resolve_parent(to)?;
+        if self.lookup(new_parent, new_name)?.is_some() {
+            return Err(FileSystemError::AlreadyExists(to.to_string()));
+        }
+
+        // The new entry is added first, so that a failure can't lose the file
+        self.add_entry(new_parent, new_name, inumber)?;
+        self.remove_entry(old_parent, old_name)?;
+        Ok(())
+    }
+
+    /// Creates the directory at the path along with any missing parent directories. Directories
+    /// that already exist are left as they are.
+    pub fn create_dir_all(&mut self, path: &str) -> Result<INumber, FileSystemError> {
+        let mut inumber = ROOT_INUMBER;
+        let mut prefix = String::new();
+        for name in components(path) {
+            prefix.push('/');
+            prefix.push_str(name);
+            inumber = match self.lookup(inumber, name)? {
+                Some(child) if self.stat(child)?.kind == InodeKind::Directory => child,
+                Some(_) => return Err(FileSystemError::NotADirectory(prefix)),
+                None => self.create_at(&prefix, InodeKind::Directory)?,
+            };
+        }
+        Ok(inumber)
+    }
+
+    /// Removes the file or directory at the path, along with everything below it. Entries are
+    /// removed depth first, if one can't be removed the removal stops there and the error names
+    /// its path. The root directory can't be removed.
+    ///
+    /// Stops with [`FileSystemError::Interrupted`] between entries once `cancel` or the job running
+    /// the removal is cancelled, leaving the entries not yet removed.
+    pub fn remove_recursive(
+        &mut self,
+        path: &str,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), FileSystemError> {
+        let components = components(path);
+        if components.is_empty() {
+            return Err(FileSystemError::RemoveRoot);
+        }
+        let path = components.join("/");
+        let inumber = self.resolve(&path)?;
+        self.remove_tree(&path, inumber, cancel)
+    }
+
+    fn remove_tree(
+        &mut self,
+        path: &str,
+        inumber: INumber,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), FileSystemError> {
+        if job::cancelled(cancel) {
+            return Err(FileSystemError::Interrupted);
+        }
+        let failed = |error| FileSystemError::RemoveFailed {
+            path: path.to_string(),
+            error: Box::new(error),
+        };
+        if self.stat(inumber).map_err(failed)?.kind == InodeKind::Directory {
+            for entry in self.list(inumber).map_err(failed)? {
+                let entry_path = format!("{}/{}", path, entry.name);
+                self.remove_tree(&entry_path, entry.inumber, cancel)?;
+            }
+        }
+        self.remove(path).map_err(failed)
+    }
+}
+
+#[test_case]
+fn test_matches_glob() {
+    for (pattern, name) in [
+        ("*.txt", "notes.txt"),
+        ("*.txt", ".txt"),
+        ("a?c", "abc"),
+        ("a?c", "a.c"),
+        ("*", ""),
+        ("*", "anything"),
+        ("a*b*c", "axxbyybc"),
+        ("a*b", "ab"),
+        ("**a", "a"),
+        ("?", "é"),
+        ("exact", "exact"),
+    ] {
+        assert!(
+            matches_glob(pattern, name),
+            "{} should match {}",
+            pattern,
+            name
+        );
+    }
+    for (pattern, name) in [
+        ("*.txt", "notes.txt.bak"),
+        ("*.txt", "notes.tx"),
+        ("a?c", "ac"),
+        ("a?c", "abbc"),
+        ("a*b", "abba"),
+        ("?", ""),
+        ("exact", "Exact"),
+        ("", "a"),
+    ] {
+        assert!(
+            !matches_glob(pattern, name),
+            "{} shouldn't match {}",
+            pattern,
+            name
+        );
+    }
+}
+
+#[test_case]
+fn test_glob() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    fs.create_dir_all("logs").unwrap();
+    for path in [
+        "b.tmp",
+        "a.tmp",
+        "c.txt",
+        "logs/x.log",
+        "logs/y.log",
+        "logs/z.txt",
+    ] {
+        fs.create_at(path, InodeKind::File).unwrap();
+    }
+    assert_eq!(fs.glob("*.tmp").unwrap(), ["a.tmp", "b.tmp"]);
+    assert_eq!(fs.glob("?.t*").unwrap(), ["a.tmp", "b.tmp", "c.txt"]);
+    assert_eq!(
+        fs.glob("/logs/*.log").unwrap(),
+        ["/logs/x.log", "/logs/y.log"]
+    );
+    assert_eq!(
+        fs.glob("./logs/../logs/z*").unwrap(),
+        ["./logs/../logs/z.txt"]
+    );
+    assert!(fs.glob("*.none").unwrap().is_empty());
+    // Only the last component is a pattern
+    assert!(fs.glob("l*/x.log").unwrap().is_empty());
+    assert!(fs.glob("missing/*").unwrap().is_empty());
+    assert!(fs.glob("c.txt/*").unwrap().is_empty());
+}
+
+#[test_case]
+fn test_remove_recursive() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let blocks_before = fs.used_blocks().len();
+
+    fs.create_dir_all("a/b/c").unwrap();
+    for dir in ["a", "a/b", "a/b/c"] {
+        let file = fs
+            .create_at(&format!("{}/file", dir), InodeKind::File)
+            .unwrap();
+        fs.write(file, 0, &[7; 3000]).unwrap();
+    }
+    assert!(fs.used_blocks().len() > blocks_before);
+    // Creating it again is fine, the existing directories are reused
+    let c = fs.resolve("a/b/c").unwrap();
+    assert_eq!(fs.create_dir_all("/a/./b/c/").unwrap(), c);
+
+    fs.remove_recursive("a", None).unwrap();
+    assert!(matches!(fs.resolve("a"), Err(FileSystemError::NotFound(_))));
+    assert!(fs.list(ROOT_INUMBER).unwrap().is_empty());
+    assert_eq!(fs.used_blocks().len(), blocks_before);
+}
+
+#[test_case]
+fn test_create_dir_all_through_file() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    fs.create_dir_all("a").unwrap();
+    fs.create_at("a/file", InodeKind::File).unwrap();
+    match fs.create_dir_all("a/file/b") {
+        Err(FileSystemError::NotADirectory(path)) => assert_eq!(path, "/a/file"),
+        other => panic!("expected NotADirectory, got {:?}", other),
+    }
+    assert!(fs.resolve("a/file/b").is_err());
+}
+
+#[test_case]
+fn test_remove_recursive_refuses_root() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    fs.create_at("keep", InodeKind::File).unwrap();
+    for path in ["/", "", "a/.."] {
+        assert!(matches!(
+            fs.remove_recursive(path, None),
+            Err(FileSystemError::RemoveRoot)
+        ));
+    }
+    assert!(fs.resolve("keep").is_ok());
+}
+
+#[test_case]
+fn test_hard_links() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let blocks_before = fs.used_blocks().len();
+
+    let file = fs.create_at("a", InodeKind::File).unwrap();
+    fs.create_dir_all("dir").unwrap();
+    fs.link_at("a", "dir/b").unwrap();
+    assert_eq!(fs.resolve("dir/b").unwrap(), file);
+    assert_eq!(fs.stat(file).unwrap().links, 2);
+    assert!(fs.check(None).unwrap().is_empty());
+
+    // Writes through one link are read through the other
+    let a = fs.resolve("a").unwrap();
+    fs.write(a, 0, &[7; 3000]).unwrap();
+    let mut buf = [0; 3000];
+    fs.read(fs.resolve("dir/b").unwrap(), 0, &mut buf).unwrap();
+    assert_eq!(buf, [7; 3000]);
+
+    // Renaming keeps the count, removing one link keeps the data
+    fs.rename("dir/b", "c").unwrap();
+    assert_eq!(fs.stat(file).unwrap().links, 2);
+    fs.remove("a").unwrap();
+    assert_eq!(fs.stat(file).unwrap().links, 1);
+    let mut buf = [0; 3000];
+    fs.read(fs.resolve("c").unwrap(), 0, &mut buf).unwrap();
+    assert_eq!(buf, [7; 3000]);
+    assert!(fs.check(None).unwrap().is_empty());
+
+    // Directories can't be linked or moved into themselves
+    assert!(matches!(
+        fs.link_at("dir", "dir2"),
+        Err(FileSystemError::IsADirectory(_))
+    ));
+    assert!(matches!(
+        fs.rename("dir", "dir/inner"),
+        Err(FileSystemError::MoveIntoSelf(_))
+    ));
+
+    fs.remove("c").unwrap();
+    fs.remove("dir").unwrap();
+    assert!(matches!(
+        fs.stat(file),
+        Err(FileSystemError::InvalidInode(_))
+    ));
+    assert_eq!(fs.used_blocks().len(), blocks_before);
+}
+
+#[test_case]
+fn test_check_finds_link_count_mismatch() {
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let file = fs.create_at("a", InodeKind::File).unwrap();
+    // An entry added behind the back of the link count
+    fs.add_entry(ROOT_INUMBER, "b", file).unwrap();
+    let problems = fs.check(None).unwrap();
+    assert_eq!(problems.len(), 1);
+    assert!(matches!(
+        problems[0],
+        FileSystemError::LinkCountMismatch { inumber, links: 1, entries: 2 } if inumber == file
+    ));
+}
@@ -0,0 +1,432 @@
+use core::fmt::{self, Write};
+
+use alloc::{format, string::ToString, vec::Vec};
+
+use super::{
+    dir::DirEntry,
+    disk,
+    file::{FileSystem, FileSystemError, INumber, InodeKind, Metadata},
+};
+use crate::{
+    allocator, boot,
+    interrupts::{self, InterruptIndex},
+    log::{self, LogLevel},
+    swap,
+    task::{executor, Priority},
+    timer,
+};
+
+/// The inumber of the `/proc` directory. The nodes in it get the inumbers after it, which are far
+/// past the inodes of any disk.
+pub const PROC_DIR: INumber = 0xffff_ff00;
+
+/// The name of `/proc` in the root directory. It hides a real entry with the same name.
+pub const PROC_NAME: &str = "proc";
+
+/// A file in `/proc` whose contents are generated from kernel state every time it's read.
+pub trait ProcNode: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Writes the contents of the node to `out`. Generating may stop early if `out` returns an
+    /// error, which it does once the reader's buffer is full.
+    fn generate(&self, fs: &FileSystem, out: &mut dyn Write) -> fmt::Result;
+
+    /// Reads the contents from `offset` into `buf`, generating only as much as needed. The
+    /// contents are generated again for every read, so reads at increasing offsets page through
+    /// them without anything being kept in between.
+    fn read(&self, fs: &FileSystem, offset: usize, buf: &mut [u8]) -> usize {
+        let mut window = Window {
+            buf,
+            skip: offset,
+            len: 0,
+        };
+        // An error only means the buffer is full
+        let _ = self.generate(fs, &mut window);
+        window.len
+    }
+
+    /// Changes the kernel state behind the node. Most nodes can only be read.
+    fn write(&self, _data: &[u8]) -> Result<usize, FileSystemError> {
+        Err(FileSystemError::NotSupported(path(self.name())))
+    }
+}
+
+/// The nodes in `/proc`, in the order they're listed.
+static NODES: [&dyn ProcNode; 7] = [
+    &Uptime,
+    &Boot,
+    &MemInfo,
+    &Tasks,
+    &Interrupts,
+    &FsStats,
+    &LogLevelNode,
+];
+
+/// Formats text into a window of it, dropping the first `skip` bytes and anything past the end of
+/// `buf`.
+struct Window<'a> {
+    buf: &'a mut [u8],
+    skip: usize,
+    len: usize,
+}
+
+impl Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let skipped = self.skip.min(s.len());
+        self.skip -= skipped;
+        let bytes = &s.as_bytes()[skipped..];
+        let copied = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + copied].copy_from_slice(&bytes[..copied]);
+        self.len += copied;
+        match copied < bytes.len() {
+            true => Err(fmt::Error),
+            false => Ok(()),
+        }
+    }
+}
+
+fn path(name: &str) -> alloc::string::String {
+    format!("/{}/{}", PROC_NAME, name)
+}
+
+/// Returns `true` for `/proc` and the nodes in it.
+pub fn is_proc(inumber: INumber) -> bool {
+    inumber >= PROC_DIR
+}
+
+fn node(inumber: INumber) -> Result<&'static dyn ProcNode, FileSystemError> {
+    let index = inumber.checked_sub(PROC_DIR + 1);
+    index
+        .and_then(|index| NODES.get(index as usize).copied())
+        .ok_or(FileSystemError::InvalidInode(inumber))
+}
+
+/// Looks up the node called `name` in `/proc`.
+pub fn lookup(name: &str) -> Option<INumber> {
+    NODES
+        .iter()
+        .position(|node| node.name() == name)
+        .map(|index| PROC_DIR + 1 + index as INumber)
+}
+
+/// Lists the nodes in `/proc`.
+pub fn list() -> Vec<DirEntry> {
+    NODES
+        .iter()
+        .enumerate()
+        .map(|(index, node)| DirEntry {
+            name: node.name().to_string(),
+            inumber: PROC_DIR + 1 + index as INumber,
+            kind: InodeKind::File,
+        })
+        .collect()
+}
+
+/// Describes `/proc` or one of its nodes. Nodes have a size of zero, as their contents are only
+/// known once they're read.
+pub fn stat(inumber: INumber) -> Result<Metadata, FileSystemError> {
+    let kind = match inumber {
+        PROC_DIR => InodeKind::Directory,
+        _ => node(inumber).map(|_| InodeKind::File)?,
+    };
+    let now = timer::ticks();
+    Ok(Metadata {
+        kind,
+        size: 0,
+        physical_size: 0,
+        compressed: false,
+        blocks: 0,
+        created: now,
+        modified: now,
+        links: 1,
+    })
+}
+
+pub fn read(
+    fs: &FileSystem,
+    inumber: INumber,
+    offset: usize,
+    buf: &mut [u8],
+) -> Result<usize, FileSystemError> {
+    if inumber == PROC_DIR {
+        return Err(FileSystemError::IsADirectory(path("")));
+    }
+    Ok(node(inumber)?.read(fs, offset, buf))
+}
+
+pub fn write(inumber: INumber, data: &[u8]) -> Result<usize, FileSystemError> {
+    if inumber == PROC_DIR {
+        return Err(FileSystemError::IsADirectory(path("")));
+    }
+    node(inumber)?.write(data)
+}
+
+/// The error for changes which proc nodes don't support, like truncating or linking.
+pub fn not_supported(inumber: INumber) -> FileSystemError {
+    let name = node(inumber).map_or("", |node| node.name());
+    FileSystemError::NotSupported(path(name))
+}
+
+/// `/proc/uptime`: the seconds since boot with two decimals, then the timer ticks since boot.
+struct Uptime;
+
+impl ProcNode for Uptime {
+    fn name(&self) -> &'static str {
+        "uptime"
+    }
+
+    fn generate(&self, _fs: &FileSystem, out: &mut dyn Write) -> fmt::Result {
+        let millis = timer::millis();
+        writeln!(
+            out,
+            "{}.{:02} {}",
+            millis / 1000,
+            millis % 1000 / 10,
+            timer::ticks()
+        )
+    }
+}
+
+/// `/proc/boot`: how long each phase of the boot took, see [`boot::Table`].
+struct Boot;
+
+impl ProcNode for Boot {
+    fn name(&self) -> &'static str {
+        "boot"
+    }
+
+    fn generate(&self, _fs: &FileSystem, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", boot::Table)
+    }
+}
+
+/// `/proc/meminfo`: heap usage in bytes, what was reclaimed when the heap ran out, and swap
+/// usage in pages if a swap device is attached.
+struct MemInfo;
+
+impl ProcNode for MemInfo {
+    fn name(&self) -> &'static str {
+        "meminfo"
+    }
+
+    fn generate(&self, _fs: &FileSystem, out: &mut dyn Write) -> fmt::Result {
+        let stats = allocator::stats();
+        writeln!(out, "heap_total: {}", stats.total)?;
+        writeln!(out, "heap_used: {}", stats.used)?;
+        writeln!(out, "heap_peak: {}", stats.peak)?;
+        writeln!(out, "allocations: {}", stats.allocations)?;
+        let oom = allocator::oom::stats();
+        writeln!(out, "reclaims: {}", oom.reclaims)?;
+        writeln!(out, "reclaimed: {}", oom.reclaimed)?;
+        writeln!(out, "reclaim_recovered: {}", oom.recovered)?;
+        writeln!(out, "emergency_pool_used: {}", oom.emergency_used)?;
+        if let Some((used, total)) = swap::usage() {
+            writeln!(out, "swap_total: {}", total)?;
+            writeln!(out, "swap_used: {}", used)?;
+        }
+        Ok(())
+    }
+}
+
+/// `/proc/tasks`: a line per task of the executors with its id, priority and number of polls.
+struct Tasks;
+
+impl ProcNode for Tasks {
+    fn name(&self) -> &'static str {
+        "tasks"
+    }
+
+    fn generate(&self, _fs: &FileSystem, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "{:>6} {:<8} {:>10}", "id", "priority", "polls")?;
+        let mut result = Ok(());
+        executor::for_each_task(|task| {
+            if result.is_ok() {
+                let priority = match task.priority {
+                    Priority::High => "high",
+                    Priority::Normal => "normal",
+                    Priority::Low => "low",
+                };
+                result = writeln!(out, "{:>6} {:<8} {:>10}", task.id.0, priority, task.polls);
+            }
+        });
+        result
+    }
+}
+
+/// `/proc/interrupts`: a line per handled interrupt with its IRQ line, name and count.
+struct Interrupts;
+
+impl ProcNode for Interrupts {
+    fn name(&self) -> &'static str {
+        "interrupts"
+    }
+
+    fn generate(&self, _fs: &FileSystem, out: &mut dyn Write) -> fmt::Result {
+        for (index, name) in InterruptIndex::HANDLED {
+            let count = interrupts::irq_count(index);
+            writeln!(out, "{:>3}: {:>12} {}", index.irq(), count, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// `/proc/fs`: the size and usage of the mounted filesystem, and how the block cache has written
+/// to the disk.
+struct FsStats;
+
+impl ProcNode for FsStats {
+    fn name(&self) -> &'static str {
+        "fs"
+    }
+
+    fn generate(&self, fs: &FileSystem, out: &mut dyn Write) -> fmt::Result {
+        let blocks = fs.blocks();
+        let free = fs.free_blocks();
+        writeln!(out, "blocks: {}", blocks)?;
+        writeln!(out, "used_blocks: {}", blocks - free)?;
+        writeln!(out, "free_blocks: {}", free)?;
+        writeln!(out, "reserved_blocks: {}", fs.reserved_blocks())?;
+        writeln!(out, "bad_blocks: {}", fs.bad_blocks().len())?;
+        writeln!(out, "inodes: {}", fs.inodes())?;
+        writeln!(out, "read_only: {}", fs.is_read_only())?;
+        let lookups = fs.dir_index_stats();
+        writeln!(out, "dir_lookups_indexed: {}", lookups.indexed)?;
+        writeln!(out, "dir_lookups_scanned: {}", lookups.scans)?;
+        let inodes = fs.inode_cache_stats();
+        writeln!(out, "inode_cache_hits: {}", inodes.hits)?;
+        writeln!(out, "inode_cache_misses: {}", inodes.misses)?;
+        let cache = disk::cache_stats();
+        writeln!(out, "blocks_flushed: {}", cache.flushed)?;
+        writeln!(out, "flush_runs: {}", cache.flush_runs)?;
+        writeln!(out, "largest_flush_run: {}", cache.largest_flush_run)?;
+        writeln!(out, "cache_copies: {}", cache.copies)?;
+        writeln!(out, "cache_borrows: {}", cache.borrows)
+    }
+}
+
+/// `/proc/loglevel`: the most verbose log level which is printed, as its number. Writing a number
+/// from 0 to 4 changes it.
+struct LogLevelNode;
+
+impl ProcNode for LogLevelNode {
+    fn name(&self) -> &'static str {
+        "loglevel"
+    }
+
+    fn generate(&self, _fs: &FileSystem, out: &mut dyn Write) -> fmt::Result {
+        let level = log::level();
+        writeln!(out, "{} {}", level as u8, level.name())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FileSystemError> {
+        let level = core::str::from_utf8(data)
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+            .and_then(LogLevel::from_u8)
+            .ok_or_else(|| FileSystemError::InvalidArgument(path(self.name())))?;
+        log::set_level(level);
+        Ok(data.len())
+    }
+}
+
+#[test_case]
+fn test_proc_nodes() {
+    use alloc::string::String;
+
+    super::init().unwrap();
+    let mut fs = super::FILESYSTEM.lock();
+    let read_all = |fs: &FileSystem, path: &str| {
+        let mut file = fs.open(path).unwrap();
+        let mut text = Vec::new();
+        let mut buf = [0; 16];
+        loop {
+            let read = file.read(fs, &mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            text.extend_from_slice(&buf[..read]);
+        }
+        String::from_utf8(text).unwrap()
+    };
+    let field = |text: &str, key: &str| -> usize {
+        text.lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(": "))
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+
+    let uptime = read_all(&fs, "/proc/uptime");
+    let (seconds, ticks) = uptime.trim().split_once(' ').unwrap();
+    let ticks: u64 = ticks.parse().unwrap();
+    assert!(ticks > 0 && ticks <= timer::ticks());
+    let (whole, hundredths) = seconds.split_once('.').unwrap();
+    assert_eq!(hundredths.len(), 2);
+    assert!(whole.parse::<u64>().unwrap() <= timer::millis() / 1000);
+
+    let boot = read_all(&fs, "/proc/boot");
+    assert!(boot.starts_with("phase "));
+    assert!(boot.lines().any(|line| line.starts_with("heap ")));
+
+    let meminfo = read_all(&fs, "/proc/meminfo");
+    let (used, total) = (field(&meminfo, "heap_used"), field(&meminfo, "heap_total"));
+    assert!(0 < used && used <= field(&meminfo, "heap_peak") && used < total);
+
+    let interrupts = read_all(&fs, "/proc/interrupts");
+    let timer_line = interrupts.lines().find(|line| line.ends_with(" timer"));
+    let count: u64 = timer_line
+        .unwrap()
+        .split_whitespace()
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(count > 0);
+
+    let stats = read_all(&fs, "proc/fs");
+    assert_eq!(field(&stats, "blocks"), fs.blocks());
+    assert_eq!(
+        field(&stats, "used_blocks") + field(&stats, "free_blocks"),
+        fs.blocks()
+    );
+    // Formatting flushed the superblock, the bad block list and the inode blocks as one run
+    assert!(field(&stats, "largest_flush_run") > 1);
+    assert!(field(&stats, "flush_runs") <= field(&stats, "blocks_flushed"));
+
+    // The task table lists the tasks of a running executor
+    let mut executor = executor::Executor::new();
+    executor.spawn(crate::task::Task::new(core::future::pending()));
+    executor.run_ready_tasks();
+    let tasks = read_all(&fs, "/proc/tasks");
+    assert!(tasks.lines().skip(1).any(|line| line.ends_with(" 1")));
+    drop(executor);
+
+    // Reading at an offset returns the rest of the contents
+    let inumber = fs.resolve("/proc/meminfo").unwrap();
+    let mut buf = [0; 8];
+    let read = fs.read(inumber, 5, &mut buf).unwrap();
+    assert_eq!(&buf[..read], &meminfo.as_bytes()[5..13]);
+    assert_eq!(fs.read(inumber, meminfo.len(), &mut buf).unwrap(), 0);
+
+    let names = fs.list(fs.resolve("/proc").unwrap()).unwrap();
+    assert_eq!(names.len(), NODES.len());
+    assert!(matches!(
+        fs.write(inumber, 0, b"x"),
+        Err(FileSystemError::NotSupported(_))
+    ));
+    assert!(matches!(
+        fs.create_at("/proc/new", InodeKind::File),
+        Err(FileSystemError::NotSupported(_))
+    ));
+
+    let previous = log::level();
+    let loglevel = fs.resolve("/proc/loglevel").unwrap();
+    fs.write(loglevel, 0, b"3\n").unwrap();
+    assert_eq!(log::level(), LogLevel::Debug);
+    assert_eq!(read_all(&fs, "/proc/loglevel"), "3 DEBUG\n");
+    assert!(matches!(
+        fs.write(loglevel, 0, b"9"),
+        Err(FileSystemError::InvalidArgument(_))
+    ));
+    log::set_level(previous);
+}
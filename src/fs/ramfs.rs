@@ -0,0 +1,328 @@
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use super::{
+    dir::{DirEntry, MAX_NAME_LEN},
+    disk::BLOCK_SIZE,
+    file::{FileSystemError, INumber, InodeKind, Metadata, MAX_FILE_SIZE, ROOT_INUMBER},
+    handle::File,
+    path::components,
+    vfs::Vfs,
+};
+use crate::task::{cancel::CancellationToken, job};
+
+/// A filesystem kept in the heap, for scratch files which don't need to outlive it. Everything on
+/// it is lost once it's unmounted. Files have a single link, and inumbers are never reused, so a
+/// handle to a removed file fails with [`FileSystemError::InvalidInode`].
+pub struct RamFs {
+    nodes: BTreeMap<INumber, Node>,
+    next_inumber: INumber,
+}
+
+struct Node {
+    kind: InodeKind,
+    data: Vec<u8>,
+    /// The entries of a directory by name, empty for files.
+    entries: BTreeMap<String, INumber>,
+    created: u64,
+    modified: u64,
+}
+
+impl Node {
+    fn new(kind: InodeKind) -> Self {
+        let now = crate::timer::ticks();
+        Self {
+            kind,
+            data: Vec::new(),
+            entries: BTreeMap::new(),
+            created: now,
+            modified: now,
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RamFs {
+    /// Creates a filesystem holding an empty root directory.
+    pub fn new() -> Self {
+        Self {
+            nodes: BTreeMap::from([(ROOT_INUMBER, Node::new(InodeKind::Directory))]),
+            next_inumber: ROOT_INUMBER + 1,
+        }
+    }
+
+    fn node(&self, inumber: INumber) -> Result<&Node, FileSystemError> {
+        self.nodes
+            .get(&inumber)
+            .ok_or(FileSystemError::InvalidInode(inumber))
+    }
+
+    fn node_mut(&mut self, inumber: INumber) -> Result<&mut Node, FileSystemError> {
+        self.nodes
+            .get_mut(&inumber)
+            .ok_or(FileSystemError::InvalidInode(inumber))
+    }
+
+    /// Returns the directory containing the last component of the path, along with the name of
+    /// the last component, like [`FileSystem::resolve_parent`](super::file::FileSystem).
+    fn resolve_parent<'a>(&self, path: &'a str) -> Result<(INumber, &'a str), FileSystemError> {
+        let mut components = components(path);
+        let name = components.pop().ok_or(FileSystemError::RemoveRoot)?;
+        let parent = self.resolve(&components.join("/"))?;
+        if self.node(parent)?.kind != InodeKind::Directory {
+            return Err(FileSystemError::NotADirectory(path.to_string()));
+        }
+        Ok((parent, name))
+    }
+
+    fn entry(&self, path: &str) -> Result<(INumber, String, INumber), FileSystemError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let inumber = self.node(parent)?.entries.get(name).copied();
+        let inumber = inumber.ok_or_else(|| FileSystemError::NotFound(path.to_string()))?;
+        Ok((parent, name.to_string(), inumber))
+    }
+}
+
+impl Vfs for RamFs {
+    fn kind(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn resolve(&self, path: &str) -> Result<INumber, FileSystemError> {
+        let mut inumber = ROOT_INUMBER;
+        for name in components(path) {
+            let node = self.node(inumber)?;
+            if node.kind != InodeKind::Directory {
+                return Err(FileSystemError::NotADirectory(path.to_string()));
+            }
+            inumber = *node
+                .entries
+                .get(name)
+                .ok_or_else(|| FileSystemError::NotFound(path.to_string()))?;
+        }
+        Ok(inumber)
+    }
+
+    fn stat(&self, inumber: INumber) -> Result<Metadata, FileSystemError> {
+        let node = self.node(inumber)?;
+        Ok(Metadata {
+            kind: node.kind,
+            size: node.data.len(),
+            physical_size: node.data.len(),
+            compressed: false,
+            blocks: node.data.len().div_ceil(BLOCK_SIZE),
+            created: node.created,
+            modified: node.modified,
+            links: 1,
+        })
+    }
+
+    fn list(&self, dir: INumber) -> Result<Vec<DirEntry>, FileSystemError> {
+        self.node(dir)?
+            .entries
+            .iter()
+            .map(|(name, &inumber)| {
+                Ok(DirEntry {
+                    name: name.clone(),
+                    inumber,
+                    kind: self.node(inumber)?.kind,
+                })
+            })
+            .collect()
+    }
+
+    fn create_at(&mut self, path: &str, kind: InodeKind) -> Result<INumber, FileSystemError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        if name.len() > MAX_NAME_LEN {
+            return Err(FileSystemError::NameTooLong(name.to_string()));
+        }
+        if self.node(parent)?.entries.contains_key(name) {
+            return Err(FileSystemError::AlreadyExists(path.to_string()));
+        }
+        let inumber = self.next_inumber;
+        self.next_inumber += 1;
+        self.nodes.insert(inumber, Node::new(kind));
+        let dir = self.node_mut(parent)?;
+        dir.entries.insert(name.to_string(), inumber);
+        dir.modified = crate::timer::ticks();
+        Ok(inumber)
+    }
+
+    fn create_dir_all(&mut self, path: &str) -> Result<INumber, FileSystemError> {
+        let mut prefix = String::new();
+        for name in components(path) {
+            prefix.push('/');
+            prefix.push_str(name);
+            match self.resolve(&prefix) {
+                Ok(inumber) if self.node(inumber)?.kind == InodeKind::Directory => {}
+                Ok(_) => return Err(FileSystemError::NotADirectory(prefix)),
+                Err(FileSystemError::NotFound(_)) => {
+                    self.create_at(&prefix, InodeKind::Directory)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.resolve(path)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (parent, name, inumber) = self.entry(path)?;
+        if !self.node(inumber)?.entries.is_empty() {
+            return Err(FileSystemError::DirectoryNotEmpty(path.to_string()));
+        }
+        self.nodes.remove(&inumber);
+        let dir = self.node_mut(parent)?;
+        dir.entries.remove(&name);
+        dir.modified = crate::timer::ticks();
+        Ok(())
+    }
+
+    fn remove_recursive(
+        &mut self,
+        path: &str,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), FileSystemError> {
+        // Nodes are only dropped from the heap, so it's quick enough to only check before starting
+        if job::cancelled(cancel) {
+            return Err(FileSystemError::Interrupted);
+        }
+        let (parent, name, inumber) = self.entry(path)?;
+        self.node_mut(parent)?.entries.remove(&name);
+        // An explicit stack rather than recursion, as the kernel stack is small
+        let mut stack = vec![inumber];
+        while let Some(inumber) = stack.pop() {
+            if let Some(node) = self.nodes.remove(&inumber) {
+                stack.extend(node.entries.into_values());
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        let (old_parent, old_name, inumber) = self.entry(from)?;
+        if self.node(inumber)?.kind == InodeKind::Directory
+            && components(to).starts_with(&components(from))
+        {
+            return Err(FileSystemError::MoveIntoSelf(from.to_string()));
+        }
+        let (new_parent, new_name) = self.resolve_parent(to)?;
+        if new_name.len() > MAX_NAME_LEN {
+            return Err(FileSystemError::NameTooLong(new_name.to_string()));
+        }
+        if self.node(new_parent)?.entries.contains_key(new_name) {
+            return Err(FileSystemError::AlreadyExists(to.to_string()));
+        }
+        let new_name = new_name.to_string();
+        self.node_mut(old_parent)?.entries.remove(&old_name);
+        self.node_mut(new_parent)?.entries.insert(new_name, inumber);
+        Ok(())
+    }
+
+    fn touch(&mut self, inumber: INumber) -> Result<(), FileSystemError> {
+        self.node_mut(inumber)?.modified = crate::timer::ticks();
+        Ok(())
+    }
+
+    fn truncate(&mut self, inumber: INumber, size: usize) -> Result<(), FileSystemError> {
+        if size > MAX_FILE_SIZE {
+            return Err(FileSystemError::FileTooLarge(size));
+        }
+        let node = self.node_mut(inumber)?;
+        node.data.resize(size, 0);
+        node.modified = crate::timer::ticks();
+        Ok(())
+    }
+
+    fn open(&self, inumber: INumber) -> Result<File, FileSystemError> {
+        self.node(inumber)?;
+        Ok(File::new(inumber, 0))
+    }
+
+    fn read(&self, file: &mut File, buf: &mut [u8]) -> Result<usize, FileSystemError> {
+        let data = &self.node(file.inumber())?.data;
+        let offset = file.offset();
+        if offset > data.len() {
+            return Err(FileSystemError::OffsetPastEnd(offset));
+        }
+        let read = buf.len().min(data.len() - offset);
+        buf[..read].copy_from_slice(&data[offset..offset + read]);
+        file.seek(offset + read);
+        Ok(read)
+    }
+
+    fn write(&mut self, file: &mut File, data: &[u8]) -> Result<usize, FileSystemError> {
+        let append = file.is_append();
+        let node = self.node_mut(file.inumber())?;
+        if node.kind == InodeKind::Directory {
+            return Err(FileSystemError::IsADirectory(format!(
+                "inode {}",
+                file.inumber()
+            )));
+        }
+        let offset = if append {
+            node.data.len()
+        } else {
+            file.offset()
+        };
+        let end = offset + data.len();
+        if end > MAX_FILE_SIZE {
+            return Err(FileSystemError::FileTooLarge(end));
+        }
+        if end > node.data.len() {
+            node.data.resize(end, 0);
+        }
+        node.data[offset..end].copy_from_slice(data);
+        node.modified = crate::timer::ticks();
+        file.seek(end);
+        Ok(data.len())
+    }
+}
+
+#[test_case]
+fn test_ram_fs() {
+    let mut fs = RamFs::new();
+    fs.create_dir_all("a/b").unwrap();
+    let inumber = fs.create_at("a/b/notes", InodeKind::File).unwrap();
+    assert!(matches!(
+        fs.create_at("a/b/notes", InodeKind::File),
+        Err(FileSystemError::AlreadyExists(_))
+    ));
+
+    let mut file = fs.open(inumber).unwrap();
+    assert_eq!(fs.write(&mut file, b"hello world").unwrap(), 11);
+    file.seek(6);
+    let mut buf = [0; 16];
+    assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"world");
+    assert_eq!(fs.stat(inumber).unwrap().size, 11);
+
+    fs.rename("a/b/notes", "a/notes").unwrap();
+    assert_eq!(fs.resolve("a/notes").unwrap(), inumber);
+    assert!(matches!(
+        fs.rename("a", "a/b/c"),
+        Err(FileSystemError::MoveIntoSelf(_))
+    ));
+    assert!(matches!(
+        fs.remove("a"),
+        Err(FileSystemError::DirectoryNotEmpty(_))
+    ));
+
+    // Removing a tree frees every node in it, and handles to them stop working
+    fs.remove_recursive("a", None).unwrap();
+    assert!(fs.list(ROOT_INUMBER).unwrap().is_empty());
+    assert_eq!(fs.nodes.len(), 1);
+    assert!(matches!(
+        fs.read(&mut file, &mut buf),
+        Err(FileSystemError::InvalidInode(_))
+    ));
+}
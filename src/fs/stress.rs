@@ -0,0 +1,274 @@
+use alloc::{collections::BTreeMap, format, string::String, vec, vec::Vec};
+
+use thiserror_no_std::Error;
+
+use super::{
+    disk::BLOCK_SIZE,
+    file::{FileSystemError, InodeKind},
+    vfs::VfsRouter,
+};
+use crate::{
+    error::{self, ErrorKind},
+    rand::Rng,
+};
+
+/// Names the files of a stress run are picked from.
+const FILES: usize = 12;
+/// The largest a file grows, so that writes cross block boundaries without filling the disk.
+const MAX_FILE_SIZE: usize = 2 * BLOCK_SIZE + 512;
+
+/// A difference between the filesystem and what a stress run wrote to it.
+#[derive(Error, Debug)]
+pub enum StressError {
+    #[error("{0}")]
+    FileSystem(#[from] FileSystemError),
+    #[error("after {op} operations: {path} has {found} bytes, expected {expected}")]
+    SizeMismatch {
+        op: usize,
+        path: String,
+        found: usize,
+        expected: usize,
+    },
+    #[error("after {op} operations: {path} differs at byte {offset}")]
+    ContentMismatch {
+        op: usize,
+        path: String,
+        offset: usize,
+    },
+    #[error("after {op} operations: {path} is missing")]
+    Missing { op: usize, path: String },
+    #[error("after {op} operations: {path} exists but was deleted")]
+    Unexpected { op: usize, path: String },
+}
+
+impl error::Error for StressError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::FileSystem(err) => err.kind(),
+            _ => ErrorKind::Corrupt,
+        }
+    }
+
+    fn source(&self) -> Option<&dyn error::Error> {
+        match self {
+            Self::FileSystem(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The operations a stress run did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StressReport {
+    pub creates: usize,
+    pub writes: usize,
+    pub reads: usize,
+    pub deletes: usize,
+    /// Bytes written.
+    pub written: usize,
+    /// Files left once the operations were done, all of which were checked.
+    pub files: usize,
+}
+
+/// Runs `ops` random operations on files in the new directory `dir`: creating, writing, reading
+/// and deleting them. What the files should hold is kept in memory, every read is compared with
+/// it, and so is every file at the end. The directory is removed afterwards, also when a
+/// difference is found, since the seed repeats the run.
+///
+/// The same seed of `rng` gives the same operations every time, so that a failure can be repeated.
+pub fn stress(
+    vfs: &mut VfsRouter,
+    dir: &str,
+    ops: usize,
+    rng: &mut Rng,
+) -> Result<StressReport, StressError> {
+    vfs.create(dir, InodeKind::Directory)?;
+    let result = run(vfs, dir, ops, rng);
+    let removed = vfs.remove_recursive(dir, None);
+    // The difference found matters more than failing to clean up after it
+    let report = result?;
+    removed?;
+    Ok(report)
+}
+
+fn run(
+    vfs: &mut VfsRouter,
+    dir: &str,
+    ops: usize,
+    rng: &mut Rng,
+) -> Result<StressReport, StressError> {
+    let mut model = BTreeMap::<String, Vec<u8>>::new();
+    let mut report = StressReport::default();
+
+    for op in 0..ops {
+        let path = format!("{}/f{}", dir, rng.gen_range(0..FILES));
+        match (model.get_mut(&path), rng.gen_range(0..4u8)) {
+            (None, _) => {
+                vfs.create(&path, InodeKind::File)?;
+                model.insert(path, Vec::new());
+                report.creates += 1;
+            }
+            (Some(_), 0) => {
+                vfs.remove(&path)?;
+                model.remove(&path);
+                report.deletes += 1;
+            }
+            (Some(data), 1) => {
+                let offset = rng.gen_range(0..data.len() + 1);
+                let mut buf = vec![0; rng.gen_range(0..BLOCK_SIZE + 1)];
+                let mut file = vfs.open(&path)?;
+                file.seek(offset);
+                let read = vfs.read(&mut file, &mut buf)?;
+                let expected = &data[offset..(offset + buf.len()).min(data.len())];
+                if let Some(i) = (0..read.min(expected.len())).find(|&i| buf[i] != expected[i]) {
+                    return Err(StressError::ContentMismatch {
+                        op,
+                        path,
+                        offset: offset + i,
+                    });
+                }
+                if read != expected.len() {
+                    return Err(StressError::SizeMismatch {
+                        op,
+                        path,
+                        found: offset + read,
+                        expected: data.len(),
+                    });
+                }
+                report.reads += 1;
+            }
+            (Some(data), _) => {
+                let (offset, len) = write_span(rng, data.len());
+                let mut bytes = vec![0; len];
+                rng.fill_bytes(&mut bytes);
+                let mut file = vfs.open(&path)?;
+                file.seek(offset);
+                vfs.write(&mut file, &bytes)?;
+                let end = offset + bytes.len();
+                if end > data.len() {
+                    data.resize(end, 0);
+                }
+                data[offset..end].copy_from_slice(&bytes);
+                report.written += bytes.len();
+                report.writes += 1;
+            }
+        }
+    }
+
+    check(vfs, dir, &model, ops)?;
+    report.files = model.len();
+    Ok(report)
+}
+
+/// Picks where to write to a file of `size` bytes and how much, writing at least a byte without
+/// growing it past [`MAX_FILE_SIZE`]. A full file is written before its last byte.
+fn write_span(rng: &mut Rng, size: usize) -> (usize, usize) {
+    let offset = rng.gen_range(0..size.min(MAX_FILE_SIZE - 1) + 1);
+    (offset, rng.gen_range(1..MAX_FILE_SIZE - offset + 1))
+}
+
+/// Compares every file in `dir` with what it should hold.
+fn check(
+    vfs: &VfsRouter,
+    dir: &str,
+    model: &BTreeMap<String, Vec<u8>>,
+    op: usize,
+) -> Result<(), StressError> {
+    let entries = vfs.list(dir)?;
+    for entry in &entries {
+        let path = format!("{}/{}", dir, entry.name);
+        if !model.contains_key(&path) {
+            return Err(StressError::Unexpected { op, path });
+        }
+    }
+    for (path, data) in model {
+        let name = &path[dir.len() + 1..];
+        if !entries.iter().any(|entry| entry.name == name) {
+            return Err(StressError::Missing {
+                op,
+                path: path.clone(),
+            });
+        }
+        let size = vfs.stat(path)?.size;
+        if size != data.len() {
+            return Err(StressError::SizeMismatch {
+                op,
+                path: path.clone(),
+                found: size,
+                expected: data.len(),
+            });
+        }
+        let mut buf = vec![0; size];
+        vfs.read(&mut vfs.open(path)?, &mut buf)?;
+        if let Some(offset) = buf.iter().zip(data).position(|(a, b)| a != b) {
+            return Err(StressError::ContentMismatch {
+                op,
+                path: path.clone(),
+                offset,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_stress() {
+    use super::{ramfs::RamFs, FILESYSTEM, VFS};
+    use alloc::boxed::Box;
+
+    super::init().unwrap();
+    let mut vfs = VFS.lock();
+    let free = FILESYSTEM.lock().free_blocks();
+
+    let report = stress(&mut vfs, "stress", 300, &mut Rng::new(1)).unwrap();
+    assert_eq!(
+        report.creates + report.writes + report.reads + report.deletes,
+        300
+    );
+    assert!(report.writes > 0 && report.reads > 0 && report.deletes > 0);
+    // The same seed does the same operations
+    assert_eq!(
+        stress(&mut vfs, "stress", 300, &mut Rng::new(1)).unwrap(),
+        report
+    );
+    assert!(vfs.stat("stress").is_err());
+    assert_eq!(FILESYSTEM.lock().free_blocks(), free);
+    assert!(FILESYSTEM.lock().check(None).unwrap().is_empty());
+
+    // Paths below a mount point run on the filesystem mounted there
+    vfs.create("mnt", InodeKind::Directory).unwrap();
+    vfs.mount("ram", "mnt", Box::new(RamFs::new())).unwrap();
+    let free = FILESYSTEM.lock().free_blocks();
+    let on_ram = stress(&mut vfs, "mnt/stress", 100, &mut Rng::new(2)).unwrap();
+    assert!(on_ram.writes > 0);
+    assert_eq!(FILESYSTEM.lock().free_blocks(), free);
+    vfs.umount("mnt").unwrap();
+
+    // A file which doesn't hold what was written is found
+    let mut model = BTreeMap::new();
+    vfs.create("stress", InodeKind::Directory).unwrap();
+    vfs.create("stress/f0", InodeKind::File).unwrap();
+    let mut file = vfs.open("stress/f0").unwrap();
+    vfs.write(&mut file, b"hello").unwrap();
+    model.insert(String::from("stress/f0"), b"hellO".to_vec());
+    assert!(matches!(
+        check(&vfs, "stress", &model, 1),
+        Err(StressError::ContentMismatch { offset: 4, .. })
+    ));
+    drop(file);
+
+    drop(vfs);
+    super::init().unwrap();
+}
+
+#[test_case]
+fn test_write_span() {
+    let mut rng = Rng::new(3);
+    for size in [0, 1, BLOCK_SIZE, MAX_FILE_SIZE - 1, MAX_FILE_SIZE] {
+        for _ in 0..100 {
+            let (offset, len) = write_span(&mut rng, size);
+            assert!(offset <= size && len > 0, "{} bytes at {}", len, offset);
+            assert!(offset + len <= MAX_FILE_SIZE, "{} bytes at {}", len, offset);
+        }
+    }
+}
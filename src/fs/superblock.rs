@@ -0,0 +1,127 @@
+use super::file::FileSystemError;
+use crate::util::crc32::crc32;
+
+/// The magic number a superblock starts with.
+pub(super) const MAGIC_NUMBER: u32 = 0xdeadbeef;
+
+// The superblock is stored in the first bytes of its block, every field little-endian
+// [MAGIC (4 bytes), VERSION (4 bytes), BLOCKS (8 bytes), INODE_BLOCKS (8 bytes), INODES (8 bytes),
+// INCOMPAT_FEATURES (8 bytes), BLOCK_SIZE (4 bytes), CHECKSUM (4 bytes)].
+// The checksum is the CRC-32 of the fields before it. The rest of the block is zeroes.
+const MAGIC: usize = 0;
+const VERSION: usize = 4;
+const BLOCKS: usize = 8;
+const INODE_BLOCKS: usize = 16;
+const INODES: usize = 24;
+const INCOMPAT_FEATURES: usize = 32;
+const BLOCK_SIZE: usize = 40;
+const CHECKSUM: usize = 44;
+/// The size of the superblock on the disk. It fits in the first sector of any disk, so it can be
+/// read to find out the block size doesn't match.
+pub(super) const SUPERBLOCK_SIZE: usize = 48;
+
+/// The superblock as it's kept in memory, see [`Superblock::encode`] for how it's stored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Superblock {
+    pub(super) magic_number: u32,
+    pub(super) version: u32,
+    pub(super) blocks: usize,
+    pub(super) inode_blocks: usize,
+    pub(super) inodes: usize,
+    /// Features which change how the filesystem is written. A kernel which doesn't know one of
+    /// them can still read the filesystem, but must not write to it.
+    pub(super) incompat_features: u64,
+    pub(super) block_size: usize,
+}
+
+impl Superblock {
+    /// Returns the bytes stored on the disk, ending with the checksum of the others.
+    pub(super) fn encode(&self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        bytes[MAGIC..VERSION].copy_from_slice(&self.magic_number.to_le_bytes());
+        bytes[VERSION..BLOCKS].copy_from_slice(&self.version.to_le_bytes());
+        bytes[BLOCKS..INODE_BLOCKS].copy_from_slice(&(self.blocks as u64).to_le_bytes());
+        bytes[INODE_BLOCKS..INODES].copy_from_slice(&(self.inode_blocks as u64).to_le_bytes());
+        bytes[INODES..INCOMPAT_FEATURES].copy_from_slice(&(self.inodes as u64).to_le_bytes());
+        bytes[INCOMPAT_FEATURES..BLOCK_SIZE].copy_from_slice(&self.incompat_features.to_le_bytes());
+        bytes[BLOCK_SIZE..CHECKSUM].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+        let checksum = crc32(&bytes[..CHECKSUM]);
+        bytes[CHECKSUM..].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Parses a superblock read from the disk. Fails with
+    /// [`FileSystemError::InvalidMagicNumber`] if the bytes don't start with the magic number,
+    /// and with [`FileSystemError::SuperblockChecksum`] if any other byte was changed. Whether the
+    /// fields make sense is left to the caller.
+    pub(super) fn decode(bytes: &[u8; SUPERBLOCK_SIZE]) -> Result<Self, FileSystemError> {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+        let magic_number = u32_at(MAGIC);
+        if magic_number != MAGIC_NUMBER {
+            return Err(FileSystemError::InvalidMagicNumber(magic_number));
+        }
+        let stored = u32_at(CHECKSUM);
+        let computed = crc32(&bytes[..CHECKSUM]);
+        if stored != computed {
+            return Err(FileSystemError::SuperblockChecksum { stored, computed });
+        }
+        Ok(Self {
+            magic_number,
+            version: u32_at(VERSION),
+            blocks: u64_at(BLOCKS) as usize,
+            inode_blocks: u64_at(INODE_BLOCKS) as usize,
+            inodes: u64_at(INODES) as usize,
+            incompat_features: u64_at(INCOMPAT_FEATURES),
+            block_size: u32_at(BLOCK_SIZE) as usize,
+        })
+    }
+}
+
+#[cfg(test)]
+fn example() -> Superblock {
+    Superblock {
+        magic_number: MAGIC_NUMBER,
+        version: 6,
+        blocks: 1000,
+        inode_blocks: 101,
+        inodes: 3232,
+        incompat_features: 1 << 40,
+        block_size: 4096,
+    }
+}
+
+#[test_case]
+fn test_superblock_layout() {
+    // Changing these bytes makes every formatted disk unreadable, which needs a new version
+    #[rustfmt::skip]
+    let expected = [
+        0xef, 0xbe, 0xad, 0xde, 0x06, 0x00, 0x00, 0x00,
+        0xe8, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xa0, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x10, 0x00, 0x00, 0xe3, 0xa3, 0xf4, 0xbd,
+    ];
+    assert_eq!(example().encode(), expected);
+    assert_eq!(Superblock::decode(&expected).unwrap(), example());
+}
+
+#[test_case]
+fn test_superblock_detects_bit_flips() {
+    let bytes = example().encode();
+    for bit in 0..SUPERBLOCK_SIZE * 8 {
+        let mut flipped = bytes;
+        flipped[bit / 8] ^= 1 << (bit % 8);
+        match Superblock::decode(&flipped) {
+            Err(FileSystemError::InvalidMagicNumber(_)) => assert!(bit < VERSION * 8),
+            Err(FileSystemError::SuperblockChecksum { stored, computed }) => {
+                assert_ne!(stored, computed)
+            }
+            other => panic!("bit {} flipped, got {:?}", bit, other),
+        }
+    }
+}
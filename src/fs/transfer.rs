@@ -0,0 +1,388 @@
+use core::future::Future;
+
+use alloc::vec::Vec;
+use thiserror_no_std::Error;
+
+use super::disk::{BlockDevice, DiskError, BLOCK_SIZE};
+use crate::{
+    error::{self, ErrorKind},
+    serial,
+    task::{serial::InputPause, time, yield_now},
+    timer,
+    util::crc32::Crc32,
+};
+
+// A disk image is sent as a sequence of frames, one per block, followed by a terminator. All
+// integers are little endian.
+//
+//   frame:      FRAME_MAGIC, block index (u32), length (u32), data, CRC-32 (u32)
+//   terminator: END_MAGIC, number of frames (u32)
+//
+// The CRC-32 covers the block index, the length and the data.
+pub const FRAME_MAGIC: [u8; 4] = *b"HFSB";
+pub const END_MAGIC: [u8; 4] = *b"HFSE";
+
+/// Sent by the receiver of an image over serial after each frame it has received and checked.
+pub const ACK: u8 = 0x06;
+
+/// How long to wait for each part of a frame, like its header or its data, before giving up.
+const RECEIVE_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Error, Debug)]
+pub enum TransferError {
+    #[error("timed out waiting for data")]
+    Timeout,
+    #[error("the image ended in the middle of a frame")]
+    UnexpectedEnd,
+    #[error("bad frame magic {0:02x?}")]
+    BadMagic([u8; 4]),
+    #[error("frame for block {0}, which is past the end of the disk")]
+    BlockOutOfRange(u32),
+    #[error("frame for block {block} has length {length}, expected {BLOCK_SIZE}")]
+    BadLength { block: u32, length: u32 },
+    #[error("frame for block {block}: checksum mismatch, expected {expected:#010x} but got {actual:#010x}")]
+    ChecksumMismatch {
+        block: u32,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("the image has {actual} frames, but the terminator says {expected}")]
+    FrameCountMismatch { expected: u32, actual: u32 },
+    #[error("the image doesn't fit in memory, {0} frames were received")]
+    OutOfMemory(u32),
+    #[error("disk error: {0}")]
+    Disk(#[from] DiskError),
+}
+
+impl error::Error for TransferError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Timeout => ErrorKind::Io,
+            Self::BlockOutOfRange(_) | Self::OutOfMemory(_) => ErrorKind::OutOfSpace,
+            Self::Disk(err) => err.kind(),
+            _ => ErrorKind::Corrupt,
+        }
+    }
+
+    fn source(&self) -> Option<&dyn error::Error> {
+        match self {
+            Self::Disk(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Where an image is written to.
+pub trait ImageSink {
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+/// Where an image is read from.
+pub trait ImageSource {
+    /// Fills `buf`, failing if the image ends first.
+    fn read_bytes(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<(), TransferError>>;
+
+    /// Called once a frame has been received and checked, so that the sender can send the next
+    /// one.
+    fn ack(&mut self) {}
+}
+
+impl ImageSink for Vec<u8> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+impl ImageSource for &[u8] {
+    async fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), TransferError> {
+        if self.len() < buf.len() {
+            return Err(TransferError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.split_at(buf.len());
+        buf.copy_from_slice(bytes);
+        *self = rest;
+        Ok(())
+    }
+}
+
+/// Receives an image over the serial port, acknowledging each frame with [`ACK`]. The serial
+/// shell doesn't read the port meanwhile.
+pub struct SerialSource {
+    /// How many ticks each part of a frame may take to arrive.
+    timeout: u64,
+    _pause: InputPause,
+}
+
+impl SerialSource {
+    pub fn new() -> Self {
+        Self::with_timeout(timer::millis_to_ticks(RECEIVE_TIMEOUT_MS))
+    }
+
+    /// Gives up on the image once a part of a frame takes longer than `ticks` to arrive.
+    pub fn with_timeout(ticks: u64) -> Self {
+        Self {
+            timeout: ticks,
+            _pause: InputPause::new(),
+        }
+    }
+}
+
+impl Default for SerialSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageSource for SerialSource {
+    async fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), TransferError> {
+        // The port isn't interrupt driven, so it's polled, letting other tasks run in between
+        let receive = async {
+            for byte in buf {
+                *byte = loop {
+                    if let Some(byte) = serial::try_receive() {
+                        break byte;
+                    }
+                    yield_now().await;
+                };
+            }
+        };
+        time::timeout(self.timeout, receive)
+            .await
+            .map_err(|_| TransferError::Timeout)
+    }
+
+    fn ack(&mut self) {
+        serial::send_raw(&[ACK]);
+    }
+}
+
+/// Writes the blocks of the device to `sink` as an image, returning the number of blocks written.
+pub fn dump(
+    device: &impl BlockDevice,
+    blocks: impl IntoIterator<Item = usize>,
+    sink: &mut impl ImageSink,
+) -> Result<u32, DiskError> {
+    let mut frames: u32 = 0;
+    for block in blocks {
+        dump_block(device, block, sink)?;
+        frames += 1;
+    }
+    dump_end(frames, sink);
+    Ok(frames)
+}
+
+/// Sends the blocks of the device over the serial port as an image, like [`dump`], letting other
+/// tasks run while it's sent. Returns once all of it has been sent.
+pub async fn send(
+    device: &impl BlockDevice,
+    blocks: impl IntoIterator<Item = usize>,
+) -> Result<u32, DiskError> {
+    let mut frame = Vec::new();
+    let mut frames: u32 = 0;
+    for block in blocks {
+        frame.clear();
+        dump_block(device, block, &mut frame)?;
+        serial::write_async(&frame).await;
+        frames += 1;
+    }
+    frame.clear();
+    dump_end(frames, &mut frame);
+    serial::write_async(&frame).await;
+    serial::flush_async().await;
+    Ok(frames)
+}
+
+fn dump_block(
+    device: &impl BlockDevice,
+    block: usize,
+    sink: &mut impl ImageSink,
+) -> Result<(), DiskError> {
+    let mut data = [0; BLOCK_SIZE];
+    device.read(block, &mut data)?;
+    let header = [
+        (block as u32).to_le_bytes(),
+        (BLOCK_SIZE as u32).to_le_bytes(),
+    ]
+    .concat();
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(&data);
+
+    sink.write_bytes(&FRAME_MAGIC);
+    sink.write_bytes(&header);
+    sink.write_bytes(&data);
+    sink.write_bytes(&crc.finish().to_le_bytes());
+    Ok(())
+}
+
+fn dump_end(frames: u32, sink: &mut impl ImageSink) {
+    sink.write_bytes(&END_MAGIC);
+    sink.write_bytes(&frames.to_le_bytes());
+}
+
+/// An image which has been received and checked, see [`receive`].
+pub struct Image {
+    /// The block index and data of each frame.
+    frames: Vec<(u32, Vec<u8>)>,
+}
+
+impl Image {
+    /// Returns the number of blocks in the image.
+    pub fn blocks(&self) -> u32 {
+        self.frames.len() as u32
+    }
+
+    /// Writes the blocks of the image to the device, returning the number of blocks written.
+    pub fn write_to(&self, device: &mut impl BlockDevice) -> Result<u32, TransferError> {
+        for (block, data) in &self.frames {
+            device.write(*block as usize, data)?;
+        }
+        Ok(self.blocks())
+    }
+}
+
+/// Reads an image from `source` and writes its blocks to the device, returning the number of
+/// blocks written. The whole image is received and checked before the first block is written, so a
+/// bad frame, a sender which stops or an image which doesn't fit in memory leaves the device as it
+/// was.
+pub async fn load(
+    source: &mut impl ImageSource,
+    device: &mut impl BlockDevice,
+) -> Result<u32, TransferError> {
+    receive(source, device.size()).await?.write_to(device)
+}
+
+/// Receives an image for a device of `blocks` blocks up to its terminator, checking each frame.
+pub async fn receive(source: &mut impl ImageSource, blocks: usize) -> Result<Image, TransferError> {
+    let mut frames = Vec::new();
+    loop {
+        let received = frames.len() as u32;
+        let mut magic = [0; 4];
+        source.read_bytes(&mut magic).await?;
+        match magic {
+            FRAME_MAGIC => {}
+            END_MAGIC => {
+                let expected = read_u32(source).await?;
+                if expected != received {
+                    return Err(TransferError::FrameCountMismatch {
+                        expected,
+                        actual: received,
+                    });
+                }
+                return Ok(Image { frames });
+            }
+            magic => return Err(TransferError::BadMagic(magic)),
+        }
+
+        let block = read_u32(source).await?;
+        let length = read_u32(source).await?;
+        if block as usize >= blocks {
+            return Err(TransferError::BlockOutOfRange(block));
+        }
+        if length as usize != BLOCK_SIZE {
+            return Err(TransferError::BadLength { block, length });
+        }
+        let mut data = Vec::new();
+        if frames.try_reserve(1).is_err() || data.try_reserve_exact(BLOCK_SIZE).is_err() {
+            return Err(TransferError::OutOfMemory(received));
+        }
+        data.resize(BLOCK_SIZE, 0);
+        source.read_bytes(&mut data).await?;
+        let expected = read_u32(source).await?;
+
+        let mut crc = Crc32::new();
+        crc.update(&block.to_le_bytes());
+        crc.update(&length.to_le_bytes());
+        crc.update(&data);
+        let actual = crc.finish();
+        if actual != expected {
+            return Err(TransferError::ChecksumMismatch {
+                block,
+                expected,
+                actual,
+            });
+        }
+
+        frames.push((block, data));
+        source.ack();
+    }
+}
+
+async fn read_u32(source: &mut impl ImageSource) -> Result<u32, TransferError> {
+    let mut bytes = [0; 4];
+    source.read_bytes(&mut bytes).await?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[test_case]
+fn test_dump_and_load() {
+    use super::{
+        disk::{self, Disk, KernelDisk},
+        file::InodeKind,
+        FILESYSTEM,
+    };
+    use crate::task::block_on;
+
+    super::init().unwrap();
+    {
+        let mut fs = FILESYSTEM.lock();
+        let inumber = fs.create_at("data", InodeKind::File).unwrap();
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 239) as u8).collect();
+        fs.write(inumber, 0, &data).unwrap();
+    }
+
+    let mut image = Vec::new();
+    let frames = dump(&KernelDisk, 0..disk::size(), &mut image).unwrap();
+    assert_eq!(frames as usize, disk::size());
+
+    let mut copy = Disk::new(disk::size());
+    assert_eq!(
+        block_on(load(&mut image.as_slice(), &mut copy)).unwrap(),
+        frames
+    );
+    let (mut expected, mut actual) = ([0; BLOCK_SIZE], [0; BLOCK_SIZE]);
+    for block in 0..disk::size() {
+        KernelDisk.read(block, &mut expected).unwrap();
+        BlockDevice::read(&copy, block, &mut actual).unwrap();
+        assert!(expected == actual, "block {} differs", block);
+    }
+
+    // Nothing is written when a frame is corrupted, not even the frames before it
+    let frame_len = 4 + 4 + 4 + BLOCK_SIZE + 4;
+    image[frame_len + 100] ^= 1;
+    // The disk lives on the heap, which doesn't have room for many copies
+    drop(copy);
+    let mut copy = Disk::new(disk::size());
+    assert!(matches!(
+        block_on(load(&mut image.as_slice(), &mut copy)),
+        Err(TransferError::ChecksumMismatch { block: 1, .. })
+    ));
+    for block in 0..disk::size() {
+        BlockDevice::read(&copy, block, &mut actual).unwrap();
+        assert!(
+            actual.iter().all(|&byte| byte == 0),
+            "block {} written",
+            block
+        );
+    }
+
+    assert!(matches!(
+        block_on(load(&mut &image[..10], &mut copy)),
+        Err(TransferError::UnexpectedEnd)
+    ));
+}
+
+#[test_case]
+fn test_serial_load_times_out() {
+    use super::disk::Disk;
+    use crate::task::block_on;
+
+    // Nothing is sent over the serial port during the tests
+    let mut disk = Disk::new(4);
+    let start = timer::ticks();
+    assert!(matches!(
+        block_on(load(&mut SerialSource::with_timeout(3), &mut disk)),
+        Err(TransferError::Timeout)
+    ));
+    assert!(timer::ticks() >= start + 3);
+}
@@ -0,0 +1,674 @@
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+
+use super::{
+    dir::DirEntry,
+    disk::BLOCK_SIZE,
+    file::{FileSystemError, INumber, InodeKind, Metadata},
+    handle::File,
+    path::{components, matches_glob},
+    watch::{FsEventKind, WatchHandle, WatchMask, Watches},
+    FILESYSTEM,
+};
+use crate::task::cancel::CancellationToken;
+
+/// A filesystem which can be mounted in the namespace of a [`VfsRouter`]. Paths passed to it are
+/// relative to its own root, and so are the paths in the errors it returns.
+pub trait Vfs: Send {
+    /// The type of the filesystem, as listed by `mount`.
+    fn kind(&self) -> &'static str;
+
+    fn resolve(&self, path: &str) -> Result<INumber, FileSystemError>;
+    fn stat(&self, inumber: INumber) -> Result<Metadata, FileSystemError>;
+    fn list(&self, dir: INumber) -> Result<Vec<DirEntry>, FileSystemError>;
+    fn create_at(&mut self, path: &str, kind: InodeKind) -> Result<INumber, FileSystemError>;
+    fn create_dir_all(&mut self, path: &str) -> Result<INumber, FileSystemError>;
+    fn remove(&mut self, path: &str) -> Result<(), FileSystemError>;
+    /// Removes the file or directory at the path with everything in it, stopping with
+    /// [`FileSystemError::Interrupted`] once `cancel` is cancelled.
+    fn remove_recursive(
+        &mut self,
+        path: &str,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), FileSystemError>;
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError>;
+
+    /// Creates a hard link at `new` to the file at `existing`. Not every filesystem has them.
+    fn link_at(&mut self, _existing: &str, new: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported(new.to_string()))
+    }
+
+    fn touch(&mut self, inumber: INumber) -> Result<(), FileSystemError>;
+    fn truncate(&mut self, inumber: INumber, size: usize) -> Result<(), FileSystemError>;
+
+    /// Returns whether each block of the file has data or is a hole, see
+    /// [`FileSystem::block_map`](super::file::FileSystem::block_map). Filesystems without holes
+    /// have data in every block.
+    fn block_map(&self, inumber: INumber) -> Result<Vec<bool>, FileSystemError> {
+        let blocks = self.stat(inumber)?.size.div_ceil(BLOCK_SIZE);
+        Ok(alloc::vec![true; blocks])
+    }
+
+    /// Checks the contents of the file at `path` against their checksum, see
+    /// [`FileSystem::verify`](super::file::FileSystem::verify). Not every filesystem keeps them.
+    fn verify(&self, path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported(path.to_string()))
+    }
+
+    /// Rewrites the file at `path` compressed or not, see
+    /// [`FileSystem::set_compressed`](super::file::FileSystem::set_compressed). Not every
+    /// filesystem compresses files.
+    fn set_compressed(&mut self, path: &str, _compressed: bool) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported(path.to_string()))
+    }
+
+    /// Opens the file using the inode, positioned at its start.
+    fn open(&self, inumber: INumber) -> Result<File, FileSystemError>;
+    /// Reads from the position of the file, advancing it by the number of bytes read.
+    fn read(&self, file: &mut File, buf: &mut [u8]) -> Result<usize, FileSystemError>;
+    /// Writes at the position of the file, or at its end in append mode, advancing the position.
+    fn write(&mut self, file: &mut File, data: &[u8]) -> Result<usize, FileSystemError>;
+}
+
+/// The filesystem on the disk, as mounted in [`FILESYSTEM`]. Every operation locks it, so it must
+/// not be locked by the caller.
+pub struct KernelFs;
+
+impl Vfs for KernelFs {
+    fn kind(&self) -> &'static str {
+        "diskfs"
+    }
+
+    fn resolve(&self, path: &str) -> Result<INumber, FileSystemError> {
+        FILESYSTEM.lock().resolve(path)
+    }
+
+    fn stat(&self, inumber: INumber) -> Result<Metadata, FileSystemError> {
+        FILESYSTEM.lock().stat(inumber)
+    }
+
+    fn list(&self, dir: INumber) -> Result<Vec<DirEntry>, FileSystemError> {
+        FILESYSTEM.lock().list(dir)
+    }
+
+    fn create_at(&mut self, path: &str, kind: InodeKind) -> Result<INumber, FileSystemError> {
+        FILESYSTEM.lock().create_at(path, kind)
+    }
+
+    fn create_dir_all(&mut self, path: &str) -> Result<INumber, FileSystemError> {
+        FILESYSTEM.lock().create_dir_all(path)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), FileSystemError> {
+        FILESYSTEM.lock().remove(path)
+    }
+
+    fn remove_recursive(
+        &mut self,
+        path: &str,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), FileSystemError> {
+        FILESYSTEM.lock().remove_recursive(path, cancel)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        FILESYSTEM.lock().rename(from, to)
+    }
+
+    fn link_at(&mut self, existing: &str, new: &str) -> Result<(), FileSystemError> {
+        FILESYSTEM.lock().link_at(existing, new)
+    }
+
+    fn touch(&mut self, inumber: INumber) -> Result<(), FileSystemError> {
+        FILESYSTEM.lock().touch(inumber)
+    }
+
+    fn truncate(&mut self, inumber: INumber, size: usize) -> Result<(), FileSystemError> {
+        FILESYSTEM.lock().truncate_with_cancel(inumber, size, None)
+    }
+
+    fn block_map(&self, inumber: INumber) -> Result<Vec<bool>, FileSystemError> {
+        FILESYSTEM.lock().block_map(inumber)
+    }
+
+    fn verify(&self, path: &str) -> Result<(), FileSystemError> {
+        let fs = FILESYSTEM.lock();
+        fs.verify(fs.resolve(path)?)
+    }
+
+    fn set_compressed(&mut self, path: &str, compressed: bool) -> Result<(), FileSystemError> {
+        let mut fs = FILESYSTEM.lock();
+        let inumber = fs.resolve(path)?;
+        fs.set_compressed(inumber, compressed)
+    }
+
+    fn open(&self, inumber: INumber) -> Result<File, FileSystemError> {
+        FILESYSTEM.lock().open_inode(inumber)
+    }
+
+    fn read(&self, file: &mut File, buf: &mut [u8]) -> Result<usize, FileSystemError> {
+        file.read(&FILESYSTEM.lock(), buf)
+    }
+
+    fn write(&mut self, file: &mut File, data: &[u8]) -> Result<usize, FileSystemError> {
+        file.write(&mut FILESYSTEM.lock(), data)
+    }
+}
+
+struct Mount {
+    device: String,
+    /// The mount point, normalized to start with a `/` and have no `.`, `..` or trailing `/`.
+    point: String,
+    fs: Box<dyn Vfs>,
+    /// Cloned into every file opened on the filesystem, so that the number of clones is the number
+    /// of files open on it.
+    files: Arc<()>,
+}
+
+/// A filesystem in the mount table, as listed by `mount`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub device: String,
+    pub point: String,
+    pub kind: &'static str,
+    pub open_files: usize,
+}
+
+/// A file opened through a [`VfsRouter`], which keeps the filesystem it's on from being unmounted
+/// until it's dropped.
+#[derive(Debug)]
+pub struct VfsFile {
+    file: File,
+    mount: Arc<()>,
+    /// The normalized path the file was opened with, which writes are posted to watches for.
+    path: String,
+}
+
+impl VfsFile {
+    pub fn offset(&self) -> usize {
+        self.file.offset()
+    }
+
+    /// Moves the position used by the next read or write.
+    pub fn seek(&mut self, offset: usize) {
+        self.file.seek(offset);
+    }
+
+    /// Makes every write go to the end of the file, see [`File::set_append`].
+    pub fn set_append(&mut self, append: bool) {
+        self.file.set_append(append);
+    }
+}
+
+/// The mount table, stitching filesystems together into a single tree of paths. Every path is
+/// handled by the filesystem mounted at the longest mount point it starts with, and is passed on
+/// relative to that mount point. The root filesystem is mounted at `/` and can't be unmounted.
+///
+/// A filesystem can only be mounted on a directory, which it hides until it's unmounted. Mount
+/// points, and directories with mount points in them, can't be removed or renamed, and nothing
+/// can be renamed from one filesystem to another.
+///
+/// Changes made through the router are posted to the watches registered with
+/// [`watch`](Self::watch) once the filesystem has made them.
+pub struct VfsRouter {
+    /// The mounted filesystems in the order they were mounted, starting with the root.
+    mounts: Vec<Mount>,
+    watches: Arc<Mutex<Watches>>,
+}
+
+/// Normalizes a path to start with a `/` and have no `.`, `..` or trailing `/`.
+pub fn normalize(path: &str) -> String {
+    format!("/{}", components(path).join("/"))
+}
+
+impl VfsRouter {
+    pub fn new(device: &str, root: Box<dyn Vfs>) -> Self {
+        Self {
+            mounts: alloc::vec![Mount {
+                device: device.to_string(),
+                point: String::from("/"),
+                fs: root,
+                files: Arc::new(()),
+            }],
+            watches: Arc::default(),
+        }
+    }
+
+    /// Changes the device the root filesystem is listed as mounted from, for when the same
+    /// filesystem lives on another device.
+    pub fn set_root_device(&mut self, device: &str) {
+        self.mounts[0].device = device.to_string();
+    }
+
+    /// Mounts `fs` on the directory at `point`.
+    pub fn mount(
+        &mut self,
+        device: &str,
+        point: &str,
+        fs: Box<dyn Vfs>,
+    ) -> Result<(), FileSystemError> {
+        let point = normalize(point);
+        if self.mounts.iter().any(|mount| mount.point == point) {
+            return Err(FileSystemError::Busy(point));
+        }
+        if self.stat(&point)?.kind != InodeKind::Directory {
+            return Err(FileSystemError::NotADirectory(point));
+        }
+        self.mounts.push(Mount {
+            device: device.to_string(),
+            point,
+            fs,
+            files: Arc::new(()),
+        });
+        Ok(())
+    }
+
+    /// Unmounts the filesystem mounted at `point` and returns it. Fails with
+    /// [`FileSystemError::Busy`] while files are open on it or other filesystems are mounted
+    /// inside it.
+    pub fn umount(&mut self, point: &str) -> Result<Box<dyn Vfs>, FileSystemError> {
+        let point = normalize(point);
+        let index = self
+            .mounts
+            .iter()
+            .position(|mount| mount.point == point)
+            .ok_or_else(|| FileSystemError::NotMountPoint(point.clone()))?;
+        let mount = &self.mounts[index];
+        if index == 0 || Arc::strong_count(&mount.files) > 1 || self.covers(&point, index) {
+            return Err(FileSystemError::Busy(point));
+        }
+        Ok(self.mounts.remove(index).fs)
+    }
+
+    /// Lists the mounted filesystems in the order they were mounted.
+    pub fn mounts(&self) -> Vec<MountInfo> {
+        self.mounts
+            .iter()
+            .map(|mount| MountInfo {
+                device: mount.device.clone(),
+                point: mount.point.clone(),
+                kind: mount.fs.kind(),
+                open_files: Arc::strong_count(&mount.files) - 1,
+            })
+            .collect()
+    }
+
+    /// Returns the index of the mount handling `path` and the path relative to its mount point.
+    /// Paths on the root filesystem are passed on as they are, so that its errors show them as
+    /// they were written.
+    fn route(&self, path: &str) -> (usize, String) {
+        let names = components(path);
+        let (index, depth) = self
+            .mounts
+            .iter()
+            .enumerate()
+            .map(|(index, mount)| (index, components(&mount.point)))
+            .filter(|(_, point)| names.starts_with(point))
+            .map(|(index, point)| (index, point.len()))
+            .max_by_key(|&(_, depth)| depth)
+            .unwrap_or((0, 0));
+        match index {
+            0 => (0, path.to_string()),
+            _ => (index, names[depth..].join("/")),
+        }
+    }
+
+    /// Returns `true` if a filesystem other than the one at `index` is mounted at `path` or below
+    /// it, so that removing or renaming the path would pull the mount point from under it.
+    fn covers(&self, path: &str, index: usize) -> bool {
+        let names = components(path);
+        self.mounts
+            .iter()
+            .enumerate()
+            .skip(1)
+            .any(|(i, mount)| i != index && components(&mount.point).starts_with(&names))
+    }
+
+    /// Adds the mount point to the paths in an error of the filesystem at `index`, which are
+    /// relative to it.
+    fn in_mount(&self, index: usize, err: FileSystemError) -> FileSystemError {
+        if index == 0 {
+            return err;
+        }
+        let point = &self.mounts[index].point;
+        let full = |path: String| match path.is_empty() {
+            true => point.clone(),
+            false => format!("{}/{}", point, path),
+        };
+        match err {
+            FileSystemError::NotFound(path) => FileSystemError::NotFound(full(path)),
+            FileSystemError::AlreadyExists(path) => FileSystemError::AlreadyExists(full(path)),
+            FileSystemError::NotADirectory(path) => FileSystemError::NotADirectory(full(path)),
+            FileSystemError::IsADirectory(path) => FileSystemError::IsADirectory(full(path)),
+            FileSystemError::DirectoryNotEmpty(path) => {
+                FileSystemError::DirectoryNotEmpty(full(path))
+            }
+            FileSystemError::NotSupported(path) => FileSystemError::NotSupported(full(path)),
+            FileSystemError::MoveIntoSelf(path) => FileSystemError::MoveIntoSelf(full(path)),
+            FileSystemError::RemoveFailed { path, error } => FileSystemError::RemoveFailed {
+                path: full(path),
+                error,
+            },
+            err => err,
+        }
+    }
+
+    /// Runs `f` on the filesystem handling `path`, with the path relative to it.
+    fn with_fs<T>(
+        &self,
+        path: &str,
+        f: impl FnOnce(&dyn Vfs, &str) -> Result<T, FileSystemError>,
+    ) -> Result<T, FileSystemError> {
+        let (index, relative) = self.route(path);
+        f(&*self.mounts[index].fs, &relative).map_err(|err| self.in_mount(index, err))
+    }
+
+    /// Runs `f` on the filesystem handling `path` for a change, see [`Self::with_fs`].
+    fn with_fs_mut<T>(
+        &mut self,
+        path: &str,
+        f: impl FnOnce(&mut dyn Vfs, &str) -> Result<T, FileSystemError>,
+    ) -> Result<T, FileSystemError> {
+        let (index, relative) = self.route(path);
+        f(&mut *self.mounts[index].fs, &relative).map_err(|err| self.in_mount(index, err))
+    }
+
+    /// Posts an event for `path` to the watches it concerns.
+    fn notify(&self, path: &str, kind: FsEventKind) {
+        self.watches.lock().post(&normalize(path), kind);
+    }
+
+    /// Watches the file or directory at `path` for the events selected by `mask`. A watch on a
+    /// directory is also notified of changes to the entries directly in it.
+    pub fn watch(&self, path: &str, mask: WatchMask) -> Result<WatchHandle, FileSystemError> {
+        self.stat(path)?;
+        Ok(Watches::watch(&self.watches, normalize(path), mask))
+    }
+
+    /// Fails if `path` is a mount point or has one below it.
+    fn check_not_mounted_on(&self, path: &str) -> Result<(), FileSystemError> {
+        match self.covers(path, 0) {
+            true => Err(FileSystemError::Busy(normalize(path))),
+            false => Ok(()),
+        }
+    }
+
+    pub fn stat(&self, path: &str) -> Result<Metadata, FileSystemError> {
+        self.with_fs(path, |fs, path| fs.stat(fs.resolve(path)?))
+    }
+
+    /// Returns the entries of the directory at `path`.
+    pub fn list(&self, path: &str) -> Result<Vec<DirEntry>, FileSystemError> {
+        self.with_fs(path, |fs, path| {
+            let inumber = fs.resolve(path)?;
+            if fs.stat(inumber)?.kind != InodeKind::Directory {
+                return Err(FileSystemError::NotADirectory(path.to_string()));
+            }
+            fs.list(inumber)
+        })
+    }
+
+    /// Returns whether each block of the file at `path` has data or is a hole, see
+    /// [`Vfs::block_map`].
+    pub fn block_map(&self, path: &str) -> Result<Vec<bool>, FileSystemError> {
+        self.with_fs(path, |fs, path| fs.block_map(fs.resolve(path)?))
+    }
+
+    /// Checks the contents of the file at `path` against their checksum, see [`Vfs::verify`].
+    pub fn verify(&self, path: &str) -> Result<(), FileSystemError> {
+        self.with_fs(path, |fs, path| fs.verify(path))
+    }
+
+    /// Rewrites the file at `path` compressed or not, see [`Vfs::set_compressed`].
+    pub fn set_compressed(&mut self, path: &str, compressed: bool) -> Result<(), FileSystemError> {
+        self.with_fs_mut(path, |fs, path| fs.set_compressed(path, compressed))?;
+        self.notify(path, FsEventKind::Modified);
+        Ok(())
+    }
+
+    /// Returns `true` if both paths point to the same file.
+    pub fn same_file(&self, a: &str, b: &str) -> Result<bool, FileSystemError> {
+        let (a_index, _) = self.route(a);
+        let (b_index, _) = self.route(b);
+        let a_inumber = self.with_fs(a, |fs, path| fs.resolve(path))?;
+        let b_inumber = self.with_fs(b, |fs, path| fs.resolve(path))?;
+        Ok(a_index == b_index && a_inumber == b_inumber)
+    }
+
+    /// Returns the paths matching a glob pattern, sorted, like
+    /// [`FileSystem::glob`](super::file::FileSystem::glob).
+    pub fn glob(&self, pattern: &str) -> Result<Vec<String>, FileSystemError> {
+        let (dir, name) = pattern.split_at(pattern.rfind('/').map_or(0, |slash| slash + 1));
+        let entries = match self.list(dir) {
+            Ok(entries) => entries,
+            Err(FileSystemError::NotFound(_) | FileSystemError::NotADirectory(_)) => {
+                return Ok(Vec::new())
+            }
+            Err(err) => return Err(err),
+        };
+        let mut paths = entries
+            .into_iter()
+            .filter(|entry| matches_glob(name, &entry.name))
+            .map(|entry| format!("{}{}", dir, entry.name))
+            .collect::<Vec<_>>();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Creates a new empty file or directory at the path.
+    pub fn create(&mut self, path: &str, kind: InodeKind) -> Result<(), FileSystemError> {
+        self.with_fs_mut(path, |fs, path| fs.create_at(path, kind))?;
+        self.notify(path, FsEventKind::Created);
+        Ok(())
+    }
+
+    /// Creates the directory at the path along with any missing parent directories.
+    pub fn create_dir_all(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let names = components(path);
+        let missing = (1..=names.len())
+            .map(|depth| format!("/{}", names[..depth].join("/")))
+            .filter(|dir| self.stat(dir).is_err())
+            .collect::<Vec<_>>();
+        self.with_fs_mut(path, |fs, path| fs.create_dir_all(path))?;
+        for dir in missing {
+            self.notify(&dir, FsEventKind::Created);
+        }
+        Ok(())
+    }
+
+    /// Sets the modification time of the file at the path to now.
+    pub fn touch(&mut self, path: &str) -> Result<(), FileSystemError> {
+        self.with_fs_mut(path, |fs, path| fs.touch(fs.resolve(path)?))?;
+        self.notify(path, FsEventKind::Modified);
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, path: &str, size: usize) -> Result<(), FileSystemError> {
+        self.with_fs_mut(path, |fs, path| fs.truncate(fs.resolve(path)?, size))?;
+        self.notify(path, FsEventKind::Modified);
+        Ok(())
+    }
+
+    /// Removes the file or empty directory at the path.
+    pub fn remove(&mut self, path: &str) -> Result<(), FileSystemError> {
+        self.check_not_mounted_on(path)?;
+        self.with_fs_mut(path, |fs, path| fs.remove(path))?;
+        self.notify(path, FsEventKind::Removed);
+        Ok(())
+    }
+
+    /// Removes the file or directory at the path along with everything in it. Only the path
+    /// itself is posted as removed. Stops between entries once `cancel` is cancelled, see
+    /// [`Vfs::remove_recursive`].
+    pub fn remove_recursive(
+        &mut self,
+        path: &str,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), FileSystemError> {
+        self.check_not_mounted_on(path)?;
+        self.with_fs_mut(path, |fs, path| fs.remove_recursive(path, cancel))?;
+        self.notify(path, FsEventKind::Removed);
+        Ok(())
+    }
+
+    /// Moves the entry at `from` to `to`, which must be on the same filesystem.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FileSystemError> {
+        self.check_not_mounted_on(from)?;
+        self.check_not_mounted_on(to)?;
+        let ((from_index, from_relative), (to_index, to_relative)) =
+            (self.route(from), self.route(to));
+        if from_index != to_index {
+            return Err(FileSystemError::CrossDevice(from.to_string()));
+        }
+        self.mounts[from_index]
+            .fs
+            .rename(&from_relative, &to_relative)
+            .map_err(|err| self.in_mount(from_index, err))?;
+        self.notify(from, FsEventKind::Renamed);
+        self.notify(to, FsEventKind::Renamed);
+        Ok(())
+    }
+
+    /// Creates a hard link at `new` to the file at `existing`, which must be on the same
+    /// filesystem.
+    pub fn link(&mut self, existing: &str, new: &str) -> Result<(), FileSystemError> {
+        let ((index, existing_relative), (new_index, new_relative)) =
+            (self.route(existing), self.route(new));
+        if index != new_index {
+            return Err(FileSystemError::CrossDevice(existing.to_string()));
+        }
+        self.mounts[index]
+            .fs
+            .link_at(&existing_relative, &new_relative)
+            .map_err(|err| self.in_mount(index, err))?;
+        self.notify(new, FsEventKind::Created);
+        Ok(())
+    }
+
+    /// Opens the file at the path, positioned at its start.
+    pub fn open(&self, path: &str) -> Result<VfsFile, FileSystemError> {
+        let (index, _) = self.route(path);
+        let file = self.with_fs(path, |fs, path| fs.open(fs.resolve(path)?))?;
+        Ok(VfsFile {
+            file,
+            mount: self.mounts[index].files.clone(),
+            path: normalize(path),
+        })
+    }
+
+    /// Returns the index of the mount the file was opened on.
+    fn mount_of(&self, file: &VfsFile) -> Result<usize, FileSystemError> {
+        self.mounts
+            .iter()
+            .position(|mount| Arc::ptr_eq(&mount.files, &file.mount))
+            .ok_or(FileSystemError::NotMounted)
+    }
+
+    /// Reads from the position of the file, advancing it by the number of bytes read.
+    pub fn read(&self, file: &mut VfsFile, buf: &mut [u8]) -> Result<usize, FileSystemError> {
+        let index = self.mount_of(file)?;
+        self.mounts[index].fs.read(&mut file.file, buf)
+    }
+
+    /// Writes at the position of the file, or at its end in append mode, advancing the position.
+    pub fn write(&mut self, file: &mut VfsFile, data: &[u8]) -> Result<usize, FileSystemError> {
+        let index = self.mount_of(file)?;
+        let written = self.mounts[index].fs.write(&mut file.file, data)?;
+        self.watches.lock().post(&file.path, FsEventKind::Modified);
+        Ok(written)
+    }
+}
+
+#[test_case]
+fn test_routing() {
+    use super::ramfs::RamFs;
+
+    let mut vfs = VfsRouter::new("root", Box::new(RamFs::new()));
+    vfs.create_dir_all("mnt/sub").unwrap();
+    vfs.create("mnt/hidden", InodeKind::File).unwrap();
+    vfs.mount("ram0", "/mnt/", Box::new(RamFs::new())).unwrap();
+    // The directory mounted on is hidden, and the new filesystem is empty
+    assert!(vfs.list("mnt").unwrap().is_empty());
+    assert!(matches!(
+        vfs.mount("ram1", "mnt/none", Box::new(RamFs::new())),
+        Err(FileSystemError::NotFound(path)) if path == "/mnt/none"
+    ));
+
+    // Files on both sides are created on the filesystem the path leads to
+    vfs.create("a", InodeKind::File).unwrap();
+    vfs.create("mnt/b", InodeKind::File).unwrap();
+    let names = |vfs: &VfsRouter, path| {
+        let entries = vfs.list(path).unwrap();
+        entries.into_iter().map(|e| e.name).collect::<Vec<_>>()
+    };
+    assert_eq!(names(&vfs, "/"), ["a", "mnt"]);
+    assert_eq!(names(&vfs, "/mnt"), ["b"]);
+
+    // The longest mount point wins, and /mnt/sub is only found inside /mnt once it's made there
+    vfs.create("mnt/sub", InodeKind::Directory).unwrap();
+    vfs.mount("ram1", "mnt/./sub", Box::new(RamFs::new()))
+        .unwrap();
+    vfs.create("mnt/sub/c", InodeKind::File).unwrap();
+    assert_eq!(names(&vfs, "mnt/sub"), ["c"]);
+    assert_eq!(names(&vfs, "mnt"), ["b", "sub"]);
+    let mut writer = vfs.open("mnt/sub/c").unwrap();
+    vfs.write(&mut writer, b"deep").unwrap();
+    drop(writer);
+    let mut file = vfs.open("/mnt/../mnt/sub/c").unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(vfs.read(&mut file, &mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"deep");
+    assert_eq!(
+        vfs.mounts()
+            .iter()
+            .map(|m| (m.point.as_str(), m.open_files))
+            .collect::<Vec<_>>(),
+        [("/", 0), ("/mnt", 0), ("/mnt/sub", 1)]
+    );
+
+    // Errors name the whole path, not the one within the filesystem
+    assert!(matches!(
+        vfs.stat("mnt/sub/missing"),
+        Err(FileSystemError::NotFound(path)) if path == "/mnt/sub/missing"
+    ));
+
+    // Renames stay within a filesystem
+    vfs.rename("mnt/b", "mnt/b2").unwrap();
+    for (from, to) in [("a", "mnt/a"), ("mnt/b2", "mnt/sub/b"), ("mnt/sub/c", "c")] {
+        assert!(matches!(
+            vfs.rename(from, to),
+            Err(FileSystemError::CrossDevice(_))
+        ));
+    }
+    assert!(matches!(
+        vfs.rename("mnt", "elsewhere"),
+        Err(FileSystemError::Busy(_))
+    ));
+    assert!(matches!(
+        vfs.remove_recursive("mnt", None),
+        Err(FileSystemError::Busy(_))
+    ));
+
+    // Nothing is unmounted while a file is open on it or a filesystem is mounted inside it
+    assert!(matches!(
+        vfs.umount("mnt/sub"),
+        Err(FileSystemError::Busy(_))
+    ));
+    assert!(matches!(vfs.umount("mnt"), Err(FileSystemError::Busy(_))));
+    assert!(matches!(vfs.umount("/"), Err(FileSystemError::Busy(_))));
+    assert!(matches!(
+        vfs.umount("a"),
+        Err(FileSystemError::NotMountPoint(_))
+    ));
+    drop(file);
+    vfs.umount("mnt/sub").unwrap();
+    vfs.umount("mnt").unwrap();
+    assert_eq!(names(&vfs, "mnt"), ["hidden", "sub"]);
+    assert!(vfs.stat("mnt/sub/c").is_err());
+}
@@ -0,0 +1,282 @@
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{task::AtomicWaker, Stream};
+use spin::Mutex;
+
+/// Capacity of the queue of each watch. Events arriving while it's full are dropped, and an
+/// [`FsEventKind::Overflowed`] event takes their place.
+pub const WATCH_QUEUE_SIZE: usize = 32;
+
+/// The events a watch is notified of, combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchMask(u8);
+
+impl WatchMask {
+    /// Data was written to a file, or it was truncated or touched.
+    pub const MODIFIED: Self = Self(1);
+    pub const CREATED: Self = Self(1 << 1);
+    pub const REMOVED: Self = Self(1 << 2);
+    /// An entry was moved, which is posted for both the old and the new path.
+    pub const RENAMED: Self = Self(1 << 3);
+
+    pub const fn all() -> Self {
+        Self(0b1111)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for WatchMask {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsEventKind {
+    Modified,
+    Created,
+    Removed,
+    Renamed,
+    /// Events were dropped because the queue of the watch was full. Its path is the watched one.
+    Overflowed,
+}
+
+impl FsEventKind {
+    /// The mask selecting the event. Overflows are never masked out.
+    fn mask(self) -> WatchMask {
+        match self {
+            Self::Modified => WatchMask::MODIFIED,
+            Self::Created => WatchMask::CREATED,
+            Self::Removed => WatchMask::REMOVED,
+            Self::Renamed => WatchMask::RENAMED,
+            Self::Overflowed => WatchMask(0),
+        }
+    }
+}
+
+impl core::fmt::Display for FsEventKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = match self {
+            Self::Modified => "modified",
+            Self::Created => "created",
+            Self::Removed => "removed",
+            Self::Renamed => "renamed",
+            Self::Overflowed => "overflowed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A change to a watched path, or to an entry directly in a watched directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsEvent {
+    /// The path which changed, normalized like [`normalize`](super::vfs::normalize) does.
+    pub path: String,
+    pub kind: FsEventKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WatchId(u64);
+
+struct WatchChannel {
+    path: String,
+    mask: WatchMask,
+    queue: ArrayQueue<FsEvent>,
+    /// Set when an event was dropped, after which all events are until the overflow is read.
+    overflowed: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl WatchChannel {
+    /// Returns `true` if an event for `path` concerns the watch: the path is the watched one, or
+    /// an entry directly in it.
+    fn matches(&self, path: &str) -> bool {
+        let parent = match path.rsplit_once('/') {
+            Some(("", _)) => "/",
+            Some((parent, _)) => parent,
+            None => "",
+        };
+        path == self.path || parent == self.path
+    }
+}
+
+/// The watches registered with a [`VfsRouter`](super::vfs::VfsRouter). Shared with the handles,
+/// so that dropping one doesn't need the router.
+#[derive(Default)]
+pub(super) struct Watches {
+    channels: Vec<(WatchId, Arc<WatchChannel>)>,
+    next_id: u64,
+}
+
+impl Watches {
+    /// Registers a watch on the normalized `path`.
+    pub(super) fn watch(watches: &Arc<Mutex<Self>>, path: String, mask: WatchMask) -> WatchHandle {
+        let channel = Arc::new(WatchChannel {
+            path,
+            mask,
+            queue: ArrayQueue::new(WATCH_QUEUE_SIZE),
+            overflowed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        let mut locked = watches.lock();
+        let id = WatchId(locked.next_id);
+        locked.next_id += 1;
+        locked.channels.push((id, channel.clone()));
+        WatchHandle {
+            id,
+            channel,
+            watches: watches.clone(),
+        }
+    }
+
+    /// Posts an event for the normalized `path` to every watch it concerns.
+    pub(super) fn post(&self, path: &str, kind: FsEventKind) {
+        for (_, channel) in &self.channels {
+            if !channel.mask.contains(kind.mask()) || !channel.matches(path) {
+                continue;
+            }
+            if channel.overflowed.load(Ordering::SeqCst) {
+                continue;
+            }
+            let event = FsEvent {
+                path: String::from(path),
+                kind,
+            };
+            if channel.queue.push(event).is_err() {
+                channel.overflowed.store(true, Ordering::SeqCst);
+            }
+            channel.waker.wake();
+        }
+    }
+}
+
+/// The events of a watch registered with [`VfsRouter::watch`](super::vfs::VfsRouter::watch), in
+/// the order they happened. The watch is removed once this is dropped.
+pub struct WatchHandle {
+    id: WatchId,
+    channel: Arc<WatchChannel>,
+    watches: Arc<Mutex<Watches>>,
+}
+
+impl WatchHandle {
+    /// Returns the next event if there is one, without waiting.
+    pub fn try_next(&self) -> Option<FsEvent> {
+        if let Some(event) = self.channel.queue.pop() {
+            return Some(event);
+        }
+        // Only reported once the events before it have been read
+        self.channel
+            .overflowed
+            .swap(false, Ordering::SeqCst)
+            .then(|| FsEvent {
+                path: self.channel.path.clone(),
+                kind: FsEventKind::Overflowed,
+            })
+    }
+}
+
+impl Stream for WatchHandle {
+    type Item = FsEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(event) = self.try_next() {
+            return Poll::Ready(Some(event));
+        }
+
+        self.channel.waker.register(cx.waker());
+        match self.try_next() {
+            Some(event) => {
+                self.channel.waker.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.watches
+            .lock()
+            .channels
+            .retain(|(id, _)| *id != self.id);
+    }
+}
+
+#[test_case]
+fn test_watch_events() {
+    use super::{file::InodeKind, ramfs::RamFs, vfs::VfsRouter};
+    use alloc::boxed::Box;
+    use futures_util::StreamExt as _;
+
+    let event = |path: &str, kind| FsEvent {
+        path: String::from(path),
+        kind,
+    };
+    let mut vfs = VfsRouter::new("root", Box::new(RamFs::new()));
+    let mut created = vfs.watch("/", WatchMask::CREATED).unwrap();
+    vfs.create("logs", InodeKind::Directory).unwrap();
+    let dir = vfs.watch("logs/", WatchMask::all()).unwrap();
+
+    vfs.create("logs/boot", InodeKind::File).unwrap();
+    let mut file = vfs.open("logs/boot").unwrap();
+    vfs.write(&mut file, b"booted").unwrap();
+    vfs.rename("logs/boot", "logs/boot.old").unwrap();
+    // Entries further down aren't direct children of the directory
+    vfs.create_dir_all("logs/deep/er").unwrap();
+    vfs.remove("logs/boot.old").unwrap();
+    assert_eq!(
+        core::iter::from_fn(|| dir.try_next()).collect::<Vec<_>>(),
+        [
+            event("/logs/boot", FsEventKind::Created),
+            event("/logs/boot", FsEventKind::Modified),
+            event("/logs/boot", FsEventKind::Renamed),
+            event("/logs/boot.old", FsEventKind::Renamed),
+            event("/logs/deep", FsEventKind::Created),
+            event("/logs/boot.old", FsEventKind::Removed),
+        ]
+    );
+    // The root only sees what's created in it, which the stream delivers as well
+    assert_eq!(
+        crate::task::block_on(created.next()),
+        Some(event("/logs", FsEventKind::Created))
+    );
+    assert!(created.try_next().is_none());
+
+    // A full queue drops events and reports it once the queued ones are read
+    vfs.create("logs/spam", InodeKind::File).unwrap();
+    let mut file = vfs.open("logs/spam").unwrap();
+    for _ in 0..WATCH_QUEUE_SIZE + 5 {
+        vfs.write(&mut file, b"x").unwrap();
+    }
+    vfs.remove("logs/spam").unwrap();
+    let events = core::iter::from_fn(|| dir.try_next()).collect::<Vec<_>>();
+    assert_eq!(events.len(), WATCH_QUEUE_SIZE + 1);
+    assert_eq!(events[0], event("/logs/spam", FsEventKind::Created));
+    assert_eq!(
+        events[WATCH_QUEUE_SIZE],
+        event("/logs", FsEventKind::Overflowed)
+    );
+    vfs.touch("logs").unwrap();
+    assert_eq!(dir.try_next(), Some(event("/logs", FsEventKind::Modified)));
+
+    assert!(vfs.watch("missing", WatchMask::all()).is_err());
+
+    // Dropping a handle removes the watch
+    let watches = Arc::new(Mutex::new(Watches::default()));
+    let handle = Watches::watch(&watches, String::from("/"), WatchMask::all());
+    assert_eq!(watches.lock().channels.len(), 1);
+    drop(handle);
+    assert!(watches.lock().channels.is_empty());
+}
@@ -1,13 +1,14 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
-use pic8259::ChainedPics;
 use spin::Mutex;
-use x86_64::{
-    instructions::port::Port,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
-};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
-use crate::println;
+use crate::{
+    io::{self, Port, PIC1_COMMAND, PIC1_DATA, PIC2_COMMAND, PIC2_DATA},
+    println,
+};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -21,6 +22,8 @@ lazy_static! {
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
         idt[InterruptIndex::Video.as_usize()].set_handler_fn(video_interrupt_handler);
+        idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(ata_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt
     };
@@ -32,10 +35,102 @@ pub fn init_idt() {
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-pub const PS2_PORT: u16 = 0x60;
 
-pub static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: Mutex<ChainedPics> = Mutex::new(ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET));
+
+// Initialization command words
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// One of the two 8259 interrupt controllers, each raising 8 IRQs as the interrupts starting at
+/// its offset.
+struct Pic {
+    offset: u8,
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    fn handles(&self, interrupt: u8) -> bool {
+        (self.offset..self.offset + 8).contains(&interrupt)
+    }
+}
+
+/// The primary PIC and the secondary one chained to its IRQ 2.
+pub struct ChainedPics {
+    pics: [Pic; 2],
+}
+
+impl ChainedPics {
+    pub const fn new(offset1: u8, offset2: u8) -> Self {
+        Self {
+            pics: [
+                Pic {
+                    offset: offset1,
+                    command: PIC1_COMMAND,
+                    data: PIC1_DATA,
+                },
+                Pic {
+                    offset: offset2,
+                    command: PIC2_COMMAND,
+                    data: PIC2_DATA,
+                },
+            ],
+        }
+    }
+
+    /// Moves the IRQs to the offsets, keeping which of them are masked.
+    pub fn initialize(&mut self) {
+        let masks = self.pics.each_ref().map(|pic| pic.data.read());
+        let [primary, secondary] = &self.pics;
+        for pic in &self.pics {
+            pic.command.write(ICW1_INIT);
+            io::io_wait();
+        }
+        for pic in &self.pics {
+            pic.data.write(pic.offset);
+            io::io_wait();
+        }
+        // The secondary PIC is on IRQ 2 of the primary one
+        primary.data.write(1 << 2);
+        io::io_wait();
+        secondary.data.write(2);
+        io::io_wait();
+        for pic in &self.pics {
+            pic.data.write(ICW4_8086);
+            io::io_wait();
+        }
+        for (pic, mask) in self.pics.iter().zip(masks) {
+            pic.data.write(mask);
+        }
+    }
+
+    /// Lets the PICs raise the IRQ, along with IRQ 2 for the ones of the secondary PIC.
+    pub fn unmask(&mut self, irq: usize) {
+        let [primary, secondary] = &self.pics;
+        let (pic, line) = match irq {
+            0..=7 => (primary, irq),
+            _ => {
+                primary.data.write(primary.data.read() & !(1 << 2));
+                (secondary, irq - 8)
+            }
+        };
+        pic.data.write(pic.data.read() & !(1 << line));
+    }
+
+    /// Tells the PICs that the interrupt has been handled, so that they raise the next one.
+    /// Interrupts which aren't from the PICs are ignored.
+    pub fn notify_end_of_interrupt(&mut self, interrupt: u8) {
+        let [primary, secondary] = &self.pics;
+        if secondary.handles(interrupt) {
+            secondary.command.write(END_OF_INTERRUPT);
+        }
+        if primary.handles(interrupt) || secondary.handles(interrupt) {
+            primary.command.write(END_OF_INTERRUPT);
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -44,26 +139,78 @@ pub enum InterruptIndex {
     Keyboard,
     Video,
     Equipment,
-    MemSize,
+    Com1,
     Disk,
+    PrimaryAta = PIC_2_OFFSET + 6,
 }
 
 impl InterruptIndex {
+    /// The interrupts which have a handler, along with their names.
+    pub const HANDLED: [(Self, &'static str); 5] = [
+        (Self::Timer, "timer"),
+        (Self::Keyboard, "keyboard"),
+        (Self::Video, "video"),
+        (Self::Com1, "com1"),
+        (Self::PrimaryAta, "ata0"),
+    ];
+
     fn as_u8(self) -> u8 {
         self as u8
     }
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+    /// The IRQ line of the interrupt.
+    pub fn irq(self) -> usize {
+        usize::from(self.as_u8() - PIC_1_OFFSET)
+    }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+// The number of times each IRQ line has fired since boot
+static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+/// Returns the number of times the interrupt has fired since boot.
+pub fn irq_count(index: InterruptIndex) -> u64 {
+    IRQ_COUNTS[index.irq()].load(Ordering::Relaxed)
+}
+
+fn count_irq(index: InterruptIndex) {
+    IRQ_COUNTS[index.irq()].fetch_add(1, Ordering::Relaxed);
+}
+
+// The number of IRQ handlers running, which is only more than one if they nest
+static HANDLERS_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns `true` while an IRQ handler runs, for code which behaves differently when called from
+/// one, like the wakers of the executor.
+pub fn in_handler() -> bool {
+    HANDLERS_RUNNING.load(Ordering::Relaxed) != 0
+}
+
+/// Marks an IRQ handler as running until it's dropped, see [`in_handler`].
+pub(crate) struct InHandler(());
+
+impl InHandler {
+    pub(crate) fn enter() -> Self {
+        HANDLERS_RUNNING.fetch_add(1, Ordering::Relaxed);
+        Self(())
     }
 }
 
+impl Drop for InHandler {
+    fn drop(&mut self) {
+        HANDLERS_RUNNING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = InHandler::enter();
+    count_irq(InterruptIndex::Timer);
+    crate::timer::tick();
+    PICS.lock()
+        .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     lazy_static! {
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
@@ -71,22 +218,36 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
         );
     }
 
-    let mut port = Port::new(PS2_PORT);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
+    let _handler = InHandler::enter();
+    count_irq(InterruptIndex::Keyboard);
+    crate::task::keyboard::add_scancode(io::PS2_DATA.read());
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    PICS.lock()
+        .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
 }
 
 extern "x86-interrupt" fn video_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = InHandler::enter();
+    count_irq(InterruptIndex::Video);
     println!("Video interrupt");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Video.as_u8());
-    }
+    PICS.lock()
+        .notify_end_of_interrupt(InterruptIndex::Video.as_u8());
+}
+
+extern "x86-interrupt" fn com1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = InHandler::enter();
+    count_irq(InterruptIndex::Com1);
+    crate::serial::handle_interrupt();
+    PICS.lock()
+        .notify_end_of_interrupt(InterruptIndex::Com1.as_u8());
+}
+
+extern "x86-interrupt" fn ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = InHandler::enter();
+    count_irq(InterruptIndex::PrimaryAta);
+    crate::fs::ata::handle_interrupt();
+    PICS.lock()
+        .notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
@@ -97,6 +258,9 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    if let Some(depth) = crate::stack::overflow_depth(stack_frame.stack_pointer.as_u64()) {
+        crate::stack::overflowed(depth);
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -0,0 +1,197 @@
+use core::marker::PhantomData;
+
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+/// An I/O port which is both read and written, with values of `T`: `u8`, `u16` or `u32`.
+///
+/// Ports can only be made with an `unsafe` promise that they're the ones of the device, so that
+/// reading and writing them is safe. The ports of every device are made in this module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T> {
+    number: u16,
+    _value: PhantomData<T>,
+}
+
+/// An I/O port which can only be read, like the PS/2 status register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOnlyPort<T> {
+    number: u16,
+    _value: PhantomData<T>,
+}
+
+/// An I/O port which can only be written, like the PIT mode/command register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOnlyPort<T> {
+    number: u16,
+    _value: PhantomData<T>,
+}
+
+macro_rules! port_constructor {
+    ($port:ident) => {
+        impl<T> $port<T> {
+            /// # Safety
+            ///
+            /// `number` must be a port of a device for which reading or writing any value can't
+            /// break memory safety, as both are safe once the port is made.
+            pub const unsafe fn new(number: u16) -> Self {
+                Self {
+                    number,
+                    _value: PhantomData,
+                }
+            }
+
+            pub const fn number(&self) -> u16 {
+                self.number
+            }
+        }
+    };
+}
+
+port_constructor!(Port);
+port_constructor!(ReadOnlyPort);
+port_constructor!(WriteOnlyPort);
+
+impl<T: PortRead> Port<T> {
+    pub fn read(&self) -> T {
+        unsafe { T::read_from_port(self.number) }
+    }
+}
+
+impl<T: PortWrite> Port<T> {
+    pub fn write(&self, value: T) {
+        unsafe { T::write_to_port(self.number, value) }
+    }
+}
+
+impl<T: PortRead> ReadOnlyPort<T> {
+    pub fn read(&self) -> T {
+        unsafe { T::read_from_port(self.number) }
+    }
+}
+
+impl<T: PortWrite> WriteOnlyPort<T> {
+    pub fn write(&self, value: T) {
+        unsafe { T::write_to_port(self.number, value) }
+    }
+}
+
+/// Waits roughly a microsecond by writing to the POST code port, which nothing listens to. Old
+/// devices like the PIC need this between writes.
+pub fn io_wait() {
+    POST.write(0);
+}
+
+// QEMU
+
+/// The `isa-debug-exit` device, which exits QEMU with `(value << 1) | 1`.
+pub const QEMU_EXIT: WriteOnlyPort<u32> = unsafe { WriteOnlyPort::new(0xf4) };
+/// The POST code port, written by [`io_wait`].
+pub const POST: WriteOnlyPort<u8> = unsafe { WriteOnlyPort::new(0x80) };
+
+// 8259 PICs
+
+pub const PIC1_COMMAND: Port<u8> = unsafe { Port::new(0x20) };
+pub const PIC1_DATA: Port<u8> = unsafe { Port::new(0x21) };
+pub const PIC2_COMMAND: Port<u8> = unsafe { Port::new(0xa0) };
+pub const PIC2_DATA: Port<u8> = unsafe { Port::new(0xa1) };
+
+// 8253/8254 PIT
+
+pub const PIT_CH0: Port<u8> = unsafe { Port::new(0x40) };
+pub const PIT_COMMAND: WriteOnlyPort<u8> = unsafe { WriteOnlyPort::new(0x43) };
+
+// PS/2 controller
+
+pub const PS2_DATA: Port<u8> = unsafe { Port::new(0x60) };
+pub const PS2_STATUS: ReadOnlyPort<u8> = unsafe { ReadOnlyPort::new(0x64) };
+pub const PS2_COMMAND: WriteOnlyPort<u8> = unsafe { WriteOnlyPort::new(0x64) };
+
+// CMOS and its real-time clock
+
+/// Selects the CMOS register read and written through [`CMOS_DATA`]. The top bit disables NMIs.
+pub const CMOS_ADDR: WriteOnlyPort<u8> = unsafe { WriteOnlyPort::new(0x70) };
+pub const CMOS_DATA: Port<u8> = unsafe { Port::new(0x71) };
+
+// First serial port
+
+/// The base of the first serial port's registers, for `uart_16550`.
+pub const COM1: u16 = 0x3f8;
+pub const COM1_DATA: Port<u8> = unsafe { Port::new(COM1) };
+pub const COM1_INTERRUPT_ENABLE: Port<u8> = unsafe { Port::new(COM1 + 1) };
+/// Reading this acknowledges the transmit empty interrupt.
+pub const COM1_INTERRUPT_ID: ReadOnlyPort<u8> = unsafe { ReadOnlyPort::new(COM1 + 2) };
+pub const COM1_LINE_STATUS: ReadOnlyPort<u8> = unsafe { ReadOnlyPort::new(COM1 + 5) };
+
+// PCI configuration space
+
+/// Selects the register of a PCI function read and written through [`PCI_CONFIG_DATA`].
+pub const PCI_CONFIG_ADDRESS: Port<u32> = unsafe { Port::new(0xcf8) };
+pub const PCI_CONFIG_DATA: Port<u32> = unsafe { Port::new(0xcfc) };
+
+// Primary ATA channel
+
+pub const ATA_DATA: Port<u16> = unsafe { Port::new(0x1f0) };
+pub const ATA_ERROR: ReadOnlyPort<u8> = unsafe { ReadOnlyPort::new(0x1f1) };
+pub const ATA_SECTOR_COUNT: Port<u8> = unsafe { Port::new(0x1f2) };
+pub const ATA_LBA_LOW: Port<u8> = unsafe { Port::new(0x1f3) };
+pub const ATA_LBA_MID: Port<u8> = unsafe { Port::new(0x1f4) };
+pub const ATA_LBA_HIGH: Port<u8> = unsafe { Port::new(0x1f5) };
+/// Selects the drive, along with the top bits of the LBA.
+pub const ATA_DRIVE: Port<u8> = unsafe { Port::new(0x1f6) };
+/// Reading the status acknowledges the interrupt of the channel.
+pub const ATA_STATUS: ReadOnlyPort<u8> = unsafe { ReadOnlyPort::new(0x1f7) };
+pub const ATA_COMMAND: WriteOnlyPort<u8> = unsafe { WriteOnlyPort::new(0x1f7) };
+/// The status, read without acknowledging the interrupt.
+pub const ATA_ALT_STATUS: ReadOnlyPort<u8> = unsafe { ReadOnlyPort::new(0x3f6) };
+pub const ATA_CONTROL: WriteOnlyPort<u8> = unsafe { WriteOnlyPort::new(0x3f6) };
+
+/// The bus master registers of the primary channel of an IDE controller, which run its DMA
+/// transfers. They're at an I/O base the controller is configured with, see [`bus_master`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusMasterPorts {
+    pub command: Port<u8>,
+    pub status: Port<u8>,
+    /// The physical address of the table of memory regions to transfer.
+    pub prdt: Port<u32>,
+}
+
+/// Returns the bus master registers at `base`.
+///
+/// # Safety
+///
+/// `base` must be the bus master base of an IDE controller found on the PCI bus.
+pub const unsafe fn bus_master(base: u16) -> BusMasterPorts {
+    BusMasterPorts {
+        command: Port::new(base),
+        status: Port::new(base + 2),
+        prdt: Port::new(base + 4),
+    }
+}
+
+// Fails to build if a read-only port can be written or a write-only port read. Methods of their
+// own are picked over those of `Fallback`, which are the only ones returning `Missing`.
+const _: () = {
+    struct Missing;
+
+    trait Fallback {
+        fn read(&self) -> Missing {
+            Missing
+        }
+
+        fn write(&self, _value: u8) -> Missing {
+            Missing
+        }
+    }
+
+    impl<T> Fallback for T {}
+
+    #[allow(dead_code)]
+    fn check() {
+        let _: Missing = PS2_STATUS.write(0);
+        let _: Missing = PIT_COMMAND.read();
+        let _: u8 = PS2_STATUS.read();
+        let _: () = PIT_COMMAND.write(0);
+        let _: u8 = PS2_DATA.read();
+        let _: () = PS2_DATA.write(0);
+    }
+};
@@ -2,6 +2,7 @@
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
@@ -10,31 +11,207 @@ use bootloader::{entry_point, BootInfo};
 
 extern crate alloc;
 
+use core::{
+    ops::BitOr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use thiserror_no_std::Error;
+
 pub mod allocator;
+pub mod boot;
+pub mod cmdline;
+pub mod console;
+pub mod error;
 pub mod fs;
 pub mod gdt;
 pub mod interrupts;
+pub mod io;
+pub mod log;
 pub mod memory;
+pub mod panic;
+pub mod pci;
+pub mod rand;
+pub mod rtc;
+pub mod screenshot;
 pub mod serial;
+pub mod settings;
 pub mod shell;
+pub mod stack;
+pub mod statusbar;
+pub mod swap;
 pub mod task;
+pub mod timer;
+pub mod ui;
+pub mod util;
+pub mod version;
 pub mod vgabuf;
 
-pub fn init() {
-    gdt::init();
-    interrupts::init_idt();
-    unsafe {
+pub use version::version;
+
+static INIT_STATE: AtomicU8 = AtomicU8::new(InitState::Uninitialized as u8);
+static INITIALIZED: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InitState {
+    Uninitialized = 0,
+    InProgress = 1,
+    Ready = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitStatus {
+    Initialized,
+    AlreadyInitialized,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InitError {
+    #[error("initialization is already in progress")]
+    InProgress,
+    #[error("timer: {0}")]
+    Timer(#[from] timer::TimerError),
+}
+
+/// A set of kernel subsystems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitFlags(u8);
+
+impl InitFlags {
+    pub const NONE: Self = Self(0);
+    /// The consoles and log level selected on the command line.
+    pub const CONSOLES: Self = Self(1 << 0);
+    /// The GDT, IDT, PICs and timer.
+    pub const INTERRUPTS: Self = Self(1 << 1);
+    /// The scancode queue of the keyboard interrupt handler.
+    pub const KEYBOARD: Self = Self(1 << 2);
+    /// The kernel heap, set up separately by `allocator::init_heap`.
+    pub const HEAP: Self = Self(1 << 3);
+    /// The kernel page tables used by `memory::map_mmio`, set up separately by [`init_paging`].
+    pub const PAGING: Self = Self(1 << 4);
+    pub const ALL: Self = Self(Self::CONSOLES.0 | Self::INTERRUPTS.0 | Self::KEYBOARD.0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for InitFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+pub fn init_state() -> InitState {
+    match INIT_STATE.load(Ordering::SeqCst) {
+        0 => InitState::Uninitialized,
+        1 => InitState::InProgress,
+        _ => InitState::Ready,
+    }
+}
+
+/// Returns the subsystems which have been initialized.
+pub fn initialized() -> InitFlags {
+    InitFlags(INITIALIZED.load(Ordering::SeqCst))
+}
+
+/// Records that a subsystem has been initialized.
+pub(crate) fn mark_initialized(flags: InitFlags) {
+    INITIALIZED.fetch_or(flags.0, Ordering::SeqCst);
+}
+
+/// Initializes every subsystem, see [`init_with`].
+pub fn init() -> Result<InitStatus, InitError> {
+    init_with(InitFlags::ALL)
+}
+
+/// Initializes the given subsystems. Only the first call does anything, later calls return
+/// [`InitStatus::AlreadyInitialized`] even if they ask for other subsystems.
+pub fn init_with(flags: InitFlags) -> Result<InitStatus, InitError> {
+    match INIT_STATE.compare_exchange(
+        InitState::Uninitialized as u8,
+        InitState::InProgress as u8,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+    ) {
+        Ok(_) => {}
+        Err(state) if state == InitState::InProgress as u8 => return Err(InitError::InProgress),
+        Err(_) => return Ok(InitStatus::AlreadyInitialized),
+    }
+
+    stack::poison();
+    rand::seed_from_tsc();
+
+    if flags.contains(InitFlags::CONSOLES) {
+        let args = cmdline::args();
+        vgabuf::set_console(args.console);
+        log::set_level(args.loglevel);
+        task::serial::set_echo(args.serial_echo);
+        mark_initialized(InitFlags::CONSOLES);
+    }
+    if flags.contains(InitFlags::INTERRUPTS) {
+        boot::mark("gdt+idt");
+        gdt::init();
+        interrupts::init_idt();
+        boot::mark("gdt+idt");
+        boot::mark("pic");
         interrupts::PICS.lock().initialize();
+        boot::mark("pic");
+        boot::mark("pit");
+        timer::configure(timer::DEFAULT_TICK_HZ)?;
+        boot::mark("pit");
+        x86_64::instructions::interrupts::enable();
+        mark_initialized(InitFlags::INTERRUPTS);
+    }
+    if flags.contains(InitFlags::KEYBOARD) {
+        task::keyboard::init();
     }
-    x86_64::instructions::interrupts::enable();
+
+    INIT_STATE.store(InitState::Ready as u8, Ordering::SeqCst);
+    Ok(InitStatus::Initialized)
+}
+
+/// Hands the page table and frame allocator to [`memory::map_mmio`], makes sure the guard page
+/// below the kernel stack is unmapped and moves the VGA output to an uncached mapping of its own.
+/// The heap must be initialized first.
+pub fn init_paging(
+    mapper: x86_64::structures::paging::OffsetPageTable<'static>,
+    frame_allocator: memory::BootInfoFrameAllocator,
+) -> Result<(), memory::MmioError> {
+    memory::install(mapper, frame_allocator);
+    memory::unmap_guard_page(x86_64::VirtAddr::new(stack::GUARD_PAGE))?;
+    let vga = memory::map_mmio(
+        x86_64::PhysAddr::new(vgabuf::BUF_ADDR as u64),
+        vgabuf::BUF_SIZE,
+        memory::MMIO_FLAGS,
+    )?;
+    unsafe { vgabuf::attach_output(vga) };
+    mark_initialized(InitFlags::PAGING);
+    Ok(())
 }
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    boot::mark("heap");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    boot::mark("heap");
+    boot::mark("paging");
+    init_paging(mapper, frame_allocator).expect("paging initialization failed");
+    boot::mark("paging");
+
     test_main();
     hlt_loop();
 }
@@ -47,12 +224,7 @@ pub enum QemuExitCode {
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
-    }
+    io::QEMU_EXIT.write(exit_code as u32);
 }
 
 pub trait Testable {
@@ -79,8 +251,8 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &core::panic::PanicInfo) -> ! {
-    sprintln!("[failed]\n");
-    sprintln!("Error: {}\n", info);
+    panic::enter();
+    panic::emit(format_args!("[failed]\n\nError: {}\n\n", info));
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
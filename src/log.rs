@@ -0,0 +1,266 @@
+use core::{
+    fmt::{self, Write},
+    future::poll_fn,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Poll, Waker},
+};
+
+use alloc::{string::String, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::timer;
+
+/// Bytes of messages kept for `dmesg`. Once full, the oldest messages are dropped to make room.
+pub const LOG_SIZE: usize = 16 * 1024;
+/// Messages longer than this many bytes are cut off when they're kept.
+const MAX_MESSAGE: usize = 512;
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static MESSAGES: Mutex<LogRing<LOG_SIZE>> = Mutex::new(LogRing::new());
+/// The tasks waiting in [`wait_for_messages`], at most one waker for each.
+static WAITERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl LogLevel {
+    pub fn from_u8(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(Self::Error),
+            1 => Some(Self::Warn),
+            2 => Some(Self::Info),
+            3 => Some(Self::Debug),
+            4 => Some(Self::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARNING",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        }
+    }
+}
+
+/// Sets the most verbose level of messages which are printed.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Applies the `loglevel` setting, the number of a level from 0 to 4. Registered with
+/// [`settings::register_hook`](crate::settings::register_hook), unsetting it goes back to `INFO`.
+pub fn apply_setting(key: &str, value: Option<&str>) {
+    if key != "loglevel" {
+        return;
+    }
+    let Some(value) = value else {
+        return set_level(LogLevel::Info);
+    };
+    match value.parse().ok().and_then(LogLevel::from_u8) {
+        Some(level) => set_level(level),
+        None => crate::log!(
+            LogLevel::Warn,
+            "settings: loglevel {:?} isn't from 0 to 4",
+            value
+        ),
+    }
+}
+
+pub fn level() -> LogLevel {
+    LogLevel::from_u8(LEVEL.load(Ordering::Relaxed)).unwrap()
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level <= self::level()
+}
+
+/// The last messages logged, one per line. Positions count the bytes logged since boot, so a
+/// reader can ask for what was logged after the last message it saw.
+struct LogRing<const N: usize> {
+    bytes: [u8; N],
+    /// The position of the oldest byte kept.
+    start: u64,
+    /// The position after the newest byte.
+    end: u64,
+}
+
+impl<const N: usize> LogRing<N> {
+    const fn new() -> Self {
+        // Otherwise a message could push out the start of itself
+        const { assert!(N > MAX_MESSAGE) };
+        Self {
+            bytes: [0; N],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn byte(&self, pos: u64) -> u8 {
+        self.bytes[(pos % N as u64) as usize]
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.end - self.start == N as u64 {
+            self.drop_line();
+        }
+        self.bytes[(self.end % N as u64) as usize] = byte;
+        self.end += 1;
+    }
+
+    /// Drops the oldest line.
+    fn drop_line(&mut self) {
+        while self.start < self.end {
+            self.start += 1;
+            if self.byte(self.start - 1) == b'\n' {
+                return;
+            }
+        }
+    }
+
+    /// Keeps a message as a line, cut off after [`MAX_MESSAGE`] bytes.
+    fn push_message(&mut self, args: fmt::Arguments) {
+        let mut message = Message { ring: self, len: 0 };
+        let _ = message.write_fmt(args);
+        self.push(b'\n');
+    }
+
+    /// Returns the lines kept from position `pos` on, or all of them if `pos` was dropped
+    /// already, along with the position after them.
+    fn since(&self, pos: u64) -> (String, u64) {
+        let bytes = (pos.max(self.start)..self.end)
+            .map(|pos| self.byte(pos))
+            .collect::<Vec<_>>();
+        // A message may have been cut off in the middle of a character
+        (String::from_utf8_lossy(&bytes).into_owned(), self.end)
+    }
+}
+
+/// Writes a message into a [`LogRing`], up to [`MAX_MESSAGE`] bytes of it.
+struct Message<'a, const N: usize> {
+    ring: &'a mut LogRing<N>,
+    len: usize,
+}
+
+impl<const N: usize> fmt::Write for Message<'_, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes().iter().take(MAX_MESSAGE - self.len) {
+            // A newline would split the message over several lines
+            self.ring.push(if byte == b'\n' { b' ' } else { byte });
+        }
+        self.len = MAX_MESSAGE.min(self.len + s.len());
+        Ok(())
+    }
+}
+
+/// Prints a message prefixed with its level and keeps it for `dmesg` with the time since boot, if
+/// the level is enabled. Called by [`log!`].
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let millis = timer::millis();
+    interrupts::without_interrupts(|| {
+        MESSAGES.lock().push_message(format_args!(
+            "[{:>5}.{:03}] {}: {}",
+            millis / 1000,
+            millis % 1000,
+            level.name(),
+            args
+        ));
+        // Drained rather than taken, so that logging from a handler doesn't free memory
+        WAITERS.lock().drain(..).for_each(Waker::wake);
+    });
+    crate::println!("{}: {}", level.name(), args);
+}
+
+/// Returns the messages logged from position `pos` on, one per line, along with the position
+/// after them. Messages which were dropped to make room are left out, so `messages_since(0)`
+/// returns every message kept.
+pub fn messages_since(pos: u64) -> (String, u64) {
+    interrupts::without_interrupts(|| MESSAGES.lock().since(pos))
+}
+
+/// Waits until a message is logged at position `pos` or later, see [`messages_since`].
+pub async fn wait_for_messages(pos: u64) {
+    poll_fn(|cx| {
+        interrupts::without_interrupts(|| {
+            if MESSAGES.lock().end > pos {
+                return Poll::Ready(());
+            }
+            let mut waiters = WAITERS.lock();
+            if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    })
+    .await
+}
+
+/// Prints a message prefixed with its level and keeps it for `dmesg`, if the level is enabled.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::_log($level, format_args!($($arg)*))
+    };
+}
+
+#[test_case]
+fn test_log_ring_drops_oldest_lines() {
+    let mut ring = LogRing::<{ MAX_MESSAGE + 16 }>::new();
+    ring.push_message(format_args!("first"));
+    ring.push_message(format_args!("two\nlines"));
+    assert_eq!(ring.since(0), (String::from("first\ntwo lines\n"), 16));
+    assert_eq!(ring.since(6), (String::from("two lines\n"), 16));
+
+    // A long message is cut off, and only pushes out the lines it needs room for
+    let long = "x".repeat(MAX_MESSAGE + 10);
+    ring.push_message(format_args!("{}", long));
+    let (text, end) = ring.since(0);
+    assert_eq!(end, 16 + MAX_MESSAGE as u64 + 1);
+    assert_eq!(
+        text,
+        alloc::format!("two lines\n{}\n", &long[..MAX_MESSAGE])
+    );
+    // Positions which were dropped start from the oldest line kept
+    assert_eq!(ring.since(3).0, text);
+}
+
+#[test_case]
+fn test_wait_for_messages() {
+    use crate::task::{executor::Executor, Task};
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    let (_, pos) = messages_since(0);
+    let mut executor = Executor::new();
+    let done = Arc::new(AtomicBool::new(false));
+    executor.spawn(Task::new({
+        let done = done.clone();
+        async move {
+            wait_for_messages(pos).await;
+            done.store(true, Ordering::SeqCst);
+        }
+    }));
+    executor.run_ready_tasks();
+    assert!(!done.load(Ordering::SeqCst));
+
+    crate::log!(LogLevel::Error, "woken by {}", "this");
+    executor.run_ready_tasks();
+    assert!(done.load(Ordering::SeqCst));
+    let (text, _) = messages_since(pos);
+    assert!(text.starts_with('[') && text.ends_with("] ERROR: woken by this\n"));
+}
@@ -6,38 +6,141 @@
 
 extern crate alloc;
 
-use core::panic::PanicInfo;
+use core::{cell::RefCell, panic::PanicInfo};
 
+use alloc::{boxed::Box, rc::Rc};
 use bootloader::{entry_point, BootInfo};
 use hannos::{
-    allocator,
+    allocator, boot, cmdline, console,
+    fs::{
+        self,
+        ata::AtaDisk,
+        disk::{self, Disk},
+    },
+    log,
     memory::{self, BootInfoFrameAllocator},
-    println,
-    shell::Shell,
-    task::{executor::Executor, keyboard::process_keypresses, Task},
+    print_warn, println,
+    serial::{self, drain_output},
+    settings,
+    shell::{keep_prompt_current, terminal::SerialTerminal, Shell},
+    stack, statusbar, swap,
+    task::{
+        self, deferred,
+        executor::Executor,
+        keyboard::{process_keypresses, route_keypresses, update_leds},
+        serial::process_serial_input,
+        trace, Priority, Task,
+    },
+    ui,
 };
 use x86_64::VirtAddr;
 
 entry_point!(kernel_main);
 
+/// The size of the simulated swap device in blocks. Like the disk it lives on the heap, so it only
+/// relieves the heap until real disks are supported.
+const SWAP_BLOCKS: usize = 64;
+
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    hannos::init();
+    hannos::init().expect("initialization failed");
+    let args = cmdline::args();
 
     let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_memory_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
+    allocator::select(args.allocator);
+    boot::mark("heap");
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    boot::mark("heap");
+    boot::mark("paging");
+    hannos::init_paging(mapper, frame_allocator).expect("paging initialization failed");
+    boot::mark("paging");
+    // A damaged disk shouldn't keep the kernel from booting, the shell can recover it
+    boot::mark("fs");
+    if let Some(drive) = args.disk {
+        match AtaDisk::open(drive) {
+            Ok(ata) => drop(disk::attach(Box::new(ata))),
+            Err(err) => print_warn!(
+                "using the simulated disk, {} can't be used: {}",
+                drive.name(),
+                err
+            ),
+        }
+    }
+    if let Err(err) = fs::mount_root() {
+        print_warn!("mounting the filesystem failed: {}", err);
+        print_warn!("no filesystem is mounted, try `fsck --repair`, `mount` or `format --force`");
+    }
+    // The caches are dropped before the heap is reported as exhausted
+    allocator::register_reclaim(fs::disk::reclaim_cache);
+    allocator::register_reclaim(fs::reclaim_inodes);
+    // The subsystems take their initial values from the saved settings
+    settings::register_hook(log::apply_setting);
+    settings::register_hook(ui::apply_setting);
+    settings::register_hook(task::serial::apply_setting);
+    settings::init();
+    boot::mark("fs");
+    boot::mark("swap");
+    swap::attach(Box::new(Disk::new(SWAP_BLOCKS))).expect("swap initialization failed");
+    boot::mark("swap");
     println!("Boot successful!");
+    hannos::version::print_banner();
 
     #[cfg(test)]
     test_main();
 
+    boot::mark("executor");
     let mut exec = Executor::new();
-    let mut shell = Shell::new();
-    exec.spawn(Task::new(process_keypresses(move |key| {
-        shell.handle_keypress(key)
-    })));
+    exec.set_tracer(&trace::KERNEL);
+    let shell = Rc::new(RefCell::new(Shell::new()));
+    let serial_shell = Rc::new(RefCell::new(Shell::with_terminal(SerialTerminal::new())));
+    statusbar::enable();
+    shell.borrow_mut().run_rc();
+    if let Some(command) = args.init {
+        shell.borrow_mut().execute(command);
+    }
+    shell.borrow_mut().set_spawner(exec.spawner());
+    serial_shell.borrow_mut().set_spawner(exec.spawner());
+    // Spawned first, so the work queue is set up before the other tasks submit to it
+    exec.spawn(Task::with_priority(deferred::run(), Priority::High));
+    exec.spawn(Task::with_priority(route_keypresses(), Priority::High));
+    exec.spawn(Task::new(update_leds()));
+    exec.spawn(Task::with_priority(statusbar::run(), Priority::Low));
+    exec.spawn(Task::with_priority(console::run_log_pane(), Priority::Low));
+    exec.spawn(Task::with_priority(stack::watch(), Priority::Low));
+    exec.spawn(Task::with_priority(drain_output(), Priority::Low));
+    exec.spawn(Task::with_priority(allocator::scrub::run(), Priority::Low));
+    allocator::register_reclaim(serial::reclaim_output);
+    exec.spawn(Task::with_priority(
+        keep_prompt_current(shell.clone()),
+        Priority::Low,
+    ));
+    exec.spawn(Task::with_priority(
+        keep_prompt_current(serial_shell.clone()),
+        Priority::Low,
+    ));
+    exec.spawn(Task::with_priority(
+        process_keypresses(move |key, modifiers| {
+            shell.borrow_mut().handle_keypress(key, modifiers)
+        }),
+        Priority::High,
+    ));
+    exec.spawn(Task::with_priority(
+        process_serial_input(move |key, modifiers| {
+            serial_shell.borrow_mut().handle_keypress(key, modifiers)
+        }),
+        Priority::High,
+    ));
+    boot::mark("executor");
+    boot::finish();
+    exec.spawn(Task::with_priority(
+        async {
+            boot::calibrate().await;
+            println!("{}", boot::Summary);
+        },
+        Priority::Low,
+    ));
     exec.run();
 }
 
@@ -45,7 +148,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    hannos::println!("{}", info);
+    hannos::panic::report(info);
     hannos::hlt_loop();
 }
 
@@ -1,10 +1,57 @@
+use alloc::vec::Vec;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use thiserror_no_std::Error;
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+/// Virtual addresses handed out by `map_mmio`.
+pub const MMIO_START: u64 = 0x_5555_5555_0000;
+pub const MMIO_SIZE: u64 = 256 * Size4KiB::SIZE;
+
+/// Flags for device memory, which must not be cached since the device reads and writes it
+/// behind the CPU's back.
+pub const MMIO_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_CACHE);
+
+static KERNEL_PAGING: Mutex<Option<KernelPaging>> = Mutex::new(None);
+
+struct KernelPaging {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+    next_mmio: u64,
+    // Physical ranges (start, end exclusive) mapped by `map_mmio`
+    mmio_regions: Vec<(u64, u64)>,
+}
+
+#[derive(Error, Debug)]
+pub enum MmioError {
+    #[error("kernel paging has not been installed")]
+    NoPaging,
+    #[error("cannot map an empty range")]
+    Empty,
+    #[error("physical range at {0:#x} is already mapped")]
+    Overlap(u64),
+    #[error("out of virtual address space for device memory")]
+    OutOfSpace,
+    #[error("mapping failed: {0:?}")]
+    Map(MapToError<Size4KiB>),
+    #[error("unmapping failed: {0:?}")]
+    Unmap(UnmapError),
+    #[error("out of physical memory")]
+    NoFrames,
+    #[error("frame at {0:#x} is out of reach of 32-bit DMA")]
+    AboveDmaLimit(u64),
+}
+
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
@@ -35,6 +82,21 @@ impl BootInfoFrameAllocator {
         // create `PhysFrame` types from the start addresses
         frame_addrs.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Returns the number of usable frames which haven't been handed out yet.
+    pub fn free_frames(&self) -> usize {
+        self.usable_frames().count().saturating_sub(self.next)
+    }
+
+    /// Returns the end of the highest region in the memory map, which is where the mapping of all
+    /// physical memory ends relative to its offset.
+    pub fn phys_end(&self) -> u64 {
+        self.memory_map
+            .iter()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0)
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -67,3 +129,124 @@ fn active_lvl4_table_inner(phys_memory_offset: VirtAddr) -> &'static mut PageTab
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
     unsafe { &mut *page_table_ptr }
 }
+
+/// Hands the kernel page table and frame allocator over to `map_mmio`, once the heap is set up.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *KERNEL_PAGING.lock() = Some(KernelPaging {
+        mapper,
+        frame_allocator,
+        next_mmio: MMIO_START,
+        mmio_regions: Vec::new(),
+    });
+}
+
+/// Maps `size` bytes of device memory at `phys` to a fresh virtual address and returns it,
+/// always adding `PRESENT` to `flags`. A range is only mapped once, mapping a range that
+/// overlaps an earlier one fails with [`MmioError::Overlap`].
+pub fn map_mmio(phys: PhysAddr, size: usize, flags: PageTableFlags) -> Result<VirtAddr, MmioError> {
+    if size == 0 {
+        return Err(MmioError::Empty);
+    }
+    let mut guard = KERNEL_PAGING.lock();
+    let paging = guard.as_mut().ok_or(MmioError::NoPaging)?;
+
+    let start = phys.align_down(Size4KiB::SIZE).as_u64();
+    let end = (phys + size as u64).align_up(Size4KiB::SIZE).as_u64();
+    if let Some(&(overlap, _)) = paging
+        .mmio_regions
+        .iter()
+        .find(|&&(s, e)| start < e && s < end)
+    {
+        return Err(MmioError::Overlap(overlap));
+    }
+    let virt = paging.next_mmio;
+    if virt + (end - start) > MMIO_START + MMIO_SIZE {
+        return Err(MmioError::OutOfSpace);
+    }
+
+    for offset in (0..end - start).step_by(Size4KiB::SIZE as usize) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt + offset));
+        let frame = PhysFrame::containing_address(PhysAddr::new(start + offset));
+        unsafe {
+            paging
+                .mapper
+                .map_to(
+                    page,
+                    frame,
+                    flags | PageTableFlags::PRESENT,
+                    &mut paging.frame_allocator,
+                )
+                .map_err(MmioError::Map)?
+                .flush();
+        }
+    }
+    paging.next_mmio += end - start;
+    paging.mmio_regions.push((start, end));
+    Ok(VirtAddr::new(virt + (phys.as_u64() - start)))
+}
+
+/// Allocates a frame for a device to transfer data to and from, and maps it like device memory.
+/// Returns its physical address, for the device, and its virtual one. Devices only take 32-bit
+/// addresses, so frames above 4 GiB are refused.
+pub fn alloc_dma_frame() -> Result<(PhysAddr, VirtAddr), MmioError> {
+    let frame = KERNEL_PAGING
+        .lock()
+        .as_mut()
+        .ok_or(MmioError::NoPaging)?
+        .frame_allocator
+        .allocate_frame()
+        .ok_or(MmioError::NoFrames)?;
+    let phys = frame.start_address();
+    if phys.as_u64() + Size4KiB::SIZE > 1 << 32 {
+        return Err(MmioError::AboveDmaLimit(phys.as_u64()));
+    }
+    let virt = map_mmio(phys, Size4KiB::SIZE as usize, MMIO_FLAGS)?;
+    Ok((phys, virt))
+}
+
+/// Makes sure the page containing `addr` is not mapped, so that any access to it faults.
+pub fn unmap_guard_page(addr: VirtAddr) -> Result<(), MmioError> {
+    let mut guard = KERNEL_PAGING.lock();
+    let paging = guard.as_mut().ok_or(MmioError::NoPaging)?;
+    match paging
+        .mapper
+        .unmap(Page::<Size4KiB>::containing_address(addr))
+    {
+        Ok((_, flush)) => flush.flush(),
+        Err(UnmapError::PageNotMapped) => {}
+        Err(err) => return Err(MmioError::Unmap(err)),
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_map_mmio_twice() {
+    use x86_64::structures::paging::Translate;
+
+    let frame = KERNEL_PAGING
+        .lock()
+        .as_mut()
+        .expect("paging is installed")
+        .frame_allocator
+        .allocate_frame()
+        .expect("a free frame");
+    let phys = frame.start_address();
+
+    let virt = map_mmio(phys, 4096, MMIO_FLAGS).expect("first mapping");
+    let translated = KERNEL_PAGING
+        .lock()
+        .as_ref()
+        .unwrap()
+        .mapper
+        .translate_addr(virt);
+    assert_eq!(translated, Some(phys));
+
+    assert!(matches!(
+        map_mmio(phys, 4096, MMIO_FLAGS),
+        Err(MmioError::Overlap(_))
+    ));
+    assert!(matches!(
+        map_mmio(phys + 0x800u64, 16, MMIO_FLAGS),
+        Err(MmioError::Overlap(_))
+    ));
+}
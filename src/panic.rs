@@ -0,0 +1,136 @@
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::{
+    serial,
+    shell::jobs,
+    ui::{self, Style},
+    vgabuf::{self, WRITER},
+};
+
+const PANIC_BUFFER_SIZE: usize = 1024;
+/// Times a lock is tried while panicking before the code which panicked is assumed to hold it.
+const LOCK_ATTEMPTS: usize = 100_000;
+
+static IN_PANIC: AtomicBool = AtomicBool::new(false);
+static PANIC_BUFFER: Mutex<PanicBuffer> = Mutex::new(PanicBuffer::new());
+
+/// A fixed size buffer which panic messages are formatted into, so that reporting a panic never
+/// touches the heap. Output that doesn't fit is truncated.
+struct PanicBuffer {
+    buf: [u8; PANIC_BUFFER_SIZE],
+    len: usize,
+}
+
+impl PanicBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; PANIC_BUFFER_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole `str`s are copied in, unless truncated in the middle of a character
+        match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(s) => s,
+            Err(err) => unsafe { core::str::from_utf8_unchecked(&self.buf[..err.valid_up_to()]) },
+        }
+    }
+}
+
+impl Write for PanicBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(PANIC_BUFFER_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        if len < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returns `true` if the kernel is panicking. While set, the global allocator fails every
+/// allocation instead of risking recursing into a corrupted heap.
+pub fn in_panic() -> bool {
+    IN_PANIC.load(Ordering::SeqCst)
+}
+
+/// Marks the kernel as panicking and disables interrupts. Called first thing in every panic
+/// handler.
+pub fn enter() {
+    x86_64::instructions::interrupts::disable();
+    IN_PANIC.store(true, Ordering::SeqCst);
+}
+
+/// Locks `mutex` for the panic handler. Nothing else runs while panicking, so if the lock is still
+/// held after [`LOCK_ATTEMPTS`] tries, the code which panicked holds it and will never release
+/// it, and the lock is broken. Returns whether it was broken, in which case whatever the lock
+/// protects may have been left in the middle of an update.
+pub(crate) fn lock_or_break<T>(mutex: &Mutex<T>) -> (MutexGuard<'_, T>, bool) {
+    for _ in 0..LOCK_ATTEMPTS {
+        if let Some(guard) = mutex.try_lock() {
+            return (guard, false);
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { mutex.force_unlock() };
+    (mutex.lock(), true)
+}
+
+/// Formats `args` into the static panic buffer and writes it to the serial port, without
+/// allocating or waiting on any lock held by the code that panicked.
+pub fn emit(args: fmt::Arguments) {
+    let mut buffer = match PANIC_BUFFER.try_lock() {
+        Some(buffer) => buffer,
+        None => {
+            // A panic while reporting a panic, the buffer can't be trusted
+            serial::write_str_unlocked("\nnested panic while reporting panic\n");
+            return;
+        }
+    };
+    buffer.len = 0;
+    let truncated = buffer.write_fmt(args).is_err();
+    let (color, reset) = match ui::console_colors() {
+        true => (Style::Error.color(), ui::RESET),
+        false => ("", ""),
+    };
+    serial::write_str_unlocked(color);
+    serial::write_str_unlocked(buffer.as_str());
+    if truncated {
+        serial::write_str_unlocked("... (truncated)\n");
+    }
+    serial::write_str_unlocked(reset);
+
+    // Also show the message on screen. A panic while printing leaves the writer locked, and
+    // possibly halfway through scrolling, so the screen is cleared before the message then
+    let (mut writer, broken) = lock_or_break(&WRITER);
+    if broken {
+        writer.mark_inconsistent();
+    }
+    writer.write_str(color);
+    writer.write_str(buffer.as_str());
+    writer.write_str(reset);
+    writer.flush();
+}
+
+/// Reports a panic through the non-allocating output path, naming the shell job which was running
+/// if there was one.
+pub fn report(info: &PanicInfo) {
+    enter();
+    // Leave a full-screen app, so the message shows on the console
+    vgabuf::restore_console();
+    emit(format_args!("{}\n{}", info, jobs::PanickingJob));
+}
+
+/// Calls `f` with the last message emitted through the panic buffer, if the buffer is not in use.
+pub fn with_last_message<R>(f: impl FnOnce(&str) -> R) -> Option<R> {
+    PANIC_BUFFER.try_lock().map(|buffer| f(buffer.as_str()))
+}
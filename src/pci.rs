@@ -0,0 +1,159 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::io::{PCI_CONFIG_ADDRESS, PCI_CONFIG_DATA};
+
+// Each function of a PCI device has 256 bytes of configuration registers, read and written 4 bytes
+// at a time by selecting [ENABLE (1 bit), RESERVED (7 bits), BUS (8 bits), DEVICE (5 bits),
+// FUNCTION (3 bits), OFFSET (8 bits)] in CONFIG_ADDRESS and then going through CONFIG_DATA. The
+// registers used here, all little-endian:
+// VENDOR_ID: [VENDOR (2 bytes), DEVICE (2 bytes)], a vendor of ffff meaning there's no function
+// COMMAND: [COMMAND (2 bytes), STATUS (2 bytes)]
+// CLASS: [REVISION, PROG IF, SUBCLASS, CLASS]
+// HEADER: [CACHE LINE SIZE, LATENCY TIMER, HEADER TYPE, BIST], the top bit of the header type set
+// on function 0 of a device with several functions
+// BAR0 to BAR5: the base address registers, where the device's ports or memory are
+const ENABLE: u32 = 1 << 31;
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const CLASS: u8 = 0x08;
+const HEADER: u8 = 0x0c;
+const BAR0: u8 = 0x10;
+
+const NO_VENDOR: u16 = 0xffff;
+const MULTI_FUNCTION: u32 = 0x80 << 16;
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+/// The address and data registers are used in pairs, which mustn't be interleaved. Interrupt
+/// handlers don't use them.
+static CONFIG: Mutex<()> = Mutex::new(());
+
+/// A function of a device on the PCI bus, found by [`scan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciFunction {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    /// The programming interface, telling apart functions of the same class and subclass.
+    pub prog_if: u8,
+}
+
+impl PciFunction {
+    /// Reads the identity of the function, if there is one at the address.
+    fn probe(bus: u8, device: u8, function: u8) -> Option<Self> {
+        let id = read_config(bus, device, function, VENDOR_ID);
+        if id as u16 == NO_VENDOR {
+            return None;
+        }
+        let [_, prog_if, subclass, class] = read_config(bus, device, function, CLASS).to_le_bytes();
+        Some(Self {
+            bus,
+            device,
+            function,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class,
+            subclass,
+            prog_if,
+        })
+    }
+
+    /// Reads the 4 byte register at `offset`, which is rounded down to a multiple of 4.
+    pub fn read(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn write(&self, offset: u8, value: u32) {
+        write_config(self.bus, self.device, self.function, offset, value);
+    }
+
+    /// Reads base address register `n`, from 0 to 5. The lowest bit is set for a range of I/O
+    /// ports, which start at the address with the two lowest bits cleared.
+    pub fn bar(&self, n: u8) -> u32 {
+        assert!(n < 6, "no base address register {}", n);
+        self.read(BAR0 + 4 * n)
+    }
+
+    /// Lets the function use its I/O ports and transfer data to and from memory on its own.
+    pub fn enable_bus_master(&self) {
+        let command = self.read(COMMAND);
+        // The status half is cleared by writing ones, so it's written back as zeroes
+        let enabled = (command & 0xffff) | COMMAND_IO_SPACE | COMMAND_BUS_MASTER;
+        if enabled != command & 0xffff {
+            self.write(COMMAND, enabled);
+        }
+    }
+}
+
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    ENABLE
+        | u32::from(bus) << 16
+        | u32::from(device) << 11
+        | u32::from(function) << 8
+        | u32::from(offset & 0xfc)
+}
+
+fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let _config = CONFIG.lock();
+    PCI_CONFIG_ADDRESS.write(config_address(bus, device, function, offset));
+    PCI_CONFIG_DATA.read()
+}
+
+fn write_config(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    let _config = CONFIG.lock();
+    PCI_CONFIG_ADDRESS.write(config_address(bus, device, function, offset));
+    PCI_CONFIG_DATA.write(value);
+}
+
+/// Returns every function on the PCI bus, trying each bus and device number in turn. Only devices
+/// with several functions are asked for more than the first.
+pub fn scan() -> Vec<PciFunction> {
+    let mut functions = Vec::new();
+    for bus in 0..=u8::MAX {
+        for device in 0..DEVICES_PER_BUS {
+            let Some(first) = PciFunction::probe(bus, device, 0) else {
+                continue;
+            };
+            functions.push(first);
+            if first.read(HEADER) & MULTI_FUNCTION != 0 {
+                for function in 1..FUNCTIONS_PER_DEVICE {
+                    functions.extend(PciFunction::probe(bus, device, function));
+                }
+            }
+        }
+    }
+    functions
+}
+
+/// Returns the first function of the class and subclass, like 0x01 and 0x01 for an IDE
+/// controller.
+pub fn find(class: u8, subclass: u8) -> Option<PciFunction> {
+    scan()
+        .into_iter()
+        .find(|function| (function.class, function.subclass) == (class, subclass))
+}
+
+#[test_case]
+fn test_config_address() {
+    assert_eq!(config_address(0, 0, 0, 0), 0x8000_0000);
+    assert_eq!(config_address(1, 2, 3, 0x13), 0x8001_1310);
+    assert_eq!(config_address(0xff, 31, 7, 0xfc), 0x80ff_fffc);
+}
+
+#[test_case]
+fn test_scan_finds_qemu_devices() {
+    // QEMU's PC has a host bridge at 00:00.0 and an IDE controller on its ISA bridge
+    let functions = scan();
+    let bridge = functions.first().expect("a host bridge");
+    assert_eq!((bridge.bus, bridge.device, bridge.function), (0, 0, 0));
+    assert_eq!((bridge.class, bridge.subclass), (0x06, 0x00));
+    let ide = find(0x01, 0x01).expect("an IDE controller");
+    assert!(functions.contains(&ide));
+}
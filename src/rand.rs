@@ -0,0 +1,191 @@
+use core::{fmt::Debug, ops::Range};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The generator behind the global functions, seeded from the time stamp counter at boot.
+static RNG: Mutex<Rng> = Mutex::new(Rng::new(0));
+
+/// A xoshiro256** pseudo-random number generator. It's fast and passes the usual statistical
+/// tests, but its output can be predicted from a few values, so it's no good for secrets.
+///
+/// The global functions of this module share a generator behind a lock. Code drawing a lot of
+/// numbers in a loop can take a generator of its own with [`rng`] instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rng {
+    state: [u64; 4],
+}
+
+impl Rng {
+    /// Creates a generator which always produces the same numbers for the same `seed`. The seed is
+    /// spread over the state with SplitMix64, so that similar seeds give unrelated numbers.
+    pub const fn new(seed: u64) -> Self {
+        let mut state = [0; 4];
+        let mut x = seed;
+        let mut i = 0;
+        while i < state.len() {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            state[i] = z ^ (z >> 31);
+            i += 1;
+        }
+        Self { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    /// Returns the upper half of the next `u64`, which are its best bits.
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Returns a number in `range`, every one of them equally likely. Panics if the range is
+    /// empty.
+    pub fn gen_range<T: Uniform>(&mut self, range: Range<T>) -> T {
+        assert!(
+            range.start < range.end,
+            "gen_range called with the empty range {:?}",
+            range
+        );
+        let (start, span) = (
+            range.start.to_u64(),
+            range.end.to_u64() - range.start.to_u64(),
+        );
+        // Taking the remainder of any number would favour the low values when the span doesn't
+        // divide 2^64, so the numbers below 2^64 % span, which make up the extra, are drawn again
+        let threshold = span.wrapping_neg() % span;
+        loop {
+            let x = self.next_u64();
+            if x >= threshold {
+                return T::from_u64(start + x % span);
+            }
+        }
+    }
+
+    pub fn fill_bytes(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+}
+
+/// The unsigned integers [`Rng::gen_range`] can draw.
+pub trait Uniform: Copy + PartialOrd + Debug {
+    fn to_u64(self) -> u64;
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! uniform {
+    ($($t:ty),*) => {
+        $(
+            impl Uniform for $t {
+                fn to_u64(self) -> u64 {
+                    self as u64
+                }
+
+                fn from_u64(value: u64) -> Self {
+                    value as $t
+                }
+            }
+        )*
+    };
+}
+
+uniform!(u8, u16, u32, u64, usize);
+
+fn with_rng<R>(f: impl FnOnce(&mut Rng) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut RNG.lock()))
+}
+
+/// Seeds the global generator, so that the numbers which follow are the same every time. For
+/// tests which need to be reproducible.
+pub fn seed(seed: u64) {
+    with_rng(|rng| *rng = Rng::new(seed));
+}
+
+/// Seeds the global generator from the time stamp counter, so that every boot gets different
+/// numbers. Called at boot.
+pub fn seed_from_tsc() {
+    seed(unsafe { core::arch::x86_64::_rdtsc() });
+}
+
+pub fn next_u32() -> u32 {
+    with_rng(Rng::next_u32)
+}
+
+pub fn next_u64() -> u64 {
+    with_rng(Rng::next_u64)
+}
+
+/// Returns a number in `range` from the global generator, see [`Rng::gen_range`].
+pub fn gen_range<T: Uniform>(range: Range<T>) -> T {
+    with_rng(|rng| rng.gen_range(range))
+}
+
+pub fn fill_bytes(bytes: &mut [u8]) {
+    with_rng(|rng| rng.fill_bytes(bytes));
+}
+
+/// Returns a generator of its own, seeded from the global one, for drawing many numbers without
+/// taking the lock for each.
+pub fn rng() -> Rng {
+    Rng::new(next_u64())
+}
+
+#[test_case]
+fn test_known_output() {
+    let mut rng = Rng::new(42);
+    assert_eq!(rng.next_u64(), 0x1578_0b2e_0c2e_c716);
+    assert_eq!(rng.next_u64(), 0x6104_d986_6d11_3a7e);
+    assert_eq!(rng.next_u32(), 0xae17_5332);
+    let mut rng = Rng::new(0);
+    assert_eq!(rng.next_u64(), 0x99ec_5f36_cb75_f2b4);
+
+    let mut bytes = [0; 11];
+    Rng::new(42).fill_bytes(&mut bytes);
+    assert_eq!(bytes, [22, 199, 46, 12, 46, 11, 120, 21, 126, 58, 17]);
+
+    // The global generator repeats itself when seeded again
+    seed(7);
+    let first = [next_u64(), next_u64()];
+    seed(7);
+    assert_eq!([next_u64(), next_u64()], first);
+    seed_from_tsc();
+}
+
+#[test_case]
+fn test_gen_range() {
+    let mut rng = Rng::new(1);
+    for _ in 0..1000 {
+        let n = rng.gen_range(10..13u8);
+        assert!((10..13).contains(&n), "{}", n);
+    }
+    assert_eq!(rng.gen_range(5..6usize), 5);
+    let big = rng.gen_range(u64::MAX - 3..u64::MAX);
+    assert!((u64::MAX - 3..u64::MAX).contains(&big));
+
+    // Rolling a die 6000 times gives each face about a thousand times
+    let mut faces = [0; 6];
+    for _ in 0..6000 {
+        faces[rng.gen_range(0..6usize)] += 1;
+    }
+    assert!(
+        faces.iter().all(|&n| (850..1150).contains(&n)),
+        "{:?}",
+        faces
+    );
+}
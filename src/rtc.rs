@@ -0,0 +1,143 @@
+use core::fmt::{self, Display};
+
+use x86_64::instructions::interrupts;
+
+use crate::io::{CMOS_ADDR, CMOS_DATA};
+
+// CMOS registers of the clock
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+// Status register bits
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+// Set in the hours in 12 hour mode after noon
+const PM: u8 = 1 << 7;
+
+/// Reads of the clock which disagree before giving up and using the last one, which only happens
+/// if something keeps the clock from being read for seconds at a time.
+const MAX_READS: usize = 8;
+
+/// A date and time as kept by the real-time clock, in whatever timezone it was set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Decodes the clock registers, read in the order `SECONDS`, `MINUTES`, `HOURS`, `DAY`,
+    /// `MONTH` and `YEAR`, using the format `status_b` says they're in. The clock only keeps two
+    /// digits of the year, which are taken to be in this century.
+    fn decode(registers: [u8; 6], status_b: u8) -> Self {
+        let [second, minute, hour, day, month, year] = registers;
+        let value = |raw: u8| match status_b & BINARY {
+            0 => (raw >> 4) * 10 + (raw & 0x0f),
+            _ => raw,
+        };
+        let mut hour_24 = value(hour & !PM);
+        if status_b & HOURS_24 == 0 {
+            // 12 AM is midnight and 12 PM is noon
+            hour_24 %= 12;
+            if hour & PM != 0 {
+                hour_24 += 12;
+            }
+        }
+        Self {
+            year: 2000 + u16::from(value(year)),
+            month: value(month),
+            day: value(day),
+            hour: hour_24,
+            minute: value(minute),
+            second: value(second),
+        }
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    CMOS_ADDR.write(register);
+    CMOS_DATA.read()
+}
+
+/// Reads the clock registers once the clock isn't updating them.
+fn read_registers() -> [u8; 6] {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(read_register)
+}
+
+/// Returns the date and time from the real-time clock.
+pub fn now() -> DateTime {
+    interrupts::without_interrupts(|| {
+        // An update may start right after the check, so the registers are read until two reads
+        // in a row agree
+        let mut registers = read_registers();
+        for _ in 0..MAX_READS {
+            let again = read_registers();
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        DateTime::decode(registers, read_register(STATUS_B))
+    })
+}
+
+#[test_case]
+fn test_decode() {
+    use alloc::string::ToString;
+
+    // 2026-10-16 23:59:07 in BCD, 24 hour mode
+    let bcd = [0x07, 0x59, 0x23, 0x16, 0x10, 0x26];
+    let time = DateTime::decode(bcd, HOURS_24);
+    assert_eq!(time.to_string(), "2026-10-16 23:59:07");
+    assert_eq!(
+        DateTime::decode([7, 59, 23, 16, 10, 26], HOURS_24 | BINARY),
+        time
+    );
+
+    // 12 hour mode, with 12 AM being midnight
+    let hours = |raw: u8| DateTime::decode([0, 0, raw, 1, 1, 0], 0).hour;
+    assert_eq!(hours(0x12), 0);
+    assert_eq!(hours(0x01), 1);
+    assert_eq!(hours(PM | 0x12), 12);
+    assert_eq!(hours(PM | 0x11), 23);
+}
+
+#[test_case]
+fn test_read_clock() {
+    let time = now();
+    assert!(time.year >= 2024, "{}", time);
+    assert!((1..=12).contains(&time.month), "{}", time);
+    assert!((1..=31).contains(&time.day), "{}", time);
+    assert!(
+        time.hour < 24 && time.minute < 60 && time.second < 60,
+        "{}",
+        time
+    );
+    // The clock doesn't run backwards
+    let later = now();
+    assert!(later >= time, "{} before {}", later, time);
+}
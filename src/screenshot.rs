@@ -0,0 +1,249 @@
+use alloc::{format, string::String, vec, vec::Vec};
+use thiserror_no_std::Error;
+
+use crate::{
+    error::{self, ErrorKind, KernelError},
+    fs::{
+        file::{FileSystemError, InodeKind},
+        vfs::VfsRouter,
+    },
+    vgabuf::{ScreenSnapshot, VGABufferEntry, VGAColor, HEIGHT, WIDTH},
+};
+
+/// Where screenshots are saved unless a path is given, numbered from `000.scr`.
+pub const SCREENSHOT_DIR: &str = "/screenshots";
+const EXTENSION: &str = ".scr";
+
+// A screenshot starts with a header
+// [MAGIC (4 bytes), WIDTH (2 bytes, LE), HEIGHT (2 bytes, LE), CURSOR_ROW (2 bytes, LE),
+// CURSOR_COL (2 bytes, LE)],
+// followed by the glyph of every cell, row by row, and then the attribute byte of every cell in
+// the same order.
+const MAGIC: [u8; 4] = *b"HSCR";
+const HEADER_SIZE: usize = 12;
+/// The size of a screenshot of the whole screen.
+pub const SCREENSHOT_SIZE: usize = HEADER_SIZE + 2 * WIDTH * HEIGHT;
+
+#[derive(Error, Debug)]
+pub enum ScreenshotError {
+    #[error("not a screenshot")]
+    NotAScreenshot,
+    #[error("the screenshot is {width}x{height}, the screen is {WIDTH}x{HEIGHT}")]
+    WrongSize { width: usize, height: usize },
+    #[error("the screenshot is {0} bytes, expected {SCREENSHOT_SIZE}")]
+    Truncated(usize),
+    #[error("the cursor at row {row}, column {col} is off the screen")]
+    CursorOffScreen { row: usize, col: usize },
+}
+
+impl error::Error for ScreenshotError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::WrongSize { .. } => ErrorKind::Unsupported,
+            _ => ErrorKind::Corrupt,
+        }
+    }
+}
+
+/// Returns the bytes of a screenshot of `screen`, every row including the status bar.
+pub fn encode(screen: &ScreenSnapshot) -> Vec<u8> {
+    let (row, col) = screen.cursor();
+    let mut bytes = Vec::with_capacity(SCREENSHOT_SIZE);
+    bytes.extend_from_slice(&MAGIC);
+    for field in [WIDTH, HEIGHT, row, col] {
+        bytes.extend_from_slice(&(field as u16).to_le_bytes());
+    }
+    let cells = screen.cells().iter().flatten();
+    bytes.extend(cells.clone().map(|entry| entry.ascii_char));
+    bytes.extend(cells.map(|entry| entry.color.attribute()));
+    bytes
+}
+
+/// Parses a screenshot written by [`encode`].
+pub fn decode(bytes: &[u8]) -> Result<ScreenSnapshot, ScreenshotError> {
+    if bytes.len() < HEADER_SIZE || bytes[..4] != MAGIC {
+        return Err(ScreenshotError::NotAScreenshot);
+    }
+    let field = |i: usize| u16::from_le_bytes([bytes[4 + 2 * i], bytes[5 + 2 * i]]) as usize;
+    let (width, height, row, col) = (field(0), field(1), field(2), field(3));
+    if (width, height) != (WIDTH, HEIGHT) {
+        return Err(ScreenshotError::WrongSize { width, height });
+    }
+    if bytes.len() != SCREENSHOT_SIZE {
+        return Err(ScreenshotError::Truncated(bytes.len()));
+    }
+    // The column is past the last one after a full row was written
+    if row >= HEIGHT || col > WIDTH {
+        return Err(ScreenshotError::CursorOffScreen { row, col });
+    }
+
+    let (glyphs, attributes) = bytes[HEADER_SIZE..].split_at(WIDTH * HEIGHT);
+    let cells = core::array::from_fn(|row| {
+        core::array::from_fn(|col| VGABufferEntry {
+            ascii_char: glyphs[row * WIDTH + col],
+            color: VGAColor::from_attribute(attributes[row * WIDTH + col]),
+        })
+    });
+    Ok(ScreenSnapshot::new(cells, (row, col)))
+}
+
+/// Saves a screenshot of `screen` at `path`, or at the next numbered path in [`SCREENSHOT_DIR`]
+/// if none is given, returning the path. The file is written under a temporary name first and
+/// only renamed once all of it was written, so a full disk leaves no partial screenshot behind.
+pub fn save(
+    vfs: &mut VfsRouter,
+    screen: &ScreenSnapshot,
+    path: Option<&str>,
+) -> Result<String, KernelError> {
+    let path = match path {
+        Some(path) => String::from(path),
+        None => {
+            vfs.create_dir_all(SCREENSHOT_DIR)?;
+            next_path(vfs)?
+        }
+    };
+    if vfs.stat(&path).is_ok() {
+        return Err(FileSystemError::AlreadyExists(path).into());
+    }
+
+    let temp = format!("{}.tmp", path);
+    match vfs.remove(&temp) {
+        Ok(()) | Err(FileSystemError::NotFound(_)) => {}
+        Err(err) => return Err(err.into()),
+    }
+    vfs.create(&temp, InodeKind::File)?;
+    let mut file = vfs.open(&temp)?;
+    let result = vfs
+        .write(&mut file, &encode(screen))
+        .and_then(|_| vfs.rename(&temp, &path));
+    if let Err(err) = result {
+        // Nothing is left of the failed attempt, the error is what matters
+        let _ = vfs.remove(&temp);
+        return Err(err.into());
+    }
+    Ok(path)
+}
+
+/// Reads the screenshot at `path`.
+pub fn load(vfs: &VfsRouter, path: &str) -> Result<ScreenSnapshot, KernelError> {
+    let size = vfs.stat(path)?.size;
+    if size != SCREENSHOT_SIZE {
+        return Err(ScreenshotError::Truncated(size).into());
+    }
+    let mut bytes = vec![0; size];
+    let mut file = vfs.open(path)?;
+    let mut read = 0;
+    while read < size {
+        match vfs.read(&mut file, &mut bytes[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(decode(&bytes[..read])?)
+}
+
+/// Returns the path after the highest numbered screenshot in [`SCREENSHOT_DIR`].
+fn next_path(vfs: &VfsRouter) -> Result<String, FileSystemError> {
+    let next = vfs
+        .list(SCREENSHOT_DIR)?
+        .iter()
+        .filter_map(|entry| entry.name.strip_suffix(EXTENSION)?.parse::<usize>().ok())
+        .max()
+        .map_or(0, |last| last + 1);
+    Ok(format!("{}/{:03}{}", SCREENSHOT_DIR, next, EXTENSION))
+}
+
+#[test_case]
+fn test_screenshot_round_trip() {
+    use crate::{
+        fs::VFS,
+        vgabuf::{Color, MemoryTarget, VGAWriter},
+    };
+
+    crate::fs::init().unwrap();
+    let mut writer = VGAWriter::new_with_target(MemoryTarget::new());
+    writer.render_status_bar("status", "", "bar");
+    writer.write_str("plain text\n\x1b[31mred text\x1b[0m\nunder the cursor");
+    writer.write_row_colored(
+        HEIGHT - 3,
+        &[
+            ('x', VGAColor::new(Color::Yellow, Color::Blue)),
+            ('½', VGAColor::new(Color::Pink, Color::Black)),
+        ],
+    );
+    let screen = writer.save_screen();
+
+    let mut vfs = VFS.lock();
+    assert_eq!(
+        save(&mut vfs, &screen, None).unwrap(),
+        "/screenshots/000.scr"
+    );
+    assert_eq!(
+        save(&mut vfs, &screen, None).unwrap(),
+        "/screenshots/001.scr"
+    );
+    assert_eq!(
+        vfs.stat("/screenshots/000.scr").unwrap().size,
+        SCREENSHOT_SIZE
+    );
+
+    // Every cell comes back, the status bar included, and the cursor with them
+    let loaded = load(&vfs, "/screenshots/000.scr").unwrap();
+    assert_eq!(loaded.cells(), screen.cells());
+    assert_eq!(loaded.cursor(), screen.cursor());
+    let mut shown = VGAWriter::new_with_target(MemoryTarget::new());
+    shown.restore_screen(&loaded);
+    assert_eq!(shown.target().cells(), screen.cells());
+    assert_eq!(shown.target().cursor(), (HEIGHT - 1, 16));
+
+    assert!(matches!(
+        decode(&encode(&screen)[..SCREENSHOT_SIZE - 1]),
+        Err(ScreenshotError::Truncated(_))
+    ));
+    assert!(matches!(
+        decode(b"not a screenshot"),
+        Err(ScreenshotError::NotAScreenshot)
+    ));
+    drop(vfs);
+    crate::fs::init().unwrap();
+}
+
+#[test_case]
+fn test_screenshot_on_full_disk() {
+    use crate::fs::{FILESYSTEM, VFS};
+
+    crate::fs::init().unwrap();
+    let blank = VGABufferEntry {
+        ascii_char: b' ',
+        color: VGAColor::from_attribute(0x0f),
+    };
+    let screen = ScreenSnapshot::new([[blank; WIDTH]; HEIGHT], (0, 0));
+    let mut vfs = VFS.lock();
+    vfs.create_dir_all(SCREENSHOT_DIR).unwrap();
+    // Files can't have any of the blocks left, the directory still can
+    let reserved = {
+        let mut fs = FILESYSTEM.lock();
+        let reserved = fs.reserved_blocks();
+        let free = fs.free_blocks();
+        fs.set_reserved_blocks(free);
+        reserved
+    };
+    assert!(matches!(
+        save(&mut vfs, &screen, None),
+        Err(KernelError::FileSystem(FileSystemError::NoFreeBlocks))
+    ));
+    // Neither the screenshot nor the temporary file is left
+    assert!(vfs.list(SCREENSHOT_DIR).unwrap().is_empty());
+
+    FILESYSTEM.lock().set_reserved_blocks(reserved);
+    assert_eq!(
+        save(&mut vfs, &screen, Some("/shot.scr")).unwrap(),
+        "/shot.scr"
+    );
+    assert!(matches!(
+        save(&mut vfs, &screen, Some("/shot.scr")),
+        Err(KernelError::FileSystem(FileSystemError::AlreadyExists(_)))
+    ));
+    drop(vfs);
+    crate::fs::init().unwrap();
+}
@@ -1,11 +1,48 @@
+use core::{
+    future::{poll_fn, Future},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Poll, Waker},
+};
+
+use alloc::{collections::VecDeque, vec::Vec};
+use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 use x86_64::instructions::interrupts;
 
+use crate::{
+    interrupts::{InterruptIndex, PICS},
+    io::{COM1, COM1_DATA, COM1_INTERRUPT_ENABLE, COM1_INTERRUPT_ID, COM1_LINE_STATUS},
+    task::{
+        deferred::{self, WorkItem},
+        select2,
+    },
+    timer,
+};
+
+/// The bits of the line status register which are set when a byte has been received and when the
+/// port is ready to send the next byte.
+const DATA_READY: u8 = 1;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+/// The bit of the interrupt enable register which raises an interrupt when the transmit FIFO
+/// empties.
+const TRANSMIT_EMPTY_INTERRUPT: u8 = 1 << 1;
+/// The size of the transmit FIFO, which is empty whenever the transmit empty bit is set.
+const FIFO_SIZE: usize = 16;
+/// How much output can wait to be sent before writers have to wait for room.
+const OUTPUT_CAPACITY: usize = 16 * 1024;
+
+/// Output written with [`write_async`], sent by the [`drain_output`] task.
+static OUTPUT: BufferedOutput = BufferedOutput::new(OUTPUT_CAPACITY);
+/// Whether the [`drain_output`] task is running. Until it is, output is sent right away.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+/// Woken by the interrupt raised when the first serial port has sent everything it was given.
+static TRANSMIT_WAKER: AtomicWaker = AtomicWaker::new();
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -22,6 +59,233 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Returns the next byte received on the serial port, if there is one, without blocking.
+pub fn try_receive() -> Option<u8> {
+    interrupts::without_interrupts(|| {
+        // Hold the lock so that the port is initialized and no one else touches it meanwhile
+        let _serial = SERIAL1.lock();
+        (COM1_LINE_STATUS.read() & DATA_READY != 0).then(|| COM1_DATA.read())
+    })
+}
+
+/// Sends bytes exactly as given. `SerialPort::send` turns backspace and delete into a sequence
+/// erasing a character, which would corrupt binary data.
+pub fn send_raw(bytes: &[u8]) {
+    interrupts::without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        for &byte in bytes {
+            while COM1_LINE_STATUS.read() & TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            COM1_DATA.write(byte);
+        }
+    })
+}
+
+/// Something bytes can be handed to without waiting for them to be sent.
+pub trait Transmit {
+    /// Takes as many of `bytes` as can be taken right now, returning how many it took.
+    fn transmit(&mut self, bytes: &[u8]) -> usize;
+
+    /// Waits until bytes can be taken again.
+    fn ready(&mut self) -> impl Future<Output = ()>;
+}
+
+/// The first serial port, which takes a FIFO full of bytes once the ones before have been sent.
+pub struct Com1;
+
+impl Transmit for Com1 {
+    fn transmit(&mut self, bytes: &[u8]) -> usize {
+        interrupts::without_interrupts(|| {
+            let _serial = SERIAL1.lock();
+            if COM1_LINE_STATUS.read() & TRANSMIT_EMPTY == 0 {
+                return 0;
+            }
+            let len = bytes.len().min(FIFO_SIZE);
+            for &byte in &bytes[..len] {
+                COM1_DATA.write(byte);
+            }
+            len
+        })
+    }
+
+    async fn ready(&mut self) {
+        let transmit_empty = poll_fn(|cx| {
+            TRANSMIT_WAKER.register(cx.waker());
+            let status = interrupts::without_interrupts(|| {
+                let _serial = SERIAL1.lock();
+                COM1_LINE_STATUS.read()
+            });
+            match status & TRANSMIT_EMPTY != 0 {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        });
+        // The wake from the interrupt is dropped if the deferred work queue is full, in which
+        // case the next tick looks again
+        select2(transmit_empty, timer::sleep_ticks(1)).await;
+    }
+}
+
+/// Called from the interrupt handler of the first serial port, which only raises the transmit
+/// empty interrupt, see [`drain_output`].
+pub(crate) fn handle_interrupt() {
+    COM1_INTERRUPT_ID.read();
+    let _ = deferred::submit(WorkItem::Call(wake_transmit));
+}
+
+fn wake_transmit() {
+    TRANSMIT_WAKER.wake();
+}
+
+/// Output kept in a ring buffer until it's sent, so that writing a lot of it doesn't stall
+/// everything else while the port sends it a byte at a time. Writers only wait when the buffer is
+/// full, and a task running [`run`](Self::run) sends the buffered bytes as fast as the port takes
+/// them.
+pub struct BufferedOutput {
+    state: Mutex<OutputState>,
+    /// Woken when bytes are added to an empty buffer.
+    drainer: AtomicWaker,
+}
+
+struct OutputState {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    /// Writers waiting for room, and flushes waiting for the buffer to empty.
+    waiters: Vec<Waker>,
+}
+
+impl BufferedOutput {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(OutputState {
+                buffer: VecDeque::new(),
+                capacity,
+                waiters: Vec::new(),
+            }),
+            drainer: AtomicWaker::new(),
+        }
+    }
+
+    /// Adds `bytes` to the buffer, waiting for room whenever it's full.
+    pub async fn write(&self, mut bytes: &[u8]) {
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            let len = bytes.len().min(state.capacity - state.buffer.len());
+            state.buffer.extend(&bytes[..len]);
+            bytes = &bytes[len..];
+            if len > 0 {
+                self.drainer.wake();
+            }
+            if bytes.is_empty() {
+                return Poll::Ready(());
+            }
+            state.waiters.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Waits until everything written so far has been handed to the port.
+    pub async fn flush(&self) {
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            if state.buffer.is_empty() {
+                return Poll::Ready(());
+            }
+            state.waiters.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Hands as many buffered bytes to `port` as it takes, returning how many it took.
+    pub fn drain(&self, port: &mut impl Transmit) -> usize {
+        let mut state = self.state.lock();
+        let len = port.transmit(state.buffer.as_slices().0);
+        state.buffer.drain(..len);
+        if len > 0 {
+            state.waiters.drain(..).for_each(Waker::wake);
+        }
+        len
+    }
+
+    /// Gives the memory of the buffer back to the heap if it's empty, returning the number of bytes
+    /// given back. Doesn't allocate, so it can be used to reclaim memory when the heap runs out.
+    pub fn trim(&self) -> usize {
+        let Some(mut state) = self.state.try_lock() else {
+            return 0;
+        };
+        if !state.buffer.is_empty() {
+            return 0;
+        }
+        let freed = state.buffer.capacity();
+        state.buffer = VecDeque::new();
+        freed
+    }
+
+    /// Keeps draining the buffer to `port`. Between drains this waits for the port to be ready
+    /// for more, and while the buffer is empty for something to be written.
+    pub async fn run(&self, port: &mut impl Transmit) {
+        loop {
+            self.drain(port);
+            poll_fn(|cx| {
+                self.drainer.register(cx.waker());
+                match self.state.lock().buffer.is_empty() {
+                    true => Poll::Pending,
+                    false => Poll::Ready(()),
+                }
+            })
+            .await;
+            port.ready().await;
+        }
+    }
+}
+
+/// Writes bytes exactly as given, like [`send_raw`], without waiting for them to be sent. Only
+/// waits if a lot of output is already waiting to be sent. Output printed with `sprint!` isn't
+/// buffered, so it may overtake output written here.
+pub async fn write_async(bytes: &[u8]) {
+    match DRAINING.load(Ordering::Acquire) {
+        true => OUTPUT.write(bytes).await,
+        false => send_raw(bytes),
+    }
+}
+
+/// Waits until everything written with [`write_async`] has been sent.
+pub async fn flush_async() {
+    OUTPUT.flush().await
+}
+
+/// Sends the output written with [`write_async`], woken by the interrupt raised whenever the port
+/// has sent what it was given. Received bytes are polled for, so that's the only interrupt the
+/// port raises.
+pub async fn drain_output() {
+    interrupts::without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        COM1_INTERRUPT_ENABLE.write(TRANSMIT_EMPTY_INTERRUPT);
+        PICS.lock().unmask(InterruptIndex::Com1.irq());
+    });
+    DRAINING.store(true, Ordering::Release);
+    OUTPUT.run(&mut Com1).await
+}
+
+/// Trims the buffer of [`write_async`] when the heap runs out, see [`BufferedOutput::trim`].
+/// Registered with [`allocator::register_reclaim`](crate::allocator::register_reclaim).
+pub fn reclaim_output() -> usize {
+    OUTPUT.trim()
+}
+
+/// Writes a string to the serial port, breaking the port lock if it stays locked. Only for use
+/// while panicking, as the code that panicked might be holding the lock and will never release it,
+/// see [`panic::lock_or_break`](crate::panic::lock_or_break).
+pub(crate) fn write_str_unlocked(s: &str) {
+    let (mut serial, _) = crate::panic::lock_or_break(&SERIAL1);
+    for byte in s.bytes() {
+        serial.send(byte);
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! sprint {
@@ -38,3 +302,159 @@ macro_rules! sprintln {
     ($fmt:expr, $($arg:tt)*) => ($crate::sprint!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+#[test_case]
+fn test_buffered_output() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    use crate::task::{cancel::CancellationToken, executor::Executor, yield_now, Priority};
+
+    /// A port which takes a FIFO full of bytes every time.
+    struct MockPort(Arc<Mutex<Vec<u8>>>);
+
+    impl Transmit for MockPort {
+        fn transmit(&mut self, bytes: &[u8]) -> usize {
+            let len = bytes.len().min(FIFO_SIZE);
+            self.0.lock().extend_from_slice(&bytes[..len]);
+            len
+        }
+
+        async fn ready(&mut self) {
+            yield_now().await
+        }
+    }
+
+    let data = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let output = Arc::new(BufferedOutput::new(4096));
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let done = CancellationToken::new();
+    // How often another task ran while the output was partly sent
+    let progress = Arc::new(AtomicUsize::new(0));
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let writer = {
+        let (output, data, done) = (output.clone(), data.clone(), done.clone());
+        async move {
+            output.write(&data).await;
+            output.flush().await;
+            done.cancel();
+        }
+    };
+    let drainer = {
+        let (output, mut port, done) = (output.clone(), MockPort(sent.clone()), done.clone());
+        async move {
+            select2(output.run(&mut port), done.cancelled()).await;
+        }
+    };
+    let counter = {
+        let (sent, progress, done) = (sent.clone(), progress.clone(), done.clone());
+        async move {
+            while !done.is_cancelled() {
+                if (1..64 * 1024).contains(&sent.lock().len()) {
+                    progress.fetch_add(1, Ordering::Relaxed);
+                }
+                yield_now().await;
+            }
+        }
+    };
+    let _writer = spawner.spawn(writer, Priority::Normal);
+    let _drainer = spawner.spawn(drainer, Priority::Low);
+    let _counter = spawner.spawn(counter, Priority::Normal);
+    executor.run_until_done();
+
+    assert!(*sent.lock() == data, "output sent out of order");
+    assert!(progress.load(Ordering::Relaxed) > 0);
+}
+
+#[test_case]
+fn test_drain_waits_for_port() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    use crate::task::{cancel::CancellationToken, executor::Executor, yield_now, Priority};
+
+    /// A port which is busy sending after taking a FIFO full of bytes, until `interrupt` says
+    /// it's done.
+    struct SlowPort {
+        sent: Arc<Mutex<Vec<u8>>>,
+        busy: Arc<AtomicBool>,
+        interrupt: Arc<AtomicWaker>,
+        // Bytes offered while the port was busy
+        refused: Arc<AtomicUsize>,
+    }
+
+    impl Transmit for SlowPort {
+        fn transmit(&mut self, bytes: &[u8]) -> usize {
+            if self.busy.load(Ordering::SeqCst) {
+                self.refused.fetch_add(1, Ordering::SeqCst);
+                return 0;
+            }
+            let len = bytes.len().min(FIFO_SIZE);
+            self.sent.lock().extend_from_slice(&bytes[..len]);
+            self.busy.store(true, Ordering::SeqCst);
+            len
+        }
+
+        async fn ready(&mut self) {
+            poll_fn(|cx| {
+                self.interrupt.register(cx.waker());
+                match self.busy.load(Ordering::SeqCst) {
+                    true => Poll::Pending,
+                    false => Poll::Ready(()),
+                }
+            })
+            .await
+        }
+    }
+
+    let data = (0..1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let output = Arc::new(BufferedOutput::new(256));
+    let (busy, interrupt) = (
+        Arc::new(AtomicBool::new(false)),
+        Arc::new(AtomicWaker::new()),
+    );
+    let port = SlowPort {
+        sent: Arc::new(Mutex::new(Vec::new())),
+        busy: busy.clone(),
+        interrupt: interrupt.clone(),
+        refused: Arc::new(AtomicUsize::new(0)),
+    };
+    let (sent, refused) = (port.sent.clone(), port.refused.clone());
+    let done = CancellationToken::new();
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let writer = {
+        let (output, data, done) = (output.clone(), data.clone(), done.clone());
+        async move {
+            output.write(&data).await;
+            output.flush().await;
+            done.cancel();
+        }
+    };
+    let drainer = {
+        let (output, mut port, done) = (output.clone(), port, done.clone());
+        async move {
+            select2(output.run(&mut port), done.cancelled()).await;
+        }
+    };
+    // Sending a FIFO full takes a while, during which other tasks run
+    let sender = async move {
+        while !done.is_cancelled() {
+            for _ in 0..5 {
+                yield_now().await;
+            }
+            busy.store(false, Ordering::SeqCst);
+            interrupt.wake();
+        }
+    };
+    let _writer = spawner.spawn(writer, Priority::Normal);
+    let _drainer = spawner.spawn(drainer, Priority::Low);
+    let _sender = spawner.spawn(sender, Priority::Normal);
+    executor.run_until_done();
+
+    assert!(*sent.lock() == data, "output sent out of order");
+    assert_eq!(refused.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,459 @@
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec,
+};
+use spin::Mutex;
+use thiserror_no_std::Error;
+
+use crate::{
+    error::{self, ErrorKind, KernelError},
+    fs::{
+        file::{FileSystemError, InodeKind},
+        vfs::VfsRouter,
+        VFS,
+    },
+    log,
+    log::LogLevel,
+    print_warn,
+};
+
+/// Where the settings are saved, one `key=value` per line.
+pub const SETTINGS_PATH: &str = "/etc/settings";
+const SETTINGS_DIR: &str = "/etc";
+/// New settings are written here first and only take the place of the old ones once all of them
+/// were written.
+const TEMP_PATH: &str = "/etc/settings.tmp";
+/// The most change hooks which can be registered.
+const MAX_HOOKS: usize = 8;
+
+/// Called with the key of a setting which changed and its new value, or `None` if it was unset.
+pub type ChangeHook = fn(key: &str, value: Option<&str>);
+
+static SETTINGS: Mutex<Settings> = Mutex::new(Settings::new());
+static HOOKS: Mutex<[Option<ChangeHook>; MAX_HOOKS]> = Mutex::new([None; MAX_HOOKS]);
+
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("invalid setting name {0:?}, names are made of a-z, 0-9, '.', '-' and '_'")]
+    InvalidKey(String),
+    #[error("{0}: values can't span several lines")]
+    InvalidValue(String),
+    #[error("{0}: no such setting")]
+    NotSet(String),
+}
+
+impl error::Error for SettingsError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NotSet(_) => ErrorKind::NotFound,
+            _ => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// Settings by their keys, in the order of the keys.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Settings {
+    values: BTreeMap<String, String>,
+}
+
+impl Settings {
+    pub const fn new() -> Self {
+        Self {
+            values: BTreeMap::new(),
+        }
+    }
+
+    /// Parses the lines of a settings file. Blank lines and lines starting with `#` are ignored,
+    /// other lines which aren't a valid `key=value` are skipped with a warning.
+    pub fn parse(text: &str) -> Self {
+        let mut settings = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) if check_key(key.trim()).is_ok() => {
+                    settings.insert(key.trim(), value.trim());
+                }
+                _ => log!(
+                    LogLevel::Warn,
+                    "{}: skipping line {}, not a setting: {:?}",
+                    SETTINGS_PATH,
+                    i + 1,
+                    line
+                ),
+            }
+        }
+        settings
+    }
+
+    /// Returns the settings as the lines of a settings file.
+    pub fn encode(&self) -> String {
+        self.iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    /// Removes a setting, returning its value if it was set.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+fn check_key(key: &str) -> Result<(), SettingsError> {
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || ".-_".contains(c);
+    match !key.is_empty() && key.chars().all(valid) {
+        true => Ok(()),
+        false => Err(SettingsError::InvalidKey(key.to_string())),
+    }
+}
+
+/// Reads the settings saved at [`SETTINGS_PATH`], which are empty if none were saved yet.
+///
+/// A save interrupted after the old file was removed left the complete new settings under their
+/// temporary name, and they're put in place. A temporary file next to the settings is left of a
+/// save interrupted before then, and is removed.
+pub fn load_from(vfs: &mut VfsRouter) -> Result<Settings, KernelError> {
+    let path = match (vfs.stat(SETTINGS_PATH).is_ok(), vfs.stat(TEMP_PATH).is_ok()) {
+        // Either of them may fail on a read-only disk, which doesn't keep the settings from
+        // being read
+        (false, true) => match vfs.rename(TEMP_PATH, SETTINGS_PATH) {
+            Ok(()) => SETTINGS_PATH,
+            Err(_) => TEMP_PATH,
+        },
+        (true, true) => {
+            let _ = vfs.remove(TEMP_PATH);
+            SETTINGS_PATH
+        }
+        (_, false) => SETTINGS_PATH,
+    };
+    let size = match vfs.stat(path) {
+        Ok(metadata) => metadata.size,
+        Err(FileSystemError::NotFound(_)) => return Ok(Settings::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut bytes = vec![0; size];
+    let mut file = vfs.open(path)?;
+    let mut read = 0;
+    while read < size {
+        match vfs.read(&mut file, &mut bytes[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(Settings::parse(&String::from_utf8_lossy(&bytes[..read])))
+}
+
+/// Saves the settings at [`SETTINGS_PATH`]. They're written under a temporary name first, and
+/// only replace the old settings once all of them were written, so that an interrupted save
+/// leaves either the old or the new settings behind for [`load_from`].
+pub fn save_to(vfs: &mut VfsRouter, settings: &Settings) -> Result<(), KernelError> {
+    vfs.create_dir_all(SETTINGS_DIR)?;
+    match vfs.remove(TEMP_PATH) {
+        Ok(()) | Err(FileSystemError::NotFound(_)) => {}
+        Err(err) => return Err(err.into()),
+    }
+    vfs.create(TEMP_PATH, InodeKind::File)?;
+    let written = vfs
+        .open(TEMP_PATH)
+        .and_then(|mut file| vfs.write(&mut file, settings.encode().as_bytes()));
+    if let Err(err) = written {
+        let _ = vfs.remove(TEMP_PATH);
+        return Err(err.into());
+    }
+    match vfs.remove(SETTINGS_PATH) {
+        Ok(()) | Err(FileSystemError::NotFound(_)) => {}
+        Err(err) => return Err(err.into()),
+    }
+    // Until the rename only the temporary file holds the settings, so it's kept even if the
+    // rename fails
+    vfs.rename(TEMP_PATH, SETTINGS_PATH)?;
+    Ok(())
+}
+
+/// Loads the saved settings at boot, once the filesystem is mounted, and passes every one of them
+/// to the change hooks so that the subsystems start out with the saved values. If the settings
+/// can't be read, a warning is printed and the defaults are used.
+pub fn init() {
+    let loaded = load_from(&mut VFS.lock());
+    let settings = loaded.unwrap_or_else(|err| {
+        print_warn!("loading the settings failed: {}", err);
+        Settings::new()
+    });
+    *SETTINGS.lock() = settings.clone();
+    for (key, value) in settings.iter() {
+        notify(key, Some(value));
+    }
+}
+
+/// Registers a hook which is called whenever a setting is set or unset, and for every setting
+/// loaded by [`init`]. Hooks are called without any lock held, and are only registered once.
+pub fn register_hook(hook: ChangeHook) {
+    let mut hooks = HOOKS.lock();
+    if hooks
+        .iter()
+        .flatten()
+        .any(|&registered| core::ptr::fn_addr_eq(registered, hook))
+    {
+        return;
+    }
+    let slot = hooks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many settings hooks");
+    *slot = Some(hook);
+}
+
+fn notify(key: &str, value: Option<&str>) {
+    // Copied out, a hook may change a setting itself
+    let hooks = *HOOKS.lock();
+    for hook in hooks.iter().flatten() {
+        hook(key, value);
+    }
+}
+
+pub fn get(key: &str) -> Option<String> {
+    SETTINGS.lock().get(key).map(String::from)
+}
+
+pub fn get_str(key: &str, default: &str) -> String {
+    get(key).unwrap_or_else(|| default.to_string())
+}
+
+/// Returns the setting as a number, or `default` if it isn't set or isn't a number.
+pub fn get_u64(key: &str, default: u64) -> u64 {
+    get(key)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Returns the setting as a flag, or `default` if it isn't set or isn't one, see [`parse_bool`].
+pub fn get_bool(key: &str, default: bool) -> bool {
+    get(key)
+        .and_then(|value| parse_bool(&value))
+        .unwrap_or(default)
+}
+
+/// Parses a flag, `on`, `true`, `yes` or `1` for set and `off`, `false`, `no` or `0` for unset.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "on" | "true" | "yes" | "1" => Some(true),
+        "off" | "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Returns every setting.
+pub fn all() -> Settings {
+    SETTINGS.lock().clone()
+}
+
+/// Changes a setting and saves the settings to the disk right away. If saving them fails, the
+/// setting keeps its old value.
+pub fn set(key: &str, value: &str) -> Result<(), KernelError> {
+    check_key(key)?;
+    if value.contains(['\n', '\r']) {
+        return Err(SettingsError::InvalidValue(key.to_string()).into());
+    }
+    update(key, Some(value.trim()))
+}
+
+pub fn set_u64(key: &str, value: u64) -> Result<(), KernelError> {
+    set(key, &value.to_string())
+}
+
+pub fn set_bool(key: &str, value: bool) -> Result<(), KernelError> {
+    set(key, if value { "on" } else { "off" })
+}
+
+/// Removes a setting, so that its default is used again, and saves the settings.
+pub fn unset(key: &str) -> Result<(), KernelError> {
+    update(key, None)
+}
+
+fn update(key: &str, value: Option<&str>) -> Result<(), KernelError> {
+    let apply = |settings: &mut Settings| match value {
+        Some(value) => {
+            settings.insert(key, value);
+            Ok(())
+        }
+        None => match settings.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(SettingsError::NotSet(key.to_string())),
+        },
+    };
+    // The disk isn't written with the settings locked, the change is made to them once it's saved
+    let mut changed = SETTINGS.lock().clone();
+    apply(&mut changed)?;
+    save_to(&mut VFS.lock(), &changed)?;
+    let _ = apply(&mut SETTINGS.lock());
+    notify(key, value);
+    Ok(())
+}
+
+/// Empties the settings held in memory, for tests which start from a fresh filesystem.
+#[cfg(test)]
+fn reset() {
+    *SETTINGS.lock() = Settings::new();
+}
+
+#[test_case]
+fn test_settings_round_trip() {
+    crate::fs::init().unwrap();
+    reset();
+    assert_eq!(get_str("greeting", "hello"), "hello");
+    assert_eq!(get_u64("answer", 7), 7);
+    assert!(get_bool("flag", true));
+
+    set("greeting", "good morning").unwrap();
+    set_u64("answer", 42).unwrap();
+    set_bool("flag", false).unwrap();
+    set("broken", "not a number").unwrap();
+    assert_eq!(get_str("greeting", "hello"), "good morning");
+    assert_eq!(get_u64("answer", 7), 42);
+    assert!(!get_bool("flag", true));
+    assert_eq!(get_u64("broken", 3), 3);
+    assert!(matches!(
+        set("Bad Key", "x"),
+        Err(KernelError::Settings(SettingsError::InvalidKey(_)))
+    ));
+    assert!(matches!(
+        set("multi", "two\nlines"),
+        Err(KernelError::Settings(SettingsError::InvalidValue(_)))
+    ));
+
+    // What was saved comes back, and unset settings fall back to their defaults
+    let loaded = load_from(&mut VFS.lock()).unwrap();
+    assert_eq!(loaded, all());
+    unset("answer").unwrap();
+    assert_eq!(get_u64("answer", 7), 7);
+    assert!(matches!(
+        unset("answer"),
+        Err(KernelError::Settings(SettingsError::NotSet(_)))
+    ));
+    assert_eq!(load_from(&mut VFS.lock()).unwrap().get("answer"), None);
+
+    // Corrupt lines are skipped, the rest of the file still counts
+    let parsed = Settings::parse("# comment\n\na=1\nno equals sign\nB=2\n c = 3 \n");
+    assert_eq!(
+        parsed.iter().collect::<alloc::vec::Vec<_>>(),
+        [("a", "1"), ("c", "3")]
+    );
+    assert_eq!(parsed.encode(), "a=1\nc=3\n");
+    reset();
+}
+
+#[test_case]
+fn test_settings_survive_interrupted_save() {
+    use crate::fs::{disk, FILESYSTEM};
+    use alloc::{boxed::Box, sync::Arc};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // The number of writes left before the disk fails
+    let writes_left = Arc::new(AtomicUsize::new(usize::MAX));
+    let mut device = disk::Disk::new(disk::size());
+    let hook_writes_left = writes_left.clone();
+    device.set_write_hook(Box::new(move |block| {
+        hook_writes_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .map(|_| ())
+            .map_err(|_| disk::DiskError::WriteFailed(block))
+    }));
+    let previous = disk::attach(Box::new(device));
+
+    let mut old = Settings::new();
+    old.insert("version", "1");
+    let mut new = old.clone();
+    new.insert("version", "2");
+    new.insert("extra", "a longer value, so the file changes size");
+    for cut in 0.. {
+        writes_left.store(usize::MAX, Ordering::SeqCst);
+        crate::fs::init().unwrap();
+        save_to(&mut VFS.lock(), &old).unwrap();
+
+        writes_left.store(cut, Ordering::SeqCst);
+        let finished = save_to(&mut VFS.lock(), &new).is_ok();
+
+        // The disk comes back and is mounted from scratch
+        writes_left.store(usize::MAX, Ordering::SeqCst);
+        disk::invalidate_cache();
+        *FILESYSTEM.lock() = crate::fs::file::FileSystem::new();
+        FILESYSTEM.lock().mount().unwrap();
+        let loaded = load_from(&mut VFS.lock()).unwrap();
+        assert!(
+            loaded == old || loaded == new,
+            "write failed after {} writes: {:?}",
+            cut,
+            loaded
+        );
+        assert!(VFS.lock().stat(TEMP_PATH).is_err());
+        if finished {
+            assert_eq!(loaded, new);
+            break;
+        }
+    }
+
+    disk::attach(previous);
+    crate::fs::init().unwrap();
+}
+
+#[test_case]
+fn test_settings_hook() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CHANGES: AtomicUsize = AtomicUsize::new(0);
+    static LAST: Mutex<Option<(String, Option<String>)>> = Mutex::new(None);
+    fn hook(key: &str, value: Option<&str>) {
+        if key.starts_with("test.") {
+            CHANGES.fetch_add(1, Ordering::SeqCst);
+            *LAST.lock() = Some((key.to_string(), value.map(String::from)));
+        }
+    }
+
+    crate::fs::init().unwrap();
+    reset();
+    register_hook(hook);
+    register_hook(hook);
+    set("test.hooked", "yes").unwrap();
+    assert_eq!(CHANGES.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        *LAST.lock(),
+        Some((String::from("test.hooked"), Some(String::from("yes"))))
+    );
+
+    // A failed change isn't passed on
+    assert!(unset("test.missing").is_err());
+    unset("test.hooked").unwrap();
+    assert_eq!(CHANGES.load(Ordering::SeqCst), 2);
+    assert_eq!(*LAST.lock(), Some((String::from("test.hooked"), None)));
+
+    // Loading at boot passes on every saved setting
+    init();
+    assert_eq!(CHANGES.load(Ordering::SeqCst), 2);
+    set("test.loaded", "1").unwrap();
+    reset();
+    init();
+    assert_eq!(CHANGES.load(Ordering::SeqCst), 4);
+    assert_eq!(get("test.loaded").as_deref(), Some("1"));
+    reset();
+}
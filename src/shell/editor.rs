@@ -0,0 +1,1003 @@
+use core::ops::Range;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use futures_util::StreamExt as _;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::{
+    input::{LineDiscipline, PartialLine, TerminalMode},
+    ShellError,
+};
+use crate::{
+    error::KernelError,
+    fs::{
+        disk::BLOCK_SIZE,
+        file::{FileSystemError, InodeKind},
+        vfs::VfsRouter,
+        VFS,
+    },
+    swap::{self, SwapError, SwappableBuffer},
+    task::{
+        cancel::CancellationToken,
+        keyboard::{self, KeyPress, Modifiers},
+        select2, Either,
+    },
+    ui,
+    vgabuf::{self, AnsiParser, ScreenSession},
+};
+
+/// The fewest digits the line number gutter is drawn with, so it only grows past line 99.
+const MIN_GUTTER_DIGITS: usize = 2;
+
+/// Text longer than this is kept in a [`SwappableBuffer`] while it's edited, if there's a swap
+/// device.
+pub const SWAP_THRESHOLD: usize = 64 * 1024;
+
+/// Where the text of a [`TextBuffer`] is kept.
+enum Storage {
+    Heap(String),
+    /// Only a few pages of the text on the heap, the rest on the swap device. Only whole
+    /// characters are written to it, so it holds valid UTF-8.
+    Swapped(SwappableBuffer),
+}
+
+/// The text being edited, with the offset each line starts at kept up to date across edits. Going
+/// to a line or finding the line the cursor is on doesn't scan the text.
+///
+/// Lines are separated by `\n`, and text ending with one has an empty last line. Reading and
+/// editing the text only fails if it's swapped, see [`with_capacity`](Self::with_capacity).
+pub struct TextBuffer {
+    storage: Storage,
+    /// The byte offset of the start of each line, the first being 0.
+    line_starts: Vec<usize>,
+}
+
+impl TextBuffer {
+    /// Creates a buffer holding `text` on the heap.
+    pub fn new(text: &str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(newlines(text, 0));
+        Self {
+            storage: Storage::Heap(String::from(text)),
+            line_starts,
+        }
+    }
+
+    /// Creates an empty buffer for text of about `len` bytes, which is kept in a
+    /// [`SwappableBuffer`] if it's longer than [`SWAP_THRESHOLD`] and there's a swap device.
+    pub fn with_capacity(len: usize) -> Self {
+        let storage = match len > SWAP_THRESHOLD && swap::usage().is_some() {
+            true => Storage::Swapped(SwappableBuffer::new(0)),
+            false => Storage::Heap(String::with_capacity(len)),
+        };
+        Self {
+            storage,
+            line_starts: vec![0],
+        }
+    }
+
+    pub fn is_swapped(&self) -> bool {
+        matches!(self.storage, Storage::Swapped(_))
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.storage {
+            Storage::Heap(text) => text.len(),
+            Storage::Swapped(buffer) => buffer.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// Returns the whole text.
+    pub fn text(&mut self) -> Result<String, SwapError> {
+        self.with_text(0..self.len(), str::to_string)
+    }
+
+    /// Calls `f` with each part of the text in order, without copying it to the heap.
+    pub fn for_each_chunk(&mut self, mut f: impl FnMut(&[u8])) -> Result<(), SwapError> {
+        match &mut self.storage {
+            Storage::Heap(text) => {
+                f(text.as_bytes());
+                Ok(())
+            }
+            Storage::Swapped(buffer) => buffer.for_each_chunk(0, buffer.len(), f),
+        }
+    }
+
+    /// Returns line `n`, counting from 0, without its newline.
+    pub fn line(&mut self, n: usize) -> Result<Option<String>, SwapError> {
+        match self.line_range(n) {
+            Some(range) => self.with_text(range, str::to_string).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the offset line `n` starts at, or the end of the text past the last line.
+    pub fn line_start(&self, n: usize) -> usize {
+        self.line_starts.get(n).copied().unwrap_or(self.len())
+    }
+
+    /// Returns the line `offset` is on, counting from 0.
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.line_starts.partition_point(|&start| start <= offset) - 1
+    }
+
+    /// Inserts `s` at `offset`, which has to be on a character boundary.
+    pub fn insert(&mut self, offset: usize, s: &str) -> Result<(), SwapError> {
+        match &mut self.storage {
+            Storage::Heap(text) => text.insert_str(offset, s),
+            Storage::Swapped(buffer) => {
+                let len = buffer.len();
+                buffer.resize(len + s.len())?;
+                buffer.copy_within(offset..len, offset + s.len())?;
+                buffer.write(offset, s.as_bytes())?;
+            }
+        }
+        let line = self.line_of(offset);
+        for start in &mut self.line_starts[line + 1..] {
+            *start += s.len();
+        }
+        self.line_starts
+            .splice(line + 1..line + 1, newlines(s, offset));
+        Ok(())
+    }
+
+    /// Removes the text in `range`, which has to start and end on character boundaries.
+    pub fn delete(&mut self, range: Range<usize>) -> Result<(), SwapError> {
+        let removed = range.len();
+        if removed == 0 {
+            return Ok(());
+        }
+        match &mut self.storage {
+            Storage::Heap(text) => text.replace_range(range.clone(), ""),
+            Storage::Swapped(buffer) => {
+                let len = buffer.len();
+                buffer.copy_within(range.end..len, range.start)?;
+                buffer.resize(len - removed)?;
+            }
+        }
+        // The lines starting after a removed newline are gone, those after the range move back
+        let first = self.line_of(range.start) + 1;
+        let end = self
+            .line_starts
+            .partition_point(|&start| start <= range.end);
+        self.line_starts.drain(first..end);
+        for start in &mut self.line_starts[first..] {
+            *start -= removed;
+        }
+        Ok(())
+    }
+
+    /// Returns the offset line `number` starts at, counting from 1 like line numbers are shown.
+    /// Numbers past the last line go to the last line, and 0 to the first.
+    pub fn goto_line(&self, number: usize) -> usize {
+        let line = number.saturating_sub(1).min(self.line_count() - 1);
+        self.line_starts[line]
+    }
+
+    /// Finds the first match of `pattern` at or after `from`, which has to be on a character
+    /// boundary, wrapping around to the start of the text if there's none before the end. An empty
+    /// pattern finds nothing.
+    ///
+    /// Matches don't span lines: a pattern containing a newline finds nothing either, since it's
+    /// typed at a prompt where Enter ends it. Only a line at a time is read from a swapped text.
+    pub fn find(&mut self, pattern: &str, from: usize) -> Result<Option<usize>, SwapError> {
+        if pattern.is_empty() || pattern.contains('\n') {
+            return Ok(None);
+        }
+        let from = from.min(self.len());
+        let first = self.line_of(from);
+        let end = self
+            .line_range(first)
+            .map_or(from, |range| range.end.max(from));
+        if let Some(i) = self.with_text(from..end, |text| text.find(pattern))? {
+            return Ok(Some(from + i));
+        }
+        let lines = (first + 1..self.line_count()).chain(0..=first);
+        for n in lines {
+            let Some(range) = self.line_range(n) else {
+                continue;
+            };
+            // A match on the line of `from` is before it, or it would have been found already
+            let start = range.start;
+            if let Some(i) = self.with_text(range, |text| text.find(pattern))? {
+                return Ok(Some(start + i));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the range of line `n`, without its newline.
+    fn line_range(&self, n: usize) -> Option<Range<usize>> {
+        let start = *self.line_starts.get(n)?;
+        let end = self
+            .line_starts
+            .get(n + 1)
+            .map_or(self.len(), |next| next - 1);
+        Some(start..end)
+    }
+
+    /// Calls `f` with the text in `range`, which has to start and end on character boundaries.
+    fn with_text<R>(
+        &mut self,
+        range: Range<usize>,
+        f: impl FnOnce(&str) -> R,
+    ) -> Result<R, SwapError> {
+        match &mut self.storage {
+            Storage::Heap(text) => Ok(f(&text[range])),
+            Storage::Swapped(buffer) => {
+                let mut bytes = vec![0; range.len()];
+                buffer.read(range.start, &mut bytes)?;
+                Ok(f(&String::from_utf8_lossy(&bytes)))
+            }
+        }
+    }
+}
+
+/// Returns the offsets just after each newline in `s`, as if it started at `base`.
+fn newlines(s: &str, base: usize) -> impl Iterator<Item = usize> + '_ {
+    s.match_indices('\n').map(move |(i, _)| base + i + 1)
+}
+
+/// Returns the width of the line number gutter for a buffer of `lines` lines, counting the space
+/// separating it from the text.
+pub fn gutter_width(lines: usize) -> usize {
+    let digits = lines.max(1).ilog10() as usize + 1;
+    digits.max(MIN_GUTTER_DIGITS) + 1
+}
+
+/// Returns the gutter for line `number`, right-aligned in `width` columns.
+pub fn gutter(number: usize, width: usize) -> String {
+    format!("{:>1$} ", number, width - 1)
+}
+
+/// Returns the columns left for the text on a screen `cols` wide, with the gutter shown or not.
+pub fn text_width(cols: usize, lines: usize, gutter_shown: bool) -> usize {
+    match gutter_shown {
+        true => cols.saturating_sub(gutter_width(lines)),
+        false => cols,
+    }
+}
+
+/// Returns the first line to show in a view of `rows` lines starting at `top`, so that `line`
+/// is in it. A line which is already shown doesn't move the view, and one further away is put in
+/// the middle.
+pub fn scroll_to(top: usize, rows: usize, line: usize) -> usize {
+    let rows = rows.max(1);
+    if (top..top + rows).contains(&line) {
+        return top;
+    }
+    line.saturating_sub(rows / 2)
+}
+
+/// `edit <path>`: edits a text file on the whole screen until Ctrl+X is pressed, see [`Editor`]
+/// for the keys. A file which doesn't exist yet starts out empty and is created when saved. Takes
+/// the keyboard focus and switches the terminal to raw mode meanwhile, except while a question is
+/// answered at the input line.
+pub async fn edit(
+    args: &[&str],
+    discipline: &LineDiscipline,
+    cancel: &CancellationToken,
+) -> Result<(), KernelError> {
+    let &[path] = args else {
+        return Err(ShellError::Usage("edit <path>").into());
+    };
+    let buffer = load(&VFS.lock(), path)?;
+    let mut keys = keyboard::subscribe();
+    let mut focus = keys.acquire_focus();
+    let mut raw = discipline.enter(TerminalMode::Raw, PartialLine::Discard);
+    let _screen = ScreenSession::start();
+    let (first_row, last_row) = vgabuf::scroll_region();
+    let mut editor = Editor::new(path, buffer, last_row - first_row + 1, vgabuf::columns());
+
+    loop {
+        draw(first_row, &editor.render());
+        let Either::Left(Some(KeyPress { key, modifiers })) =
+            select2(keys.next(), cancel.cancelled()).await
+        else {
+            return Ok(());
+        };
+        match editor.handle_key(key, modifiers) {
+            Action::None => {}
+            Action::Save => {
+                let saved = save(&mut VFS.lock(), path, &mut editor.buffer);
+                editor.saved(saved);
+            }
+            Action::Quit => return Ok(()),
+            Action::Prompt(question) => {
+                // The answer is typed at the input line, which only gets keys while the editor
+                // doesn't have them
+                drop(focus);
+                drop(raw);
+                let answer = select2(discipline.prompt(question.text()), cancel.cancelled()).await;
+                let Either::Left(answer) = answer else {
+                    return Ok(());
+                };
+                raw = discipline.enter(TerminalMode::Raw, PartialLine::Discard);
+                focus = keys.acquire_focus();
+                editor.answer(question, answer.as_deref());
+            }
+        }
+    }
+}
+
+/// Reads the file to edit, which is empty if it doesn't exist yet. Large files are read into swap
+/// rather than the heap, see [`TextBuffer::with_capacity`].
+fn load(vfs: &VfsRouter, path: &str) -> Result<TextBuffer, KernelError> {
+    let metadata = match vfs.stat(path) {
+        Ok(metadata) => metadata,
+        Err(FileSystemError::NotFound(_)) => return Ok(TextBuffer::new("")),
+        Err(err) => return Err(err.into()),
+    };
+    if metadata.kind == InodeKind::Directory {
+        return Err(FileSystemError::IsADirectory(path.to_string()).into());
+    }
+    let mut buffer = TextBuffer::with_capacity(metadata.size);
+    let mut file = vfs.open(path)?;
+    let mut block = [0; BLOCK_SIZE];
+    // The start of a character split between two reads is kept at the start of the block
+    let mut split = 0;
+    loop {
+        let read = vfs.read(&mut file, &mut block[split..])?;
+        let data = &block[..split + read];
+        let valid = match core::str::from_utf8(data) {
+            Ok(text) => text.len(),
+            Err(err) if err.error_len().is_none() && read > 0 => err.valid_up_to(),
+            // Saving a file which isn't text would mangle it
+            Err(_) => return Err(ShellError::NotText(path.to_string()).into()),
+        };
+        let text = core::str::from_utf8(&data[..valid]).unwrap_or_default();
+        buffer.insert(buffer.len(), text)?;
+        if read == 0 {
+            return Ok(buffer);
+        }
+        block.copy_within(valid..split + read, 0);
+        split = split + read - valid;
+    }
+}
+
+/// Replaces the contents of the file at `path` with the text, creating it if it doesn't exist.
+fn save(vfs: &mut VfsRouter, path: &str, buffer: &mut TextBuffer) -> Result<(), KernelError> {
+    match vfs.stat(path) {
+        Ok(_) => vfs.truncate(path, 0)?,
+        Err(FileSystemError::NotFound(_)) => vfs.create(path, InodeKind::File)?,
+        Err(err) => return Err(err.into()),
+    }
+    let mut file = vfs.open(path)?;
+    let mut written = Ok(0);
+    buffer.for_each_chunk(|chunk| {
+        if written.is_ok() {
+            written = vfs.write(&mut file, chunk);
+        }
+    })?;
+    written?;
+    Ok(())
+}
+
+/// Replaces the rows of the screen starting at `first_row` with the rows of the editor, drawing
+/// the highlighted parts in reverse video.
+fn draw(first_row: usize, rows: &[String]) {
+    for (i, row) in rows.iter().enumerate() {
+        let mut ansi = AnsiParser::new();
+        let cells = row
+            .chars()
+            .filter_map(|c| ansi.feed(c).map(|c| (c, ansi.color())))
+            .collect::<Vec<_>>();
+        vgabuf::write_row_colored(first_row + i, &cells);
+    }
+}
+
+/// A question the editor asks at the input line, see [`Action::Prompt`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Question {
+    GotoLine,
+    Find,
+}
+
+impl Question {
+    pub fn text(self) -> &'static str {
+        match self {
+            Self::GotoLine => "go to line: ",
+            Self::Find => "find: ",
+        }
+    }
+}
+
+/// What the editor needs done after a key, which it can't do itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// The question is to be asked, and the answer given to [`Editor::answer`].
+    Prompt(Question),
+    /// The text is to be saved, and the outcome given to [`Editor::saved`].
+    Save,
+    Quit,
+}
+
+/// A text editor filling the screen, which is drawn from [`render`](Self::render) and given keys
+/// with [`handle_key`](Self::handle_key). Typed text is inserted at the cursor, which is moved
+/// with the arrow keys, Home, End, Page Up and Page Down. Ctrl+O saves and Ctrl+X quits, the
+/// first Ctrl+X only warning about unsaved changes. Ctrl+S and Ctrl+Q pause the screen before the
+/// editor sees them, so they aren't used.
+///
+/// Ctrl+G asks for a line number to go to, and Ctrl+F for text to find after the cursor, which F3
+/// then finds again. The match is highlighted until the next key. Ctrl+N hides or shows the line
+/// number gutter.
+///
+/// Lines longer than the screen are cut off, except the one with the cursor, which scrolls
+/// sideways to keep it in view. Tabs and other control characters are shown as spaces.
+pub struct Editor {
+    name: String,
+    buffer: TextBuffer,
+    /// The offset of the cursor in the text, on a character boundary.
+    cursor: usize,
+    /// The first line shown.
+    top: usize,
+    /// The rows of text shown, with the status line below them.
+    rows: usize,
+    cols: usize,
+    gutter: bool,
+    /// The text F3 finds, the last one asked for with Ctrl+F.
+    pattern: Option<String>,
+    /// The match which was just found.
+    found: Option<Range<usize>>,
+    /// Shown on the status line in place of the cursor position until the next key.
+    message: Option<String>,
+    modified: bool,
+    /// Set by a Ctrl+X which only warned about unsaved changes, so that the next one quits.
+    quitting: bool,
+}
+
+impl Editor {
+    /// Creates an editor for the text in `buffer` on a screen of `rows` rows, the last one being
+    /// the status line, and `cols` columns. The cursor starts at the start of the text and the
+    /// gutter is shown.
+    pub fn new(name: &str, buffer: TextBuffer, rows: usize, cols: usize) -> Self {
+        Self {
+            name: String::from(name),
+            buffer,
+            cursor: 0,
+            top: 0,
+            rows: rows.saturating_sub(1).max(1),
+            cols,
+            gutter: true,
+            pattern: None,
+            found: None,
+            message: None,
+            modified: false,
+            quitting: false,
+        }
+    }
+
+    /// Returns the first line shown, counting from 0.
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    /// Returns the line and the column of the cursor, both counting from 0.
+    pub fn cursor(&mut self) -> (usize, usize) {
+        let line = self.buffer.line_of(self.cursor);
+        let before = self.cursor - self.buffer.line_start(line);
+        let col = self
+            .line(line)
+            .get(..before)
+            .map_or(0, |text| text.chars().count());
+        (line, col)
+    }
+
+    pub fn handle_key(&mut self, key: DecodedKey, modifiers: Modifiers) -> Action {
+        self.message = None;
+        self.found = None;
+        let quitting = core::mem::take(&mut self.quitting);
+        let (line, col) = self.cursor();
+        match key {
+            DecodedKey::Unicode(c) if modifiers.ctrl => match c.to_ascii_lowercase() {
+                'g' => return Action::Prompt(Question::GotoLine),
+                'f' => return Action::Prompt(Question::Find),
+                'o' => return Action::Save,
+                'x' if self.modified && !quitting => {
+                    self.quitting = true;
+                    self.message = Some(String::from("unsaved changes, Ctrl+X again to quit"));
+                }
+                'x' => return Action::Quit,
+                'n' => self.gutter = !self.gutter,
+                _ => {}
+            },
+            DecodedKey::Unicode('\u{8}') => {
+                if let Some(prev) = self.prev_boundary() {
+                    self.edit(prev..self.cursor, "");
+                }
+            }
+            DecodedKey::Unicode('\u{7f}') => {
+                if let Some(next) = self.next_boundary() {
+                    self.edit(self.cursor..next, "");
+                }
+            }
+            DecodedKey::Unicode(c)
+                if !modifiers.alt && (!c.is_control() || matches!(c, '\n' | '\t')) =>
+            {
+                self.edit(self.cursor..self.cursor, c.encode_utf8(&mut [0; 4]));
+            }
+            DecodedKey::Unicode(_) => {}
+            DecodedKey::RawKey(key) => match key {
+                KeyCode::ArrowLeft => self.cursor = self.prev_boundary().unwrap_or(self.cursor),
+                KeyCode::ArrowRight => self.cursor = self.next_boundary().unwrap_or(self.cursor),
+                KeyCode::ArrowUp => self.move_to(line.saturating_sub(1), col),
+                KeyCode::ArrowDown => self.move_to(line + 1, col),
+                KeyCode::PageUp => self.move_to(line.saturating_sub(self.rows), col),
+                KeyCode::PageDown => self.move_to(line + self.rows, col),
+                KeyCode::Home => self.move_to(line, 0),
+                KeyCode::End => self.move_to(line, usize::MAX),
+                KeyCode::F3 => {
+                    let from = self.next_boundary().unwrap_or(self.cursor);
+                    self.find(from);
+                }
+                _ => {}
+            },
+        }
+        self.scroll();
+        Action::None
+    }
+
+    /// Takes the answer to a question asked for [`Action::Prompt`], `None` if it was cancelled.
+    pub fn answer(&mut self, question: Question, answer: Option<&str>) {
+        let Some(answer) = answer else {
+            return;
+        };
+        match question {
+            Question::GotoLine => match answer.trim().parse() {
+                Ok(number) => self.cursor = self.buffer.goto_line(number),
+                Err(_) => self.message = Some(format!("not a line number: {}", answer)),
+            },
+            // Searching for nothing doesn't forget what F3 finds
+            Question::Find if answer.is_empty() => {}
+            Question::Find => {
+                self.pattern = Some(String::from(answer));
+                self.find(self.cursor);
+            }
+        }
+        self.scroll();
+    }
+
+    /// Takes the outcome of saving the text for [`Action::Save`].
+    pub fn saved(&mut self, result: Result<(), KernelError>) {
+        self.message = Some(match result {
+            Ok(()) => {
+                self.modified = false;
+                format!("saved {} lines", self.buffer.line_count())
+            }
+            Err(err) => format!("not saved: {}", err),
+        });
+    }
+
+    /// Returns the rows to show: the lines from the top of the view, then the status line. The
+    /// cursor and the match just found are highlighted with [`ui::INVERSE`].
+    pub fn render(&mut self) -> Vec<String> {
+        let lines = self.buffer.line_count();
+        let gutter_width = gutter_width(lines);
+        let width = text_width(self.cols, lines, self.gutter);
+        let (cursor_line, cursor_col) = self.cursor();
+        let mut rows = Vec::with_capacity(self.rows + 1);
+        for n in self.top..self.top + self.rows {
+            if n >= lines {
+                rows.push(String::new());
+                continue;
+            }
+            let text = self.line(n);
+            let mut row = match self.gutter {
+                true => gutter(n + 1, gutter_width),
+                false => String::new(),
+            };
+            let start = self.buffer.line_start(n);
+            let skip = match n == cursor_line {
+                true => (cursor_col + 1).saturating_sub(width),
+                false => 0,
+            };
+            // The cursor can also be just past the end of its line
+            let end = (self.cursor == start + text.len()).then_some((text.len(), ' '));
+            let mut inverse = false;
+            for (i, c) in text.char_indices().chain(end).skip(skip).take(width) {
+                let offset = start + i;
+                let highlighted = offset == self.cursor
+                    || self
+                        .found
+                        .as_ref()
+                        .is_some_and(|found| found.contains(&offset));
+                if highlighted != inverse {
+                    row.push_str(if highlighted {
+                        ui::INVERSE
+                    } else {
+                        ui::NO_INVERSE
+                    });
+                    inverse = highlighted;
+                }
+                row.push(if c.is_control() { ' ' } else { c });
+            }
+            if inverse {
+                row.push_str(ui::NO_INVERSE);
+            }
+            rows.push(row);
+        }
+        let modified = if self.modified { " [modified]" } else { "" };
+        let status = match &self.message {
+            Some(message) => message.clone(),
+            None => format!("line {}/{}, col {}", cursor_line + 1, lines, cursor_col + 1),
+        };
+        rows.push(format!("{}{}  {}", self.name, modified, status));
+        rows
+    }
+
+    /// Replaces `range` of the text with `s`, leaving the cursor after it.
+    fn edit(&mut self, range: Range<usize>, s: &str) {
+        self.modified = true;
+        self.cursor = range.start;
+        let edited = self.buffer.delete(range.clone());
+        match edited.and_then(|()| self.buffer.insert(range.start, s)) {
+            Ok(()) => self.cursor += s.len(),
+            Err(err) => self.message = Some(format!("not edited: {}", err)),
+        }
+    }
+
+    /// Returns line `n`, or nothing if it couldn't be read back from swap, which the status line
+    /// then tells.
+    fn line(&mut self, n: usize) -> String {
+        match self.buffer.line(n) {
+            Ok(line) => line.unwrap_or_default(),
+            Err(err) => {
+                self.message = Some(format!("not read: {}", err));
+                String::new()
+            }
+        }
+    }
+
+    /// Moves the cursor to column `col` of `line`, or the end of the line if it's shorter. Lines
+    /// past the last one go to the last one.
+    fn move_to(&mut self, line: usize, col: usize) {
+        let line = line.min(self.buffer.line_count() - 1);
+        let text = self.line(line);
+        let offset = text.char_indices().nth(col).map_or(text.len(), |(i, _)| i);
+        self.cursor = self.buffer.line_start(line) + offset;
+    }
+
+    /// Returns the offset of the character before the cursor, the newline before its line if
+    /// it's at the start of one.
+    fn prev_boundary(&mut self) -> Option<usize> {
+        let line = self.buffer.line_of(self.cursor);
+        let before = self.cursor - self.buffer.line_start(line);
+        if before == 0 {
+            return self.cursor.checked_sub(1);
+        }
+        let c = self.line(line).get(..before)?.chars().next_back()?;
+        Some(self.cursor - c.len_utf8())
+    }
+
+    /// Returns the offset after the character at the cursor, the newline ending its line if it's
+    /// at the end of one.
+    fn next_boundary(&mut self) -> Option<usize> {
+        let line = self.buffer.line_of(self.cursor);
+        let before = self.cursor - self.buffer.line_start(line);
+        match self.line(line).get(before..)?.chars().next() {
+            Some(c) => Some(self.cursor + c.len_utf8()),
+            None => (self.cursor < self.buffer.len()).then_some(self.cursor + 1),
+        }
+    }
+
+    /// Moves the cursor to the next match of the pattern at or after `from`, wrapping around to
+    /// the start of the text.
+    fn find(&mut self, from: usize) {
+        let Some(pattern) = &self.pattern else {
+            self.message = Some(String::from("nothing to find, Ctrl+F to search"));
+            return;
+        };
+        match self.buffer.find(pattern, from) {
+            Ok(Some(start)) => {
+                if start < from {
+                    self.message = Some(String::from("search wrapped"));
+                }
+                self.cursor = start;
+                self.found = Some(start..start + pattern.len());
+            }
+            Ok(None) => self.message = Some(format!("not found: {}", pattern)),
+            Err(err) => self.message = Some(format!("not searched: {}", err)),
+        }
+    }
+
+    /// Moves the view so that the cursor is in it.
+    fn scroll(&mut self) {
+        self.top = scroll_to(self.top, self.rows, self.cursor().0);
+    }
+}
+
+#[cfg(test)]
+fn numbered_lines(n: usize) -> String {
+    (1..=n).map(|i| format!("line {}\n", i)).collect()
+}
+
+#[test_case]
+fn test_line_index_follows_edits() {
+    let mut buffer = TextBuffer::new(&numbered_lines(300));
+    let line = |buffer: &mut TextBuffer, n| buffer.line(n).unwrap().unwrap();
+    // Ends with a newline, so the last line is empty
+    assert_eq!(buffer.line_count(), 301);
+    assert_eq!(line(&mut buffer, 0), "line 1");
+    assert_eq!(line(&mut buffer, 299), "line 300");
+    assert_eq!(line(&mut buffer, 300), "");
+    assert_eq!(buffer.line(301), Ok(None));
+
+    // Edits within a line, across lines, and adding and joining lines
+    let start = buffer.line_start(10);
+    buffer.insert(start + 4, "X\nY").unwrap();
+    assert_eq!(line(&mut buffer, 10), "lineX");
+    assert_eq!(line(&mut buffer, 11), "Y 11");
+    let start = buffer.line_start(100);
+    buffer.delete(start - 1..start + 5).unwrap();
+    assert_eq!(line(&mut buffer, 99), "line 99100");
+    let start = buffer.line_start(200);
+    buffer.delete(start..buffer.line_start(250)).unwrap();
+    buffer.insert(0, "first\n\n").unwrap();
+    buffer.insert(buffer.len(), "last").unwrap();
+    buffer.delete(5..6).unwrap();
+    let text = buffer.text().unwrap();
+    assert_eq!(buffer.line_starts, TextBuffer::new(&text).line_starts);
+    assert_eq!(line(&mut buffer, 0), "first");
+    let last = buffer.line_count() - 1;
+    assert_eq!(line(&mut buffer, last), "last");
+
+    let offset = text.find("line 42\n").unwrap();
+    let n = buffer.line_of(offset + 3);
+    assert_eq!(line(&mut buffer, n), "line 42");
+    assert_eq!(buffer.line_of(buffer.len()), buffer.line_count() - 1);
+}
+
+#[test_case]
+fn test_goto_and_find() {
+    let mut buffer = TextBuffer::new(&numbered_lines(500));
+    let line = buffer.line_of(buffer.goto_line(250));
+    assert_eq!(buffer.line(line), Ok(Some(String::from("line 250"))));
+    assert_eq!(buffer.goto_line(0), 0);
+    // Past the end goes to the last line
+    assert_eq!(buffer.goto_line(10_000), buffer.len());
+
+    let found = buffer.find("line 37", buffer.goto_line(100)).unwrap();
+    assert_eq!(found.map(|found| buffer.line_of(found)), Some(369));
+    // Repeating from after the match wraps around to the start
+    let found = buffer.find("line 37", buffer.goto_line(400)).unwrap();
+    assert_eq!(found.map(|found| buffer.line_of(found)), Some(36));
+    assert_eq!(buffer.find("", 0), Ok(None));
+    assert_eq!(buffer.find("missing", 0), Ok(None));
+    assert_eq!(buffer.find("7\nline", 0), Ok(None));
+
+    // A match straddling the start of the search is found when wrapping
+    let mut buffer = TextBuffer::new("one two\nthree\n");
+    assert_eq!(buffer.find("two", 5), Ok(Some(4)));
+}
+
+#[test_case]
+fn test_large_text_is_swapped() {
+    use crate::{allocator, fs::disk::Disk};
+
+    let text = (1..=2000)
+        .map(|i| format!("line {} {}\n", i, "x".repeat(90)))
+        .collect::<String>();
+    let pages = text.len().div_ceil(swap::PAGE_SIZE);
+    let previous = swap::attach(alloc::boxed::Box::new(Disk::new(pages + 1))).unwrap();
+    {
+        // Small texts stay on the heap
+        assert!(!TextBuffer::with_capacity(SWAP_THRESHOLD).is_swapped());
+        let mut buffer = TextBuffer::with_capacity(text.len());
+        assert!(buffer.is_swapped());
+        allocator::reset_peak();
+        let start = allocator::stats().used;
+        for chunk in text.as_bytes().chunks(1000) {
+            let chunk = core::str::from_utf8(chunk).unwrap();
+            buffer.insert(buffer.len(), chunk).unwrap();
+        }
+        let found = buffer.find("line 1999 ", 0).unwrap();
+        assert_eq!(found.map(|found| buffer.line_of(found)), Some(1998));
+        let peak = allocator::stats().peak - start;
+        assert!(peak < text.len() / 2, "buffer used {} bytes of heap", peak);
+
+        // Edits move the text through swap, across pages, and it reads back the same
+        let mut heap = TextBuffer::new(&text);
+        for buffer in [&mut buffer, &mut heap] {
+            buffer.insert(100, "inserted\n").unwrap();
+            buffer.delete(5000..150_000).unwrap();
+            buffer.insert(buffer.len() - 10, "\nend").unwrap();
+        }
+        assert_eq!(buffer.line_starts, heap.line_starts);
+        assert_eq!(buffer.text(), heap.text());
+    }
+    assert_eq!(swap::usage().map(|(used, _)| used), Some(0));
+    if let Some(previous) = previous {
+        swap::attach(previous).unwrap();
+    }
+}
+
+#[test_case]
+fn test_gutter() {
+    assert_eq!(gutter_width(0), 3);
+    assert_eq!(gutter_width(99), 3);
+    assert_eq!(gutter_width(100), 4);
+    assert_eq!(gutter_width(999), 4);
+    assert_eq!(gutter_width(1000), 5);
+    assert_eq!(gutter(7, gutter_width(150)), "  7 ");
+    assert_eq!(gutter(150, gutter_width(150)), "150 ");
+    assert_eq!(text_width(80, 150, true), 76);
+    assert_eq!(text_width(80, 150, false), 80);
+
+    // The view only moves for lines off the screen
+    assert_eq!(scroll_to(10, 25, 20), 10);
+    assert_eq!(scroll_to(10, 25, 300), 288);
+    assert_eq!(scroll_to(100, 25, 5), 0);
+}
+
+#[cfg(test)]
+fn press(editor: &mut Editor, key: DecodedKey) -> Action {
+    editor.handle_key(key, Modifiers::default())
+}
+
+#[cfg(test)]
+fn ctrl(editor: &mut Editor, c: char) -> Action {
+    let modifiers = Modifiers {
+        ctrl: true,
+        ..Modifiers::default()
+    };
+    editor.handle_key(DecodedKey::Unicode(c), modifiers)
+}
+
+#[test_case]
+fn test_editor_goto_and_find() {
+    use ui::{INVERSE, NO_INVERSE};
+
+    let f3 = DecodedKey::RawKey(KeyCode::F3);
+    let mut editor = Editor::new("numbers", TextBuffer::new(&numbered_lines(500)), 25, 80);
+    let rows = editor.render();
+    assert_eq!(rows.len(), 25);
+    assert_eq!(rows[0], format!("  1 {}l{}ine 1", INVERSE, NO_INVERSE));
+    assert_eq!(rows[24], "numbers  line 1/501, col 1");
+
+    // Going to a line off the screen puts it in the middle of the view
+    assert_eq!(ctrl(&mut editor, 'g'), Action::Prompt(Question::GotoLine));
+    editor.answer(Question::GotoLine, Some("250"));
+    assert_eq!((editor.cursor(), editor.top()), ((249, 0), 237));
+    assert_eq!(
+        editor.render()[12],
+        format!("250 {}l{}ine 250", INVERSE, NO_INVERSE)
+    );
+    editor.answer(Question::GotoLine, Some("255"));
+    assert_eq!((editor.cursor(), editor.top()), ((254, 0), 237));
+    // Past the end goes to the last line, and nothing else moves the cursor
+    editor.answer(Question::GotoLine, Some("10000"));
+    assert_eq!((editor.cursor(), editor.top()), ((500, 0), 488));
+    editor.answer(Question::GotoLine, Some("ten"));
+    editor.answer(Question::GotoLine, None);
+    assert_eq!(editor.cursor(), (500, 0));
+    assert_eq!(editor.render()[24], "numbers  not a line number: ten");
+
+    // The match is highlighted, and F3 finds the next one
+    editor.answer(Question::GotoLine, Some("100"));
+    assert_eq!(ctrl(&mut editor, 'f'), Action::Prompt(Question::Find));
+    editor.answer(Question::Find, Some("line 37"));
+    assert_eq!((editor.cursor(), editor.top()), ((369, 0), 357));
+    assert_eq!(
+        editor.render()[12],
+        format!("370 {}line 37{}0", INVERSE, NO_INVERSE)
+    );
+    assert_eq!(press(&mut editor, f3), Action::None);
+    assert_eq!((editor.cursor(), editor.top()), ((370, 0), 357));
+    assert_eq!(
+        editor.render()[13],
+        format!("371 {}line 37{}1", INVERSE, NO_INVERSE)
+    );
+
+    // Searching on from past the last match wraps around to the start
+    editor.answer(Question::GotoLine, Some("400"));
+    press(&mut editor, f3);
+    assert_eq!((editor.cursor(), editor.top()), ((36, 0), 24));
+    let rows = editor.render();
+    assert_eq!(rows[12], format!(" 37 {}line 37{}", INVERSE, NO_INVERSE));
+    assert_eq!(rows[24], "numbers  search wrapped");
+    // The highlight lasts until the next key
+    press(&mut editor, DecodedKey::RawKey(KeyCode::ArrowRight));
+    assert_eq!(
+        editor.render()[12],
+        format!(" 37 l{}i{}ne 37", INVERSE, NO_INVERSE)
+    );
+
+    // Searching for nothing does nothing, and text which isn't there leaves the cursor
+    editor.answer(Question::Find, Some(""));
+    assert_eq!(editor.render()[24], "numbers  line 37/501, col 2");
+    editor.answer(Question::Find, Some("missing"));
+    assert_eq!(editor.cursor(), (36, 1));
+    assert_eq!(editor.render()[24], "numbers  not found: missing");
+}
+
+#[test_case]
+fn test_editor_gutter_and_editing() {
+    use ui::{INVERSE, NO_INVERSE};
+
+    let key = |c| DecodedKey::Unicode(c);
+    let raw = DecodedKey::RawKey;
+    // 99 lines, the last one empty, so the gutter has two digits
+    let mut editor = Editor::new("short", TextBuffer::new(&numbered_lines(98)), 10, 20);
+    assert_eq!(editor.render()[1], " 2 line 2");
+
+    // The 100th line widens the gutter
+    press(&mut editor, key('\n'));
+    let rows = editor.render();
+    assert_eq!(rows[0], "  1 ");
+    assert_eq!(rows[1], format!("  2 {}l{}ine 1", INVERSE, NO_INVERSE));
+    assert_eq!(rows[9], "short [modified]  line 2/100, col 1");
+
+    // Without the gutter the text has the whole width, and the line with the cursor scrolls
+    // sideways to keep it in view
+    ctrl(&mut editor, 'n');
+    assert_eq!(
+        editor.render()[1],
+        format!("{}l{}ine 1", INVERSE, NO_INVERSE)
+    );
+    for _ in 0..25 {
+        press(&mut editor, key('x'));
+    }
+    assert_eq!(
+        editor.render()[1],
+        format!("{}{}l{}", "x".repeat(19), INVERSE, NO_INVERSE)
+    );
+    press(&mut editor, raw(KeyCode::ArrowDown));
+    assert_eq!(editor.cursor(), (2, 6));
+    let rows = editor.render();
+    assert_eq!(rows[1], "x".repeat(20));
+    assert_eq!(rows[2], format!("line 2{} {}", INVERSE, NO_INVERSE));
+
+    // Backspace and Delete remove characters, joining lines at their ends
+    press(&mut editor, key('\u{8}'));
+    press(&mut editor, key('\u{7f}'));
+    assert_eq!(
+        editor.render()[2],
+        format!("line {}l{}ine 3", INVERSE, NO_INVERSE)
+    );
+    assert!(editor
+        .buffer
+        .text()
+        .unwrap()
+        .starts_with(&format!("\n{}line 1\nline line 3\n", "x".repeat(25))));
+    ctrl(&mut editor, 'n');
+    assert_eq!(
+        editor.render()[2],
+        format!(" 3 line {}l{}ine 3", INVERSE, NO_INVERSE)
+    );
+
+    // Page Down and End move within the text
+    press(&mut editor, raw(KeyCode::PageDown));
+    press(&mut editor, raw(KeyCode::End));
+    assert_eq!((editor.cursor(), editor.top()), ((11, 7), 7));
+
+    // Quitting with unsaved changes takes a second Ctrl+X
+    assert_eq!(ctrl(&mut editor, 'o'), Action::Save);
+    editor.saved(Err(KernelError::from(ShellError::Interrupted)));
+    assert_eq!(
+        editor.render()[9],
+        "short [modified]  not saved: interrupted"
+    );
+    assert_eq!(ctrl(&mut editor, 'x'), Action::None);
+    assert_eq!(
+        editor.render()[9],
+        "short [modified]  unsaved changes, Ctrl+X again to quit"
+    );
+    assert_eq!(ctrl(&mut editor, 'x'), Action::Quit);
+    editor.saved(Ok(()));
+    assert_eq!(editor.render()[9], "short  saved 99 lines");
+    assert_eq!(ctrl(&mut editor, 'x'), Action::Quit);
+}
@@ -0,0 +1,208 @@
+use alloc::{format, string::String, vec, vec::Vec};
+
+use super::{CommandOutput, ShellError};
+use crate::{
+    error::KernelError,
+    fs::{file::InodeKind, path, vfs::VfsRouter, VFS},
+    task::job,
+};
+
+const USAGE: &str = "find <path> [-name <glob>] [-type f|d] [-size [+|-]<bytes>] [-maxdepth <n>]";
+
+/// How deep `find` descends when no `-maxdepth` is given. Directories can't be linked, so the
+/// tree has no cycles, this only bounds the walk on a damaged filesystem.
+const MAX_DEPTH: usize = 64;
+
+/// The tests an entry has to pass to be printed by `find`.
+#[derive(Debug, Default)]
+struct Filters<'a> {
+    name: Option<&'a str>,
+    kind: Option<InodeKind>,
+    size: Option<SizeFilter>,
+    max_depth: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SizeFilter {
+    Above(usize),
+    Below(usize),
+    Exactly(usize),
+}
+
+impl<'a> Filters<'a> {
+    fn parse(args: &[&'a str]) -> Result<Self, ShellError> {
+        let mut filters = Self::default();
+        let mut args = args.iter();
+        while let Some(&option) = args.next() {
+            let value = *args.next().ok_or(ShellError::Usage(USAGE))?;
+            match option {
+                "-name" => filters.name = Some(value),
+                "-type" => {
+                    filters.kind = Some(match value {
+                        "f" => InodeKind::File,
+                        "d" => InodeKind::Directory,
+                        _ => return Err(ShellError::Usage(USAGE)),
+                    })
+                }
+                "-size" => {
+                    let bytes = |digits: &str| digits.parse().map_err(|_| ShellError::Usage(USAGE));
+                    filters.size = Some(match value.as_bytes().first() {
+                        Some(b'+') => SizeFilter::Above(bytes(&value[1..])?),
+                        Some(b'-') => SizeFilter::Below(bytes(&value[1..])?),
+                        _ => SizeFilter::Exactly(bytes(value)?),
+                    })
+                }
+                "-maxdepth" => {
+                    filters.max_depth = Some(value.parse().map_err(|_| ShellError::Usage(USAGE))?)
+                }
+                _ => return Err(ShellError::Usage(USAGE)),
+            }
+        }
+        Ok(filters)
+    }
+
+    fn matches(&self, name: &str, kind: InodeKind, size: usize) -> bool {
+        self.name.is_none_or(|glob| path::matches_glob(glob, name))
+            && self.kind.is_none_or(|wanted| kind == wanted)
+            && match self.size {
+                None => true,
+                Some(SizeFilter::Above(bytes)) => size > bytes,
+                Some(SizeFilter::Below(bytes)) => size < bytes,
+                Some(SizeFilter::Exactly(bytes)) => size == bytes,
+            }
+    }
+}
+
+/// An entry waiting to be visited by the walk.
+struct Pending {
+    path: String,
+    name: String,
+    depth: usize,
+}
+
+/// Walks the tree under a path depth-first, printing the full path of every entry which passes
+/// all of the filters as soon as it's found. The path itself is at depth 0, and may be a file.
+/// The walk goes on into the filesystems mounted below the path.
+pub fn find(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+    let [start, options @ ..] = args else {
+        return Err(ShellError::Usage(USAGE).into());
+    };
+    let filters = Filters::parse(options)?;
+    let max_depth = filters.max_depth.unwrap_or(MAX_DEPTH).min(MAX_DEPTH);
+    let limited = filters.max_depth.is_none_or(|max| max > MAX_DEPTH);
+
+    let vfs = VFS.lock();
+    let components = path::components(start);
+    let mut stack = vec![Pending {
+        path: format!("/{}", components.join("/")),
+        name: String::from(components.last().copied().unwrap_or("/")),
+        depth: 0,
+    }];
+    // An explicit stack rather than recursion, as the kernel stack is small
+    while let Some(entry) = stack.pop() {
+        if job::cancel_requested() {
+            return Err(ShellError::Interrupted.into());
+        }
+        let metadata = vfs.stat(&entry.path)?;
+        if filters.matches(&entry.name, metadata.kind, metadata.size) {
+            writeln!(out, "{}", entry.path);
+        }
+        if metadata.kind != InodeKind::Directory {
+            continue;
+        }
+        if entry.depth < max_depth {
+            push_children(&vfs, &entry, &mut stack)?;
+        } else if limited {
+            writeln!(out, "find: {}: nested too deep, not descending", entry.path);
+        }
+    }
+    Ok(())
+}
+
+/// Pushes the entries of a directory so that they're popped in order of their names.
+fn push_children(
+    vfs: &VfsRouter,
+    dir: &Pending,
+    stack: &mut Vec<Pending>,
+) -> Result<(), KernelError> {
+    let mut children = vfs.list(&dir.path)?;
+    children.sort_by(|a, b| b.name.cmp(&a.name));
+    let separator = if dir.path.ends_with('/') { "" } else { "/" };
+    stack.extend(children.into_iter().map(|child| Pending {
+        path: format!("{}{}{}", dir.path, separator, child.name),
+        name: child.name,
+        depth: dir.depth + 1,
+    }));
+    Ok(())
+}
+
+#[test_case]
+fn test_find() {
+    crate::fs::init().unwrap();
+    {
+        let mut fs = crate::fs::FILESYSTEM.lock();
+        fs.create_dir_all("docs/old").unwrap();
+        fs.create_dir_all("src").unwrap();
+        for (path, size) in [
+            ("abc", 10),
+            ("docs/a.txt", 100),
+            ("docs/old/b.txt", 5000),
+            ("docs/old/adc", 0),
+            ("src/main.txt.bak", 20),
+        ] {
+            let inumber = fs.create_at(path, InodeKind::File).unwrap();
+            fs.write(inumber, 0, &vec![b'x'; size]).unwrap();
+        }
+    }
+    let found = |args: &[&str]| {
+        let mut out = CommandOutput::Captured(String::new());
+        find(args, &mut out).unwrap();
+        out.into_captured().unwrap()
+    };
+
+    assert_eq!(
+        found(&["/"]),
+        "/\n/abc\n/docs\n/docs/a.txt\n/docs/old\n/docs/old/adc\n/docs/old/b.txt\n/src\n\
+         /src/main.txt.bak\n"
+    );
+    assert_eq!(
+        found(&["/", "-name", "*.txt"]),
+        "/docs/a.txt\n/docs/old/b.txt\n"
+    );
+    assert_eq!(found(&["/", "-name", "a?c"]), "/abc\n/docs/old/adc\n");
+    assert_eq!(found(&["docs", "-type", "d"]), "/docs\n/docs/old\n");
+    assert_eq!(
+        found(&["/", "-type", "f", "-size", "+50"]),
+        "/docs/a.txt\n/docs/old/b.txt\n"
+    );
+    assert_eq!(
+        found(&["/", "-type", "f", "-size", "-20"]),
+        "/abc\n/docs/old/adc\n"
+    );
+    assert_eq!(found(&["/", "-size", "20"]), "/src/main.txt.bak\n");
+    assert_eq!(
+        found(&["/", "-maxdepth", "1", "-type", "d"]),
+        "/\n/docs\n/src\n"
+    );
+    assert_eq!(found(&["docs/./old/../a.txt"]), "/docs/a.txt\n");
+    assert_eq!(found(&["docs", "-maxdepth", "0"]), "/docs\n");
+
+    let mut out = CommandOutput::Captured(String::new());
+    assert!(matches!(
+        find(&["missing"], &mut out),
+        Err(KernelError::FileSystem(
+            crate::fs::file::FileSystemError::NotFound(_)
+        ))
+    ));
+    for args in [
+        &[][..],
+        &["/", "-type", "x"],
+        &["/", "-size", "+x"],
+        &["/", "-name"],
+    ] {
+        assert!(matches!(
+            find(args, &mut out),
+            Err(KernelError::Shell(ShellError::Usage(_)))
+        ));
+    }
+}
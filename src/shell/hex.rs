@@ -0,0 +1,80 @@
+use core::fmt::Write;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::util::fmt::{write_hex, write_padded, Align, FmtBuf};
+
+/// Number of bytes shown on each line of a hexdump.
+const BYTES_PER_LINE: usize = 16;
+
+/// Parses a number given in decimal, or in hex with a `0x` prefix.
+pub fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Parses bytes written as pairs of hex digits, like `dead00ff`.
+pub fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() || text.len() & 1 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Formats bytes as lines of their offset, the bytes in hex and the printable ones as text.
+/// `start` is the offset of the first byte.
+pub fn hexdump(start: usize, bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut hex = FmtBuf::<{ BYTES_PER_LINE * 3 }>::new();
+            let _ = write_hex(&mut hex, chunk);
+            let text = chunk
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                })
+                .collect::<String>();
+            // Writing to a `String` can't fail
+            let mut line = String::new();
+            let _ = write!(line, "{:04x}  ", start + i * BYTES_PER_LINE);
+            let _ = write_padded(&mut line, hex.as_str(), BYTES_PER_LINE * 3, Align::Left);
+            let _ = write!(line, "|{}|", text);
+            line
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_parse_hex() {
+    assert_eq!(parse_number("42"), Some(42));
+    assert_eq!(parse_number("0x1f"), Some(0x1f));
+    assert_eq!(parse_number("0xg"), None);
+    assert_eq!(parse_number("-1"), None);
+    assert_eq!(
+        parse_bytes("dead00FF"),
+        Some(alloc::vec![0xde, 0xad, 0, 0xff])
+    );
+    assert_eq!(parse_bytes("abc"), None);
+    assert_eq!(parse_bytes(""), None);
+    assert_eq!(parse_bytes("zz"), None);
+}
+
+#[test_case]
+fn test_hexdump() {
+    let lines = hexdump(0x10, b"hello, world!\n\0\x7fabc");
+    assert_eq!(
+        lines,
+        [
+            "0010  68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0a 00 7f |hello, world!...|",
+            "0020  61 62 63                                        |abc|",
+        ]
+    );
+}
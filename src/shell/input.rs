@@ -0,0 +1,476 @@
+use core::{future::poll_fn, task::Poll, task::Waker};
+
+use alloc::{
+    collections::VecDeque,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+
+use super::{draw_input_line, terminal::Terminal};
+use crate::{
+    task::keyboard::{KeyPress, Modifiers},
+    ui::{self, Style},
+};
+
+/// Added to the question asked by [`LineDiscipline::confirm`].
+pub const CONFIRM_PROMPT: &str = "[y/N] ";
+
+/// How a terminal hands typed keys to whoever reads them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalMode {
+    /// Every key is passed on as it is, for programs doing their own editing.
+    Raw,
+    /// Keys are gathered into lines, which are passed on once Enter is pressed. Backspace and
+    /// Ctrl+U edit the line, and Ctrl+C interrupts instead of being typed. Without `echo` the
+    /// line isn't shown while typed, though a terminal echoing keys itself shows it anyway.
+    ///
+    /// Only keys typed while a line is read count, others are dropped, so that keys typed ahead
+    /// don't answer a question asked later.
+    Cooked { echo: bool },
+}
+
+/// What becomes of a line partly typed in cooked mode when the terminal switches to raw mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartialLine {
+    /// The line is passed on as if Enter had been pressed, to whoever is reading it.
+    Flush,
+    Discard,
+}
+
+/// A key passed on by a [`LineDiscipline`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// A key typed in raw mode.
+    Key(KeyPress),
+    /// Ctrl+C was pressed in cooked mode while no line was being read.
+    Interrupt,
+}
+
+/// Sits between the keys typed on a terminal and the program reading them, in the mode the
+/// program asked for. In raw mode keys are passed straight on by
+/// [`handle_key`](Self::handle_key), in cooked mode they make up lines read with
+/// [`read_line`](Self::read_line).
+///
+/// The shell switches its terminal to raw mode while it edits the command line itself, and back
+/// to cooked mode while a job runs, so that the job can ask questions.
+#[derive(Clone)]
+pub struct LineDiscipline {
+    terminal: Arc<Mutex<dyn Terminal>>,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    /// The mode when no [`ModeGuard`] is held.
+    base: TerminalMode,
+    /// The modes entered with guards, the last of which is in effect.
+    entered: Vec<(u64, TerminalMode, PartialLine)>,
+    next_guard: u64,
+    /// The question shown before the line while it's read.
+    question: Option<String>,
+    /// The line typed so far in cooked mode, which is empty unless a line is being read.
+    buffer: Vec<char>,
+    /// Lines finished while one is read, `None` for an interrupted one. Those the reader doesn't
+    /// take are dropped when it's done.
+    lines: VecDeque<Finished>,
+    /// Set while [`LineDiscipline::read_line`] waits for a line.
+    reading: bool,
+    waker: Option<Waker>,
+}
+
+struct Finished {
+    line: Option<String>,
+    /// Whether the line was shown while typed.
+    echo: bool,
+}
+
+impl State {
+    fn mode(&self) -> TerminalMode {
+        self.entered.last().map_or(self.base, |&(_, mode, _)| mode)
+    }
+
+    /// Deals with the line typed so far when the mode changes from `from` to `to`.
+    fn switch(&mut self, from: TerminalMode, to: TerminalMode, partial: PartialLine) {
+        let TerminalMode::Cooked { echo } = from else {
+            return;
+        };
+        if to != TerminalMode::Raw || self.buffer.is_empty() {
+            return;
+        }
+        let line = self.buffer.drain(..).collect::<String>();
+        if partial == PartialLine::Flush {
+            self.finish(Some(line), echo);
+        }
+    }
+
+    fn finish(&mut self, line: Option<String>, echo: bool) {
+        self.buffer.clear();
+        self.lines.push_back(Finished { line, echo });
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Ends a [`LineDiscipline::read_line`] when dropped, whether it got a line or was given up on,
+/// dropping whatever else was typed meanwhile.
+struct Reading<'a>(&'a Mutex<State>);
+
+impl Drop for Reading<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.reading = false;
+        state.buffer.clear();
+        state.lines.clear();
+        state.waker = None;
+    }
+}
+
+/// Keeps a terminal in the mode entered with [`LineDiscipline::enter`] until dropped, after which
+/// it goes back to the mode it was in before.
+pub struct ModeGuard {
+    id: u64,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for ModeGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        let from = state.mode();
+        // Guards are usually dropped in reverse order, but remove the right entry even if not
+        if let Some(pos) = state.entered.iter().rposition(|(id, ..)| *id == self.id) {
+            let (_, _, partial) = state.entered.remove(pos);
+            let to = state.mode();
+            state.switch(from, to, partial);
+        }
+    }
+}
+
+impl LineDiscipline {
+    /// Creates the discipline of a terminal, which starts out in cooked mode with echo.
+    pub fn new(terminal: Arc<Mutex<dyn Terminal>>) -> Self {
+        Self {
+            terminal,
+            state: Arc::new(Mutex::new(State {
+                base: TerminalMode::Cooked { echo: true },
+                entered: Vec::new(),
+                next_guard: 0,
+                question: None,
+                buffer: Vec::new(),
+                lines: VecDeque::new(),
+                reading: false,
+                waker: None,
+            })),
+        }
+    }
+
+    pub fn mode(&self) -> TerminalMode {
+        self.state.lock().mode()
+    }
+
+    /// Sets the mode the terminal is in when no guard is held. Takes effect at once unless a
+    /// guard is.
+    pub fn set_mode(&self, mode: TerminalMode, partial: PartialLine) {
+        let mut state = self.state.lock();
+        let from = state.mode();
+        state.base = mode;
+        let to = state.mode();
+        state.switch(from, to, partial);
+    }
+
+    /// Switches the terminal to `mode` until the returned guard is dropped. `partial` says what
+    /// happens to a line partly typed in cooked mode, both now and when the guard is dropped.
+    pub fn enter(&self, mode: TerminalMode, partial: PartialLine) -> ModeGuard {
+        let mut state = self.state.lock();
+        let from = state.mode();
+        let id = state.next_guard;
+        state.next_guard += 1;
+        state.entered.push((id, mode, partial));
+        state.switch(from, mode, partial);
+        ModeGuard {
+            id,
+            state: self.state.clone(),
+        }
+    }
+
+    /// Returns `true` while a line is waited for.
+    pub fn is_reading(&self) -> bool {
+        self.state.lock().reading
+    }
+
+    /// Handles a typed key, returning it if it's passed on rather than added to the line.
+    pub fn handle_key(&self, key: DecodedKey, modifiers: Modifiers) -> Option<Input> {
+        let mut state = self.state.lock();
+        let TerminalMode::Cooked { echo } = state.mode() else {
+            return Some(Input::Key(KeyPress { key, modifiers }));
+        };
+        let interrupt = modifiers.ctrl && matches!(key, DecodedKey::Unicode('c' | 'C'));
+        if !state.reading {
+            return interrupt.then_some(Input::Interrupt);
+        }
+        match key {
+            _ if interrupt => {
+                state.finish(None, echo);
+                return None;
+            }
+            DecodedKey::Unicode('\n') => {
+                let line = state.buffer.iter().collect();
+                state.finish(Some(line), echo);
+                return None;
+            }
+            DecodedKey::Unicode('\u{8}') => {
+                state.buffer.pop();
+            }
+            DecodedKey::Unicode('u' | 'U') if modifiers.ctrl => state.buffer.clear(),
+            DecodedKey::Unicode(c) if !modifiers.ctrl && !modifiers.alt && !c.is_control() => {
+                state.buffer.push(c);
+            }
+            _ => return None,
+        }
+        if echo && !self.terminal.lock().local_echo() {
+            self.draw_line(&state);
+        }
+        None
+    }
+
+    /// Waits for a line typed in cooked mode. Returns `None` if Ctrl+C was pressed instead.
+    pub async fn read_line(&self) -> Option<String> {
+        let _reading = Reading(&self.state);
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            match state.lines.pop_front() {
+                Some(finished) => {
+                    self.draw_finished(&state, &finished);
+                    Poll::Ready(finished.line)
+                }
+                None => {
+                    state.reading = true;
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Shows `question` on the input line and waits for a line to be typed after it. Returns
+    /// `None` if Ctrl+C was pressed instead. The question and the answer are then moved into the
+    /// output.
+    pub async fn prompt(&self, question: &str) -> Option<String> {
+        {
+            let mut state = self.state.lock();
+            state.question = Some(question.to_string());
+            self.draw_line(&state);
+        }
+        let answer = self.read_line().await;
+        self.state.lock().question = None;
+        answer
+    }
+
+    /// Asks a yes or no question, which is only answered with yes by typing `y` or `yes`. Ctrl+C
+    /// answers no.
+    pub async fn confirm(&self, question: &str) -> bool {
+        let answer = self
+            .prompt(&format!("{} {}", question, CONFIRM_PROMPT))
+            .await;
+        matches!(answer.as_deref().map(str::trim), Some("y" | "Y" | "yes"))
+    }
+
+    /// Draws the question with the line typed so far, which is left out without echo or if the
+    /// terminal shows typed keys itself.
+    fn draw_line(&self, state: &State) {
+        let mut terminal = self.terminal.lock();
+        let typed = match state.mode() {
+            TerminalMode::Cooked { echo: true } if !terminal.local_echo() => {
+                state.buffer.iter().collect()
+            }
+            _ => String::new(),
+        };
+        draw_input_line(
+            &mut *terminal,
+            state.question.as_deref().unwrap_or(""),
+            &typed,
+        );
+    }
+
+    /// Moves the question and the line read after it into the output.
+    fn draw_finished(&self, state: &State, finished: &Finished) {
+        let suffix = if finished.line.is_some() { "" } else { "^C" };
+        let mut terminal = self.terminal.lock();
+        if terminal.local_echo() {
+            terminal.write_str(&format!("{}\n", suffix));
+            return;
+        }
+        let question = state.question.as_deref().unwrap_or("");
+        let question = ui::styled(Style::Prompt, terminal.colors(), question);
+        let typed = match &finished.line {
+            Some(line) if finished.echo => line.as_str(),
+            _ => "",
+        };
+        terminal.clear_line();
+        terminal.write_str(&format!("{}{}{}\n", question, typed, suffix));
+    }
+}
+
+#[cfg(test)]
+fn type_keys(discipline: &LineDiscipline, keys: &str) -> Vec<Input> {
+    keys.chars()
+        .filter_map(|c| discipline.handle_key(DecodedKey::Unicode(c), Modifiers::default()))
+        .collect()
+}
+
+#[cfg(test)]
+fn ctrl(discipline: &LineDiscipline, c: char) -> Option<Input> {
+    let modifiers = Modifiers {
+        ctrl: true,
+        ..Modifiers::default()
+    };
+    discipline.handle_key(DecodedKey::Unicode(c), modifiers)
+}
+
+#[test_case]
+fn test_cooked_mode() {
+    use super::terminal::MockTerminal;
+    use crate::task::{executor::Executor, job::JoinHandle, Priority};
+
+    // Asks the question in a job, which waits for the answer once this returns
+    fn ask(
+        executor: &mut Executor,
+        discipline: &LineDiscipline,
+        question: &'static str,
+    ) -> JoinHandle<Option<String>> {
+        let discipline = discipline.clone();
+        let handle = executor.spawner().spawn(
+            async move { discipline.prompt(question).await },
+            Priority::Normal,
+        );
+        executor.run_ready_tasks();
+        handle
+    }
+
+    let terminal = Arc::new(Mutex::new(MockTerminal::default()));
+    let discipline = LineDiscipline::new(terminal.clone());
+    let mut executor = Executor::new();
+
+    // Backspace and Ctrl+U edit the line, which is shown after the question as it's typed
+    let mut answer = ask(&mut executor, &discipline, "name? ");
+    assert!(discipline.is_reading());
+    assert!(type_keys(&discipline, "ab\u{8}c").is_empty());
+    assert_eq!(terminal.lock().line(), "name? ac");
+    assert_eq!(ctrl(&discipline, 'u'), None);
+    type_keys(&discipline, "xy");
+    assert_eq!(terminal.lock().line(), "name? xy");
+    type_keys(&discipline, "\n");
+    executor.run_until_done();
+    assert_eq!(answer.try_take(), Some(Some(String::from("xy"))));
+    assert!(!discipline.is_reading());
+    assert_eq!(terminal.lock().line(), "");
+
+    // Without echo only the question is shown
+    let hidden = discipline.enter(TerminalMode::Cooked { echo: false }, PartialLine::Discard);
+    let mut answer = ask(&mut executor, &discipline, "secret? ");
+    type_keys(&discipline, "hunter2");
+    assert_eq!(terminal.lock().line(), "secret? ");
+    type_keys(&discipline, "\n");
+    executor.run_until_done();
+    assert_eq!(answer.try_take(), Some(Some(String::from("hunter2"))));
+    drop(hidden);
+
+    // Ctrl+C ends the line being read, and is passed on as an interrupt when none is
+    let mut answer = ask(&mut executor, &discipline, "continue? ");
+    type_keys(&discipline, "y");
+    assert_eq!(ctrl(&discipline, 'c'), None);
+    executor.run_until_done();
+    assert_eq!(answer.try_take(), Some(None));
+    type_keys(&discipline, "abc");
+    assert_eq!(ctrl(&discipline, 'c'), Some(Input::Interrupt));
+
+    // Keys typed while nothing reads a line don't answer the next question
+    type_keys(&discipline, "y\ny");
+    let mut confirmed = executor.spawner().spawn(
+        {
+            let discipline = discipline.clone();
+            async move { discipline.confirm("sure?").await }
+        },
+        Priority::Normal,
+    );
+    executor.run_ready_tasks();
+    assert_eq!(terminal.lock().line(), "sure? [y/N] ");
+    type_keys(&discipline, "\n");
+    executor.run_until_done();
+    assert_eq!(confirmed.try_take(), Some(false));
+
+    // Nor do lines typed before the reader got to the one it waited for
+    let mut answer = ask(&mut executor, &discipline, "first? ");
+    type_keys(&discipline, "one\ntwo\n");
+    executor.run_until_done();
+    assert_eq!(answer.try_take(), Some(Some(String::from("one"))));
+    let mut answer = ask(&mut executor, &discipline, "second? ");
+    type_keys(&discipline, "three\n");
+    executor.run_until_done();
+    assert_eq!(answer.try_take(), Some(Some(String::from("three"))));
+}
+
+#[test_case]
+fn test_raw_mode() {
+    use super::terminal::MockTerminal;
+    use crate::task::{executor::Executor, job::JoinHandle, Priority};
+
+    // Reads a line in a job, which waits for it once this returns
+    fn read(executor: &mut Executor, discipline: &LineDiscipline) -> JoinHandle<Option<String>> {
+        let discipline = discipline.clone();
+        let handle = executor.spawner().spawn(
+            async move { discipline.read_line().await },
+            Priority::Normal,
+        );
+        executor.run_ready_tasks();
+        handle
+    }
+
+    let discipline = LineDiscipline::new(Arc::new(Mutex::new(MockTerminal::default())));
+    let raw = discipline.enter(TerminalMode::Raw, PartialLine::Discard);
+    assert_eq!(
+        type_keys(&discipline, "a\n"),
+        [
+            Input::Key(KeyPress {
+                key: DecodedKey::Unicode('a'),
+                modifiers: Modifiers::default()
+            }),
+            Input::Key(KeyPress {
+                key: DecodedKey::Unicode('\n'),
+                modifiers: Modifiers::default()
+            })
+        ]
+    );
+    assert!(matches!(ctrl(&discipline, 'c'), Some(Input::Key(_))));
+    drop(raw);
+    assert_eq!(discipline.mode(), TerminalMode::Cooked { echo: true });
+
+    // A line partly typed when switching to raw mode is either passed on or thrown away
+    let mut executor = Executor::new();
+    let mut line = read(&mut executor, &discipline);
+    type_keys(&discipline, "abc");
+    let raw = discipline.enter(TerminalMode::Raw, PartialLine::Flush);
+    executor.run_until_done();
+    assert_eq!(line.try_take(), Some(Some(String::from("abc"))));
+    drop(raw);
+    let mut line = read(&mut executor, &discipline);
+    type_keys(&discipline, "def");
+    let raw = discipline.enter(TerminalMode::Raw, PartialLine::Discard);
+    drop(raw);
+    type_keys(&discipline, "g\n");
+    executor.run_until_done();
+    assert_eq!(line.try_take(), Some(Some(String::from("g"))));
+
+    // The mode of the innermost guard holds, whichever guard is dropped first
+    discipline.set_mode(TerminalMode::Raw, PartialLine::Discard);
+    let cooked = discipline.enter(TerminalMode::Cooked { echo: true }, PartialLine::Flush);
+    let raw = discipline.enter(TerminalMode::Raw, PartialLine::Flush);
+    drop(cooked);
+    assert_eq!(discipline.mode(), TerminalMode::Raw);
+    drop(raw);
+    assert_eq!(discipline.mode(), TerminalMode::Raw);
+}
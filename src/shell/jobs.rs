@@ -0,0 +1,326 @@
+use core::{
+    fmt,
+    future::{poll_fn, Future},
+    pin::pin,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use spin::Mutex;
+
+use super::ShellError;
+use crate::{
+    error::KernelError,
+    fs::{
+        file::{FileSystemError, InodeKind},
+        vfs::{VfsFile, VfsRouter},
+    },
+};
+
+/// The files opened by every running job, by job id. Jobs add and remove their own files while
+/// `lsof` lists them from another task, so the table is only locked briefly. When the mount table
+/// is locked as well, it's locked first.
+static JOBS: Mutex<BTreeMap<usize, JobEntry>> = Mutex::new(BTreeMap::new());
+
+static NEXT_JOB_ID: AtomicUsize = AtomicUsize::new(1);
+// The job whose commands are being run, or 0 if none is
+static CURRENT_JOB: AtomicUsize = AtomicUsize::new(0);
+
+/// A small number standing for a file opened by a job, only valid within that job.
+pub type Fd = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    Read,
+    Write,
+    /// Writing at the end of the file.
+    Append,
+}
+
+impl fmt::Display for OpenMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Self::Read => "r",
+            Self::Write => "w",
+            Self::Append => "a",
+        })
+    }
+}
+
+struct JobEntry {
+    command: String,
+    files: BTreeMap<Fd, OpenFile>,
+}
+
+struct OpenFile {
+    path: String,
+    mode: OpenMode,
+    file: VfsFile,
+}
+
+/// A file open in a job, as listed by `lsof`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenFileInfo {
+    pub job: usize,
+    pub command: String,
+    pub fd: Fd,
+    pub path: String,
+    pub mode: OpenMode,
+}
+
+/// The files a job has open. The job is registered when this is created, and every file it still
+/// has open is closed when this is dropped, however the job ended.
+pub struct JobFiles {
+    id: usize,
+}
+
+impl JobFiles {
+    /// Registers a job running `command`.
+    pub fn new(command: &str) -> Self {
+        let id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+        let entry = JobEntry {
+            command: command.to_string(),
+            files: BTreeMap::new(),
+        };
+        JOBS.lock().insert(id, entry);
+        Self { id }
+    }
+
+    /// Runs the commands of the job in `future`, so that a panic in them is reported along with
+    /// the job, see [`PanickingJob`]. Jobs run inside of other jobs are the current one until
+    /// they're done.
+    pub async fn run<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        poll_fn(|cx| {
+            let outer = CURRENT_JOB.swap(self.id, Ordering::Relaxed);
+            let result = future.as_mut().poll(cx);
+            CURRENT_JOB.store(outer, Ordering::Relaxed);
+            result
+        })
+        .await
+    }
+
+    /// Opens the file at `path`, returning the lowest descriptor the job isn't using. The
+    /// filesystem it's on can't be unmounted until the file is closed.
+    pub fn open(&self, vfs: &VfsRouter, path: &str, mode: OpenMode) -> Result<Fd, FileSystemError> {
+        let mut file = vfs.open(path)?;
+        file.set_append(mode == OpenMode::Append);
+        let mut jobs = JOBS.lock();
+        let files = &mut jobs.get_mut(&self.id).unwrap().files;
+        let fd = (0..).find(|fd| !files.contains_key(fd)).unwrap();
+        let path = path.to_string();
+        files.insert(fd, OpenFile { path, mode, file });
+        Ok(fd)
+    }
+
+    /// Opens the file at `path` for the output of a command, creating it if it's missing. In
+    /// [`OpenMode::Write`] the file is emptied first, in [`OpenMode::Append`] its contents are
+    /// kept and the output goes after them.
+    pub fn open_output(
+        &self,
+        vfs: &mut VfsRouter,
+        path: &str,
+        mode: OpenMode,
+    ) -> Result<Fd, FileSystemError> {
+        let kind = match vfs.stat(path) {
+            Ok(metadata) => metadata.kind,
+            Err(FileSystemError::NotFound(_)) => {
+                vfs.create(path, InodeKind::File)?;
+                InodeKind::File
+            }
+            Err(err) => return Err(err),
+        };
+        if kind == InodeKind::Directory {
+            return Err(FileSystemError::IsADirectory(path.to_string()));
+        }
+        if mode == OpenMode::Write {
+            vfs.truncate(path, 0)?;
+        }
+        self.open(vfs, path, mode)
+    }
+
+    /// Reads from the file, see [`VfsRouter::read`].
+    pub fn read(&self, fd: Fd, vfs: &VfsRouter, buf: &mut [u8]) -> Result<usize, KernelError> {
+        let mut jobs = JOBS.lock();
+        let open = jobs
+            .get_mut(&self.id)
+            .and_then(|job| job.files.get_mut(&fd))
+            .ok_or(ShellError::BadDescriptor(fd))?;
+        Ok(vfs.read(&mut open.file, buf)?)
+    }
+
+    /// Writes to the file, see [`VfsRouter::write`].
+    pub fn write(&self, fd: Fd, vfs: &mut VfsRouter, data: &[u8]) -> Result<usize, KernelError> {
+        let mut jobs = JOBS.lock();
+        let open = jobs
+            .get_mut(&self.id)
+            .and_then(|job| job.files.get_mut(&fd))
+            .ok_or(ShellError::BadDescriptor(fd))?;
+        Ok(vfs.write(&mut open.file, data)?)
+    }
+
+    /// Moves the position of the file used by the next read or write.
+    pub fn seek(&self, fd: Fd, offset: usize) -> Result<(), KernelError> {
+        let mut jobs = JOBS.lock();
+        let open = jobs
+            .get_mut(&self.id)
+            .and_then(|job| job.files.get_mut(&fd))
+            .ok_or(ShellError::BadDescriptor(fd))?;
+        open.file.seek(offset);
+        Ok(())
+    }
+
+    /// Closes the file, after which the descriptor may be given to another file.
+    pub fn close(&self, fd: Fd) {
+        if let Some(job) = JOBS.lock().get_mut(&self.id) {
+            job.files.remove(&fd);
+        }
+    }
+}
+
+impl Drop for JobFiles {
+    fn drop(&mut self) {
+        // Dropping the handles outside of the lock, as they may take other locks
+        let entry = JOBS.lock().remove(&self.id);
+        drop(entry);
+    }
+}
+
+/// Names the job whose commands were running when the kernel panicked, as a line for the panic
+/// report, or nothing if no job was. There's no unwinding to stop the panic at the job, so this
+/// only tells which command to blame before the kernel halts. Doesn't allocate, and leaves out
+/// the command if the code which panicked holds the job table.
+pub struct PanickingJob;
+
+impl fmt::Display for PanickingJob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // An interrupt handler which panics isn't the job's doing
+        let id = CURRENT_JOB.load(Ordering::Relaxed);
+        if id == 0 || crate::interrupts::in_handler() {
+            return Ok(());
+        }
+        match JOBS.try_lock() {
+            Some(jobs) => match jobs.get(&id) {
+                Some(entry) => writeln!(f, "in job {}: {}", id, entry.command),
+                None => writeln!(f, "in job {}", id),
+            },
+            None => writeln!(f, "in job {}", id),
+        }
+    }
+}
+
+/// Returns the number of jobs running, in every shell.
+pub fn running() -> usize {
+    JOBS.lock().len()
+}
+
+/// Returns every file open in a job, ordered by job and descriptor.
+pub fn open_files() -> Vec<OpenFileInfo> {
+    let jobs = JOBS.lock();
+    jobs.iter()
+        .flat_map(|(&job, entry)| {
+            entry.files.iter().map(move |(&fd, open)| OpenFileInfo {
+                job,
+                command: entry.command.clone(),
+                fd,
+                path: open.path.clone(),
+                mode: open.mode,
+            })
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_panicking_job_names_command() {
+    use alloc::format;
+
+    use crate::task::{block_on, yield_now};
+
+    assert_eq!(format!("{}", PanickingJob), "");
+    let (outer, inner) = (JobFiles::new("outer job"), JobFiles::new("inner | job"));
+    let report = block_on(outer.run(async {
+        let before = format!("{}", PanickingJob);
+        // Still the current job after being suspended
+        let nested = inner.run(async {
+            yield_now().await;
+            format!("{}", PanickingJob)
+        });
+        (before, nested.await, format!("{}", PanickingJob))
+    }));
+    assert_eq!(report.0, format!("in job {}: outer job\n", outer.id));
+    assert_eq!(report.1, format!("in job {}: inner | job\n", inner.id));
+    assert_eq!(report.2, report.0);
+    assert_eq!(format!("{}", PanickingJob), "");
+}
+
+#[test_case]
+fn test_files_closed_when_job_is_killed() {
+    use crate::{
+        fs::{file::InodeKind, FILESYSTEM, VFS},
+        task::{executor::Executor, Priority},
+        timer,
+    };
+
+    crate::fs::init().unwrap();
+    FILESYSTEM
+        .lock()
+        .create_at("held", InodeKind::File)
+        .unwrap();
+    let in_job = |id: usize| {
+        open_files()
+            .into_iter()
+            .filter(|info| info.job == id)
+            .map(|info| (info.fd, info.path, info.mode))
+            .collect::<Vec<_>>()
+    };
+
+    // A job which opens a file and sleeps, never getting to close it
+    let mut executor = Executor::new();
+    let files = JobFiles::new("hold held");
+    let id = files.id;
+    let job = async move {
+        let fd = files.open(&VFS.lock(), "held", OpenMode::Read).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(files.read(fd, &VFS.lock(), &mut buf).unwrap(), 0);
+        timer::sleep(60000).await;
+        unreachable!("the job is killed while it sleeps");
+    };
+    let handle = executor.spawner().spawn(job, Priority::Normal);
+    executor.run_ready_tasks();
+    assert_eq!(in_job(id), [(0, "held".into(), OpenMode::Read)]);
+
+    // Killed while suspended, the job's future is dropped without running any further
+    drop(executor);
+    assert!(!handle.is_finished());
+    assert!(in_job(id).is_empty());
+    assert!(!JOBS.lock().contains_key(&id));
+
+    // Descriptors are reused once closed
+    let files = JobFiles::new("test");
+    let vfs = VFS.lock();
+    let first = files.open(&vfs, "held", OpenMode::Read).unwrap();
+    let second = files.open(&vfs, "held", OpenMode::Write).unwrap();
+    files.close(first);
+    assert_eq!(files.open(&vfs, "held", OpenMode::Read).unwrap(), first);
+    assert!(matches!(
+        files.read(second + 1, &vfs, &mut []),
+        Err(KernelError::Shell(ShellError::BadDescriptor(_)))
+    ));
+
+    let mut out = super::CommandOutput::Captured(String::new());
+    <super::Shell>::lsof(&[], &mut out).unwrap();
+    let listed = out.into_captured().unwrap();
+    let id = files.id;
+    assert!(listed.contains(&alloc::format!(
+        "{:>4}   0 r    held                 test\n",
+        id
+    )));
+    assert!(listed.contains(&alloc::format!(
+        "{:>4}   1 w    held                 test\n",
+        id
+    )));
+}
@@ -0,0 +1,210 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use crate::{
+    error::KernelError,
+    fs::{
+        file::{FileSystemError, InodeKind},
+        vfs::VfsRouter,
+    },
+    task::keyboard::Modifiers,
+};
+
+use super::ShellError;
+
+/// The directory macros are saved in, one file per macro.
+pub const MACRO_DIR: &str = "/macros";
+
+/// The raw keys which can be recorded, the ones the shell handles. Other raw keys do nothing, so
+/// they're left out of macros.
+const RAW_KEYS: [KeyCode; 5] = [
+    KeyCode::ArrowUp,
+    KeyCode::ArrowDown,
+    KeyCode::ArrowLeft,
+    KeyCode::ArrowRight,
+    KeyCode::Insert,
+];
+
+/// A key of a macro, with the modifiers the shell looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacroKey {
+    pub key: DecodedKey,
+    pub modifiers: Modifiers,
+}
+
+impl MacroKey {
+    /// Returns `None` for raw keys which can't be recorded.
+    pub fn new(key: DecodedKey, modifiers: Modifiers) -> Option<Self> {
+        if let DecodedKey::RawKey(code) = key {
+            if !RAW_KEYS.contains(&code) {
+                return None;
+            }
+        }
+        let modifiers = Modifiers {
+            shift: modifiers.shift,
+            ctrl: modifiers.ctrl,
+            alt: modifiers.alt,
+            ..Modifiers::default()
+        };
+        Some(Self { key, modifiers })
+    }
+
+    /// Whether this is Ctrl+X, which starts and stops recording.
+    pub fn is_record_toggle(&self) -> bool {
+        self.modifiers.ctrl && matches!(self.key, DecodedKey::Unicode('x' | 'X'))
+    }
+
+    /// Formats the key as a line of a macro file: the held modifiers as `s`, `c` and `a`, or `-`
+    /// if none are held, then the character as `u+` and its code point in hex or the name of the
+    /// raw key. Ctrl+A is `c u+0061`, and the left arrow `- ArrowLeft`.
+    fn to_line(self) -> String {
+        let mut flags = String::new();
+        for (held, flag) in [
+            (self.modifiers.shift, 's'),
+            (self.modifiers.ctrl, 'c'),
+            (self.modifiers.alt, 'a'),
+        ] {
+            if held {
+                flags.push(flag);
+            }
+        }
+        if flags.is_empty() {
+            flags.push('-');
+        }
+        match self.key {
+            DecodedKey::Unicode(c) => format!("{} u+{:04x}", flags, c as u32),
+            DecodedKey::RawKey(code) => format!("{} {:?}", flags, code),
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let (flags, key) = line.trim().split_once(' ')?;
+        let key = match key.strip_prefix("u+") {
+            Some(hex) => DecodedKey::Unicode(char::from_u32(u32::from_str_radix(hex, 16).ok()?)?),
+            None => DecodedKey::RawKey(*RAW_KEYS.iter().find(|code| format!("{:?}", code) == key)?),
+        };
+        if flags != "-" && !flags.chars().all(|flag| "sca".contains(flag)) {
+            return None;
+        }
+        let modifiers = Modifiers {
+            shift: flags.contains('s'),
+            ctrl: flags.contains('c'),
+            alt: flags.contains('a'),
+            ..Modifiers::default()
+        };
+        Some(Self { key, modifiers })
+    }
+}
+
+/// Formats a macro as the contents of its file, one key per line.
+pub fn serialize(keys: &[MacroKey]) -> String {
+    keys.iter().map(|key| key.to_line() + "\n").collect()
+}
+
+/// Parses the contents of a macro file. `name` is only used for the error.
+pub fn deserialize(name: &str, text: &str) -> Result<Vec<MacroKey>, ShellError> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            MacroKey::from_line(line)
+                .ok_or_else(|| ShellError::InvalidMacro(name.to_string(), i + 1))
+        })
+        .collect()
+}
+
+/// Checks that `name` can be used as the name of a macro file.
+pub fn check_name(name: &str) -> Result<(), ShellError> {
+    match name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        true => Err(ShellError::Usage("macro save <name>")),
+        false => Ok(()),
+    }
+}
+
+/// Writes a macro to its file in [`MACRO_DIR`], replacing the file if it exists.
+pub fn save(vfs: &mut VfsRouter, name: &str, keys: &[MacroKey]) -> Result<(), KernelError> {
+    if let Err(FileSystemError::NotFound(_)) = vfs.stat(MACRO_DIR) {
+        vfs.create(MACRO_DIR, InodeKind::Directory)?;
+    }
+    let path = format!("{}/{}", MACRO_DIR, name);
+    match vfs.stat(&path) {
+        Ok(_) => vfs.truncate(&path, 0)?,
+        Err(FileSystemError::NotFound(_)) => vfs.create(&path, InodeKind::File)?,
+        Err(err) => return Err(err.into()),
+    }
+    let mut file = vfs.open(&path)?;
+    vfs.write(&mut file, serialize(keys).as_bytes())?;
+    Ok(())
+}
+
+/// Reads a macro from its file in [`MACRO_DIR`].
+pub fn load(vfs: &VfsRouter, name: &str) -> Result<Vec<MacroKey>, KernelError> {
+    let path = format!("{}/{}", MACRO_DIR, name);
+    let size = match vfs.stat(&path) {
+        Ok(metadata) => metadata.size,
+        Err(FileSystemError::NotFound(_)) => {
+            return Err(ShellError::MacroNotFound(name.to_string()).into())
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut data = alloc::vec![0; size];
+    let mut file = vfs.open(&path)?;
+    let mut read = 0;
+    while read < size {
+        match vfs.read(&mut file, &mut data[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    data.truncate(read);
+    let text =
+        String::from_utf8(data).map_err(|_| ShellError::InvalidMacro(name.to_string(), 1))?;
+    Ok(deserialize(name, &text)?)
+}
+
+/// Returns the names of the macros saved in [`MACRO_DIR`].
+pub fn saved_names(vfs: &VfsRouter) -> Result<Vec<String>, KernelError> {
+    match vfs.list(MACRO_DIR) {
+        Ok(entries) => Ok(entries.into_iter().map(|entry| entry.name).collect()),
+        Err(FileSystemError::NotFound(_) | FileSystemError::NotMounted) => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[test_case]
+fn test_macro_file_format() {
+    let ctrl = Modifiers {
+        ctrl: true,
+        ..Modifiers::default()
+    };
+    let keys = [
+        MacroKey::new(DecodedKey::Unicode('a'), Modifiers::default()).unwrap(),
+        MacroKey::new(DecodedKey::Unicode('ä'), ctrl).unwrap(),
+        MacroKey::new(DecodedKey::RawKey(KeyCode::ArrowLeft), Modifiers::default()).unwrap(),
+        MacroKey::new(DecodedKey::Unicode('\n'), Modifiers::default()).unwrap(),
+    ];
+    let text = serialize(&keys);
+    assert_eq!(text, "- u+0061\nc u+00e4\n- ArrowLeft\n- u+000a\n");
+    assert_eq!(deserialize("m", &text).unwrap(), keys);
+
+    // Lock keys aren't kept, and raw keys the shell ignores aren't recorded
+    let caps = Modifiers {
+        caps_lock: true,
+        ..Modifiers::default()
+    };
+    assert_eq!(
+        MacroKey::new(DecodedKey::Unicode('A'), caps)
+            .unwrap()
+            .modifiers,
+        Modifiers::default()
+    );
+    assert!(MacroKey::new(DecodedKey::RawKey(KeyCode::F1), Modifiers::default()).is_none());
+    assert!(matches!(
+        deserialize("m", "- u+0061\nx ArrowUp\n"),
+        Err(ShellError::InvalidMacro(_, 2))
+    ));
+}
@@ -0,0 +1,5040 @@
+use core::{cell::RefCell, fmt};
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    format,
+    rc::Rc,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use futures_util::StreamExt as _;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+use thiserror_no_std::Error;
+
+use crate::{
+    allocator::{
+        self, memtest,
+        scrub::{self, ScrubStats},
+    },
+    boot, console,
+    error::{self, ErrorKind, KernelError, ResultExt},
+    fs::{
+        disk::{self, BlockDevice, Disk, DiskError, KernelDisk, BLOCK_SIZE},
+        ext2::Ext2Fs,
+        file::{
+            FileSystem, FileSystemError, INumber, InodeFlags, InodeKind, MountFlags, SuperblockCopy,
+        },
+        partitions::{self, Partition, PartitionError},
+        path,
+        ramfs::RamFs,
+        stress,
+        transfer::{self, SerialSource},
+        watch::WatchMask,
+        DISK_DEVICE, FILESYSTEM, VFS,
+    },
+    log, rand, rtc, screenshot,
+    settings::{self, SettingsError},
+    stack, statusbar, swap,
+    task::{
+        self,
+        cancel::CancellationToken,
+        executor::Spawner,
+        job::JoinHandle,
+        keyboard::{self, KeyPress, Modifiers},
+        select2, trace, yield_now, Either, Priority,
+    },
+    timer,
+    ui::{self, Style},
+    util::fmt::{write_padded, Align, HumanBytes, HumanDuration},
+    vgabuf::{self, ScreenSession},
+};
+
+use self::{
+    input::{Input, LineDiscipline, ModeGuard, PartialLine, TerminalMode},
+    jobs::{JobFiles, OpenMode},
+    macros::MacroKey,
+    pager::{PageLimit, Pager},
+    prompt::{Prompt, PromptCache, PromptInputs, PROMPT_VAR},
+    search::HistorySearch,
+    terminal::{Terminal, VgaTerminal},
+    text::MAX_LINE_LEN,
+    words::{Chain, Redirect, Stage, Variables},
+};
+
+pub mod editor;
+mod find;
+mod hex;
+pub mod input;
+pub mod jobs;
+pub mod macros;
+mod pager;
+pub mod prompt;
+mod script;
+mod search;
+pub mod terminal;
+mod text;
+mod top;
+mod words;
+
+/// Prompts shown for a new command unless the `PROMPT` variable sets another, and for a line
+/// continuing the previous one.
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
+/// How often [`keep_prompt_current`] checks whether the prompt changed.
+const PROMPT_REFRESH_MS: u64 = 1000;
+/// Commands which change the shell itself, so they always run in the shell rather than as a job.
+const BUILTINS: [&str; 3] = ["set", "unset", "macro"];
+/// Commands which finish right away, so they run in the shell when they're run on their own.
+const INSTANT_COMMANDS: [&str; 6] = ["echo", "help", "clear", "history", "statusbar", "color"];
+/// Commands which run a script, see [`Shell::run_script`].
+const SCRIPT_COMMANDS: [&str; 2] = ["sh", "run"];
+
+/// Number of kills remembered by the kill ring.
+const KILL_RING_SIZE: usize = 8;
+
+/// `cp` shows its progress for files of at least this many blocks.
+const COPY_PROGRESS_THRESHOLD: usize = 16;
+/// Number of blocks copied between each progress update.
+const COPY_PROGRESS_INTERVAL: usize = 4;
+/// The name of the device `mount` makes a new RAM filesystem for.
+const RAM_DEVICE: &str = "ram";
+/// Width of each column printed by `df`, which fits sizes like `1023.99 KiB`.
+const DF_COLUMN_WIDTH: usize = 12;
+/// Width of the device names printed by `lsblk`.
+const LSBLK_NAME_WIDTH: usize = 8;
+
+/// A shell reading keypresses and drawing on a [`Terminal`], by default the VGA screen. Each shell
+/// has its own input line and history.
+pub struct Shell<T: Terminal = VgaTerminal> {
+    terminal: Arc<Mutex<T>>,
+    buffer: Vec<char>,
+    cursor_pos: usize,
+    command_history: Vec<String>,
+    command_history_index: usize,
+    kill_ring: KillRing,
+    last_edit: LastEdit,
+    /// Where the selection made with Shift and the arrow keys started. It spans from here to the
+    /// cursor.
+    selection: Option<usize>,
+    /// The lines of a command continued with a trailing backslash, without the backslashes.
+    pending: Option<Vec<String>>,
+    /// Whether typed characters replace the character under the cursor instead of being inserted.
+    overwrite: bool,
+    /// The command line as last drawn after the prompt, or `None` if the input line has to be
+    /// drawn from scratch.
+    rendered: Option<Vec<char>>,
+    prompt_cache: PromptCache,
+    variables: Variables,
+    /// Reads the answers to questions asked by jobs.
+    discipline: LineDiscipline,
+    /// Keeps the terminal in raw mode while the command line is edited, the shell doing its own
+    /// editing. Dropped while a job runs, so that the job reads cooked lines.
+    editing: Option<ModeGuard>,
+    spawner: Option<Spawner>,
+    /// The command running as a job, during which the input line is hidden.
+    job: Option<ForegroundJob>,
+    /// The macros saved with `macro save`, by name.
+    macros: BTreeMap<String, Vec<MacroKey>>,
+    /// The keys typed since Ctrl+X started recording.
+    recording: Option<Vec<MacroKey>>,
+    /// The keys of the last finished recording, which `macro save` saves.
+    recorded: Vec<MacroKey>,
+    /// A macro to play once the key which ran `macro play` has been handled.
+    playback: Option<Playback>,
+    /// Whether a macro is being played, during which keys aren't recorded.
+    playing: bool,
+    /// Output which didn't fit on the screen, shown as keys are pressed. The input line is hidden
+    /// meanwhile.
+    pager: Option<Pager>,
+    /// Keeps the terminal in raw mode while paging.
+    paging: Option<ModeGuard>,
+    /// The history search started with Ctrl+R, shown instead of the input line.
+    search: Option<HistorySearch>,
+    /// The number of scripts running, each run by the one before.
+    script_depth: usize,
+}
+
+/// A command line running as a job on the executor, see [`Shell::set_spawner`].
+struct ForegroundJob {
+    /// Tells whether the commands succeeded, along with the output left to page through.
+    handle: JoinHandle<(bool, Option<Pager>)>,
+    cancel: CancellationToken,
+}
+
+/// What the commands of a pipeline can use besides their arguments and input.
+struct JobContext<'a> {
+    history: &'a [String],
+    cancel: &'a CancellationToken,
+    /// Lets commands ask questions, which only commands running as a job can.
+    reader: Option<&'a LineDiscipline>,
+    files: &'a JobFiles,
+}
+
+impl<'a> JobContext<'a> {
+    /// Returns the reader of the job's keys, failing with [`ShellError::NotAtPrompt`] unless
+    /// `command` runs as a job. Keys, file events and log messages only arrive while the executor
+    /// runs, which it doesn't outside of a job.
+    fn at_prompt(&self, command: &'static str) -> Result<&'a LineDiscipline, ShellError> {
+        self.reader.ok_or(ShellError::NotAtPrompt(command))
+    }
+}
+
+/// The state of the shell which is changed by [`BUILTINS`].
+struct BuiltinState<'a> {
+    variables: &'a mut Variables,
+    macros: &'a mut BTreeMap<String, Vec<MacroKey>>,
+    recorded: &'a [MacroKey],
+    playback: &'a mut Option<Playback>,
+    playing: bool,
+}
+
+/// The keys of a macro to play, and the number of timer ticks to wait before each key.
+struct Playback {
+    keys: Vec<MacroKey>,
+    delay: u64,
+}
+
+/// Bytes to write to a block of the disk, given to `blkwrite`.
+struct BlockWrite {
+    block: usize,
+    offset: usize,
+    bytes: Vec<u8>,
+}
+
+/// A readline-style ring of the most recently killed text, most recent first.
+struct KillRing {
+    entries: VecDeque<Vec<char>>,
+    yank_index: usize,
+}
+
+/// The previous editing action, used to merge consecutive kills and to allow cycling a yank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LastEdit {
+    Kill,
+    Yank { start: usize, len: usize },
+    Other,
+}
+
+#[derive(Error, Debug)]
+pub enum ShellError {
+    #[error("command not found: {0}")]
+    CommandNotFound(String),
+    #[error("!{0}: history entry out of range (history has {1} entries)")]
+    HistoryOutOfRange(usize, usize),
+    #[error("!{0}: no matching command in history")]
+    HistoryNotFound(String),
+    #[error("usage: {0}")]
+    Usage(&'static str),
+    #[error("syntax error: empty command in pipeline")]
+    EmptyPipelineCommand,
+    #[error("syntax error: missing file name after redirection")]
+    MissingRedirectPath,
+    #[error("line {0} is longer than {MAX_LINE_LEN} bytes")]
+    LineTooLong(usize),
+    #[error("syntax error: missing closing {0}")]
+    UnterminatedQuote(char),
+    #[error("{0}: bad substitution")]
+    BadSubstitution(String),
+    #[error("{0}: not a valid variable name")]
+    InvalidVariableName(String),
+    #[error("no match: {0}")]
+    NoMatch(String),
+    #[error("{0}: no such option")]
+    NoSuchOption(String),
+    #[error("interrupted")]
+    Interrupted,
+    #[error("{0}: no such macro")]
+    MacroNotFound(String),
+    #[error("{0}: line {1} is not a valid key")]
+    InvalidMacro(String, usize),
+    #[error("{0}: macros can't start recording")]
+    MacroRecords(String),
+    #[error("no macro has been recorded, record one with Ctrl+X")]
+    NothingRecorded,
+    #[error("macros can't be played by a macro")]
+    NestedMacro,
+    #[error("{0}")]
+    DeadCanary(allocator::DeadCanary),
+    #[error("{0}: can't ask for confirmation here, run it at the prompt")]
+    NoInput(&'static str),
+    #[error("bad file descriptor {0}")]
+    BadDescriptor(usize),
+    #[error("{0}: no such device")]
+    NoSuchDevice(String),
+    #[error("{0}: unknown filesystem type")]
+    UnknownFsType(String),
+    #[error("{0}: can only be run at the prompt")]
+    NotAtPrompt(&'static str),
+    #[error("syntax error: missing command next to {0}")]
+    MissingCommand(&'static str),
+    #[error("{0}: directives have to come before the first command")]
+    LateDirective(String),
+    #[error("{0}: unknown directive")]
+    UnknownDirective(String),
+    #[error("{0}: scripts are nested more than {max} deep", max = script::MAX_DEPTH)]
+    ScriptTooDeep(String),
+    #[error("{0}: stopped at line {1}")]
+    ScriptStopped(String, usize),
+    #[error("{0}: scripts can't be part of a pipeline or redirected")]
+    ScriptInPipeline(String),
+    #[error("{0}: not a text file")]
+    NotText(String),
+}
+
+impl error::Error for ShellError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::CommandNotFound(_)
+            | Self::HistoryOutOfRange(..)
+            | Self::HistoryNotFound(_)
+            | Self::MacroNotFound(_)
+            | Self::NothingRecorded
+            | Self::NoSuchDevice(_)
+            | Self::NoMatch(_) => ErrorKind::NotFound,
+            Self::UnknownFsType(_) | Self::NotText(_) => ErrorKind::Unsupported,
+            Self::Usage(_)
+            | Self::EmptyPipelineCommand
+            | Self::MissingRedirectPath
+            | Self::LineTooLong(_)
+            | Self::UnterminatedQuote(_)
+            | Self::BadSubstitution(_)
+            | Self::InvalidVariableName(_)
+            | Self::NoSuchOption(_)
+            | Self::InvalidMacro(..)
+            | Self::BadDescriptor(_)
+            | Self::MissingCommand(_)
+            | Self::LateDirective(_)
+            | Self::UnknownDirective(_) => ErrorKind::InvalidInput,
+            Self::MacroRecords(_)
+            | Self::NestedMacro
+            | Self::NoInput(_)
+            | Self::NotAtPrompt(_)
+            | Self::ScriptTooDeep(_)
+            | Self::ScriptInPipeline(_) => ErrorKind::Unsupported,
+            Self::ScriptStopped(..) => ErrorKind::Other,
+            Self::DeadCanary(_) => ErrorKind::Corrupt,
+            Self::Interrupted => ErrorKind::Other,
+        }
+    }
+}
+
+/// Where a command writes its output: the shell's terminal, or a buffer which is given to the
+/// next command of a pipeline. Paged output is written to the terminal until it fills the screen,
+/// the rest is held back for the pager.
+pub enum CommandOutput<'a> {
+    Console(&'a Mutex<dyn Terminal>),
+    Paged(&'a Mutex<dyn Terminal>, PageLimit),
+    Captured(String),
+}
+
+impl CommandOutput<'_> {
+    /// Writes formatted output, which can't fail, so that `write!` can be used without handling
+    /// errors.
+    pub fn write_fmt(&mut self, args: fmt::Arguments) {
+        match self {
+            Self::Console(terminal) => terminal.lock().write_str(&format!("{}", args)),
+            Self::Paged(terminal, limit) => {
+                limit.write(&mut *terminal.lock(), &format!("{}", args))
+            }
+            Self::Captured(buf) => {
+                let _ = fmt::Write::write_fmt(buf, args);
+            }
+        }
+    }
+
+    /// Replaces the current line of the terminal with a progress message, which is left out of
+    /// captured output.
+    fn progress(&mut self, args: fmt::Arguments) {
+        if let Self::Console(terminal) | Self::Paged(terminal, _) = self {
+            let mut terminal = terminal.lock();
+            terminal.clear_line();
+            terminal.write_str(&format!("{}", args));
+        }
+    }
+
+    /// Whether the output may be colored. Captured output never is.
+    fn colors(&self) -> bool {
+        match self {
+            Self::Console(terminal) | Self::Paged(terminal, _) => terminal.lock().colors(),
+            Self::Captured(_) => false,
+        }
+    }
+
+    fn set_colors(&mut self, enabled: bool) {
+        if let Self::Console(terminal) | Self::Paged(terminal, _) = self {
+            terminal.lock().set_colors(enabled);
+        }
+    }
+
+    /// The width to lay out output for. Captured output has no width, so that it's laid out one
+    /// item per line.
+    fn width(&self) -> usize {
+        match self {
+            Self::Console(terminal) | Self::Paged(terminal, _) => terminal.lock().width(),
+            Self::Captured(_) => 0,
+        }
+    }
+
+    fn into_captured(self) -> Option<String> {
+        match self {
+            Self::Console(_) | Self::Paged(..) => None,
+            Self::Captured(buf) => Some(buf),
+        }
+    }
+
+    fn into_pager(self) -> Option<Pager> {
+        match self {
+            Self::Paged(_, limit) => limit.into_pager(),
+            _ => None,
+        }
+    }
+}
+
+impl BlockWrite {
+    /// Checks the arguments of `blkwrite <block> <offset> <hexbytes>`.
+    fn parse(args: &[&str]) -> Result<Self, KernelError> {
+        const USAGE: &str = "blkwrite <block> <offset> <hexbytes>";
+        let &[block, offset, bytes] = args else {
+            return Err(ShellError::Usage(USAGE).into());
+        };
+        let write = Self {
+            block: hex::parse_number(block).ok_or(ShellError::Usage(USAGE))?,
+            offset: hex::parse_number(offset).ok_or(ShellError::Usage(USAGE))?,
+            bytes: hex::parse_bytes(bytes).ok_or(ShellError::Usage(USAGE))?,
+        };
+        check_block_range(write.block, write.offset, write.bytes.len())?;
+        Ok(write)
+    }
+}
+
+impl BuiltinState<'_> {
+    fn run(
+        &mut self,
+        command: &str,
+        args: &[&str],
+        out: &mut CommandOutput,
+    ) -> Result<(), KernelError> {
+        match command {
+            "set" => Self::set(self.variables, args, out)?,
+            "unset" => {
+                for name in args {
+                    self.variables.unset(name);
+                }
+            }
+            "macro" => self.macro_command(args, out)?,
+            _ => return Err(ShellError::CommandNotFound(command.to_string()).into()),
+        }
+        Ok(())
+    }
+
+    /// Saves the last recording, plays or lists macros. Saved macros are also written to
+    /// [`macros::MACRO_DIR`], so macros saved by another shell can be played.
+    fn macro_command(&mut self, args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        const USAGE: &str = "macro save <name> | play <name> [delay ticks] | list";
+        match args {
+            ["save", name] => {
+                macros::check_name(name)?;
+                if self.recorded.is_empty() {
+                    return Err(ShellError::NothingRecorded.into());
+                }
+                self.macros.insert(name.to_string(), self.recorded.to_vec());
+                macros::save(&mut VFS.lock(), name, self.recorded)?;
+            }
+            ["play", name, delay @ ..] => {
+                let delay = match delay {
+                    [] => 0,
+                    [delay] => delay.parse().map_err(|_| ShellError::Usage(USAGE))?,
+                    _ => return Err(ShellError::Usage(USAGE).into()),
+                };
+                if self.playing {
+                    return Err(ShellError::NestedMacro.into());
+                }
+                let keys = match self.macros.get(*name) {
+                    Some(keys) => keys.clone(),
+                    None => macros::load(&VFS.lock(), name)?,
+                };
+                if keys.iter().any(MacroKey::is_record_toggle) {
+                    return Err(ShellError::MacroRecords(name.to_string()).into());
+                }
+                *self.playback = Some(Playback { keys, delay });
+            }
+            ["list"] => {
+                let mut listed = self
+                    .macros
+                    .iter()
+                    .map(|(name, keys)| (name.clone(), Ok(keys.len())))
+                    .collect::<BTreeMap<_, _>>();
+                let vfs = VFS.lock();
+                for name in macros::saved_names(&vfs)? {
+                    listed
+                        .entry(name)
+                        .or_insert_with_key(|name| macros::load(&vfs, name).map(|keys| keys.len()));
+                }
+                for (name, keys) in listed {
+                    match keys {
+                        Ok(keys) => writeln!(out, "{:<16} {} keys", name, keys),
+                        Err(err) => writeln!(out, "{:<16} {}", name, err),
+                    }
+                }
+            }
+            _ => return Err(ShellError::Usage(USAGE).into()),
+        }
+        Ok(())
+    }
+
+    /// Sets the variables given as `NAME=value`, or lists every variable if none are given.
+    /// `set -o <option>` turns an option on and `set +o <option>` off, `set -o` lists them.
+    fn set(
+        vars: &mut Variables,
+        args: &[&str],
+        out: &mut CommandOutput,
+    ) -> Result<(), KernelError> {
+        const USAGE: &str = "set [NAME=value...] | set -o|+o [option]";
+        match args {
+            [] => {
+                for (name, value) in vars.iter() {
+                    writeln!(out, "{}={}", name, value);
+                }
+            }
+            ["-o"] => {
+                for option in words::OPTIONS {
+                    let state = if vars.option(option) { "on" } else { "off" };
+                    writeln!(out, "{:<12}{}", option, state);
+                }
+            }
+            [flag @ ("-o" | "+o"), option] => vars.set_option(option, *flag == "-o")?,
+            _ => {
+                for arg in args {
+                    let (name, value) = arg.split_once('=').ok_or(ShellError::Usage(USAGE))?;
+                    vars.set(name, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Shell {
+    /// Creates a shell on the VGA screen, reserving the bottom row for its prompt until dropped.
+    pub fn new() -> Self {
+        Self::with_terminal(VgaTerminal::new())
+    }
+}
+
+impl<T: Terminal + 'static> Shell<T> {
+    pub fn with_terminal(terminal: T) -> Self {
+        let terminal = Arc::new(Mutex::new(terminal));
+        let discipline = LineDiscipline::new(terminal.clone());
+        let mut shell = Self {
+            editing: Some(discipline.enter(TerminalMode::Raw, PartialLine::Discard)),
+            discipline,
+            terminal,
+            buffer: Vec::new(),
+            cursor_pos: 0,
+            command_history: Vec::new(),
+            command_history_index: 0,
+            kill_ring: KillRing::new(),
+            last_edit: LastEdit::Other,
+            selection: None,
+            pending: None,
+            overwrite: false,
+            rendered: None,
+            prompt_cache: PromptCache::new(),
+            variables: Variables::new(),
+            spawner: None,
+            job: None,
+            macros: BTreeMap::new(),
+            recording: None,
+            recorded: Vec::new(),
+            playback: None,
+            playing: false,
+            pager: None,
+            paging: None,
+            search: None,
+            script_depth: 0,
+        };
+        shell.render_input_line();
+        shell
+    }
+
+    /// Runs commands as foreground jobs on the executor of `spawner` from now on, so that keys are
+    /// still handled while they run. Without a spawner commands run to completion before
+    /// [`handle_keypress`](Self::handle_keypress) returns.
+    pub fn set_spawner(&mut self, spawner: Spawner) {
+        self.spawner = Some(spawner);
+    }
+
+    pub fn handle_keypress(&mut self, key: DecodedKey, modifiers: Modifiers) {
+        use pc_keyboard::KeyCode as KC;
+
+        self.collect_job();
+        if let DecodedKey::RawKey(KC::PrintScreen) = key {
+            self.print_screen();
+            return;
+        }
+        let (key, modifiers) = match self.discipline.handle_key(key, modifiers) {
+            Some(Input::Key(KeyPress { key, modifiers })) => (key, modifiers),
+            Some(Input::Interrupt) => {
+                if let Some(job) = &self.job {
+                    job.cancel.cancel();
+                }
+                return;
+            }
+            None => return,
+        };
+        if let Some(job) = &self.job {
+            // Keys of a job which switched to raw mode are dropped, Ctrl+C still cancels it
+            if modifiers.ctrl && matches!(key, DecodedKey::Unicode('c' | 'C')) {
+                job.cancel.cancel();
+            }
+            return;
+        }
+        if let Some(pager) = &mut self.pager {
+            let c = match key {
+                DecodedKey::Unicode('c' | 'C') if modifiers.ctrl => 'q',
+                DecodedKey::Unicode(c) => c,
+                DecodedKey::RawKey(_) => return,
+            };
+            if !pager.handle_key(c, &mut *self.terminal.lock()) {
+                self.pager = None;
+                self.paging = None;
+                self.rendered = None;
+                self.render_input_line();
+            }
+            return;
+        }
+        if let Some(key) = MacroKey::new(key, modifiers) {
+            if key.is_record_toggle() {
+                self.toggle_recording();
+                self.render_input_line();
+                return;
+            }
+            if let (Some(keys), false) = (&mut self.recording, self.playing) {
+                keys.push(key);
+            }
+        }
+        if self.search.is_some() {
+            self.handle_search_key(key, modifiers);
+            self.render_input_line();
+            self.play_requested_macro();
+            return;
+        }
+        let last_edit = core::mem::replace(&mut self.last_edit, LastEdit::Other);
+        // The selection only lasts while Shift is held to move the cursor
+        let selected = self.selected();
+        let anchor = self.selection.take();
+        if let (DecodedKey::RawKey(KC::ArrowLeft | KC::ArrowRight), true) = (key, modifiers.shift) {
+            self.selection = Some(anchor.unwrap_or(self.cursor_pos));
+        }
+        match key {
+            DecodedKey::Unicode(c) if modifiers.ctrl => match c.to_ascii_lowercase() {
+                'r' => {
+                    self.search = Some(HistorySearch::new());
+                    self.rendered = None;
+                }
+                'u' => self.kill(0..self.cursor_pos, last_edit),
+                'k' => self.kill(self.cursor_pos..self.buffer.len(), last_edit),
+                'y' => self.yank(),
+                'c' => match selected {
+                    Some(range) => self.kill_ring.push(self.buffer[range].to_vec()),
+                    None => self.cancel(),
+                },
+                _ => {}
+            },
+            DecodedKey::Unicode('y' | 'Y') if modifiers.alt => self.yank_pop(last_edit),
+            DecodedKey::Unicode(c) => match selected {
+                // Typing replaces the selection, Backspace and Delete remove it
+                Some(range) if c != '\n' => {
+                    self.buffer.drain(range.clone());
+                    self.cursor_pos = range.start;
+                    if !matches!(c, '\u{8}' | '\u{7f}') {
+                        self.insert_chars(&[c]);
+                    }
+                }
+                _ => self.process_unicode(c),
+            },
+            DecodedKey::RawKey(key) => match key {
+                KC::ArrowUp => {
+                    if self.command_history_index < self.command_history.len() {
+                        self.command_history_index += 1;
+                        self.replace_buffer_with_past_command();
+                    }
+                }
+                KC::ArrowDown => {
+                    if self.command_history_index > 0 {
+                        self.command_history_index -= 1;
+                        self.replace_buffer_with_past_command();
+                    }
+                }
+                KC::ArrowLeft => self.cursor_pos = self.cursor_pos.checked_sub(1).unwrap_or(0),
+                KC::ArrowRight => self.cursor_pos = self.buffer.len().min(self.cursor_pos + 1),
+                KC::Insert => self.overwrite = !self.overwrite,
+                _ => {}
+            },
+        }
+        self.render_input_line();
+        self.play_requested_macro();
+    }
+
+    /// Handles a key during a history search. Typed characters narrow the search and Ctrl+R moves
+    /// on to an older match. Enter runs the match, while Escape and the arrow keys put it in the
+    /// input line to be edited. Ctrl+C cancels the search along with the line being typed.
+    fn handle_search_key(&mut self, key: DecodedKey, modifiers: Modifiers) {
+        use pc_keyboard::KeyCode as KC;
+
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        let history = &self.command_history;
+        match key {
+            DecodedKey::Unicode(c) if modifiers.ctrl => match c.to_ascii_lowercase() {
+                'r' => search.older(history),
+                'c' => {
+                    self.search = None;
+                    self.cancel();
+                }
+                _ => {}
+            },
+            DecodedKey::Unicode('\u{8}') => search.pop(history),
+            DecodedKey::Unicode(c @ ('\n' | '\x1b')) => {
+                self.accept_search();
+                if c == '\n' {
+                    self.process_buffer();
+                }
+            }
+            DecodedKey::Unicode(c) if !modifiers.alt && !c.is_control() => search.push(c, history),
+            DecodedKey::RawKey(KC::ArrowUp | KC::ArrowDown | KC::ArrowLeft | KC::ArrowRight) => {
+                self.accept_search()
+            }
+            _ => {}
+        }
+    }
+
+    /// Ends the history search, putting the match in the input line.
+    fn accept_search(&mut self) {
+        let Some(search) = self.search.take() else {
+            return;
+        };
+        if let Some(found) = search.matched(&self.command_history) {
+            self.buffer = found.chars().collect();
+            self.cursor_pos = self.buffer.len();
+            self.command_history_index = 0;
+        }
+        self.rendered = None;
+    }
+
+    /// Saves what's on the screen to the next numbered file in [`screenshot::SCREENSHOT_DIR`]
+    /// when PrintScreen is pressed. Where it went is only printed at the prompt, so that it
+    /// doesn't end up in the middle of the output of a job or the pager.
+    fn print_screen(&mut self) {
+        let screen = vgabuf::save_screen();
+        let result = screenshot::save(&mut VFS.lock(), &screen, None);
+        if self.job.is_some() || self.pager.is_some() {
+            return;
+        }
+        self.terminal.lock().clear_line();
+        match result {
+            Ok(path) => self.print_line(format_args!("saved {}", path)),
+            Err(err) => self.print_error(err.context("screenshot not saved")),
+        }
+        self.render_input_line();
+    }
+
+    /// Starts recording keys into a macro, or stops and keeps the recording for `macro save`.
+    fn toggle_recording(&mut self) {
+        self.terminal.lock().clear_line();
+        match self.recording.take() {
+            None => {
+                self.print_line("recording a macro, Ctrl+X to stop");
+                self.recording = Some(Vec::new());
+            }
+            Some(keys) => {
+                self.print_line(format_args!("recorded {} keys", keys.len()));
+                self.recorded = keys;
+            }
+        }
+    }
+
+    /// Handles the keys of the macro `macro play` asked for as if they were typed. Commands run to
+    /// completion before the next key, rather than as jobs which would drop the keys typed while
+    /// they run.
+    fn play_requested_macro(&mut self) {
+        let Some(playback) = self.playback.take() else {
+            return;
+        };
+        let spawner = self.spawner.take();
+        self.playing = true;
+        for key in playback.keys {
+            if playback.delay > 0 {
+                task::block_on(timer::sleep_ticks(playback.delay));
+            }
+            self.handle_keypress(key.key, key.modifiers);
+        }
+        self.playing = false;
+        self.spawner = spawner;
+    }
+
+    /// Runs `line` as if it had been typed at the prompt.
+    pub fn execute(&mut self, line: &str) {
+        self.buffer = line.chars().collect();
+        self.cursor_pos = self.buffer.len();
+        self.render_input_line();
+        self.process_buffer();
+        self.render_input_line();
+        self.play_requested_macro();
+    }
+
+    fn prompt_inputs(&self) -> PromptInputs {
+        let terminal = self.terminal.lock();
+        let status = self.variables.get(words::STATUS);
+        PromptInputs {
+            jobs: jobs::running(),
+            overwrite: self.overwrite,
+            ..PromptInputs::new(
+                status.and_then(|status| status.parse().ok()).unwrap_or(0),
+                terminal.width(),
+                terminal.colors(),
+            )
+        }
+    }
+
+    /// Brings the prompt up to date with its template and the state of the shell. Returns `true`
+    /// if it was expanded again, which typing alone never does.
+    fn update_prompt(&mut self) -> bool {
+        let inputs = self.prompt_inputs();
+        let template = prompt_template(self.pending.is_some(), &self.variables);
+        self.prompt_cache.get(template, inputs).1
+    }
+
+    /// Draws the prompt again if what it shows changed while no key was pressed, like the time of
+    /// `\t` or the jobs counted by `\j`. Takes the status of a job which finished first. Called
+    /// once a second by [`keep_prompt_current`].
+    pub fn refresh_prompt(&mut self) {
+        self.collect_job();
+        // Jobs, the pager and searches have the line, and a terminal which shows what's typed
+        // itself would lose it
+        if self.job.is_some()
+            || self.pager.is_some()
+            || self.search.is_some()
+            || self.terminal.lock().local_echo()
+        {
+            return;
+        }
+        if self.update_prompt() {
+            self.rendered = None;
+            self.render_input_line();
+        }
+    }
+
+    fn prompt(&mut self) -> &str {
+        self.update_prompt();
+        &self.prompt_cache.prompt().text
+    }
+
+    /// Draws the prompt and the line being typed, with the cursor at the editing position. Only
+    /// the part of the line which changed since it was last drawn is rewritten.
+    fn render_input_line(&mut self) {
+        if self.job.is_some() || self.pager.is_some() {
+            return;
+        }
+        if let Some(search) = &self.search {
+            let found = search.matched(&self.command_history).unwrap_or("");
+            draw_input_line(&mut *self.terminal.lock(), &search.prompt(), found);
+            return;
+        }
+        // The prompt is colored as a whole, so it's drawn again if it changed
+        if self.update_prompt() {
+            self.rendered = None;
+        }
+        let prompt = self.prompt_cache.prompt();
+        let mut terminal = self.terminal.lock();
+        if terminal.local_echo() {
+            // The terminal shows what's typed, so only the prompt is drawn
+            if self.rendered.is_none() {
+                draw_input_line(&mut *terminal, &prompt.text, "");
+                self.rendered = Some(Vec::new());
+            }
+            return;
+        }
+        if let (Some(range), true) = (self.selected(), terminal.colors()) {
+            // The selection is drawn in reverse video, which the diffing below doesn't know about,
+            // so the line is drawn from scratch while there's one and once more after it's gone
+            let text = |chars: &[char]| chars.iter().collect::<String>();
+            let buffer = format!(
+                "{}{}{}{}{}",
+                text(&self.buffer[..range.start]),
+                ui::INVERSE,
+                text(&self.buffer[range.clone()]),
+                ui::NO_INVERSE,
+                text(&self.buffer[range.end..])
+            );
+            draw_input_line(&mut *terminal, &prompt.text, &buffer);
+            terminal.move_cursor(prompt.width + self.cursor_pos);
+            self.rendered = None;
+            return;
+        }
+        match &self.rendered {
+            Some(rendered) => {
+                let start = rendered
+                    .iter()
+                    .zip(&self.buffer)
+                    .take_while(|(old, new)| old == new)
+                    .count();
+                if start < rendered.len().max(self.buffer.len()) {
+                    // Blank out what's left of a longer line
+                    let blanks = rendered.len().saturating_sub(self.buffer.len());
+                    let text =
+                        self.buffer[start..].iter().collect::<String>() + &" ".repeat(blanks);
+                    terminal.move_cursor(prompt.width + start);
+                    terminal.write_str(&text);
+                }
+            }
+            None => {
+                let buffer = self.buffer.iter().collect::<String>();
+                draw_input_line(&mut *terminal, &prompt.text, &buffer);
+            }
+        }
+        terminal.move_cursor(prompt.width + self.cursor_pos);
+        self.rendered = Some(self.buffer.clone());
+    }
+
+    /// Writes a line of output, after which the input line has to be rendered again.
+    fn print_line(&mut self, line: impl fmt::Display) {
+        self.terminal.lock().write_str(&format!("{}\n", line));
+        self.rendered = None;
+    }
+
+    /// Writes an error as a line of output, in red if the terminal has colors.
+    fn print_error(&mut self, err: KernelError) {
+        write_error(&mut *self.terminal.lock(), &err);
+        self.rendered = None;
+    }
+
+    /// Writes an error like [`print_error`](Self::print_error), with `context` in front if there
+    /// is one.
+    fn print_error_in(&mut self, err: KernelError, context: Option<&str>) {
+        match context {
+            Some(context) => self.print_error(err.context(context)),
+            None => self.print_error(err),
+        }
+    }
+
+    /// Replaces the input line with `line` followed by `suffix`, and moves it into the output.
+    fn finish_input_line(&mut self, line: &str, suffix: &str) {
+        if self.terminal.lock().local_echo() {
+            self.print_line(suffix);
+            return;
+        }
+        let colors = self.terminal.lock().colors();
+        let prompt = ui::styled(Style::Prompt, colors, self.prompt());
+        let line = format!("{}{}{}", prompt, line, suffix);
+        self.terminal.lock().clear_line();
+        self.print_line(line);
+    }
+
+    fn replace_buffer_with_past_command(&mut self) {
+        self.buffer = if self.command_history_index == 0 {
+            Vec::new()
+        } else {
+            self.command_history
+                .get(self.command_history.len() - self.command_history_index)
+                .unwrap()
+                .chars()
+                .collect()
+        };
+        self.cursor_pos = self.buffer.len();
+    }
+
+    /// The part of the buffer which is selected, `None` if nothing is.
+    fn selected(&self) -> Option<core::ops::Range<usize>> {
+        let anchor = self.selection?;
+        let range = anchor.min(self.cursor_pos)..anchor.max(self.cursor_pos);
+        (!range.is_empty()).then_some(range)
+    }
+
+    /// Removes `range` from the buffer and stores it in the kill ring. Directly following a
+    /// previous kill, the text is merged into the most recent kill ring entry instead.
+    fn kill(&mut self, range: core::ops::Range<usize>, last_edit: LastEdit) {
+        if range.is_empty() {
+            return;
+        }
+
+        let killed: Vec<char> = self.buffer.drain(range.clone()).collect();
+        if last_edit != LastEdit::Kill {
+            self.kill_ring.push(killed);
+        } else if range.start < self.cursor_pos {
+            self.kill_ring.prepend(&killed);
+        } else {
+            self.kill_ring.append(&killed);
+        }
+        self.cursor_pos = range.start;
+        self.last_edit = LastEdit::Kill;
+    }
+
+    /// Inserts the most recent kill at the cursor.
+    fn yank(&mut self) {
+        self.kill_ring.yank_index = 0;
+        let text = match self.kill_ring.current() {
+            Some(text) => text.clone(),
+            None => return,
+        };
+        let start = self.cursor_pos;
+        self.insert_chars(&text);
+        self.last_edit = LastEdit::Yank {
+            start,
+            len: text.len(),
+        };
+    }
+
+    /// Replaces the text inserted by the previous yank with the next older kill.
+    fn yank_pop(&mut self, last_edit: LastEdit) {
+        let LastEdit::Yank { start, len } = last_edit else {
+            return;
+        };
+        let text = match self.kill_ring.rotate() {
+            Some(text) => text.clone(),
+            None => return,
+        };
+
+        self.buffer.drain(start..start + len);
+        self.cursor_pos = start;
+        self.insert_chars(&text);
+        self.last_edit = LastEdit::Yank {
+            start,
+            len: text.len(),
+        };
+    }
+
+    fn insert_chars(&mut self, chars: &[char]) {
+        self.buffer
+            .splice(self.cursor_pos..self.cursor_pos, chars.iter().copied());
+        self.cursor_pos += chars.len();
+    }
+
+    fn process_unicode(&mut self, c: char) {
+        match c {
+            '\n' => self.process_buffer(),
+            // match backspace
+            '\u{8}' => {
+                if self.cursor_pos == 0 {
+                    return;
+                }
+
+                self.cursor_pos -= 1;
+                if self.cursor_pos == self.buffer.len() {
+                    self.buffer.pop();
+                } else {
+                    self.buffer.remove(self.cursor_pos);
+                }
+            }
+            // match delete
+            '\u{7f}' => {
+                if self.cursor_pos < self.buffer.len() {
+                    self.buffer.remove(self.cursor_pos);
+                }
+            }
+            _ => {
+                if self.cursor_pos == self.buffer.len() {
+                    self.buffer.push(c);
+                } else if self.overwrite {
+                    self.buffer[self.cursor_pos] = c;
+                } else {
+                    self.buffer.insert(self.cursor_pos, c);
+                }
+                self.cursor_pos += 1;
+            }
+        }
+    }
+
+    /// Discards the line being typed, along with any lines it continues.
+    fn cancel(&mut self) {
+        let line = self.buffer.iter().collect::<String>();
+        self.finish_input_line(&line, "^C");
+        self.buffer.clear();
+        self.cursor_pos = 0;
+        self.pending = None;
+    }
+
+    fn process_buffer(&mut self) {
+        let line = self.buffer.iter().collect::<String>();
+        // Keep the command in the output above the prompt
+        self.finish_input_line(&line, "");
+        self.buffer.clear();
+        self.cursor_pos = 0;
+
+        // A single trailing backslash continues the command on the next line. The lines are
+        // joined with a space, so the command is stored in the history as a single line.
+        if let Some(continued) = line.strip_suffix('\\') {
+            if !continued.ends_with('\\') {
+                self.pending
+                    .get_or_insert_with(Vec::new)
+                    .push(continued.trim().to_string());
+                return;
+            }
+        }
+        let line = match self.pending.take() {
+            Some(mut lines) => {
+                lines.push(line.trim().to_string());
+                lines.retain(|line| !line.is_empty());
+                lines.join(" ")
+            }
+            None => line,
+        };
+        if line.is_empty() {
+            return;
+        }
+
+        let command = match self.expand_history(&line) {
+            Ok(command) => command,
+            Err(err) => {
+                self.print_error(err.into());
+                self.variables.set_status(false);
+                return;
+            }
+        };
+        if command != line {
+            self.print_line(&command);
+        }
+
+        // The commands run by a script aren't added, only the command running it
+        self.command_history.push(command.clone());
+        self.run_list(&command, None);
+    }
+
+    /// Runs the pipelines of a line chained with `&&` and `||`, each after the one before unless
+    /// it's skipped: `a && b` runs `b` only if `a` succeeded, and `a || b` only if `a` failed. `$?`
+    /// is updated after each pipeline which runs. Errors are printed with `context` in front, and
+    /// the return value tells whether the last pipeline which ran succeeded.
+    ///
+    /// A line with a single pipeline runs as a job if there's a spawner, whose status is only known
+    /// when it's done. The pipelines of a longer line run to completion one after the other.
+    fn run_list(&mut self, line: &str, context: Option<&str>) -> bool {
+        let list = match words::split_list(line) {
+            Ok(list) => list,
+            Err(err) => {
+                self.print_error_in(err.into(), context);
+                self.variables.set_status(false);
+                return false;
+            }
+        };
+        let chained = list.len() > 1;
+        let spawner = if chained { self.spawner.take() } else { None };
+        let mut success = true;
+        for (chain, pipeline) in list {
+            match chain {
+                Some(Chain::And) if !success => continue,
+                Some(Chain::Or) if success => continue,
+                _ => {}
+            }
+            let result = match self.script_path(pipeline) {
+                Some(path) => path.and_then(|path| self.run_script(&path)),
+                None => self.run_pipeline(pipeline).map(|()| true),
+            };
+            // A job reports how it went when it's done
+            if self.job.is_some() {
+                break;
+            }
+            success = matches!(result, Ok(true));
+            self.variables.set_status(success);
+            if let Err(err) = result {
+                self.print_error_in(err, context);
+            }
+        }
+        if chained {
+            self.spawner = spawner;
+        }
+        success
+    }
+
+    /// Returns the path of the script `pipeline` runs, if it's `sh <path>` or `run <path>`.
+    fn script_path(&self, pipeline: &str) -> Option<Result<String, KernelError>> {
+        // Anything else is left for `run_pipeline`, which reports syntax errors
+        let stages = words::split(pipeline).ok()?;
+        let [words] = &stages[..] else {
+            return None;
+        };
+        let stage = match words::expand_stage(words, &self.variables, glob) {
+            Ok(stage) => stage,
+            Err(err) => return Some(Err(err.into())),
+        };
+        let command = stage.words.first()?;
+        if !SCRIPT_COMMANDS.contains(&command.as_str()) || stage.output.is_some() {
+            return None;
+        }
+        Some(match &stage.words[1..] {
+            [path] => Ok(path.clone()),
+            _ => Err(ShellError::Usage("sh <path>").into()),
+        })
+    }
+
+    /// Runs the commands of the script at `path`, see [`script::parse`], as if each line had been
+    /// typed at the prompt. Commands run to completion one after the other, and errors are printed
+    /// with the path and line of the command. Returns whether the last command succeeded.
+    ///
+    /// With `@stop-on-error` the script stops at the first command which fails. Scripts can run
+    /// other scripts, [`script::MAX_DEPTH`] deep.
+    fn run_script(&mut self, path: &str) -> Result<bool, KernelError> {
+        if self.script_depth >= script::MAX_DEPTH {
+            return Err(ShellError::ScriptTooDeep(path.to_string()).into());
+        }
+        let script = script::load(&VFS.lock(), path)?;
+        let spawner = self.spawner.take();
+        self.script_depth += 1;
+        let mut result = Ok(true);
+        for (number, line) in &script.lines {
+            let success = self.run_list(line, Some(&format!("{}:{}", path, number)));
+            result = Ok(success);
+            if !success && script.stop_on_error {
+                result = Err(ShellError::ScriptStopped(path.to_string(), *number).into());
+                break;
+            }
+        }
+        self.script_depth -= 1;
+        self.spawner = spawner;
+        result
+    }
+
+    /// Runs [`script::RC_PATH`] at boot, if it exists.
+    pub fn run_rc(&mut self) {
+        if VFS.lock().stat(script::RC_PATH).is_err() {
+            return;
+        }
+        let success = self.run_script(script::RC_PATH).unwrap_or_else(|err| {
+            self.print_error(err);
+            false
+        });
+        self.variables.set_status(success);
+        self.render_input_line();
+    }
+
+    /// Runs the commands separated by `|`. The variables and glob patterns in the words of every
+    /// command are expanded first, see [`words::expand_stage`]. With a spawner the commands run as
+    /// a foreground job, unless they're builtins or a single instant command.
+    fn run_pipeline(&mut self, line: &str) -> Result<(), KernelError> {
+        let stages = words::split(line)?
+            .iter()
+            .map(|words| words::expand_stage(words, &self.variables, glob))
+            .collect::<Result<Vec<_>, _>>()?;
+        let builtin = stages
+            .iter()
+            .filter_map(|stage| stage.words.first())
+            .any(|command| BUILTINS.contains(&command.as_str()));
+        let instant = matches!(&stages[..], [Stage { words, .. }]
+            if words.first().is_some_and(|command| INSTANT_COMMANDS.contains(&command.as_str())));
+        let cancel = CancellationToken::new();
+        let spawner = match &self.spawner {
+            Some(spawner) if !builtin && !instant => spawner,
+            _ => {
+                let builtins = BuiltinState {
+                    variables: &mut self.variables,
+                    macros: &mut self.macros,
+                    recorded: &self.recorded,
+                    playback: &mut self.playback,
+                    playing: self.playing,
+                };
+                let files = JobFiles::new(line);
+                let job = JobContext {
+                    history: &self.command_history,
+                    cancel: &cancel,
+                    reader: None,
+                    files: &files,
+                };
+                let pipeline = Self::run_stages(&stages, &*self.terminal, &job, Some(builtins));
+                let pager = task::block_on(files.run(pipeline))?;
+                self.show_pager(pager);
+                if let Some(pager) = &self.pager {
+                    pager.draw_status(&mut *self.terminal.lock());
+                }
+                return Ok(());
+            }
+        };
+
+        let terminal: Arc<Mutex<dyn Terminal>> = self.terminal.clone();
+        let history = self.command_history.clone();
+        let template = String::from(prompt_template(false, &self.variables));
+        let inputs = self.prompt_inputs();
+        let reader = self.discipline.clone();
+        let files = JobFiles::new(line);
+        let job = {
+            let cancel = cancel.clone();
+            async move {
+                // Lets code which isn't handed the job, like a copy deep in the filesystem, see it
+                // cancelled
+                cancel.install();
+                let job = JobContext {
+                    history: &history,
+                    cancel: &cancel,
+                    reader: Some(&reader),
+                    files: &files,
+                };
+                let result = files
+                    .run(Self::run_stages(&stages, &*terminal, &job, None))
+                    .await;
+                let mut terminal = terminal.lock();
+                match &result {
+                    Ok(Some(pager)) => pager.draw_status(&mut *terminal),
+                    Ok(None) => {}
+                    Err(err) => write_error(&mut *terminal, err),
+                }
+                if !matches!(result, Ok(Some(_))) {
+                    // The shell only learns the status once it takes the result, so the prompt is
+                    // expanded here with it. This job is still registered, so it isn't counted.
+                    let inputs = PromptInputs {
+                        status: u8::from(result.is_err()),
+                        jobs: jobs::running().saturating_sub(1),
+                        ..inputs
+                    };
+                    let mut prompt = Prompt::default();
+                    prompt::expand(&template, &inputs, &mut prompt);
+                    draw_input_line(&mut *terminal, &prompt.text, "");
+                    terminal.move_cursor(prompt.width);
+                }
+                (result.is_ok(), result.ok().flatten())
+            }
+        };
+        self.job = Some(ForegroundJob {
+            handle: spawner.spawn(job, Priority::Normal),
+            cancel,
+        });
+        // Back to cooked mode, so that the job can ask questions
+        self.editing = None;
+        Ok(())
+    }
+
+    /// Takes the status of the foreground job if it has finished, after which keys are handled
+    /// again. The job has already drawn the prompt, or the status of the pager if its output
+    /// didn't fit on the screen.
+    fn collect_job(&mut self) {
+        let Some((success, pager)) = self.job.as_mut().and_then(|job| job.handle.try_take()) else {
+            return;
+        };
+        self.job = None;
+        // Keys typed ahead while the job ran don't end up on the command line
+        self.editing = Some(
+            self.discipline
+                .enter(TerminalMode::Raw, PartialLine::Discard),
+        );
+        self.show_pager(pager);
+        self.variables.set_status(success);
+        self.rendered = None;
+    }
+
+    /// Pages through `pager`, or stops paging if it's `None`.
+    fn show_pager(&mut self, pager: Option<Pager>) {
+        self.paging = pager.as_ref().map(|_| {
+            self.discipline
+                .enter(TerminalMode::Raw, PartialLine::Discard)
+        });
+        self.pager = pager;
+    }
+
+    /// Runs the commands of a pipeline, giving the output of each command to the next one as its
+    /// input. Output redirected to a file is written to it once the command has finished, and the
+    /// next command gets no input. Builtins can only be run if `builtins` is given. Returns a pager
+    /// for the output of the last command if it didn't fit on the screen.
+    async fn run_stages(
+        stages: &[Stage],
+        terminal: &Mutex<dyn Terminal>,
+        job: &JobContext<'_>,
+        mut builtins: Option<BuiltinState<'_>>,
+    ) -> Result<Option<Pager>, KernelError> {
+        let mut input = None;
+        let mut pager = None;
+        for (i, stage) in stages.iter().enumerate() {
+            if job.cancel.is_cancelled() {
+                return Err(ShellError::Interrupted.into());
+            }
+            let mut words = stage.words.iter().map(String::as_str);
+            let command = match words.next() {
+                Some(command) => command,
+                None if stages.len() > 1 => return Err(ShellError::EmptyPipelineCommand.into()),
+                None => "",
+            };
+            let args = words.collect::<Vec<_>>();
+            let last = i + 1 == stages.len();
+            let height = terminal.lock().height();
+            // The file is opened before the command runs, so that it's listed by `lsof` meanwhile
+            let redirect = match &stage.output {
+                Some((redirect, path)) => {
+                    let mode = match redirect {
+                        Redirect::Truncate => OpenMode::Write,
+                        Redirect::Append => OpenMode::Append,
+                    };
+                    Some(job.files.open_output(&mut VFS.lock(), path, mode)?)
+                }
+                None => None,
+            };
+            let mut out = match (last && redirect.is_none(), height) {
+                (false, _) => CommandOutput::Captured(String::new()),
+                (true, None) => CommandOutput::Console(terminal),
+                (true, Some(height)) => {
+                    // A row is left for the command line, which is shown above the output
+                    let width = terminal.lock().width();
+                    CommandOutput::Paged(terminal, PageLimit::new(height - 1, width))
+                }
+            };
+            match builtins.as_mut() {
+                Some(builtins) if BUILTINS.contains(&command) => {
+                    builtins.run(command, &args, &mut out)?
+                }
+                // A redirection on its own, like `> path`, only creates or empties the file
+                _ if stage.words.is_empty() => {}
+                _ => Self::run_command(command, &args, input.as_deref(), &mut out, job).await?,
+            }
+            if let Some(fd) = redirect {
+                let output = out.into_captured().unwrap_or_default();
+                job.files.write(fd, &mut VFS.lock(), output.as_bytes())?;
+                job.files.close(fd);
+                input = Some(String::new());
+            } else if last {
+                pager = out.into_pager();
+            } else {
+                input = out.into_captured();
+            }
+        }
+        Ok(pager)
+    }
+
+    /// Expands a leading history designator in `line`: `!!` is the last command, `!n` is
+    /// history entry `n` (1-based) and `!prefix` is the most recent command starting with
+    /// `prefix`. Anything after the designator is appended to the expanded command.
+    fn expand_history(&self, line: &str) -> Result<String, ShellError> {
+        let designator = match line.strip_prefix('!') {
+            Some(designator) => designator,
+            None => return Ok(line.to_string()),
+        };
+        let (event, rest) = designator.split_at(
+            designator
+                .find(char::is_whitespace)
+                .unwrap_or(designator.len()),
+        );
+        if event.is_empty() {
+            return Ok(line.to_string());
+        }
+
+        let expanded = if event == "!" {
+            self.command_history.last()
+        } else if let Ok(n) = event.parse::<usize>() {
+            match n.checked_sub(1).and_then(|i| self.command_history.get(i)) {
+                Some(command) => Some(command),
+                None => return Err(ShellError::HistoryOutOfRange(n, self.command_history.len())),
+            }
+        } else {
+            self.command_history
+                .iter()
+                .rev()
+                .find(|command| command.starts_with(event))
+        };
+
+        match expanded {
+            Some(command) => Ok(format!("{}{}", command, rest)),
+            None => Err(ShellError::HistoryNotFound(event.to_string())),
+        }
+    }
+
+    /// Shows what the kernel's executor did: how many times it did what with `trace`, and the last
+    /// events with `trace dump`. `trace reset` forgets the events.
+    fn trace(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        match args {
+            [] => {
+                let counts = trace::COUNTS.counts();
+                writeln!(
+                    out,
+                    "{} spawned, {} completed, {} polls ({} pending)",
+                    counts.spawned, counts.completed, counts.polls, counts.pending
+                );
+                writeln!(
+                    out,
+                    "{} wakes, {} from interrupts",
+                    counts.wakes, counts.irq_wakes
+                );
+            }
+            ["dump"] => {
+                writeln!(out, "{:>10} {:>6}  event", "ticks", "task");
+                for record in trace::RING.records() {
+                    writeln!(out, "{}", record);
+                }
+                match trace::RING.dropped() {
+                    0 => {}
+                    dropped => writeln!(out, "{} events dropped", dropped),
+                }
+            }
+            ["reset"] => trace::RING.reset(),
+            _ => return Err(ShellError::Usage("trace [dump|reset]").into()),
+        }
+        Ok(())
+    }
+
+    /// Shows heap usage, with `-v` also the free blocks of each size. `compact` merges free
+    /// blocks instead.
+    fn mem(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        let verbose = match args {
+            [] => false,
+            ["-v"] => true,
+            ["compact"] => {
+                match allocator::compact() {
+                    Some(merges) => writeln!(out, "merged {} pairs of free blocks", merges),
+                    None => writeln!(out, "only the fixed size allocator can be compacted"),
+                }
+                return Ok(());
+            }
+            ["check"] => {
+                allocator::check_canaries().map_err(ShellError::DeadCanary)?;
+                writeln!(out, "canaries ok");
+                return Ok(());
+            }
+            _ => return Err(ShellError::Usage("mem [-v|compact|check]").into()),
+        };
+        let stats = allocator::stats();
+        writeln!(
+            out,
+            "heap: {} of {} used, {} allocations since boot",
+            HumanBytes(stats.used as u64),
+            HumanBytes(stats.total as u64),
+            stats.allocations
+        );
+        writeln!(out, "peak: {} used", HumanBytes(stats.peak as u64));
+        if stats.scrub.enabled || stats.scrub.scrubbed_blocks > 0 {
+            Self::scrub_stats(&stats.scrub, out);
+        }
+        if let Some((used, total)) = swap::usage() {
+            writeln!(out, "swap: {} of {} pages used", used, total);
+        }
+        if let (true, Some(report)) = (verbose, stats.fragmentation) {
+            for (size, count) in allocator::fixed::BLOCK_SIZES.iter().zip(report.free_blocks) {
+                writeln!(out, "{:>6} bytes: {} free", size, count);
+            }
+            writeln!(
+                out,
+                "{} in free blocks, {} untouched",
+                HumanBytes(report.free_bytes as u64),
+                HumanBytes(report.untouched_bytes as u64)
+            );
+        }
+        Ok(())
+    }
+
+    fn scrub_stats(stats: &ScrubStats, out: &mut CommandOutput) {
+        writeln!(
+            out,
+            "scrubbed: {} blocks, {}, {} quarantined",
+            stats.scrubbed_blocks,
+            HumanBytes(stats.scrubbed_bytes as u64),
+            stats.quarantined
+        );
+        if stats.bypassed > 0 {
+            writeln!(
+                out,
+                "{} blocks freed unscrubbed, the quarantine was full",
+                stats.bypassed
+            );
+        }
+    }
+
+    /// Lists the files open in every job, with the command line of the job.
+    fn lsof(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage("lsof").into());
+        }
+        writeln!(
+            out,
+            "{:>4} {:>3} {:<4} {:<20} command",
+            "job", "fd", "mode", "path"
+        );
+        for file in jobs::open_files() {
+            writeln!(
+                out,
+                "{:>4} {:>3} {:<4} {:<20} {}",
+                file.job, file.fd, file.mode, file.path, file.command
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes bytes to a block of the disk, once the user has confirmed the write.
+    async fn blkwrite(
+        args: &[&str],
+        out: &mut CommandOutput<'_>,
+        reader: Option<&LineDiscipline>,
+    ) -> Result<(), KernelError> {
+        let write = BlockWrite::parse(args)?;
+        let reader = reader.ok_or(ShellError::NoInput("blkwrite"))?;
+        writeln!(
+            out,
+            "writing {} bytes to block {} at offset {} can corrupt the filesystem",
+            write.bytes.len(),
+            write.block,
+            write.offset
+        );
+        if !reader.confirm("continue?").await {
+            writeln!(out, "nothing written");
+            return Ok(());
+        }
+        // Inodes cached by the filesystem are written first and read again after, in case the
+        // block holds some of them
+        let fs = FILESYSTEM.lock();
+        fs.sync()?;
+        disk::write(write.block, write.offset, &write.bytes)?;
+        disk::flush()?;
+        fs.invalidate_inode_cache();
+        writeln!(out, "wrote {} bytes", write.bytes.len());
+        Ok(())
+    }
+
+    /// Runs a single command. `input` is the output of the previous command of a pipeline.
+    async fn run_command(
+        command: &str,
+        args: &[&str],
+        input: Option<&str>,
+        out: &mut CommandOutput<'_>,
+        job: &JobContext<'_>,
+    ) -> Result<(), KernelError> {
+        match command {
+            "echo" => text::echo(args, out),
+            "help" => {
+                writeln!(out, "Available commands:");
+                for command in [
+                    "echo",
+                    "help",
+                    "clear",
+                    "history",
+                    "set",
+                    "unset",
+                    "cp",
+                    "ls",
+                    "find",
+                    "touch",
+                    "mkdir",
+                    "rm",
+                    "ln",
+                    "mv",
+                    "time",
+                    "sleep",
+                    "stackwatch",
+                    "mem",
+                    "top",
+                    "trace",
+                    "uname",
+                    "bootinfo",
+                    "date",
+                    "verify",
+                    "compress",
+                    "fsck",
+                    "fsstress",
+                    "memtest",
+                    "selftest",
+                    "scrub",
+                    "mount",
+                    "umount",
+                    "watch",
+                    "screenshot",
+                    "showshot",
+                    "format",
+                    "grep",
+                    "wc",
+                    "cat",
+                    "less",
+                    "edit",
+                    "statusbar",
+                    "split",
+                    "dmesg",
+                    "serial",
+                    "color",
+                    "settings",
+                    "sh",
+                    "run",
+                    "badblocks",
+                    "df",
+                    "lsblk",
+                    "fsdump",
+                    "fsload",
+                    "blkread",
+                    "blkwrite",
+                    "inode",
+                    "lsof",
+                    "macro",
+                ] {
+                    writeln!(out, "\t{}", command);
+                }
+            }
+            "clear" => {
+                for _ in 0..100 {
+                    writeln!(out);
+                }
+            }
+            "cp" => Self::cp(args, out, job.files, job.cancel).await?,
+            "ls" => {
+                let width = out.width();
+                for line in Self::ls(args, width, timer::ticks())? {
+                    writeln!(out, "{}", line);
+                }
+            }
+            "find" => find::find(args, out)?,
+            "touch" => Self::touch(args)?,
+            "mkdir" => Self::mkdir(args)?,
+            "rm" => Self::rm(args, job.cancel)?,
+            "ln" => Self::ln(args)?,
+            "mv" => Self::mv(args)?,
+            "time" => {
+                let Some((&command, args)) = args.split_first() else {
+                    return Err(ShellError::Usage("time <command> [args...]").into());
+                };
+                let (start, allocations) = (timer::ticks(), allocator::stats().allocations);
+                let result = Box::pin(Self::run_command(command, args, input, out, job)).await;
+                let elapsed = timer::ticks_to_millis(timer::ticks() - start);
+                let allocations = allocator::stats().allocations - allocations;
+                writeln!(out, "real {} ms, {} allocations", elapsed, allocations);
+                result?;
+            }
+            "stackwatch" => {
+                writeln!(
+                    out,
+                    "kernel stack: {} bytes in use, {} bytes total",
+                    stack::usage(),
+                    stack::STACK_SIZE
+                );
+                match stack::max_usage() {
+                    Some(max) => writeln!(out, "at most {} bytes used since boot", max),
+                    None => writeln!(out, "maximum usage unknown, the stack was not poisoned"),
+                }
+            }
+            "mem" => Self::mem(args, out)?,
+            "trace" => Self::trace(args, out)?,
+            "uname" => Self::uname(args, out)?,
+            "bootinfo" => match args {
+                [] => write!(out, "{}", boot::Table),
+                _ => return Err(ShellError::Usage("bootinfo").into()),
+            },
+            "date" => match args {
+                [] => writeln!(out, "{}", rtc::now()),
+                _ => return Err(ShellError::Usage("date").into()),
+            },
+            "top" => top::top(args, job.at_prompt("top")?, job.cancel).await?,
+            "sleep" => {
+                let &[ms] = args else {
+                    return Err(ShellError::Usage("sleep <ms>").into());
+                };
+                let ms = ms
+                    .parse::<u64>()
+                    .map_err(|_| ShellError::Usage("sleep <ms>"))?;
+                if let Either::Right(()) = select2(timer::sleep(ms), job.cancel.cancelled()).await {
+                    return Err(ShellError::Interrupted.into());
+                }
+            }
+            "verify" => Self::verify(args, out)?,
+            "compress" => Self::compress(args, out)?,
+            "fsck" => Self::fsck(args, out, job.cancel).await?,
+            "fsstress" => Self::fsstress(args, out)?,
+            "memtest" => Self::memtest(args, out)?,
+            "selftest" => Self::selftest(args, out, job.cancel)?,
+            "scrub" => match args {
+                ["on"] => scrub::enable(),
+                ["off"] => scrub::disable(),
+                [] => {
+                    let stats = scrub::stats();
+                    let state = if stats.enabled { "on" } else { "off" };
+                    writeln!(out, "heap scrubbing is {}", state);
+                    Self::scrub_stats(&stats, out);
+                }
+                _ => return Err(ShellError::Usage("scrub [on|off]").into()),
+            },
+            "mount" => Self::mount(args, out)?,
+            "umount" => Self::umount(args)?,
+            "watch" => {
+                job.at_prompt("watch")?;
+                Self::watch(args, out, job.cancel).await?;
+            }
+            "screenshot" => Self::screenshot(args, out)?,
+            "showshot" => Self::showshot(args, job.at_prompt("showshot")?, job.cancel).await?,
+            "format" => Self::format(args, out, job.reader).await?,
+            "grep" => text::grep(args, input, out, job.files)?,
+            "wc" => text::wc(args, input, out, job.files)?,
+            "cat" => text::cat(args, input, out, job.files)?,
+            "less" => text::less(args, input, out, job.files)?,
+            "edit" => editor::edit(args, job.at_prompt("edit")?, job.cancel).await?,
+            "lsof" => Self::lsof(args, out)?,
+            "history" => {
+                for (i, command) in job.history.iter().enumerate() {
+                    writeln!(out, "{:>4}  {}", i + 1, command);
+                }
+            }
+            "badblocks" => Self::badblocks(args, out)?,
+            "df" => Self::df(args, out)?,
+            "lsblk" => match args {
+                [] => Self::lsblk(out)?,
+                _ => return Err(ShellError::Usage("lsblk").into()),
+            },
+            "fsdump" => Self::fsdump(args, out).await?,
+            "blkread" => Self::blkread(args, out)?,
+            "blkwrite" => Self::blkwrite(args, out, job.reader).await?,
+            "inode" => Self::inode(args, out)?,
+            "fsload" => {
+                writeln!(out, "waiting for an image on the serial port");
+                // The filesystem is only touched once the whole image has arrived
+                let image = transfer::receive(&mut SerialSource::new(), disk::size()).await?;
+                let mut fs = FILESYSTEM.lock();
+                // The image replaces whatever is mounted, even if it was mounted read-only
+                fs.unmount();
+                let written = image.write_to(&mut KernelDisk);
+                // Mounted again even if a write failed, so that what did reach the disk is used
+                let mounted = fs.mount();
+                let blocks = written?;
+                mounted?;
+                writeln!(out, "loaded {} blocks", blocks);
+            }
+            "statusbar" => match args {
+                ["on"] => statusbar::enable(),
+                ["off"] => statusbar::disable(),
+                [] => writeln!(
+                    out,
+                    "status bar is {}",
+                    if statusbar::is_enabled() { "on" } else { "off" }
+                ),
+                _ => return Err(ShellError::Usage("statusbar [on|off]").into()),
+            },
+            "split" => match args {
+                ["on"] => {
+                    console::split();
+                }
+                ["off"] => {
+                    console::unsplit();
+                }
+                [] => writeln!(
+                    out,
+                    "the screen is {}",
+                    if vgabuf::is_split() {
+                        "split"
+                    } else {
+                        "not split"
+                    }
+                ),
+                _ => return Err(ShellError::Usage("split [on|off]").into()),
+            },
+            // On their own they're run by the shell itself, see `Shell::run_list`
+            "sh" | "run" => return Err(ShellError::ScriptInPipeline(command.to_string()).into()),
+            "dmesg" => {
+                if args == ["-f"] {
+                    job.at_prompt("dmesg")?;
+                }
+                Self::dmesg(args, out, job.cancel).await?;
+            }
+            "serial" => match args {
+                ["echo"] => writeln!(
+                    out,
+                    "serial echo is {}",
+                    if task::serial::echo() { "on" } else { "off" }
+                ),
+                ["echo", setting @ ("on" | "off")] => {
+                    task::serial::set_echo(*setting == "on");
+                    save_setting("serial.echo", setting, out);
+                }
+                _ => return Err(ShellError::Usage("serial echo [on|off]").into()),
+            },
+            "color" => match args {
+                [] => writeln!(
+                    out,
+                    "colors are {}",
+                    if out.colors() { "on" } else { "off" }
+                ),
+                [setting @ ("on" | "off")] => {
+                    let enabled = *setting == "on";
+                    out.set_colors(enabled);
+                    ui::set_console_colors(enabled);
+                    save_setting("color", setting, out);
+                }
+                _ => return Err(ShellError::Usage("color [on|off]").into()),
+            },
+            "settings" => match args {
+                [] => {
+                    for (key, value) in settings::all().iter() {
+                        writeln!(out, "{}={}", key, value);
+                    }
+                }
+                ["get", key] => match settings::get(key) {
+                    Some(value) => writeln!(out, "{}", value),
+                    None => return Err(SettingsError::NotSet(key.to_string()).into()),
+                },
+                ["set", key, value @ ..] if !value.is_empty() => {
+                    settings::set(key, &value.join(" "))?
+                }
+                ["unset", key] => settings::unset(key)?,
+                _ => {
+                    return Err(ShellError::Usage(
+                        "settings [get <key>|set <key> <value>|unset <key>]",
+                    )
+                    .into())
+                }
+            },
+            _ => return Err(ShellError::CommandNotFound(command.to_string()).into()),
+        }
+        Ok(())
+    }
+
+    /// Copies a file, showing the progress for large files. The destination must not exist unless
+    /// `-f` is passed. Copying into a directory keeps the name of the source file. Holes in the
+    /// source are skipped, so the copy takes no more blocks than the source.
+    async fn cp(
+        args: &[&str],
+        out: &mut CommandOutput<'_>,
+        files: &JobFiles,
+        cancel: &CancellationToken,
+    ) -> Result<(), KernelError> {
+        let (force, paths) = match args {
+            ["-f", paths @ ..] => (true, paths),
+            paths => (false, paths),
+        };
+        let &[src, dst] = paths else {
+            return Err(ShellError::Usage("cp [-f] <src> <dst>").into());
+        };
+
+        // Listed by lsof while the copy runs. The files may be on different filesystems, so the
+        // copy goes through the mount table a block at a time
+        let (size, dst, map, src_fd, dst_fd) = {
+            let mut vfs = VFS.lock();
+            let metadata = vfs.stat(src)?;
+            if metadata.kind == InodeKind::Directory {
+                return Err(FileSystemError::IsADirectory(src.to_string()).into());
+            }
+            let dst = match vfs.stat(dst) {
+                Ok(metadata) if metadata.kind == InodeKind::Directory => {
+                    let name = path::components(src).pop().unwrap_or(src);
+                    format!("{}/{}", dst, name)
+                }
+                _ => dst.to_string(),
+            };
+            match vfs.stat(&dst) {
+                Ok(metadata) if force => {
+                    if metadata.kind == InodeKind::Directory {
+                        return Err(FileSystemError::IsADirectory(dst).into());
+                    }
+                    // Emptying the destination would empty the source as well
+                    if vfs.same_file(src, &dst)? {
+                        return Ok(());
+                    }
+                }
+                Ok(_) => return Err(FileSystemError::AlreadyExists(dst).into()),
+                Err(FileSystemError::NotFound(_)) => {}
+                Err(err) => return Err(err.into()),
+            }
+            let map = vfs.block_map(src)?;
+            let src_fd = files.open(&vfs, src, OpenMode::Read)?;
+            let dst_fd = files.open_output(&mut vfs, &dst, OpenMode::Write)?;
+            (metadata.size, dst, map, src_fd, dst_fd)
+        };
+
+        let blocks = size.div_ceil(BLOCK_SIZE);
+        let show_progress = blocks >= COPY_PROGRESS_THRESHOLD;
+        // The mount table is only locked while a block is copied, yielding in between lets the
+        // keyboard task see a Ctrl+C and other jobs run
+        let copy = async {
+            let mut buf = [0; BLOCK_SIZE];
+            let mut copied = 0;
+            while copied < size {
+                if cancel.is_cancelled() {
+                    return Err(FileSystemError::Interrupted.into());
+                }
+                if map.get(copied / BLOCK_SIZE) == Some(&false) {
+                    copied = size.min(copied + BLOCK_SIZE);
+                    files.seek(src_fd, copied)?;
+                    files.seek(dst_fd, copied)?;
+                } else {
+                    let mut vfs = VFS.lock();
+                    let read = files.read(src_fd, &vfs, &mut buf)?;
+                    if read == 0 {
+                        break;
+                    }
+                    files.write(dst_fd, &mut vfs, &buf[..read])?;
+                    copied += read;
+                }
+                let copied_blocks = copied.div_ceil(BLOCK_SIZE);
+                if show_progress && (copied_blocks % COPY_PROGRESS_INTERVAL == 0 || copied == size)
+                {
+                    out.progress(format_args!("copied {}/{} blocks", copied_blocks, blocks));
+                }
+                yield_now().await;
+            }
+            // A hole at the end isn't written, but still counts towards the size
+            if map.last() == Some(&false) {
+                VFS.lock().truncate(&dst, size)?;
+            }
+            Ok::<_, KernelError>(())
+        };
+        let result = copy.await.context("cp: copy failed");
+        files.close(src_fd);
+        files.close(dst_fd);
+        result?;
+        if show_progress {
+            writeln!(out);
+        }
+        Ok(())
+    }
+
+    /// Creates an empty file, or sets the modification time of an existing one to now.
+    fn touch(args: &[&str]) -> Result<(), KernelError> {
+        let &[path] = args else {
+            return Err(ShellError::Usage("touch <path>").into());
+        };
+        let mut vfs = VFS.lock();
+        match vfs.touch(path) {
+            Err(FileSystemError::NotFound(_)) => vfs.create(path, InodeKind::File)?,
+            result => result?,
+        }
+        Ok(())
+    }
+
+    /// Creates a directory. With `-p` missing parent directories are created as well and an
+    /// existing directory is not an error.
+    fn mkdir(args: &[&str]) -> Result<(), KernelError> {
+        let mut vfs = VFS.lock();
+        match args {
+            ["-p", path] => vfs.create_dir_all(path)?,
+            [path] if !path.starts_with('-') => vfs.create(path, InodeKind::Directory)?,
+            _ => return Err(ShellError::Usage("mkdir [-p] <path>").into()),
+        }
+        Ok(())
+    }
+
+    /// Removes files or empty directories, stopping at the first which can't be removed. With `-r`
+    /// directories are removed along with their contents.
+    fn rm(args: &[&str], cancel: &CancellationToken) -> Result<(), KernelError> {
+        let (recursive, paths) = match args {
+            ["-r", paths @ ..] => (true, paths),
+            paths => (false, paths),
+        };
+        if paths.is_empty() || paths.iter().any(|path| path.starts_with('-')) {
+            return Err(ShellError::Usage("rm [-r] <path>...").into());
+        }
+        let mut vfs = VFS.lock();
+        for path in paths {
+            match recursive {
+                true => vfs.remove_recursive(path, Some(cancel))?,
+                false => vfs.remove(path)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds another name for an existing file.
+    fn ln(args: &[&str]) -> Result<(), KernelError> {
+        let &[existing, new] = args else {
+            return Err(ShellError::Usage("ln <existing> <newname>").into());
+        };
+        VFS.lock().link(existing, new)?;
+        Ok(())
+    }
+
+    /// Moves a file or directory to a path on the same filesystem which doesn't exist yet.
+    fn mv(args: &[&str]) -> Result<(), KernelError> {
+        let &[from, to] = args else {
+            return Err(ShellError::Usage("mv <from> <to>").into());
+        };
+        VFS.lock().rename(from, to)?;
+        Ok(())
+    }
+
+    /// Checks the contents of a file against its checksum, or of every file if no path is given.
+    fn verify(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        match args {
+            [] => {
+                let mismatches = FILESYSTEM.lock().verify_all()?;
+                for mismatch in &mismatches {
+                    writeln!(out, "{}", mismatch);
+                }
+                if mismatches.is_empty() {
+                    writeln!(out, "all files ok");
+                }
+            }
+            [path] => {
+                VFS.lock().verify(path)?;
+                writeln!(out, "{}: ok", path);
+            }
+            _ => return Err(ShellError::Usage("verify [path]").into()),
+        }
+        Ok(())
+    }
+
+    /// Rewrites a file with its contents compressed, or uncompressed with `-d`, and shows the space
+    /// they take up before and after. Compressing a compressed file packs it again, see
+    /// [`FileSystem::set_compressed`].
+    fn compress(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        let (compressed, path) = match args {
+            ["-d", path] => (false, *path),
+            [path] if !path.starts_with('-') => (true, *path),
+            _ => return Err(ShellError::Usage("compress [-d] <path>").into()),
+        };
+        let mut vfs = VFS.lock();
+        let before = vfs.stat(path)?;
+        if before.kind == InodeKind::Directory {
+            return Err(FileSystemError::IsADirectory(path.to_string()).into());
+        }
+        vfs.set_compressed(path, compressed)?;
+        let after = vfs.stat(path)?;
+        writeln!(
+            out,
+            "{}: {} on disk, was {}",
+            path,
+            HumanBytes(after.physical_size as u64),
+            HumanBytes(before.physical_size as u64)
+        );
+        Ok(())
+    }
+
+    /// Sends the blocks in use, or all blocks with `-a`, over the serial port. See
+    /// [`transfer::dump`] for the format.
+    async fn fsdump(args: &[&str], out: &mut CommandOutput<'_>) -> Result<(), KernelError> {
+        // The blocks are kept as they are now while they're sent, even if files are written
+        // meanwhile, so the image is of the filesystem at one point in time
+        let (blocks, snapshot) = {
+            let fs = FILESYSTEM.lock();
+            fs.sync()?;
+            let blocks = match args {
+                [] => {
+                    fs.check_mounted()?;
+                    // The backup superblock lies outside of the filesystem, but belongs in the
+                    // image
+                    let mut blocks = fs.used_blocks();
+                    blocks.push(SuperblockCopy::Backup.block());
+                    blocks
+                }
+                ["-a"] => (0..disk::size()).collect(),
+                _ => return Err(ShellError::Usage("fsdump [-a]").into()),
+            };
+            let snapshot = disk::snapshot(blocks.iter().copied())?;
+            (blocks, snapshot)
+        };
+        let blocks = transfer::send(&snapshot, blocks).await?;
+        writeln!(out, "dumped {} blocks", blocks);
+        Ok(())
+    }
+
+    /// Hexdumps `len` bytes of a block of the disk starting at `offset`, by default the rest of the
+    /// block. Doesn't need a mounted filesystem.
+    fn blkread(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        const USAGE: &str = "blkread <block> [offset] [len]";
+        let numbers = args
+            .iter()
+            .map(|arg| hex::parse_number(arg))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(ShellError::Usage(USAGE))?;
+        let (block, offset, len) = match numbers[..] {
+            [block] => (block, 0, BLOCK_SIZE),
+            [block, offset] => (block, offset, BLOCK_SIZE.saturating_sub(offset)),
+            [block, offset, len] => (block, offset, len),
+            _ => return Err(ShellError::Usage(USAGE).into()),
+        };
+        check_block_range(block, offset, len)?;
+        FILESYSTEM.lock().sync()?;
+        let mut bytes = vec![0; len];
+        disk::read(block, offset, &mut bytes)?;
+        for line in hex::hexdump(offset, &bytes) {
+            writeln!(out, "{}", line);
+        }
+        Ok(())
+    }
+
+    /// Shows the fields of an inode as they are on the disk, whether it's in use or not.
+    fn inode(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        let &[inumber] = args else {
+            return Err(ShellError::Usage("inode <inumber>").into());
+        };
+        let inumber = hex::parse_number(inumber)
+            .and_then(|inumber| INumber::try_from(inumber).ok())
+            .ok_or(ShellError::Usage("inode <inumber>"))?;
+        let inode = FILESYSTEM.lock().raw_inode(inumber)?;
+        let kind = match InodeKind::from_u8(inode.kind) {
+            Some(kind) => format!("{:?}", kind),
+            None => format!("unknown ({})", inode.kind),
+        };
+        let block = |ptr: u32| match ptr {
+            0 => "-".to_string(),
+            ptr => ptr.to_string(),
+        };
+        writeln!(out, "inode {}", inumber);
+        let flags = InodeFlags::from_bits(inode.flags);
+        writeln!(out, "  valid:      {}", flags.contains(InodeFlags::VALID));
+        writeln!(out, "  kind:       {}", kind);
+        writeln!(out, "  generation: {}", inode.generation);
+        writeln!(out, "  size:       {}", inode.size);
+        writeln!(out, "  checksum:   {:#010x}", inode.checksum);
+        writeln!(out, "  direct:     {}", inode.direct.map(block).join(" "));
+        writeln!(out, "  indirect:   {}", block(inode.indirect));
+        writeln!(out, "  links:      {}", inode.links);
+        writeln!(
+            out,
+            "  compressed: {}",
+            flags.contains(InodeFlags::COMPRESSED)
+        );
+        Ok(())
+    }
+
+    /// Lists the blocks the filesystem avoids, or adds one with `add <block>`.
+    fn badblocks(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        let mut fs = FILESYSTEM.lock();
+        match args {
+            [] => {
+                fs.check_mounted()?;
+                for block in fs.bad_blocks() {
+                    writeln!(out, "{}", block);
+                }
+            }
+            ["add", block] => {
+                let block = block
+                    .parse()
+                    .map_err(|_| ShellError::Usage("badblocks [add <block>]"))?;
+                fs.mark_bad(block)?;
+            }
+            _ => return Err(ShellError::Usage("badblocks [add <block>]").into()),
+        }
+        Ok(())
+    }
+
+    /// Checks the filesystem and prints the problems found. `--repair` first makes both copies of
+    /// the superblock match, restoring a damaged one from the other, and mounts the filesystem if
+    /// it wasn't mounted.
+    async fn fsck(
+        args: &[&str],
+        out: &mut CommandOutput<'_>,
+        cancel: &CancellationToken,
+    ) -> Result<(), KernelError> {
+        match args {
+            [] => {}
+            ["--repair"] => {
+                let mut fs = FILESYSTEM.lock();
+                for copy in fs.repair_superblock()? {
+                    writeln!(out, "restored the {} superblock", copy);
+                }
+                if !fs.is_mounted() {
+                    fs.mount()?;
+                    writeln!(out, "mounted the filesystem");
+                }
+            }
+            _ => return Err(ShellError::Usage("fsck [--repair]").into()),
+        }
+        // Checked an inode at a time, yielding in between so that Ctrl+C can stop a long check
+        let mut check = FILESYSTEM.lock().start_check()?;
+        while FILESYSTEM.lock().check_step(&mut check, Some(cancel))? {
+            yield_now().await;
+        }
+        let problems = check.finish();
+        for problem in &problems {
+            writeln!(out, "{}", problem);
+        }
+        if problems.is_empty() {
+            writeln!(out, "no problems found");
+        }
+        Ok(())
+    }
+
+    /// Runs random operations on files in `/fsstress` and checks that the files hold what was
+    /// written, see [`stress::stress`]. The seed is printed, so that a failing run can be repeated
+    /// by passing it.
+    fn fsstress(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        const USAGE: &str = "fsstress <ops> [seed]";
+        let parse = |arg: &str| arg.parse::<u64>().map_err(|_| ShellError::Usage(USAGE));
+        let (ops, seed) = match args {
+            [ops] => (parse(ops)?, rand::next_u64()),
+            [ops, seed] => (parse(ops)?, parse(seed)?),
+            _ => return Err(ShellError::Usage(USAGE).into()),
+        };
+        writeln!(out, "seed {}", seed);
+        let report = stress::stress(
+            &mut VFS.lock(),
+            "fsstress",
+            ops as usize,
+            &mut rand::Rng::new(seed),
+        )?;
+        writeln!(
+            out,
+            "{} creates, {} writes ({}), {} reads, {} deletes",
+            report.creates,
+            report.writes,
+            HumanBytes(report.written as u64),
+            report.reads,
+            report.deletes
+        );
+        writeln!(out, "{} files checked, no differences", report.files);
+        Ok(())
+    }
+
+    /// Allocates and frees random buffers on the kernel heap, checking that none of them overlap
+    /// or are written over, see [`memtest::memtest`]. Runs 100 iterations unless told otherwise.
+    fn memtest(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        const USAGE: &str = "memtest [iterations]";
+        let iterations = match args {
+            [] => 100,
+            [iterations] => iterations
+                .parse::<usize>()
+                .map_err(|_| ShellError::Usage(USAGE))?,
+            _ => return Err(ShellError::Usage(USAGE).into()),
+        };
+        let seed = rand::next_u64();
+        writeln!(out, "seed {}", seed);
+        let report = memtest::memtest(iterations, &mut rand::Rng::new(seed))?;
+        writeln!(
+            out,
+            "{} iterations, {} allocations, peak {} live",
+            report.iterations,
+            report.allocations,
+            HumanBytes(report.peak_live_bytes as u64)
+        );
+        if report.failed > 0 {
+            writeln!(
+                out,
+                "{} allocations failed with the heap full",
+                report.failed
+            );
+        }
+        writeln!(out, "no overlapping or corrupted blocks");
+        Ok(())
+    }
+
+    /// Runs short versions of the kernel's own checks: `memtest` of the heap and, if the filesystem
+    /// is mounted, `fsck`. Stops at the first problem found.
+    fn selftest(
+        args: &[&str],
+        out: &mut CommandOutput,
+        cancel: &CancellationToken,
+    ) -> Result<(), KernelError> {
+        /// Enough for the heap to be filled and freed a few times without taking long.
+        const MEMTEST_ITERATIONS: usize = 10;
+        if !args.is_empty() {
+            return Err(ShellError::Usage("selftest").into());
+        }
+        let seed = rand::next_u64();
+        let report = memtest::memtest(MEMTEST_ITERATIONS, &mut rand::Rng::new(seed))
+            .context(&format!("memtest with seed {}", seed))?;
+        writeln!(
+            out,
+            "memtest: ok, {} allocations, peak {} live",
+            report.allocations,
+            HumanBytes(report.peak_live_bytes as u64)
+        );
+        let fs = FILESYSTEM.lock();
+        if !fs.is_mounted() {
+            writeln!(out, "fsck: skipped, no filesystem is mounted");
+            return Ok(());
+        }
+        if let Some(problem) = fs.check(Some(cancel))?.into_iter().next() {
+            return Err(KernelError::from(problem).context("fsck"));
+        }
+        writeln!(out, "fsck: ok");
+        Ok(())
+    }
+
+    /// Lists the mounted filesystems, or mounts one. `ram <path>` mounts a new, empty filesystem
+    /// kept in memory on a directory.
+    ///
+    /// The filesystem on the disk is always mounted at `/`, `disk` mounts it again, for when it
+    /// failed to mount at boot or has been changed behind the filesystem's back, and `diskpN`
+    /// mounts the one in a partition of the disk instead, see [`partitions::attach`]. Without a
+    /// device the one mounted last is mounted again. The filesystem is left unmounted if it fails. With `-r` nothing is written to the disk while it's mounted, for
+    /// looking at a damaged image, and `-w` makes a filesystem mounted that way writable again if
+    /// `fsck` finds no problems.
+    ///
+    /// With `-t ext2` the device is an image file instead, see [`Self::mount_image`].
+    fn mount(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        const USAGE: &str = "mount [[-r|-w] disk[pN] | ram <path> | <image> <path> -t ext2 [-r]]";
+        let (mode, device) = match args {
+            [] => {
+                for mount in VFS.lock().mounts() {
+                    writeln!(
+                        out,
+                        "{} on {} type {}, {} files open",
+                        mount.device, mount.point, mount.kind, mount.open_files
+                    );
+                }
+                return Ok(());
+            }
+            _ if args.contains(&"-t") => return Self::mount_image(args, USAGE),
+            [mode @ ("-r" | "-w"), device @ ..] => (Some(*mode), device),
+            device => (None, device),
+        };
+        let partition = match (mode, device) {
+            (_, []) => partitions::attached(),
+            (_, [device]) if disk_partition(device).is_ok() => disk_partition(device)?,
+            (None, [RAM_DEVICE, point]) => {
+                VFS.lock()
+                    .mount(RAM_DEVICE, point, Box::new(RamFs::new()))?;
+                return Ok(());
+            }
+            (_, [device, ..]) if *device != RAM_DEVICE && disk_partition(device).is_err() => {
+                if device.starts_with('-') {
+                    return Err(ShellError::Usage(USAGE).into());
+                }
+                return Err(ShellError::NoSuchDevice(device.to_string()).into());
+            }
+            _ => return Err(ShellError::Usage(USAGE).into()),
+        };
+        let device = disk_device_name(partition);
+        if partition != partitions::attached() {
+            FILESYSTEM.lock().unmount();
+            Self::attach_partition(partition)?;
+        }
+        let mut fs = FILESYSTEM.lock();
+        if mode == Some("-w") && fs.is_mounted() {
+            fs.remount_writable()?;
+            writeln!(out, "remounted {} read-write", device);
+            return Ok(());
+        }
+        let read_only = mode == Some("-r");
+        fs.mount_with(match read_only {
+            true => MountFlags::READ_ONLY,
+            false => MountFlags::empty(),
+        })?;
+        if fs.mounted_from() == Some(SuperblockCopy::Backup) {
+            match read_only {
+                true => writeln!(
+                    out,
+                    "the primary superblock is damaged, mounted from the backup without \
+                     restoring it"
+                ),
+                false => writeln!(
+                    out,
+                    "the primary superblock is damaged, mounted from the backup; \
+                     run `fsck --repair` to restore it"
+                ),
+            }
+        }
+        writeln!(
+            out,
+            "mounted {}{}: {} blocks, {} free",
+            device,
+            if read_only { " read-only" } else { "" },
+            fs.blocks(),
+            fs.free_blocks()
+        );
+        Ok(())
+    }
+
+    /// Mounts the ext2 filesystem in an image file read-only on a directory, with the options
+    /// anywhere among the paths. The image is copied into a RAM disk, so it can't be written to
+    /// and changes to the file don't show up until it's mounted again.
+    fn mount_image(args: &[&str], usage: &'static str) -> Result<(), KernelError> {
+        let mut kind = None;
+        let mut paths = Vec::new();
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            match arg {
+                "-t" => kind = Some(*args.next().ok_or(ShellError::Usage(usage))?),
+                "-r" => {}
+                "-w" => return Err(FileSystemError::ReadOnly.into()),
+                _ if arg.starts_with('-') => return Err(ShellError::Usage(usage).into()),
+                _ => paths.push(arg),
+            }
+        }
+        let (Some(kind), &[image, point]) = (kind, paths.as_slice()) else {
+            return Err(ShellError::Usage(usage).into());
+        };
+        if kind != "ext2" {
+            return Err(ShellError::UnknownFsType(kind.to_string()).into());
+        }
+
+        let mut vfs = VFS.lock();
+        let size = vfs.stat(image)?.size;
+        let mut disk = Disk::new(size.div_ceil(BLOCK_SIZE));
+        let mut file = vfs.open(image)?;
+        let mut block = vec![0; BLOCK_SIZE];
+        for n in 0..size.div_ceil(BLOCK_SIZE) {
+            let len = BLOCK_SIZE.min(size - n * BLOCK_SIZE);
+            let mut read = 0;
+            while read < len {
+                match vfs.read(&mut file, &mut block[read..len])? {
+                    0 => return Err(FileSystemError::OffsetPastEnd(file.offset()).into()),
+                    bytes => read += bytes,
+                }
+            }
+            BlockDevice::write(&mut disk, n, &block[..len])?;
+        }
+        drop(file);
+        let fs = Ext2Fs::new(Box::new(disk))?;
+        vfs.mount(image, point, Box::new(fs))?;
+        Ok(())
+    }
+
+    /// Unmounts the filesystem mounted on a directory, which fails while files are open on it.
+    /// Whatever was on a RAM filesystem is gone once it's unmounted.
+    fn umount(args: &[&str]) -> Result<(), KernelError> {
+        let &[point] = args else {
+            return Err(ShellError::Usage("umount <path>").into());
+        };
+        VFS.lock().umount(point)?;
+        Ok(())
+    }
+
+    /// Prints the changes made to the file or directory at the path, or to the entries directly
+    /// in it, as they happen until the job is cancelled.
+    async fn watch(
+        args: &[&str],
+        out: &mut CommandOutput<'_>,
+        cancel: &CancellationToken,
+    ) -> Result<(), KernelError> {
+        let &[path] = args else {
+            return Err(ShellError::Usage("watch <path>").into());
+        };
+        let mut events = VFS.lock().watch(path, WatchMask::all())?;
+        while let Either::Left(Some(event)) = select2(events.next(), cancel.cancelled()).await {
+            writeln!(out, "{} {}", event.kind, event.path);
+        }
+        Ok(())
+    }
+
+    /// Prints the messages kept in the kernel log, and with `-f` the ones logged after them as they
+    /// arrive until the job is cancelled.
+    async fn dmesg(
+        args: &[&str],
+        out: &mut CommandOutput<'_>,
+        cancel: &CancellationToken,
+    ) -> Result<(), KernelError> {
+        let follow = match args {
+            [] => false,
+            ["-f"] => true,
+            _ => return Err(ShellError::Usage("dmesg [-f]").into()),
+        };
+        let (messages, mut pos) = log::messages_since(0);
+        write!(out, "{}", messages);
+        if !follow {
+            return Ok(());
+        }
+        while let Either::Left(()) = select2(log::wait_for_messages(pos), cancel.cancelled()).await
+        {
+            let (messages, next) = log::messages_since(pos);
+            write!(out, "{}", messages);
+            pos = next;
+        }
+        Ok(())
+    }
+
+    /// Saves what's on the screen, see [`screenshot::save`].
+    fn screenshot(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        let path = match args {
+            [] => None,
+            [path] => Some(*path),
+            _ => return Err(ShellError::Usage("screenshot [path]").into()),
+        };
+        let screen = vgabuf::save_screen();
+        let path = screenshot::save(&mut VFS.lock(), &screen, path)?;
+        writeln!(out, "saved {}", path);
+        Ok(())
+    }
+
+    /// Shows a screenshot on the whole screen until a key is pressed, then puts back the screen.
+    async fn showshot(
+        args: &[&str],
+        discipline: &LineDiscipline,
+        cancel: &CancellationToken,
+    ) -> Result<(), KernelError> {
+        let &[path] = args else {
+            return Err(ShellError::Usage("showshot <path>").into());
+        };
+        let screen = screenshot::load(&VFS.lock(), path)?;
+        let mut keys = keyboard::subscribe();
+        let _focus = keys.acquire_focus();
+        let _raw = discipline.enter(TerminalMode::Raw, PartialLine::Discard);
+        let _session = ScreenSession::start();
+        vgabuf::restore_screen(&screen);
+        select2(keys.next(), cancel.cancelled()).await;
+        Ok(())
+    }
+
+    /// Formats the disk, or a partition of it, and mounts the new, empty filesystem, once the user
+    /// has confirmed that every file on it may be lost. Without a device the one mounted last is
+    /// formatted, see [`Self::mount`].
+    async fn format(
+        args: &[&str],
+        out: &mut CommandOutput<'_>,
+        reader: Option<&LineDiscipline>,
+    ) -> Result<(), KernelError> {
+        const USAGE: &str = "format [disk[pN]] --force";
+        let partition = match args {
+            ["--force"] => partitions::attached(),
+            [device, "--force"] => disk_partition(device)?,
+            _ => return Err(ShellError::Usage(USAGE).into()),
+        };
+        let device = disk_device_name(partition);
+        let reader = reader.ok_or(ShellError::NoInput("format"))?;
+        writeln!(out, "formatting {} erases every file on it", device);
+        if !reader.confirm("continue?").await {
+            writeln!(out, "nothing formatted");
+            return Ok(());
+        }
+        FILESYSTEM.lock().unmount();
+        Self::attach_partition(partition)?;
+        let mut fs = FILESYSTEM.lock();
+        FileSystem::format()?;
+        fs.mount()?;
+        writeln!(out, "formatted {}: {} blocks", device, fs.blocks());
+        Ok(())
+    }
+
+    /// Makes the filesystem mounted at `/` live in a partition of the disk, or on the whole disk,
+    /// see [`partitions::attach`]. The filesystem has to be unmounted.
+    fn attach_partition(partition: Option<usize>) -> Result<(), KernelError> {
+        let attached = partitions::attach(partition);
+        // The whole disk is attached again if the partition can't be
+        VFS.lock()
+            .set_root_device(&disk_device_name(partitions::attached()));
+        Ok(attached?)
+    }
+
+    /// Prints the version of the kernel with the details of the build, and the constants which are
+    /// worth knowing when reporting a bug. `-s` only prints the one line banner shown at boot.
+    fn uname(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        let info = crate::version();
+        match args {
+            [] => {}
+            ["-s"] => {
+                writeln!(out, "{}", info);
+                return Ok(());
+            }
+            _ => return Err(ShellError::Usage("uname [-s]").into()),
+        }
+        let dirty = match info.dirty {
+            Some(true) => " (dirty)",
+            Some(false) => " (clean)",
+            None => "",
+        };
+        let features = match info.features {
+            [] => String::from("none"),
+            features => features.join(", "),
+        };
+        writeln!(out, "version:    {}", info.version);
+        writeln!(out, "git:        {}{}", info.git_hash, dirty);
+        writeln!(out, "profile:    {}", info.profile);
+        writeln!(out, "features:   {}", features);
+        writeln!(out, "allocator:  {}", allocator::selected().name());
+        let heap = HumanBytes(allocator::heap_info().size as u64);
+        writeln!(out, "heap:       {}", heap);
+        writeln!(out, "block size: {}", HumanBytes(BLOCK_SIZE as u64));
+        writeln!(out, "tick rate:  {} Hz", timer::tick_hz());
+        Ok(())
+    }
+
+    /// Shows how many blocks of the filesystem are used and how many are still free for files,
+    /// besides the ones reserved for directories, and how much compressed files save. `reserve
+    /// <blocks>` changes the reserve.
+    fn df(args: &[&str], out: &mut CommandOutput) -> Result<(), KernelError> {
+        const USAGE: &str = "df [reserve <blocks>]";
+        let mut fs = FILESYSTEM.lock();
+        match args {
+            [] => {}
+            ["reserve", blocks] => {
+                let blocks = blocks.parse().map_err(|_| ShellError::Usage(USAGE))?;
+                fs.set_reserved_blocks(blocks);
+                return Ok(());
+            }
+            _ => return Err(ShellError::Usage(USAGE).into()),
+        }
+        fs.check_mounted()?;
+        let (blocks, free) = (fs.blocks(), fs.free_blocks());
+        let reserved = fs.reserved_blocks().min(free);
+        let columns = [
+            ("size", blocks),
+            ("used", blocks - free),
+            ("avail", free - reserved),
+            ("reserved", reserved),
+        ];
+        // Writing to a `String` can't fail
+        let (mut header, mut row) = (String::new(), String::new());
+        for (name, blocks) in columns {
+            let _ = write_padded(&mut header, name, DF_COLUMN_WIDTH, Align::Right);
+            let size = HumanBytes((blocks * BLOCK_SIZE) as u64);
+            let _ = write_padded(&mut row, size, DF_COLUMN_WIDTH, Align::Right);
+        }
+        writeln!(out, "{}", header);
+        writeln!(out, "{}", row);
+        let compression = fs.compression_stats()?;
+        if compression.files > 0 {
+            writeln!(
+                out,
+                "{} compressed files hold {} in {}",
+                compression.files,
+                HumanBytes(compression.size as u64),
+                HumanBytes(compression.physical_size as u64)
+            );
+        }
+        Ok(())
+    }
+
+    /// Lists the disk and the partitions in its partition table, with their sizes and types.
+    /// Partitions are named after the disk, `diskp1` to `diskp4`, see [`disk_partition`].
+    fn lsblk(out: &mut CommandOutput) -> Result<(), KernelError> {
+        let (table, size) = partitions::with_whole_disk(|disk| {
+            let size = disk.size() * disk.block_size();
+            (partitions::read_table(disk), size)
+        });
+        let partitions = match table {
+            Ok(partitions) => partitions,
+            Err(PartitionError::NoPartitionTable) => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut row = |name: &str, size: &dyn fmt::Display, kind: &str| {
+            // Writing to a `String` can't fail
+            let mut line = String::new();
+            let _ = write_padded(&mut line, name, LSBLK_NAME_WIDTH, Align::Left);
+            let _ = write_padded(&mut line, size, DF_COLUMN_WIDTH, Align::Right);
+            writeln!(out, "{}  {}", line, kind);
+        };
+        row("NAME", &"SIZE", "TYPE");
+        row(DISK_DEVICE, &HumanBytes(size as u64), "disk");
+        for partition in partitions {
+            let kind = format!("{} ({:02x})", partition.type_name(), partition.kind);
+            let size = HumanBytes(partition.size());
+            row(&partition.device_name(DISK_DEVICE), &size, &kind);
+        }
+        Ok(())
+    }
+
+    /// Lists the entries of a directory sorted by name, or just the file if the path is a file.
+    /// `-l` shows the kind, size, block count and age of each entry at tick `now`, otherwise the
+    /// names are laid out in columns fitting in `width`. `-d` lists directories before files.
+    fn ls(args: &[&str], width: usize, now: u64) -> Result<Vec<String>, KernelError> {
+        let mut long = false;
+        let mut dirs_first = false;
+        let mut path = None;
+        for &arg in args {
+            match arg.strip_prefix('-') {
+                Some(flags) if !flags.is_empty() => {
+                    for flag in flags.chars() {
+                        match flag {
+                            'l' => long = true,
+                            'd' => dirs_first = true,
+                            _ => return Err(ShellError::Usage("ls [-l] [-d] [path]").into()),
+                        }
+                    }
+                }
+                _ if path.is_none() => path = Some(arg),
+                _ => return Err(ShellError::Usage("ls [-l] [-d] [path]").into()),
+            }
+        }
+        let path = path.unwrap_or("/");
+
+        let vfs = VFS.lock();
+        let metadata = vfs.stat(path)?;
+        let is_dir = metadata.kind == InodeKind::Directory;
+        let mut entries = match is_dir {
+            true => vfs
+                .list(path)?
+                .into_iter()
+                .map(|entry| (entry.name, entry.kind))
+                .collect(),
+            false => vec![(path.to_string(), metadata.kind)],
+        };
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        if dirs_first {
+            entries.sort_by_key(|&(_, kind)| kind != InodeKind::Directory);
+        }
+
+        if !long {
+            let column_width = entries
+                .iter()
+                .map(|(name, _)| name.len())
+                .max()
+                .unwrap_or(0)
+                + 2;
+            let columns = (width / column_width).max(1);
+            return Ok(entries
+                .chunks(columns)
+                .map(|row| {
+                    row.iter()
+                        .map(|(name, _)| format!("{:<column_width$}", name))
+                        .collect::<String>()
+                        .trim_end()
+                        .to_string()
+                })
+                .collect());
+        }
+
+        let rows = entries
+            .into_iter()
+            .map(|(name, _)| match is_dir {
+                // Entries are looked up by path, so that a mount point shows what's mounted there
+                true => {
+                    let entry = format!("{}/{}", path.trim_end_matches('/'), name);
+                    Ok((vfs.stat(&entry)?, name))
+                }
+                false => Ok((metadata, name)),
+            })
+            .collect::<Result<Vec<_>, FileSystemError>>()?;
+        let size_width = rows.iter().map(|(m, _)| m.size.to_string().len()).max();
+        let blocks_width = rows.iter().map(|(m, _)| m.blocks.to_string().len()).max();
+        let (size_width, blocks_width) = (size_width.unwrap_or(0), blocks_width.unwrap_or(0));
+        let ages = rows
+            .iter()
+            .map(|(metadata, _)| format_age(metadata.modified, now))
+            .collect::<Vec<_>>();
+        let age_width = ages.iter().map(|age| age.len()).max().unwrap_or(0);
+        Ok(rows
+            .into_iter()
+            .zip(ages)
+            .map(|((metadata, name), age)| {
+                let kind = match metadata.kind {
+                    InodeKind::File => '-',
+                    InodeKind::Directory => 'd',
+                    InodeKind::Device => 'c',
+                };
+                format!(
+                    "{} {:>size_width$} {:>blocks_width$} {:>age_width$} {}",
+                    kind, metadata.size, metadata.blocks, age, name
+                )
+            })
+            .collect())
+    }
+}
+
+/// Returns the template of the prompt for a new command, or for a line continuing the previous one.
+/// See [`prompt::expand`].
+fn prompt_template(continued: bool, variables: &Variables) -> &str {
+    match continued {
+        true => CONTINUATION_PROMPT,
+        false => variables.get(PROMPT_VAR).unwrap_or(PROMPT),
+    }
+}
+
+/// Draws the prompt of the shell again whenever what it shows changes while nothing is typed, see
+/// [`Shell::refresh_prompt`]. A check is skipped while the shell is busy with a key.
+pub async fn keep_prompt_current<T: Terminal + 'static>(shell: Rc<RefCell<Shell<T>>>) {
+    loop {
+        timer::sleep(PROMPT_REFRESH_MS).await;
+        if let Ok(mut shell) = shell.try_borrow_mut() {
+            shell.refresh_prompt();
+        }
+    }
+}
+
+/// Draws the input line from scratch, leaving the cursor at its end.
+fn draw_input_line(terminal: &mut dyn Terminal, prompt: &str, buffer: &str) {
+    let prompt = ui::styled(Style::Prompt, terminal.colors(), prompt);
+    terminal.clear_line();
+    terminal.write_str(&format!("{}{}", prompt, buffer));
+}
+
+/// Writes an error as a line of output, in red if the terminal has colors.
+fn write_error(terminal: &mut dyn Terminal, err: &KernelError) {
+    let err = ui::styled(Style::Error, terminal.colors(), err);
+    terminal.write_str(&format!("{}\n", err));
+}
+
+/// Returns the partition of the disk called `name`, or `None` for the whole disk.
+fn disk_partition(name: &str) -> Result<Option<usize>, ShellError> {
+    if name == DISK_DEVICE {
+        return Ok(None);
+    }
+    Partition::number_in_name(name, DISK_DEVICE)
+        .map(Some)
+        .ok_or_else(|| ShellError::NoSuchDevice(name.to_string()))
+}
+
+/// Returns the name of the whole disk, or of one of its partitions.
+fn disk_device_name(partition: Option<usize>) -> String {
+    match partition {
+        Some(number) => format!("{}p{}", DISK_DEVICE, number),
+        None => DISK_DEVICE.to_string(),
+    }
+}
+
+/// Saves a setting which a command already changed. It took effect either way, so a disk which
+/// can't be written, or isn't mounted, only gets a warning.
+fn save_setting(key: &str, value: &str, out: &mut CommandOutput) {
+    if let Err(err) = settings::set(key, value) {
+        let colors = out.colors();
+        let warning = format_args!("{} was changed but not saved: {}", key, err);
+        writeln!(out, "{}", ui::styled(Style::Warning, colors, warning));
+    }
+}
+
+/// Returns the paths matching a glob pattern in the mounted filesystems. A filesystem which can't
+/// be read, like one which isn't mounted, matches nothing.
+fn glob(pattern: &str) -> Vec<String> {
+    VFS.lock().glob(pattern).unwrap_or_default()
+}
+
+/// Checks that `len` bytes at `offset` lie within a block of the disk.
+fn check_block_range(block: usize, offset: usize, len: usize) -> Result<(), DiskError> {
+    if block >= disk::size() {
+        return Err(DiskError::BlockOutOfBounds(block));
+    }
+    if offset > BLOCK_SIZE {
+        return Err(DiskError::OffsetOutOfBounds(offset));
+    }
+    if len > BLOCK_SIZE - offset {
+        return Err(DiskError::BufferTooLarge(len, offset));
+    }
+    Ok(())
+}
+
+/// Formats a timestamp as the time passed since then, like "3m 12s ago". Timestamps from the
+/// future, which can only come from a disk image written during another boot, are shown as raw
+/// ticks.
+fn format_age(ticks: u64, now: u64) -> String {
+    match now.checked_sub(ticks) {
+        Some(elapsed) => format!("{} ago", HumanDuration(elapsed)),
+        None => format!("{} ticks", ticks),
+    }
+}
+
+impl KillRing {
+    fn new() -> Self {
+        Self {
+            entries: VecDeque::with_capacity(KILL_RING_SIZE),
+            yank_index: 0,
+        }
+    }
+
+    /// Adds a new entry, forgetting the oldest one if the ring is full.
+    fn push(&mut self, text: Vec<char>) {
+        if self.entries.len() == KILL_RING_SIZE {
+            self.entries.pop_back();
+        }
+        self.entries.push_front(text);
+        self.yank_index = 0;
+    }
+
+    fn append(&mut self, text: &[char]) {
+        match self.entries.front_mut() {
+            Some(entry) => entry.extend_from_slice(text),
+            None => self.push(text.to_vec()),
+        }
+    }
+
+    fn prepend(&mut self, text: &[char]) {
+        match self.entries.front_mut() {
+            Some(entry) => {
+                entry.splice(0..0, text.iter().copied());
+            }
+            None => self.push(text.to_vec()),
+        }
+    }
+
+    fn current(&self) -> Option<&Vec<char>> {
+        self.entries.get(self.yank_index)
+    }
+
+    /// Moves on to the next older entry, wrapping around to the most recent one.
+    fn rotate(&mut self) -> Option<&Vec<char>> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        self.yank_index = (self.yank_index + 1) % self.entries.len();
+        self.current()
+    }
+}
+
+#[cfg(test)]
+fn type_str<T: Terminal + 'static>(shell: &mut Shell<T>, s: &str) {
+    for c in s.chars() {
+        shell.handle_keypress(DecodedKey::Unicode(c), Modifiers::default());
+    }
+}
+
+#[cfg(test)]
+fn ctrl<T: Terminal + 'static>(shell: &mut Shell<T>, c: char) {
+    let modifiers = Modifiers {
+        ctrl: true,
+        ..Modifiers::default()
+    };
+    shell.handle_keypress(DecodedKey::Unicode(c), modifiers);
+}
+
+#[cfg(test)]
+fn alt<T: Terminal + 'static>(shell: &mut Shell<T>, c: char) {
+    let modifiers = Modifiers {
+        alt: true,
+        ..Modifiers::default()
+    };
+    shell.handle_keypress(DecodedKey::Unicode(c), modifiers);
+}
+
+#[cfg(test)]
+fn move_cursor<T: Terminal + 'static>(
+    shell: &mut Shell<T>,
+    key: pc_keyboard::KeyCode,
+    times: usize,
+) {
+    for _ in 0..times {
+        shell.handle_keypress(DecodedKey::RawKey(key), Modifiers::default());
+    }
+}
+
+#[cfg(test)]
+fn buffer_str<T: Terminal + 'static>(shell: &Shell<T>) -> String {
+    shell.buffer.iter().collect()
+}
+
+#[cfg(test)]
+fn run_line<T: Terminal + 'static>(shell: &mut Shell<T>, line: &str) {
+    type_str(shell, line);
+    type_str(shell, "\n");
+}
+
+#[test_case]
+fn test_kill_and_yank() {
+    use pc_keyboard::KeyCode;
+
+    let mut shell = Shell::new();
+    type_str(&mut shell, "echo hello world");
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 6);
+    ctrl(&mut shell, 'k');
+    assert_eq!(buffer_str(&shell), "echo hello");
+    ctrl(&mut shell, 'u');
+    assert_eq!(buffer_str(&shell), "");
+
+    // Consecutive kills are merged into a single entry
+    ctrl(&mut shell, 'y');
+    assert_eq!(buffer_str(&shell), "echo hello world");
+    assert_eq!(shell.kill_ring.entries.len(), 1);
+}
+
+#[test_case]
+fn test_kill_empty_region_keeps_ring() {
+    let mut shell = Shell::new();
+    ctrl(&mut shell, 'y');
+    assert_eq!(buffer_str(&shell), "");
+
+    type_str(&mut shell, "abc");
+    ctrl(&mut shell, 'k');
+    assert!(shell.kill_ring.entries.is_empty());
+    ctrl(&mut shell, 'u');
+    assert_eq!(shell.kill_ring.entries.len(), 1);
+    ctrl(&mut shell, 'k');
+    ctrl(&mut shell, 'u');
+    assert_eq!(shell.kill_ring.entries.len(), 1);
+}
+
+#[test_case]
+fn test_shift_arrow_selection() {
+    use crate::vgabuf::{row_attributes, Color, VGAColor, DEFAULT_COLOR};
+    use pc_keyboard::KeyCode;
+
+    fn select(shell: &mut Shell, key: KeyCode, times: usize) {
+        let shift = Modifiers {
+            shift: true,
+            ..Modifiers::default()
+        };
+        for _ in 0..times {
+            shell.handle_keypress(DecodedKey::RawKey(key), shift);
+        }
+    }
+    let inverted = VGAColor::new(Color::Black, Color::White).attribute();
+    let plain = DEFAULT_COLOR.attribute();
+
+    let mut shell = Shell::new();
+    type_str(&mut shell, "echo hello world");
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 6);
+    select(&mut shell, KeyCode::ArrowLeft, 5);
+    assert_eq!(shell.selected(), Some(5..10));
+    // The prompt takes up the first two cells
+    let attributes = row_attributes(terminal::INPUT_ROW);
+    assert_eq!(attributes[6], plain);
+    assert_eq!(attributes[7..12], [inverted; 5]);
+    assert_eq!(attributes[12], plain);
+
+    // Moving back past the anchor selects the other side of it
+    select(&mut shell, KeyCode::ArrowRight, 8);
+    assert_eq!(shell.selected(), Some(10..13));
+    let attributes = row_attributes(terminal::INPUT_ROW);
+    assert_eq!(attributes[7..12], [plain; 5]);
+    assert_eq!(attributes[12..15], [inverted; 3]);
+
+    // Ctrl+C copies the selection rather than discarding the line
+    ctrl(&mut shell, 'c');
+    assert_eq!(buffer_str(&shell), "echo hello world");
+    assert_eq!(shell.kill_ring.current(), Some(&vec![' ', 'w', 'o']));
+    assert_eq!(shell.selected(), None);
+    assert!(row_attributes(terminal::INPUT_ROW)[2..18]
+        .iter()
+        .all(|&a| a == plain));
+
+    // Unshifted movement drops the selection, typing replaces it
+    select(&mut shell, KeyCode::ArrowLeft, 2);
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 1);
+    assert_eq!(shell.selected(), None);
+    select(&mut shell, KeyCode::ArrowLeft, 5);
+    type_str(&mut shell, "J");
+    assert_eq!(buffer_str(&shell), "echo J world");
+    assert_eq!(shell.cursor_pos, 6);
+
+    select(&mut shell, KeyCode::ArrowRight, 2);
+    type_str(&mut shell, "\u{8}");
+    assert_eq!(buffer_str(&shell), "echo Jorld");
+    select(&mut shell, KeyCode::ArrowLeft, 1);
+    type_str(&mut shell, "\u{7f}");
+    assert_eq!(buffer_str(&shell), "echo orld");
+
+    // The whole buffer, and a selection on a line longer than the screen, which scrolls
+    move_cursor(&mut shell, KeyCode::ArrowRight, 4);
+    type_str(&mut shell, &"x".repeat(100));
+    select(&mut shell, KeyCode::ArrowLeft, 200);
+    assert_eq!(shell.selected(), Some(0..109));
+    move_cursor(&mut shell, KeyCode::ArrowRight, 79);
+    select(&mut shell, KeyCode::ArrowRight, 30);
+    assert_eq!(shell.selected(), Some(79..109));
+    // The line is 111 cells with the prompt, the cursor after it is in the last column
+    let attributes = row_attributes(terminal::INPUT_ROW);
+    assert_eq!(attributes[48], plain);
+    assert_eq!(attributes[49..79], [inverted; 30]);
+
+    // Enter runs the whole line and drops the selection
+    type_str(&mut shell, "\n");
+    assert_eq!(shell.selected(), None);
+    assert_eq!(
+        shell.command_history.last().unwrap(),
+        &("echo orld".to_string() + &"x".repeat(100))
+    );
+}
+
+#[test_case]
+fn test_yank_pop_rotates() {
+    let mut shell = Shell::new();
+    for word in ["one", "two", "three"] {
+        type_str(&mut shell, word);
+        ctrl(&mut shell, 'u');
+        type_str(&mut shell, " ");
+        ctrl(&mut shell, 'u');
+    }
+    assert_eq!(shell.kill_ring.entries.len(), 6);
+
+    type_str(&mut shell, "> ");
+    ctrl(&mut shell, 'y');
+    assert_eq!(buffer_str(&shell), ">  ");
+    alt(&mut shell, 'y');
+    assert_eq!(buffer_str(&shell), "> three");
+    alt(&mut shell, 'y');
+    alt(&mut shell, 'y');
+    assert_eq!(buffer_str(&shell), "> two");
+}
+
+#[test_case]
+fn test_kill_ring_is_bounded() {
+    let mut shell = Shell::new();
+    for _ in 0..KILL_RING_SIZE * 2 {
+        type_str(&mut shell, "x");
+        ctrl(&mut shell, 'u');
+        type_str(&mut shell, " ");
+    }
+    assert_eq!(shell.kill_ring.entries.len(), KILL_RING_SIZE);
+}
+
+#[test_case]
+fn test_yank_longer_than_screen() {
+    let mut shell = Shell::new();
+    let long = "y".repeat(200);
+    type_str(&mut shell, &long);
+    ctrl(&mut shell, 'u');
+    ctrl(&mut shell, 'y');
+    ctrl(&mut shell, 'y');
+    assert_eq!(shell.buffer.len(), 400);
+    assert_eq!(shell.cursor_pos, 400);
+}
+
+#[test_case]
+fn test_history_expansion() {
+    let mut shell = Shell::new();
+    run_line(&mut shell, "echo one");
+    run_line(&mut shell, "echo two");
+    run_line(&mut shell, "help");
+
+    run_line(&mut shell, "!1");
+    assert_eq!(shell.command_history.last().unwrap(), "echo one");
+    run_line(&mut shell, "!!");
+    assert_eq!(shell.command_history.last().unwrap(), "echo one");
+    run_line(&mut shell, "!he");
+    assert_eq!(shell.command_history.last().unwrap(), "help");
+    run_line(&mut shell, "!2 three");
+    assert_eq!(shell.command_history.last().unwrap(), "echo two three");
+    assert_eq!(
+        shell.command_history,
+        [
+            "echo one",
+            "echo two",
+            "help",
+            "echo one",
+            "echo one",
+            "help",
+            "echo two three"
+        ]
+    );
+}
+
+#[test_case]
+fn test_history_expansion_errors() {
+    let mut shell = Shell::new();
+    run_line(&mut shell, "echo one");
+
+    assert!(matches!(
+        shell.expand_history("!0"),
+        Err(ShellError::HistoryOutOfRange(0, 1))
+    ));
+    assert!(matches!(
+        shell.expand_history("!2"),
+        Err(ShellError::HistoryOutOfRange(2, 1))
+    ));
+    assert!(matches!(
+        shell.expand_history("!foo"),
+        Err(ShellError::HistoryNotFound(_))
+    ));
+
+    run_line(&mut shell, "!2");
+    run_line(&mut shell, "!foo");
+    assert_eq!(shell.command_history, ["echo one"]);
+}
+
+#[test_case]
+fn test_cp_existing_destination() {
+    crate::fs::init().unwrap();
+    let src = {
+        let mut fs = FILESYSTEM.lock();
+        let src = fs.create_at("src", InodeKind::File).unwrap();
+        fs.write(src, 0, b"hello").unwrap();
+        fs.create_at("dst", InodeKind::File).unwrap();
+        src
+    };
+
+    let (files, cancel) = (JobFiles::new("cp"), CancellationToken::new());
+    let mut out = CommandOutput::Captured(String::new());
+    assert!(matches!(
+        task::block_on(<Shell>::cp(&["src", "dst"], &mut out, &files, &cancel)),
+        Err(KernelError::FileSystem(FileSystemError::AlreadyExists(_)))
+    ));
+    task::block_on(<Shell>::cp(
+        &["-f", "src", "dst"],
+        &mut out,
+        &files,
+        &cancel,
+    ))
+    .unwrap();
+    // The files are only open while copying
+    assert!(jobs::open_files().is_empty());
+
+    let fs = FILESYSTEM.lock();
+    let dst = fs.resolve("dst").unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(fs.read(dst, 0, &mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(fs.stat(src).unwrap().size, 5);
+}
+
+#[test_case]
+fn test_cp_keeps_holes() {
+    crate::fs::init().unwrap();
+    {
+        let mut fs = FILESYSTEM.lock();
+        let src = fs.create_at("sparse", InodeKind::File).unwrap();
+        fs.write(src, 3 * BLOCK_SIZE, b"data").unwrap();
+        fs.truncate(src, 6 * BLOCK_SIZE).unwrap();
+    }
+
+    let (files, cancel) = (JobFiles::new("cp"), CancellationToken::new());
+    let mut out = CommandOutput::Captured(String::new());
+    task::block_on(<Shell>::cp(&["sparse", "copy"], &mut out, &files, &cancel)).unwrap();
+
+    let fs = FILESYSTEM.lock();
+    let dst = fs.resolve("copy").unwrap();
+    let metadata = fs.stat(dst).unwrap();
+    assert_eq!((metadata.size, metadata.blocks), (6 * BLOCK_SIZE, 1));
+    let mut buf = [0xff; 8];
+    fs.read(dst, 3 * BLOCK_SIZE - 4, &mut buf).unwrap();
+    assert_eq!(&buf, b"\0\0\0\0data");
+}
+
+#[test_case]
+fn test_cp_stops_when_cancelled() {
+    use crate::task::{executor::Executor, Task};
+
+    const BLOCKS: usize = 16;
+    crate::fs::init().unwrap();
+    {
+        let mut fs = FILESYSTEM.lock();
+        let src = fs.create_at("big", InodeKind::File).unwrap();
+        fs.write(src, 0, &[7; BLOCKS * BLOCK_SIZE]).unwrap();
+    }
+
+    // The copy yields after each block, letting the other task cancel it a few blocks in
+    let cancel = CancellationToken::new();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new({
+        let cancel = cancel.clone();
+        async move {
+            let files = JobFiles::new("cp");
+            let mut out = CommandOutput::Captured(String::new());
+            let result = <Shell>::cp(&["big", "copy"], &mut out, &files, &cancel).await;
+            let Err(KernelError::Context { cause, .. }) = result else {
+                panic!("the copy wasn't interrupted: {:?}", result);
+            };
+            assert!(matches!(
+                *cause,
+                KernelError::FileSystem(FileSystemError::Interrupted)
+            ));
+        }
+    }));
+    executor.spawn(Task::new(async move {
+        for _ in 0..3 {
+            yield_now().await;
+        }
+        cancel.cancel();
+    }));
+    executor.run_until_done();
+
+    assert!(jobs::open_files().is_empty());
+    let fs = FILESYSTEM.lock();
+    let copied = fs.stat(fs.resolve("copy").unwrap()).unwrap().size;
+    assert!(
+        (BLOCK_SIZE..BLOCKS * BLOCK_SIZE).contains(&copied),
+        "{} bytes copied",
+        copied
+    );
+}
+
+#[test_case]
+fn test_ls() {
+    crate::fs::init().unwrap();
+    {
+        let mut fs = FILESYSTEM.lock();
+        let notes = fs.create_at("notes.txt", InodeKind::File).unwrap();
+        fs.write(notes, 0, &[b'x'; 300]).unwrap();
+        let big = fs.create_at("big.bin", InodeKind::File).unwrap();
+        fs.write(big, 0, &[0; 2 * BLOCK_SIZE + 1]).unwrap();
+        fs.create_at("zdir", InodeKind::Directory).unwrap();
+        fs.create_at("adir", InodeKind::Directory).unwrap();
+        fs.create_at("adir/empty", InodeKind::File).unwrap();
+    }
+
+    let modified = |path: &str| {
+        let fs = FILESYSTEM.lock();
+        fs.stat(fs.resolve(path).unwrap()).unwrap().modified
+    };
+    let times = ["adir", "big.bin", "notes.txt", "zdir", "adir/empty"].map(modified);
+    // Listed a minute and a half after the last change, so that every age is as wide
+    let now = times.iter().max().unwrap() + 90 * u64::from(timer::tick_hz());
+    let [adir, big, notes, zdir, empty] = times.map(|ticks| format_age(ticks, now));
+    let ls = |args: &[&str]| <Shell>::ls(args, crate::vgabuf::WIDTH, now).unwrap();
+
+    assert_eq!(ls(&[]), ["adir       big.bin    notes.txt  zdir"]);
+    assert_eq!(
+        ls(&["-l"]),
+        [
+            format!("d   32 1 {} adir", adir),
+            format!("- 8193 3 {} big.bin", big),
+            format!("-  300 1 {} notes.txt", notes),
+            format!("d    0 0 {} zdir", zdir),
+        ]
+    );
+    assert_eq!(ls(&["-d"]), ["adir       zdir       big.bin    notes.txt"]);
+    assert_eq!(
+        ls(&["-l", "adir/empty"]),
+        [format!("- 0 0 {} adir/empty", empty)]
+    );
+    assert!(matches!(
+        <Shell>::ls(&["missing"], crate::vgabuf::WIDTH, now),
+        Err(KernelError::FileSystem(FileSystemError::NotFound(_)))
+    ));
+}
+
+#[test_case]
+fn test_touch() {
+    crate::fs::init().unwrap();
+    <Shell>::touch(&["new.txt"]).unwrap();
+    let inumber = FILESYSTEM.lock().resolve("new.txt").unwrap();
+    let before = FILESYSTEM.lock().stat(inumber).unwrap();
+    assert_eq!(before.size, 0);
+
+    let start = timer::ticks();
+    while timer::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    <Shell>::touch(&["new.txt"]).unwrap();
+    let after = FILESYSTEM.lock().stat(inumber).unwrap();
+    assert_eq!(after.created, before.created);
+    assert!(after.modified > before.modified);
+
+    let hz = u64::from(timer::tick_hz());
+    assert_eq!(format_age(100, 100 + 90 * hz), "1m 30s ago");
+    assert_eq!(format_age(100, 50), "100 ticks");
+}
+
+#[test_case]
+fn test_mkdir_rm() {
+    crate::fs::init().unwrap();
+    assert!(matches!(
+        <Shell>::mkdir(&["a/b"]),
+        Err(KernelError::FileSystem(FileSystemError::NotFound(_)))
+    ));
+    <Shell>::mkdir(&["-p", "a/b"]).unwrap();
+    <Shell>::mkdir(&["-p", "a/b"]).unwrap();
+    <Shell>::mkdir(&["a/c"]).unwrap();
+    assert!(matches!(
+        <Shell>::mkdir(&["a/c"]),
+        Err(KernelError::FileSystem(FileSystemError::AlreadyExists(_)))
+    ));
+    <Shell>::touch(&["a/b/file"]).unwrap();
+    let cancel = CancellationToken::new();
+
+    assert!(matches!(
+        <Shell>::rm(&["a"], &cancel),
+        Err(KernelError::FileSystem(FileSystemError::DirectoryNotEmpty(
+            _
+        )))
+    ));
+    <Shell>::rm(&["a/b/file"], &cancel).unwrap();
+    <Shell>::rm(&["-r", "a"], &cancel).unwrap();
+    assert!(FILESYSTEM.lock().resolve("a").is_err());
+    assert!(matches!(
+        <Shell>::rm(&["-r", "/"], &cancel),
+        Err(KernelError::FileSystem(FileSystemError::RemoveRoot))
+    ));
+
+    // A cancelled job removes nothing more
+    <Shell>::mkdir(&["-p", "a/b"]).unwrap();
+    cancel.cancel();
+    assert!(matches!(
+        <Shell>::rm(&["-r", "a"], &cancel),
+        Err(KernelError::FileSystem(FileSystemError::Interrupted))
+    ));
+    assert!(FILESYSTEM.lock().resolve("a/b").is_ok());
+}
+
+#[test_case]
+fn test_time() {
+    fn timed(args: &[&str]) -> (Result<(), KernelError>, String) {
+        let mut out = CommandOutput::Captured(String::new());
+        let (cancel, files) = (CancellationToken::new(), JobFiles::new("time"));
+        let job = JobContext {
+            history: &[],
+            cancel: &cancel,
+            reader: None,
+            files: &files,
+        };
+        let command = <Shell>::run_command("time", args, None, &mut out, &job);
+        let result = task::block_on(command);
+        (result, out.into_captured().unwrap())
+    }
+    // Returns the milliseconds reported on a timing line
+    fn millis(line: &str) -> u64 {
+        let ms = line.strip_prefix("real ").unwrap().split(' ').next();
+        ms.unwrap().parse().unwrap()
+    }
+
+    let (result, output) = timed(&["sleep", "50"]);
+    result.unwrap();
+    let elapsed = millis(output.trim_end());
+    assert!((50..=70).contains(&elapsed), "{} ms elapsed", elapsed);
+
+    let (result, output) = timed(&["time", "echo", "hi"]);
+    result.unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "hi");
+    assert!(lines[1].starts_with("real ") && lines[2].starts_with("real "));
+
+    let (result, output) = timed(&["sleep"]);
+    assert!(matches!(
+        result,
+        Err(KernelError::Shell(ShellError::Usage("sleep <ms>")))
+    ));
+    assert!(output.starts_with("real "));
+    assert!(matches!(
+        timed(&[]).0,
+        Err(KernelError::Shell(ShellError::Usage(_)))
+    ));
+}
+
+#[test_case]
+fn test_background_output_keeps_prompt() {
+    use crate::task::{simple_executor::SimpleExecutor, yield_now, Task};
+
+    let mut shell = Shell::new();
+    type_str(&mut shell, "echo half");
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        for i in 0..3 {
+            crate::println!("background {}", i);
+            yield_now().await;
+        }
+    }));
+    executor.run();
+
+    assert_eq!(crate::vgabuf::row_text(terminal::INPUT_ROW), "> echo half");
+    for i in 0..3 {
+        assert_eq!(
+            crate::vgabuf::row_text(terminal::INPUT_ROW - 4 + i),
+            format!("background {}", i)
+        );
+    }
+    assert_eq!(crate::vgabuf::row_text(terminal::INPUT_ROW - 1), "");
+}
+
+#[test_case]
+fn test_non_ascii_input() {
+    use crate::vgabuf::{glyph, row_glyphs, REPLACEMENT_GLYPH};
+    use pc_keyboard::KeyCode;
+
+    let mut shell = Shell::new();
+    type_str(&mut shell, "echo åäö");
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 2);
+    type_str(&mut shell, "ø\u{8}é");
+    assert_eq!(buffer_str(&shell), "echo åéäö");
+    assert_eq!(shell.cursor_pos, 7);
+
+    // Every character takes up one cell, even without a glyph of its own
+    type_str(&mut shell, "ø");
+    let row = row_glyphs(terminal::INPUT_ROW);
+    let mut expected = *b"> echo ";
+    expected.iter_mut().for_each(|b| *b = glyph(*b as char));
+    assert_eq!(row[..7], expected);
+    assert_eq!(row[7..12], [0x86, 0x82, REPLACEMENT_GLYPH, 0x84, 0x94]);
+    assert_eq!(row[12], b' ');
+
+    type_str(&mut shell, "\n");
+    assert_eq!(shell.command_history, ["echo åéøäö"]);
+    let output = row_glyphs(terminal::INPUT_ROW - 2);
+    assert_eq!(output[..5], [0x86, 0x82, REPLACEMENT_GLYPH, 0x84, 0x94]);
+}
+
+#[test_case]
+fn test_error_line_colors() {
+    use crate::vgabuf::{row_attributes, row_text, Color, VGAColor, DEFAULT_COLOR};
+
+    let red = VGAColor::new(Color::LightRed, Color::Black).attribute();
+    let green = VGAColor::new(Color::LightGreen, Color::Black).attribute();
+    let mut shell = Shell::new();
+    shell.execute("nonexistent");
+
+    let error = "command not found: nonexistent";
+    assert_eq!(row_text(terminal::INPUT_ROW - 2), error);
+    let attributes = row_attributes(terminal::INPUT_ROW - 2);
+    assert!(attributes[..error.len()].iter().all(|&a| a == red));
+    assert_eq!(attributes[error.len()], DEFAULT_COLOR.attribute());
+
+    // The prompt is green, both in the output and on the input line
+    assert_eq!(row_text(terminal::INPUT_ROW - 3), "> nonexistent");
+    for row in [terminal::INPUT_ROW - 3, terminal::INPUT_ROW] {
+        let attributes = row_attributes(row);
+        assert_eq!(attributes[..2], [green; 2]);
+        assert_eq!(attributes[2], DEFAULT_COLOR.attribute());
+    }
+}
+
+#[test_case]
+fn test_color_setting() {
+    use terminal::{MockTerminal, TerminalCall::Write};
+
+    fn error_output(shell: &mut Shell<MockTerminal>) -> Vec<terminal::TerminalCall> {
+        shell.terminal.lock().take_calls();
+        shell.execute("b");
+        let mut calls = shell.terminal.lock().take_calls();
+        calls.retain(|call| matches!(call, Write(text) if text.contains("not found")));
+        calls
+    }
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert_eq!(
+        error_output(&mut shell),
+        [Write("error: command not found: b\n".into())]
+    );
+
+    shell.execute("color on");
+    assert!(shell.terminal.lock().colors);
+    assert_eq!(
+        error_output(&mut shell),
+        [Write("\x1b[91mcommand not found: b\x1b[0m\n".into())]
+    );
+
+    shell.execute("color off");
+    assert!(!shell.terminal.lock().colors && !ui::console_colors());
+    assert_eq!(
+        error_output(&mut shell),
+        [Write("error: command not found: b\n".into())]
+    );
+    assert_eq!(settings::get("color").as_deref(), Some("off"));
+    settings::unset("color").unwrap();
+    ui::set_console_colors(true);
+}
+
+#[test_case]
+fn test_settings_command() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert!(output(&mut shell, "settings set test.motd hello there").is_empty());
+    assert_eq!(
+        output(&mut shell, "settings get test.motd"),
+        ["hello there\n"]
+    );
+    assert!(output(&mut shell, "settings").contains(&String::from("test.motd=hello there\n")));
+    assert_eq!(
+        settings::load_from(&mut VFS.lock())
+            .unwrap()
+            .get("test.motd"),
+        Some("hello there")
+    );
+    assert!(output(&mut shell, "settings unset test.motd").is_empty());
+    assert_eq!(
+        output(&mut shell, "settings get test.motd"),
+        ["error: test.motd: no such setting\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "settings set"),
+        ["error: usage: settings [get <key>|set <key> <value>|unset <key>]\n"]
+    );
+}
+
+#[test_case]
+fn test_scripts() {
+    use terminal::MockTerminal;
+
+    let write_script = |path: &str, text: &str| {
+        let mut fs = FILESYSTEM.lock();
+        let script = fs.create_at(path, InodeKind::File).unwrap();
+        fs.write(script, 0, text.as_bytes()).unwrap();
+    };
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    write_script(
+        "/chain",
+        "# skipped, as is the blank line\n\n\
+         echo one && echo two\n\
+         nope && echo skipped\n\
+         echo $?\n\
+         nope || echo rescued\n\
+         echo $?\n",
+    );
+    assert_eq!(
+        output(&mut shell, "sh /chain"),
+        [
+            "one\n",
+            "two\n",
+            "error: /chain:4: command not found: nope\n",
+            "1\n",
+            "error: /chain:6: command not found: nope\n",
+            "rescued\n",
+            "0\n",
+        ]
+    );
+    // Only the command running the script is in the history
+    assert_eq!(shell.command_history, ["sh /chain"]);
+    assert_eq!(output(&mut shell, "echo $? || echo no"), ["0\n"]);
+
+    write_script("/strict", "@stop-on-error\necho before\nnope\necho after\n");
+    assert_eq!(
+        output(&mut shell, "run /strict"),
+        [
+            "before\n",
+            "error: /strict:3: command not found: nope\n",
+            "error: /strict: stopped at line 3\n",
+        ]
+    );
+    assert_eq!(output(&mut shell, "echo $?"), ["1\n"]);
+    assert_eq!(
+        output(&mut shell, "sh /strict || echo failed"),
+        [
+            "before\n",
+            "error: /strict:3: command not found: nope\n",
+            "error: /strict: stopped at line 3\n",
+            "failed\n",
+        ]
+    );
+
+    write_script("/late", "echo one\n@stop-on-error\n");
+    assert_eq!(
+        output(&mut shell, "sh /late"),
+        ["error: /late:2: @stop-on-error: directives have to come before the first command\n"]
+    );
+
+    // A script running itself stops once it's nested too deep
+    write_script("/again", "sh /again\n");
+    assert_eq!(
+        output(&mut shell, "sh /again"),
+        ["error: /again:1: /again: scripts are nested more than 8 deep\n"]
+    );
+    assert_eq!(shell.script_depth, 0);
+    assert_eq!(
+        output(&mut shell, "sh /chain | wc"),
+        ["error: sh: scripts can't be part of a pipeline or redirected\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "echo a &&"),
+        ["error: syntax error: missing command next to &&\n"]
+    );
+}
+
+/// Runs a line, returning what it printed without the echoed command line and prompts.
+#[cfg(test)]
+fn output(shell: &mut Shell<terminal::MockTerminal>, line: &str) -> Vec<String> {
+    shell.terminal.lock().take_calls();
+    shell.execute(line);
+    let calls = shell.terminal.lock().take_calls();
+    calls
+        .into_iter()
+        .filter_map(|call| match call {
+            terminal::TerminalCall::Write(text) if !text.starts_with(PROMPT) => Some(text),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_trace_command() {
+    use crate::task::{trace::ExecutorTracer, TaskId, TaskName};
+    use terminal::MockTerminal;
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert!(output(&mut shell, "trace reset").is_empty());
+    trace::RING.on_spawn(TaskId(12), TaskName("hannos::demo::{{closure}}"));
+    trace::RING.on_wake(TaskId(12), true);
+    let lines = output(&mut shell, "trace dump");
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "     ticks   task  event\n");
+    assert!(lines[1].ends_with("     12  spawn demo\n"));
+    assert!(lines[2].ends_with("     12  wake from irq\n"));
+
+    output(&mut shell, "trace reset");
+    assert_eq!(output(&mut shell, "trace dump").len(), 1);
+    assert_eq!(
+        output(&mut shell, "trace all"),
+        ["error: usage: trace [dump|reset]\n"]
+    );
+}
+
+#[test_case]
+fn test_variables() {
+    use terminal::MockTerminal;
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert!(output(&mut shell, "set X=ex GREETING=hi").is_empty());
+    assert_eq!(shell.variables.get("X"), Some("ex"));
+    assert_eq!(
+        output(&mut shell, "set"),
+        ["?=0\n", "GREETING=hi\n", "X=ex\n"]
+    );
+
+    assert_eq!(
+        output(
+            &mut shell,
+            "echo $GREETING \"$X  $X\" '$X' ${X}y $$X $NOPE."
+        ),
+        ["hi ex  ex $X exy $X .\n"]
+    );
+
+    assert!(output(&mut shell, "unset X").is_empty());
+    assert_eq!(shell.variables.get("X"), None);
+    assert_eq!(output(&mut shell, "echo [$X]"), ["[]\n"]);
+
+    // $? is the status of the command before
+    output(&mut shell, "nonexistent");
+    assert_eq!(output(&mut shell, "echo $?"), ["1\n"]);
+    assert_eq!(output(&mut shell, "echo $?"), ["0\n"]);
+    assert_eq!(
+        output(&mut shell, "set 1X=y"),
+        ["error: 1X: not a valid variable name\n"]
+    );
+    assert_eq!(output(&mut shell, "echo $?"), ["1\n"]);
+}
+
+#[test_case]
+fn test_memtest_command() {
+    use terminal::MockTerminal;
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let report = output(&mut shell, "memtest 10").concat();
+    assert!(report.starts_with("seed "), "{}", report);
+    assert!(report.contains("\n10 iterations, "), "{}", report);
+    assert!(
+        report.ends_with("no overlapping or corrupted blocks\n"),
+        "{}",
+        report
+    );
+    assert_eq!(
+        output(&mut shell, "memtest 1 2"),
+        ["error: usage: memtest [iterations]\n"]
+    );
+}
+
+#[test_case]
+fn test_selftest_command() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let report = output(&mut shell, "selftest");
+    assert_eq!(report.len(), 2, "{:?}", report);
+    assert!(report[0].starts_with("memtest: ok, "), "{:?}", report);
+    assert_eq!(report[1], "fsck: ok\n");
+
+    // A problem in the filesystem fails the test
+    disk::write(SuperblockCopy::Backup.block(), 0, &[0; 16]).unwrap();
+    let report = output(&mut shell, "selftest");
+    assert!(report[1].starts_with("error: fsck: "), "{:?}", report);
+
+    FILESYSTEM.lock().unmount();
+    assert_eq!(
+        output(&mut shell, "selftest")[1],
+        "fsck: skipped, no filesystem is mounted\n"
+    );
+    assert_eq!(
+        output(&mut shell, "selftest now"),
+        ["error: usage: selftest\n"]
+    );
+    crate::fs::init().unwrap();
+}
+
+#[test_case]
+fn test_scrub_command() {
+    use terminal::MockTerminal;
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    shell.execute("scrub on");
+    let report = output(&mut shell, "scrub");
+    assert_eq!(report[0], "heap scrubbing is on\n");
+    assert!(report[1].starts_with("scrubbed: "), "{:?}", report);
+    shell.execute("scrub off");
+    assert!(!scrub::is_enabled());
+    assert_eq!(
+        output(&mut shell, "scrub maybe"),
+        ["error: usage: scrub [on|off]\n"]
+    );
+}
+
+#[test_case]
+fn test_screenshot_command() {
+    use pc_keyboard::KeyCode;
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert_eq!(
+        output(&mut shell, "screenshot /shot.scr"),
+        ["saved /shot.scr\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "screenshot /shot.scr"),
+        ["error: /shot.scr: file exists\n"]
+    );
+
+    // PrintScreen saves to the next numbered file, while typing is kept
+    type_str(&mut shell, "ls");
+    shell.terminal.lock().take_calls();
+    shell.handle_keypress(
+        DecodedKey::RawKey(KeyCode::PrintScreen),
+        Modifiers::default(),
+    );
+    assert!(VFS.lock().stat("/screenshots/000.scr").is_ok());
+    assert_eq!(shell.terminal.lock().line(), "> ls");
+    assert!(shell
+        .terminal
+        .lock()
+        .take_calls()
+        .contains(&terminal::TerminalCall::Write(String::from(
+            "saved /screenshots/000.scr\n"
+        ))));
+
+    assert_eq!(
+        output(&mut shell, "showshot /shot.scr"),
+        ["error: showshot: can only be run at the prompt\n"]
+    );
+    crate::fs::init().unwrap();
+}
+
+#[test_case]
+fn test_fsstress_command() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let report = output(&mut shell, "fsstress 200 5").concat();
+    assert!(report.starts_with("seed 5\n"), "{}", report);
+    assert!(
+        report.ends_with("files checked, no differences\n"),
+        "{}",
+        report
+    );
+    // The same seed does the same operations, and nothing is left behind
+    assert_eq!(output(&mut shell, "fsstress 200 5").concat(), report);
+    assert!(FILESYSTEM.lock().resolve("fsstress").is_err());
+    assert!(output(&mut shell, "fsstress 10")
+        .concat()
+        .starts_with("seed "));
+    assert_eq!(
+        output(&mut shell, "fsstress many"),
+        ["error: usage: fsstress <ops> [seed]\n"]
+    );
+}
+
+#[test_case]
+fn test_globbing() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    {
+        let mut fs = FILESYSTEM.lock();
+        fs.create_dir_all("tmp").unwrap();
+        for path in ["b.tmp", "a.tmp", "c.txt", "tmp/1.log", "tmp/2.log"] {
+            fs.create_at(path, InodeKind::File).unwrap();
+        }
+    }
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert_eq!(output(&mut shell, "echo *.tmp"), ["a.tmp b.tmp\n"]);
+    assert_eq!(
+        output(&mut shell, "echo /tmp/*.log"),
+        ["/tmp/1.log /tmp/2.log\n"]
+    );
+    assert_eq!(output(&mut shell, "echo ?.t?t"), ["c.txt\n"]);
+    assert_eq!(output(&mut shell, "echo *.none"), ["*.none\n"]);
+    assert_eq!(
+        output(&mut shell, "echo '*.tmp' \"?.txt\""),
+        ["*.tmp ?.txt\n"]
+    );
+
+    assert!(output(&mut shell, "set -o failglob").is_empty());
+    assert_eq!(output(&mut shell, "set -o"), ["failglob    on\n"]);
+    assert_eq!(
+        output(&mut shell, "echo *.none"),
+        ["error: no match: *.none\n"]
+    );
+    assert_eq!(output(&mut shell, "echo $?"), ["1\n"]);
+    assert_eq!(output(&mut shell, "echo '*.none'"), ["*.none\n"]);
+    assert!(output(&mut shell, "set +o failglob").is_empty());
+    assert_eq!(
+        output(&mut shell, "set -o nullglob"),
+        ["error: nullglob: no such option\n"]
+    );
+
+    assert!(output(&mut shell, "rm *.tmp").is_empty());
+    assert_eq!(output(&mut shell, "echo *"), ["c.txt tmp\n"]);
+}
+
+#[test_case]
+fn test_line_continuation() {
+    crate::fs::init().unwrap();
+    FILESYSTEM.lock().create_at("src", InodeKind::File).unwrap();
+
+    let mut shell = Shell::new();
+    run_line(&mut shell, "cp \\");
+    assert_eq!(shell.prompt(), CONTINUATION_PROMPT);
+    run_line(&mut shell, "  src\\");
+    run_line(&mut shell, "dst");
+    assert_eq!(shell.prompt(), PROMPT);
+    assert_eq!(shell.command_history, ["cp src dst"]);
+    assert!(FILESYSTEM.lock().resolve("dst").is_ok());
+
+    // Ctrl+C drops the continued lines as well as the current one
+    run_line(&mut shell, "cp src \\");
+    type_str(&mut shell, "other");
+    ctrl(&mut shell, 'c');
+    assert_eq!(shell.prompt(), PROMPT);
+    assert!(shell.buffer.is_empty());
+    run_line(&mut shell, "echo done");
+    assert_eq!(shell.command_history, ["cp src dst", "echo done"]);
+    assert!(FILESYSTEM.lock().resolve("other").is_err());
+
+    // An escaped backslash doesn't continue the line
+    run_line(&mut shell, "echo \\\\");
+    assert_eq!(shell.command_history.last().unwrap(), "echo \\\\");
+}
+
+#[test_case]
+fn test_terminal_rendering() {
+    use pc_keyboard::KeyCode;
+    use terminal::{MockTerminal, TerminalCall::*};
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert_eq!(
+        shell.terminal.lock().take_calls(),
+        [ClearLine, Write("> ".into()), MoveCursor(2)]
+    );
+
+    // Only the typed characters are written
+    type_str(&mut shell, "ab");
+    assert_eq!(
+        shell.terminal.lock().take_calls(),
+        [
+            MoveCursor(2),
+            Write("a".into()),
+            MoveCursor(3),
+            MoveCursor(3),
+            Write("b".into()),
+            MoveCursor(4),
+        ]
+    );
+
+    // Deleting rewrites the rest of the line, blanking out its old end
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 1);
+    type_str(&mut shell, "\u{8}");
+    assert_eq!(
+        shell.terminal.lock().take_calls(),
+        [
+            MoveCursor(3),
+            MoveCursor(2),
+            Write("b ".into()),
+            MoveCursor(2)
+        ]
+    );
+    assert_eq!(shell.terminal.lock().line(), "> b ");
+
+    type_str(&mut shell, "\n");
+    assert_eq!(
+        shell.terminal.lock().take_calls(),
+        [
+            ClearLine,
+            Write("> b\n".into()),
+            Write("error: command not found: b\n".into()),
+            ClearLine,
+            Write("> ".into()),
+            MoveCursor(2),
+        ]
+    );
+
+    move_cursor(&mut shell, KeyCode::ArrowUp, 1);
+    assert_eq!(
+        shell.terminal.lock().take_calls(),
+        [MoveCursor(2), Write("b".into()), MoveCursor(3)]
+    );
+}
+
+#[test_case]
+fn test_prompt_template() {
+    use terminal::MockTerminal;
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    run_line(&mut shell, "set 'PROMPT=\\w [\\$?] \\j> '");
+    assert_eq!(shell.terminal.lock().line(), "/ [] 0> ");
+    run_line(&mut shell, "nosuchcommand");
+    assert_eq!(shell.terminal.lock().line(), "/ [1] 0> ");
+
+    // Typing draws the prompt expanded when the command finished
+    let renders = shell.prompt_cache.renders();
+    type_str(&mut shell, "echo hi");
+    assert_eq!(shell.prompt_cache.renders(), renders);
+    assert_eq!(shell.terminal.lock().line(), "/ [1] 0> echo hi");
+    assert_eq!(shell.terminal.lock().cursor(), 16);
+    type_str(&mut shell, "\n");
+    assert_eq!(shell.terminal.lock().line(), "/ [] 0> ");
+
+    // Jobs of other shells are counted, and the prompt catches up without a key being pressed
+    let other = JobFiles::new("sleep 10");
+    shell.refresh_prompt();
+    assert_eq!(shell.terminal.lock().line(), "/ [] 1> ");
+    // Nothing is drawn while the prompt stays the same
+    shell.terminal.lock().take_calls();
+    shell.refresh_prompt();
+    assert!(shell.terminal.lock().take_calls().is_empty());
+    drop(other);
+
+    // The status is red, inside the color of the whole prompt
+    shell.terminal.lock().colors = true;
+    run_line(&mut shell, "set 'PROMPT=\\$?> '");
+    run_line(&mut shell, "nosuchcommand");
+    assert_eq!(
+        shell.terminal.lock().line(),
+        "\x1b[92m\x1b[91m1\x1b[92m> \x1b[0m"
+    );
+
+    run_line(&mut shell, "unset PROMPT");
+    assert_eq!(shell.prompt(), PROMPT);
+}
+
+#[test_case]
+fn test_overwrite_mode() {
+    use pc_keyboard::KeyCode;
+    use terminal::{MockTerminal, TerminalCall::*};
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    type_str(&mut shell, "echo hello");
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 5);
+
+    // Toggling the mode changes the prompt but not the cursor position
+    move_cursor(&mut shell, KeyCode::Insert, 1);
+    assert_eq!(shell.cursor_pos, 5);
+    assert_eq!(shell.terminal.lock().line(), "[ovr]> echo hello");
+    assert_eq!(
+        shell.terminal.lock().cursor(),
+        prompt::OVERWRITE_MARK.len() + PROMPT.len() + 5
+    );
+
+    shell.terminal.lock().take_calls();
+    type_str(&mut shell, "HE");
+    assert_eq!(
+        shell.terminal.lock().take_calls(),
+        [
+            MoveCursor(12),
+            Write("H".into()),
+            MoveCursor(13),
+            MoveCursor(13),
+            Write("E".into()),
+            MoveCursor(14),
+        ]
+    );
+
+    // Overwriting at the end of the buffer extends it
+    move_cursor(&mut shell, KeyCode::ArrowRight, 3);
+    type_str(&mut shell, "!");
+    assert_eq!(shell.buffer.iter().collect::<String>(), "echo HEllo!");
+    assert_eq!(shell.terminal.lock().line(), "[ovr]> echo HEllo!");
+
+    move_cursor(&mut shell, KeyCode::Insert, 1);
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 1);
+    type_str(&mut shell, "?");
+    assert_eq!(shell.buffer.iter().collect::<String>(), "echo HEllo?!");
+    // The old, longer prompt is blanked out
+    assert_eq!(shell.terminal.lock().line().trim_end(), "> echo HEllo?!");
+    assert_eq!(shell.terminal.lock().cursor(), PROMPT.len() + 11);
+}
+
+#[test_case]
+fn test_block_commands() {
+    use crate::fs::file::FileSystem;
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let inumber = {
+        let mut fs = FILESYSTEM.lock();
+        let inumber = fs.create_at("file", InodeKind::File).unwrap();
+        fs.write(inumber, 0, b"hello").unwrap();
+        inumber
+    };
+    let block = FILESYSTEM.lock().raw_inode(inumber).unwrap().direct[0];
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+
+    let blkread = format!("blkread {} 0 5", block);
+    let hello = format!("0000  68 65 6c 6c 6f {:33}|hello|\n", "");
+    assert_eq!(output(&mut shell, &blkread), [hello.as_str()]);
+    let inode = output(&mut shell, &format!("inode {}", inumber));
+    assert_eq!(
+        inode[1..3],
+        ["  valid:      true\n", "  kind:       File\n"]
+    );
+    assert_eq!(inode[4], "  size:       5\n");
+    assert_eq!(inode[6], format!("  direct:     {} - - - - -\n", block));
+    assert_eq!(inode[7], "  indirect:   -\n");
+    assert_eq!(inode[8], "  links:      1\n");
+
+    // Writes have to be confirmed, which can't be done without a job
+    assert_eq!(
+        output(&mut shell, &format!("blkwrite {} 0 4a", block)),
+        [
+            "error: blkwrite: can't ask for confirmation here, run it at the prompt
+"
+        ]
+    );
+    assert_eq!(output(&mut shell, &blkread), [hello.as_str()]);
+
+    // Nothing is asked when the write is out of bounds
+    let size = disk::size();
+    assert_eq!(
+        output(&mut shell, &format!("blkwrite {} 0 00", size)),
+        [format!("error: disk error: block {} out of bounds\n", size)]
+    );
+    assert_eq!(
+        output(&mut shell, &format!("blkread {} 0xfff 2", block)),
+        ["error: disk error: tried to access 2 bytes at offset 4095, which exceeds block size of 4096\n"]
+    );
+
+    // Only inode needs a mounted filesystem
+    *FILESYSTEM.lock() = FileSystem::new();
+    assert_eq!(
+        output(&mut shell, "inode 0"),
+        ["error: no filesystem is mounted\n"]
+    );
+    assert_eq!(output(&mut shell, "blkread 0 0 0x10").len(), 1);
+    crate::fs::init().unwrap();
+}
+
+#[test_case]
+fn test_interrupt_job() {
+    use crate::task::executor::Executor;
+    use terminal::{MockTerminal, TerminalCall::*};
+
+    let mut executor = Executor::new();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    shell.set_spawner(executor.spawner());
+
+    // Instant commands still run right away
+    assert_eq!(output(&mut shell, "echo hi"), ["hi\n"]);
+    assert!(shell.job.is_none());
+
+    let start = timer::millis();
+    run_line(&mut shell, "sleep 60000");
+    assert!(shell.job.is_some());
+    executor.run_ready_tasks();
+    shell.terminal.lock().take_calls();
+
+    // Keys other than Ctrl+C are dropped, and the input line stays hidden
+    type_str(&mut shell, "x\n");
+    assert!(shell.terminal.lock().take_calls().is_empty());
+    assert!(shell.buffer.is_empty());
+
+    ctrl(&mut shell, 'c');
+    assert!(shell.job.as_ref().unwrap().cancel.is_cancelled());
+    executor.run_until_done();
+    assert!(timer::millis() - start < 60000);
+    assert_eq!(
+        shell.terminal.lock().take_calls(),
+        [
+            Write("error: interrupted\n".into()),
+            ClearLine,
+            Write("> ".into()),
+            MoveCursor(2)
+        ]
+    );
+
+    // The next key collects the job and is handled as usual
+    type_str(&mut shell, "e");
+    assert!(shell.job.is_none());
+    assert_eq!(shell.terminal.lock().line(), "> e");
+    type_str(&mut shell, "\u{8}");
+    assert_eq!(output(&mut shell, "echo $?"), ["1\n"]);
+}
+
+#[test_case]
+fn test_confirm_blkwrite() {
+    use crate::task::executor::Executor;
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let block = FILESYSTEM.lock().blocks() - 1;
+    disk::write(block, 0, b"hello").unwrap();
+    let mut executor = Executor::new();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    shell.set_spawner(executor.spawner());
+    let read = || {
+        let mut buf = [0; 5];
+        disk::read(block, 0, &mut buf).unwrap();
+        buf
+    };
+    // Runs blkwrite, answering the question with `keys`. Returns the output lines and the input
+    // line once the job is done.
+    let mut answer = |shell: &mut Shell<MockTerminal>, keys: &[(char, bool)]| {
+        run_line(shell, &format!("blkwrite {} 0 4a", block));
+        executor.run_ready_tasks();
+        assert!(shell.discipline.is_reading());
+        assert_eq!(shell.terminal.lock().line(), "continue? [y/N] ");
+        shell.terminal.lock().take_calls();
+        for &(c, with_ctrl) in keys {
+            match with_ctrl {
+                true => ctrl(shell, c),
+                false => type_str(shell, &c.to_string()),
+            }
+        }
+        executor.run_until_done();
+        let mut terminal = shell.terminal.lock();
+        let lines = terminal
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                terminal::TerminalCall::Write(text) if text.ends_with('\n') => Some(text),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        (lines, terminal.line())
+    };
+
+    // Anything but yes refuses, backspace edits the answer
+    let (lines, line) = answer(
+        &mut shell,
+        &[('y', false), ('\u{8}', false), ('n', false), ('\n', false)],
+    );
+    assert_eq!(lines, ["continue? [y/N] n\n", "nothing written\n"]);
+    assert_eq!(line, "> ");
+    assert_eq!(&read(), b"hello");
+
+    let (lines, _) = answer(&mut shell, &[('c', true)]);
+    assert_eq!(lines, ["continue? [y/N] ^C\n", "nothing written\n"]);
+    assert_eq!(&read(), b"hello");
+
+    let (lines, line) = answer(&mut shell, &[('y', false), ('\n', false)]);
+    assert_eq!(lines, ["continue? [y/N] y\n", "wrote 1 bytes\n"]);
+    assert_eq!(line, "> ");
+    assert_eq!(&read(), b"Jello");
+
+    // The prompt is back, with keys going to the input line again
+    type_str(&mut shell, "e");
+    assert!(shell.job.is_none() && !shell.discipline.is_reading());
+    assert_eq!(shell.terminal.lock().line(), "> e");
+    crate::fs::init().unwrap();
+}
+
+#[test_case]
+fn test_watch() {
+    use crate::task::executor::Executor;
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut executor = Executor::new();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert_eq!(
+        output(&mut shell, "watch logs"),
+        ["error: watch: can only be run at the prompt\n"]
+    );
+    shell.set_spawner(executor.spawner());
+    VFS.lock().create("logs", InodeKind::Directory).unwrap();
+
+    run_line(&mut shell, "watch logs");
+    executor.run_ready_tasks();
+    shell.terminal.lock().take_calls();
+    {
+        let mut vfs = VFS.lock();
+        vfs.create("logs/boot", InodeKind::File).unwrap();
+        let mut file = vfs.open("logs/boot").unwrap();
+        vfs.write(&mut file, b"booted").unwrap();
+        vfs.remove("logs/boot").unwrap();
+    }
+    executor.run_ready_tasks();
+    let lines = shell
+        .terminal
+        .lock()
+        .take_calls()
+        .into_iter()
+        .filter_map(|call| match call {
+            terminal::TerminalCall::Write(text) => Some(text),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            "created /logs/boot\n",
+            "modified /logs/boot\n",
+            "removed /logs/boot\n"
+        ]
+    );
+
+    // Ctrl+C ends it, and the prompt is back
+    ctrl(&mut shell, 'c');
+    executor.run_until_done();
+    type_str(&mut shell, "e");
+    assert!(shell.job.is_none());
+    assert_eq!(shell.terminal.lock().line(), "> e");
+    crate::fs::init().unwrap();
+}
+
+#[test_case]
+fn test_dmesg() {
+    use crate::{log::LogLevel, task::executor::Executor};
+    use terminal::{MockTerminal, TerminalCall};
+
+    let mut executor = Executor::new();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    crate::log!(LogLevel::Warn, "kept for dmesg");
+    let kept = output(&mut shell, "dmesg").concat();
+    assert!(kept.ends_with("] WARNING: kept for dmesg\n"), "{}", kept);
+    assert_eq!(
+        output(&mut shell, "dmesg -f"),
+        ["error: dmesg: can only be run at the prompt\n"]
+    );
+
+    // Following prints the messages kept, then every new one as it's logged
+    shell.set_spawner(executor.spawner());
+    run_line(&mut shell, "dmesg -f");
+    executor.run_ready_tasks();
+    let written = || {
+        let calls = shell.terminal.lock().take_calls().into_iter();
+        calls
+            .filter_map(|call| match call {
+                TerminalCall::Write(text) => Some(text),
+                _ => None,
+            })
+            .collect::<String>()
+    };
+    assert!(written().contains(&kept));
+    crate::log!(LogLevel::Error, "logged while following");
+    executor.run_ready_tasks();
+    let new = written();
+    assert!(
+        new.ends_with("] ERROR: logged while following\n"),
+        "{}",
+        new
+    );
+    assert_eq!(new.lines().count(), 1);
+
+    ctrl(&mut shell, 'c');
+    executor.run_until_done();
+    assert!(shell.job.is_none());
+}
+
+#[test_case]
+fn test_macros() {
+    use pc_keyboard::KeyCode;
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    ctrl(&mut shell, 'x');
+    type_str(&mut shell, "ouch macro_filx\u{8}e");
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 15);
+    run_line(&mut shell, "t");
+    run_line(&mut shell, "echo done");
+    ctrl(&mut shell, 'x');
+    // Neither Ctrl+X is recorded
+    assert_eq!(shell.recorded.len(), 15 + 2 + 15 + 2 + 10);
+    let recorded = shell.command_history.clone();
+    assert_eq!(recorded, ["touch macro_file", "echo done"]);
+
+    output(&mut shell, "rm macro_file");
+    assert!(output(&mut shell, "macro save m").is_empty());
+    assert!(output(&mut shell, "macro play m").contains(&"done\n".to_string()));
+    assert_eq!(
+        shell.command_history[shell.command_history.len() - 2..],
+        recorded
+    );
+    assert!(FILESYSTEM.lock().resolve("macro_file").is_ok());
+    assert_eq!(
+        output(&mut shell, "macro list"),
+        [format!("{:<16} 44 keys\n", "m")]
+    );
+
+    // Another shell plays the macro from its file
+    output(&mut shell, "rm macro_file");
+    let mut other = Shell::with_terminal(MockTerminal::default());
+    output(&mut other, "macro play m 1");
+    assert_eq!(other.command_history[1..], recorded);
+    assert!(FILESYSTEM.lock().resolve("macro_file").is_ok());
+
+    // A macro can't play another one, or start recording
+    output(&mut shell, "rm macro_file");
+    ctrl(&mut shell, 'x');
+    run_line(&mut shell, "macro play m");
+    ctrl(&mut shell, 'x');
+    output(&mut shell, "rm macro_file");
+    output(&mut shell, "macro save outer");
+    let outer = output(&mut shell, "macro play outer");
+    assert!(outer.contains(&"error: macros can't be played by a macro\n".to_string()));
+    assert!(FILESYSTEM.lock().resolve("macro_file").is_err());
+
+    let modifiers = Modifiers {
+        ctrl: true,
+        ..Modifiers::default()
+    };
+    let ctrl_x = MacroKey::new(DecodedKey::Unicode('x'), modifiers).unwrap();
+    macros::save(&mut VFS.lock(), "rec", &[ctrl_x]).unwrap();
+    assert_eq!(
+        output(&mut shell, "macro play rec"),
+        ["error: rec: macros can't start recording\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "macro play nope"),
+        ["error: nope: no such macro\n"]
+    );
+}
+
+#[test_case]
+fn test_pager() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let lines = (1..=100)
+        .map(|i| format!("line {}\n", i))
+        .collect::<String>();
+    {
+        let mut fs = FILESYSTEM.lock();
+        let inumber = fs.create_at("paged", InodeKind::File).unwrap();
+        fs.write(inumber, 0, lines.as_bytes()).unwrap();
+    }
+    let mut terminal = MockTerminal::default();
+    terminal.height = Some(10);
+    let mut shell = Shell::with_terminal(terminal);
+
+    // A screen less the command line is shown, and the input line is hidden while paging
+    let shown = output(&mut shell, "less paged").concat();
+    assert!(shown.starts_with("line 1\n") && shown.ends_with("line 9\n--More-- (7%)"));
+    assert!(shell.pager.is_some());
+    type_str(&mut shell, " ");
+    let page = (10..=18)
+        .map(|i| format!("line {}\n", i))
+        .collect::<String>();
+    let calls = shell.terminal.lock().take_calls();
+    assert!(calls.contains(&terminal::TerminalCall::Write(page)));
+    type_str(&mut shell, "\n");
+    assert_eq!(shell.terminal.lock().line(), "--More-- (18%)");
+
+    // Quitting brings back the prompt, and the rest of the output is dropped
+    type_str(&mut shell, "q");
+    assert!(shell.pager.is_none());
+    assert_eq!(shell.terminal.lock().line(), PROMPT);
+    assert_eq!(output(&mut shell, "echo hi"), ["hi\n"]);
+
+    // Piped output is paged too, and Ctrl+C quits
+    assert!(output(&mut shell, "cat paged | less")
+        .concat()
+        .ends_with("--More-- (7%)"));
+    ctrl(&mut shell, 'c');
+    assert!(shell.pager.is_none());
+    output(&mut shell, "rm paged");
+}
+
+#[test_case]
+fn test_serial_paste_and_echo() {
+    use crate::task::serial::SerialDecoder;
+    use terminal::MockTerminal;
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let mut decoder = SerialDecoder::new();
+    output(&mut shell, "echo old");
+    let mut send = |shell: &mut Shell<MockTerminal>, bytes: &[u8]| {
+        for &byte in bytes {
+            decoder.decode(byte, |key, modifiers| shell.handle_keypress(key, modifiers));
+        }
+    };
+
+    // The arrow key sequence in the paste is typed instead of going back in the history
+    send(&mut shell, b"echo \x1b[200~a\x1b[Ab\x1b[201~");
+    assert_eq!(buffer_str(&shell), "echo a[Ab");
+    send(&mut shell, b"\x1b[A");
+    assert_eq!(buffer_str(&shell), "echo old");
+    ctrl(&mut shell, 'u');
+
+    // A terminal which echoes keys itself only gets the prompt and the output
+    shell.terminal.lock().local_echo = true;
+    shell.terminal.lock().take_calls();
+    send(&mut shell, b"echo hi\r\n");
+    let calls = shell.terminal.lock().take_calls();
+    assert!(!calls
+        .iter()
+        .any(|call| matches!(call, terminal::TerminalCall::Write(text) if text.contains("echo"))));
+    assert!(calls.contains(&terminal::TerminalCall::Write("hi\n".into())));
+    assert_eq!(shell.terminal.lock().line(), PROMPT);
+    assert_eq!(shell.command_history.last().unwrap(), "echo hi");
+}
+
+#[test_case]
+fn test_history_search() {
+    use pc_keyboard::KeyCode;
+    use terminal::{MockTerminal, TerminalCall};
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    for command in [
+        "echo apple pie",
+        "echo banana",
+        "echo apple",
+        "echo apple",
+        "echo cherry",
+    ] {
+        output(&mut shell, command);
+    }
+    let line = |shell: &Shell<MockTerminal>| shell.terminal.lock().line();
+
+    // Typing narrows the search, Ctrl+R skips to older matches with other text
+    ctrl(&mut shell, 'r');
+    assert_eq!(line(&shell), "(reverse-i-search)`': ");
+    type_str(&mut shell, "ap");
+    assert_eq!(line(&shell), "(reverse-i-search)`ap': echo apple");
+    ctrl(&mut shell, 'r');
+    assert_eq!(line(&shell), "(reverse-i-search)`ap': echo apple pie");
+    ctrl(&mut shell, 'r');
+    assert_eq!(
+        line(&shell),
+        "(failed reverse-i-search)`ap': echo apple pie"
+    );
+    type_str(&mut shell, "x");
+    assert_eq!(
+        line(&shell),
+        "(failed reverse-i-search)`apx': echo apple pie"
+    );
+    type_str(&mut shell, "\u{8}p");
+    assert_eq!(line(&shell), "(reverse-i-search)`app': echo apple");
+
+    // An arrow key puts the match in the input line for editing
+    move_cursor(&mut shell, KeyCode::ArrowLeft, 1);
+    assert!(shell.search.is_none());
+    type_str(&mut shell, "s");
+    assert_eq!(buffer_str(&shell), "echo apples");
+    assert_eq!(line(&shell), "> echo apples");
+    ctrl(&mut shell, 'u');
+
+    // Enter runs the match
+    ctrl(&mut shell, 'r');
+    type_str(&mut shell, "ban");
+    shell.terminal.lock().take_calls();
+    type_str(&mut shell, "\n");
+    let calls = shell.terminal.lock().take_calls();
+    assert!(calls.contains(&TerminalCall::Write("banana\n".into())));
+    assert_eq!(shell.command_history.last().unwrap(), "echo banana");
+
+    // Ctrl+C cancels back to an empty prompt
+    ctrl(&mut shell, 'r');
+    type_str(&mut shell, "ch");
+    assert_eq!(line(&shell), "(reverse-i-search)`ch': echo cherry");
+    ctrl(&mut shell, 'c');
+    assert!(shell.search.is_none());
+    assert!(shell.buffer.is_empty());
+    assert_eq!(line(&shell), PROMPT);
+    assert_eq!(shell.command_history.len(), 6);
+}
+
+#[test_case]
+fn test_df() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let (blocks, free) = {
+        let fs = FILESYSTEM.lock();
+        (fs.blocks(), fs.free_blocks())
+    };
+    let row = |reserved: usize| {
+        let size = |blocks: usize| HumanBytes((blocks * BLOCK_SIZE) as u64);
+        format!(
+            "{:>12}{:>12}{:>12}{:>12}\n",
+            size(blocks),
+            size(blocks - free),
+            size(free - reserved),
+            size(reserved)
+        )
+    };
+    let reserved = crate::fs::file::DEFAULT_RESERVED_BLOCKS;
+    assert_eq!(
+        output(&mut shell, "df")[0],
+        "        size        used       avail    reserved\n"
+    );
+    assert_eq!(output(&mut shell, "df")[1], row(reserved));
+    assert!(output(&mut shell, "df reserve 4").is_empty());
+    assert_eq!(output(&mut shell, "df")[1], row(4));
+    output(&mut shell, &format!("df reserve {}", reserved));
+}
+
+#[test_case]
+fn test_compress() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let text = "the same line over and over\n".repeat(200);
+    let inumber = {
+        let mut fs = FILESYSTEM.lock();
+        let inumber = fs.create_at("notes", InodeKind::File).unwrap();
+        fs.write(inumber, 0, text.as_bytes()).unwrap();
+        inumber
+    };
+    let physical_size = || FILESYSTEM.lock().stat(inumber).unwrap().physical_size as u64;
+    let before = physical_size();
+
+    assert_eq!(output(&mut shell, "df").len(), 2);
+    let lines = output(&mut shell, "compress notes");
+    assert_eq!(
+        lines,
+        [format!(
+            "notes: {} on disk, was {}\n",
+            HumanBytes(physical_size()),
+            HumanBytes(before)
+        )]
+    );
+    assert!(physical_size() < before);
+    assert_eq!(output(&mut shell, "cat notes").concat(), text);
+    assert_eq!(
+        output(&mut shell, "df")[2],
+        format!(
+            "1 compressed files hold {} in {}\n",
+            HumanBytes(text.len() as u64),
+            HumanBytes(physical_size())
+        )
+    );
+
+    output(&mut shell, "compress -d notes");
+    assert_eq!(physical_size(), before);
+    assert!(!FILESYSTEM.lock().stat(inumber).unwrap().compressed);
+    assert_eq!(output(&mut shell, "df").len(), 2);
+    assert_eq!(
+        output(&mut shell, "compress -x"),
+        ["error: usage: compress [-d] <path>\n"]
+    );
+}
+
+#[test_case]
+fn test_lsblk() {
+    use terminal::MockTerminal;
+
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let image = partitions::test_image(96, &[(0x83, 1, 8), (0x0c, 16, 64)]);
+    let previous = disk::attach(Box::new(image));
+    assert_eq!(
+        output(&mut shell, "lsblk"),
+        [
+            "NAME            SIZE  TYPE\n",
+            "disk      384.00 KiB  disk\n",
+            "diskp1     32.00 KiB  Linux (83)\n",
+            "diskp2    256.00 KiB  FAT32 (0c)\n",
+        ]
+    );
+
+    // The kernel filesystem can live in a partition, which lsblk still lists the disk around
+    assert_eq!(
+        output(&mut shell, "mount diskp5"),
+        ["error: diskp5: no such device\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "mount diskp3"),
+        ["error: partition 3 isn't in the partition table\n"]
+    );
+    partitions::attach(Some(2)).unwrap();
+    FileSystem::format().unwrap();
+    partitions::attach(None).unwrap();
+    let mounted = output(&mut shell, "mount diskp2");
+    let (blocks, free) = {
+        let fs = FILESYSTEM.lock();
+        (fs.blocks(), fs.free_blocks())
+    };
+    assert_eq!(
+        mounted,
+        [format!(
+            "mounted diskp2: {} blocks, {} free\n",
+            blocks, free
+        )]
+    );
+    // The backup superblock takes the last block of the partition
+    assert_eq!((blocks, partitions::attached()), (63, Some(2)));
+    assert_eq!(output(&mut shell, "lsblk").len(), 4);
+    assert!(output(&mut shell, "mount")[0].starts_with("diskp2 on / "));
+    assert!(output(&mut shell, "touch in-partition").is_empty());
+    assert_eq!(
+        output(&mut shell, "mount disk"),
+        ["error: invalid magic number 0x0, is the disk formatted?\n"]
+    );
+    assert_eq!(partitions::attached(), None);
+
+    // The filesystem isn't partitioned
+    disk::attach(previous);
+    crate::fs::init().unwrap();
+    assert_eq!(output(&mut shell, "lsblk").len(), 2);
+}
+
+#[test_case]
+fn test_unmounted_shell() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let backup = SuperblockCopy::Backup.block();
+    let mut saved = [0; 64];
+    disk::read(backup, 0, &mut saved).unwrap();
+
+    // With both superblocks damaged nothing is mounted at boot, and every command using the
+    // filesystem says so
+    for copy in [SuperblockCopy::Primary, SuperblockCopy::Backup] {
+        disk::write(copy.block(), 0, &[0xff; 64]).unwrap();
+    }
+    assert!(matches!(
+        crate::fs::mount_root(),
+        Err(FileSystemError::InvalidMagicNumber(u32::MAX))
+    ));
+    for command in [
+        "ls",
+        "touch new",
+        "cat file",
+        "df",
+        "fsck",
+        "verify",
+        "badblocks",
+    ] {
+        let lines = output(&mut shell, command);
+        assert_eq!(lines.len(), 1, "{}", command);
+        assert!(
+            lines[0].starts_with("error: ") && lines[0].ends_with("no filesystem is mounted\n"),
+            "{}: {}",
+            command,
+            lines[0]
+        );
+    }
+    // A setting still takes effect when it can't be saved
+    let lines = output(&mut shell, "color off");
+    assert!(
+        lines.len() == 1
+            && lines[0].starts_with("warning: color was changed but not saved: ")
+            && lines[0].ends_with("no filesystem is mounted\n"),
+        "{:?}",
+        lines
+    );
+    assert!(!shell.terminal.lock().colors && !ui::console_colors());
+    ui::set_console_colors(true);
+    let bad_magic = "error: invalid magic number 0xffffffff, is the disk formatted?\n";
+    assert_eq!(output(&mut shell, "mount disk"), [bad_magic]);
+    assert_eq!(output(&mut shell, "fsck --repair"), [bad_magic]);
+    assert_eq!(
+        output(&mut shell, "mount sda"),
+        ["error: sda: no such device\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "format disk"),
+        ["error: usage: format [disk[pN]] --force\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "format --force"),
+        ["error: format: can't ask for confirmation here, run it at the prompt\n"]
+    );
+
+    // Once the backup is readable again it's mounted from, and the primary restored
+    disk::write(backup, 0, &saved).unwrap();
+    let (blocks, free) = {
+        let mut fs = FILESYSTEM.lock();
+        fs.mount().unwrap();
+        let sizes = (fs.blocks(), fs.free_blocks());
+        fs.unmount();
+        sizes
+    };
+    assert_eq!(
+        output(&mut shell, "mount disk"),
+        [
+            "the primary superblock is damaged, mounted from the backup; run `fsck --repair` to \
+             restore it\n"
+                .to_string(),
+            format!("mounted disk: {} blocks, {} free\n", blocks, free)
+        ]
+    );
+    assert_eq!(
+        output(&mut shell, "fsck"),
+        ["the primary superblock doesn't match the mounted filesystem\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "fsck --repair"),
+        ["restored the primary superblock\n", "no problems found\n"]
+    );
+    assert!(output(&mut shell, "touch new").is_empty());
+    crate::fs::init().unwrap();
+}
+
+#[test_case]
+fn test_read_only_mount() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert!(output(&mut shell, "touch kept").is_empty());
+    let (blocks, free) = {
+        let fs = FILESYSTEM.lock();
+        (fs.blocks(), fs.free_blocks())
+    };
+
+    assert_eq!(
+        output(&mut shell, "mount -r disk"),
+        [format!(
+            "mounted disk read-only: {} blocks, {} free\n",
+            blocks, free
+        )]
+    );
+    let writes = disk::stats().writes;
+    for command in [
+        "touch new",
+        "mkdir dir",
+        "rm kept",
+        "echo data > kept",
+        "fsck --repair",
+    ] {
+        assert_eq!(
+            output(&mut shell, command),
+            ["error: the filesystem is mounted read-only\n"],
+            "{}",
+            command
+        );
+    }
+    assert_eq!(output(&mut shell, "ls"), ["kept\n"]);
+    assert_eq!(output(&mut shell, "fsck"), ["no problems found\n"]);
+    assert_eq!(disk::stats().writes, writes);
+
+    assert_eq!(
+        output(&mut shell, "mount -w"),
+        ["remounted disk read-write\n"]
+    );
+    assert!(output(&mut shell, "touch new").is_empty());
+    assert_eq!(
+        output(&mut shell, "mount -x"),
+        ["error: usage: mount [[-r|-w] disk[pN] | ram <path> | <image> <path> -t ext2 [-r]]\n"]
+    );
+}
+
+#[test_case]
+fn test_mount_table() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    assert_eq!(
+        output(&mut shell, "mount ram mnt"),
+        ["error: /mnt: no such file or directory\n"]
+    );
+    assert!(output(&mut shell, "mkdir mnt").is_empty());
+    assert!(output(&mut shell, "mount ram /mnt").is_empty());
+    assert_eq!(
+        output(&mut shell, "mount"),
+        [
+            "disk on / type diskfs, 0 files open\n",
+            "ram on /mnt type ramfs, 0 files open\n"
+        ]
+    );
+
+    // Files end up on the filesystem their path leads to, and are copied between them
+    for command in [
+        "echo on disk > a",
+        "echo in ram > mnt/b",
+        "cp a mnt/c",
+        "mkdir mnt/sub",
+    ] {
+        assert!(output(&mut shell, command).is_empty(), "{}", command);
+    }
+    assert_eq!(output(&mut shell, "ls mnt"), ["b    c    sub\n"]);
+    assert_eq!(output(&mut shell, "cat mnt/c"), ["on disk\n"]);
+    assert_eq!(
+        output(&mut shell, "find mnt -type f"),
+        ["/mnt/b\n", "/mnt/c\n"]
+    );
+    {
+        let fs = FILESYSTEM.lock();
+        assert!(fs.list(fs.resolve("mnt").unwrap()).unwrap().is_empty());
+    }
+    assert_eq!(
+        output(&mut shell, "mv a mnt/a"),
+        ["error: a: can't move or link to another filesystem\n"]
+    );
+    assert!(output(&mut shell, "mv mnt/b mnt/sub/b").is_empty());
+    assert_eq!(output(&mut shell, "ls mnt/sub"), ["b\n"]);
+
+    // Commands which only work on the disk say so for files elsewhere, rather than looking for
+    // them on the disk
+    assert_eq!(
+        output(&mut shell, "verify mnt/c"),
+        ["error: /mnt/c: operation not supported\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "compress mnt/c"),
+        ["error: /mnt/c: operation not supported\n"]
+    );
+    // Macros are found wherever their directory is mounted from
+    for command in [
+        "mkdir macros",
+        "mount ram macros",
+        "echo - u+0061 > macros/m",
+    ] {
+        assert!(output(&mut shell, command).is_empty(), "{}", command);
+    }
+    assert_eq!(
+        output(&mut shell, "macro list"),
+        [format!("{:<16} 1 keys\n", "m")]
+    );
+    assert!(output(&mut shell, "umount macros").is_empty());
+
+    // The filesystem stays mounted while a job has a file open on it
+    let files = JobFiles::new("hold");
+    files.open(&VFS.lock(), "mnt/c", OpenMode::Read).unwrap();
+    assert_eq!(
+        output(&mut shell, "mount")[1],
+        "ram on /mnt type ramfs, 1 files open\n"
+    );
+    assert_eq!(
+        output(&mut shell, "umount mnt"),
+        ["error: /mnt: a filesystem is mounted there or files are open on it\n"]
+    );
+    drop(files);
+    assert!(output(&mut shell, "umount mnt").is_empty());
+    assert!(output(&mut shell, "ls mnt").is_empty());
+    assert_eq!(
+        output(&mut shell, "umount mnt"),
+        ["error: /mnt: not a mount point\n"]
+    );
+}
+
+#[test_case]
+fn test_mount_ext2_image() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    for command in ["mkdir img", "mkdir mnt", "mount ram img"] {
+        assert!(output(&mut shell, command).is_empty(), "{}", command);
+    }
+    {
+        // The fixture has the zeroes at the end of the filesystem cut off
+        let image = include_bytes!("../../tests/fixtures/ext2-1k.img");
+        let mut vfs = VFS.lock();
+        vfs.create("img/ext2.img", InodeKind::File).unwrap();
+        let mut file = vfs.open("img/ext2.img").unwrap();
+        vfs.write(&mut file, image).unwrap();
+        vfs.truncate("img/ext2.img", 512 * 1024).unwrap();
+    }
+
+    assert!(output(&mut shell, "mount img/ext2.img mnt -t ext2 -r").is_empty());
+    assert_eq!(
+        output(&mut shell, "mount")[2],
+        "img/ext2.img on /mnt type ext2, 0 files open\n"
+    );
+    assert_eq!(
+        output(&mut shell, "cat mnt/hello.txt"),
+        ["Hello from the host!\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "cat /mnt/docs/notes.txt"),
+        ["ext2 directories are linked lists of entries\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "mkdir mnt/new"),
+        ["error: the filesystem is mounted read-only\n"]
+    );
+    assert!(output(&mut shell, "umount mnt").is_empty());
+
+    assert_eq!(
+        output(&mut shell, "mount -t ext4 img/ext2.img mnt"),
+        ["error: ext4: unknown filesystem type\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "mount -t ext2 img/ext2.img"),
+        ["error: usage: mount [[-r|-w] disk[pN] | ram <path> | <image> <path> -t ext2 [-r]]\n"]
+    );
+    // Not an ext2 image
+    assert!(output(&mut shell, "echo text > img/text").is_empty());
+    assert_eq!(
+        output(&mut shell, "mount -t ext2 img/text mnt"),
+        ["error: ext2: not an ext2 filesystem, the magic number is 0x0000\n"]
+    );
+    assert!(output(&mut shell, "umount img").is_empty());
+}
+
+#[test_case]
+fn test_redirection() {
+    use terminal::MockTerminal;
+
+    crate::fs::init().unwrap();
+    let mut shell = Shell::with_terminal(MockTerminal::default());
+    let contents = |path: &str| {
+        let fs = FILESYSTEM.lock();
+        let inumber = fs.resolve(path).unwrap();
+        let mut buf = vec![0; fs.stat(inumber).unwrap().size];
+        fs.read(inumber, 0, &mut buf).unwrap();
+        buf
+    };
+
+    // `>>` creates a missing file, `>` empties an existing one
+    assert!(output(&mut shell, "echo one >> log").is_empty());
+    assert!(output(&mut shell, "echo -n two>>log").is_empty());
+    assert_eq!(contents("log"), b"one\ntwo");
+    assert!(output(&mut shell, "echo -e 'a\\tb' > log").is_empty());
+    assert_eq!(contents("log"), b"a\tb\n");
+    assert!(output(&mut shell, "> log").is_empty());
+    assert_eq!(contents("log"), b"");
+
+    // Appending to a file spanning several blocks continues in its last block
+    let big = (0..3 * BLOCK_SIZE - 5)
+        .map(|i| b'a' + (i % 26) as u8)
+        .collect::<Vec<_>>();
+    {
+        let mut fs = FILESYSTEM.lock();
+        let inumber = fs.create_at("big", InodeKind::File).unwrap();
+        fs.write(inumber, 0, &big).unwrap();
+    }
+    assert!(output(&mut shell, "echo appended >> big").is_empty());
+    assert_eq!(contents("big"), [&big[..], b"appended\n"].concat());
+
+    // Any command can be redirected, and the next command of a pipeline gets no input
+    assert!(output(&mut shell, "cat log >> big2 | wc").contains(&"0 0 0\n".to_string()));
+    assert!(output(&mut shell, "history > hist").is_empty());
+    assert!(String::from_utf8(contents("hist"))
+        .unwrap()
+        .contains("history > hist"));
+    assert_eq!(
+        output(&mut shell, "echo x >"),
+        ["error: syntax error: missing file name after redirection\n"]
+    );
+    assert_eq!(
+        output(&mut shell, "echo x > /"),
+        ["error: /: is a directory\n"]
+    );
+}
@@ -0,0 +1,208 @@
+use alloc::{format, string::String};
+
+use super::terminal::Terminal;
+
+/// Counts the screen rows text takes up. ANSI escape sequences take up no room.
+#[derive(Debug, Clone, Copy)]
+struct RowCounter {
+    width: usize,
+    col: usize,
+    escape: bool,
+}
+
+impl RowCounter {
+    fn new(width: usize) -> Self {
+        Self {
+            width: width.max(1),
+            col: 0,
+            escape: false,
+        }
+    }
+
+    /// Feeds the next character, returning the number of rows the line took up if it ended.
+    fn feed(&mut self, c: char) -> Option<usize> {
+        match c {
+            _ if self.escape => self.escape = !c.is_ascii_alphabetic(),
+            '\x1b' => self.escape = true,
+            '\n' => {
+                let rows = self.col.max(1).div_ceil(self.width);
+                self.col = 0;
+                return Some(rows);
+            }
+            _ => self.col += 1,
+        }
+        None
+    }
+
+    /// Returns the length of the start of `text` which fits in the `rows` left, ending at a
+    /// newline, and takes the rows it fills from `rows`. All of `text` fits if it doesn't fill
+    /// them.
+    fn fit(&mut self, text: &str, rows: &mut usize) -> usize {
+        for (i, c) in text.char_indices() {
+            if let Some(used) = self.feed(c) {
+                *rows = rows.saturating_sub(used);
+                if *rows == 0 {
+                    return i + 1;
+                }
+            }
+        }
+        text.len()
+    }
+}
+
+/// Output written to the terminal until it fills a screen, after which the rest is held back for
+/// a [`Pager`].
+pub struct PageLimit {
+    /// The rows of a screen, which is also how many rows the pager shows at a time.
+    rows: usize,
+    rows_left: usize,
+    counter: RowCounter,
+    /// The number of bytes written to the terminal.
+    shown: usize,
+    held: String,
+}
+
+impl PageLimit {
+    pub fn new(rows: usize, width: usize) -> Self {
+        Self {
+            rows: rows.max(1),
+            rows_left: rows.max(1),
+            counter: RowCounter::new(width),
+            shown: 0,
+            held: String::new(),
+        }
+    }
+
+    /// Writes as much of `s` as still fits on the screen, and holds back the rest.
+    pub fn write(&mut self, terminal: &mut dyn Terminal, s: &str) {
+        if self.rows_left == 0 {
+            self.held.push_str(s);
+            return;
+        }
+        let len = self.counter.fit(s, &mut self.rows_left);
+        terminal.write_str(&s[..len]);
+        self.shown += len;
+        self.held.push_str(&s[len..]);
+    }
+
+    /// Returns a pager for the output which was held back, if any was.
+    pub fn into_pager(self) -> Option<Pager> {
+        if self.held.is_empty() {
+            return None;
+        }
+        let mut text = self.held;
+        // The last line has to be finished, or it would be left on the line the status is drawn on
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        Some(Pager {
+            text,
+            pos: 0,
+            shown: self.shown,
+            page_rows: self.rows,
+            width: self.counter.width,
+        })
+    }
+}
+
+/// Shows output which didn't fit on the screen a page at a time, like `less`. The status line
+/// `--More-- (n%)` is drawn on the input line, and keys are given to
+/// [`handle_key`](Self::handle_key) until it returns `false`.
+pub struct Pager {
+    text: String,
+    /// How much of `text` has been shown.
+    pos: usize,
+    /// The number of bytes shown before the pager started.
+    shown: usize,
+    page_rows: usize,
+    width: usize,
+}
+
+impl Pager {
+    /// Handles a key while paging: Space shows the next page, Enter the next line and `q` drops
+    /// the rest of the output. Other keys are ignored. Returns `false` once paging is over, with
+    /// the input line cleared.
+    pub fn handle_key(&mut self, c: char, terminal: &mut dyn Terminal) -> bool {
+        match c {
+            ' ' => self.show(terminal, self.page_rows),
+            '\n' => self.show(terminal, 1),
+            'q' | 'Q' => self.pos = self.text.len(),
+            _ => return true,
+        }
+        if self.pos == self.text.len() {
+            terminal.clear_line();
+            return false;
+        }
+        self.draw_status(terminal);
+        true
+    }
+
+    /// Replaces the input line with how far into the output the pager is.
+    pub fn draw_status(&self, terminal: &mut dyn Terminal) {
+        let percent = (self.shown + self.pos) * 100 / (self.shown + self.text.len());
+        terminal.clear_line();
+        terminal.write_str(&format!("--More-- ({}%)", percent));
+    }
+
+    /// Writes the next `rows` rows of the output.
+    fn show(&mut self, terminal: &mut dyn Terminal, mut rows: usize) {
+        let rest = &self.text[self.pos..];
+        let len = RowCounter::new(self.width).fit(rest, &mut rows);
+        terminal.clear_line();
+        terminal.write_str(&rest[..len]);
+        self.pos += len;
+    }
+}
+
+#[test_case]
+fn test_pager_keys() {
+    use super::terminal::{MockTerminal, TerminalCall};
+
+    let lines = (1..=100)
+        .map(|i| format!("line {}\n", i))
+        .collect::<String>();
+    let mut terminal = MockTerminal::default();
+    let mut limit = PageLimit::new(10, 80);
+    // Written in pieces which don't end at line ends
+    for chunk in lines.as_bytes().chunks(7) {
+        limit.write(&mut terminal, core::str::from_utf8(chunk).unwrap());
+    }
+    let written = |terminal: &mut MockTerminal| {
+        terminal
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                TerminalCall::Write(text) => Some(text),
+                _ => None,
+            })
+            .collect::<String>()
+    };
+    let first = written(&mut terminal);
+    assert_eq!(first.lines().last(), Some("line 10"));
+    let mut pager = limit.into_pager().unwrap();
+    pager.draw_status(&mut terminal);
+    assert_eq!(terminal.line(), "--More-- (8%)");
+
+    // Space shows a page, Enter a line, and other keys nothing
+    assert!(pager.handle_key(' ', &mut terminal));
+    let page = written(&mut terminal);
+    assert!(page.starts_with("line 11\n") && page.contains("line 20\n--More--"));
+    assert!(pager.handle_key('\n', &mut terminal));
+    assert!(written(&mut terminal).starts_with("line 21\n--More--"));
+    assert!(pager.handle_key('x', &mut terminal));
+    assert!(terminal.take_calls().is_empty());
+
+    // Quitting drops the rest
+    assert!(!pager.handle_key('q', &mut terminal));
+    assert_eq!(written(&mut terminal), "");
+    assert_eq!(terminal.line(), "");
+
+    // Output which fits isn't paged, wrapped lines take up more rows
+    let mut limit = PageLimit::new(3, 4);
+    limit.write(&mut terminal, "abcdefgh\nx\n");
+    assert!(limit.into_pager().is_none());
+    let mut limit = PageLimit::new(3, 4);
+    limit.write(&mut terminal, "abcdefgh\nx\ny\n");
+    let held = limit.into_pager().unwrap();
+    assert_eq!(held.text, "y\n");
+}
@@ -0,0 +1,459 @@
+use core::fmt::Write;
+
+use alloc::string::String;
+
+use crate::{rtc, timer, ui::Style};
+
+/// The variable holding the prompt template, see [`expand`].
+pub const PROMPT_VAR: &str = "PROMPT";
+/// Put in front of the prompt in overwrite mode.
+pub const OVERWRITE_MARK: &str = "[ovr]";
+/// What `\w` shows. The shell has no working directory yet, every path is resolved from the root.
+const WORKING_DIR: &str = "/";
+/// Ends a prompt which was cut short.
+const ELLIPSIS: &str = "...";
+/// `\w` shows only the last components of deeper directories.
+const DIR_COMPONENTS: usize = 2;
+
+/// The colors `\c{name}` switches to.
+const COLORS: [(&str, &str); 8] = [
+    ("red", "\x1b[91m"),
+    ("green", "\x1b[92m"),
+    ("yellow", "\x1b[93m"),
+    ("blue", "\x1b[94m"),
+    ("magenta", "\x1b[95m"),
+    ("cyan", "\x1b[96m"),
+    ("white", "\x1b[97m"),
+    ("gray", "\x1b[37m"),
+];
+
+/// What a prompt shows besides its template. The prompt is only expanded again when these or the
+/// template change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptInputs {
+    /// The directory shown by `\w`.
+    pub dir: &'static str,
+    /// The exit status of the last command, for `\$?`.
+    pub status: u8,
+    /// The number of jobs running in every shell, for `\j`.
+    pub jobs: usize,
+    /// The seconds since boot, which only matter to templates showing the time with `\t`.
+    pub second: u64,
+    pub overwrite: bool,
+    /// The number of columns the prompt has to fit in.
+    pub width: usize,
+    pub colors: bool,
+}
+
+impl PromptInputs {
+    /// The inputs of a shell at the root directory, with no jobs.
+    pub fn new(status: u8, width: usize, colors: bool) -> Self {
+        Self {
+            dir: WORKING_DIR,
+            status,
+            jobs: 0,
+            second: timer::ticks() / u64::from(timer::tick_hz()),
+            overwrite: false,
+            width,
+            colors,
+        }
+    }
+}
+
+/// An expanded prompt.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Prompt {
+    /// The text, with escape sequences switching colors.
+    pub text: String,
+    /// The number of columns the text takes up.
+    pub width: usize,
+}
+
+/// Keeps the last expanded prompt, so that typing doesn't expand it again for every key.
+#[derive(Debug, Default)]
+pub struct PromptCache {
+    template: String,
+    /// The inputs the prompt was expanded with, or `None` before the first expansion.
+    inputs: Option<PromptInputs>,
+    prompt: Prompt,
+    renders: usize,
+}
+
+impl PromptCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the prompt for the template, expanding it again if it or the inputs changed since
+    /// the last call. Returns `true` along with it if it was expanded again.
+    pub fn get(&mut self, template: &str, mut inputs: PromptInputs) -> (&Prompt, bool) {
+        // The time only changes templates which show it
+        if !template.contains("\\t") {
+            inputs.second = 0;
+        }
+        if self.inputs == Some(inputs) && self.template == template {
+            return (&self.prompt, false);
+        }
+        self.template.clear();
+        self.template.push_str(template);
+        self.inputs = Some(inputs);
+        expand(template, &inputs, &mut self.prompt);
+        self.renders += 1;
+        (&self.prompt, true)
+    }
+
+    /// Returns the prompt as last expanded.
+    pub fn prompt(&self) -> &Prompt {
+        &self.prompt
+    }
+
+    /// Returns the number of times a prompt has been expanded.
+    pub fn renders(&self) -> usize {
+        self.renders
+    }
+}
+
+/// Writes text to a prompt, counting the columns it takes up. Once it no longer fits, the rest is
+/// dropped and the end is replaced by [`ELLIPSIS`].
+struct PromptWriter<'a> {
+    prompt: &'a mut Prompt,
+    limit: usize,
+    colors: bool,
+    /// Where the text is cut if it doesn't fit, leaving room for the ellipsis.
+    cut: Option<usize>,
+    full: bool,
+    /// The color switched to with `\c`, restored after the exit status is shown in red.
+    color: Option<&'static str>,
+}
+
+impl PromptWriter<'_> {
+    fn push(&mut self, c: char) {
+        if self.full {
+            return;
+        }
+        if self.prompt.width < self.limit {
+            self.prompt.text.push(c);
+            self.prompt.width += 1;
+            if self.prompt.width + ELLIPSIS.len() == self.limit {
+                self.cut = Some(self.prompt.text.len());
+            }
+            return;
+        }
+        let ellipsis = &ELLIPSIS[..ELLIPSIS.len().min(self.limit)];
+        self.prompt.text.truncate(self.cut.unwrap_or(0));
+        self.prompt.width = self.limit;
+        self.escape(Style::Prompt.color());
+        self.prompt.text.push_str(ellipsis);
+        self.full = true;
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.chars().for_each(|c| self.push(c));
+    }
+
+    /// Switches to a color, or back to the one the prompt is drawn in.
+    fn switch(&mut self, color: Option<&'static str>) {
+        self.color = color;
+        self.escape(color.unwrap_or(Style::Prompt.color()));
+    }
+
+    /// Writes an escape sequence, which takes up no columns, if colors are on.
+    fn escape(&mut self, sequence: &str) {
+        if self.colors && !self.full {
+            self.prompt.text.push_str(sequence);
+        }
+    }
+}
+
+impl Write for PromptWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+/// Expands a prompt template into `prompt`, reusing its buffer. The template is literal text with
+/// these escapes:
+///
+/// - `\w` the working directory, only its last two components if it's deeper
+/// - `\$?` the exit status of the last command, in red, if it failed
+/// - `\t` the time of the real-time clock, as `HH:MM:SS`
+/// - `\j` the number of jobs running in every shell
+/// - `\c{color}` switches to a color: red, green, yellow, blue, magenta, cyan, white or gray
+/// - `\r` switches back to the color of [`Style::Prompt`]
+/// - `\\` a backslash
+///
+/// Other backslashes are kept as they are. The prompt is cut short with an ellipsis if it's wider
+/// than the inputs allow, leaving a column for the cursor.
+pub fn expand(template: &str, inputs: &PromptInputs, prompt: &mut Prompt) {
+    prompt.text.clear();
+    prompt.width = 0;
+    let mut out = PromptWriter {
+        prompt,
+        limit: inputs.width.saturating_sub(1).max(1),
+        colors: inputs.colors,
+        cut: None,
+        full: false,
+        color: None,
+    };
+    if inputs.overwrite {
+        out.push_str(OVERWRITE_MARK);
+    }
+    let mut rest = template;
+    while let Some(backslash) = rest.find('\\') {
+        if out.full {
+            return;
+        }
+        out.push_str(&rest[..backslash]);
+        let escape = &rest[backslash + 1..];
+        // The length of the escape after the backslash
+        let len = match escape.as_bytes().first() {
+            Some(b'w') => {
+                let (prefix, dir) = short_dir(inputs.dir);
+                out.push_str(prefix);
+                out.push_str(dir);
+                1
+            }
+            Some(b'$') if escape.starts_with("$?") => {
+                if inputs.status != 0 {
+                    out.escape(Style::Error.color());
+                    let _ = write!(out, "{}", inputs.status);
+                    out.switch(out.color);
+                }
+                2
+            }
+            Some(b't') => {
+                let now = rtc::now();
+                let (h, m, s) = (now.hour, now.minute, now.second);
+                let _ = write!(out, "{:02}:{:02}:{:02}", h, m, s);
+                1
+            }
+            Some(b'j') => {
+                let _ = write!(out, "{}", inputs.jobs);
+                1
+            }
+            Some(b'c') => match parse_color(&escape[1..]) {
+                Some((len, color)) => {
+                    out.switch(Some(color));
+                    1 + len
+                }
+                None => {
+                    out.push('\\');
+                    0
+                }
+            },
+            Some(b'r') => {
+                out.switch(None);
+                1
+            }
+            Some(b'\\') => {
+                out.push('\\');
+                1
+            }
+            _ => {
+                out.push('\\');
+                0
+            }
+        };
+        rest = &escape[len..];
+    }
+    out.push_str(rest);
+}
+
+/// Parses `{name}` at the start of `text`, returning its length and the escape sequence of the
+/// color.
+fn parse_color(text: &str) -> Option<(usize, &'static str)> {
+    let end = text.find('}')?;
+    let name = text.strip_prefix('{')?.get(..end - 1)?;
+    let (_, color) = COLORS.iter().find(|(color, _)| *color == name)?;
+    Some((end + 1, color))
+}
+
+/// Shortens directories deeper than [`DIR_COMPONENTS`] to their last components, returning them
+/// along with the ellipsis to put in front, like `...` and `/share/doc` for `/usr/share/doc`.
+pub fn short_dir(dir: &str) -> (&'static str, &str) {
+    let trimmed = dir.trim_end_matches('/');
+    match trimmed.rmatch_indices('/').nth(DIR_COMPONENTS - 1) {
+        Some((i, _)) if i > 0 => (ELLIPSIS, &trimmed[i..]),
+        _ => ("", dir),
+    }
+}
+
+/// Splits an expanded prompt into the characters it shows and their colors.
+#[cfg(test)]
+fn cells(text: &str) -> alloc::vec::Vec<(char, crate::vgabuf::VGAColor)> {
+    let mut parser = crate::vgabuf::AnsiParser::new();
+    text.chars()
+        .filter_map(|c| parser.feed(c).map(|c| (c, parser.color())))
+        .collect()
+}
+
+#[test_case]
+fn test_expand_prompt() {
+    use crate::vgabuf::{Color, VGAColor, DEFAULT_COLOR};
+
+    let inputs = PromptInputs {
+        dir: "/usr/share/doc",
+        status: 1,
+        jobs: 2,
+        second: 0,
+        overwrite: false,
+        width: 80,
+        colors: true,
+    };
+    let expanded = |template: &str, inputs: &PromptInputs| {
+        let mut prompt = Prompt::default();
+        expand(template, inputs, &mut prompt);
+        prompt
+    };
+    let prompt = expanded("\\w [\\j] \\\\ \\x> ", &inputs);
+    assert_eq!(prompt.text, ".../share/doc [2] \\ \\x> ");
+    assert_eq!(prompt.width, prompt.text.len());
+
+    // The status is red and only shown when nonzero, after which the chosen color comes back
+    let prompt = expanded("\\c{cyan}a\\$?b\\rc", &inputs);
+    let cyan = VGAColor::new(Color::LightCyan, Color::Black);
+    let red = VGAColor::new(Color::LightRed, Color::Black);
+    let green = VGAColor::new(Color::LightGreen, Color::Black);
+    assert_eq!(
+        cells(&prompt.text),
+        [('a', cyan), ('1', red), ('b', cyan), ('c', green)]
+    );
+    assert_eq!(prompt.width, 4);
+    // Without a chosen color, the prompt's own color comes back rather than the default one
+    let prompt = expanded("a\\$?b", &inputs);
+    let drawn = alloc::format!("{}", crate::ui::styled(Style::Prompt, true, &prompt.text));
+    let drawn = cells(&drawn);
+    assert_eq!(drawn, [('a', green), ('1', red), ('b', green)]);
+    assert!(drawn.iter().all(|&(_, color)| color != DEFAULT_COLOR));
+    let ok = PromptInputs {
+        status: 0,
+        ..inputs
+    };
+    assert_eq!(expanded("\\c{cyan}a\\$?b", &ok).text, "\x1b[96mab");
+    let plain = PromptInputs {
+        colors: false,
+        ..inputs
+    };
+    assert_eq!(expanded("\\c{cyan}a\\$?b\\r", &plain).text, "a1b");
+    // Unknown colors are kept as typed
+    assert_eq!(expanded("\\c{pink}", &plain).text, "\\c{pink}");
+
+    let prompt = expanded("\\t", &inputs);
+    let bytes = prompt.text.as_bytes();
+    assert!(prompt.text.len() == 8 && bytes[2] == b':' && bytes[5] == b':');
+
+    let overwrite = PromptInputs {
+        overwrite: true,
+        ..plain
+    };
+    assert_eq!(expanded("> ", &overwrite).text, "[ovr]> ");
+}
+
+#[test_case]
+fn test_prompt_fits_one_row() {
+    let inputs = PromptInputs {
+        dir: "/",
+        status: 0,
+        jobs: 0,
+        second: 0,
+        overwrite: false,
+        width: 20,
+        colors: true,
+    };
+    let mut prompt = Prompt::default();
+    expand(&"\\c{red}ab".repeat(100), &inputs, &mut prompt);
+    assert_eq!(prompt.width, 19);
+    assert_eq!(cells(&prompt.text).len(), 19);
+    assert!(prompt.text.ends_with("\x1b[92m..."));
+    // Exactly filling the row isn't cut
+    expand(&"x".repeat(19), &inputs, &mut prompt);
+    assert_eq!(prompt.text, "x".repeat(19));
+
+    assert_eq!(short_dir("/"), ("", "/"));
+    assert_eq!(short_dir("/usr/share"), ("", "/usr/share"));
+    assert_eq!(short_dir("/usr/share/doc/"), ("...", "/share/doc"));
+    assert_eq!(short_dir("/a/b/c/d"), ("...", "/c/d"));
+}
+
+#[test_case]
+fn test_prompt_cache() {
+    let mut cache = PromptCache::new();
+    let inputs = PromptInputs {
+        dir: "/",
+        status: 0,
+        jobs: 0,
+        second: 5,
+        overwrite: false,
+        width: 80,
+        colors: false,
+    };
+    assert_eq!(
+        cache.get("\\w> ", inputs),
+        (
+            &Prompt {
+                text: "/> ".into(),
+                width: 3
+            },
+            true
+        )
+    );
+    // Nothing changed, and the time doesn't matter to this template
+    assert!(!cache.get("\\w> ", inputs).1);
+    assert!(
+        !cache
+            .get(
+                "\\w> ",
+                PromptInputs {
+                    second: 6,
+                    ..inputs
+                }
+            )
+            .1
+    );
+    assert_eq!(cache.renders(), 1);
+
+    // A command finishing with another status, the directory or the template changing
+    assert!(
+        cache
+            .get(
+                "\\w> ",
+                PromptInputs {
+                    status: 1,
+                    ..inputs
+                }
+            )
+            .1
+    );
+    assert!(
+        cache
+            .get(
+                "\\w> ",
+                PromptInputs {
+                    dir: "/etc",
+                    ..inputs
+                }
+            )
+            .1
+    );
+    assert!(cache.get("\\j> ", inputs).1);
+    assert!(cache.get("\\j> ", PromptInputs { jobs: 1, ..inputs }).1);
+    assert_eq!(cache.renders(), 5);
+
+    // The time is shown again once a second
+    assert!(cache.get("\\t> ", inputs).1);
+    assert!(!cache.get("\\t> ", inputs).1);
+    assert!(
+        cache
+            .get(
+                "\\t> ",
+                PromptInputs {
+                    second: 6,
+                    ..inputs
+                }
+            )
+            .1
+    );
+    assert_eq!(cache.renders(), 7);
+    assert_eq!(cache.prompt().width, 10);
+}
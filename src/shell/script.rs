@@ -0,0 +1,99 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{
+    error::KernelError,
+    fs::{
+        file::{FileSystemError, InodeKind},
+        vfs::VfsRouter,
+    },
+};
+
+use super::ShellError;
+
+/// Run at boot, if it exists, before the first prompt.
+pub const RC_PATH: &str = "/etc/rc";
+/// How deep scripts may run other scripts, which keeps a script running itself from going on
+/// forever.
+pub const MAX_DEPTH: usize = 8;
+/// Makes a script stop at the first command which fails, like `set -e`.
+const STOP_ON_ERROR: &str = "@stop-on-error";
+
+/// The commands of a script, one per line, with their line numbers.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Script {
+    pub lines: Vec<(usize, String)>,
+    pub stop_on_error: bool,
+}
+
+/// Parses a script. Blank lines and lines starting with `#` are skipped, and directives starting
+/// with `@` have to come before the first command. Fails with the number of the line which isn't
+/// valid.
+pub fn parse(text: &str) -> Result<Script, (usize, ShellError)> {
+    let mut script = Script::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = match line {
+            STOP_ON_ERROR if script.lines.is_empty() => {
+                script.stop_on_error = true;
+                continue;
+            }
+            STOP_ON_ERROR => ShellError::LateDirective(line.to_string()),
+            _ if line.starts_with('@') => ShellError::UnknownDirective(line.to_string()),
+            _ => {
+                script.lines.push((i + 1, line.to_string()));
+                continue;
+            }
+        };
+        return Err((i + 1, error));
+    }
+    Ok(script)
+}
+
+/// Reads and parses the script at `path`. Errors in it are prefixed by the path and the line.
+pub fn load(vfs: &VfsRouter, path: &str) -> Result<Script, KernelError> {
+    let metadata = vfs.stat(path)?;
+    if metadata.kind == InodeKind::Directory {
+        return Err(FileSystemError::IsADirectory(path.to_string()).into());
+    }
+    let mut data = alloc::vec![0; metadata.size];
+    let mut file = vfs.open(path)?;
+    let mut read = 0;
+    while read < data.len() {
+        match vfs.read(&mut file, &mut data[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    parse(&String::from_utf8_lossy(&data[..read]))
+        .map_err(|(line, err)| KernelError::from(err).context(format!("{}:{}", path, line)))
+}
+
+#[test_case]
+fn test_parse_script() {
+    let script = parse("@stop-on-error\n# comment\n\n  echo one \necho two && echo three\n");
+    assert_eq!(
+        script.unwrap(),
+        Script {
+            lines: alloc::vec![
+                (4, String::from("echo one")),
+                (5, String::from("echo two && echo three")),
+            ],
+            stop_on_error: true,
+        }
+    );
+    assert!(matches!(
+        parse("echo one\n@stop-on-error\n"),
+        Err((2, ShellError::LateDirective(_)))
+    ));
+    assert!(matches!(
+        parse("@stop-on-warning\n"),
+        Err((1, ShellError::UnknownDirective(_)))
+    ));
+}
@@ -0,0 +1,67 @@
+use alloc::{format, string::String};
+
+/// A reverse incremental search through the command history, started with Ctrl+R. The newest
+/// entry containing the pattern is shown, and [`older`](Self::older) moves on to older ones.
+#[derive(Debug, Default)]
+pub struct HistorySearch {
+    pattern: String,
+    /// The index of the history entry shown, `None` until something has matched.
+    found: Option<usize>,
+    /// Whether the last change found nothing, in which case the previous match is still shown.
+    failed: bool,
+}
+
+impl HistorySearch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a character to the pattern. The entry shown is kept if it still matches.
+    pub fn push(&mut self, c: char, history: &[String]) {
+        self.pattern.push(c);
+        let before = self.found.map_or(history.len(), |i| i + 1);
+        self.search(history, before, None);
+    }
+
+    /// Removes the last character of the pattern, and searches again from the newest entry.
+    pub fn pop(&mut self, history: &[String]) {
+        self.pattern.pop();
+        self.found = None;
+        self.failed = false;
+        if !self.pattern.is_empty() {
+            self.search(history, history.len(), None);
+        }
+    }
+
+    /// Moves on to the next older entry which matches. Entries with the same text as the one
+    /// shown are skipped, so a command run many times is only shown once.
+    pub fn older(&mut self, history: &[String]) {
+        if self.pattern.is_empty() {
+            return;
+        }
+        let shown = self.found.map(|i| history[i].as_str());
+        self.search(history, self.found.unwrap_or(history.len()), shown);
+    }
+
+    /// Returns the entry shown, if any.
+    pub fn matched<'a>(&self, history: &'a [String]) -> Option<&'a str> {
+        self.found.map(|i| history[i].as_str())
+    }
+
+    /// Returns what's shown in place of the prompt, like ``(reverse-i-search)`pat': ``.
+    pub fn prompt(&self) -> String {
+        let failed = if self.failed { "failed " } else { "" };
+        format!("({}reverse-i-search)`{}': ", failed, self.pattern)
+    }
+
+    /// Finds the newest entry before `before` containing the pattern, other than `skip`.
+    fn search(&mut self, history: &[String], before: usize, skip: Option<&str>) {
+        let found = history[..before]
+            .iter()
+            .rposition(|entry| entry.contains(&*self.pattern) && Some(entry.as_str()) != skip);
+        self.failed = found.is_none();
+        if found.is_some() {
+            self.found = found;
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use alloc::vec::Vec;
+
+use crate::{
+    serial, task, ui,
+    vgabuf::{self, AnsiParser, VGAColor},
+};
+
+/// The screen row the VGA terminal draws the input line on. Output scrolls in the rows above it.
+pub const INPUT_ROW: usize = vgabuf::HEIGHT - 1;
+
+/// Size assumed for the serial console, which has no way to report its size.
+const SERIAL_WIDTH: usize = 80;
+const SERIAL_HEIGHT: usize = 24;
+
+/// Where a shell draws its prompt and writes the output of commands.
+///
+/// The line the cursor is on is the input line. Writing a newline finishes the input line, so
+/// that it becomes part of the output, and starts a new empty input line.
+pub trait Terminal {
+    /// Writes text at the cursor.
+    fn write_str(&mut self, s: &str);
+    /// Clears the input line and moves the cursor to its start.
+    fn clear_line(&mut self);
+    /// Moves the cursor to column `col` of the input line.
+    fn move_cursor(&mut self, col: usize);
+    /// Returns the number of columns of the terminal.
+    fn width(&self) -> usize;
+    /// Returns the number of rows output is shown in above the input line, or `None` if output
+    /// should never be paged.
+    fn height(&self) -> Option<usize>;
+    /// Returns `true` if the terminal shows typed keys itself, in which case the shell only draws
+    /// the prompt and leaves the input line to it.
+    fn local_echo(&self) -> bool;
+    /// Returns `true` if output may be colored with ANSI escape sequences.
+    fn colors(&self) -> bool;
+    fn set_colors(&mut self, enabled: bool);
+}
+
+/// A terminal on the VGA screen. The bottom row is reserved for the input line until the
+/// terminal is dropped, so that output printed while typing doesn't garble it.
+pub struct VgaTerminal {
+    // The characters of the input line and their colors
+    line: Vec<(char, VGAColor)>,
+    cursor: usize,
+    ansi: AnsiParser,
+    colors: bool,
+}
+
+impl VgaTerminal {
+    pub fn new() -> Self {
+        let (top, _) = vgabuf::scroll_region();
+        vgabuf::set_scroll_region(top, INPUT_ROW - 1);
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            ansi: AnsiParser::new(),
+            // Follows the `color` setting loaded at boot
+            colors: ui::console_colors(),
+        }
+    }
+
+    fn draw(&self) {
+        // Every character takes up one cell, so keep the cursor in view by skipping characters
+        let skip = (self.cursor + 1).saturating_sub(vgabuf::columns());
+        vgabuf::write_row_colored(INPUT_ROW, self.line.get(skip..).unwrap_or_default());
+    }
+}
+
+impl Default for VgaTerminal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Terminal for VgaTerminal {
+    fn write_str(&mut self, s: &str) {
+        for c in s.chars() {
+            // Escape sequences only change the color of the characters after them
+            let Some(c) = self.ansi.feed(c) else {
+                continue;
+            };
+            if c == '\n' {
+                let len = self
+                    .line
+                    .iter()
+                    .rposition(|&(c, _)| c != ' ')
+                    .map_or(0, |last| last + 1);
+                vgabuf::write_colored(&self.line[..len]);
+                vgabuf::write_str("\n");
+                self.line.clear();
+                self.cursor = 0;
+                continue;
+            }
+            // Overwrite the character under the cursor, padding the line if it's past the end
+            let cell = (c, self.ansi.color());
+            if self.cursor < self.line.len() {
+                self.line[self.cursor] = cell;
+            } else {
+                self.line.resize(self.cursor, (' ', vgabuf::DEFAULT_COLOR));
+                self.line.push(cell);
+            }
+            self.cursor += 1;
+        }
+        self.draw();
+    }
+
+    fn clear_line(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.draw();
+    }
+
+    fn move_cursor(&mut self, col: usize) {
+        self.cursor = col;
+        self.draw();
+    }
+
+    fn width(&self) -> usize {
+        vgabuf::columns()
+    }
+
+    fn height(&self) -> Option<usize> {
+        let (top, bottom) = vgabuf::scroll_region();
+        Some(bottom + 1 - top)
+    }
+
+    fn local_echo(&self) -> bool {
+        false
+    }
+
+    fn colors(&self) -> bool {
+        self.colors
+    }
+
+    fn set_colors(&mut self, enabled: bool) {
+        self.colors = enabled;
+    }
+}
+
+impl Drop for VgaTerminal {
+    fn drop(&mut self) {
+        vgabuf::write_row(INPUT_ROW, "");
+        let (top, _) = vgabuf::scroll_region();
+        vgabuf::set_scroll_region(top, vgabuf::HEIGHT - 1);
+    }
+}
+
+/// A terminal on the serial port, controlled with ANSI escape sequences. Colors can be turned off
+/// for terminals which don't support them.
+pub struct SerialTerminal {
+    colors: bool,
+}
+
+impl SerialTerminal {
+    pub fn new() -> Self {
+        Self {
+            colors: ui::console_colors(),
+        }
+    }
+}
+
+impl Default for SerialTerminal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Terminal for SerialTerminal {
+    fn write_str(&mut self, s: &str) {
+        serial::_print(format_args!("{}", s.replace('\n', "\r\n")));
+    }
+
+    fn clear_line(&mut self) {
+        serial::_print(format_args!("\r\x1b[2K"));
+    }
+
+    fn move_cursor(&mut self, col: usize) {
+        match col {
+            0 => serial::_print(format_args!("\r")),
+            col => serial::_print(format_args!("\r\x1b[{}C", col)),
+        }
+    }
+
+    fn width(&self) -> usize {
+        SERIAL_WIDTH
+    }
+
+    fn height(&self) -> Option<usize> {
+        Some(SERIAL_HEIGHT - 1)
+    }
+
+    fn local_echo(&self) -> bool {
+        !task::serial::echo()
+    }
+
+    fn colors(&self) -> bool {
+        self.colors
+    }
+
+    fn set_colors(&mut self, enabled: bool) {
+        self.colors = enabled;
+    }
+}
+
+/// A call made to a [`MockTerminal`].
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TerminalCall {
+    Write(alloc::string::String),
+    ClearLine,
+    MoveCursor(usize),
+}
+
+/// Records the calls made to it and keeps track of what the input line would show, for testing
+/// what a shell draws.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockTerminal {
+    pub calls: alloc::vec::Vec<TerminalCall>,
+    /// Colors are off unless a test turns them on.
+    pub colors: bool,
+    /// Output isn't paged unless a test sets a height.
+    pub height: Option<usize>,
+    pub local_echo: bool,
+    line: alloc::vec::Vec<char>,
+    cursor: usize,
+}
+
+#[cfg(test)]
+impl MockTerminal {
+    /// Returns the text on the input line.
+    pub fn line(&self) -> alloc::string::String {
+        self.line.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Returns the calls made since the last time this was called.
+    pub fn take_calls(&mut self) -> alloc::vec::Vec<TerminalCall> {
+        core::mem::take(&mut self.calls)
+    }
+}
+
+#[cfg(test)]
+impl Terminal for MockTerminal {
+    fn write_str(&mut self, s: &str) {
+        self.calls.push(TerminalCall::Write(s.into()));
+        for c in s.chars() {
+            if c == '\n' {
+                self.line.clear();
+                self.cursor = 0;
+                continue;
+            }
+            match self.line.get_mut(self.cursor) {
+                Some(cell) => *cell = c,
+                None => self.line.push(c),
+            }
+            self.cursor += 1;
+        }
+    }
+
+    fn clear_line(&mut self) {
+        self.calls.push(TerminalCall::ClearLine);
+        self.line.clear();
+        self.cursor = 0;
+    }
+
+    fn move_cursor(&mut self, col: usize) {
+        self.calls.push(TerminalCall::MoveCursor(col));
+        self.cursor = col;
+    }
+
+    fn width(&self) -> usize {
+        SERIAL_WIDTH
+    }
+
+    fn height(&self) -> Option<usize> {
+        self.height
+    }
+
+    fn local_echo(&self) -> bool {
+        self.local_echo
+    }
+
+    fn colors(&self) -> bool {
+        self.colors
+    }
+
+    fn set_colors(&mut self, enabled: bool) {
+        self.colors = enabled;
+    }
+}
@@ -0,0 +1,433 @@
+use alloc::{string::String, vec::Vec};
+
+use super::{
+    jobs::{Fd, JobFiles, OpenMode},
+    CommandOutput, ShellError,
+};
+use crate::{
+    error::KernelError,
+    fs::{disk::BLOCK_SIZE, VFS},
+};
+
+/// The longest line `grep` handles. Longer lines are an error rather than being truncated.
+pub const MAX_LINE_LEN: usize = 512;
+
+/// Where a text command reads from, a block at a time. Files are opened in the job's file table,
+/// and closed when the source is dropped.
+enum Source<'a> {
+    File(Fd, &'a JobFiles),
+    Piped(&'a [u8]),
+}
+
+impl<'a> Source<'a> {
+    /// Reads from the file at `path`, or from the piped input if no path is given.
+    fn open(
+        path: Option<&str>,
+        input: Option<&'a str>,
+        files: &'a JobFiles,
+    ) -> Result<Self, KernelError> {
+        match (path, input) {
+            (Some(path), _) => {
+                let fd = files.open(&VFS.lock(), path, OpenMode::Read)?;
+                Ok(Self::File(fd, files))
+            }
+            (None, Some(input)) => Ok(Self::Piped(input.as_bytes())),
+            (None, None) => {
+                Err(ShellError::Usage("a path is required unless input is piped").into())
+            }
+        }
+    }
+
+    /// Reads the next block of data into `buf`, returning 0 at the end of the input.
+    fn read(&mut self, buf: &mut [u8; BLOCK_SIZE]) -> Result<usize, KernelError> {
+        match self {
+            Self::File(fd, files) => files.read(*fd, &VFS.lock(), buf),
+            Self::Piped(input) => {
+                let len = input.len().min(BLOCK_SIZE);
+                buf[..len].copy_from_slice(&input[..len]);
+                *input = &input[len..];
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl Drop for Source<'_> {
+    fn drop(&mut self) {
+        if let Self::File(fd, files) = self {
+            files.close(*fd);
+        }
+    }
+}
+
+/// Splits a source into lines, holding at most one block and one line in memory.
+struct Lines<'a> {
+    source: Source<'a>,
+    pending: Vec<u8>,
+    line_number: usize,
+    eof: bool,
+}
+
+impl<'a> Lines<'a> {
+    fn new(source: Source<'a>) -> Self {
+        Self {
+            source,
+            pending: Vec::new(),
+            line_number: 0,
+            eof: false,
+        }
+    }
+
+    /// Returns the next line without its newline.
+    fn next_line(&mut self) -> Result<Option<String>, KernelError> {
+        loop {
+            let end = self.pending.iter().position(|&b| b == b'\n');
+            if end.unwrap_or(self.pending.len()) > MAX_LINE_LEN {
+                return Err(ShellError::LineTooLong(self.line_number + 1).into());
+            }
+            let line_len = match end {
+                Some(end) => end,
+                None if self.eof && !self.pending.is_empty() => self.pending.len(),
+                None if self.eof => return Ok(None),
+                None => {
+                    let mut block = [0; BLOCK_SIZE];
+                    let read = self.source.read(&mut block)?;
+                    self.eof = read == 0;
+                    self.pending.extend_from_slice(&block[..read]);
+                    continue;
+                }
+            };
+
+            let line = String::from_utf8_lossy(&self.pending[..line_len]).into_owned();
+            self.pending.drain(..(line_len + 1).min(self.pending.len()));
+            self.line_number += 1;
+            return Ok(Some(line));
+        }
+    }
+}
+
+/// `grep [-n] <pattern> [path]`: prints the lines containing `pattern`, prefixed by their line
+/// number with `-n`.
+pub fn grep(
+    args: &[&str],
+    input: Option<&str>,
+    out: &mut CommandOutput,
+    files: &JobFiles,
+) -> Result<(), KernelError> {
+    let (numbered, args) = match args {
+        ["-n", args @ ..] => (true, args),
+        args => (false, args),
+    };
+    let (pattern, path) = match args {
+        [pattern] => (pattern, None),
+        [pattern, path] => (pattern, Some(*path)),
+        _ => return Err(ShellError::Usage("grep [-n] <pattern> [path]").into()),
+    };
+
+    let mut lines = Lines::new(Source::open(path, input, files)?);
+    while let Some(line) = lines.next_line()? {
+        if !line.contains(pattern) {
+            continue;
+        }
+        if numbered {
+            writeln!(out, "{}:{}", lines.line_number, line);
+        } else {
+            writeln!(out, "{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// `wc [path]`: prints the number of lines, words and bytes.
+pub fn wc(
+    args: &[&str],
+    input: Option<&str>,
+    out: &mut CommandOutput,
+    files: &JobFiles,
+) -> Result<(), KernelError> {
+    let path = match args {
+        [] => None,
+        [path] => Some(*path),
+        _ => return Err(ShellError::Usage("wc [path]").into()),
+    };
+
+    let mut source = Source::open(path, input, files)?;
+    let (mut lines, mut words, mut bytes) = (0, 0, 0);
+    // Words can span blocks
+    let mut in_word = false;
+    let mut block = [0; BLOCK_SIZE];
+    loop {
+        let read = source.read(&mut block)?;
+        if read == 0 {
+            break;
+        }
+        for &byte in &block[..read] {
+            if byte == b'\n' {
+                lines += 1;
+            }
+            if byte.is_ascii_whitespace() {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                words += 1;
+            }
+        }
+        bytes += read;
+    }
+
+    match path {
+        Some(path) => writeln!(out, "{} {} {} {}", lines, words, bytes, path),
+        None => writeln!(out, "{} {} {}", lines, words, bytes),
+    }
+    Ok(())
+}
+
+/// `cat [path]`: prints the contents of the file, or the piped input.
+pub fn cat(
+    args: &[&str],
+    input: Option<&str>,
+    out: &mut CommandOutput,
+    files: &JobFiles,
+) -> Result<(), KernelError> {
+    let path = match args {
+        [] => None,
+        [path] => Some(*path),
+        _ => return Err(ShellError::Usage("cat [path]").into()),
+    };
+
+    let mut source = Source::open(path, input, files)?;
+    let mut block = [0; BLOCK_SIZE];
+    loop {
+        let read = source.read(&mut block)?;
+        if read == 0 {
+            break;
+        }
+        write!(out, "{}", String::from_utf8_lossy(&block[..read]));
+    }
+    Ok(())
+}
+
+/// `less [path]`: the same as `cat`, as the output of the last command of a pipeline is paged
+/// if it doesn't fit on the screen.
+pub fn less(
+    args: &[&str],
+    input: Option<&str>,
+    out: &mut CommandOutput,
+    files: &JobFiles,
+) -> Result<(), KernelError> {
+    if args.len() > 1 {
+        return Err(ShellError::Usage("less [path]").into());
+    }
+    cat(args, input, out, files)
+}
+
+/// `echo [-n] [-e] [args...]`: prints the arguments separated by spaces. `-n` leaves out the
+/// newline at the end and `-e` interprets backslash escapes like `\t` in the arguments. Flags can
+/// be combined like `-ne`, and flag parsing stops at `--` or the first argument which isn't one.
+pub fn echo(args: &[&str], out: &mut CommandOutput) {
+    let (mut newline, mut escapes) = (true, false);
+    let mut args = args;
+    while let Some((&arg, rest)) = args.split_first() {
+        let Some(flags) = arg.strip_prefix('-') else {
+            break;
+        };
+        if flags == "-" {
+            args = rest;
+            break;
+        }
+        if flags.is_empty() || !flags.chars().all(|flag| matches!(flag, 'n' | 'e')) {
+            break;
+        }
+        newline &= !flags.contains('n');
+        escapes |= flags.contains('e');
+        args = rest;
+    }
+
+    let text = args.join(" ");
+    let text = match escapes {
+        true => unescape(&text),
+        false => text,
+    };
+    match newline {
+        true => writeln!(out, "{}", text),
+        false => write!(out, "{}", text),
+    }
+}
+
+/// Replaces the backslash escapes `\\`, `\n`, `\r`, `\t`, `\0nnn` with up to three octal digits
+/// and `\xHH` with up to two hex digits by the characters they stand for. Any other backslash is
+/// kept as it is, along with the character after it.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        let (radix, max_digits) = match chars.peek() {
+            Some('0') => (8, 3),
+            Some('x') => (16, 2),
+            _ => (0, 0),
+        };
+        if radix != 0 {
+            let prefix = chars.next().unwrap();
+            let mut value = 0u32;
+            let mut digits = 0;
+            while digits < max_digits {
+                let Some(digit) = chars.peek().and_then(|c| c.to_digit(radix)) else {
+                    break;
+                };
+                if value * radix + digit > 0xff {
+                    break;
+                }
+                value = value * radix + digit;
+                digits += 1;
+                chars.next();
+            }
+            match (prefix, digits) {
+                ('x', 0) => unescaped.push_str("\\x"),
+                _ => unescaped.push(char::from(value as u8)),
+            }
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+fn create_file(path: &str, contents: &[u8]) {
+    use crate::fs::{file::InodeKind, FILESYSTEM};
+
+    let mut fs = FILESYSTEM.lock();
+    let inumber = fs.create_at(path, InodeKind::File).unwrap();
+    fs.write(inumber, 0, contents).unwrap();
+}
+
+#[cfg(test)]
+type TextCommand =
+    fn(&[&str], Option<&str>, &mut CommandOutput, &JobFiles) -> Result<(), KernelError>;
+
+#[cfg(test)]
+fn captured(command: TextCommand, args: &[&str], input: Option<&str>) -> String {
+    let mut out = CommandOutput::Captured(String::new());
+    command(args, input, &mut out, &JobFiles::new("test")).unwrap();
+    out.into_captured().unwrap()
+}
+
+#[test_case]
+fn test_grep_file() {
+    crate::fs::init().unwrap();
+    create_file("notes", b"apple pie\nbanana\ncherry\napple juice");
+
+    assert_eq!(
+        captured(grep, &["-n", "apple", "notes"], None),
+        "1:apple pie\n4:apple juice\n"
+    );
+    assert_eq!(captured(grep, &["an", "notes"], None), "banana\n");
+    assert_eq!(captured(grep, &["durian", "notes"], None), "");
+}
+
+#[test_case]
+fn test_grep_lines_spanning_blocks() {
+    crate::fs::init().unwrap();
+    let mut contents = alloc::vec![b'.'; BLOCK_SIZE - 3];
+    contents.extend_from_slice(b"\nneedle in the next block\n");
+    create_file("big", &contents);
+
+    assert_eq!(
+        captured(grep, &["-n", "needle", "big"], None),
+        "2:needle in the next block\n"
+    );
+    assert!(matches!(
+        grep(
+            &["x", "big"],
+            None,
+            &mut CommandOutput::Captured(String::new()),
+            &JobFiles::new("test")
+        ),
+        Err(KernelError::Shell(ShellError::LineTooLong(1)))
+    ));
+}
+
+#[test_case]
+fn test_grep_piped() {
+    assert_eq!(captured(grep, &["b"], Some("a\nb\nab")), "b\nab\n");
+    assert!(matches!(
+        grep(
+            &["b"],
+            None,
+            &mut CommandOutput::Captured(String::new()),
+            &JobFiles::new("test")
+        ),
+        Err(KernelError::Shell(ShellError::Usage(_)))
+    ));
+}
+
+#[test_case]
+fn test_wc() {
+    crate::fs::init().unwrap();
+    create_file("text", b"hello  world\nfoo");
+
+    assert_eq!(captured(wc, &["text"], None), "1 3 16 text\n");
+    assert_eq!(captured(wc, &[], Some("one two\nthree\n")), "2 3 14\n");
+    assert_eq!(captured(wc, &[], Some("")), "0 0 0\n");
+}
+
+#[test_case]
+fn test_cat() {
+    crate::fs::init().unwrap();
+    create_file("notes", b"apple pie\n");
+
+    assert_eq!(captured(cat, &["notes"], None), "apple pie\n");
+    assert_eq!(captured(cat, &[], Some("piped")), "piped");
+    let meminfo = captured(cat, &["/proc/meminfo"], None);
+    assert!(meminfo.starts_with("heap_total: "));
+}
+
+#[test_case]
+fn test_echo() {
+    let echo = |args: &[&str]| {
+        let mut out = CommandOutput::Captured(String::new());
+        echo(args, &mut out);
+        out.into_captured().unwrap()
+    };
+    assert_eq!(echo(&[]), "\n");
+    assert_eq!(echo(&["a", "b"]), "a b\n");
+    assert_eq!(echo(&["-n", "a", "b"]), "a b");
+    assert_eq!(echo(&["a\\tb"]), "a\\tb\n");
+    assert_eq!(echo(&["-e", "a\\tb"]), "a\tb\n");
+    for flags in [&["-n", "-e"][..], &["-e", "-n"], &["-ne"], &["-en"]] {
+        assert_eq!(echo(&[flags, &["a\\nb"]].concat()), "a\nb");
+    }
+    // Flag parsing stops at `--` and at anything which isn't a flag
+    assert_eq!(echo(&["--", "-n"]), "-n\n");
+    assert_eq!(echo(&["-n", "--", "-e", "x"]), "-e x");
+    assert_eq!(echo(&["-x", "-n"]), "-x -n\n");
+    assert_eq!(echo(&["-", "a"]), "- a\n");
+    assert_eq!(echo(&["a", "-n"]), "a -n\n");
+}
+
+#[test_case]
+fn test_unescape() {
+    assert_eq!(unescape("a\\\\b\\r\\n"), "a\\b\r\n");
+    assert_eq!(unescape("\\x41\\x4a\\x7"), "AJ\x07");
+    assert_eq!(unescape("\\0101\\0\\07x"), "A\0\x07x");
+    // Octal escapes stop before a value which doesn't fit in a byte
+    assert_eq!(unescape("\\0477"), "\x277");
+    // Invalid escapes are kept, including a backslash at the end
+    assert_eq!(unescape("\\q\\xg\\"), "\\q\\xg\\");
+    assert_eq!(unescape("end\\x"), "end\\x");
+    assert_eq!(unescape("end\\0"), "end\0");
+}
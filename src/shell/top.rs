@@ -0,0 +1,161 @@
+use alloc::{format, string::String, vec, vec::Vec};
+use futures_util::StreamExt as _;
+use pc_keyboard::DecodedKey;
+
+use super::{
+    input::{LineDiscipline, PartialLine, TerminalMode},
+    ShellError,
+};
+use crate::{
+    error::KernelError,
+    task::{
+        cancel::CancellationToken,
+        executor::{CpuAccounting, CpuUsage},
+        keyboard::{self, KeyPress},
+        select2, Either,
+    },
+    timer,
+    vgabuf::{self, ScreenSession},
+};
+
+/// Time between refreshes of the task list.
+const REFRESH_MS: u64 = 1000;
+
+/// Shows the tasks using the most CPU time on the whole screen, refreshed every second, until `q`
+/// or Ctrl+C is pressed. Takes the keyboard focus and switches the terminal to raw mode meanwhile,
+/// and puts both back afterwards along with the screen.
+pub async fn top(
+    args: &[&str],
+    discipline: &LineDiscipline,
+    cancel: &CancellationToken,
+) -> Result<(), KernelError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("top").into());
+    }
+    let accounting = CpuAccounting::start();
+    let mut keys = keyboard::subscribe();
+    let _focus = keys.acquire_focus();
+    let _raw = discipline.enter(TerminalMode::Raw, PartialLine::Discard);
+    let _screen = ScreenSession::start();
+    let (first_row, last_row) = vgabuf::scroll_region();
+    let rows = last_row - first_row + 1;
+
+    draw(first_row, rows, &[String::from("sampling...")]);
+    let mut previous = accounting.sample();
+    loop {
+        let quit = async {
+            while let Some(KeyPress { key, modifiers }) = keys.next().await {
+                match key {
+                    DecodedKey::Unicode('q' | 'Q') => break,
+                    DecodedKey::Unicode('c' | 'C') if modifiers.ctrl => break,
+                    _ => {}
+                }
+            }
+        };
+        let refresh = timer::sleep(REFRESH_MS);
+        if let Either::Right(_) = select2(refresh, select2(quit, cancel.cancelled())).await {
+            return Ok(());
+        }
+        let sample = accounting.sample();
+        draw(
+            first_row,
+            rows,
+            &render(&sample.usage_since(&previous), rows),
+        );
+        previous = sample;
+    }
+}
+
+/// Replaces `rows` rows of the screen starting at `first_row` with the lines.
+fn draw(first_row: usize, rows: usize, lines: &[String]) {
+    for row in 0..rows {
+        vgabuf::write_row(first_row + row, lines.get(row).map_or("", String::as_str));
+    }
+}
+
+/// Returns the lines shown by `top`, at most `rows` of them: a summary, the column headers, the
+/// time the executors spent halted and then the tasks, busiest first. The share of the CPU is
+/// the time used since the last refresh, the time and polls are totals since accounting started.
+fn render(usage: &CpuUsage, rows: usize) -> Vec<String> {
+    let percent = |cycles| {
+        let per_mille = usage.per_mille(cycles);
+        format!("{}.{}", per_mille / 10, per_mille % 10)
+    };
+    let millis = |cycles| match usage.cycles_to_millis(cycles) {
+        Some(ms) => format!("{}.{:02}s", ms / 1000, ms % 1000 / 10),
+        None => String::from("-"),
+    };
+    let busy = usage.tasks.iter().map(|task| task.cycles).sum::<u64>();
+    let mut lines = vec![
+        format!(
+            "{} tasks, {}% busy, q to quit",
+            usage.tasks.len(),
+            percent(busy)
+        ),
+        format!(
+            "{:>6} {:>6} {:>9} {:>9}  {}",
+            "id", "cpu%", "time", "polls", "name"
+        ),
+        format!(
+            "{:>6} {:>6} {:>9} {:>9}  {}",
+            "-",
+            percent(usage.idle),
+            "",
+            "",
+            "(idle)"
+        ),
+    ];
+    for task in &usage.tasks {
+        lines.push(format!(
+            "{:>6} {:>6} {:>9} {:>9}  {}",
+            task.info.id.0,
+            percent(task.cycles),
+            millis(task.info.cycles),
+            task.info.polls,
+            task.info.name
+        ));
+    }
+    lines.truncate(rows);
+    lines
+}
+
+#[test_case]
+fn test_top_lines() {
+    use crate::task::{
+        executor::{TaskInfo, TaskUsage},
+        Priority, TaskId, TaskName,
+    };
+
+    let task = |id, name, cycles, total| TaskUsage {
+        info: TaskInfo {
+            id: TaskId(id),
+            priority: Priority::Normal,
+            name: TaskName(name),
+            polls: 7,
+            cycles: total,
+        },
+        cycles,
+        polls: 3,
+    };
+    let usage = CpuUsage {
+        elapsed: 1000,
+        ticks: timer::millis_to_ticks(1000),
+        idle: 250,
+        tasks: vec![
+            task(4, "hannos::shell::top::{{closure}}", 700, 2500),
+            task(2, "statusbar::run", 45, 45),
+        ],
+    };
+    let lines = render(&usage, 25);
+    assert_eq!(
+        lines,
+        [
+            "2 tasks, 74.5% busy, q to quit",
+            "    id   cpu%      time     polls  name",
+            "     -   25.0                      (idle)",
+            "     4   70.0     2.50s         7  shell::top",
+            "     2    4.5     0.04s         7  statusbar::run",
+        ]
+    );
+    assert_eq!(render(&usage, 3).len(), 3);
+}
@@ -0,0 +1,486 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::ShellError;
+
+/// Holds the exit status of the last command, `0` if it succeeded and `1` if it failed.
+pub const STATUS: &str = "?";
+
+/// Makes a glob pattern matching nothing an error, instead of leaving it as it is.
+pub const FAILGLOB: &str = "failglob";
+/// The options of a shell, turned on with `set -o <option>` and off with `set +o <option>`.
+pub const OPTIONS: [&str; 1] = [FAILGLOB];
+
+/// How a part of a word was quoted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quote {
+    None,
+    /// Single quotes, nothing inside is expanded.
+    Single,
+    /// Double quotes, variables inside are expanded.
+    Double,
+}
+
+/// How the output of a command is written to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redirect {
+    /// `>`, the file is emptied first.
+    Truncate,
+    /// `>>`, the output is added to the end of the file.
+    Append,
+}
+
+/// How a pipeline is chained to the one before it on a command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chain {
+    /// `&&`, the pipeline runs if the one before succeeded.
+    And,
+    /// `||`, the pipeline runs if the one before failed.
+    Or,
+}
+
+impl Chain {
+    pub fn operator(self) -> &'static str {
+        match self {
+            Self::And => "&&",
+            Self::Or => "||",
+        }
+    }
+}
+
+/// A word of a command line, made of parts which were quoted differently, like `a'b'"c"`. An
+/// unquoted `>` or `>>` is a word of its own, with no parts.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Word {
+    parts: Vec<(Quote, String)>,
+    redirect: Option<Redirect>,
+}
+
+/// A command of a pipeline, with the variables in its words expanded.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stage {
+    pub words: Vec<String>,
+    /// The file the output is written to instead of the terminal or the next command.
+    pub output: Option<(Redirect, String)>,
+}
+
+impl Word {
+    /// Joins the parts of the word, expanding the variables in the parts which aren't single
+    /// quoted.
+    pub fn expand(&self, vars: &Variables) -> Result<String, ShellError> {
+        let mut word = String::new();
+        for (quote, text) in &self.parts {
+            match quote {
+                Quote::Single => word.push_str(text),
+                Quote::None | Quote::Double => word.push_str(&vars.expand(text)?),
+            }
+        }
+        Ok(word)
+    }
+
+    /// Whether the word is a glob pattern, which has a `*` or `?` and isn't quoted at all.
+    fn is_glob(&self) -> bool {
+        self.parts.iter().all(|(quote, _)| *quote == Quote::None)
+            && self.parts.iter().any(|(_, text)| text.contains(['*', '?']))
+    }
+}
+
+/// Splits a command line at the `&&` and `||` outside of quotes into pipelines, each with how it's
+/// chained to the one before it. The first pipeline isn't chained. Quotes are left for [`split`].
+pub fn split_list(line: &str) -> Result<Vec<(Option<Chain>, &str)>, ShellError> {
+    let mut list = Vec::new();
+    let (mut chain, mut start, mut quote) = (None, 0, None);
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some(end), c) if c == end => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '&' | '|') if chars.next_if(|&(_, next)| next == c).is_some() => {
+                let next = if c == '&' { Chain::And } else { Chain::Or };
+                let pipeline = line[start..i].trim();
+                if pipeline.is_empty() {
+                    return Err(ShellError::MissingCommand(next.operator()));
+                }
+                list.push((chain, pipeline));
+                chain = Some(next);
+                start = i + 2;
+            }
+            _ => {}
+        }
+    }
+    let pipeline = line[start..].trim();
+    if let (Some(chain), true) = (chain, pipeline.is_empty()) {
+        return Err(ShellError::MissingCommand(chain.operator()));
+    }
+    list.push((chain, pipeline));
+    Ok(list)
+}
+
+/// Splits a command line into the stages of a pipeline, and each stage into words. Words are
+/// separated by whitespace and stages by `|`, except inside single or double quotes. The quotes
+/// are removed, variables are expanded later with [`expand_stage`].
+pub fn split(line: &str) -> Result<Vec<Vec<Word>>, ShellError> {
+    let mut stages = Vec::new();
+    let mut words = Vec::new();
+    let mut word: Option<Word> = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                let (quote, end) = match c {
+                    '\'' => (Quote::Single, '\''),
+                    _ => (Quote::Double, '"'),
+                };
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some(c) if c == end => break,
+                        Some(c) => text.push(c),
+                        None => return Err(ShellError::UnterminatedQuote(end)),
+                    }
+                }
+                word.get_or_insert_with(Word::default)
+                    .parts
+                    .push((quote, text));
+            }
+            '|' => {
+                words.extend(word.take());
+                stages.push(core::mem::take(&mut words));
+            }
+            '>' => {
+                words.extend(word.take());
+                let redirect = match chars.next_if_eq(&'>') {
+                    Some(_) => Redirect::Append,
+                    None => Redirect::Truncate,
+                };
+                words.push(Word {
+                    parts: Vec::new(),
+                    redirect: Some(redirect),
+                });
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => {
+                let parts = &mut word.get_or_insert_with(Word::default).parts;
+                match parts.last_mut() {
+                    Some((Quote::None, text)) => text.push(c),
+                    _ => parts.push((Quote::None, c.to_string())),
+                }
+            }
+        }
+    }
+    words.extend(word);
+    stages.push(words);
+    Ok(stages)
+}
+
+/// Expands the words of a stage and takes out the redirection of its output, which is followed by
+/// the path of the file. If the output is redirected more than once the last redirection is used.
+///
+/// Glob patterns are replaced by the paths `glob` returns for them. A pattern matching nothing is
+/// left as it is, or is an error with the [`FAILGLOB`] option. The path of a redirection is never
+/// a pattern.
+pub fn expand_stage(
+    words: &[Word],
+    vars: &Variables,
+    mut glob: impl FnMut(&str) -> Vec<String>,
+) -> Result<Stage, ShellError> {
+    let mut stage = Stage::default();
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        match word.redirect {
+            Some(redirect) => {
+                let path = words
+                    .next()
+                    .filter(|path| path.redirect.is_none())
+                    .ok_or(ShellError::MissingRedirectPath)?;
+                stage.output = Some((redirect, path.expand(vars)?));
+            }
+            None if word.is_glob() => {
+                let pattern = word.expand(vars)?;
+                let paths = glob(&pattern);
+                if !paths.is_empty() {
+                    stage.words.extend(paths);
+                } else if vars.option(FAILGLOB) {
+                    return Err(ShellError::NoMatch(pattern));
+                } else {
+                    stage.words.push(pattern);
+                }
+            }
+            None => stage.words.push(word.expand(vars)?),
+        }
+    }
+    Ok(stage)
+}
+
+/// The variables of a shell.
+#[derive(Debug, Default)]
+pub struct Variables {
+    vars: BTreeMap<String, String>,
+    /// The [`OPTIONS`] which are on.
+    options: BTreeSet<&'static str>,
+}
+
+impl Variables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value of a variable, or `None` if it isn't set.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    /// Sets a variable. Names are letters, digits and underscores, not starting with a digit.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ShellError> {
+        if !is_valid_name(name) {
+            return Err(ShellError::InvalidVariableName(name.to_string()));
+        }
+        self.vars.insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+
+    /// Removes a variable, returning `false` if it wasn't set.
+    pub fn unset(&mut self, name: &str) -> bool {
+        self.vars.remove(name).is_some()
+    }
+
+    /// Turns one of the [`OPTIONS`] on or off.
+    pub fn set_option(&mut self, name: &str, on: bool) -> Result<(), ShellError> {
+        let option = OPTIONS
+            .into_iter()
+            .find(|&option| option == name)
+            .ok_or_else(|| ShellError::NoSuchOption(name.to_string()))?;
+        match on {
+            true => self.options.insert(option),
+            false => self.options.remove(option),
+        };
+        Ok(())
+    }
+
+    pub fn option(&self, name: &str) -> bool {
+        self.options.contains(name)
+    }
+
+    /// Records whether the last command succeeded, for `$?`.
+    pub fn set_status(&mut self, success: bool) {
+        let status = if success { "0" } else { "1" };
+        self.vars.insert(STATUS.to_string(), status.to_string());
+    }
+
+    /// Returns the variables sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Replaces `$NAME` and `${NAME}` in `text` with the value of the variable, or nothing if it
+    /// isn't set. `$$` is a literal `$`, as is a `$` not followed by a name.
+    fn expand(&self, text: &str) -> Result<String, ShellError> {
+        let mut expanded = String::new();
+        let mut rest = text;
+        while let Some(dollar) = rest.find('$') {
+            expanded.push_str(&rest[..dollar]);
+            let after = &rest[dollar + 1..];
+            if let Some(after) = after.strip_prefix('$') {
+                expanded.push('$');
+                rest = after;
+            } else if let Some(braced) = after.strip_prefix('{') {
+                let name = braced
+                    .find('}')
+                    .map(|end| &braced[..end])
+                    .filter(|&name| name == STATUS || is_valid_name(name))
+                    .ok_or_else(|| ShellError::BadSubstitution(text.to_string()))?;
+                expanded.push_str(self.get(name).unwrap_or_default());
+                rest = &braced[name.len() + 1..];
+            } else {
+                let len = match after.starts_with(STATUS) {
+                    true => STATUS.len(),
+                    false => name_len(after),
+                };
+                match len {
+                    0 => expanded.push('$'),
+                    len => expanded.push_str(self.get(&after[..len]).unwrap_or_default()),
+                }
+                rest = &after[len..];
+            }
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+}
+
+/// Returns the length of the variable name at the start of `text`.
+fn name_len(text: &str) -> usize {
+    if !text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return 0;
+    }
+    text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len())
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name_len(name) == name.len()
+}
+
+#[test_case]
+fn test_split_quotes() {
+    let vars = Variables::new();
+    let expand = |line| {
+        split(line)
+            .unwrap()
+            .iter()
+            .map(|words| {
+                words
+                    .iter()
+                    .map(|word| word.expand(&vars).unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(expand("  echo a   b "), [["echo", "a", "b"]]);
+    assert_eq!(
+        expand("echo 'a | b'\"c  d\"e|wc"),
+        [alloc::vec!["echo", "a | bc  de"], alloc::vec!["wc"]]
+    );
+    assert_eq!(expand("echo '' \"\""), [["echo", "", ""]]);
+    assert!(matches!(
+        split("echo 'a"),
+        Err(ShellError::UnterminatedQuote('\''))
+    ));
+}
+
+#[test_case]
+fn test_split_list() {
+    use Chain::{And, Or};
+
+    assert_eq!(split_list("echo a").unwrap(), [(None, "echo a")]);
+    assert_eq!(
+        split_list("a && b | c||d").unwrap(),
+        [(None, "a"), (Some(And), "b | c"), (Some(Or), "d")]
+    );
+    // Inside quotes and on their own, `&` and `|` are left alone
+    assert_eq!(
+        split_list("echo '&&' \"||\" a&b").unwrap(),
+        [(None, "echo '&&' \"||\" a&b")]
+    );
+    assert!(matches!(
+        split_list("&& b"),
+        Err(ShellError::MissingCommand("&&"))
+    ));
+    assert!(matches!(
+        split_list("a ||  "),
+        Err(ShellError::MissingCommand("||"))
+    ));
+}
+
+#[test_case]
+fn test_split_redirections() {
+    let mut vars = Variables::new();
+    vars.set("F", "out").unwrap();
+    let stages = |line| {
+        split(line)
+            .unwrap()
+            .iter()
+            .map(|words| expand_stage(words, &vars, |_| Vec::new()).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let stage = |words: &[&str], output: Option<(Redirect, &str)>| Stage {
+        words: words.iter().map(|word| word.to_string()).collect(),
+        output: output.map(|(redirect, path)| (redirect, path.to_string())),
+    };
+    assert_eq!(
+        stages("echo a>b c"),
+        [stage(&["echo", "a", "c"], Some((Redirect::Truncate, "b")))]
+    );
+    assert_eq!(
+        stages("cat x >> $F.txt | wc"),
+        [
+            stage(&["cat", "x"], Some((Redirect::Append, "out.txt"))),
+            stage(&["wc"], None)
+        ]
+    );
+    assert_eq!(
+        stages("echo '>' \">>\" > a >b"),
+        [stage(&["echo", ">", ">>"], Some((Redirect::Truncate, "b")))]
+    );
+    for line in ["echo >", "echo > >> a"] {
+        assert!(matches!(
+            expand_stage(&split(line).unwrap()[0], &vars, |_| Vec::new()),
+            Err(ShellError::MissingRedirectPath)
+        ));
+    }
+}
+
+#[test_case]
+fn test_expand_variables() {
+    let mut vars = Variables::new();
+    vars.set("X", "ex").unwrap();
+    vars.set("long_name1", "long").unwrap();
+    vars.set_status(false);
+
+    assert_eq!(vars.expand("$X ${X}y $Xy").unwrap(), "ex exy ");
+    assert_eq!(vars.expand("$long_name1-$?").unwrap(), "long-1");
+    assert_eq!(vars.expand("$$X costs $5 $").unwrap(), "$X costs $5 $");
+    assert_eq!(vars.expand("${?}${UNSET}.").unwrap(), "1.");
+    assert!(matches!(
+        vars.expand("${X"),
+        Err(ShellError::BadSubstitution(_))
+    ));
+    assert!(matches!(
+        vars.set("1X", "y"),
+        Err(ShellError::InvalidVariableName(_))
+    ));
+}
+
+#[test_case]
+fn test_expand_globs() {
+    use alloc::vec;
+
+    let mut vars = Variables::new();
+    vars.set("EXT", "tmp").unwrap();
+    let glob = |pattern: &str| match pattern {
+        "*.tmp" => vec!["a.tmp".to_string(), "b.tmp".to_string()],
+        _ => Vec::new(),
+    };
+    let words = |line, vars: &Variables| expand_stage(&split(line).unwrap()[0], vars, glob);
+
+    assert_eq!(
+        words("rm *.$EXT c.txt > *.tmp", &vars).unwrap(),
+        Stage {
+            words: vec!["rm".into(), "a.tmp".into(), "b.tmp".into(), "c.txt".into()],
+            output: Some((Redirect::Truncate, "*.tmp".into())),
+        }
+    );
+    // Patterns matching nothing are kept, and quoted ones are never expanded
+    assert_eq!(
+        words("echo ?.none", &vars).unwrap().words,
+        ["echo", "?.none"]
+    );
+    assert_eq!(
+        words("echo '*.tmp' \"*\".tmp *'.tmp'", &vars)
+            .unwrap()
+            .words,
+        ["echo", "*.tmp", "*.tmp", "*.tmp"]
+    );
+
+    vars.set_option(FAILGLOB, true).unwrap();
+    assert!(matches!(
+        words("echo ?.none", &vars),
+        Err(ShellError::NoMatch(pattern)) if pattern == "?.none"
+    ));
+    assert_eq!(
+        words("echo *.tmp", &vars).unwrap().words,
+        ["echo", "a.tmp", "b.tmp"]
+    );
+    vars.set_option(FAILGLOB, false).unwrap();
+    assert!(words("echo ?.none", &vars).is_ok());
+    assert!(matches!(
+        vars.set_option("nullglob", true),
+        Err(ShellError::NoSuchOption(_))
+    ));
+}
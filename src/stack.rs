@@ -0,0 +1,139 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    allocator::{self, CANARY_SIZE},
+    log,
+    log::LogLevel,
+    task::{self, TaskId},
+    timer,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+// These must match `kernel-stack-address` and `kernel-stack-size` in Cargo.toml. The bootloader
+// leaves the first page unmapped and maps the stack pages above it.
+/// The unmapped page below the kernel stack, which a stack overflow runs into.
+pub const GUARD_PAGE: u64 = 0x_6666_0000_0000;
+pub const STACK_PAGES: u64 = 128;
+/// The lowest address of the kernel stack.
+pub const STACK_BOTTOM: u64 = GUARD_PAGE + PAGE_SIZE;
+/// The address the kernel stack grows down from.
+pub const STACK_TOP: u64 = STACK_BOTTOM + STACK_PAGES * PAGE_SIZE;
+pub const STACK_SIZE: usize = (STACK_TOP - STACK_BOTTOM) as usize;
+
+const POISON: u64 = 0x57ac_57ac_57ac_57ac;
+/// Bytes below the stack pointer which [`poison`] leaves alone.
+const POISON_MARGIN: u64 = 512;
+/// Time between the debug log messages of [`watch`].
+const WATCH_INTERVAL_MS: u64 = 60_000;
+
+static POISONED: AtomicBool = AtomicBool::new(false);
+
+fn stack_pointer() -> u64 {
+    let sp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack, preserves_flags)) };
+    sp
+}
+
+/// Fills the unused part of the kernel stack with a pattern, so that [`max_usage`] can tell how
+/// deep the stack has been since, and places a canary at its base. Does nothing if not running on
+/// the kernel stack.
+pub fn poison() {
+    let sp = stack_pointer();
+    if !(STACK_BOTTOM..=STACK_TOP).contains(&sp) {
+        return;
+    }
+    // Nothing is called in the loop, so nothing below the stack pointer is in use
+    for addr in (STACK_BOTTOM + CANARY_SIZE as u64..sp - POISON_MARGIN).step_by(8) {
+        unsafe { (addr as *mut u64).write_volatile(POISON) };
+    }
+    unsafe { allocator::place_canary(STACK_BOTTOM as usize) };
+    POISONED.store(true, Ordering::SeqCst);
+}
+
+/// Whether the stack has been poisoned, which also places its canary.
+pub(crate) fn poisoned() -> bool {
+    POISONED.load(Ordering::SeqCst)
+}
+
+/// Returns the number of bytes of the kernel stack in use right now.
+pub fn usage() -> usize {
+    STACK_TOP.saturating_sub(stack_pointer()) as usize
+}
+
+/// Returns the most bytes of the kernel stack which have been in use since [`poison`], or `None`
+/// if the stack hasn't been poisoned.
+pub fn max_usage() -> Option<usize> {
+    if !poisoned() {
+        return None;
+    }
+    let untouched = (STACK_BOTTOM + CANARY_SIZE as u64..STACK_TOP)
+        .step_by(8)
+        .take_while(|&addr| unsafe { (addr as *const u64).read_volatile() } == POISON)
+        .count();
+    Some(STACK_SIZE - CANARY_SIZE - untouched * 8)
+}
+
+/// Returns how many bytes deep the stack was if `sp` is within a page of the guard page, which is
+/// where the stack pointer of a fault caused by a stack overflow ends up.
+pub fn overflow_depth(sp: u64) -> Option<usize> {
+    (GUARD_PAGE..STACK_BOTTOM + PAGE_SIZE)
+        .contains(&sp)
+        .then(|| (STACK_TOP - sp) as usize)
+}
+
+/// Reports a kernel stack overflow from the double fault handler, naming the task that was being
+/// polled. Never returns, as the stack is gone.
+pub fn overflowed(depth: usize) -> ! {
+    match task::current() {
+        Some(TaskId(id)) => panic!("kernel stack overflow: {} bytes deep in task {}", depth, id),
+        None => panic!(
+            "kernel stack overflow: {} bytes deep outside of any task",
+            depth
+        ),
+    }
+}
+
+/// Logs the maximum stack usage at debug level now and then.
+pub async fn watch() {
+    loop {
+        timer::sleep(WATCH_INTERVAL_MS).await;
+        if let Some(max) = max_usage() {
+            log!(
+                LogLevel::Debug,
+                "kernel stack: {} of {} bytes used at most",
+                max,
+                STACK_SIZE
+            );
+        }
+    }
+}
+
+#[test_case]
+fn test_max_usage() {
+    #[inline(never)]
+    fn recurse(depth: usize) -> u8 {
+        let buf = unsafe { core::ptr::read_volatile(&[depth as u8; 256]) };
+        let byte = buf[1];
+        if depth == 0 {
+            byte
+        } else {
+            byte.wrapping_add(recurse(depth - 1))
+        }
+    }
+
+    let before = max_usage().expect("the kernel stack is poisoned");
+    let usage = usage();
+    recurse(64);
+    let after = max_usage().unwrap();
+    assert!(after >= usage + 64 * 256);
+    assert!(after >= before);
+    assert_eq!(overflow_depth(STACK_TOP), None);
+    assert_eq!(
+        overflow_depth(GUARD_PAGE + 8),
+        Some(STACK_SIZE + PAGE_SIZE as usize - 8)
+    );
+}
@@ -0,0 +1,116 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    allocator, console,
+    task::keyboard,
+    timer,
+    util::fmt::{FmtBuf, HumanBytes, HumanDuration},
+    vgabuf,
+};
+
+/// Time between status bar updates.
+const UPDATE_INTERVAL_MS: u64 = 1000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Reserves the top row of the screen for the status bar and draws it. While the screen is split
+/// it's only shown once the panes are joined again, see [`console::split`].
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+    if !vgabuf::is_split() {
+        show();
+    }
+}
+
+/// Removes the status bar, giving the top row back to normal output.
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+    if !vgabuf::is_split() {
+        hide();
+    }
+}
+
+/// Reserves the top row and draws the status bar, without changing whether it's enabled.
+pub(crate) fn show() {
+    let (_, bottom) = vgabuf::scroll_region();
+    vgabuf::set_scroll_region(1, bottom);
+    render();
+}
+
+/// Gives the top row back to normal output, without changing whether the status bar is enabled.
+pub(crate) fn hide() {
+    vgabuf::write_row(0, "");
+    let (_, bottom) = vgabuf::scroll_region();
+    vgabuf::set_scroll_region(0, bottom);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Draws the uptime, heap usage and lock key state on the status bar, and whether output is paused.
+/// Formats them on the stack, so drawing it doesn't change the heap usage it shows. Draws nothing
+/// while the screen is split.
+pub fn render() {
+    if vgabuf::is_split() {
+        return;
+    }
+    let mut uptime = FmtBuf::<32>::new();
+    let _ = write!(uptime, " up {}", HumanDuration(timer::ticks()));
+    let stats = allocator::stats();
+    let mut heap = FmtBuf::<32>::new();
+    let _ = write!(
+        heap,
+        "heap {}/{}",
+        HumanBytes(stats.used as u64),
+        HumanBytes(stats.total as u64)
+    );
+    let modifiers = keyboard::modifiers();
+    let locks = match (modifiers.caps_lock, modifiers.num_lock) {
+        (false, false) => "         ",
+        (true, false) => "CAPS     ",
+        (false, true) => "     NUM ",
+        (true, true) => "CAPS NUM ",
+    };
+    let mut right = FmtBuf::<32>::new();
+    if console::is_paused() {
+        let _ = right.write_str("PAUSED ");
+    }
+    let _ = right.write_str(locks);
+    vgabuf::render_status_bar(uptime.as_str(), heap.as_str(), right.as_str());
+}
+
+/// Redraws the status bar every second while it's enabled.
+pub async fn run() {
+    loop {
+        if is_enabled() {
+            render();
+        }
+        timer::sleep(UPDATE_INTERVAL_MS).await;
+    }
+}
+
+#[test_case]
+fn test_status_bar_keeps_top_row() {
+    use crate::{println, vgabuf::row_text};
+
+    enable();
+    let status = row_text(0);
+    assert!(status.starts_with(" up "));
+    assert!(status.contains("heap ") && status.contains("B/"));
+    assert!(status.ends_with("NUM"));
+
+    for i in 0..vgabuf::HEIGHT * 2 {
+        println!("filler line {}", i);
+    }
+    assert_eq!(row_text(0), status);
+    let last = alloc::format!("filler line {}", vgabuf::HEIGHT * 2 - 1);
+    assert!((1..vgabuf::HEIGHT).any(|row| row_text(row) == last));
+
+    disable();
+    assert_eq!(row_text(0), "");
+    assert_eq!(vgabuf::scroll_region().0, 0);
+}
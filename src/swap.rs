@@ -0,0 +1,539 @@
+use core::ops::Range;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use spin::Mutex;
+use thiserror_no_std::Error;
+
+use crate::{
+    error::{self, ErrorKind},
+    fs::disk::{BlockDevice, DiskError, BLOCK_SIZE},
+};
+
+/// The size of the pages of a [`SwappableBuffer`], one block of the swap device each.
+pub const PAGE_SIZE: usize = BLOCK_SIZE;
+/// The number of pages a [`SwappableBuffer`] keeps on the heap unless told otherwise.
+pub const DEFAULT_RESIDENT_PAGES: usize = 8;
+
+/// The device evicted pages are written to, `None` until one is attached.
+static SWAP: Mutex<Option<SwapSpace>> = Mutex::new(None);
+
+/// A swap device and which of its blocks hold pages.
+struct SwapSpace {
+    device: Box<dyn BlockDevice + Send>,
+    used: Vec<bool>,
+}
+
+impl SwapSpace {
+    fn allocate(&mut self) -> Result<usize, SwapError> {
+        let slot = self
+            .used
+            .iter()
+            .position(|used| !used)
+            .ok_or(SwapError::Full)?;
+        self.used[slot] = true;
+        Ok(slot)
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SwapError {
+    #[error("no swap device is attached")]
+    NoDevice,
+    #[error("swap is full")]
+    Full,
+    #[error("swap is in use by {0} pages")]
+    InUse(usize),
+    #[error("swap devices need blocks of {PAGE_SIZE} bytes, not {0}")]
+    BlockSize(usize),
+    #[error("tried to access {0} bytes at offset {1}, which exceeds buffer size of {2}")]
+    OutOfBounds(usize, usize, usize),
+    #[error("swap device: {0}")]
+    Disk(#[from] DiskError),
+}
+
+impl error::Error for SwapError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NoDevice => ErrorKind::NotFound,
+            Self::Full => ErrorKind::OutOfSpace,
+            Self::InUse(_) => ErrorKind::Other,
+            Self::BlockSize(_) => ErrorKind::Unsupported,
+            Self::OutOfBounds(..) => ErrorKind::InvalidInput,
+            Self::Disk(err) => err.kind(),
+        }
+    }
+
+    fn source(&self) -> Option<&dyn error::Error> {
+        match self {
+            Self::Disk(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Makes `device` the swap device, returning the previous one. Fails if any page is swapped out
+/// to the previous device.
+pub fn attach(
+    device: Box<dyn BlockDevice + Send>,
+) -> Result<Option<Box<dyn BlockDevice + Send>>, SwapError> {
+    if device.block_size() != PAGE_SIZE {
+        return Err(SwapError::BlockSize(device.block_size()));
+    }
+    let mut swap = SWAP.lock();
+    if let Some(space) = &*swap {
+        match space.used.iter().filter(|&&used| used).count() {
+            0 => {}
+            used => return Err(SwapError::InUse(used)),
+        }
+    }
+    let used = vec![false; device.size()];
+    let previous = swap.replace(SwapSpace { device, used });
+    Ok(previous.map(|space| space.device))
+}
+
+/// Returns the number of pages swapped out and the size of the swap device in pages, or `None`
+/// if no swap device is attached.
+pub fn usage() -> Option<(usize, usize)> {
+    SWAP.lock().as_ref().map(|space| {
+        let used = space.used.iter().filter(|&&used| used).count();
+        (used, space.used.len())
+    })
+}
+
+/// Where a page of a [`SwappableBuffer`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageState {
+    /// Never written, all zeroes.
+    Zero,
+    /// Evicted to a block of the swap device.
+    Swapped(usize),
+    /// On the heap, at the index in the resident pages.
+    Resident(usize),
+}
+
+struct ResidentPage {
+    page: usize,
+    data: Box<[u8; PAGE_SIZE]>,
+    /// The block of the swap device the page was last evicted to, still holding its contents
+    /// unless the page is dirty.
+    slot: Option<usize>,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// A buffer of bytes which keeps at most a set number of its pages on the heap, and the rest on
+/// the swap device. Pages are read back from the swap device when they're accessed, evicting
+/// the least recently used page if the buffer is at its budget. Only pages which were written
+/// since they were last read back are written to the swap device when evicted.
+pub struct SwappableBuffer {
+    len: usize,
+    pages: Vec<PageState>,
+    resident: Vec<ResidentPage>,
+    budget: usize,
+    // Counts accesses, for finding the least recently used page
+    clock: u64,
+}
+
+impl SwappableBuffer {
+    /// Creates a zeroed buffer of `len` bytes, keeping at most [`DEFAULT_RESIDENT_PAGES`] pages on
+    /// the heap.
+    pub fn new(len: usize) -> Self {
+        Self::with_budget(len, DEFAULT_RESIDENT_PAGES)
+    }
+
+    /// Creates a zeroed buffer of `len` bytes, keeping at most `budget` pages on the heap.
+    /// Panics if the budget is zero.
+    pub fn with_budget(len: usize, budget: usize) -> Self {
+        assert!(
+            budget > 0,
+            "a swappable buffer needs at least one resident page"
+        );
+        Self {
+            len,
+            pages: vec![PageState::Zero; len.div_ceil(PAGE_SIZE)],
+            resident: Vec::with_capacity(budget),
+            budget,
+            clock: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Returns the number of pages on the heap.
+    pub fn resident_pages(&self) -> usize {
+        self.resident.len()
+    }
+
+    /// Changes how many pages are kept on the heap, evicting pages until the buffer is within the
+    /// new budget. Panics if the budget is zero.
+    pub fn set_budget(&mut self, budget: usize) -> Result<(), SwapError> {
+        assert!(
+            budget > 0,
+            "a swappable buffer needs at least one resident page"
+        );
+        while self.resident.len() > budget {
+            let index = self.least_recently_used();
+            self.evict(index)?;
+            self.remove_resident(index);
+        }
+        self.resident.shrink_to(budget);
+        self.budget = budget;
+        Ok(())
+    }
+
+    /// Copies the bytes starting at `offset` into `buf`.
+    pub fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), SwapError> {
+        self.check_bounds(offset, buf.len())?;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (page, page_offset) = ((offset + pos) / PAGE_SIZE, (offset + pos) % PAGE_SIZE);
+            let len = (PAGE_SIZE - page_offset).min(buf.len() - pos);
+            let index = self.fault_in(page)?;
+            let data = &self.resident[index].data;
+            buf[pos..pos + len].copy_from_slice(&data[page_offset..page_offset + len]);
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Copies `buf` into the buffer starting at `offset`.
+    pub fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), SwapError> {
+        self.check_bounds(offset, buf.len())?;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (page, page_offset) = ((offset + pos) / PAGE_SIZE, (offset + pos) % PAGE_SIZE);
+            let len = (PAGE_SIZE - page_offset).min(buf.len() - pos);
+            let index = self.fault_in(page)?;
+            let resident = &mut self.resident[index];
+            resident.data[page_offset..page_offset + len].copy_from_slice(&buf[pos..pos + len]);
+            resident.dirty = true;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with each part of the `len` bytes starting at `offset` which lies within a
+    /// single page, in order, without copying them.
+    pub fn for_each_chunk(
+        &mut self,
+        offset: usize,
+        len: usize,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<(), SwapError> {
+        self.check_bounds(offset, len)?;
+        let mut pos = 0;
+        while pos < len {
+            let (page, page_offset) = ((offset + pos) / PAGE_SIZE, (offset + pos) % PAGE_SIZE);
+            let chunk = (PAGE_SIZE - page_offset).min(len - pos);
+            let index = self.fault_in(page)?;
+            f(&self.resident[index].data[page_offset..page_offset + chunk]);
+            pos += chunk;
+        }
+        Ok(())
+    }
+
+    /// Copies the bytes in `src` to the same number of bytes starting at `dest`, like
+    /// [`slice::copy_within`]. The ranges may overlap.
+    pub fn copy_within(&mut self, src: Range<usize>, dest: usize) -> Result<(), SwapError> {
+        let len = src.end.saturating_sub(src.start);
+        self.check_bounds(src.start, len)?;
+        self.check_bounds(dest, len)?;
+        let mut chunk = vec![0; PAGE_SIZE.min(len)];
+        let mut copied = 0;
+        while copied < len {
+            let n = chunk.len().min(len - copied);
+            // Copying towards the end starts from the end, so bytes aren't overwritten before
+            // they're copied
+            let pos = match dest > src.start {
+                true => len - copied - n,
+                false => copied,
+            };
+            self.read(src.start + pos, &mut chunk[..n])?;
+            self.write(dest + pos, &chunk[..n])?;
+            copied += n;
+        }
+        Ok(())
+    }
+
+    /// Grows or shrinks the buffer to `len` bytes. Bytes added at the end are zero, and the pages
+    /// past the new end are dropped along with their blocks of the swap device.
+    pub fn resize(&mut self, len: usize) -> Result<(), SwapError> {
+        if len < self.len && !len.is_multiple_of(PAGE_SIZE) {
+            // The rest of the new last page is zeroed for when the buffer grows again
+            let end = self.len.min(len.next_multiple_of(PAGE_SIZE));
+            self.write(len, &vec![0; end - len])?;
+        }
+        let pages = len.div_ceil(PAGE_SIZE);
+        while self.pages.len() > pages {
+            let slot = match self.pages.pop() {
+                Some(PageState::Swapped(slot)) => Some(slot),
+                Some(PageState::Resident(index)) => {
+                    let slot = self.resident[index].slot;
+                    self.remove_resident(index);
+                    slot
+                }
+                _ => None,
+            };
+            if let Some(slot) = slot {
+                free(slot);
+            }
+        }
+        self.pages.resize(pages, PageState::Zero);
+        self.len = len;
+        Ok(())
+    }
+
+    fn check_bounds(&self, offset: usize, len: usize) -> Result<(), SwapError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(SwapError::OutOfBounds(len, offset, self.len)),
+        }
+    }
+
+    /// Makes the page resident, returning its index in the resident pages.
+    fn fault_in(&mut self, page: usize) -> Result<usize, SwapError> {
+        self.clock += 1;
+        let slot = match self.pages[page] {
+            PageState::Resident(index) => {
+                self.resident[index].last_used = self.clock;
+                return Ok(index);
+            }
+            PageState::Swapped(slot) => Some(slot),
+            PageState::Zero => None,
+        };
+
+        let index = match self.resident.len() < self.budget {
+            true => {
+                self.resident.push(ResidentPage {
+                    page,
+                    data: Box::new([0; PAGE_SIZE]),
+                    slot: None,
+                    dirty: false,
+                    last_used: 0,
+                });
+                self.resident.len() - 1
+            }
+            false => {
+                // The evicted page's memory is reused, so the buffer never holds more than its
+                // budget of pages
+                let index = self.least_recently_used();
+                self.evict(index)?;
+                index
+            }
+        };
+
+        let resident = &mut self.resident[index];
+        match slot {
+            Some(slot) => {
+                let mut swap = SWAP.lock();
+                let space = swap.as_mut().ok_or(SwapError::NoDevice)?;
+                if let Err(err) = space.device.read(slot, &mut resident.data[..]) {
+                    drop(swap);
+                    // The page is still on the swap device, and the evicted page is gone
+                    self.remove_resident(index);
+                    return Err(err.into());
+                }
+            }
+            None => resident.data.fill(0),
+        }
+        resident.page = page;
+        resident.slot = slot;
+        resident.dirty = false;
+        resident.last_used = self.clock;
+        self.pages[page] = PageState::Resident(index);
+        Ok(index)
+    }
+
+    /// Frees the memory of the resident page at `index`, which must have been evicted.
+    fn remove_resident(&mut self, index: usize) {
+        self.resident.swap_remove(index);
+        if let Some(moved) = self.resident.get(index) {
+            self.pages[moved.page] = PageState::Resident(index);
+        }
+    }
+
+    fn least_recently_used(&self) -> usize {
+        self.resident
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, resident)| resident.last_used)
+            .map(|(index, _)| index)
+            .expect("no resident pages")
+    }
+
+    /// Moves the resident page at `index` out to the swap device, writing it first if it's dirty.
+    /// The memory of the page is left in the resident pages for the caller to reuse or free.
+    fn evict(&mut self, index: usize) -> Result<(), SwapError> {
+        let resident = &mut self.resident[index];
+        let state = match (resident.dirty, resident.slot) {
+            (false, Some(slot)) => PageState::Swapped(slot),
+            (false, None) => PageState::Zero,
+            (true, slot) => {
+                let mut swap = SWAP.lock();
+                let space = swap.as_mut().ok_or(SwapError::NoDevice)?;
+                let slot = match slot {
+                    Some(slot) => slot,
+                    None => space.allocate()?,
+                };
+                if let Err(err) = space.device.write(slot, &resident.data[..]) {
+                    if resident.slot.is_none() {
+                        space.used[slot] = false;
+                    }
+                    return Err(err.into());
+                }
+                PageState::Swapped(slot)
+            }
+        };
+        self.pages[resident.page] = state;
+        Ok(())
+    }
+}
+
+/// Marks a block of the swap device as free again.
+fn free(slot: usize) {
+    if let Some(space) = SWAP.lock().as_mut() {
+        space.used[slot] = false;
+    }
+}
+
+impl Drop for SwappableBuffer {
+    fn drop(&mut self) {
+        let slots = self.pages.iter().filter_map(|state| match state {
+            PageState::Swapped(slot) => Some(*slot),
+            PageState::Resident(index) => self.resident[*index].slot,
+            PageState::Zero => None,
+        });
+        let mut swap = SWAP.lock();
+        if let Some(space) = swap.as_mut() {
+            for slot in slots {
+                space.used[slot] = false;
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_swappable_buffer() {
+    use crate::{allocator, fs::disk::Disk};
+
+    const BUDGET: usize = 4;
+    const LEN: usize = 4 * BUDGET * PAGE_SIZE;
+    let pattern = |i: usize| (i * 7 + i / PAGE_SIZE) as u8;
+
+    let previous = attach(Box::new(Disk::new(LEN / PAGE_SIZE))).unwrap();
+    allocator::reset_peak();
+    let start = allocator::stats().used;
+    {
+        let mut buffer = SwappableBuffer::with_budget(LEN, BUDGET);
+        let mut chunk = [0; 100];
+        for offset in (0..LEN).step_by(chunk.len()) {
+            let len = chunk.len().min(LEN - offset);
+            for (i, byte) in chunk[..len].iter_mut().enumerate() {
+                *byte = pattern(offset + i);
+            }
+            buffer.write(offset, &chunk[..len]).unwrap();
+        }
+        assert_eq!(buffer.resident_pages(), BUDGET);
+        assert_eq!(usage(), Some((LEN / PAGE_SIZE - BUDGET, LEN / PAGE_SIZE)));
+
+        for offset in (0..LEN).step_by(chunk.len()) {
+            let len = chunk.len().min(LEN - offset);
+            buffer.read(offset, &mut chunk[..len]).unwrap();
+            for (i, &byte) in chunk[..len].iter().enumerate() {
+                assert_eq!(byte, pattern(offset + i), "byte {} differs", offset + i);
+            }
+        }
+        assert_eq!(
+            buffer.read(LEN - 1, &mut [0; 2]),
+            Err(SwapError::OutOfBounds(2, LEN - 1, LEN))
+        );
+
+        let peak = allocator::stats().peak - start;
+        assert!(
+            peak < (BUDGET + 1) * PAGE_SIZE,
+            "buffer used {} bytes of heap",
+            peak
+        );
+    }
+    // Dropping the buffer frees its pages on the swap device
+    assert_eq!(usage(), Some((0, LEN / PAGE_SIZE)));
+    if let Some(previous) = previous {
+        attach(previous).unwrap();
+    }
+}
+
+#[test_case]
+fn test_swap_budget() {
+    use crate::fs::disk::Disk;
+
+    let previous = attach(Box::new(Disk::new(2))).unwrap();
+    {
+        let mut buffer = SwappableBuffer::with_budget(4 * PAGE_SIZE, 4);
+        for page in 0..4 {
+            buffer.write(page * PAGE_SIZE, &[page as u8 + 1]).unwrap();
+        }
+        // Only dirty pages take up swap, so the third one doesn't fit
+        assert_eq!(buffer.set_budget(1), Err(SwapError::Full));
+        assert_eq!(buffer.resident_pages(), 2);
+        assert!(matches!(
+            attach(Box::new(Disk::new(1))),
+            Err(SwapError::InUse(2))
+        ));
+
+        let mut byte = [0];
+        buffer.read(0, &mut byte).unwrap();
+        assert_eq!(byte, [1]);
+        buffer.read(3 * PAGE_SIZE, &mut byte).unwrap();
+        assert_eq!(byte, [4]);
+    }
+    if let Some(previous) = previous {
+        attach(previous).unwrap();
+    }
+}
+
+#[test_case]
+fn test_resize_and_copy_within() {
+    use crate::fs::disk::Disk;
+
+    let previous = attach(Box::new(Disk::new(8))).unwrap();
+    {
+        let mut buffer = SwappableBuffer::with_budget(PAGE_SIZE + 10, 1);
+        let bytes = (0..buffer.len()).map(|i| i as u8).collect::<Vec<_>>();
+        buffer.write(0, &bytes).unwrap();
+
+        // Overlapping copies in both directions, across pages
+        buffer.copy_within(0..PAGE_SIZE, 10).unwrap();
+        let mut read = vec![0; buffer.len()];
+        buffer.read(0, &mut read).unwrap();
+        assert_eq!(read[..10], bytes[..10]);
+        assert_eq!(read[10..], bytes[..PAGE_SIZE]);
+        buffer.copy_within(10..PAGE_SIZE + 10, 0).unwrap();
+        buffer.read(0, &mut read).unwrap();
+        assert_eq!(read[..PAGE_SIZE], bytes[..PAGE_SIZE]);
+
+        // Growing adds zeroes, even where the buffer was shrunk from, and shrinking frees swap
+        buffer.resize(3 * PAGE_SIZE).unwrap();
+        buffer.write(3 * PAGE_SIZE - 1, &[1]).unwrap();
+        buffer.read(0, &mut [0]).unwrap();
+        assert_eq!(usage(), Some((3, 8)));
+        buffer.resize(5).unwrap();
+        assert_eq!(usage(), Some((1, 8)));
+        buffer.resize(PAGE_SIZE + 10).unwrap();
+        buffer.read(0, &mut read).unwrap();
+        assert_eq!(read[..5], bytes[..5]);
+        assert!(read[5..].iter().all(|&byte| byte == 0));
+    }
+    if let Some(previous) = previous {
+        attach(previous).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Poll, Waker},
+};
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+
+/// Asks a job to stop. Clones share the same flag, so whoever started the job keeps a clone to
+/// cancel it with while the job checks its own. Jobs stop at the next point they check the token,
+/// cancelling doesn't interrupt anything by itself.
+///
+/// Every task waiting in [`cancelled`](Self::cancelled) is woken when the token is cancelled, so
+/// waiting for it can be raced against other work with [`select2`](super::select2) or
+/// [`timeout`](super::time::timeout).
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Arc<CancelState>,
+}
+
+struct CancelState {
+    cancelled: AtomicBool,
+    /// The tasks waiting for the token to be cancelled, at most one waker for each.
+    waiters: Mutex<Vec<Waker>>,
+    /// The tokens made by `child_token`, which are cancelled along with this one. Children which
+    /// were dropped are pruned whenever a new one is made.
+    children: Mutex<Vec<Weak<CancelState>>>,
+}
+
+impl CancelState {
+    fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            children: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn cancel(&self) {
+        if self.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Taken out of the locks first, a woken task may run on this stack and look at the token
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        waiters.into_iter().for_each(Waker::wake);
+        let children = core::mem::take(&mut *self.children.lock());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(CancelState::new()),
+        }
+    }
+
+    /// Cancels the token and every child token made from it, waking the tasks waiting for any of
+    /// them. Cancelling a token again does nothing.
+    pub fn cancel(&self) {
+        self.state.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Makes a token which is cancelled along with this one, or right away if this one already
+    /// is. Cancelling the child doesn't cancel this token, so one part of a job can be stopped
+    /// without stopping the rest.
+    pub fn child_token(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        let mut children = self.state.children.lock();
+        if self.is_cancelled() {
+            drop(children);
+            child.cancel();
+        } else {
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(&child.state));
+        }
+        child
+    }
+
+    /// Waits until the token is cancelled. Every task waiting is woken, not only the last one.
+    pub async fn cancelled(&self) {
+        poll_fn(|cx| {
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+            let mut waiters = self.state.waiters.lock();
+            if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+            drop(waiters);
+            // Cancelled after the first check, before the waker was registered
+            match self.is_cancelled() {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_cancel_before_wait() {
+    use super::{executor::Executor, Task};
+
+    let token = CancellationToken::new();
+    token.cancel();
+    token.cancel();
+    let mut executor = Executor::new();
+    let done = Arc::new(AtomicBool::new(false));
+    executor.spawn(Task::new({
+        let (token, done) = (token.clone(), done.clone());
+        async move {
+            token.cancelled().await;
+            done.store(true, Ordering::SeqCst);
+        }
+    }));
+    executor.run_ready_tasks();
+    assert!(done.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_cancel_wakes_every_waiter() {
+    use super::{executor::Executor, Priority};
+
+    let token = CancellationToken::new();
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let waiters = (0..3)
+        .map(|_| {
+            let token = token.clone();
+            spawner.spawn(async move { token.cancelled().await }, Priority::Normal)
+        })
+        .collect::<Vec<_>>();
+    executor.run_ready_tasks();
+    assert!(waiters.iter().all(|waiter| !waiter.is_finished()));
+    // Parked tasks aren't polled again until they're woken
+    executor.run_ready_tasks();
+    assert_eq!(token.state.waiters.lock().len(), 3);
+
+    token.cancel();
+    executor.run_ready_tasks();
+    assert!(waiters.iter().all(|waiter| waiter.is_finished()));
+    assert!(token.state.waiters.lock().is_empty());
+}
+
+#[test_case]
+fn test_child_tokens() {
+    let parent = CancellationToken::new();
+    let child = parent.child_token();
+    let grandchild = child.child_token();
+    let sibling = parent.child_token();
+
+    // Cancelling a child leaves the parent and the other children alone
+    sibling.cancel();
+    assert!(sibling.is_cancelled());
+    assert!(!parent.is_cancelled() && !child.is_cancelled());
+
+    drop(sibling);
+    parent.child_token();
+    // The dropped children were pruned, the one just made was dropped right away
+    assert_eq!(parent.state.children.lock().len(), 2);
+
+    parent.cancel();
+    assert!(child.is_cancelled() && grandchild.is_cancelled());
+    assert!(parent.child_token().is_cancelled());
+}
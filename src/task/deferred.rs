@@ -0,0 +1,199 @@
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::Poll,
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use thiserror_no_std::Error;
+
+use super::keyboard;
+use crate::timer;
+
+/// The number of work items which can wait to be run.
+pub const QUEUE_SIZE: usize = 64;
+
+static QUEUE: OnceCell<ArrayQueue<WorkItem>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static CONSUMER_TAKEN: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Work handed from an interrupt handler to the [`run`] task, to be done outside of interrupt
+/// context.
+#[derive(Debug, Clone, Copy)]
+pub enum WorkItem {
+    /// Wake the tasks whose sleep has ended.
+    WakeSleepers,
+    /// Set the keyboard LEDs to the given bitmask, see [`keyboard::update_leds`].
+    SetLeds(u8),
+    /// Call a function.
+    Call(fn()),
+}
+
+impl WorkItem {
+    fn perform(self) {
+        match self {
+            WorkItem::WakeSleepers => timer::wake_sleepers(),
+            WorkItem::SetLeds(leds) => keyboard::request_leds(leds),
+            WorkItem::Call(f) => f(),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferredError {
+    #[error("the deferred work queue is full")]
+    QueueFull,
+    #[error("the deferred work queue is not initialized")]
+    Uninitialized,
+}
+
+/// Sets up the work queue. Calling it again has no effect.
+pub fn init() {
+    let _ = QUEUE.try_init_once(|| ArrayQueue::new(QUEUE_SIZE));
+}
+
+/// Queues work to be done by the [`run`] task. Never allocates or blocks, so it can be called from
+/// interrupt handlers. Work which can't be queued is counted in [`dropped`].
+pub fn submit(item: WorkItem) -> Result<(), DeferredError> {
+    let result = match QUEUE.try_get() {
+        Ok(queue) => queue.push(item).map_err(|_| DeferredError::QueueFull),
+        Err(_) => Err(DeferredError::Uninitialized),
+    };
+    match result {
+        Ok(()) => WAKER.wake(),
+        Err(_) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    result
+}
+
+/// The number of work items which have been dropped since boot because they couldn't be queued.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Releases the queue for another consumer when the [`run`] task is dropped.
+struct Consumer;
+
+impl Consumer {
+    fn take() -> Option<Self> {
+        (!CONSUMER_TAKEN.swap(true, Ordering::SeqCst)).then_some(Consumer)
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        CONSUMER_TAKEN.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Does the work submitted by interrupt handlers, in the order it was submitted. There must be at
+/// most one of these tasks running, and it should run at [`Priority::High`](super::Priority::High)
+/// so the work isn't held up by other tasks.
+pub async fn run() {
+    let _consumer = Consumer::take().expect("deferred work is already being run");
+    init();
+    let queue = QUEUE.try_get().unwrap();
+    poll_fn(|cx| {
+        while let Some(item) = queue.pop() {
+            item.perform();
+        }
+        WAKER.register(cx.waker());
+        // Work submitted before the waker was registered didn't wake us
+        if !queue.is_empty() {
+            cx.waker().wake_by_ref();
+        }
+        Poll::<()>::Pending
+    })
+    .await
+}
+
+/// Takes the queued work without doing it. Waking sleepers is still done, since the timer doesn't
+/// queue it again until it has been.
+#[cfg(test)]
+pub(crate) fn take_pending() -> alloc::vec::Vec<WorkItem> {
+    let queue = QUEUE
+        .try_get()
+        .expect("deferred work queue is not initialized");
+    core::iter::from_fn(|| queue.pop())
+        .filter(|item| match item {
+            WorkItem::WakeSleepers => {
+                item.perform();
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_work_runs_in_order() {
+    use alloc::vec::Vec;
+    use spin::Mutex;
+    use x86_64::instructions::interrupts;
+
+    use super::{executor::Executor, select2, yield_now, Task};
+
+    static DONE: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+    fn first() {
+        DONE.lock().push(1);
+    }
+    fn second() {
+        DONE.lock().push(2);
+    }
+    fn third() {
+        DONE.lock().push(3);
+    }
+
+    init();
+    take_pending();
+    let producer = async {
+        // Submitted with interrupts disabled, as an interrupt handler would
+        interrupts::without_interrupts(|| {
+            submit(WorkItem::Call(first)).unwrap();
+            submit(WorkItem::Call(second)).unwrap();
+        });
+        yield_now().await;
+        assert_eq!(*DONE.lock(), [1, 2]);
+        interrupts::without_interrupts(|| submit(WorkItem::Call(third)).unwrap());
+        while DONE.lock().len() < 3 {
+            yield_now().await;
+        }
+    };
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        select2(producer, run()).await;
+    }));
+    executor.run_until_done();
+    assert_eq!(*DONE.lock(), [1, 2, 3]);
+    // The consumer was dropped with the task, so another one can be started
+    assert!(Consumer::take().is_some());
+}
+
+#[test_case]
+fn test_full_queue_drops_work() {
+    use x86_64::instructions::interrupts;
+
+    fn nothing() {}
+
+    init();
+    // Keep the timer from queueing work in the middle of the test
+    interrupts::without_interrupts(|| {
+        take_pending();
+        let dropped_before = dropped();
+        for _ in 0..QUEUE_SIZE {
+            assert_eq!(submit(WorkItem::Call(nothing)), Ok(()));
+        }
+        assert_eq!(
+            submit(WorkItem::Call(nothing)),
+            Err(DeferredError::QueueFull)
+        );
+        assert_eq!(submit(WorkItem::SetLeds(0)), Err(DeferredError::QueueFull));
+        assert_eq!(dropped() - dropped_before, 2);
+        assert_eq!(take_pending().len(), QUEUE_SIZE);
+    });
+}
@@ -1,46 +1,290 @@
-use core::task::{Context, Poll, Waker};
+use core::{
+    cell::RefCell,
+    future::Future,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{collections::BTreeMap, rc::Rc, sync::Arc, task::Wake, vec::Vec};
 use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
 use x86_64::instructions::interrupts;
 
-use super::{Task, TaskId};
+use super::{
+    job::{self, JoinHandle},
+    trace::{ExecutorTracer, TraceCounts},
+    Priority, Task, TaskId, TaskName,
+};
+use crate::timer;
+
+/// After this many polls of higher priority tasks in a row, a ready task of a lower priority is
+/// polled, so that a busy high priority task can't starve the others.
+const STARVATION_LIMIT: usize = 16;
+
+// The tasks of every executor, kept up to date as tasks are spawned, polled and completed
+static TASK_TABLE: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+// The number of live `CpuAccounting`s. Polls are only timed while there's one, so that not being
+// watched costs a load and a branch per poll.
+static WATCHERS: AtomicUsize = AtomicUsize::new(0);
+// Cycles spent halted waiting for an interrupt while polls were timed
+static IDLE_CYCLES: AtomicU64 = AtomicU64::new(0);
 
 /// TODO:
-/// - Implement a better scheduling, with e.g. priorities
-/// - The `spawn` function takes a `&mut self`, which disallows spawning new tasks after calling `run`.
-///   Maybe create a new `Spawner` type which allows creating tasks while the executor is running?
 /// - Implement threads, and load balancing
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// One ready queue per priority, highest priority first.
+    task_queues: [Arc<ArrayQueue<TaskId>>; Priority::COUNT],
     waker_cache: BTreeMap<TaskId, Waker>,
+    /// The number of polls in a row which skipped over the ready tasks of each priority.
+    skipped: [usize; Priority::COUNT],
+    stats: ExecutorStats,
+    /// Tasks spawned through a [`Spawner`], which are added before the next poll.
+    new_tasks: Rc<RefCell<Vec<Task>>>,
+    tracer: Option<&'static dyn ExecutorTracer>,
+}
+
+/// Spawns tasks on an [`Executor`] while it's running, unlike [`Executor::spawn`]. Must not be
+/// used from interrupt handlers.
+#[derive(Clone)]
+pub struct Spawner {
+    new_tasks: Rc<RefCell<Vec<Task>>>,
+}
+
+impl Spawner {
+    /// Spawns a task running `future`, returning a handle to wait for its output with.
+    pub fn spawn<T: 'static, F: Future<Output = T> + 'static>(
+        &self,
+        future: F,
+        priority: Priority,
+    ) -> JoinHandle<T> {
+        let (task, handle) = job::joinable(future);
+        // Named after the future spawned rather than the wrapper reporting its output
+        let task = Task::with_priority(task, priority).with_name(core::any::type_name::<F>());
+        self.new_tasks.borrow_mut().push(task);
+        handle
+    }
+}
+
+/// Counters describing the work done by an [`Executor`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutorStats {
+    /// The number of times tasks of each priority have been polled, indexed by [`Priority`].
+    pub polls: [u64; Priority::COUNT],
+    /// What the executor did with its tasks, counted by its tracer if that's a
+    /// [`CountingTracer`](super::trace::CountingTracer) or a pair with one.
+    pub events: Option<TraceCounts>,
+}
+
+/// A task spawned on an [`Executor`], as listed by [`for_each_task`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub priority: Priority,
+    pub name: TaskName,
+    /// The number of times the task has been polled.
+    pub polls: u64,
+    /// The CPU cycles spent polling the task while a [`CpuAccounting`] was alive.
+    pub cycles: u64,
+}
+
+/// Times every poll of every executor while it's alive, along with the time executors spend
+/// halted, so that [`CpuAccounting::sample`] can tell what the CPU is busy with. Time is counted
+/// in cycles of the CPU's timestamp counter.
+pub struct CpuAccounting {
+    _private: (),
+}
+
+impl CpuAccounting {
+    pub fn start() -> Self {
+        WATCHERS.fetch_add(1, Ordering::Relaxed);
+        Self { _private: () }
+    }
+
+    /// Takes the time spent in every task and halted so far. The time used in between is found
+    /// by comparing two samples with [`CpuSample::usage_since`].
+    pub fn sample(&self) -> CpuSample {
+        let tasks = TASK_TABLE.lock().values().copied().collect();
+        CpuSample {
+            cycles: read_cycles(),
+            ticks: timer::ticks(),
+            idle: IDLE_CYCLES.load(Ordering::Relaxed),
+            tasks,
+        }
+    }
+}
+
+impl Drop for CpuAccounting {
+    fn drop(&mut self) {
+        WATCHERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The CPU time used so far, taken by [`CpuAccounting::sample`].
+#[derive(Debug, Clone)]
+pub struct CpuSample {
+    /// The timestamp counter when the sample was taken.
+    pub cycles: u64,
+    /// The timer ticks when the sample was taken.
+    pub ticks: u64,
+    /// The cycles spent halted so far.
+    pub idle: u64,
+    pub tasks: Vec<TaskInfo>,
+}
+
+impl CpuSample {
+    /// Returns the time used by each task between `earlier` and this sample, busiest task first.
+    /// Tasks spawned in between count from zero.
+    pub fn usage_since(&self, earlier: &CpuSample) -> CpuUsage {
+        let before = |id| earlier.tasks.iter().find(|task: &&TaskInfo| task.id == id);
+        let mut tasks = self
+            .tasks
+            .iter()
+            .map(|task| TaskUsage {
+                info: *task,
+                cycles: task.cycles - before(task.id).map_or(0, |task| task.cycles),
+                polls: task.polls - before(task.id).map_or(0, |task| task.polls),
+            })
+            .collect::<Vec<_>>();
+        tasks.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.info.id.cmp(&b.info.id)));
+        CpuUsage {
+            elapsed: self.cycles.saturating_sub(earlier.cycles),
+            ticks: self.ticks - earlier.ticks,
+            idle: self.idle - earlier.idle,
+            tasks,
+        }
+    }
+}
+
+/// The CPU time used between two [`CpuSample`]s.
+#[derive(Debug, Clone)]
+pub struct CpuUsage {
+    /// The cycles between the samples.
+    pub elapsed: u64,
+    /// The timer ticks between the samples.
+    pub ticks: u64,
+    /// The cycles spent halted.
+    pub idle: u64,
+    /// The tasks alive at the later sample, busiest first.
+    pub tasks: Vec<TaskUsage>,
+}
+
+impl CpuUsage {
+    /// Returns `cycles` in thousandths of the time between the samples.
+    pub fn per_mille(&self, cycles: u64) -> u64 {
+        match self.elapsed {
+            0 => 0,
+            elapsed => (cycles as u128 * 1000 / elapsed as u128) as u64,
+        }
+    }
+
+    /// Converts cycles to milliseconds, using the timer to find out how fast the timestamp counter
+    /// runs. Returns `None` if no tick passed between the samples.
+    pub fn cycles_to_millis(&self, cycles: u64) -> Option<u64> {
+        let millis = timer::ticks_to_millis(self.ticks);
+        match (self.elapsed, millis) {
+            (0, _) | (_, 0) => None,
+            (elapsed, millis) => Some((cycles as u128 * millis as u128 / elapsed as u128) as u64),
+        }
+    }
+}
+
+/// The time a task used between two samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskUsage {
+    /// The task as of the later sample, with the totals since it was spawned.
+    pub info: TaskInfo,
+    pub cycles: u64,
+    pub polls: u64,
+}
+
+/// Reads the timestamp counter, which counts CPU cycles.
+fn read_cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+fn timing_polls() -> bool {
+    WATCHERS.load(Ordering::Relaxed) != 0
+}
+
+/// Calls `f` with every task which hasn't completed yet, across all executors, in the order they
+/// were spawned. The task table is locked meanwhile, so `f` must not spawn or poll tasks.
+pub fn for_each_task(mut f: impl FnMut(&TaskInfo)) {
+    for info in TASK_TABLE.lock().values() {
+        f(info);
+    }
 }
 
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    tracer: Option<&'static dyn ExecutorTracer>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queues: core::array::from_fn(|_| Arc::new(ArrayQueue::new(100))),
             waker_cache: BTreeMap::new(),
+            skipped: [0; Priority::COUNT],
+            stats: ExecutorStats::default(),
+            new_tasks: Rc::new(RefCell::new(Vec::new())),
+            tracer: None,
+        }
+    }
+
+    /// Reports what the executor does with its tasks to `tracer` from now on. Wakers handed out
+    /// earlier wouldn't report their wakes, so the tracer can only be set before any task is
+    /// polled, and only once.
+    pub fn set_tracer(&mut self, tracer: &'static dyn ExecutorTracer) {
+        assert!(
+            self.tracer.is_none() && self.waker_cache.is_empty(),
+            "the tracer must be set once, before tasks are polled"
+        );
+        self.tracer = Some(tracer);
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            new_tasks: self.new_tasks.clone(),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let priority = task.priority;
+        TASK_TABLE.lock().insert(
+            task_id,
+            TaskInfo {
+                id: task_id,
+                priority,
+                name: task.name,
+                polls: 0,
+                cycles: 0,
+            },
+        );
+        if let Some(tracer) = self.tracer {
+            tracer.on_spawn(task_id, task.name);
+        }
         if self.tasks.insert(task_id, task).is_some() {
             panic!(
                 "tried to insert task with id {} but it was already present!",
                 task_id.0
             );
         }
-        self.task_queue.push(task_id).expect("task queue full");
+        self.task_queues[priority as usize]
+            .push(task_id)
+            .expect("task queue full");
+    }
+
+    pub fn stats(&self) -> ExecutorStats {
+        ExecutorStats {
+            events: self.tracer.and_then(|tracer| tracer.counts()),
+            ..self.stats
+        }
     }
 
     pub fn run(&mut self) -> ! {
@@ -50,54 +294,148 @@ impl Executor {
         }
     }
 
-    fn run_ready_tasks(&mut self) {
+    /// Runs tasks until every spawned task has completed.
+    pub fn run_until_done(&mut self) {
+        loop {
+            self.spawn_new_tasks();
+            if self.tasks.is_empty() {
+                break;
+            }
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Polls tasks until none of them are ready, without waiting for any.
+    pub fn run_ready_tasks(&mut self) {
+        while self.poll_next() {}
+    }
+
+    fn spawn_new_tasks(&mut self) {
+        let new_tasks = self.new_tasks.take();
+        for task in new_tasks {
+            self.spawn(task);
+        }
+    }
+
+    /// Polls the next ready task, returning false if no task was ready.
+    fn poll_next(&mut self) -> bool {
+        self.spawn_new_tasks();
+        let Some(priority) = self.next_priority() else {
+            return false;
+        };
         // Destructuring here lets us use each field mutably without the borrow checker complaining
         let Self {
             tasks,
-            task_queue,
+            task_queues,
             waker_cache,
+            stats,
+            tracer,
+            ..
         } = self;
+        let tracer = *tracer;
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue,
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+        let task_queue = &task_queues[priority];
+        let task_id = task_queue.pop().unwrap();
+        let task = match tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return true,
+        };
+        let waker = waker_cache
+            .entry(task_id)
+            .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone(), tracer));
+        let mut context = Context::from_waker(waker);
+        stats.polls[priority] += 1;
+        if let Some(info) = TASK_TABLE.lock().get_mut(&task_id) {
+            info.polls += 1;
+        }
+        if let Some(tracer) = tracer {
+            tracer.on_poll_start(task_id);
+        }
+        let start = timing_polls().then(read_cycles);
+        let poll = task.poll(&mut context);
+        if let Some(start) = start {
+            if let Some(info) = TASK_TABLE.lock().get_mut(&task_id) {
+                info.cycles += read_cycles() - start;
+            }
+        }
+        if let Some(tracer) = tracer {
+            tracer.on_poll_end(task_id, poll.is_ready());
+        }
+        match poll {
+            Poll::Ready(()) => {
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+                TASK_TABLE.lock().remove(&task_id);
+                if let Some(tracer) = tracer {
+                    tracer.on_complete(task_id);
                 }
-                Poll::Pending => {}
             }
+            Poll::Pending => {}
+        }
+        true
+    }
+
+    /// Picks the priority to poll a task of next. That's the highest priority with a ready task,
+    /// unless a lower priority has been skipped over [`STARVATION_LIMIT`] times in a row.
+    fn next_priority(&mut self) -> Option<usize> {
+        let ready = |priority: &usize| !self.task_queues[*priority].is_empty();
+        let highest = (0..Priority::COUNT).find(ready)?;
+        let priority = (highest + 1..Priority::COUNT)
+            .filter(ready)
+            .find(|&priority| self.skipped[priority] >= STARVATION_LIMIT)
+            .unwrap_or(highest);
+
+        for lower in (priority + 1..Priority::COUNT).filter(ready) {
+            self.skipped[lower] += 1;
         }
+        self.skipped[priority] = 0;
+        Some(priority)
     }
 
     fn sleep_if_idle(&self) {
         // We disable interrupts, otherwise an interrupt might happen between the check and the `hlt` instruction
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        let idle = self.task_queues.iter().all(|queue| queue.is_empty());
+        if idle && self.new_tasks.borrow().is_empty() {
+            let start = timing_polls().then(read_cycles);
             // <-- an interrupt could happen here
             interrupts::enable_and_hlt();
+            if let Some(start) = start {
+                IDLE_CYCLES.fetch_add(read_cycles() - start, Ordering::Relaxed);
+            }
         } else {
             interrupts::enable();
         }
     }
 }
 
+impl Drop for Executor {
+    fn drop(&mut self) {
+        let mut table = TASK_TABLE.lock();
+        for task_id in self.tasks.keys() {
+            table.remove(task_id);
+        }
+    }
+}
+
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(
+        task_id: TaskId,
+        task_queue: Arc<ArrayQueue<TaskId>>,
+        tracer: Option<&'static dyn ExecutorTracer>,
+    ) -> Waker {
         Waker::from(Arc::new(Self {
             task_id,
             task_queue,
+            tracer,
         }))
     }
 
     fn wake_task(&self) {
+        if let Some(tracer) = self.tracer {
+            tracer.on_wake(self.task_id, crate::interrupts::in_handler());
+        }
         self.task_queue.push(self.task_id).expect("task queue full");
     }
 }
@@ -111,3 +449,161 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[test_case]
+fn test_priority_wake_latency() {
+    use super::yield_now;
+    use alloc::vec::Vec;
+    use core::{
+        future::poll_fn,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    let mut executor = Executor::new();
+    for _ in 0..20 {
+        executor.spawn(Task::with_priority(
+            async {
+                loop {
+                    yield_now().await;
+                }
+            },
+            Priority::Low,
+        ));
+    }
+
+    // The high priority task waits for the loop below to wake it, and records how many polls it
+    // took to be polled after that
+    let clock = Arc::new(AtomicUsize::new(0));
+    let woken_at = Arc::new(Mutex::new((None::<usize>, None::<Waker>)));
+    let latencies = Arc::new(Mutex::new(Vec::new()));
+    executor.spawn(Task::with_priority(
+        {
+            let (clock, woken_at, latencies) = (clock.clone(), woken_at.clone(), latencies.clone());
+            async move {
+                loop {
+                    poll_fn(|cx| {
+                        let mut woken_at = woken_at.lock();
+                        match woken_at.0.take() {
+                            Some(time) => {
+                                let now = clock.load(Ordering::Relaxed);
+                                latencies.lock().push(now - time);
+                                Poll::Ready(())
+                            }
+                            None => {
+                                woken_at.1 = Some(cx.waker().clone());
+                                Poll::Pending
+                            }
+                        }
+                    })
+                    .await;
+                }
+            }
+        },
+        Priority::High,
+    ));
+
+    for time in 0..1000 {
+        clock.store(time, Ordering::Relaxed);
+        if time % 50 == 25 {
+            let mut woken_at = woken_at.lock();
+            woken_at.0 = Some(time);
+            if let Some(waker) = woken_at.1.take() {
+                waker.wake();
+            }
+        }
+        assert!(executor.poll_next());
+    }
+
+    let latencies = latencies.lock();
+    assert_eq!(latencies.len(), 20);
+    assert!(latencies.iter().all(|&latency| latency <= 1));
+    let stats = executor.stats();
+    assert_eq!(stats.polls[Priority::High as usize], 21);
+    assert_eq!(stats.polls[Priority::Normal as usize], 0);
+    assert_eq!(stats.polls[Priority::Low as usize], 1000 - 21);
+    // Nothing counts the events without a tracer
+    assert_eq!(stats.events, None);
+}
+
+#[test_case]
+fn test_cpu_accounting() {
+    use super::{deferred, yield_now};
+
+    let accounting = CpuAccounting::start();
+    let mut executor = Executor::new();
+    executor.spawn(Task::with_priority(deferred::run(), Priority::High));
+    let busy = Task::new(async {
+        loop {
+            let start = read_cycles();
+            while read_cycles() - start < 100_000 {
+                core::hint::spin_loop();
+            }
+            yield_now().await;
+        }
+    });
+    executor.spawn(busy.with_name("busy"));
+    let sleepy = Task::new(async {
+        loop {
+            timer::sleep_ticks(2).await;
+        }
+    });
+    executor.spawn(sleepy.with_name("sleepy"));
+    let mut run_for = |ticks: u64| {
+        let end = timer::ticks() + ticks;
+        while timer::ticks() < end {
+            if !executor.poll_next() {
+                executor.sleep_if_idle();
+            }
+        }
+    };
+
+    // Ticks the tasks are timed for, the first of which may already be partly over
+    const TICKS: u64 = 10;
+    run_for(2);
+    let first = accounting.sample();
+    run_for(TICKS);
+    let usage = accounting.sample().usage_since(&first);
+    let task = |name| {
+        let usage = usage
+            .tasks
+            .iter()
+            .find(|task| task.info.name == TaskName(name));
+        *usage.unwrap()
+    };
+    let (busy, sleepy) = (task("busy"), task("sleepy"));
+    // How much of the time the tasks got depends on the host, only how it's shared is certain
+    assert_eq!(usage.tasks[0].info.name, TaskName("busy"));
+    assert!(usage.per_mille(busy.cycles) > usage.per_mille(sleepy.cycles));
+    assert!(busy.cycles > sleepy.cycles * 10);
+    assert!(sleepy.polls > 0 && busy.polls > sleepy.polls);
+    // Half the time that passed at least, leaving room for the cycle counter's calibration
+    let min_millis = (TICKS - 1) * 1000 / u64::from(timer::tick_hz()) / 2;
+    assert!(usage.cycles_to_millis(usage.elapsed).unwrap() >= min_millis);
+    drop(accounting);
+
+    // Nothing is timed while no one watches
+    let before = CpuAccounting::start().sample();
+    run_for(2);
+    let after = CpuAccounting::start().sample();
+    assert_eq!(after.usage_since(&before).tasks[0].cycles, 0);
+}
+
+#[test_case]
+fn test_task_names() {
+    use alloc::format;
+
+    let name = |name| format!("{}", TaskName(name));
+    assert_eq!(
+        name("hannos::statusbar::run::{{closure}}"),
+        "statusbar::run"
+    );
+    assert_eq!(
+        name("hannos::shell::Shell<hannos::shell::terminal::SerialTerminal>::run::{{closure}}"),
+        "shell::Shell::run"
+    );
+    assert_eq!(
+        name("core::future::pending::Pending<fn() -> u8>"),
+        "core::future::pending::Pending"
+    );
+    assert_eq!(name("busy"), "busy");
+}
@@ -0,0 +1,163 @@
+use core::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use super::cancel::CancellationToken;
+use crate::task_local;
+
+task_local! {
+    // The token of the job the task runs, see `CancellationToken::install`
+    static JOB_CANCEL: RefCell<Option<CancellationToken>> = RefCell::new(None);
+}
+
+impl CancellationToken {
+    /// Makes this the token of the job the current task runs, so that code deep inside the job can
+    /// check it with [`cancel_requested`] without it being passed down. Panics outside of a task.
+    pub fn install(&self) {
+        JOB_CANCEL.with(|cancel| *cancel.borrow_mut() = Some(self.clone()));
+    }
+}
+
+/// Whether the job the current task runs has been cancelled, see [`CancellationToken::install`].
+/// Always `false` outside of a task, or in a task which didn't install a token.
+pub fn cancel_requested() -> bool {
+    JOB_CANCEL
+        .try_with(|cancel| {
+            cancel
+                .borrow()
+                .as_ref()
+                .is_some_and(|token| token.is_cancelled())
+        })
+        .unwrap_or(false)
+}
+
+/// Whether `token` or the job the current task runs has been cancelled, for code which is passed
+/// a token but may run inside a job as well, see [`cancel_requested`].
+pub fn cancelled(token: Option<&CancellationToken>) -> bool {
+    cancel_requested() || token.is_some_and(CancellationToken::is_cancelled)
+}
+
+/// Waits for a task started with [`Spawner::spawn`](super::executor::Spawner::spawn) and takes
+/// its output. Dropping the handle doesn't stop the task.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+
+    /// Takes the output of the task if it has finished and the output hasn't been taken yet.
+    pub fn try_take(&mut self) -> Option<T> {
+        self.state.lock().output.take()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None if state.finished => panic!("JoinHandle polled after its output was taken"),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Wraps a future so that its output is handed to the returned [`JoinHandle`].
+pub(super) fn joinable<F: Future>(future: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>) {
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        finished: false,
+        waker: None,
+    }));
+    let handle = JoinHandle {
+        state: state.clone(),
+    };
+    let task = async move {
+        let output = future.await;
+        let waker = {
+            let mut state = state.lock();
+            state.output = Some(output);
+            state.finished = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    };
+    (task, handle)
+}
+
+#[test_case]
+fn test_join_handle() {
+    use super::{executor::Executor, yield_now, Priority};
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let mut handle = spawner.spawn(
+        async {
+            yield_now().await;
+            42
+        },
+        Priority::Normal,
+    );
+    assert!(!handle.is_finished());
+    // A task spawned by a running task can be waited for by it
+    let inner = spawner.clone();
+    let outer = spawner.spawn(
+        async move { inner.spawn(async { 1 }, Priority::Low).await + 1 },
+        Priority::Normal,
+    );
+    executor.run_until_done();
+
+    assert!(handle.is_finished());
+    assert_eq!(handle.try_take(), Some(42));
+    assert_eq!(handle.try_take(), None);
+    assert_eq!(outer.state.lock().output, Some(2));
+}
+
+#[test_case]
+fn test_cancellation_token() {
+    use super::{executor::Executor, select2, Either, Priority};
+
+    let token = CancellationToken::new();
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let job = {
+        let token = token.clone();
+        spawner.spawn(
+            async move {
+                match select2(core::future::pending::<()>(), token.cancelled()).await {
+                    Either::Left(()) => false,
+                    Either::Right(()) => token.is_cancelled(),
+                }
+            },
+            Priority::Normal,
+        )
+    };
+    executor.run_ready_tasks();
+    assert!(!job.is_finished());
+    token.cancel();
+    executor.run_until_done();
+    assert!(job.is_finished());
+    assert_eq!(job.state.lock().output, Some(true));
+}
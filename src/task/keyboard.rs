@@ -1,42 +1,220 @@
-use crate::print;
 use core::{
+    future::poll_fn,
     pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
     task::{Context, Poll},
 };
 
-use crate::println;
-
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use futures_util::{task::AtomicWaker, Stream, StreamExt as _};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1,
+    ScancodeSet2,
+};
+use spin::Mutex;
+use thiserror_no_std::Error;
+use x86_64::instructions::interrupts;
+
+use super::{
+    deferred::{self, WorkItem},
+    time,
+};
+use crate::{
+    cmdline,
+    console::{self, Pane},
+    io::{self, PS2_DATA, PS2_STATUS},
+    log,
+    log::LogLevel,
+    print, print_warn, timer, vgabuf,
+};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the queue the keyboard interrupt handler puts scancodes in. Calling it again has no
+/// effect.
+pub fn init() {
+    let _ = SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100));
+    crate::mark_initialized(crate::InitFlags::KEYBOARD);
+}
 
 pub(crate) fn add_scancode(scancode: u8) {
+    // The answer to a command we sent isn't a key
+    if matches!(scancode, ACK | RESEND) && AWAITING_RESPONSE.swap(false, Ordering::SeqCst) {
+        RESPONSE.store(scancode, Ordering::SeqCst);
+        RESPONSE_WAKER.wake();
+        return;
+    }
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            print_warn!("scancode queue full; dropping keyboard input");
         } else {
             WAKER.wake();
         }
     } else {
-        println!("WARNING: keyboard scancode queue uninitialized");
+        print_warn!("keyboard scancode queue uninitialized");
+    }
+}
+
+static MODIFIERS: Mutex<Modifiers> = Mutex::new(Modifiers::new());
+
+/// The modifier keys held down when a key was pressed, and the state of the lock keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    /// NumLock starts out on, matching the decoder in `pc_keyboard`.
+    const fn new() -> Self {
+        Self {
+            shift: false,
+            ctrl: false,
+            alt: false,
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    /// Updates the modifiers from a raw key event. Returns `true` if a lock key was toggled.
+    fn update(&mut self, event: &KeyEvent) -> bool {
+        let pressed = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => self.shift = pressed,
+            KeyCode::ControlLeft | KeyCode::ControlRight => self.ctrl = pressed,
+            KeyCode::AltLeft | KeyCode::AltRight => self.alt = pressed,
+            KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if pressed => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if pressed => self.scroll_lock = !self.scroll_lock,
+            _ => return false,
+        }
+        matches!(
+            event.code,
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock
+        )
+    }
+
+    /// The keyboard LED bitmask for the lock keys.
+    fn leds(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
     }
 }
 
+impl Default for Modifiers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the current modifiers and lock key state.
+pub fn modifiers() -> Modifiers {
+    *MODIFIERS.lock()
+}
+
+// Status register bits
+const INPUT_FULL: u8 = 1 << 1;
+
+const SET_LEDS: u8 = 0xed;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+/// Stored in [`RESPONSE`] until the keyboard answers.
+const NO_RESPONSE: u8 = 0;
+
+/// Number of status register polls before giving up on the keyboard controller taking a byte,
+/// about a microsecond apart.
+const PS2_TIMEOUT: usize = 100_000;
+/// How long the keyboard has to answer a command byte.
+const ACK_TIMEOUT_MS: u64 = 50;
+/// Number of times a byte is resent when the keyboard asks for it.
+const PS2_RETRIES: usize = 3;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KeyboardError {
+    #[error("keyboard did not respond")]
+    Timeout,
+    #[error("keyboard did not acknowledge command (response {0:#x})")]
+    NoAck(u8),
+    #[error("the scancode stream is already in use")]
+    StreamTaken,
+}
+
+/// Set while a command byte waits for the keyboard's answer, which the interrupt handler then puts
+/// in [`RESPONSE`] instead of the scancode queue.
+static AWAITING_RESPONSE: AtomicBool = AtomicBool::new(false);
+static RESPONSE: AtomicU8 = AtomicU8::new(NO_RESPONSE);
+static RESPONSE_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Requested LED state for the [`update_leds`] task, set by [`request_leds`].
+static LEDS: AtomicU8 = AtomicU8::new(0);
+static LEDS_CHANGED: AtomicBool = AtomicBool::new(false);
+static LEDS_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Sends a byte to the keyboard and waits for it to be acknowledged, giving up after
+/// [`ACK_TIMEOUT_MS`].
+async fn send_keyboard_byte(byte: u8) -> Result<(), KeyboardError> {
+    for _ in 0..PS2_RETRIES {
+        let ready = (0..PS2_TIMEOUT).any(|_| {
+            let ready = PS2_STATUS.read() & INPUT_FULL == 0;
+            if !ready {
+                io::io_wait();
+            }
+            ready
+        });
+        if !ready {
+            return Err(KeyboardError::Timeout);
+        }
+        interrupts::without_interrupts(|| {
+            RESPONSE.store(NO_RESPONSE, Ordering::SeqCst);
+            AWAITING_RESPONSE.store(true, Ordering::SeqCst);
+            PS2_DATA.write(byte);
+        });
+        match wait_for_response(timer::millis_to_ticks(ACK_TIMEOUT_MS)).await? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            response => return Err(KeyboardError::NoAck(response)),
+        }
+    }
+    Err(KeyboardError::NoAck(RESEND))
+}
+
+/// Waits for the interrupt handler to receive the keyboard's answer to a command, for at most
+/// `ticks` timer ticks.
+async fn wait_for_response(ticks: u64) -> Result<u8, KeyboardError> {
+    let response = poll_fn(|cx| {
+        RESPONSE_WAKER.register(cx.waker());
+        match RESPONSE.swap(NO_RESPONSE, Ordering::SeqCst) {
+            NO_RESPONSE => Poll::Pending,
+            response => Poll::Ready(response),
+        }
+    });
+    let result = time::timeout(ticks, response).await;
+    AWAITING_RESPONSE.store(false, Ordering::SeqCst);
+    result.map_err(|_| KeyboardError::Timeout)
+}
+
 // The `_private` field is here to make the `new` function the only way to create the struct
 pub struct ScancodeStream {
     _private: (),
 }
 
 impl ScancodeStream {
-    pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once!");
-        ScancodeStream { _private: () }
+    /// Takes the stream of scancodes. There can only be one, as every scancode is only received
+    /// once.
+    pub fn new() -> Result<Self, KeyboardError> {
+        if STREAM_TAKEN.swap(true, Ordering::SeqCst) {
+            return Err(KeyboardError::StreamTaken);
+        }
+        init();
+        Ok(ScancodeStream { _private: () })
     }
 }
 
@@ -64,31 +242,634 @@ impl Stream for ScancodeStream {
     }
 }
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+/// Capacity of the queue of each keyboard subscriber, and of the queue of keypresses waiting for a
+/// subscriber to take focus.
+const KEYPRESS_QUEUE_SIZE: usize = 100;
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(keyevent)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(keyevent) {
-                match key {
-                    DecodedKey::Unicode(c) => print!("{}", c),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+static SUBSCRIPTIONS: Mutex<Subscriptions> = Mutex::new(Subscriptions::new());
+
+/// A decoded keypress along with the modifiers held down at the time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub key: DecodedKey,
+    pub modifiers: Modifiers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SubscriberId(u64);
+
+struct KeyChannel {
+    queue: ArrayQueue<KeyPress>,
+    waker: AtomicWaker,
+}
+
+/// All keyboard subscribers, and the stack of subscribers which have held the focus. The
+/// subscriber on top of the stack receives all keypresses, unless the log pane of a split screen
+/// has the focus and a subscriber takes the keys for it.
+struct Subscriptions {
+    subscribers: Vec<(SubscriberId, Arc<KeyChannel>)>,
+    focus: Vec<SubscriberId>,
+    log_pane: Option<SubscriberId>,
+    // Keypresses arriving while no one holds the focus, delivered to the next focus holder
+    pending: VecDeque<KeyPress>,
+}
+
+impl Subscriptions {
+    const fn new() -> Self {
+        Self {
+            subscribers: Vec::new(),
+            focus: Vec::new(),
+            log_pane: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn channel(&self, id: SubscriberId) -> Option<&Arc<KeyChannel>> {
+        self.subscribers
+            .iter()
+            .find(|(sub_id, _)| *sub_id == id)
+            .map(|(_, channel)| channel)
+    }
+
+    fn dispatch(&mut self, keypress: KeyPress) {
+        let focus = match console::focused_pane() {
+            Pane::Log => self.log_pane.or(self.focus.last().copied()),
+            Pane::Console => self.focus.last().copied(),
+        };
+        let channel = match focus.and_then(|id| self.channel(id)) {
+            Some(channel) => channel,
+            None => {
+                if self.pending.len() < KEYPRESS_QUEUE_SIZE {
+                    self.pending.push_back(keypress);
+                } else {
+                    print_warn!(
+                        "no keyboard focus and pending queue full; dropping keyboard input"
+                    );
                 }
+                return;
             }
+        };
+
+        if channel.queue.push(keypress).is_err() {
+            print_warn!("keypress queue full; dropping keyboard input");
+        } else {
+            channel.waker.wake();
         }
     }
 }
 
-pub async fn process_keypresses(mut key_press_handler: impl FnMut(DecodedKey)) {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+/// Receives the keypresses routed to it while it holds the keyboard focus.
+pub struct KeySubscriber {
+    id: SubscriberId,
+    channel: Arc<KeyChannel>,
+}
+
+/// Keeps the keyboard focus with a subscriber until dropped, after which the focus returns to
+/// whoever held it before.
+pub struct FocusGuard {
+    id: SubscriberId,
+}
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(keyevent)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(keyevent) {
-                key_press_handler(key);
+/// Keeps the keys typed in the log pane going to a subscriber until dropped, see
+/// [`KeySubscriber::acquire_log_pane`].
+pub struct LogPaneGuard {
+    id: SubscriberId,
+}
+
+/// Registers a new keyboard subscriber. It receives no keypresses until it acquires the focus.
+pub fn subscribe() -> KeySubscriber {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = SubscriberId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let channel = Arc::new(KeyChannel {
+        queue: ArrayQueue::new(KEYPRESS_QUEUE_SIZE),
+        waker: AtomicWaker::new(),
+    });
+    SUBSCRIPTIONS.lock().subscribers.push((id, channel.clone()));
+    KeySubscriber { id, channel }
+}
+
+impl KeySubscriber {
+    /// Routes all keypresses to this subscriber until the returned guard is dropped.
+    pub fn acquire_focus(&self) -> FocusGuard {
+        let mut subscriptions = SUBSCRIPTIONS.lock();
+        subscriptions.focus.push(self.id);
+        while let Some(keypress) = subscriptions.pending.pop_front() {
+            subscriptions.dispatch(keypress);
+        }
+        FocusGuard { id: self.id }
+    }
+
+    /// Routes the keypresses typed while the log pane of a split screen has the focus to this
+    /// subscriber until the returned guard is dropped, see [`console::split`]. Without one they go
+    /// to the subscriber holding the focus.
+    pub fn acquire_log_pane(&self) -> LogPaneGuard {
+        SUBSCRIPTIONS.lock().log_pane = Some(self.id);
+        LogPaneGuard { id: self.id }
+    }
+
+    /// Returns the next keypress if one is available, without waiting.
+    pub fn try_next(&self) -> Option<KeyPress> {
+        self.channel.queue.pop()
+    }
+}
+
+impl Stream for KeySubscriber {
+    type Item = KeyPress;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(keypress) = self.channel.queue.pop() {
+            return Poll::Ready(Some(keypress));
+        }
+
+        self.channel.waker.register(cx.waker());
+        match self.channel.queue.pop() {
+            Some(keypress) => {
+                self.channel.waker.take();
+                Poll::Ready(Some(keypress))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for KeySubscriber {
+    fn drop(&mut self) {
+        let mut subscriptions = SUBSCRIPTIONS.lock();
+        subscriptions.subscribers.retain(|(id, _)| *id != self.id);
+        subscriptions.focus.retain(|id| *id != self.id);
+        if subscriptions.log_pane == Some(self.id) {
+            subscriptions.log_pane = None;
+        }
+    }
+}
+
+impl Drop for LogPaneGuard {
+    fn drop(&mut self) {
+        let mut subscriptions = SUBSCRIPTIONS.lock();
+        if subscriptions.log_pane == Some(self.id) {
+            subscriptions.log_pane = None;
+        }
+    }
+}
+
+impl Drop for FocusGuard {
+    fn drop(&mut self) {
+        // Guards are usually dropped in reverse order, but remove the right entry even if not
+        let mut subscriptions = SUBSCRIPTIONS.lock();
+        if let Some(pos) = subscriptions.focus.iter().rposition(|id| *id == self.id) {
+            subscriptions.focus.remove(pos);
+        }
+    }
+}
+
+/// The scancode sets the keyboard can send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSetKind {
+    /// Also what the PS/2 controller translates set 2 into, the usual case.
+    Set1,
+    Set2,
+}
+
+/// A decoder for the selected scancode set, with the US layout.
+enum Decoder {
+    Set1(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Set2(Keyboard<layouts::Us104Key, ScancodeSet2>),
+}
+
+impl Decoder {
+    fn new(set: ScancodeSetKind) -> Self {
+        match set {
+            ScancodeSetKind::Set1 => Self::Set1(Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet1,
+                HandleControl::Ignore,
+            )),
+            ScancodeSetKind::Set2 => Self::Set2(Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet2,
+                HandleControl::Ignore,
+            )),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, pc_keyboard::Error> {
+        match self {
+            Self::Set1(keyboard) => keyboard.add_byte(byte),
+            Self::Set2(keyboard) => keyboard.add_byte(byte),
+        }
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Self::Set1(keyboard) => keyboard.process_keyevent(event),
+            Self::Set2(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+}
+
+/// Minimum time between two log messages about scancodes which can't be decoded.
+const DECODE_ERROR_LOG_INTERVAL_MS: u64 = 5000;
+
+static DECODE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of bytes from the keyboard which couldn't be decoded since boot. A keyboard sending
+/// a different scancode set than the one selected shows up here.
+pub fn decode_errors() -> u64 {
+    DECODE_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Decodes scancodes into keypresses and routes them to the subscriber holding the focus.
+pub struct KeyboardRouter {
+    keyboard: Decoder,
+    modifiers: Modifiers,
+    // When a decode error was last logged
+    last_error_log: Option<u64>,
+    // Set by a Ctrl+Q while output isn't paused, so that the next keypress isn't taken for flow
+    // control
+    quote_next: bool,
+}
+
+impl KeyboardRouter {
+    /// Creates a router for the scancode set selected on the kernel command line.
+    pub fn new() -> Self {
+        Self::with_scancode_set(cmdline::args().scancodes)
+    }
+
+    pub fn with_scancode_set(set: ScancodeSetKind) -> Self {
+        let mut router = Self {
+            keyboard: Decoder::new(set),
+            modifiers: Modifiers::new(),
+            last_error_log: None,
+            quote_next: false,
+        };
+        // Make the LEDs match the initial lock state
+        router.queue_led_update();
+        router
+    }
+
+    pub fn handle_scancode(&mut self, scancode: u8) {
+        // Acknowledgements of our commands can reach the interrupt handler, they aren't keys
+        if scancode == ACK {
+            return;
+        }
+        let keyevent = match self.keyboard.add_byte(scancode) {
+            Ok(Some(keyevent)) => keyevent,
+            Ok(None) => return,
+            Err(err) => return self.decode_error(scancode, err),
+        };
+        if self.modifiers.update(&keyevent) {
+            self.queue_led_update();
+        }
+        *MODIFIERS.lock() = self.modifiers;
+        if let Some(key) = self.keyboard.process_keyevent(keyevent) {
+            let keypress = KeyPress {
+                key,
+                modifiers: self.modifiers,
+            };
+            if !self.flow_control(keypress) && !self.switch_pane(keypress) {
+                SUBSCRIPTIONS.lock().dispatch(keypress);
             }
         }
     }
+
+    /// Takes Ctrl+S and Ctrl+Q before the subscriber holding the focus sees them, to pause and
+    /// resume console output, see [`console::pause`]. Returns `true` if the keypress was taken.
+    ///
+    /// A Ctrl+Q while output isn't paused lets the next keypress through as it is, so pressing it
+    /// twice sends a Ctrl+Q, and Ctrl+Q followed by Ctrl+S sends a Ctrl+S.
+    fn flow_control(&mut self, keypress: KeyPress) -> bool {
+        let quoted = core::mem::take(&mut self.quote_next);
+        let DecodedKey::Unicode(c) = keypress.key else {
+            return false;
+        };
+        if !keypress.modifiers.ctrl || quoted {
+            return false;
+        }
+        match c.to_ascii_lowercase() {
+            's' => console::pause(),
+            'q' if console::is_paused() => console::resume(),
+            'q' => self.quote_next = true,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Takes F6 while the screen is split, to give the focus to the other pane, see
+    /// [`console::toggle_focus`]. Returns `true` if the keypress was taken.
+    fn switch_pane(&self, keypress: KeyPress) -> bool {
+        if keypress.key != DecodedKey::RawKey(KeyCode::F6) || !vgabuf::is_split() {
+            return false;
+        }
+        console::toggle_focus();
+        true
+    }
+
+    /// Counts a byte which couldn't be decoded, and logs it unless another one was logged
+    /// recently.
+    fn decode_error(&mut self, scancode: u8, err: pc_keyboard::Error) {
+        let errors = DECODE_ERRORS.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = timer::millis();
+        if self
+            .last_error_log
+            .is_some_and(|last| now - last < DECODE_ERROR_LOG_INTERVAL_MS)
+        {
+            return;
+        }
+        self.last_error_log = Some(now);
+        log!(
+            LogLevel::Warn,
+            "keyboard: can't decode scancode {:#04x} ({:?}), {} bytes failed to decode so far",
+            scancode,
+            err,
+            errors
+        );
+    }
+
+    /// Hands the LEDs to the deferred work task, which passes them on to the [`update_leds`] task.
+    /// Routing keys doesn't wait while that task sends them and awaits the keyboard's
+    /// acknowledgement, which times out if the keyboard doesn't respond.
+    fn queue_led_update(&mut self) {
+        if let Err(err) = deferred::submit(WorkItem::SetLeds(self.modifiers.leds())) {
+            log!(
+                LogLevel::Warn,
+                "failed to queue a keyboard LED update: {}",
+                err
+            );
+        }
+    }
+}
+
+impl Default for KeyboardRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Asks the [`update_leds`] task to set the keyboard LEDs. Only the latest request is kept.
+pub(crate) fn request_leds(leds: u8) {
+    LEDS.store(leds, Ordering::SeqCst);
+    LEDS_CHANGED.store(true, Ordering::SeqCst);
+    LEDS_WAKER.wake();
+}
+
+/// Sets the keyboard LEDs as they're requested. If the keyboard doesn't respond the update is
+/// dropped, so a missing keyboard can't hang the task. There must be at most one of these tasks
+/// running.
+pub async fn update_leds() {
+    loop {
+        poll_fn(|cx| {
+            LEDS_WAKER.register(cx.waker());
+            match LEDS_CHANGED.swap(false, Ordering::SeqCst) {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+        .await;
+        let leds = LEDS.load(Ordering::SeqCst);
+        for byte in [SET_LEDS, leds] {
+            if let Err(err) = send_keyboard_byte(byte).await {
+                log!(LogLevel::Warn, "failed to update keyboard LEDs: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Consumes the scancodes from the keyboard interrupt handler and routes them as keypresses. There
+/// must be exactly one of these tasks running.
+pub async fn route_keypresses() {
+    let mut scancodes = ScancodeStream::new().expect("keypresses are already being routed");
+    let mut router = KeyboardRouter::new();
+
+    while let Some(scancode) = scancodes.next().await {
+        router.handle_scancode(scancode);
+    }
+}
+
+pub async fn print_keypresses() {
+    let mut keypresses = subscribe();
+    let _focus = keypresses.acquire_focus();
+
+    while let Some(KeyPress { key, .. }) = keypresses.next().await {
+        match key {
+            DecodedKey::Unicode(c) => print!("{}", c),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        }
+    }
+}
+
+/// Calls the handler for every keypress while holding the keyboard focus.
+pub async fn process_keypresses(mut key_press_handler: impl FnMut(DecodedKey, Modifiers)) {
+    let mut keypresses = subscribe();
+    let _focus = keypresses.acquire_focus();
+
+    while let Some(KeyPress { key, modifiers }) = keypresses.next().await {
+        key_press_handler(key, modifiers);
+    }
+}
+
+#[cfg(test)]
+fn drain(subscriber: &KeySubscriber) -> Vec<DecodedKey> {
+    core::iter::from_fn(|| subscriber.try_next())
+        .map(|keypress| keypress.key)
+        .collect()
+}
+
+#[test_case]
+fn test_focus_routing() {
+    let mut router = KeyboardRouter::new();
+    let shell = subscribe();
+    let editor = subscribe();
+
+    let _base = shell.acquire_focus();
+    // Press and release 'a', 'b' and 'c'
+    router.handle_scancode(0x1e);
+    router.handle_scancode(0x9e);
+    {
+        let _focus = editor.acquire_focus();
+        router.handle_scancode(0x30);
+        router.handle_scancode(0xb0);
+    }
+    router.handle_scancode(0x2e);
+    router.handle_scancode(0xae);
+
+    assert_eq!(
+        drain(&shell),
+        [DecodedKey::Unicode('a'), DecodedKey::Unicode('c')]
+    );
+    assert_eq!(drain(&editor), [DecodedKey::Unicode('b')]);
+}
+
+#[test_case]
+fn test_keypresses_without_focus_are_kept() {
+    let mut router = KeyboardRouter::new();
+    let subscriber = subscribe();
+    router.handle_scancode(0x1e);
+    assert_eq!(drain(&subscriber), []);
+
+    let _focus = subscriber.acquire_focus();
+    assert_eq!(drain(&subscriber), [DecodedKey::Unicode('a')]);
+}
+
+#[test_case]
+fn test_lock_keys_queue_led_updates() {
+    fn queued_leds() -> Vec<u8> {
+        deferred::take_pending()
+            .into_iter()
+            .filter_map(|item| match item {
+                WorkItem::SetLeds(leds) => Some(leds),
+                _ => None,
+            })
+            .collect()
+    }
+
+    deferred::init();
+    deferred::take_pending();
+    let mut router = KeyboardRouter::new();
+    // NumLock starts out on
+    assert_eq!(queued_leds(), [0b010]);
+
+    // Press and release CapsLock, then ScrollLock, then NumLock
+    for scancode in [0x3a, 0xba, 0x46, 0xc6, 0x45, 0xc5] {
+        router.handle_scancode(scancode);
+    }
+    assert_eq!(queued_leds(), [0b110, 0b111, 0b101]);
+    assert!(modifiers().caps_lock && !modifiers().num_lock && modifiers().scroll_lock);
+
+    // Other keys and acknowledgements don't touch the LEDs
+    for scancode in [0x1e, 0x9e, 0x2a, 0xaa, ACK] {
+        router.handle_scancode(scancode);
+    }
+    assert!(queued_leds().is_empty());
+}
+
+#[test_case]
+fn test_response_timeout() {
+    use super::block_on;
+
+    // No answer arrives
+    AWAITING_RESPONSE.store(true, Ordering::SeqCst);
+    let start = timer::ticks();
+    assert_eq!(block_on(wait_for_response(3)), Err(KeyboardError::Timeout));
+    assert!(timer::ticks() >= start + 3);
+    assert!(!AWAITING_RESPONSE.load(Ordering::SeqCst));
+
+    // The interrupt handler takes the answer, which doesn't reach the scancode queue
+    init();
+    let queue = SCANCODE_QUEUE.try_get().unwrap();
+    while queue.pop().is_some() {}
+    interrupts::without_interrupts(|| {
+        AWAITING_RESPONSE.store(true, Ordering::SeqCst);
+        add_scancode(RESEND);
+    });
+    assert_eq!(block_on(wait_for_response(3)), Ok(RESEND));
+    assert!(queue.pop().is_none());
+    // Without a command waiting it's queued like any other scancode
+    add_scancode(ACK);
+    assert_eq!(queue.pop(), Some(ACK));
+}
+
+#[test_case]
+fn test_scancode_set_2() {
+    let mut router = KeyboardRouter::with_scancode_set(ScancodeSetKind::Set2);
+    let subscriber = subscribe();
+    let _focus = subscriber.acquire_focus();
+    // 'a', then 'b' with shift held down, then the up arrow, which has an extended code
+    for scancode in [
+        0x1c, 0xf0, 0x1c, 0x12, 0x32, 0xf0, 0x32, 0xf0, 0x12, 0xe0, 0x75, 0xe0, 0xf0, 0x75,
+    ] {
+        router.handle_scancode(scancode);
+    }
+    assert_eq!(
+        drain(&subscriber),
+        [
+            DecodedKey::Unicode('a'),
+            DecodedKey::Unicode('B'),
+            DecodedKey::RawKey(KeyCode::ArrowUp)
+        ]
+    );
+}
+
+#[test_case]
+fn test_undecodable_bytes_are_counted() {
+    let mut router = KeyboardRouter::with_scancode_set(ScancodeSetKind::Set1);
+    let subscriber = subscribe();
+    let _focus = subscriber.acquire_focus();
+    let before = decode_errors();
+    // Two bytes which aren't keys in set 1, then 'a' pressed and released
+    for scancode in [0x60, 0x7f, 0x1e, 0x9e] {
+        router.handle_scancode(scancode);
+    }
+    assert_eq!(decode_errors() - before, 2);
+    assert_eq!(drain(&subscriber), [DecodedKey::Unicode('a')]);
+}
+
+#[test_case]
+fn test_flow_control_keys_are_taken() {
+    let mut router = KeyboardRouter::with_scancode_set(ScancodeSetKind::Set1);
+    let subscriber = subscribe();
+    let _focus = subscriber.acquire_focus();
+    // Presses and releases a letter with Ctrl held down
+    let mut ctrl = |letter: u8| {
+        for scancode in [0x1d, letter, letter | 0x80, 0x9d] {
+            router.handle_scancode(scancode);
+        }
+    };
+    let (s, q) = (0x1f, 0x10);
+
+    ctrl(s);
+    assert!(console::is_paused());
+    ctrl(q);
+    assert!(!console::is_paused());
+    assert_eq!(drain(&subscriber), []);
+
+    // Ctrl+Q while not paused lets the next Ctrl+Q or Ctrl+S through
+    ctrl(q);
+    ctrl(q);
+    ctrl(q);
+    ctrl(s);
+    assert!(!console::is_paused());
+    assert_eq!(
+        drain(&subscriber),
+        [DecodedKey::Unicode('q'), DecodedKey::Unicode('s')]
+    );
+}
+
+#[test_case]
+fn test_split_screen_focus_routing() {
+    let mut router = KeyboardRouter::with_scancode_set(ScancodeSetKind::Set1);
+    let shell = subscribe();
+    let log_pane = subscribe();
+    let _focus = shell.acquire_focus();
+    let _pane = log_pane.acquire_log_pane();
+    let mut press = |scancode: u8| {
+        router.handle_scancode(scancode);
+        router.handle_scancode(scancode | 0x80);
+    };
+    let (a, b, c, f6) = (0x1e, 0x30, 0x2e, 0x40);
+
+    // F6 is an ordinary key until the screen is split
+    press(f6);
+    assert_eq!(drain(&shell), [DecodedKey::RawKey(KeyCode::F6)]);
+
+    console::split();
+    press(a);
+    press(f6);
+    assert_eq!(console::focused_pane(), Pane::Log);
+    press(b);
+    press(f6);
+    press(c);
+    assert_eq!(
+        drain(&shell),
+        [DecodedKey::Unicode('a'), DecodedKey::Unicode('c')]
+    );
+    assert_eq!(drain(&log_pane), [DecodedKey::Unicode('b')]);
+
+    // Joining the panes gives the focus back to the console
+    press(f6);
+    console::unsplit();
+    assert_eq!(console::focused_pane(), Pane::Console);
+    press(a);
+    assert_eq!(drain(&shell), [DecodedKey::Unicode('a')]);
+    assert_eq!(drain(&log_pane), []);
 }
@@ -0,0 +1,158 @@
+use core::{
+    any::Any,
+    cell::RefCell,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use alloc::{boxed::Box, collections::BTreeMap};
+
+// The task-local values of the task being polled, or null if no task is
+static CURRENT: AtomicPtr<LocalMap> = AtomicPtr::new(ptr::null_mut());
+
+/// Declares task-local values, like `thread_local!` does for threads. Each task sees its own value
+/// of a key, created with the initializer the first time the task uses it, and dropped along with
+/// the task when it completes.
+///
+/// ```ignore
+/// task_local! {
+///     static COUNT: Cell<usize> = Cell::new(0);
+/// }
+/// COUNT.with(|count| count.set(count.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::task::local::LocalKey<$ty> =
+                $crate::task::local::LocalKey::new(|| $init);
+        )*
+    };
+}
+
+/// A task-local value declared with [`task_local!`](crate::task_local). Values can't be changed
+/// once created, so ones which change are kept in a `Cell` or `RefCell`.
+pub struct LocalKey<T: 'static> {
+    init: fn() -> T,
+}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> T) -> Self {
+        Self { init }
+    }
+
+    /// Calls `f` with the current task's value. Panics outside of a task.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_with(f)
+            .expect("task-local value used outside of a task")
+    }
+
+    /// Calls `f` with the current task's value, or returns `None` outside of a task.
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        // The values of a task live as long as the task, which is being polled
+        let locals = unsafe { CURRENT.load(Ordering::Relaxed).as_ref()? };
+        Some(f(locals.get_or_init(self)))
+    }
+
+    /// The key of the value in the maps, the address of the static is unique to it.
+    fn id(&'static self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+/// The task-local values of a task, by the address of their key.
+#[derive(Default)]
+pub struct LocalMap {
+    values: RefCell<BTreeMap<usize, Box<dyn Any>>>,
+}
+
+impl LocalMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes these the values seen by [`LocalKey::with`] until [`leave`](Self::leave) is called
+    /// with the returned values, which are those of the task being polled before, if any.
+    pub fn enter(&self) -> *mut LocalMap {
+        CURRENT.swap(self as *const Self as *mut Self, Ordering::Relaxed)
+    }
+
+    pub fn leave(outer: *mut LocalMap) {
+        CURRENT.store(outer, Ordering::Relaxed);
+    }
+
+    fn get_or_init<T: 'static>(&self, key: &'static LocalKey<T>) -> &T {
+        let id = key.id();
+        let existing = self
+            .values
+            .borrow()
+            .get(&id)
+            .map(|value| value_ptr::<T>(value));
+        let value = match existing {
+            Some(value) => value,
+            None => {
+                // The initializer may use other task-local values, so the map isn't borrowed
+                // while it runs
+                let value: Box<dyn Any> = Box::new((key.init)());
+                let mut values = self.values.borrow_mut();
+                value_ptr(values.entry(id).or_insert(value))
+            }
+        };
+        // Values are boxed and only removed when the map is dropped, so they don't move while
+        // the map is borrowed
+        unsafe { &*value }
+    }
+}
+
+fn value_ptr<T: 'static>(value: &Box<dyn Any>) -> *const T {
+    value
+        .downcast_ref::<T>()
+        .expect("task-local value of the wrong type")
+}
+
+#[test_case]
+fn test_task_locals() {
+    use super::{executor::Executor, yield_now, Task};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::Cell;
+
+    struct DropCounter(Rc<Cell<usize>>);
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+    task_local! {
+        static NAME: Cell<&'static str> = Cell::new("unnamed");
+        static GUARD: RefCell<Option<DropCounter>> = RefCell::new(None);
+    }
+
+    // Two tasks taking turns each keep their own value
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let drops = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    for name in ["a", "b"] {
+        let (seen, drops) = (seen.clone(), drops.clone());
+        executor.spawn(Task::new(async move {
+            assert_eq!(NAME.with(Cell::get), "unnamed");
+            NAME.with(|value| value.set(name));
+            GUARD.with(|guard| *guard.borrow_mut() = Some(DropCounter(drops)));
+            yield_now().await;
+            seen.borrow_mut().push(NAME.with(Cell::get));
+        }));
+    }
+    executor.run_until_done();
+    assert_eq!(*seen.borrow(), ["a", "b"]);
+    // The values were dropped along with the tasks
+    assert_eq!(drops.get(), 2);
+
+    // Outside of a task there are no values, and a new task starts over
+    assert_eq!(NAME.try_with(Cell::get), None);
+    executor.spawn(Task::new(async {
+        assert_eq!(NAME.with(Cell::get), "unnamed");
+        assert!(GUARD.with(|guard| guard.borrow().is_none()));
+    }));
+    executor.run_until_done();
+}
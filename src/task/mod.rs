@@ -1,34 +1,226 @@
 use core::{
+    fmt::{self, Write},
     future::Future,
-    pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
-    task::{Context, Poll},
+    pin::{pin, Pin},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
 };
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+use x86_64::instructions::interrupts;
 
+use self::local::LocalMap;
+use crate::util::fmt::FmtBuf;
+
+pub mod cancel;
+pub mod deferred;
 pub mod executor;
+pub mod job;
 pub mod keyboard;
+pub mod local;
+pub mod serial;
 pub mod simple_executor;
+pub mod time;
+pub mod trace;
 
 pub struct Task {
     id: TaskId,
+    priority: Priority,
+    name: TaskName,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    /// The task-local values, dropped after the future when the task completes.
+    locals: LocalMap,
 }
 
+/// How urgently a task should be polled once woken. Ready tasks of a higher priority are polled
+/// first, but lower priorities still get polled now and then so they can't be starved.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub enum Priority {
+    /// Input handling, which should react to keypresses right away.
+    High = 0,
+    Normal = 1,
+    /// Background work.
+    Low = 2,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(pub u64);
+
+/// What a task is called in task listings, by default the type of its future, like
+/// `hannos::statusbar::run::{{closure}}`. It's shown without the crate, generic arguments and
+/// closures, like `statusbar::run`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskName(pub &'static str);
+
+impl fmt::Display for TaskName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Generic arguments are dropped first, as they're paths themselves
+        let mut path = FmtBuf::<128>::new();
+        let (mut depth, mut prev) = (0usize, ' ');
+        for c in self.0.chars() {
+            match c {
+                '<' => depth += 1,
+                '>' if prev != '-' => depth = depth.saturating_sub(1),
+                _ if depth == 0 => path.write_char(c)?,
+                _ => {}
+            }
+            prev = c;
+        }
+        let path = path.as_str();
+        let path = path
+            .strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::"))
+            .unwrap_or(path);
+        let mut name = FmtBuf::<128>::new();
+        let segments = path.split("::").filter(|segment| *segment != "{{closure}}");
+        for (i, segment) in segments.enumerate() {
+            if i > 0 {
+                name.write_str("::")?;
+            }
+            name.write_str(segment)?;
+        }
+        f.pad(name.as_str())
+    }
+}
+
+// The task being polled, or `u64::MAX` if none is
+static CURRENT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Returns the task which is being polled, if any.
+pub fn current() -> Option<TaskId> {
+    match CURRENT.load(Ordering::Relaxed) {
+        u64::MAX => None,
+        id => Some(TaskId(id)),
+    }
+}
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Self::with_priority(future, Priority::Normal)
+    }
+
+    pub fn with_priority<F: Future<Output = ()> + 'static>(future: F, priority: Priority) -> Self {
         Self {
             id: TaskId::new(),
+            priority,
+            name: TaskName(core::any::type_name::<F>()),
             future: Box::pin(future),
+            locals: LocalMap::new(),
         }
     }
 
+    /// Names the task something other than the type of its future.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = TaskName(name);
+        self
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        let outer = CURRENT.swap(self.id.0, Ordering::Relaxed);
+        let outer_locals = self.locals.enter();
+        let result = self.future.as_mut().poll(context);
+        LocalMap::leave(outer_locals);
+        CURRENT.store(outer, Ordering::Relaxed);
+        result
+    }
+}
+
+/// Yields to the executor once, letting other ready tasks run before the current task continues.
+pub async fn yield_now() {
+    YieldNow { yielded: false }.await
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// A waker which only sets a flag, for executors which check the flag of each task themselves.
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Runs a future to completion outside of an executor, halting the CPU while it waits. Nothing else
+/// runs in the meantime, so this is for code which can't be async.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let woken = Arc::new(Woken(AtomicBool::new(false)));
+    let waker = Waker::from(woken.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        // Sleeps are woken by the deferred work task, which doesn't run meanwhile, so the future
+        // is polled again after every interrupt as well
+        interrupts::disable();
+        if woken.0.swap(false, Ordering::SeqCst) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// The output of [`select2`], telling which of the futures completed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Waits for the first of two futures to complete. The other future is dropped right away, so
+/// anything it registered to be woken by is released. If both are ready, `a` wins.
+pub fn select2<A: Future, B: Future>(a: A, b: B) -> Select2<A, B> {
+    Select2 {
+        a: Some(Box::pin(a)),
+        b: Some(Box::pin(b)),
+    }
+}
+
+pub struct Select2<A, B> {
+    a: Option<Pin<Box<A>>>,
+    b: Option<Pin<Box<B>>>,
+}
+
+impl<A: Future, B: Future> Future for Select2<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // Both futures are boxed, so `Select2` itself doesn't need to stay pinned
+        let this = self.get_mut();
+        let (Some(a), Some(b)) = (this.a.as_mut(), this.b.as_mut()) else {
+            panic!("select2 polled after completion");
+        };
+        let output = if let Poll::Ready(output) = a.as_mut().poll(cx) {
+            Either::Left(output)
+        } else if let Poll::Ready(output) = b.as_mut().poll(cx) {
+            Either::Right(output)
+        } else {
+            return Poll::Pending;
+        };
+        this.a = None;
+        this.b = None;
+        Poll::Ready(output)
     }
 }
 
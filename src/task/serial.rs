@@ -0,0 +1,249 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::keyboard::Modifiers;
+use crate::{serial, settings, timer};
+
+/// How often the serial port is checked for input. The port is polled rather than interrupt
+/// driven, so this bounds the typing latency of a serial shell.
+const POLL_INTERVAL_MS: u64 = 10;
+
+const ESCAPE: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+/// The parameters of the control sequences a terminal sends around pasted text when bracketed
+/// paste is on, `ESC [ 200 ~` and `ESC [ 201 ~`.
+const PASTE_START: u16 = 200;
+const PASTE_END: u16 = 201;
+
+/// Whether the serial shell draws what's typed. Off for host terminals which echo keys themselves.
+static ECHO: AtomicBool = AtomicBool::new(true);
+/// The number of [`InputPause`]s held.
+static PAUSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns `true` if what's typed on the serial port is echoed back to it.
+pub fn echo() -> bool {
+    ECHO.load(Ordering::Relaxed)
+}
+
+pub fn set_echo(enabled: bool) {
+    ECHO.store(enabled, Ordering::Relaxed);
+}
+
+/// Applies the `serial.echo` setting, `on` unless it's set to `off`. Registered with
+/// [`settings::register_hook`](crate::settings::register_hook).
+pub fn apply_setting(key: &str, value: Option<&str>) {
+    if key == "serial.echo" {
+        set_echo(value.and_then(settings::parse_bool).unwrap_or(true));
+    }
+}
+
+/// Keeps [`process_serial_input`] from reading the serial port while held, so that data which
+/// isn't typed, like a disk image, can be read from it instead.
+pub struct InputPause(());
+
+impl InputPause {
+    pub fn new() -> Self {
+        PAUSES.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Default for InputPause {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InputPause {
+    fn drop(&mut self) {
+        PAUSES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Turns the bytes sent by a terminal into keypresses, the way the keyboard decoder does for
+/// scancodes. Control characters become letters with Ctrl held, `ESC` followed by a character
+/// becomes that character with Alt held, and the ANSI sequences of the arrow keys become the
+/// arrow keys. `\r`, `\n` and `\r\n` are all a single Enter.
+///
+/// Pasted text between bracketed paste markers is taken literally: escape sequences in it are
+/// typed as the characters following the `ESC`, and other control characters are dropped.
+#[derive(Debug, Default)]
+pub struct SerialDecoder {
+    state: DecoderState,
+    /// The number of the control sequence being read, as far as it has been read.
+    param: u16,
+    /// Whether the last byte was `\r`, so that a `\n` following it is dropped.
+    after_cr: bool,
+    pasting: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    #[default]
+    Ground,
+    Escape,
+    /// Inside a control sequence, `ESC [` followed by parameters.
+    ControlSequence,
+}
+
+impl SerialDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes the next byte, giving the keys it completes to `key`.
+    pub fn decode(&mut self, byte: u8, mut key: impl FnMut(DecodedKey, Modifiers)) {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        let mut unicode = |c: u8, modifiers| key(DecodedKey::Unicode(c as char), modifiers);
+        let ctrl = Modifiers {
+            ctrl: true,
+            ..Modifiers::default()
+        };
+        match (self.state, byte) {
+            (DecoderState::Ground, ESCAPE) => self.state = DecoderState::Escape,
+            (DecoderState::Ground, b'\n') if after_cr => {}
+            (DecoderState::Ground, b'\r' | b'\n') => unicode(b'\n', Modifiers::default()),
+            (DecoderState::Ground, b'\t' | 0x20..=0x7e) => unicode(byte, Modifiers::default()),
+            (DecoderState::Ground, _) if self.pasting => {}
+            (DecoderState::Ground, DELETE | 0x08) => unicode(0x08, Modifiers::default()),
+            (DecoderState::Ground, 0x01..=0x1a) => unicode(b'a' + byte - 1, ctrl),
+            (DecoderState::Ground, _) => {}
+            (DecoderState::Escape, b'[') => {
+                self.state = DecoderState::ControlSequence;
+                self.param = 0;
+            }
+            (DecoderState::Escape, 0x20..=0x7e) => {
+                self.state = DecoderState::Ground;
+                let modifiers = match self.pasting {
+                    true => Modifiers::default(),
+                    false => Modifiers {
+                        alt: true,
+                        ..Modifiers::default()
+                    },
+                };
+                unicode(byte, modifiers);
+            }
+            (DecoderState::Escape, _) => self.state = DecoderState::Ground,
+            // Only the first parameter matters, the paste markers have no others
+            (DecoderState::ControlSequence, b'0'..=b'9') => {
+                self.param = self
+                    .param
+                    .saturating_mul(10)
+                    .saturating_add((byte - b'0') as u16);
+            }
+            (DecoderState::ControlSequence, b';') => {}
+            (DecoderState::ControlSequence, _) => {
+                self.state = DecoderState::Ground;
+                match (byte, self.param) {
+                    (b'~', PASTE_START) => self.pasting = true,
+                    (b'~', PASTE_END) => self.pasting = false,
+                    // Typed as the characters after the `ESC`, the parameters are lost
+                    _ if self.pasting => {
+                        unicode(b'[', Modifiers::default());
+                        if (0x20..=0x7e).contains(&byte) {
+                            unicode(byte, Modifiers::default());
+                        }
+                    }
+                    (b'A', _) => key(DecodedKey::RawKey(KeyCode::ArrowUp), Modifiers::default()),
+                    (b'B', _) => key(DecodedKey::RawKey(KeyCode::ArrowDown), Modifiers::default()),
+                    (b'C', _) => key(
+                        DecodedKey::RawKey(KeyCode::ArrowRight),
+                        Modifiers::default(),
+                    ),
+                    (b'D', _) => key(DecodedKey::RawKey(KeyCode::ArrowLeft), Modifiers::default()),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Reads keypresses from the serial port, giving them to `key_press_handler`.
+pub async fn process_serial_input(mut key_press_handler: impl FnMut(DecodedKey, Modifiers)) {
+    let mut decoder = SerialDecoder::new();
+    loop {
+        while PAUSES.load(Ordering::SeqCst) == 0 {
+            let Some(byte) = serial::try_receive() else {
+                break;
+            };
+            decoder.decode(byte, &mut key_press_handler);
+        }
+        timer::sleep(POLL_INTERVAL_MS).await;
+    }
+}
+
+#[test_case]
+fn test_serial_decoder() {
+    let mut decoder = SerialDecoder::new();
+    let mut decode = |bytes: &[u8]| {
+        let mut keys = alloc::vec::Vec::new();
+        for &byte in bytes {
+            decoder.decode(byte, |key, modifiers| keys.push((key, modifiers)));
+        }
+        keys
+    };
+
+    let keys = decode(b"a\r\x7f");
+    let keys = keys
+        .iter()
+        .map(|(key, _)| *key)
+        .collect::<alloc::vec::Vec<_>>();
+    assert_eq!(
+        keys,
+        [
+            DecodedKey::Unicode('a'),
+            DecodedKey::Unicode('\n'),
+            DecodedKey::Unicode('\u{8}')
+        ]
+    );
+
+    let [(key, modifiers)] = decode(b"\x03")[..] else {
+        panic!("expected one key");
+    };
+    assert_eq!(key, DecodedKey::Unicode('c'));
+    assert!(modifiers.ctrl);
+
+    let [(key, modifiers)] = decode(b"\x1by")[..] else {
+        panic!("expected one key");
+    };
+    assert_eq!(key, DecodedKey::Unicode('y'));
+    assert!(modifiers.alt);
+
+    let keys = decode(b"\x1b[A\x1b[1;5D\x1b[Z");
+    let keys = keys
+        .iter()
+        .map(|(key, _)| *key)
+        .collect::<alloc::vec::Vec<_>>();
+    assert_eq!(
+        keys,
+        [
+            DecodedKey::RawKey(KeyCode::ArrowUp),
+            DecodedKey::RawKey(KeyCode::ArrowLeft)
+        ]
+    );
+}
+
+#[test_case]
+fn test_serial_line_endings_and_paste() {
+    let mut decoder = SerialDecoder::new();
+    let mut decode = |bytes: &[u8]| {
+        let mut text = alloc::string::String::new();
+        for &byte in bytes {
+            decoder.decode(byte, |key, modifiers| match key {
+                DecodedKey::Unicode(c) if modifiers == Modifiers::default() => text.push(c),
+                key => text.push_str(&alloc::format!("<{:?}>", key)),
+            });
+        }
+        text
+    };
+
+    // Every kind of line ending is a single Enter
+    assert_eq!(decode(b"a\rb\nc\r\nd\r\r"), "a\nb\nc\nd\n\n");
+
+    // Escape sequences and control characters in a paste are typed literally or dropped
+    assert_eq!(
+        decode(b"\x1b[200~ls\x1b[A\x03\x1bx\r\nrm\x1b[201~\x1b[A"),
+        "ls[Ax\nrm<RawKey(ArrowUp)>"
+    );
+}
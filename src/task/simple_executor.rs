@@ -1,11 +1,17 @@
-use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
 
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, sync::Arc};
+use x86_64::instructions::interrupts;
 
-use super::Task;
+use super::{Task, Woken};
 
+/// Runs tasks in the order they were spawned, polling a task only when it has been woken. Unlike
+/// [`Executor`](super::executor::Executor) it has no priorities and tasks can't spawn new tasks.
 pub struct SimpleExecutor {
-    queue: VecDeque<Task>,
+    queue: VecDeque<(Task, Arc<Woken>)>,
 }
 
 impl SimpleExecutor {
@@ -15,31 +21,90 @@ impl SimpleExecutor {
         }
     }
 
+    /// Adds a task, which is polled on the next pass.
     pub fn spawn(&mut self, task: Task) {
-        self.queue.push_back(task);
+        self.queue
+            .push_back((task, Arc::new(Woken(AtomicBool::new(true)))));
     }
 
+    /// Runs tasks until every task has completed, halting the CPU while none of them are woken.
     pub fn run(&mut self) {
-        while let Some(mut task) = self.queue.pop_front() {
-            let waker = dummy_waker();
-            let mut context = Context::from_waker(&waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {}
-                Poll::Pending => self.queue.push_back(task),
+        while !self.queue.is_empty() {
+            self.run_ready();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Polls every task which has been woken once, without waiting for any.
+    pub fn run_ready(&mut self) {
+        for _ in 0..self.queue.len() {
+            let (mut task, woken) = self.queue.pop_front().unwrap();
+            if woken.0.swap(false, Ordering::SeqCst) {
+                let waker = Waker::from(woken.clone());
+                let mut context = Context::from_waker(&waker);
+                if task.poll(&mut context) == Poll::Ready(()) {
+                    continue;
+                }
             }
+            self.queue.push_back((task, woken));
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Interrupts are disabled so that a wake can't happen between the check and the `hlt`
+        interrupts::disable();
+        if self
+            .queue
+            .iter()
+            .any(|(_, woken)| woken.0.load(Ordering::SeqCst))
+        {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
         }
     }
 }
 
-fn dummy_raw_waker() -> RawWaker {
-    fn noop(_: *const ()) {}
-    fn clone(_: *const ()) -> RawWaker {
-        dummy_raw_waker()
+impl Default for SimpleExecutor {
+    fn default() -> Self {
+        Self::new()
     }
-    let vtable = &RawWakerVTable::new(clone, noop, noop, noop);
-    RawWaker::new(0 as *const (), vtable)
 }
 
-fn dummy_waker() -> Waker {
-    unsafe { Waker::from_raw(dummy_raw_waker()) }
+#[test_case]
+fn test_polls_only_when_woken() {
+    use alloc::rc::Rc;
+    use core::{
+        cell::Cell,
+        future::{poll_fn, Future},
+        pin::pin,
+    };
+
+    use super::{deferred, select2};
+    use crate::timer;
+
+    // Sleeps are woken by the deferred work task, after the timer interrupt queues the wake
+    let polls = Rc::new(Cell::new(0));
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new({
+        let polls = polls.clone();
+        async move {
+            let mut sleep = pin!(timer::sleep_ticks(3));
+            let counted = poll_fn(|cx| {
+                polls.set(polls.get() + 1);
+                sleep.as_mut().poll(cx)
+            });
+            select2(counted, deferred::run()).await;
+        }
+    }));
+    let start = timer::ticks();
+    executor.run_ready();
+    assert_eq!(polls.get(), 1);
+    // Nothing was woken, so nothing is polled
+    executor.run_ready();
+    assert_eq!(polls.get(), 1);
+
+    executor.run();
+    assert!(timer::ticks() - start >= 3);
+    assert_eq!(polls.get(), 2);
 }
@@ -0,0 +1,110 @@
+use core::future::Future;
+
+use thiserror_no_std::Error;
+
+use super::{select2, Either};
+use crate::timer;
+
+/// Returned by [`timeout`] when the deadline passed before the future completed.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("deadline has elapsed")]
+pub struct Elapsed;
+
+/// Runs `future` until it completes or `ticks` timer interrupts have happened, counted from the
+/// call. The future is always polled at least once, so a zero timeout still lets a future which is
+/// already ready complete.
+pub fn timeout<F: Future>(
+    ticks: u64,
+    future: F,
+) -> impl Future<Output = Result<F::Output, Elapsed>> {
+    let select = select2(future, timer::sleep_ticks(ticks));
+    async move {
+        match select.await {
+            Either::Left(output) => Ok(output),
+            Either::Right(()) => Err(Elapsed),
+        }
+    }
+}
+
+/// A future which never completes and records when it is dropped.
+#[cfg(test)]
+struct Forever(alloc::sync::Arc<core::sync::atomic::AtomicBool>);
+
+#[cfg(test)]
+impl Future for Forever {
+    type Output = ();
+
+    fn poll(
+        self: core::pin::Pin<&mut Self>,
+        _cx: &mut core::task::Context,
+    ) -> core::task::Poll<()> {
+        core::task::Poll::Pending
+    }
+}
+
+#[cfg(test)]
+impl Drop for Forever {
+    fn drop(&mut self) {
+        self.0.store(true, core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Runs `future` to completion on a real executor, along with the deferred work task which wakes
+/// sleeping tasks.
+#[cfg(test)]
+fn run_task(future: impl Future<Output = ()> + 'static) {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut executor = super::executor::Executor::new();
+    executor.spawn(super::Task::new({
+        let done = done.clone();
+        async move {
+            select2(future, super::deferred::run()).await;
+            done.store(true, Ordering::SeqCst);
+        }
+    }));
+    executor.run_until_done();
+    assert!(done.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_inner_completes_first() {
+    run_task(async {
+        let output = timeout(timer::millis_to_ticks(1000), async {
+            timer::sleep(10).await;
+            7
+        });
+        assert_eq!(output.await, Ok(7));
+        // The deadline's sleeper was dropped with the timeout
+        assert_eq!(
+            timer::configure(timer::DEFAULT_TICK_HZ),
+            Ok(timer::DEFAULT_TICK_HZ)
+        );
+    });
+}
+
+#[test_case]
+fn test_deadline_fires_first() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    run_task(async {
+        let dropped = alloc::sync::Arc::new(AtomicBool::new(false));
+        let start = timer::ticks();
+        let output = timeout(3, Forever(dropped.clone())).await;
+        assert_eq!(output, Err(Elapsed));
+        assert!(timer::ticks() >= start + 3);
+        assert!(dropped.load(Ordering::SeqCst));
+    });
+}
+
+#[test_case]
+fn test_zero_timeout() {
+    use core::future::{pending, ready};
+
+    run_task(async {
+        assert_eq!(timeout(0, ready(1)).await, Ok(1));
+        assert_eq!(timeout(0, pending::<()>()).await, Err(Elapsed));
+    });
+}
@@ -0,0 +1,438 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{TaskId, TaskName};
+use crate::timer;
+
+/// The number of events a [`RingTracer`] keeps, older ones are overwritten.
+pub const RING_SIZE: usize = 1024;
+
+/// The events of the kernel's executor, shown by `trace dump`.
+pub static RING: RingTracer = RingTracer::new();
+/// The number of events of the kernel's executor, shown by `trace`.
+pub static COUNTS: CountingTracer = CountingTracer::new();
+/// Both of the above, which the kernel's executor reports to.
+pub static KERNEL: (&RingTracer, &CountingTracer) = (&RING, &COUNTS);
+
+/// Told what an [`Executor`](super::executor::Executor) does with its tasks, see
+/// [`Executor::set_tracer`](super::executor::Executor::set_tracer). Wakes may be reported from
+/// interrupt handlers, so none of the callbacks may allocate or wait for a lock.
+pub trait ExecutorTracer: Sync {
+    fn on_spawn(&self, id: TaskId, name: TaskName);
+    fn on_poll_start(&self, id: TaskId);
+    /// `ready` tells whether the task completed in the poll.
+    fn on_poll_end(&self, id: TaskId, ready: bool);
+    /// `from_irq` tells whether the waker was called by an interrupt handler. Wakes may come in
+    /// while the task is being polled, between its poll start and end.
+    fn on_wake(&self, id: TaskId, from_irq: bool);
+    /// Called once the completed task has been dropped.
+    fn on_complete(&self, id: TaskId);
+
+    /// The events counted so far, which [`Executor::stats`](super::executor::Executor::stats)
+    /// reports. `None` for tracers which don't count them.
+    fn counts(&self) -> Option<TraceCounts> {
+        None
+    }
+}
+
+impl<T: ExecutorTracer + ?Sized> ExecutorTracer for &T {
+    fn on_spawn(&self, id: TaskId, name: TaskName) {
+        (**self).on_spawn(id, name)
+    }
+
+    fn on_poll_start(&self, id: TaskId) {
+        (**self).on_poll_start(id)
+    }
+
+    fn on_poll_end(&self, id: TaskId, ready: bool) {
+        (**self).on_poll_end(id, ready)
+    }
+
+    fn on_wake(&self, id: TaskId, from_irq: bool) {
+        (**self).on_wake(id, from_irq)
+    }
+
+    fn on_complete(&self, id: TaskId) {
+        (**self).on_complete(id)
+    }
+
+    fn counts(&self) -> Option<TraceCounts> {
+        (**self).counts()
+    }
+}
+
+/// Reports every event to both tracers, the first one first.
+impl<A: ExecutorTracer, B: ExecutorTracer> ExecutorTracer for (A, B) {
+    fn on_spawn(&self, id: TaskId, name: TaskName) {
+        self.0.on_spawn(id, name);
+        self.1.on_spawn(id, name);
+    }
+
+    fn on_poll_start(&self, id: TaskId) {
+        self.0.on_poll_start(id);
+        self.1.on_poll_start(id);
+    }
+
+    fn on_poll_end(&self, id: TaskId, ready: bool) {
+        self.0.on_poll_end(id, ready);
+        self.1.on_poll_end(id, ready);
+    }
+
+    fn on_wake(&self, id: TaskId, from_irq: bool) {
+        self.0.on_wake(id, from_irq);
+        self.1.on_wake(id, from_irq);
+    }
+
+    fn on_complete(&self, id: TaskId) {
+        self.0.on_complete(id);
+        self.1.on_complete(id);
+    }
+
+    /// The counts of the first tracer which has any.
+    fn counts(&self) -> Option<TraceCounts> {
+        self.0.counts().or_else(|| self.1.counts())
+    }
+}
+
+/// Something an executor did with a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    Spawn(TaskName),
+    PollStart,
+    PollEnd { ready: bool },
+    Wake { from_irq: bool },
+    Complete,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Spawn(name) => write!(f, "spawn {}", name),
+            Self::PollStart => write!(f, "poll"),
+            Self::PollEnd { ready: false } => write!(f, "pending"),
+            Self::PollEnd { ready: true } => write!(f, "ready"),
+            Self::Wake { from_irq: false } => write!(f, "wake"),
+            Self::Wake { from_irq: true } => write!(f, "wake from irq"),
+            Self::Complete => write!(f, "complete"),
+        }
+    }
+}
+
+/// An event recorded by a [`RingTracer`], with the timer ticks it happened at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub ticks: u64,
+    pub task: TaskId,
+    pub event: TraceEvent,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>10} {:>6}  {}", self.ticks, self.task.0, self.event)
+    }
+}
+
+/// Records the last [`RING_SIZE`] events. An event which comes in while the ring is locked, like a
+/// wake from an interrupt handler while the ring is read, is dropped and counted instead.
+pub struct RingTracer {
+    ring: Mutex<Ring>,
+    dropped: AtomicU64,
+}
+
+struct Ring {
+    records: [Option<TraceRecord>; RING_SIZE],
+    /// Where the next record goes, the oldest record once the ring is full.
+    next: usize,
+}
+
+impl RingTracer {
+    pub const fn new() -> Self {
+        Self {
+            ring: Mutex::new(Ring {
+                records: [None; RING_SIZE],
+                next: 0,
+            }),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, task: TaskId, event: TraceEvent) {
+        let record = TraceRecord {
+            ticks: timer::ticks(),
+            task,
+            event,
+        };
+        match self.ring.try_lock() {
+            Some(mut ring) => {
+                let next = ring.next;
+                ring.records[next] = Some(record);
+                ring.next = (next + 1) % RING_SIZE;
+            }
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns the recorded events, oldest first.
+    pub fn records(&self) -> Vec<TraceRecord> {
+        let ring = self.ring.lock();
+        let (newer, older) = ring.records.split_at(ring.next);
+        older.iter().chain(newer).flatten().copied().collect()
+    }
+
+    /// Returns the number of events dropped because the ring was locked.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Forgets every recorded and dropped event.
+    pub fn reset(&self) {
+        let mut ring = self.ring.lock();
+        ring.records = [None; RING_SIZE];
+        ring.next = 0;
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for RingTracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExecutorTracer for RingTracer {
+    fn on_spawn(&self, id: TaskId, name: TaskName) {
+        self.record(id, TraceEvent::Spawn(name));
+    }
+
+    fn on_poll_start(&self, id: TaskId) {
+        self.record(id, TraceEvent::PollStart);
+    }
+
+    fn on_poll_end(&self, id: TaskId, ready: bool) {
+        self.record(id, TraceEvent::PollEnd { ready });
+    }
+
+    fn on_wake(&self, id: TaskId, from_irq: bool) {
+        self.record(id, TraceEvent::Wake { from_irq });
+    }
+
+    fn on_complete(&self, id: TaskId) {
+        self.record(id, TraceEvent::Complete);
+    }
+}
+
+/// The number of events counted by a [`CountingTracer`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TraceCounts {
+    pub spawned: u64,
+    pub polls: u64,
+    /// Polls which left the task pending.
+    pub pending: u64,
+    pub wakes: u64,
+    /// Wakes from interrupt handlers, also counted in `wakes`.
+    pub irq_wakes: u64,
+    pub completed: u64,
+}
+
+/// Counts the events of each kind, see [`TraceCounts`]. An executor tracing with one reports the
+/// counts in its [`ExecutorStats`](super::executor::ExecutorStats).
+#[derive(Default)]
+pub struct CountingTracer {
+    spawned: AtomicU64,
+    polls: AtomicU64,
+    pending: AtomicU64,
+    wakes: AtomicU64,
+    irq_wakes: AtomicU64,
+    completed: AtomicU64,
+}
+
+impl CountingTracer {
+    pub const fn new() -> Self {
+        Self {
+            spawned: AtomicU64::new(0),
+            polls: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+            irq_wakes: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn counts(&self) -> TraceCounts {
+        TraceCounts {
+            spawned: self.spawned.load(Ordering::Relaxed),
+            polls: self.polls.load(Ordering::Relaxed),
+            pending: self.pending.load(Ordering::Relaxed),
+            wakes: self.wakes.load(Ordering::Relaxed),
+            irq_wakes: self.irq_wakes.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ExecutorTracer for CountingTracer {
+    fn on_spawn(&self, _id: TaskId, _name: TaskName) {
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_poll_start(&self, _id: TaskId) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_poll_end(&self, _id: TaskId, ready: bool) {
+        if !ready {
+            self.pending.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_wake(&self, _id: TaskId, from_irq: bool) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+        if from_irq {
+            self.irq_wakes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_complete(&self, _id: TaskId) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> Option<TraceCounts> {
+        Some(CountingTracer::counts(self))
+    }
+}
+
+#[test_case]
+fn test_ring_tracer() {
+    use super::{executor::Executor, yield_now, Priority, Task};
+    use crate::interrupts::InHandler;
+    use alloc::sync::Arc;
+    use core::{future::poll_fn, task::Poll};
+    use TraceEvent::*;
+
+    static TRACER: RingTracer = RingTracer::new();
+    static COUNTER: CountingTracer = CountingTracer::new();
+    static BOTH: (&RingTracer, &CountingTracer) = (&TRACER, &COUNTER);
+
+    let mut executor = Executor::new();
+    executor.set_tracer(&BOTH);
+    // A task waiting for a child it spawns, which wakes itself while it's being polled
+    let spawner = executor.spawner();
+    let parent = Task::new(async move {
+        let child = spawner.spawn(
+            async {
+                yield_now().await;
+                7
+            },
+            Priority::Normal,
+        );
+        assert_eq!(child.await, 7);
+    });
+    let parent_id = parent.id;
+    executor.spawn(parent.with_name("parent"));
+    // A task woken by an interrupt handler
+    let waker = Arc::new(Mutex::new((false, None)));
+    let irq = {
+        let waker = waker.clone();
+        Task::new(poll_fn(move |cx| {
+            let mut waker = waker.lock();
+            match waker.0 {
+                true => Poll::Ready(()),
+                false => {
+                    waker.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }))
+    };
+    let irq_id = irq.id;
+    executor.spawn(irq.with_name("irq"));
+
+    executor.run_ready_tasks();
+    let wake = {
+        let mut waker = waker.lock();
+        waker.0 = true;
+        waker.1.take().unwrap()
+    };
+    let handler = InHandler::enter();
+    wake.wake();
+    drop(handler);
+    executor.run_until_done();
+
+    let records = TRACER.records();
+    // The child is named after its future
+    let spawned = records
+        .iter()
+        .find(|record| ![parent_id, irq_id].contains(&record.task))
+        .unwrap();
+    assert!(matches!(spawned.event, Spawn(_)));
+    let (parent, child, irq) = (parent_id, spawned.task, irq_id);
+    let events = records
+        .iter()
+        .map(|record| (record.task, record.event))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [
+            (parent, Spawn(TaskName("parent"))),
+            (irq, Spawn(TaskName("irq"))),
+            (parent, PollStart),
+            (parent, PollEnd { ready: false }),
+            (child, spawned.event),
+            (irq, PollStart),
+            (irq, PollEnd { ready: false }),
+            (child, PollStart),
+            (child, Wake { from_irq: false }),
+            (child, PollEnd { ready: false }),
+            (child, PollStart),
+            (parent, Wake { from_irq: false }),
+            (child, PollEnd { ready: true }),
+            (child, Complete),
+            (parent, PollStart),
+            (parent, PollEnd { ready: true }),
+            (parent, Complete),
+            (irq, Wake { from_irq: true }),
+            (irq, PollStart),
+            (irq, PollEnd { ready: true }),
+            (irq, Complete),
+        ]
+    );
+    assert!(records
+        .windows(2)
+        .all(|pair| pair[0].ticks <= pair[1].ticks));
+    assert_eq!(executor.stats().events, Some(COUNTER.counts()));
+    assert_eq!(
+        COUNTER.counts(),
+        TraceCounts {
+            spawned: 3,
+            polls: 6,
+            pending: 3,
+            wakes: 3,
+            irq_wakes: 1,
+            completed: 3,
+        }
+    );
+
+    TRACER.reset();
+    assert!(TRACER.records().is_empty());
+    // Only the newest events are kept
+    for i in 0..RING_SIZE as u64 + 10 {
+        TRACER.on_poll_start(TaskId(i));
+    }
+    let records = TRACER.records();
+    assert_eq!(records.len(), RING_SIZE);
+    assert_eq!(records[0].task, TaskId(10));
+    assert_eq!(records[RING_SIZE - 1].task, TaskId(RING_SIZE as u64 + 9));
+    // Events coming in while the ring is locked are dropped
+    let ring = TRACER.ring.lock();
+    TRACER.on_wake(TaskId(0), true);
+    drop(ring);
+    assert_eq!(TRACER.dropped(), 1);
+    assert_eq!(TRACER.records().len(), RING_SIZE);
+}
@@ -0,0 +1,292 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use thiserror_no_std::Error;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    io::{PIT_CH0, PIT_COMMAND},
+    task::deferred::{self, WorkItem},
+};
+
+/// The frequency of the oscillator driving the PIT.
+pub const PIT_HZ: u32 = 1_193_182;
+/// The timer frequency set up at boot.
+pub const DEFAULT_TICK_HZ: u32 = 100;
+
+// Channel 0, low byte then high byte, mode 3 (square wave), binary
+const PIT_SET_CHANNEL0: u8 = 0x36;
+// A divisor of 65536 is programmed as 0
+const MAX_DIVISOR: u32 = 1 << 16;
+
+static DIVISOR: AtomicU32 = AtomicU32::new(MAX_DIVISOR);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// Oscillator cycles elapsed, which stays correct when the frequency changes
+static CYCLES: AtomicU64 = AtomicU64::new(0);
+static SLEEPERS: Mutex<Vec<Sleeper>> = Mutex::new(Vec::new());
+// The earliest deadline of the sleepers, so the interrupt handler knows when to wake them
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+// Whether waking the sleepers has been handed to the deferred work task and not done yet
+static WAKE_PENDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TimerError {
+    #[error("timer frequency {0} Hz is out of range ({min}..={PIT_HZ} Hz)", min = PIT_HZ.div_ceil(MAX_DIVISOR))]
+    FrequencyOutOfRange(u32),
+    #[error("can't change the timer frequency while tasks are sleeping")]
+    SleepersPending,
+}
+
+struct Sleeper {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+/// Programs the timer to interrupt `frequency_hz` times per second and returns the frequency
+/// actually achieved. Changing the frequency is rejected while any task is sleeping, as their
+/// deadlines are counted in ticks. A [`sleep`] which hasn't been polled yet is counted at the new
+/// frequency.
+pub fn configure(frequency_hz: u32) -> Result<u32, TimerError> {
+    let divisor = match frequency_hz {
+        0 => 0,
+        hz => (PIT_HZ + hz / 2) / hz,
+    };
+    if !(1..=MAX_DIVISOR).contains(&divisor) {
+        return Err(TimerError::FrequencyOutOfRange(frequency_hz));
+    }
+
+    interrupts::without_interrupts(|| {
+        if !SLEEPERS.lock().is_empty() {
+            return Err(TimerError::SleepersPending);
+        }
+        PIT_COMMAND.write(PIT_SET_CHANNEL0);
+        PIT_CH0.write(divisor as u8);
+        PIT_CH0.write((divisor >> 8) as u8);
+        DIVISOR.store(divisor, Ordering::SeqCst);
+        Ok(tick_hz())
+    })
+}
+
+/// The current timer frequency, rounded to the nearest Hz.
+pub fn tick_hz() -> u32 {
+    let divisor = DIVISOR.load(Ordering::SeqCst);
+    (PIT_HZ + divisor / 2) / divisor
+}
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Milliseconds since boot, counted from timer interrupts.
+pub fn millis() -> u64 {
+    (CYCLES.load(Ordering::SeqCst) as u128 * 1000 / PIT_HZ as u128) as u64
+}
+
+/// Converts milliseconds to timer ticks at the current frequency, rounding up.
+pub fn millis_to_ticks(ms: u64) -> u64 {
+    let divisor = DIVISOR.load(Ordering::SeqCst) as u128;
+    (ms as u128 * PIT_HZ as u128).div_ceil(1000 * divisor) as u64
+}
+
+/// Converts timer ticks to milliseconds at the current frequency, rounding down.
+pub fn ticks_to_millis(ticks: u64) -> u64 {
+    let divisor = DIVISOR.load(Ordering::SeqCst) as u128;
+    (ticks as u128 * 1000 * divisor / PIT_HZ as u128) as u64
+}
+
+/// Called from the timer interrupt handler.
+pub(crate) fn tick() {
+    let ticks = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    CYCLES.fetch_add(DIVISOR.load(Ordering::SeqCst) as u64, Ordering::SeqCst);
+
+    #[cfg(any(debug_assertions, feature = "canary_checks"))]
+    if ticks.is_multiple_of(crate::allocator::CANARY_CHECK_TICKS) {
+        if let Err(dead) = crate::allocator::check_canaries() {
+            panic!("{}", dead);
+        }
+    }
+
+    // Waking runs arbitrary waker code, so leave it to the deferred work task. If the queue is
+    // full, try again on the next tick.
+    if ticks >= NEXT_DEADLINE.load(Ordering::SeqCst)
+        && !WAKE_PENDING.swap(true, Ordering::SeqCst)
+        && deferred::submit(WorkItem::WakeSleepers).is_err()
+    {
+        WAKE_PENDING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Wakes the tasks whose deadline has passed. Run as deferred work queued by [`tick`].
+pub(crate) fn wake_sleepers() {
+    WAKE_PENDING.store(false, Ordering::SeqCst);
+    interrupts::without_interrupts(|| {
+        let ticks = ticks();
+        let mut sleepers = SLEEPERS.lock();
+        sleepers.retain(|sleeper| {
+            if sleeper.deadline <= ticks {
+                sleeper.waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+        let next = sleepers.iter().map(|sleeper| sleeper.deadline).min();
+        NEXT_DEADLINE.store(next.unwrap_or(u64::MAX), Ordering::SeqCst);
+    });
+}
+
+/// A future which completes after at least the given time has passed.
+pub struct Sleep {
+    id: u64,
+    deadline: Deadline,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Deadline {
+    /// Milliseconds since boot. Turned into a tick when the sleep is first polled, as the
+    /// frequency can still change before it's registered as a sleeper.
+    Millis(u64),
+    Tick(u64),
+}
+
+impl Sleep {
+    fn new(deadline: Deadline) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            deadline,
+        }
+    }
+}
+
+/// Waits for at least `ms` milliseconds.
+pub fn sleep(ms: u64) -> Sleep {
+    Sleep::new(Deadline::Millis(millis() + ms))
+}
+
+/// Waits until `ticks` more timer interrupts have happened.
+pub fn sleep_ticks(ticks: u64) -> Sleep {
+    Sleep::new(Deadline::Tick(self::ticks() + ticks))
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        interrupts::without_interrupts(|| {
+            let deadline = match self.deadline {
+                Deadline::Millis(ms) => ticks() + millis_to_ticks(ms.saturating_sub(millis())),
+                Deadline::Tick(tick) => tick,
+            };
+            self.deadline = Deadline::Tick(deadline);
+            let mut sleepers = SLEEPERS.lock();
+            if ticks() >= deadline {
+                sleepers.retain(|sleeper| sleeper.id != self.id);
+                return Poll::Ready(());
+            }
+            match sleepers.iter_mut().find(|sleeper| sleeper.id == self.id) {
+                Some(sleeper) => sleeper.waker.clone_from(cx.waker()),
+                None => {
+                    sleepers.push(Sleeper {
+                        id: self.id,
+                        deadline,
+                        waker: cx.waker().clone(),
+                    });
+                    NEXT_DEADLINE.fetch_min(deadline, Ordering::SeqCst);
+                }
+            }
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| SLEEPERS.lock().retain(|sleeper| sleeper.id != self.id));
+    }
+}
+
+#[test_case]
+fn test_millis_at_1000_hz() {
+    assert_eq!(configure(1000), Ok(1000));
+    assert_eq!(tick_hz(), 1000);
+
+    let (start_ticks, start_millis) = (ticks(), millis());
+    while ticks() < start_ticks + 20 {
+        core::hint::spin_loop();
+    }
+    let elapsed = millis() - start_millis;
+    assert!((19..=21).contains(&elapsed), "{} ms elapsed", elapsed);
+
+    assert_eq!(configure(DEFAULT_TICK_HZ), Ok(DEFAULT_TICK_HZ));
+}
+
+#[test_case]
+fn test_configure_rejected_while_sleeping() {
+    use crate::task::{deferred, select2, simple_executor::SimpleExecutor, Task};
+
+    assert_eq!(configure(0), Err(TimerError::FrequencyOutOfRange(0)));
+    assert_eq!(configure(10), Err(TimerError::FrequencyOutOfRange(10)));
+
+    let start = millis();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        select2(sleep(30), deferred::run()).await;
+    }));
+    executor.spawn(Task::new(async {
+        assert_eq!(configure(1000), Err(TimerError::SleepersPending));
+    }));
+    executor.run();
+    assert!(millis() - start >= 30);
+    assert_eq!(configure(DEFAULT_TICK_HZ), Ok(DEFAULT_TICK_HZ));
+}
+
+#[test_case]
+fn test_sleep_counted_at_frequency_when_polled() {
+    use crate::task::{deferred, select2, simple_executor::SimpleExecutor, Task};
+
+    // Not a sleeper until it's polled, so the frequency can still change
+    let start = millis();
+    let created = sleep(50);
+    assert_eq!(configure(1000), Ok(1000));
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        select2(created, deferred::run()).await;
+    }));
+    executor.run();
+    assert!(millis() - start >= 50, "{} ms elapsed", millis() - start);
+    assert_eq!(configure(DEFAULT_TICK_HZ), Ok(DEFAULT_TICK_HZ));
+}
+
+#[test_case]
+fn test_pit_counter() {
+    // Channel 0, latching its count so that both bytes of it are from the same moment
+    const PIT_LATCH_CHANNEL0: u8 = 0x00;
+
+    let count = || {
+        interrupts::without_interrupts(|| {
+            PIT_COMMAND.write(PIT_LATCH_CHANNEL0);
+            let low = PIT_CH0.read();
+            u32::from(u16::from_le_bytes([low, PIT_CH0.read()]))
+        })
+    };
+    let divisor = DIVISOR.load(Ordering::SeqCst);
+    let first = count();
+    assert!(
+        first <= divisor,
+        "count {} above divisor {}",
+        first,
+        divisor
+    );
+    // The counter keeps running down and is reloaded with the divisor
+    assert!((0..100_000).any(|_| count() != first));
+    assert!((0..1000).all(|_| count() <= divisor));
+}
@@ -0,0 +1,133 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::settings;
+
+/// Switches back to the default colors.
+pub const RESET: &str = "\x1b[0m";
+/// Swaps the foreground and background colors of the text after it, until [`NO_INVERSE`].
+pub const INVERSE: &str = "\x1b[7m";
+pub const NO_INVERSE: &str = "\x1b[27m";
+
+static CONSOLE_COLORS: AtomicBool = AtomicBool::new(true);
+
+/// How a message is highlighted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Error,
+    Warning,
+    Prompt,
+}
+
+impl Style {
+    /// The escape sequence switching to the color of the style.
+    pub fn color(self) -> &'static str {
+        match self {
+            Self::Error => "\x1b[91m",
+            Self::Warning => "\x1b[93m",
+            Self::Prompt => "\x1b[92m",
+        }
+    }
+
+    /// Marks messages of the style when colors are off.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Self::Error => "error: ",
+            Self::Warning => "warning: ",
+            Self::Prompt => "",
+        }
+    }
+}
+
+/// A message shown in the color of its style, or behind the plain prefix of the style when colors
+/// are off. The color is reset after the message, whatever escape sequences it contains.
+pub struct Styled<T> {
+    style: Style,
+    colors: bool,
+    message: T,
+}
+
+pub fn styled<T: fmt::Display>(style: Style, colors: bool, message: T) -> Styled<T> {
+    Styled {
+        style,
+        colors,
+        message,
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Styled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.colors {
+            write!(f, "{}{}{}", self.style.color(), self.message, RESET)
+        } else {
+            write!(f, "{}{}", self.style.prefix(), self.message)
+        }
+    }
+}
+
+/// Turns colors in kernel messages printed with [`print_error!`] and [`print_warn!`] and in panic
+/// messages on or off.
+pub fn set_console_colors(enabled: bool) {
+    CONSOLE_COLORS.store(enabled, Ordering::Relaxed);
+}
+
+/// Applies the `color` setting, `on` unless it's set to `off`. Registered with
+/// [`settings::register_hook`](crate::settings::register_hook).
+pub fn apply_setting(key: &str, value: Option<&str>) {
+    if key == "color" {
+        set_console_colors(value.and_then(settings::parse_bool).unwrap_or(true));
+    }
+}
+
+pub fn console_colors() -> bool {
+    CONSOLE_COLORS.load(Ordering::Relaxed)
+}
+
+/// Prints an error message to the console, in red if colors are on.
+#[macro_export]
+macro_rules! print_error {
+    ($($arg:tt)*) => {
+        $crate::println!(
+            "{}",
+            $crate::ui::styled(
+                $crate::ui::Style::Error,
+                $crate::ui::console_colors(),
+                format_args!($($arg)*)
+            )
+        )
+    };
+}
+
+/// Prints a warning to the console, in yellow if colors are on.
+#[macro_export]
+macro_rules! print_warn {
+    ($($arg:tt)*) => {
+        $crate::println!(
+            "{}",
+            $crate::ui::styled(
+                $crate::ui::Style::Warning,
+                $crate::ui::console_colors(),
+                format_args!($($arg)*)
+            )
+        )
+    };
+}
+
+#[test_case]
+fn test_styled() {
+    use alloc::format;
+
+    let message = format!("{}", styled(Style::Error, true, "bad \x1b[0mthing"));
+    assert_eq!(message, "\x1b[91mbad \x1b[0mthing\x1b[0m");
+    assert_eq!(
+        format!("{}", styled(Style::Error, false, "bad")),
+        "error: bad"
+    );
+    assert_eq!(
+        format!("{}", styled(Style::Warning, false, 42)),
+        "warning: 42"
+    );
+    assert_eq!(format!("{}", styled(Style::Prompt, false, "> ")), "> ");
+}
@@ -0,0 +1,199 @@
+use core::fmt::{self, Display, Write};
+
+use crate::timer;
+
+/// The binary units of [`HumanBytes`], each 1024 times the one before.
+const BYTE_UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// Text formatted into a buffer on the stack, for formatting without allocating. Text which
+/// doesn't fit is cut off after the last whole character which does.
+pub struct FmtBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FmtBuf<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl<const N: usize> Default for FmtBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for FmtBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(N - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// A number of bytes in the largest binary unit it reaches, with two decimals: `0 B`, `1023 B`,
+/// `1.50 KiB`. The decimals are cut off rather than rounded, so a value never shows as the next
+/// unit up: one byte short of 1 MiB is `1023.99 KiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanBytes(pub u64);
+
+impl Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = FmtBuf::<16>::new();
+        let bytes = self.0;
+        match (1..=BYTE_UNITS.len())
+            .rev()
+            .find(|&i| bytes >> (10 * i) > 0)
+        {
+            None => write!(buf, "{} B", bytes)?,
+            Some(i) => {
+                let shift = 10 * i as u32;
+                let rest = u128::from(bytes & ((1 << shift) - 1));
+                let hundredths = (rest * 100) >> shift;
+                write!(
+                    buf,
+                    "{}.{:02} {}",
+                    bytes >> shift,
+                    hundredths,
+                    BYTE_UNITS[i - 1]
+                )?;
+            }
+        }
+        f.pad(buf.as_str())
+    }
+}
+
+/// A number of timer ticks as the time they take, in the two largest units needed: `45s`,
+/// `3m 12s`, `2h 5m`, `3d 4h`. Smaller units are cut off rather than rounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanDuration(pub u64);
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = FmtBuf::<24>::new();
+        let seconds = self.0 / u64::from(timer::tick_hz()).max(1);
+        let (minutes, hours, days) = (seconds / 60, seconds / 3600, seconds / 86400);
+        match seconds {
+            0..=59 => write!(buf, "{}s", seconds)?,
+            60..=3599 => write!(buf, "{}m {}s", minutes, seconds % 60)?,
+            3600..=86399 => write!(buf, "{}h {}m", hours, minutes % 60)?,
+            _ => write!(buf, "{}d {}h", days, hours % 24)?,
+        }
+        f.pad(buf.as_str())
+    }
+}
+
+/// Writes bytes as pairs of hex digits separated by spaces, like `de ad 00`.
+pub fn write_hex(out: &mut (impl Write + ?Sized), bytes: &[u8]) -> fmt::Result {
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            out.write_char(' ')?;
+        }
+        write!(out, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+/// Which side of a column values are lined up on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+/// Writes `value` padded with spaces to `width` characters, for the columns of a table. Longer
+/// values aren't cut off.
+pub fn write_padded(
+    out: &mut (impl Write + ?Sized),
+    value: impl Display,
+    width: usize,
+    align: Align,
+) -> fmt::Result {
+    match align {
+        Align::Left => write!(out, "{:<width$}", value),
+        Align::Right => write!(out, "{:>width$}", value),
+    }
+}
+
+#[test_case]
+fn test_human_bytes() {
+    use alloc::string::ToString;
+
+    const KIB: u64 = 1024;
+    const MIB: u64 = 1024 * KIB;
+    let cases = [
+        (0, "0 B"),
+        (1, "1 B"),
+        (1023, "1023 B"),
+        (KIB, "1.00 KiB"),
+        (1536, "1.50 KiB"),
+        (MIB - KIB, "1023.00 KiB"),
+        (MIB - 1, "1023.99 KiB"),
+        (MIB, "1.00 MiB"),
+        (MIB * 14 / 10, "1.39 MiB"),
+        (1024 * MIB, "1.00 GiB"),
+        (u64::MAX, "15.99 EiB"),
+    ];
+    for (bytes, expected) in cases {
+        assert_eq!(HumanBytes(bytes).to_string(), expected);
+    }
+    assert_eq!(alloc::format!("[{:>9}]", HumanBytes(KIB)), "[ 1.00 KiB]");
+}
+
+#[test_case]
+fn test_human_duration() {
+    use alloc::string::ToString;
+
+    let hz = u64::from(timer::tick_hz());
+    let cases = [
+        (0, "0s"),
+        (hz - 1, "0s"),
+        (45 * hz, "45s"),
+        (60 * hz, "1m 0s"),
+        (192 * hz, "3m 12s"),
+        (3599 * hz, "59m 59s"),
+        (3600 * hz, "1h 0m"),
+        (86399 * hz, "23h 59m"),
+        (86400 * hz, "1d 0h"),
+        ((30 * 86400 + 5 * 3600) * hz, "30d 5h"),
+    ];
+    for (ticks, expected) in cases {
+        assert_eq!(HumanDuration(ticks).to_string(), expected);
+    }
+    let seconds = u64::MAX / hz;
+    assert_eq!(
+        HumanDuration(u64::MAX).to_string(),
+        alloc::format!("{}d {}h", seconds / 86400, seconds / 3600 % 24)
+    );
+}
+
+#[test_case]
+fn test_fmt_helpers() {
+    let mut buf = FmtBuf::<16>::new();
+    write_hex(&mut buf, &[0xde, 0xad, 0x00, 0x7f]).unwrap();
+    assert_eq!(buf.as_str(), "de ad 00 7f");
+
+    let mut buf = FmtBuf::<16>::new();
+    write_padded(&mut buf, 42, 4, Align::Right).unwrap();
+    write_padded(&mut buf, "ab", 4, Align::Left).unwrap();
+    write_padded(&mut buf, "toolong", 2, Align::Left).unwrap();
+    assert_eq!(buf.as_str(), "  42ab  toolong");
+
+    // Text which doesn't fit is cut off at a character boundary
+    let mut buf = FmtBuf::<4>::new();
+    write!(buf, "abcé").unwrap();
+    assert_eq!(buf.as_str(), "abc");
+}
@@ -0,0 +1,26 @@
+const OFFSET_BASIS: u32 = 0x811c_9dc5;
+const PRIME: u32 = 0x0100_0193;
+
+/// Computes the 32-bit FNV-1a hash of `data`. It's fast and spreads short strings like file names
+/// well, but is no defence against inputs crafted to collide.
+pub fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(PRIME)
+    })
+}
+
+#[test_case]
+fn test_fnv1a_known_vectors() {
+    assert_eq!(fnv1a(b""), 0x811c_9dc5);
+    assert_eq!(fnv1a(b"a"), 0xe40c_292c);
+    assert_eq!(fnv1a(b"foobar"), 0xbf9c_f968);
+}
+
+#[test_case]
+fn test_fnv1a_spreads_similar_names() {
+    let a = fnv1a(b"file1");
+    let b = fnv1a(b"file2");
+    assert_ne!(a, b);
+    // Names differing in their last byte still differ in the low bits a hash table uses
+    assert_ne!(a & 0xff, b & 0xff);
+}
@@ -0,0 +1,363 @@
+use alloc::{vec, vec::Vec};
+use thiserror_no_std::Error;
+
+use crate::error::{self, ErrorKind};
+
+// Compressed data is a sequence of groups, each a flag byte followed by up to 8 tokens. Bit i of
+// the flag byte, counting from the lowest, says whether token i is a literal byte (0) or a match
+// (1). A match is a little endian u16 holding the distance minus one in its low 12 bits and the
+// length minus MIN_MATCH in its high 4 bits. It repeats the `length` bytes starting `distance`
+// bytes back, which may overlap the bytes it produces.
+
+/// Matches are looked for this far back, the largest distance a match can refer to.
+pub const WINDOW_SIZE: usize = 4096;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 15;
+const HASH_BITS: u32 = 12;
+/// Earlier positions with the same hash tried before settling for the longest match found so far.
+const MAX_CHAIN: usize = 64;
+const NONE: u32 = u32::MAX;
+
+/// The size of the header [`compress_block`] puts in front of a block.
+pub const BLOCK_HEADER_SIZE: usize = 2;
+/// Set in a block header when the block is stored as it is.
+const RAW: u16 = 1 << 15;
+/// The longest block [`compress_block`] takes, as the header has 15 bits for the length.
+pub const MAX_BLOCK_SIZE: usize = RAW as usize - 1;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LzError {
+    #[error("the data ends in the middle of a match")]
+    Truncated,
+    #[error("a match at byte {at} refers {distance} bytes back, before the start of the data")]
+    BadDistance { at: usize, distance: usize },
+    #[error("the data decompresses to more than {0} bytes")]
+    TooLong(usize),
+    #[error("the block decompresses to {found} bytes instead of {expected}")]
+    WrongLength { found: usize, expected: usize },
+    #[error("the block is {stored} bytes long but only {available} bytes are left")]
+    ShortBlock { stored: usize, available: usize },
+}
+
+impl error::Error for LzError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Corrupt
+    }
+}
+
+/// Remembers where each 3 byte sequence was last seen, chained to where it was seen before that
+/// within the window.
+struct Matcher {
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let key = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+    (key.wrapping_mul(0x9e37_79b1) >> (u32::BITS - HASH_BITS)) as usize
+}
+
+impl Matcher {
+    fn new() -> Self {
+        Self {
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW_SIZE],
+        }
+    }
+
+    fn insert(&mut self, input: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= input.len() {
+            let hash = hash(&input[pos..]);
+            self.prev[pos % WINDOW_SIZE] = self.head[hash];
+            self.head[hash] = pos as u32;
+        }
+    }
+
+    /// Finds the longest match for the bytes at `pos` among the positions inserted before it,
+    /// returning its distance and length.
+    fn longest(&self, input: &[u8], pos: usize) -> (usize, usize) {
+        let max = (input.len() - pos).min(MAX_MATCH);
+        let mut best = (0, 0);
+        if max < MIN_MATCH {
+            return best;
+        }
+        let mut candidate = self.head[hash(&input[pos..])];
+        for _ in 0..MAX_CHAIN {
+            if candidate == NONE || pos - candidate as usize > WINDOW_SIZE {
+                break;
+            }
+            let start = candidate as usize;
+            let len = input[start..]
+                .iter()
+                .zip(&input[pos..pos + max])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.1 {
+                best = (pos - start, len);
+                if len == max {
+                    break;
+                }
+            }
+            candidate = self.prev[start % WINDOW_SIZE];
+        }
+        best
+    }
+}
+
+/// The compressed data as it's written, see the format above.
+struct Output<'a> {
+    bytes: &'a mut [u8],
+    len: usize,
+    /// The position of the flag byte of the current group.
+    flags: usize,
+    tokens: usize,
+}
+
+impl Output<'_> {
+    /// Adds a token, returning `None` if it doesn't fit.
+    fn push(&mut self, token: &[u8], is_match: bool) -> Option<()> {
+        if self.tokens.is_multiple_of(8) {
+            self.flags = self.len;
+            *self.bytes.get_mut(self.len)? = 0;
+            self.len += 1;
+        }
+        let end = self.len + token.len();
+        self.bytes.get_mut(self.len..end)?.copy_from_slice(token);
+        self.len = end;
+        if is_match {
+            self.bytes[self.flags] |= 1 << (self.tokens % 8);
+        }
+        self.tokens += 1;
+        Some(())
+    }
+}
+
+/// Compresses `input` into `output` with LZSS, returning the compressed length or `None` if it
+/// doesn't fit. Finding matches takes 32 KiB of heap, however long the input is.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut matcher = Matcher::new();
+    let mut out = Output {
+        bytes: output,
+        len: 0,
+        flags: 0,
+        tokens: 0,
+    };
+    let mut pos = 0;
+    while pos < input.len() {
+        let (distance, len) = matcher.longest(input, pos);
+        let len = match len >= MIN_MATCH {
+            true => {
+                let code = (distance - 1) as u16 | ((len - MIN_MATCH) as u16) << 12;
+                out.push(&code.to_le_bytes(), true)?;
+                len
+            }
+            false => {
+                out.push(&input[pos..pos + 1], false)?;
+                1
+            }
+        };
+        for pos in pos..pos + len {
+            matcher.insert(input, pos);
+        }
+        pos += len;
+    }
+    Some(out.len)
+}
+
+/// Decompresses data written by [`compress`] into `output`, returning the decompressed length.
+/// Fails rather than panic when the data is corrupt.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, LzError> {
+    let (mut i, mut len) = (0, 0);
+    while i < input.len() {
+        let flags = input[i];
+        i += 1;
+        for bit in 0..8 {
+            if i == input.len() {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                let limit = output.len();
+                *output.get_mut(len).ok_or(LzError::TooLong(limit))? = input[i];
+                (i, len) = (i + 1, len + 1);
+                continue;
+            }
+            let code = input.get(i..i + 2).ok_or(LzError::Truncated)?;
+            let code = u16::from_le_bytes([code[0], code[1]]);
+            i += 2;
+            let distance = (code & 0xfff) as usize + 1;
+            let length = (code >> 12) as usize + MIN_MATCH;
+            if distance > len {
+                return Err(LzError::BadDistance { at: len, distance });
+            }
+            if len + length > output.len() {
+                return Err(LzError::TooLong(output.len()));
+            }
+            // One byte at a time, a match overlapping itself repeats the bytes it just produced
+            for _ in 0..length {
+                output[len] = output[len - distance];
+                len += 1;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Compresses a block to be stored on its own, after a header of [`BLOCK_HEADER_SIZE`] bytes
+/// holding the stored length as a little endian u16, with the top bit set if the block is stored
+/// as it is. That's done when compressing doesn't make it smaller, so `output` needs room for
+/// the header and `input`. Returns the length written, header included.
+pub fn compress_block(input: &[u8], output: &mut [u8]) -> usize {
+    assert!(input.len() <= MAX_BLOCK_SIZE, "block too long to compress");
+    let (header, payload) = output.split_at_mut(BLOCK_HEADER_SIZE);
+    let stored = match compress(input, &mut payload[..input.len().saturating_sub(1)]) {
+        Some(len) => len as u16,
+        None => {
+            payload[..input.len()].copy_from_slice(input);
+            input.len() as u16 | RAW
+        }
+    };
+    header.copy_from_slice(&stored.to_le_bytes());
+    BLOCK_HEADER_SIZE + (stored & !RAW) as usize
+}
+
+/// Returns the length of a block written by [`compress_block`], header included.
+pub fn block_len(block: &[u8]) -> Result<usize, LzError> {
+    match block {
+        &[low, high, ..] => {
+            Ok(BLOCK_HEADER_SIZE + (u16::from_le_bytes([low, high]) & !RAW) as usize)
+        }
+        _ => Err(LzError::ShortBlock {
+            stored: BLOCK_HEADER_SIZE,
+            available: block.len(),
+        }),
+    }
+}
+
+/// Decompresses a block written by [`compress_block`], which has to fill `output` exactly. Bytes
+/// after the end of the block are ignored.
+pub fn decompress_block(block: &[u8], output: &mut [u8]) -> Result<(), LzError> {
+    let len = block_len(block)?;
+    let payload = block
+        .get(BLOCK_HEADER_SIZE..len)
+        .ok_or(LzError::ShortBlock {
+            stored: len,
+            available: block.len(),
+        })?;
+    let found = match u16::from_le_bytes([block[0], block[1]]) & RAW != 0 {
+        true => {
+            let len = payload.len().min(output.len());
+            output[..len].copy_from_slice(&payload[..len]);
+            payload.len()
+        }
+        false => decompress(payload, output)?,
+    };
+    if found != output.len() {
+        return Err(LzError::WrongLength {
+            found,
+            expected: output.len(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn pseudo_random(len: usize) -> Vec<u8> {
+    let mut state = 0x1234_5678u32;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_lz_known_vectors() {
+    let vectors: [(&[u8], &[u8]); 4] = [
+        (b"", &[]),
+        (b"abc", &[0x00, b'a', b'b', b'c']),
+        // One literal, then a match one byte back repeating it 9 times
+        (b"aaaaaaaaaa", &[0x02, b'a', 0x00, 0x60]),
+        (b"abcabcabc", &[0x08, b'a', b'b', b'c', 0x02, 0x30]),
+    ];
+    for (input, expected) in vectors {
+        let mut compressed = [0; 16];
+        let len = compress(input, &mut compressed).unwrap();
+        assert_eq!(&compressed[..len], expected);
+        let mut output = [0; 16];
+        assert_eq!(decompress(expected, &mut output), Ok(input.len()));
+        assert_eq!(&output[..input.len()], input);
+    }
+}
+
+#[test_case]
+fn test_lz_round_trips() {
+    let text = b"the quick brown fox jumps over the lazy dog. ".repeat(100);
+    let inputs = [
+        vec![0; WINDOW_SIZE],
+        text,
+        pseudo_random(WINDOW_SIZE),
+        pseudo_random(3 * WINDOW_SIZE),
+    ];
+    let prefixes = (0..40).map(|len| &inputs[1][..len]);
+    for input in inputs.iter().map(Vec::as_slice).chain(prefixes) {
+        // Every token a literal takes an extra bit
+        let mut compressed = vec![0; input.len() + input.len() / 8 + 1];
+        let len = compress(input, &mut compressed).unwrap();
+        let mut output = vec![0; input.len()];
+        assert_eq!(decompress(&compressed[..len], &mut output), Ok(input.len()));
+        assert!(output == input);
+    }
+
+    // Blocks which don't get smaller are stored as they are
+    for (input, raw) in [(&inputs[0], false), (&inputs[2], true)] {
+        let mut block = vec![0; BLOCK_HEADER_SIZE + input.len()];
+        let len = compress_block(input, &mut block);
+        assert_eq!(len == BLOCK_HEADER_SIZE + input.len(), raw);
+        assert_eq!(block_len(&block), Ok(len));
+        let mut output = vec![0; input.len()];
+        decompress_block(&block[..len], &mut output).unwrap();
+        assert!(output == *input);
+    }
+}
+
+#[test_case]
+fn test_lz_rejects_corrupt_data() {
+    let mut output = [0; 8];
+    assert_eq!(
+        decompress(&[0x01, 0x00, 0x00], &mut output),
+        Err(LzError::BadDistance { at: 0, distance: 1 })
+    );
+    assert_eq!(
+        decompress(&[0x02, b'a', 0x00], &mut output),
+        Err(LzError::Truncated)
+    );
+    assert_eq!(
+        decompress(&[0x02, b'a', 0x00, 0x60], &mut output),
+        Err(LzError::TooLong(8))
+    );
+
+    let input = b"abcabcabc abcabcabc".repeat(4);
+    let mut block = [0; 128];
+    let len = compress_block(&input, &mut block);
+    let mut output = [0; 76];
+    assert_eq!(
+        decompress_block(&block[..len - 1], &mut output),
+        Err(LzError::ShortBlock {
+            stored: len,
+            available: len - 1
+        })
+    );
+    assert!(matches!(
+        decompress_block(&block[..len], &mut output[..70]),
+        Err(LzError::TooLong(70))
+    ));
+    // Whatever byte is damaged, the block decompresses to something or fails, but never panics
+    for i in 0..len {
+        for bit in 0..8 {
+            let mut damaged = block;
+            damaged[i] ^= 1 << bit;
+            let _ = decompress_block(&damaged, &mut output);
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod crc32;
+pub mod fmt;
+pub mod fnv;
+pub mod lz;
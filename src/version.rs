@@ -0,0 +1,102 @@
+use core::fmt;
+
+/// Written in place of build details which weren't given when building.
+pub const UNKNOWN: &str = "unknown";
+
+/// The optional features the kernel was built with.
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "alloc_debug")]
+    "alloc_debug",
+    #[cfg(feature = "alloc_fail_hook")]
+    "alloc_fail_hook",
+    #[cfg(feature = "canary_checks")]
+    "canary_checks",
+    #[cfg(feature = "memtest_faults")]
+    "memtest_faults",
+];
+
+/// Which build of the kernel is running. The git details can't be found at build time without a
+/// build script, so they're passed in `HANNOS_GIT_HASH` and `HANNOS_GIT_DIRTY`, e.g.
+///
+/// ```text
+/// HANNOS_GIT_HASH=$(git rev-parse --short HEAD) \
+/// HANNOS_GIT_DIRTY=$(git diff --quiet || echo 1) cargo run
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// The version of the crate.
+    pub version: &'static str,
+    /// The commit the kernel was built from, [`UNKNOWN`] if it wasn't given.
+    pub git_hash: &'static str,
+    /// Whether the tree had uncommitted changes, `None` if it wasn't given.
+    pub dirty: Option<bool>,
+    /// `debug` or `release`.
+    pub profile: &'static str,
+    pub features: &'static [&'static str],
+}
+
+/// Returns the details of this build.
+pub fn version() -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: match option_env!("HANNOS_GIT_HASH") {
+            Some(hash) if !hash.is_empty() => hash,
+            _ => UNKNOWN,
+        },
+        dirty: match option_env!("HANNOS_GIT_DIRTY") {
+            Some("" | "0") => Some(false),
+            Some(_) => Some(true),
+            None => None,
+        },
+        profile: if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        },
+        features: FEATURES,
+    }
+}
+
+/// Prints the banner, as the kernel does once it has booted.
+pub fn print_banner() {
+    crate::println!("{}", version());
+}
+
+/// The one-line banner printed at boot, like `hannos 0.1.0 (1a2b3c4-dirty, debug, alloc_debug)`.
+impl fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hannos {} ({}", self.version, self.git_hash)?;
+        if self.dirty == Some(true) {
+            write!(f, "-dirty")?;
+        }
+        write!(f, ", {}", self.profile)?;
+        for feature in self.features {
+            write!(f, ", {}", feature)?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[test_case]
+fn test_version_banner() {
+    use alloc::string::ToString;
+
+    let info = version();
+    assert!(!info.version.is_empty());
+    assert!(!info.git_hash.is_empty());
+    assert!(!info.profile.is_empty());
+    let banner = info.to_string();
+    assert!(banner.starts_with(&alloc::format!("hannos {} (", info.version)));
+
+    let info = VersionInfo {
+        version: "1.2.3",
+        git_hash: "abc1234",
+        dirty: Some(true),
+        profile: "release",
+        features: &["alloc_debug"],
+    };
+    assert_eq!(
+        info.to_string(),
+        "hannos 1.2.3 (abc1234-dirty, release, alloc_debug)"
+    );
+}
@@ -1,8 +1,19 @@
-use core::{arch::asm, fmt, ptr::addr_of_mut};
+use core::{
+    arch::asm,
+    fmt,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
+use alloc::{boxed::Box, string::String, vec::Vec};
 use lazy_static::lazy_static;
 use spin::Mutex;
-use x86_64::instructions::interrupts;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use crate::{
+    cmdline::Console,
+    console::{self, Chunk},
+};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -34,61 +45,543 @@ impl VGAColor {
     pub fn new(fg: Color, bg: Color) -> VGAColor {
         VGAColor((bg as u8) << 4 | (fg as u8))
     }
+
+    /// Returns the attribute byte stored in the VGA buffer.
+    pub fn attribute(self) -> u8 {
+        self.0
+    }
+
+    /// The color of an attribute byte read from the VGA buffer.
+    pub fn from_attribute(attribute: u8) -> VGAColor {
+        VGAColor(attribute)
+    }
+
+    fn with_fg(self, fg: Color) -> VGAColor {
+        VGAColor(self.0 & 0xf0 | fg as u8)
+    }
+
+    /// Returns the color with the foreground and background swapped.
+    pub fn inverted(self) -> VGAColor {
+        VGAColor(self.0.rotate_left(4))
+    }
 }
 
+/// The color text is written in unless an escape sequence changes it.
+pub const DEFAULT_COLOR: VGAColor = VGAColor((Color::Black as u8) << 4 | Color::White as u8);
+
+// The colors of the ANSI foreground codes 30-37, the codes 90-97 use the light variants
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+const ANSI_LIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+/// The most parameters of an escape sequence which are kept, the rest are ignored.
+const MAX_ANSI_PARAMS: usize = 4;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(C)]
-struct VGABufferEntry {
-    ascii_char: u8,
+enum AnsiState {
+    Text,
+    /// After an `ESC`.
+    Escape,
+    /// Inside a control sequence, after `ESC [`.
+    Params {
+        params: [u8; MAX_ANSI_PARAMS],
+        len: usize,
+    },
+}
+
+/// Follows the ANSI escape sequences in written text. Sequences setting the foreground color or
+/// reverse video (`ESC [ n m`) change the color text is drawn in, all other sequences are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnsiParser {
+    state: AnsiState,
     color: VGAColor,
+    /// Whether the foreground and background of the color are swapped.
+    inverse: bool,
+}
+
+impl AnsiParser {
+    pub const fn new() -> Self {
+        Self {
+            state: AnsiState::Text,
+            color: DEFAULT_COLOR,
+            inverse: false,
+        }
+    }
+
+    /// The color to draw text in.
+    pub fn color(&self) -> VGAColor {
+        if self.inverse {
+            self.color.inverted()
+        } else {
+            self.color
+        }
+    }
+
+    /// Takes the next character of the text, returning it if it's to be drawn rather than part of
+    /// an escape sequence.
+    pub fn feed(&mut self, c: char) -> Option<char> {
+        match (self.state, c) {
+            (AnsiState::Text, '\x1b') => self.state = AnsiState::Escape,
+            (AnsiState::Text, c) => return Some(c),
+            (AnsiState::Escape, '[') => {
+                self.state = AnsiState::Params {
+                    params: [0; MAX_ANSI_PARAMS],
+                    len: 1,
+                }
+            }
+            (AnsiState::Escape, _) => self.state = AnsiState::Text,
+            (AnsiState::Params { mut params, len }, '0'..='9') => {
+                if let Some(param) = params.get_mut(len - 1) {
+                    *param = param.saturating_mul(10).saturating_add(c as u8 - b'0');
+                }
+                self.state = AnsiState::Params { params, len };
+            }
+            (AnsiState::Params { params, len }, ';') => {
+                self.state = AnsiState::Params {
+                    params,
+                    len: len + 1,
+                }
+            }
+            (AnsiState::Params { params, len }, 'm') => {
+                for &param in &params[..len.min(MAX_ANSI_PARAMS)] {
+                    self.select_graphic_rendition(param);
+                }
+                self.state = AnsiState::Text;
+            }
+            (AnsiState::Params { .. }, _) => self.state = AnsiState::Text,
+        }
+        None
+    }
+
+    fn select_graphic_rendition(&mut self, param: u8) {
+        match param {
+            0 => self.inverse = false,
+            7 => self.inverse = true,
+            27 => self.inverse = false,
+            _ => {}
+        }
+        self.color = match param {
+            0 => DEFAULT_COLOR,
+            39 => self.color.with_fg(Color::White),
+            30..=37 => self.color.with_fg(ANSI_COLORS[param as usize - 30]),
+            90..=97 => self.color.with_fg(ANSI_LIGHT_COLORS[param as usize - 90]),
+            _ => self.color,
+        };
+    }
+}
+
+impl Default for AnsiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A character cell of the screen, as laid out in the VGA buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct VGABufferEntry {
+    pub ascii_char: u8,
+    pub color: VGAColor,
+}
+
+impl VGABufferEntry {
+    const BLANK: Self = Self {
+        ascii_char: b' ',
+        color: DEFAULT_COLOR,
+    };
 }
 
-const BUF_ADDR: usize = 0xb8000;
-const WIDTH: usize = 80;
-const HEIGHT: usize = 25;
+/// The physical address of the text mode buffer, also the address the bootloader identity maps
+/// it to.
+pub const BUF_ADDR: usize = 0xb8000;
+pub const BUF_SIZE: usize = core::mem::size_of::<VGABuffer>();
+/// Drawn for characters code page 437 has no glyph for.
+pub const REPLACEMENT_GLYPH: u8 = 0xfe;
+
+// Code page 437 glyphs for the non-ASCII characters it can show
+const CP437: [(char, u8); 91] = [
+    ('Ç', 0x80),
+    ('ü', 0x81),
+    ('é', 0x82),
+    ('â', 0x83),
+    ('ä', 0x84),
+    ('à', 0x85),
+    ('å', 0x86),
+    ('ç', 0x87),
+    ('ê', 0x88),
+    ('ë', 0x89),
+    ('è', 0x8a),
+    ('ï', 0x8b),
+    ('î', 0x8c),
+    ('ì', 0x8d),
+    ('Ä', 0x8e),
+    ('Å', 0x8f),
+    ('É', 0x90),
+    ('æ', 0x91),
+    ('Æ', 0x92),
+    ('ô', 0x93),
+    ('ö', 0x94),
+    ('ò', 0x95),
+    ('û', 0x96),
+    ('ù', 0x97),
+    ('ÿ', 0x98),
+    ('Ö', 0x99),
+    ('Ü', 0x9a),
+    ('¢', 0x9b),
+    ('£', 0x9c),
+    ('¥', 0x9d),
+    ('ƒ', 0x9f),
+    ('á', 0xa0),
+    ('í', 0xa1),
+    ('ó', 0xa2),
+    ('ú', 0xa3),
+    ('ñ', 0xa4),
+    ('Ñ', 0xa5),
+    ('ª', 0xa6),
+    ('º', 0xa7),
+    ('¿', 0xa8),
+    ('¬', 0xaa),
+    ('½', 0xab),
+    ('¼', 0xac),
+    ('¡', 0xad),
+    ('«', 0xae),
+    ('»', 0xaf),
+    ('░', 0xb0),
+    ('▒', 0xb1),
+    ('▓', 0xb2),
+    ('│', 0xb3),
+    ('┤', 0xb4),
+    ('║', 0xba),
+    ('╗', 0xbb),
+    ('╝', 0xbc),
+    ('┐', 0xbf),
+    ('└', 0xc0),
+    ('┴', 0xc1),
+    ('┬', 0xc2),
+    ('├', 0xc3),
+    ('─', 0xc4),
+    ('┼', 0xc5),
+    ('╚', 0xc8),
+    ('╔', 0xc9),
+    ('╩', 0xca),
+    ('╦', 0xcb),
+    ('╠', 0xcc),
+    ('═', 0xcd),
+    ('╬', 0xce),
+    ('┘', 0xd9),
+    ('┌', 0xda),
+    ('█', 0xdb),
+    ('▄', 0xdc),
+    ('▌', 0xdd),
+    ('▐', 0xde),
+    ('▀', 0xdf),
+    ('α', 0xe0),
+    ('ß', 0xe1),
+    ('π', 0xe3),
+    ('Σ', 0xe4),
+    ('σ', 0xe5),
+    ('µ', 0xe6),
+    ('Ω', 0xea),
+    ('∞', 0xec),
+    ('≡', 0xf0),
+    ('±', 0xf1),
+    ('≥', 0xf2),
+    ('≤', 0xf3),
+    ('÷', 0xf6),
+    ('°', 0xf8),
+    ('·', 0xfa),
+    ('²', 0xfd),
+];
+
+/// Returns the code page 437 glyph showing `c`, or [`REPLACEMENT_GLYPH`] if there is none. Every
+/// character takes up exactly one cell.
+pub fn glyph(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        _ => CP437
+            .iter()
+            .find(|&&(from, _)| from == c)
+            .map_or(REPLACEMENT_GLYPH, |&(_, glyph)| glyph),
+    }
+}
+
+/// Translates the first `WIDTH` characters of `s` to glyphs, returning them and their count.
+fn glyphs(s: &str) -> ([u8; WIDTH], usize) {
+    let mut glyphs = [b' '; WIDTH];
+    let mut len = 0;
+    for (cell, c) in glyphs.iter_mut().zip(s.chars()) {
+        *cell = glyph(c);
+        len += 1;
+    }
+    (glyphs, len)
+}
+
+const STATUS_BAR_COLOR: VGAColor = VGAColor((Color::LightGray as u8) << 4 | Color::Black as u8);
+pub const WIDTH: usize = 80;
+pub const HEIGHT: usize = 25;
+/// The columns of each pane of a split screen, see [`split`].
+pub const PANE_WIDTH: usize = (WIDTH - 1) / 2;
+/// The column between the panes of a split screen.
+pub const DIVIDER_COL: usize = PANE_WIDTH;
+const DIVIDER: VGABufferEntry = VGABufferEntry {
+    ascii_char: 0xb3,
+    color: DEFAULT_COLOR,
+};
 
 #[repr(transparent)]
 struct VGABuffer {
     chars: [[VGABufferEntry; WIDTH]; HEIGHT],
 }
 
-pub struct VGAWriter {
+/// Where a [`VGAWriter`] shows the screen when it's flushed.
+pub trait RenderTarget {
+    fn put_cell(&mut self, row: usize, col: usize, entry: VGABufferEntry);
+    fn set_cursor(&mut self, row: usize, col: usize);
+}
+
+/// The memory mapped VGA text buffer.
+pub struct MmioTarget {
+    buffer: &'static mut VGABuffer,
+}
+
+impl MmioTarget {
+    /// The buffer at [`BUF_ADDR`], identity mapped by the bootloader.
+    pub fn new() -> Self {
+        unsafe { Self::at(VirtAddr::new(BUF_ADDR as u64)) }
+    }
+
+    /// The buffer through the mapping at `addr`.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must map the `BUF_SIZE` bytes of the VGA buffer at [`BUF_ADDR`].
+    pub unsafe fn at(addr: VirtAddr) -> Self {
+        Self {
+            buffer: &mut *addr.as_mut_ptr::<VGABuffer>(),
+        }
+    }
+
+    /// Reads back a cell shown on the screen.
+    fn cell(&self, row: usize, col: usize) -> VGABufferEntry {
+        unsafe { core::ptr::addr_of!(self.buffer.chars[row][col]).read_volatile() }
+    }
+}
+
+impl Default for MmioTarget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RenderTarget for MmioTarget {
+    fn put_cell(&mut self, row: usize, col: usize, entry: VGABufferEntry) {
+        let addr = addr_of_mut!(self.buffer.chars[row][col]);
+        unsafe {
+            addr.write_volatile(entry);
+        }
+    }
+
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        // Video interrupt to cursor to current position
+        unsafe {
+            asm!("push rbx", "mov bx, 0x0", "pop rbx", in("ax") 0x02, in("dx") row << 8 | col);
+        }
+    }
+}
+
+/// A target which shows nothing, for a writer which is drawn on another writer's target with
+/// [`VGAWriter::flush_to`].
+pub struct Detached;
+
+impl RenderTarget for Detached {
+    fn put_cell(&mut self, _row: usize, _col: usize, _entry: VGABufferEntry) {}
+
+    fn set_cursor(&mut self, _row: usize, _col: usize) {}
+}
+
+/// A screen kept in memory, for looking at what a writer shows without the real buffer.
+pub struct MemoryTarget {
+    cells: [[VGABufferEntry; WIDTH]; HEIGHT],
+    cursor: (usize, usize),
+}
+
+impl MemoryTarget {
+    pub fn new() -> Self {
+        Self {
+            cells: [[VGABufferEntry::BLANK; WIDTH]; HEIGHT],
+            cursor: (0, 0),
+        }
+    }
+
+    pub fn cell(&self, row: usize, col: usize) -> VGABufferEntry {
+        self.cells[row][col]
+    }
+
+    pub fn cells(&self) -> &[[VGABufferEntry; WIDTH]; HEIGHT] {
+        &self.cells
+    }
+
+    /// Returns the row and column of the cursor.
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    /// Returns the text of every row, with glyphs as the characters of the same code and without
+    /// trailing spaces.
+    pub fn snapshot(&self) -> Vec<String> {
+        self.cells
+            .iter()
+            .map(|row| {
+                let text = row
+                    .iter()
+                    .map(|entry| entry.ascii_char as char)
+                    .collect::<String>();
+                String::from(text.trim_end())
+            })
+            .collect()
+    }
+}
+
+impl Default for MemoryTarget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RenderTarget for MemoryTarget {
+    fn put_cell(&mut self, row: usize, col: usize, entry: VGABufferEntry) {
+        self.cells[row][col] = entry;
+    }
+
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        self.cursor = (row, col);
+    }
+}
+
+/// Everything a [`VGAWriter`] shows, as saved by [`VGAWriter::save_screen`]. The writer keeps no
+/// scrollback, lines scrolled off the top are gone, so the screen is all there is to save.
+pub struct ScreenSnapshot {
+    cells: Box<[[VGABufferEntry; WIDTH]; HEIGHT]>,
     row: usize,
     col: usize,
-    color: VGAColor,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    ansi: AnsiParser,
+}
+
+impl ScreenSnapshot {
+    /// A screen showing `cells` with the cursor at `cursor`, on which text scrolls over every row
+    /// and is written in the default color.
+    pub fn new(cells: [[VGABufferEntry; WIDTH]; HEIGHT], cursor: (usize, usize)) -> Self {
+        Self {
+            cells: Box::new(cells),
+            row: cursor.0,
+            col: cursor.1,
+            scroll_top: 0,
+            scroll_bottom: HEIGHT - 1,
+            ansi: AnsiParser::new(),
+        }
+    }
+
+    /// Every row of the screen, including the ones outside the scroll region.
+    pub fn cells(&self) -> &[[VGABufferEntry; WIDTH]; HEIGHT] {
+        &self.cells
+    }
+
+    /// Returns the row and column of the cursor.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+}
+
+/// Keeps the contents of the screen, and shows them on its target when flushed. A writer can be
+/// narrowed to some of the columns of its target, where text wraps at its own width.
+pub struct VGAWriter<T = MmioTarget> {
+    row: usize,
+    col: usize,
+    // The columns of the target the writer draws in, text wraps at `width`
+    left: usize,
+    width: usize,
+    // Written text scrolls within these rows (inclusive), the rows outside are only changed by
+    // `write_row`
+    scroll_top: usize,
+    scroll_bottom: usize,
+    ansi: AnsiParser,
     buffer: VGABuffer,
-    output: &'static mut VGABuffer,
+    target: T,
+    /// Set when the writer may have been left in the middle of an update, after which the screen
+    /// is cleared before anything else is written.
+    inconsistent: bool,
 }
 
 impl VGAWriter {
     pub fn new() -> VGAWriter {
+        Self::new_with_target(MmioTarget::new())
+    }
+
+    /// Moves the output to the buffer mapped at `addr` and redraws the screen there.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must map the `BUF_SIZE` bytes of the VGA buffer at [`BUF_ADDR`].
+    pub unsafe fn attach_output(&mut self, addr: VirtAddr) {
+        self.target = MmioTarget::at(addr);
+        self.flush();
+    }
+}
+
+impl<T: RenderTarget> VGAWriter<T> {
+    /// Creates a writer showing the screen on `target`, starting with a blank screen.
+    pub fn new_with_target(target: T) -> Self {
         VGAWriter {
             row: HEIGHT - 1,
             col: 0,
-            color: VGAColor::new(Color::White, Color::Black),
+            left: 0,
+            width: WIDTH,
+            scroll_top: 0,
+            scroll_bottom: HEIGHT - 1,
+            ansi: AnsiParser::new(),
             buffer: VGABuffer {
-                chars: [[VGABufferEntry {
-                    ascii_char: b' ',
-                    color: VGAColor::new(Color::White, Color::Black),
-                }; WIDTH]; HEIGHT],
+                chars: [[VGABufferEntry::BLANK; WIDTH]; HEIGHT],
             },
-            output: unsafe { &mut *(BUF_ADDR as *mut VGABuffer) },
+            target,
+            inconsistent: false,
         }
     }
 
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
     pub fn write_byte(&mut self, b: u8) {
         match b {
             b'\n' => self.newline(),
             b'\r' => self.col = 0,
             b'\t' => self.col += 4,
             b => {
-                if self.col >= WIDTH {
+                if self.col >= self.width {
                     self.newline();
                 }
 
                 self.buffer.chars[self.row][self.col] = VGABufferEntry {
                     ascii_char: b,
-                    color: self.color,
+                    color: self.ansi.color(),
                 };
 
                 self.col += 1;
@@ -96,50 +589,223 @@ impl VGAWriter {
         }
     }
 
+    /// Writes text, following the ANSI escape sequences in it which set the color.
     pub fn write_str(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' | b'\r' | b'\t' => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+        if self.inconsistent {
+            self.reset();
+        }
+        for c in s.chars() {
+            let Some(c) = self.ansi.feed(c) else {
+                continue;
+            };
+            match c {
+                '\n' | '\r' | '\t' => self.write_byte(c as u8),
+                c => self.write_byte(glyph(c)),
             }
         }
     }
 
-    pub fn flush(&mut self) {
-        for row in 0..HEIGHT {
-            for col in 0..WIDTH {
-                let entry = self.buffer.chars[row][col];
-                let addr = addr_of_mut!(self.output.chars[row][col]);
-                unsafe {
-                    addr.write_volatile(entry);
-                }
+    /// Writes characters in their own colors, without changing the color following text is
+    /// written in.
+    pub fn write_colored(&mut self, cells: &[(char, VGAColor)]) {
+        let ansi = self.ansi;
+        for &(c, color) in cells {
+            self.ansi = AnsiParser {
+                color,
+                ..AnsiParser::new()
+            };
+            self.write_str(c.encode_utf8(&mut [0; 4]));
+        }
+        self.ansi = ansi;
+    }
+
+    /// Restricts written text to rows `top..=bottom`, scrolling only those rows. If the line being
+    /// written ends up below the region, the region is scrolled to keep it at the bottom.
+    pub fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        assert!(top <= bottom && bottom < HEIGHT, "invalid scroll region");
+        if self.row > bottom {
+            let shift = self.row - bottom;
+            for row in top..=bottom {
+                self.buffer.chars[row] = self.buffer.chars[row + shift];
             }
+            self.row = bottom;
+        }
+        if self.row < top {
+            self.row = top;
+            self.col = 0;
         }
+        self.scroll_top = top;
+        self.scroll_bottom = bottom;
+    }
 
-        // Video interrupt to cursor to current position
-        unsafe {
-            asm!("push rbx", "mov bx, 0x0", "pop rbx", in("ax") 0x02, in("dx") self.row << 8 | self.col);
+    pub fn scroll_region(&self) -> (usize, usize) {
+        (self.scroll_top, self.scroll_bottom)
+    }
+
+    /// Moves the writer to `width` columns of its target starting at `left`. Text already written
+    /// isn't wrapped again, what doesn't fit the new width is cut off.
+    pub fn set_columns(&mut self, left: usize, width: usize) {
+        assert!(width > 0 && left + width <= WIDTH, "invalid columns");
+        for row in self.buffer.chars.iter_mut() {
+            row[width..].fill(VGABufferEntry::BLANK);
+        }
+        self.col = self.col.min(width);
+        self.left = left;
+        self.width = width;
+    }
+
+    /// Returns the number of columns text wraps at.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Replaces the contents of a row with `s`, truncated to the width of the writer, without
+    /// moving the position written text continues from.
+    pub fn write_row(&mut self, row: usize, s: &str) {
+        let (glyphs, len) = glyphs(s);
+        self.fill_row(row, &glyphs[..len], self.ansi.color());
+    }
+
+    /// Replaces the contents of a row with characters drawn in their own colors, see
+    /// [`write_row`](Self::write_row).
+    pub fn write_row_colored(&mut self, row: usize, cells: &[(char, VGAColor)]) {
+        self.clear_row(row);
+        let entries = self.buffer.chars[row][..self.width].iter_mut();
+        for (entry, &(c, color)) in entries.zip(cells) {
+            *entry = VGABufferEntry {
+                ascii_char: glyph(c),
+                color,
+            };
         }
     }
 
+    /// Draws a status bar on the top row, with `left` and `right` aligned to the edges of the
+    /// screen and `mid` centered. Text which doesn't fit is cut off, with `left` drawn on top.
+    pub fn render_status_bar(&mut self, left: &str, mid: &str, right: &str) {
+        let mut row = [b' '; WIDTH];
+        let (left, left_len) = glyphs(left);
+        let (mid, mid_len) = glyphs(mid);
+        let (right, right_len) = glyphs(right);
+        let (left, mid, right) = (&left[..left_len], &mid[..mid_len], &right[..right_len]);
+        let mid_start = (WIDTH - mid.len()) / 2;
+        row[mid_start..mid_start + mid.len()].copy_from_slice(mid);
+        row[WIDTH - right.len()..].copy_from_slice(right);
+        row[..left.len()].copy_from_slice(left);
+        self.fill_row(0, &row, STATUS_BAR_COLOR);
+    }
+
+    /// Replaces a row with already translated glyphs.
+    fn fill_row(&mut self, row: usize, glyphs: &[u8], color: VGAColor) {
+        let blank = VGABufferEntry {
+            ascii_char: b' ',
+            color,
+        };
+        self.buffer.chars[row] = [blank; WIDTH];
+        for (col, &glyph) in glyphs.iter().take(self.width).enumerate() {
+            self.buffer.chars[row][col] = VGABufferEntry {
+                ascii_char: glyph,
+                color,
+            };
+        }
+    }
+
+    /// Copies the screen along with the cursor position, the scroll region and the color text is
+    /// written in.
+    pub fn save_screen(&self) -> ScreenSnapshot {
+        ScreenSnapshot {
+            cells: Box::new(self.buffer.chars),
+            row: self.row,
+            col: self.col,
+            scroll_top: self.scroll_top,
+            scroll_bottom: self.scroll_bottom,
+            ansi: self.ansi,
+        }
+    }
+
+    /// Puts the screen back the way it was when `snapshot` was taken, and shows it on the target.
+    pub fn restore_screen(&mut self, snapshot: &ScreenSnapshot) {
+        self.buffer.chars = *snapshot.cells;
+        self.row = snapshot.row;
+        self.col = snapshot.col;
+        self.scroll_top = snapshot.scroll_top;
+        self.scroll_bottom = snapshot.scroll_bottom;
+        self.ansi = snapshot.ansi;
+        self.flush();
+    }
+
+    /// Marks the writer as possibly left in the middle of an update, by code which was holding the
+    /// lock when it panicked. The next text written clears the screen first.
+    pub fn mark_inconsistent(&mut self) {
+        self.inconsistent = true;
+    }
+
+    /// Blanks the screen and forgets the cursor position, the scroll region and the color.
+    fn reset(&mut self) {
+        self.buffer.chars = [[VGABufferEntry::BLANK; WIDTH]; HEIGHT];
+        self.row = HEIGHT - 1;
+        self.col = 0;
+        self.scroll_top = 0;
+        self.scroll_bottom = HEIGHT - 1;
+        self.ansi = AnsiParser::new();
+        self.inconsistent = false;
+    }
+
+    /// Shows the screen on the target.
+    pub fn flush(&mut self) {
+        let Self { buffer, target, .. } = self;
+        Self::draw(buffer, self.left, self.width, (self.row, self.col), target);
+    }
+
+    /// Shows the screen on another target, for a writer sharing the columns of a target with
+    /// other writers.
+    pub fn flush_to(&self, target: &mut impl RenderTarget) {
+        Self::draw(
+            &self.buffer,
+            self.left,
+            self.width,
+            (self.row, self.col),
+            target,
+        );
+    }
+
+    fn draw(
+        buffer: &VGABuffer,
+        left: usize,
+        width: usize,
+        cursor: (usize, usize),
+        target: &mut impl RenderTarget,
+    ) {
+        for (row, cells) in buffer.chars.iter().enumerate() {
+            for (col, &entry) in cells[..width].iter().enumerate() {
+                target.put_cell(row, left + col, entry);
+            }
+        }
+        target.set_cursor(cursor.0, left + cursor.1);
+    }
+
     fn newline(&mut self) {
-        for row in 0..HEIGHT - 1 {
+        if self.row < self.scroll_bottom {
+            self.row += 1;
+            self.clear_row(self.row);
+            self.col = 0;
+            return;
+        }
+
+        for row in self.scroll_top..self.scroll_bottom {
             for col in 0..WIDTH {
                 let entry = self.buffer.chars[row + 1][col];
                 self.buffer.chars[row][col] = entry;
             }
         }
 
-        self.clear_row(HEIGHT - 1);
+        self.clear_row(self.scroll_bottom);
         self.col = 0;
     }
 
     fn clear_row(&mut self, row: usize) {
         let blank = VGABufferEntry {
             ascii_char: b' ',
-            color: self.color,
+            color: self.ansi.color(),
         };
 
         for col in 0..WIDTH {
@@ -148,17 +814,38 @@ impl VGAWriter {
     }
 }
 
-impl fmt::Write for VGAWriter {
+impl<T: RenderTarget> fmt::Write for VGAWriter<T> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_str(s);
         Ok(())
     }
 }
 
+static CONSOLE: AtomicU8 = AtomicU8::new(Console::Vga as u8);
+
+/// Selects where `print!` and `println!` output goes.
+pub fn set_console(console: Console) {
+    CONSOLE.store(console as u8, Ordering::Relaxed);
+}
+
+pub fn console() -> Console {
+    match CONSOLE.load(Ordering::Relaxed) {
+        0 => Console::Vga,
+        1 => Console::Serial,
+        _ => Console::Both,
+    }
+}
+
 lazy_static! {
     pub static ref WRITER: Mutex<VGAWriter> = Mutex::new(VGAWriter::new());
 }
 
+/// The screens saved by the open [`ScreenSession`]s, outermost first.
+static SESSIONS: Mutex<Vec<ScreenSnapshot>> = Mutex::new(Vec::new());
+/// The writer of the right pane while the screen is split, drawn on the target of [`WRITER`].
+/// Always locked after [`WRITER`].
+static SIDE: Mutex<Option<VGAWriter<Detached>>> = Mutex::new(None);
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vgabuf::_print(format_args!($($arg)*)));
@@ -173,17 +860,256 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
+    let console = console();
+    if console != Console::Serial && !console::hold_fmt(args) {
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.write_fmt(args).unwrap();
+            writer.flush();
+        });
+    }
+    if console != Console::Vga {
+        crate::serial::_print(args);
+    }
+}
+
+/// Writes to the screen only, whatever the console is set to.
+pub fn write_str(s: &str) {
+    if console::hold(Chunk::Text(s)) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_str(s);
+        writer.flush();
+    });
+}
+
+/// Writes characters in their own colors to the screen only, see [`VGAWriter::write_colored`].
+pub fn write_colored(cells: &[(char, VGAColor)]) {
+    if console::hold(Chunk::Colored(cells)) {
+        return;
+    }
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
-        writer.write_fmt(args).unwrap();
+        writer.write_colored(cells);
         writer.flush();
     });
 }
 
+/// Writes to the VGA buffer through the mapping at `addr` instead of the bootloader's identity
+/// mapping, see [`VGAWriter::attach_output`].
+///
+/// # Safety
+///
+/// `addr` must map the `BUF_SIZE` bytes of the VGA buffer at [`BUF_ADDR`].
+pub unsafe fn attach_output(addr: VirtAddr) {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.attach_output(addr);
+        if let Some(side) = SIDE.lock().as_ref() {
+            draw_side(&mut writer, side);
+        }
+    });
+}
+
 pub fn flush() {
     interrupts::without_interrupts(|| WRITER.lock().flush());
 }
 
+/// Copies the screen, see [`VGAWriter::save_screen`].
+pub fn save_screen() -> ScreenSnapshot {
+    interrupts::without_interrupts(|| WRITER.lock().save_screen())
+}
+
+pub fn restore_screen(snapshot: &ScreenSnapshot) {
+    interrupts::without_interrupts(|| WRITER.lock().restore_screen(snapshot));
+}
+
+/// Lends the whole screen to a full-screen app. The screen is saved when the session starts and
+/// restored when it's dropped, so the console looks the same after the app as before it. Sessions
+/// can be nested.
+///
+/// Panics don't unwind, so a session isn't dropped when the app panics. The panic handler calls
+/// [`restore_console`] instead.
+pub struct ScreenSession {
+    // Not `Send`, sessions have to end in the reverse order they started in
+    _not_send: core::marker::PhantomData<*const ()>,
+}
+
+impl ScreenSession {
+    pub fn start() -> Self {
+        let snapshot = save_screen();
+        interrupts::without_interrupts(|| SESSIONS.lock().push(snapshot));
+        Self {
+            _not_send: core::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for ScreenSession {
+    fn drop(&mut self) {
+        let snapshot = interrupts::without_interrupts(|| SESSIONS.lock().pop());
+        if let Some(snapshot) = snapshot {
+            restore_screen(&snapshot);
+        }
+    }
+}
+
+/// Puts back the screen from before the outermost open [`ScreenSession`] and gives the console
+/// the whole width of the screen, for the panic handler. Doesn't wait for the writer, the
+/// sessions or the right pane if they're in use, and doesn't allocate.
+pub fn restore_console() {
+    let Some(mut writer) = WRITER.try_lock() else {
+        return;
+    };
+    if SIDE.try_lock().and_then(|mut side| side.take()).is_some() {
+        writer.set_columns(0, WIDTH);
+        writer.flush();
+    }
+    if let Some(snapshot) = SESSIONS.try_lock().as_ref().and_then(|s| s.first()) {
+        writer.restore_screen(snapshot);
+    }
+}
+
+/// Splits the screen into two panes of [`PANE_WIDTH`] columns with a divider between them. The
+/// left pane keeps the console, narrowed to the pane, and the right one starts out blank and is
+/// written with [`write_side`]. Each pane wraps its text and scrolls on its own. Returns `false`
+/// if the screen was split already.
+pub fn split() -> bool {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let mut side = SIDE.lock();
+        if side.is_some() {
+            return false;
+        }
+        writer.set_columns(0, PANE_WIDTH);
+        writer.flush();
+        let mut pane = VGAWriter::new_with_target(Detached);
+        pane.set_columns(DIVIDER_COL + 1, PANE_WIDTH);
+        draw_side(&mut writer, &pane);
+        *side = Some(pane);
+        true
+    })
+}
+
+/// Gives the whole width of the screen back to the console and redraws it, dropping what the
+/// right pane showed. Returns `false` if the screen wasn't split.
+pub fn unsplit() -> bool {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        if SIDE.lock().take().is_none() {
+            return false;
+        }
+        writer.set_columns(0, WIDTH);
+        writer.flush();
+        true
+    })
+}
+
+pub fn is_split() -> bool {
+    interrupts::without_interrupts(|| SIDE.lock().is_some())
+}
+
+/// Returns the number of columns `print!` output wraps at, fewer than [`WIDTH`] while the screen
+/// is split.
+pub fn columns() -> usize {
+    interrupts::without_interrupts(|| WRITER.lock().width())
+}
+
+/// Writes to the right pane of a split screen, see [`split`]. Returns `false` without writing if
+/// the screen isn't split.
+pub fn write_side(s: &str) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let mut side = SIDE.lock();
+        let Some(side) = side.as_mut() else {
+            return false;
+        };
+        side.write_str(s);
+        draw_side(&mut writer, side);
+        true
+    })
+}
+
+/// Draws the right pane and the divider on the target of the console. The columns after the pane
+/// are left blank.
+fn draw_side(writer: &mut VGAWriter, side: &VGAWriter<Detached>) {
+    for row in 0..HEIGHT {
+        writer.target.put_cell(row, DIVIDER_COL, DIVIDER);
+        for col in DIVIDER_COL + 1 + PANE_WIDTH..WIDTH {
+            writer.target.put_cell(row, col, VGABufferEntry::BLANK);
+        }
+    }
+    side.flush_to(&mut writer.target);
+    // The cursor belongs to the console
+    writer.target.set_cursor(writer.row, writer.col);
+}
+
+/// Restricts `print!` output to rows `top..=bottom`, see [`VGAWriter::set_scroll_region`].
+pub fn set_scroll_region(top: usize, bottom: usize) {
+    interrupts::without_interrupts(|| WRITER.lock().set_scroll_region(top, bottom));
+}
+
+/// Returns the rows `print!` output is restricted to.
+pub fn scroll_region() -> (usize, usize) {
+    interrupts::without_interrupts(|| WRITER.lock().scroll_region())
+}
+
+/// Draws the status bar on the top row, see [`VGAWriter::render_status_bar`].
+pub fn render_status_bar(left: &str, mid: &str, right: &str) {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.render_status_bar(left, mid, right);
+        writer.flush();
+    });
+}
+
+/// Replaces the contents of a row outside the scroll region and shows it on screen.
+pub fn write_row(row: usize, s: &str) {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_row(row, s);
+        writer.flush();
+    });
+}
+
+/// Replaces the contents of a row outside the scroll region with colored characters and shows it
+/// on screen.
+pub fn write_row_colored(row: usize, cells: &[(char, VGAColor)]) {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_row_colored(row, cells);
+        writer.flush();
+    });
+}
+
+/// Returns the text shown on a row, without trailing spaces.
+pub fn row_text(row: usize) -> String {
+    let glyphs = row_glyphs(row);
+    let chars = glyphs.iter().map(|&glyph| glyph as char);
+    let text = chars.collect::<String>();
+    text.trim_end().into()
+}
+
+/// Returns the glyphs shown on a row.
+pub fn row_glyphs(row: usize) -> [u8; WIDTH] {
+    let writer = WRITER.lock();
+    core::array::from_fn(|col| writer.target.cell(row, col).ascii_char)
+}
+
+/// Returns the attribute bytes shown on a row.
+#[cfg(test)]
+pub(crate) fn row_attributes(row: usize) -> [u8; WIDTH] {
+    let writer = WRITER.lock();
+    core::array::from_fn(|col| writer.target.cell(row, col).color.attribute())
+}
+
+#[cfg(test)]
+fn memory_writer() -> VGAWriter<MemoryTarget> {
+    VGAWriter::new_with_target(MemoryTarget::new())
+}
+
 #[test_case]
 fn test_print() {
     println!("Printning to VGA buffer");
@@ -196,18 +1122,26 @@ fn test_print_loads() {
     }
 }
 
-#[cfg(test)]
-fn get_char_at(row: usize, col: usize) -> char {
-    WRITER.lock().output.chars[row][col].ascii_char as char
+#[test_case]
+fn test_println_reaches_screen() {
+    let s = "Check that this string is actually printed to the VGA buffer";
+    println!("{}", s);
+    assert_eq!(row_text(HEIGHT - 2), s);
 }
 
 #[test_case]
 fn test_println() {
-    let s = "Check that this string is actually printed to the VGA buffer";
-    println!("{}", s);
-    for (i, c) in s.chars().enumerate() {
-        assert_eq!(get_char_at(HEIGHT - 2, i), c);
-    }
+    use core::fmt::Write;
+
+    let mut writer = memory_writer();
+    writeln!(writer, "first").unwrap();
+    writeln!(writer, "second line").unwrap();
+    write!(writer, "unfinished").unwrap();
+    writer.flush();
+    let screen = writer.target().snapshot();
+    assert_eq!(screen[HEIGHT - 3..], ["first", "second line", "unfinished"]);
+    assert!(screen[..HEIGHT - 3].iter().all(String::is_empty));
+    assert_eq!(writer.target().cursor(), (HEIGHT - 1, 10));
 }
 
 #[test_case]
@@ -216,18 +1150,207 @@ fn test_wrapping() {
 
     let loops = 10;
     let s = "Repeating this string should wrap around the VGA buffer";
+    let mut writer = memory_writer();
+    for _ in 0..loops {
+        write!(writer, "{}", s).unwrap();
+    }
+    writeln!(writer).unwrap();
+    writer.flush();
+
+    let text = s.chars().cycle().take(s.len() * loops).collect::<String>();
+    let rows = text.len().div_ceil(WIDTH);
+    let screen = writer.target().snapshot();
+    let start_row = HEIGHT - 1 - rows;
+    for (i, row) in screen[start_row..HEIGHT - 1].iter().enumerate() {
+        let expected = &text[i * WIDTH..text.len().min((i + 1) * WIDTH)];
+        assert_eq!(row, expected.trim_end());
+    }
+    assert_eq!(screen[HEIGHT - 1], "");
+}
+
+#[test_case]
+fn test_scroll_region() {
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
-        writeln!(writer).unwrap();
-        for _ in 0..loops {
-            write!(writer, "{}", s).unwrap();
+        writer.set_scroll_region(0, HEIGHT - 2);
+        writer.write_row(HEIGHT - 1, "reserved");
+        for i in 0..HEIGHT {
+            use core::fmt::Write;
+            writeln!(writer, "line {}", i).unwrap();
         }
-        writeln!(writer).unwrap();
-        let start_row = HEIGHT - 2 - s.len() * loops / WIDTH;
-        for (i, c) in s.chars().cycle().take(s.len() * loops).enumerate() {
-            let row = start_row + i / WIDTH;
-            let col = i % WIDTH;
-            assert_eq!(writer.output.chars[row][col].ascii_char as char, c);
+        writer.flush();
+    });
+    assert_eq!(row_text(HEIGHT - 1), "reserved");
+    assert_eq!(row_text(HEIGHT - 3), alloc::format!("line {}", HEIGHT - 1));
+    assert_eq!(row_text(HEIGHT - 2), "");
+
+    write_row(HEIGHT - 1, "");
+    set_scroll_region(0, HEIGHT - 1);
+}
+
+#[test_case]
+fn test_inconsistent_writer_clears_screen() {
+    let mut writer = VGAWriter::new_with_target(MemoryTarget::new());
+    writer.set_scroll_region(2, 5);
+    writer.write_str("\x1b[31mhalf written");
+    writer.mark_inconsistent();
+    writer.write_str("panicked");
+    writer.flush();
+
+    let text = |row: usize| writer.target().cells()[row].map(|entry| entry.ascii_char);
+    assert_eq!(&text(HEIGHT - 1)[..9], b"panicked ");
+    assert!((0..HEIGHT - 1).all(|row| text(row) == [b' '; WIDTH]));
+    assert_eq!(writer.scroll_region(), (0, HEIGHT - 1));
+    assert_eq!(
+        writer.target().cell(HEIGHT - 1, 0),
+        VGABufferEntry {
+            ascii_char: b'p',
+            color: AnsiParser::new().color(),
         }
-    })
+    );
+}
+
+#[test_case]
+fn test_render_status_bar() {
+    let mut writer = VGAWriter::new();
+    writer.render_status_bar("left", "mid", "right");
+    let row = writer.buffer.chars[0].map(|entry| entry.ascii_char);
+    assert_eq!(&row[..4], b"left");
+    assert_eq!(&row[38..41], b"mid");
+    assert_eq!(&row[WIDTH - 5..], b"right");
+    assert!(writer.buffer.chars[0]
+        .iter()
+        .all(|entry| entry.color == STATUS_BAR_COLOR));
+
+    // The left text wins when they overlap
+    writer.render_status_bar(&"l".repeat(WIDTH), "mid", "right");
+    assert!(writer.buffer.chars[0].iter().all(|e| e.ascii_char == b'l'));
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    let mut writer = VGAWriter::new();
+    writer.write_str("\x1b[91mred\x1b[0m \x1b[1;32mgreen\x1b[2Kplain\x1b[m.\x1b[7mon\x1b[27moff");
+    let row = &writer.buffer.chars[writer.row];
+    let text = row.map(|entry| entry.ascii_char);
+    assert_eq!(&text[..15], b"red green plain");
+    let red = VGAColor::new(Color::LightRed, Color::Black);
+    let green = VGAColor::new(Color::Green, Color::Black);
+    let colors = row.map(|entry| entry.color);
+    assert_eq!(colors[..3], [red; 3]);
+    assert_eq!(colors[3], DEFAULT_COLOR);
+    // Unsupported parameters and sequences are skipped, the color stays until it's reset
+    assert_eq!(colors[4..15], [green; 11]);
+    assert_eq!(colors[15], DEFAULT_COLOR);
+    // Reverse video swaps the colors until it's turned off
+    let inverted = VGAColor::new(Color::Black, Color::White);
+    assert_eq!(DEFAULT_COLOR.inverted(), inverted);
+    assert_eq!(colors[16..18], [inverted; 2]);
+    assert_eq!(colors[18..21], [DEFAULT_COLOR; 3]);
+}
+
+#[test_case]
+fn test_save_and_restore_screen() {
+    use core::fmt::Write;
+
+    let mut writer = memory_writer();
+    for i in 0..HEIGHT {
+        write!(writer, "\n\x1b[3{}mline {} of the screen", i % 8, i).unwrap();
+    }
+    writer.write_str("\x1b[92m");
+    writer.set_scroll_region(2, HEIGHT - 3);
+    writer.flush();
+    let cells = *writer.target().cells();
+    let cursor = writer.target().cursor();
+    let snapshot = writer.save_screen();
+
+    writer.set_scroll_region(0, HEIGHT - 1);
+    writer.write_str("\x1b[94m");
+    for _ in 0..HEIGHT {
+        writer.write_str(&"#".repeat(WIDTH));
+    }
+    writer.write_str("scribbled");
+    writer.flush();
+    assert_ne!(*writer.target().cells(), cells);
+
+    writer.restore_screen(&snapshot);
+    assert_eq!(*writer.target().cells(), cells);
+    assert_eq!(writer.target().cursor(), cursor);
+    assert_eq!(writer.scroll_region(), (2, HEIGHT - 3));
+    // Text is written in the color from before
+    writer.write_str("\nafter");
+    let row = writer.target().cursor().0;
+    let color = VGAColor::new(Color::LightGreen, Color::Black);
+    assert_eq!(writer.buffer.chars[row][0].color, color);
+}
+
+#[test_case]
+fn test_screen_session() {
+    println!("before the session");
+    let before = (0..HEIGHT).map(row_text).collect::<Vec<_>>();
+    {
+        let _session = ScreenSession::start();
+        for row in 0..HEIGHT {
+            write_row(row, "app");
+        }
+        {
+            let _nested = ScreenSession::start();
+            write_row(0, "nested");
+        }
+        assert_eq!(row_text(0), "app");
+
+        // As the panic handler would, with the session still open
+        restore_console();
+        assert_eq!((0..HEIGHT).map(row_text).collect::<Vec<_>>(), before);
+        write_row(0, "app");
+    }
+    assert_eq!((0..HEIGHT).map(row_text).collect::<Vec<_>>(), before);
+}
+
+#[test_case]
+fn test_split_screen() {
+    let blank = [b' '; WIDTH];
+    print!("\n");
+    assert!(split());
+    assert!(!split());
+    assert_eq!(columns(), PANE_WIDTH);
+
+    // Each pane wraps at its own width
+    println!("{}", "c".repeat(PANE_WIDTH + 3));
+    write_side(&alloc::format!("{}tail", "s".repeat(PANE_WIDTH)));
+    let row = |row: usize| row_glyphs(HEIGHT - row);
+    assert_eq!(row(3)[..PANE_WIDTH], [b'c'; PANE_WIDTH]);
+    assert_eq!(row(3)[DIVIDER_COL + 1..], blank[DIVIDER_COL + 1..]);
+    assert_eq!(row(2)[..3], *b"ccc");
+    assert_eq!(row(2)[3..DIVIDER_COL], blank[3..DIVIDER_COL]);
+    assert_eq!(row(2)[DIVIDER_COL + 1..WIDTH - 1], [b's'; PANE_WIDTH]);
+    assert_eq!(row(1)[..DIVIDER_COL], blank[..DIVIDER_COL]);
+    assert_eq!(row(1)[DIVIDER_COL + 1..DIVIDER_COL + 5], *b"tail");
+    // The column after the right pane is left blank
+    assert!((0..HEIGHT).all(|row| row_glyphs(row)[WIDTH - 1] == b' '));
+
+    // Scrolling the console leaves the divider and the side pane alone
+    for i in 0..HEIGHT * 2 {
+        println!("line {} {}", i, "x".repeat(50));
+    }
+    assert!((0..HEIGHT).all(|row| row_glyphs(row)[DIVIDER_COL] == DIVIDER.ascii_char));
+    assert_eq!(row(2)[DIVIDER_COL + 1..WIDTH - 1], [b's'; PANE_WIDTH]);
+    assert_eq!(row(1)[DIVIDER_COL + 1..DIVIDER_COL + 5], *b"tail");
+    let last = alloc::format!("line {} {}", HEIGHT * 2 - 1, "x".repeat(50));
+    assert_eq!(row(3)[..PANE_WIDTH], last.as_bytes()[..PANE_WIDTH]);
+    assert_eq!(
+        row(2)[..last.len() - PANE_WIDTH],
+        last.as_bytes()[PANE_WIDTH..]
+    );
+
+    // Joining the panes redraws the console over the whole width
+    assert!(unsplit());
+    assert!(!unsplit());
+    assert!(!write_side("dropped"));
+    assert_eq!(columns(), WIDTH);
+    let right_blank = |row: usize| row_glyphs(row)[DIVIDER_COL..] == blank[DIVIDER_COL..];
+    assert!((0..HEIGHT).all(right_blank));
+    let long = "w".repeat(WIDTH - 1);
+    println!("{}", long);
+    assert_eq!(row_text(HEIGHT - 2), long);
 }
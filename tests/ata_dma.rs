@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hannos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{cell::Cell, panic::PanicInfo};
+use hannos::{
+    allocator,
+    fs::{
+        ata::{AtaDisk, Drive, MAX_TRANSFER_SECTORS},
+        disk::{self, BlockDevice, BLOCK_SIZE, SECTOR_SIZE},
+        file::FileSystem,
+        stress, FILESYSTEM, VFS,
+    },
+    hlt_loop,
+    memory::{self, BootInfoFrameAllocator},
+    rand::Rng,
+    task::{deferred, select2, simple_executor::SimpleExecutor, yield_now, Task},
+};
+use x86_64::VirtAddr;
+
+/// Blocks read in one go by the async read, 32 DMA transfers.
+const READ_BLOCKS: usize = 512;
+const READ_TRANSFERS: usize = READ_BLOCKS * BLOCK_SIZE / (MAX_TRANSFER_SECTORS * SECTOR_SIZE);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+    // The DMA buffers are mapped through the kernel's page tables
+    hannos::init_paging(mapper, frame_allocator).expect("paging initialization failed");
+    deferred::init();
+
+    test_main();
+
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::test_panic_handler(info)
+}
+
+// QEMU is run with a blank disk as the primary slave, the primary master being the boot disk, see
+// `.cargo/runner.sh`
+
+#[test_case]
+fn test_fsstress_over_dma() {
+    let ata = AtaDisk::open(Drive::Slave).unwrap();
+    assert!(ata.uses_dma());
+    let previous = disk::attach(Box::new(ata));
+
+    FileSystem::format().unwrap();
+    FILESYSTEM.lock().mount().unwrap();
+    let report = stress::stress(&mut VFS.lock(), "fsstress", 300, &mut Rng::new(7)).unwrap();
+    assert!(report.writes > 0 && report.reads > 0);
+    FILESYSTEM.lock().unmount();
+    assert!(disk::stats().reads > 0);
+
+    disk::attach(previous);
+}
+
+#[test_case]
+fn test_async_read_lets_other_tasks_run() {
+    let mut ata = AtaDisk::open(Drive::Slave).unwrap();
+    let data = (0..READ_BLOCKS * BLOCK_SIZE)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let bufs = data
+        .chunks(BLOCK_SIZE)
+        .map(|chunk| <&[u8; BLOCK_SIZE]>::try_from(chunk).unwrap())
+        .collect::<Vec<_>>();
+    ata.write_blocks(0, &bufs).unwrap();
+    let before = ata.stats();
+
+    // The counter only runs while the reader waits for the drive
+    let done = Rc::new(Cell::new(false));
+    let count = Rc::new(Cell::new(0usize));
+    let read = Rc::new(Cell::new(None));
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new({
+        let (done, read) = (done.clone(), read.clone());
+        async move {
+            let mut buf = vec![0; READ_BLOCKS * BLOCK_SIZE];
+            let reading = async {
+                ata.read_async(0, &mut buf).await.unwrap();
+            };
+            select2(reading, deferred::run()).await;
+            let after = ata.stats();
+            read.set(Some((
+                buf == data,
+                after.reads - before.reads,
+                after.blocks_read - before.blocks_read,
+            )));
+            done.set(true);
+        }
+    }));
+    executor.spawn(Task::new({
+        let (done, count) = (done.clone(), count.clone());
+        async move {
+            while !done.get() {
+                count.set(count.get() + 1);
+                yield_now().await;
+            }
+        }
+    }));
+    executor.run();
+
+    // Each transfer is a read of the drive, and the reader waited for every one of them
+    assert_eq!(READ_TRANSFERS, 32);
+    assert_eq!(read.get(), Some((true, READ_TRANSFERS, READ_BLOCKS)));
+    assert!(
+        count.get() >= READ_TRANSFERS,
+        "the counter ran {} times",
+        count.get()
+    );
+}
@@ -4,12 +4,31 @@
 #![test_runner(hannos::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use hannos::println;
+extern crate alloc;
+
+use alloc::string::ToString;
+use bootloader::{entry_point, BootInfo};
+use hannos::{
+    allocator, hlt_loop,
+    memory::{self, BootInfoFrameAllocator},
+    println, vgabuf,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+    // Printed like the kernel prints it at boot
+    hannos::version::print_banner();
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
     test_main();
-    loop {}
+
+    hlt_loop();
 }
 
 #[panic_handler]
@@ -21,3 +40,18 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 fn test_println_simple() {
     println!("test_println_simple output");
 }
+
+#[test_case]
+fn test_version_banner() {
+    let info = hannos::version();
+    assert!(!info.version.is_empty());
+    assert!(!info.git_hash.is_empty());
+    assert!(!info.profile.is_empty());
+    // The banner printed at boot is still on the screen
+    let banner = info.to_string();
+    assert!(
+        (0..vgabuf::HEIGHT).any(|row| vgabuf::row_text(row) == banner),
+        "no \"{}\" in the boot output",
+        banner
+    );
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hannos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hannos::{
+    allocator::{self, AllocatorKind},
+    cmdline::{self, Console},
+    hlt_loop,
+    log::{self, LogLevel},
+    memory::{self, BootInfoFrameAllocator},
+    vgabuf,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // Set before `hannos::init`, which applies the command line
+    cmdline::init("console=both loglevel=4 allocator=bump init=\"echo hello\" unknown=1");
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::select(cmdline::args().allocator);
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+
+    test_main();
+
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_args_parsed() {
+    let args = cmdline::args();
+    assert_eq!(args.console, Console::Both);
+    assert_eq!(args.loglevel, LogLevel::Trace);
+    assert_eq!(args.allocator, AllocatorKind::Bump);
+    assert_eq!(args.init, Some("echo hello"));
+}
+
+#[test_case]
+fn test_console_and_loglevel_applied() {
+    assert_eq!(vgabuf::console(), Console::Both);
+    assert_eq!(log::level(), LogLevel::Trace);
+    assert!(log::enabled(LogLevel::Trace));
+}
+
+#[test_case]
+fn test_bump_allocator_selected() {
+    assert_eq!(allocator::selected(), AllocatorKind::Bump);
+    let x = Box::new(41);
+    let v = (0..100).collect::<Vec<u64>>();
+    assert_eq!(*x, 41);
+    assert_eq!(v.iter().sum::<u64>(), 99 * 100 / 2);
+}
@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hannos::{
+    allocator, exit_qemu,
+    memory::{self, BootInfoFrameAllocator},
+    sprint, sprintln, QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    sprint!("double_free... ");
+
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+    panic!("second free of the same block was not detected");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::panic::report(info);
+    let reported = hannos::panic::with_last_message(|msg| {
+        msg.contains("double free of 0x") && msg.contains("(64 bytes)")
+    });
+    if reported == Some(true) {
+        sprintln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        sprintln!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hannos::hlt_loop();
+}
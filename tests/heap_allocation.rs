@@ -19,7 +19,7 @@ use x86_64::VirtAddr;
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    hannos::init();
+    hannos::init().expect("initialization failed");
     let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_memory_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hannos::{
+    allocator::{self, oom::EMERGENCY_POOL_SIZE, AllocatorKind, Canary, CANARY_SIZE},
+    exit_qemu, hlt_loop,
+    memory::{self, BootInfoFrameAllocator},
+    sprint, sprintln, QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    sprint!("heap_canary... ");
+
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    // The bump allocator hands out the whole heap but for the emergency pool as a single
+    // allocation, ending at the canary
+    allocator::select(AllocatorKind::Bump);
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+    allocator::check_canaries().expect("canaries dead before the overrun");
+
+    let heap_size = allocator::heap_info().size;
+    let layout =
+        Layout::from_size_align(heap_size - 2 * CANARY_SIZE - EMERGENCY_POOL_SIZE, 16).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        assert!(!ptr.is_null());
+        ptr.add(layout.size()).write(0);
+    }
+    // The timer interrupt checks the canaries with the `canary_checks` feature
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::panic::report(info);
+    let reported = hannos::panic::with_last_message(|msg| {
+        let addr = msg
+            .split("heap end canary at 0x")
+            .nth(1)
+            .and_then(|rest| rest.split(' ').next())
+            .and_then(|hex| usize::from_str_radix(hex, 16).ok());
+        addr == Some(Canary::HeapEnd.addr())
+    });
+    if reported == Some(true) {
+        sprintln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        sprintln!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hannos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hannos::{
+    allocator::{self, AllocatorKind, CANARY_SIZE},
+    cmdline, hlt_loop,
+    memory::{self, BootInfoFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+const MIB: usize = 1024 * 1024;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    cmdline::init("heap_size=4");
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    // The bump allocator hands out memory up to the end of the heap and nothing past it
+    allocator::select(AllocatorKind::Bump);
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+
+    test_main();
+
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_heap_size_from_cmdline() {
+    let info = allocator::heap_info();
+    assert_eq!(info.start, allocator::HEAP_START);
+    assert_eq!(info.size, 4 * MIB);
+    assert_eq!(allocator::stats().total, 4 * MIB);
+}
+
+#[test_case]
+fn test_allocations_up_to_the_limit() {
+    // Every chunk is touched at its end, which faults if it isn't mapped
+    let chunk = Layout::from_size_align(64 * 1024, 16).unwrap();
+    let mut allocated = 0;
+    loop {
+        let ptr = unsafe { alloc(chunk) };
+        if ptr.is_null() {
+            break;
+        }
+        unsafe { ptr.add(chunk.size() - 1).write(0xAA) };
+        allocated += chunk.size();
+    }
+    // The heap is used up to the size asked for on the command line, and not past it
+    let usable = allocator::heap_info().size - 2 * CANARY_SIZE;
+    assert!(allocated <= usable);
+    assert!(allocated > usable - 2 * chunk.size());
+}
@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hannos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hannos::{
+    hlt_loop, init_state, initialized,
+    task::keyboard::{self, KeyboardError, ScancodeStream},
+    InitFlags, InitState, InitStatus,
+};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    assert_eq!(init_state(), InitState::Uninitialized);
+    assert_eq!(initialized(), InitFlags::NONE);
+    // Only what's needed to run, leaving out the keyboard
+    let status = hannos::init_with(InitFlags::CONSOLES | InitFlags::INTERRUPTS);
+    assert_eq!(status, Ok(InitStatus::Initialized));
+
+    test_main();
+
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_init_with_subset() {
+    assert_eq!(init_state(), InitState::Ready);
+    assert!(initialized().contains(InitFlags::CONSOLES | InitFlags::INTERRUPTS));
+    assert!(!initialized().contains(InitFlags::KEYBOARD));
+    assert!(!initialized().contains(InitFlags::HEAP));
+}
+
+#[test_case]
+fn test_second_init_is_noop() {
+    assert_eq!(hannos::init(), Ok(InitStatus::AlreadyInitialized));
+    assert_eq!(hannos::init(), Ok(InitStatus::AlreadyInitialized));
+    assert_eq!(init_state(), InitState::Ready);
+    assert!(!initialized().contains(InitFlags::KEYBOARD));
+}
+
+#[test_case]
+fn test_keyboard_init_is_idempotent() {
+    keyboard::init();
+    keyboard::init();
+    assert!(initialized().contains(InitFlags::KEYBOARD));
+
+    let stream = ScancodeStream::new();
+    assert!(stream.is_ok());
+    assert!(matches!(
+        ScancodeStream::new(),
+        Err(KeyboardError::StreamTaken)
+    ));
+}
@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hannos::{
+    allocator, exit_qemu, hlt_loop,
+    memory::{self, BootInfoFrameAllocator},
+    shell::jobs::JobFiles,
+    sprint, sprintln, task, QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+const COMMAND: &str = "explode --now | wc";
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    sprint!("job_panic... ");
+
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+
+    let files = JobFiles::new(COMMAND);
+    task::block_on(files.run(async {
+        task::yield_now().await;
+        let values = Vec::from([1, 2, 3]);
+        let _ = values[core::hint::black_box(3)];
+    }));
+    // Not in the job anymore, so the report doesn't name it
+    panic!("execution continued after a panic in a job");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::panic::report(info);
+    let reported = hannos::panic::with_last_message(|msg| {
+        msg.contains("index out of bounds") && msg.contains(COMMAND) && msg.contains("in job ")
+    });
+    if reported == Some(true) {
+        sprintln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        sprintln!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hannos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{
+    alloc::{alloc, dealloc, Layout},
+    boxed::Box,
+    vec::Vec,
+};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use hannos::{
+    allocator::{self, oom},
+    hlt_loop,
+    memory::{self, BootInfoFrameAllocator},
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+const CHUNK: usize = 1024;
+
+/// Cache-like data, which can be dropped whenever memory is needed.
+static CACHE: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+static RECLAIM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+
+    test_main();
+
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::test_panic_handler(info)
+}
+
+fn chunk_layout() -> Layout {
+    Layout::from_size_align(CHUNK, 8).unwrap()
+}
+
+fn drop_cache() -> usize {
+    RECLAIM_CALLS.fetch_add(1, Ordering::SeqCst);
+    let Some(mut cache) = CACHE.try_lock() else {
+        return 0;
+    };
+    let freed = cache.len() * CHUNK;
+    for ptr in cache.drain(..) {
+        unsafe { dealloc(ptr as *mut u8, chunk_layout()) };
+    }
+    freed
+}
+
+/// Allocates chunks into the cache until the heap is full. The cache has room for them all up
+/// front, so that it doesn't grow meanwhile.
+fn fill_heap() {
+    let mut cache = CACHE.lock();
+    cache.reserve(allocator::heap_info().size / CHUNK);
+    while cache.len() < cache.capacity() {
+        let ptr = unsafe { alloc(chunk_layout()) };
+        if ptr.is_null() {
+            break;
+        }
+        cache.push(ptr as usize);
+    }
+}
+
+#[test_case]
+fn test_reclaim_and_retry() {
+    // Filled before the callback is registered, so that nothing is dropped yet
+    fill_heap();
+    let cached = CACHE.lock().len();
+    assert!(cached * CHUNK > allocator::heap_info().size / 2);
+
+    allocator::register_reclaim(drop_cache);
+    allocator::register_reclaim(drop_cache);
+    let before = oom::stats();
+    let calls = RECLAIM_CALLS.load(Ordering::SeqCst);
+    // Past the end of the heap, which only works once the cache is dropped
+    let boxed = Box::new([7u8; CHUNK]);
+    assert_eq!(boxed[CHUNK - 1], 7);
+
+    // Registering twice made no difference
+    assert_eq!(RECLAIM_CALLS.load(Ordering::SeqCst), calls + 1);
+    assert!(CACHE.lock().is_empty());
+    let after = oom::stats();
+    assert_eq!(after.reclaims, before.reclaims + 1);
+    assert_eq!(after.reclaimed, before.reclaimed + cached * CHUNK);
+    assert_eq!(after.recovered, before.recovered + 1);
+}
+
+#[test_case]
+fn test_emergency_pool_untouched() {
+    // Running out again and again never dips into the pool, which is only for the final report
+    for _ in 0..3 {
+        fill_heap();
+        let v = (0..CHUNK).map(|i| i as u8).collect::<Vec<_>>();
+        assert_eq!(v[CHUNK - 1], 255);
+        assert!(CACHE.lock().is_empty());
+    }
+    assert_eq!(oom::stats().emergency_used, 0);
+}
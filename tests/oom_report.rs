@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{
+    alloc::{alloc, Layout},
+    boxed::Box,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hannos::{
+    allocator::{self, oom},
+    exit_qemu, hlt_loop,
+    memory::{self, BootInfoFrameAllocator},
+    sprint, sprintln, QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    sprint!("oom_report... ");
+
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    while !unsafe { alloc(layout) }.is_null() {}
+    // Nothing can be reclaimed, so this goes to the allocation error handler
+    let x = Box::new([0u8; 1024]);
+    panic!("allocation succeeded with the heap full: {:p}", x);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let stats = oom::stats();
+    hannos::panic::report(info);
+    let reported = hannos::panic::with_last_message(|msg| msg.contains("memory allocation"));
+    // Reclaiming was tried for the allocation which failed, before giving up
+    if reported == Some(true) && stats.reclaims > 0 && stats.recovered == 0 {
+        sprintln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        sprintln!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+use core::{fmt, panic::PanicInfo, ptr::read_volatile};
+
+use hannos::{cmdline::Console, exit_qemu, println, sprint, sprintln, vgabuf, QemuExitCode};
+
+const MESSAGE: &str = "display impl panicked";
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    sprint!("panic_in_print... ");
+
+    // Only to the screen, so that the panic happens while the VGA writer is locked
+    vgabuf::set_console(Console::Vga);
+    println!("about to panic: {}", Panicky);
+    panic!("Execution continued after a panic while printing");
+}
+
+struct Panicky;
+
+impl fmt::Display for Panicky {
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        panic!("{}", MESSAGE);
+    }
+}
+
+/// The writer is still locked by `println!`, check that the message got past it anyway.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::panic::report(info);
+    let on_serial = hannos::panic::with_last_message(|message| message.contains(MESSAGE));
+    if on_serial == Some(true) && on_screen(MESSAGE) {
+        sprintln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        sprintln!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+/// Reads the VGA buffer itself, as the writer can't be trusted.
+fn on_screen(text: &str) -> bool {
+    let buffer = vgabuf::BUF_ADDR as *const u16;
+    (0..vgabuf::HEIGHT).any(|row| {
+        let glyphs: [u8; vgabuf::WIDTH] = core::array::from_fn(|col| {
+            let cell = unsafe { read_volatile(buffer.add(row * vgabuf::WIDTH + col)) };
+            cell as u8
+        });
+        glyphs
+            .windows(text.len())
+            .any(|window| window == text.as_bytes())
+    })
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hannos::{
+    allocator, exit_qemu,
+    memory::{self, BootInfoFrameAllocator},
+    sprint, sprintln, QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    sprint!("panic_no_alloc... ");
+
+    hannos::init().expect("initialization failed");
+    let phys_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+
+    allocator::fail_allocations(true);
+    // Fails to allocate, which panics through the allocation error handler
+    let x = Box::new([0u8; 64]);
+    panic!("allocation succeeded with allocations disabled: {:p}", x);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hannos::panic::report(info);
+    let reported = hannos::panic::with_last_message(|msg| msg.contains("memory allocation"));
+    if reported == Some(true) {
+        sprintln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        sprintln!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hannos::hlt_loop();
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+use core::ptr::read_volatile;
+
+use hannos::{exit_qemu, sprint, sprintln, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    sprint!("stack_overflow_report... ");
+
+    hannos::gdt::init();
+    hannos::interrupts::init_idt();
+
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    let _x = 0;
+    stack_overflow();
+    unsafe {
+        read_volatile(&_x);
+    }
+}
+
+/// The kernel's double fault handler panics with the overflow message, check that it was sent.
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    hannos::panic::enter();
+    hannos::panic::emit(format_args!("{}\n", info));
+    let reported = hannos::panic::with_last_message(|message| {
+        message.contains("kernel stack overflow") && message.contains("outside of any task")
+    });
+    if reported == Some(true) {
+        sprintln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        sprintln!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}